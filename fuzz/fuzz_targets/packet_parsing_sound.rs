@@ -7,5 +7,9 @@ fuzz_target!(|data: Vec<u8>| {
         let mut buf = vec![];
         a.serialize(&mut buf).unwrap();
         assert_eq!(data, buf);
+
+        let mut slice = vec![0; data.len()];
+        assert_eq!(a.serialize_into(&mut slice), Ok(data.len()));
+        assert_eq!(data, slice);
     }
 });
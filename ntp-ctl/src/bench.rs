@@ -0,0 +1,483 @@
+//! A probe of the quality of a server, to help choosing the upstream servers:
+//! a burst of requests, of which the spread in offset and delay is shown,
+//! together with what else can be learned about the server from the outside.
+
+use std::{
+    fmt::Write,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use clap::Args;
+use ntp_proto::{
+    sntp::{SntpClient, SntpError},
+    NtpPacket, NtpTimestamp, ReferenceId, ResponseValidationError,
+};
+
+/// Responses are no larger than requests, but servers may add extension fields
+const MAX_RESPONSE_SIZE: usize = 1024;
+
+/// The port of NTS key exchange (RFC 8915)
+const NTS_KE_PORT: u16 = 4460;
+
+#[derive(Args)]
+pub struct BenchCommand {
+    /// The server, as a name or address with an optional port
+    server: String,
+
+    /// Number of requests in the burst
+    #[arg(short = 'n', long, default_value_t = 8)]
+    count: usize,
+
+    /// Seconds between the requests
+    #[arg(short, long, default_value_t = 2.0)]
+    interval: f64,
+
+    /// Seconds to wait for each response
+    #[arg(short, long, default_value_t = 2.0)]
+    timeout: f64,
+}
+
+/// What became of a single request
+#[derive(Debug, Clone, PartialEq)]
+enum Outcome {
+    /// Offset and delay in seconds
+    Measured {
+        offset: f64,
+        delay: f64,
+    },
+    Kiss(ReferenceId),
+    Timeout,
+    Error(String),
+}
+
+impl Outcome {
+    fn answered(&self) -> bool {
+        matches!(self, Outcome::Measured { .. } | Outcome::Kiss(_))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Report {
+    address: SocketAddr,
+    /// The outcomes of the burst, in order
+    outcomes: Vec<Outcome>,
+    /// Precision and stratum of the latest response
+    precision: Option<i8>,
+    stratum: Option<u8>,
+    /// Whether requests of these versions were answered
+    versions: Vec<(u8, bool)>,
+    /// Whether the NTS key exchange port accepts connections
+    nts_ke: bool,
+}
+
+/// Minimum, median, mean, maximum and standard deviation
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Distribution {
+    min: f64,
+    median: f64,
+    mean: f64,
+    max: f64,
+    std_dev: f64,
+}
+
+impl Distribution {
+    fn new(values: &[f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+
+        let n = sorted.len();
+        let median = match n % 2 {
+            0 => (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0,
+            _ => sorted[n / 2],
+        };
+        let mean = sorted.iter().sum::<f64>() / n as f64;
+        let variance = sorted.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n as f64;
+
+        Some(Distribution {
+            min: sorted[0],
+            median,
+            mean,
+            max: sorted[n - 1],
+            std_dev: variance.sqrt(),
+        })
+    }
+}
+
+fn system_time() -> NtpTimestamp {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => NtpTimestamp::from_unix_seconds_nanos(
+            since_epoch.as_secs() as i64,
+            since_epoch.subsec_nanos(),
+        ),
+        Err(_) => NtpTimestamp::from_unix_seconds_nanos(0, 0),
+    }
+}
+
+/// The address of the server, on port 123 unless it has a port of its own
+fn resolve(server: &str) -> std::io::Result<SocketAddr> {
+    let mut addrs = match server.to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(_) => (server.trim_start_matches('[').trim_end_matches(']'), 123).to_socket_addrs()?,
+    };
+
+    addrs
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "address did not resolve"))
+}
+
+/// Send a single request of the given version, and wait for its response.
+/// Returns the precision and stratum of the response along with the outcome.
+fn exchange(socket: &UdpSocket, version: u8, timeout: Duration) -> (Outcome, Option<(i8, u8)>) {
+    let deadline = Instant::now() + timeout;
+
+    let client = SntpClient::new();
+    let mut request = client.serialize().to_vec();
+    request[0] = (request[0] & !0x38) | (version << 3);
+
+    let send_time = system_time();
+    if let Err(error) = socket.send(&request) {
+        return (Outcome::Error(error.to_string()), None);
+    }
+
+    let mut buf = [0; MAX_RESPONSE_SIZE];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return (Outcome::Timeout, None);
+        }
+        if let Err(error) = socket.set_read_timeout(Some(remaining)) {
+            return (Outcome::Error(error.to_string()), None);
+        }
+
+        let size = match socket.recv(&mut buf) {
+            Ok(size) => size,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                return (Outcome::Timeout, None)
+            }
+            Err(error) => return (Outcome::Error(error.to_string()), None),
+        };
+        let recv_time = system_time();
+
+        let header = NtpPacket::deserialize(&buf[..size])
+            .ok()
+            .map(|packet| (packet.precision(), packet.stratum()));
+
+        let outcome = match client.handle_response(&buf[..size], send_time, recv_time) {
+            // late responses to earlier requests, or other garbage
+            Err(SntpError::Parse(_))
+            | Err(SntpError::InvalidResponse(
+                ResponseValidationError::OriginMismatch | ResponseValidationError::ZeroOrigin,
+            )) => continue,
+            Ok(measurement) => Outcome::Measured {
+                offset: measurement.offset.to_seconds(),
+                delay: measurement.delay.to_seconds(),
+            },
+            Err(SntpError::Kiss(code)) => Outcome::Kiss(code),
+            Err(error) => Outcome::Error(error.to_string()),
+        };
+
+        return (outcome, header);
+    }
+}
+
+fn kiss_code(code: ReferenceId) -> String {
+    String::from_utf8_lossy(&code.to_bytes()).into_owned()
+}
+
+fn write_distribution(output: &mut String, name: &str, values: &[f64]) {
+    if let Some(d) = Distribution::new(values) {
+        let _ = writeln!(
+            output,
+            "{name:<8} {:>10.3} {:>10.3} {:>10.3} {:>10.3} {:>10.3}",
+            d.min * 1e3,
+            d.median * 1e3,
+            d.mean * 1e3,
+            d.max * 1e3,
+            d.std_dev * 1e3,
+        );
+    }
+}
+
+fn format(report: &Report) -> String {
+    let mut output = String::new();
+
+    let measurements: Vec<(f64, f64)> = report
+        .outcomes
+        .iter()
+        .filter_map(|outcome| match outcome {
+            Outcome::Measured { offset, delay } => Some((*offset, *delay)),
+            _ => None,
+        })
+        .collect();
+
+    // writing to a string can not fail
+    let _ = writeln!(
+        output,
+        "Server {}: {} of {} requests answered with the time",
+        report.address,
+        measurements.len(),
+        report.outcomes.len()
+    );
+
+    if !measurements.is_empty() {
+        let offsets: Vec<f64> = measurements.iter().map(|(offset, _)| *offset).collect();
+        let delays: Vec<f64> = measurements.iter().map(|(_, delay)| *delay).collect();
+
+        let _ = writeln!(
+            output,
+            "\n{:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "(ms)", "Min", "Median", "Mean", "Max", "Std dev"
+        );
+        write_distribution(&mut output, "Offset", &offsets);
+        write_distribution(&mut output, "Delay", &delays);
+    }
+
+    let _ = writeln!(output);
+    for (index, outcome) in report.outcomes.iter().enumerate() {
+        match outcome {
+            Outcome::Measured { .. } => {}
+            Outcome::Kiss(code) => {
+                let _ = writeln!(
+                    output,
+                    "Request {}: Kiss-o'-Death {}",
+                    index + 1,
+                    kiss_code(*code)
+                );
+            }
+            Outcome::Timeout => {
+                let _ = writeln!(output, "Request {}: no response", index + 1);
+            }
+            Outcome::Error(error) => {
+                let _ = writeln!(output, "Request {}: {error}", index + 1);
+            }
+        }
+    }
+
+    let rate_kisses = report
+        .outcomes
+        .iter()
+        .filter(|outcome| **outcome == Outcome::Kiss(ReferenceId::KISS_RATE))
+        .count();
+    let answered = report
+        .outcomes
+        .iter()
+        .rposition(Outcome::answered)
+        .map(|last| last + 1);
+    let unanswered_tail = report.outcomes.len() - answered.unwrap_or(0);
+
+    let _ = match answered {
+        _ if rate_kisses > 0 => writeln!(
+            output,
+            "Rate limiting: {rate_kisses} RATE kisses, poll this server less often"
+        ),
+        Some(answered) if unanswered_tail >= 2 => writeln!(
+            output,
+            "Rate limiting: likely, no responses after request {answered}"
+        ),
+        _ => writeln!(output, "Rate limiting: not detected"),
+    };
+
+    if let Some(stratum) = report.stratum {
+        let _ = writeln!(output, "Stratum: {stratum}");
+    }
+    if let Some(precision) = report.precision {
+        let _ = writeln!(
+            output,
+            "Precision: 2^{precision} s ({:.3} µs)",
+            2f64.powi(precision as i32) * 1e6
+        );
+    }
+
+    for (version, supported) in &report.versions {
+        let status = match supported {
+            true => "answered",
+            false => "not answered",
+        };
+        let _ = writeln!(output, "NTPv{version}: {status}");
+    }
+
+    let nts = match report.nts_ke {
+        true => "key exchange port is open (the TLS handshake is not tried)",
+        false => "key exchange port is closed or filtered",
+    };
+    let _ = writeln!(output, "NTS: {nts}");
+
+    output
+}
+
+/// The duration of `seconds`, unless it is negative, too large or not a number
+fn duration(seconds: f64) -> Option<Duration> {
+    (0.0..u64::MAX as f64)
+        .contains(&seconds)
+        .then(|| Duration::from_secs_f64(seconds))
+}
+
+pub fn run(command: &BenchCommand) -> i32 {
+    let (timeout, interval) = match (duration(command.timeout), duration(command.interval)) {
+        (Some(timeout), Some(interval)) if !timeout.is_zero() => (timeout, interval),
+        _ => {
+            eprintln!("The timeout must be positive, and the interval not negative");
+            return 1;
+        }
+    };
+
+    let address = match resolve(&command.server) {
+        Ok(address) => address,
+        Err(error) => {
+            eprintln!("Could not resolve {}: {error}", command.server);
+            return 1;
+        }
+    };
+
+    let bind_addr: SocketAddr = match address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = match UdpSocket::bind(bind_addr).and_then(|socket| {
+        socket.connect(address)?;
+        Ok(socket)
+    }) {
+        Ok(socket) => socket,
+        Err(error) => {
+            eprintln!("Could not open a socket to {address}: {error}");
+            return 1;
+        }
+    };
+
+    let mut report = Report {
+        address,
+        outcomes: Vec::with_capacity(command.count),
+        precision: None,
+        stratum: None,
+        versions: vec![],
+        nts_ke: false,
+    };
+
+    for index in 0..command.count {
+        if index > 0 {
+            std::thread::sleep(interval);
+        }
+
+        let (outcome, header) = exchange(&socket, 4, timeout);
+        if let Some((precision, stratum)) = header {
+            report.precision = Some(precision);
+            report.stratum = Some(stratum);
+        }
+        report.outcomes.push(outcome);
+    }
+
+    // the burst shows whether version 4 is answered
+    let answered_v4 = report.outcomes.iter().any(Outcome::answered);
+    report.versions.push((4, answered_v4));
+    std::thread::sleep(interval);
+    let (outcome, _) = exchange(&socket, 3, timeout);
+    report.versions.push((3, outcome.answered()));
+
+    let nts_ke = SocketAddr::new(address.ip(), NTS_KE_PORT);
+    report.nts_ke = TcpStream::connect_timeout(&nts_ke, timeout).is_ok();
+
+    print!("{}", format(&report));
+
+    match answered_v4 {
+        true => 0,
+        false => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distribution() {
+        assert_eq!(Distribution::new(&[]), None);
+
+        let d = Distribution::new(&[4.0, 1.0, 3.0, 2.0]).unwrap();
+        assert_eq!(d.min, 1.0);
+        assert_eq!(d.median, 2.5);
+        assert_eq!(d.mean, 2.5);
+        assert_eq!(d.max, 4.0);
+        assert!((d.std_dev - 1.25f64.sqrt()).abs() < 1e-12);
+
+        assert_eq!(Distribution::new(&[3.0, 1.0, 2.0]).unwrap().median, 2.0);
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            resolve("192.0.2.1").unwrap(),
+            "192.0.2.1:123".parse().unwrap()
+        );
+        assert_eq!(
+            resolve("192.0.2.1:1123").unwrap(),
+            "192.0.2.1:1123".parse().unwrap()
+        );
+        assert_eq!(resolve("[::1]").unwrap(), "[::1]:123".parse().unwrap());
+        assert_eq!(resolve("::1").unwrap(), "[::1]:123".parse().unwrap());
+    }
+
+    fn measured(offset: f64, delay: f64) -> Outcome {
+        Outcome::Measured { offset, delay }
+    }
+
+    #[test]
+    fn test_format() {
+        let mut report = Report {
+            address: "192.0.2.1:123".parse().unwrap(),
+            outcomes: vec![
+                measured(0.001, 0.010),
+                measured(0.003, 0.030),
+                Outcome::Timeout,
+                measured(0.002, 0.020),
+            ],
+            precision: Some(-20),
+            stratum: Some(2),
+            versions: vec![(4, true), (3, false)],
+            nts_ke: true,
+        };
+
+        let output = format(&report);
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(
+            lines[0],
+            "Server 192.0.2.1:123: 3 of 4 requests answered with the time"
+        );
+        assert_eq!(
+            lines[3],
+            "Offset        1.000      2.000      2.000      3.000      0.816"
+        );
+        assert!(lines[4].starts_with("Delay        10.000     20.000     20.000     30.000"));
+        assert_eq!(lines[6], "Request 3: no response");
+        assert_eq!(lines[7], "Rate limiting: not detected");
+        assert_eq!(lines[8], "Stratum: 2");
+        assert_eq!(lines[9], "Precision: 2^-20 s (0.954 µs)");
+        assert_eq!(lines[10], "NTPv4: answered");
+        assert_eq!(lines[11], "NTPv3: not answered");
+        assert!(lines[12].starts_with("NTS: key exchange port is open"));
+
+        // responses that stop arriving
+        report.outcomes.push(Outcome::Timeout);
+        report.outcomes.push(Outcome::Timeout);
+        assert!(format(&report).contains("Rate limiting: likely, no responses after request 4\n"));
+
+        report.outcomes.push(Outcome::Kiss(ReferenceId::KISS_RATE));
+        let output = format(&report);
+        assert!(output.contains("Request 7: Kiss-o'-Death RATE\n"));
+        assert!(output.contains("Rate limiting: 1 RATE kisses"));
+
+        report.outcomes = vec![Outcome::Timeout];
+        let output = format(&report);
+        assert!(output.starts_with("Server 192.0.2.1:123: 0 of 1 requests answered"));
+        assert!(!output.contains("Median"));
+    }
+}
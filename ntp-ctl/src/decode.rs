@@ -0,0 +1,150 @@
+use std::path::Path;
+
+use clap::Args;
+use ntp_proto::{parse_pcap, NtpPacket};
+
+#[derive(Args)]
+pub struct DecodeCommand {
+    /// The packet in hexadecimal, or a file with either the packet in
+    /// hexadecimal or a pcap capture, of which all NTP packets are decoded
+    input: String,
+}
+
+/// Magic numbers of pcap files, in microsecond and nanosecond resolution and
+/// either byte order
+const PCAP_MAGIC: [[u8; 4]; 4] = [
+    [0xd4, 0xc3, 0xb2, 0xa1],
+    [0x4d, 0x3c, 0xb2, 0xa1],
+    [0xa1, 0xb2, 0xc3, 0xd4],
+    [0xa1, 0xb2, 0x3c, 0x4d],
+];
+
+/// Bytes written in hexadecimal, as in a hex dump or in the logs of the
+/// daemon. Whitespace, colons and a `0x` prefix are allowed.
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.trim();
+    let digits: Vec<u8> = text
+        .strip_prefix("0x")
+        .unwrap_or(text)
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace() && *c != b':')
+        .collect();
+
+    if digits.len() % 2 == 1 {
+        return Err("odd number of hexadecimal digits".into());
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("invalid hexadecimal `{}`", String::from_utf8_lossy(pair)))
+        })
+        .collect()
+}
+
+fn decode(data: &[u8]) -> String {
+    match NtpPacket::deserialize(data) {
+        Ok(packet) => packet.pretty().to_string(),
+        Err(error) => format!(
+            "Could not decode the packet of {} bytes: {error}\n",
+            data.len()
+        ),
+    }
+}
+
+fn decode_pcap(data: &[u8]) -> Result<String, String> {
+    let packets = parse_pcap(data).map_err(|error| error.to_string())?;
+    let first = match packets.first() {
+        Some(packet) => packet.time,
+        None => return Ok("The capture has no NTP packets\n".into()),
+    };
+
+    let mut output = String::new();
+    for (index, packet) in packets.iter().enumerate() {
+        output.push_str(&format!(
+            "Frame {}: {} -> {}, {} bytes, +{:.6} s\n",
+            index + 1,
+            packet.source,
+            packet.destination,
+            packet.data.len(),
+            (packet.time - first).to_seconds()
+        ));
+        output.push_str(&decode(&packet.data));
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+pub fn run(command: &DecodeCommand) -> i32 {
+    let path = Path::new(&command.input);
+    let result = if path.is_file() {
+        match std::fs::read(path) {
+            Ok(data) if data.len() >= 4 && PCAP_MAGIC.iter().any(|magic| data[..4] == *magic) => {
+                decode_pcap(&data)
+            }
+            Ok(data) => parse_hex(&String::from_utf8_lossy(&data)).map(|data| decode(&data)),
+            Err(error) => Err(format!("Could not read {}: {error}", path.display())),
+        }
+    } else {
+        parse_hex(&command.input).map(|data| decode(&data))
+    };
+
+    match result {
+        Ok(output) => {
+            print!("{output}");
+            0
+        }
+        Err(error) => {
+            eprintln!("{error}");
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(
+            parse_hex("0x2402 06ec\n").unwrap(),
+            [0x24, 0x02, 0x06, 0xec]
+        );
+        assert_eq!(parse_hex("24:02:06:EC").unwrap(), [0x24, 0x02, 0x06, 0xec]);
+        assert!(parse_hex("240").is_err());
+        assert!(parse_hex("24zz").is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut data = vec![0x23, 0x00, 0x06, 0xec];
+        data.extend_from_slice(&[0; 44]);
+        let output = decode(&data);
+        assert!(output.starts_with("Network Time Protocol (NTP Version 4, client)\n"));
+        assert!(output.contains("    Reference ID: \n"));
+
+        assert_eq!(
+            decode(&data[..20]),
+            "Could not decode the packet of 20 bytes: Incorrect packet length\n"
+        );
+    }
+
+    #[test]
+    fn test_decode_pcap() {
+        let output = decode_pcap(include_bytes!(
+            "../../ntp-proto/testdata/pcap/nts-exchange.pcap"
+        ))
+        .unwrap();
+        assert!(output.starts_with("Frame 1: [2001:db8::10]:40123 -> [2001:db8::1]:123, "));
+        assert!(output.contains("    Extension Field: NTS Cookie (0x0204), 104 bytes\n"));
+
+        assert_eq!(
+            decode_pcap(b"not a capture").unwrap_err(),
+            "Not a pcap file"
+        );
+    }
+}
@@ -0,0 +1,670 @@
+use std::{
+    fmt::Display,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    path::Path,
+};
+
+use ntp_daemon::{
+    observer::{ClockMode, SelectionStatus, SmearEvidence, Unreachable},
+    ObservablePeerState, ObservableState,
+};
+
+/// Peers with a quality score below this are pointed out to the user
+const LOW_QUALITY: u8 = 50;
+
+/// Offsets are only considered suspicious when they deviate at least this much
+/// (in seconds) from the other peers
+const MIN_ASYMMETRY: f64 = 0.001;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The daemon is not keeping time
+    Problem,
+    /// The daemon keeps time, but likely not as well as it could
+    Warning,
+    /// Worth knowing, but not necessarily wrong
+    Hint,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Problem => f.write_str("problem"),
+            Severity::Warning => f.write_str("warning"),
+            Severity::Hint => f.write_str("hint"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub summary: String,
+    pub advice: String,
+}
+
+impl Finding {
+    fn new(severity: Severity, summary: impl Into<String>, advice: impl Into<String>) -> Self {
+        Finding {
+            severity,
+            summary: summary.into(),
+            advice: advice.into(),
+        }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "[{}] {}", self.severity, self.summary)?;
+        write!(f, "    {}", self.advice)
+    }
+}
+
+/// Inspect the daemon and its environment, print the findings ordered by
+/// severity and return the exit code.
+pub async fn run(observation: &Path, configuration: &Path) -> i32 {
+    let mut findings = vec![];
+
+    match read_state(observation).await {
+        Ok(state) => {
+            findings.extend(check_state(&state));
+            findings.extend(check_server_sockets(&state));
+        }
+        Err(error) => findings.push(observation_socket_finding(observation, &error)),
+    }
+
+    findings.extend(check_configuration_socket(configuration));
+
+    findings.sort_by_key(|finding| finding.severity);
+
+    if findings.is_empty() {
+        println!("No problems found");
+    }
+
+    for finding in &findings {
+        println!("{}", finding);
+    }
+
+    match findings.first() {
+        Some(finding) if finding.severity == Severity::Problem => 1,
+        _ => 0,
+    }
+}
+
+async fn read_state(observation: &Path) -> std::io::Result<ObservableState> {
+    let mut stream = tokio::net::UnixStream::connect(observation).await?;
+    let mut msg = Vec::with_capacity(16 * 1024);
+    ntp_daemon::sockets::read_json(&mut stream, &mut msg).await
+}
+
+fn observation_socket_finding(path: &Path, error: &std::io::Error) -> Finding {
+    let summary = format!(
+        "Could not connect to the observation socket at {}: {}",
+        path.display(),
+        error
+    );
+
+    let advice = match error.kind() {
+        ErrorKind::PermissionDenied => {
+            "The current user may not access the socket. Run ntp-ctl as a user in the group of \
+             the daemon, or widen the `mode` of the observation socket in the configuration."
+        }
+        ErrorKind::NotFound | ErrorKind::ConnectionRefused => {
+            "The daemon is not running, or the observation socket is not enabled in its \
+             configuration. If the daemon exited, check its logs: it refuses to step the clock \
+             by more than the panic threshold and exits instead."
+        }
+        _ => "Check that the daemon is running and that the socket path is correct.",
+    };
+
+    Finding::new(Severity::Problem, summary, advice)
+}
+
+/// Findings that follow from the state reported by the daemon
+pub fn check_state(state: &ObservableState) -> Vec<Finding> {
+    let mut findings = vec![];
+
+    findings.extend(check_clock_mode(state));
+    findings.extend(check_peers(state));
+    findings.extend(check_asymmetry(state));
+    findings.extend(check_steps(state));
+    findings.extend(check_servers(state));
+
+    findings
+}
+
+fn check_clock_mode(state: &ObservableState) -> Option<Finding> {
+    match state.clock_mode {
+        ClockMode::Steering => None,
+        ClockMode::MonitorOnly => Some(Finding::new(
+            Severity::Hint,
+            "The clock is only monitored",
+            "The daemon measures the clock, but never adjusts it, as configured with the \
+             `monitor` clock backend. Something else must keep the clock synchronized.",
+        )),
+        ClockMode::MonitorOnlyFallback => Some(Finding::new(
+            Severity::Warning,
+            "The clock is only monitored, as the daemon may not adjust it",
+            "The daemon lacks CAP_SYS_TIME, for example in a container whose host owns the \
+             clock. Grant the capability to let it adjust the clock, or configure the `monitor` \
+             clock backend if this is intended.",
+        )),
+        ClockMode::Measuring => Some(Finding::new(
+            Severity::Hint,
+            "The sources are only measured",
+            "The daemon measures its sources, without clock selection, and never adjusts the \
+             clock, as configured with the `measure` clock backend. Something else must keep \
+             the clock synchronized.",
+        )),
+    }
+}
+
+fn check_peers(state: &ObservableState) -> Vec<Finding> {
+    let mut findings = vec![];
+
+    if state.peers.is_empty() {
+        findings.push(Finding::new(
+            Severity::Problem,
+            "No time sources are configured",
+            "Add at least one peer or reference clock to the configuration.",
+        ));
+        return findings;
+    }
+
+    let mut reachable = 0;
+    for peer in &state.peers {
+        if let ObservablePeerState::Observable {
+            reachability,
+            address,
+            quality,
+            unreachable,
+            standby,
+            smearing,
+            ..
+        } = peer
+        {
+            // only probed, an unreachable standby address is no problem
+            if *standby {
+                continue;
+            }
+
+            if !reachability.is_reachable() {
+                findings.push(Finding::new(
+                    Severity::Warning,
+                    format!("Peer {} is unreachable", address),
+                    unreachable_advice(*unreachable),
+                ));
+            } else {
+                reachable += 1;
+
+                if *quality < LOW_QUALITY {
+                    findings.push(Finding::new(
+                        Severity::Hint,
+                        format!("Peer {} has a low quality score of {}", address, quality),
+                        "Its measurements are noisy or it often disagrees with the other peers. \
+                         Consider replacing it with a closer or more reliable peer.",
+                    ));
+                }
+
+                if let Some(evidence) = smearing {
+                    findings.push(Finding::new(
+                        Severity::Warning,
+                        format!("Peer {} appears to smear leap seconds", address),
+                        smearing_advice(*evidence),
+                    ));
+                }
+            }
+        }
+    }
+
+    if reachable == 0 {
+        findings.push(Finding::new(
+            Severity::Problem,
+            "None of the peers are reachable",
+            "Outgoing NTP traffic (UDP port 123) may be blocked by a firewall, or name resolution \
+             may be failing. Right after startup, wait a few poll intervals and try again.",
+        ));
+    } else if let SelectionStatus::InsufficientConsensus { agreeing, required } = state.selection {
+        findings.push(Finding::new(
+            Severity::Problem,
+            format!(
+                "Insufficient consensus: {} sources agree on the time, {} required",
+                agreeing, required
+            ),
+            "The clock is not adjusted until enough sources agree. Add more independent peers, \
+             or lower `min-intersection-survivors` if fewer agreeing sources are acceptable.",
+        ));
+    } else if let SelectionStatus::InsufficientAuthenticated {
+        authenticated,
+        required,
+    } = state.selection
+    {
+        findings.push(Finding::new(
+            Severity::Problem,
+            format!(
+                "Insufficient authenticated sources: {} of the agreeing sources are authenticated, {} required",
+                authenticated, required
+            ),
+            "The clock is not adjusted until enough of the sources that agree on the time are \
+             authenticated, as set by the `authentication-policy`. Add authenticated peers or \
+             reference clocks, or relax the policy.",
+        ));
+    } else if !state.system.leap_indicator.is_synchronized() {
+        findings.push(Finding::new(
+            Severity::Warning,
+            "The clock is not synchronized",
+            "Some peers are reachable, but no consistent time could be selected yet. If this \
+             persists, the peers disagree with each other: add more independent peers.",
+        ));
+    }
+
+    findings
+}
+
+fn unreachable_advice(unreachable: Option<Unreachable>) -> &'static str {
+    match unreachable {
+        Some(Unreachable::NotListening) => {
+            "The host of the peer reports that no NTP server listens on its port. Check that the \
+             address and port are correct, and that the server is running."
+        }
+        Some(Unreachable::Prohibited) => {
+            "The network reports that NTP traffic to the peer is prohibited. Check the firewalls \
+             between this host and the peer."
+        }
+        Some(
+            Unreachable::HostUnreachable
+            | Unreachable::NetworkUnreachable
+            | Unreachable::NetworkError,
+        ) => {
+            "The network reports that the peer can not be reached. Check that the address is \
+             correct, and the routing towards the peer."
+        }
+        Some(Unreachable::Timeout) | None => {
+            "None of the last 8 polls were answered. Check that the address is correct \
+             and that the peer is still in service."
+        }
+    }
+}
+
+fn smearing_advice(evidence: SmearEvidence) -> &'static str {
+    match evidence {
+        SmearEvidence::ReferenceId => {
+            "Its reference id is the one ntpd uses while smearing a leap second. Mixing smearing \
+             and non-smearing peers pulls the clock in between, so use either kind only."
+        }
+        SmearEvidence::Drift => {
+            "Its offset drifted away from the other peers around a leap second that it did not \
+             announce. Mixing smearing and non-smearing peers pulls the clock in between, so use \
+             either kind only."
+        }
+    }
+}
+
+/// A peer whose offset deviates from the consensus by a significant part of
+/// its delay likely has an asymmetric network path, which NTP can not correct
+/// for.
+fn check_asymmetry(state: &ObservableState) -> Vec<Finding> {
+    let peers: Vec<_> = state
+        .peers
+        .iter()
+        .filter_map(|peer| match peer {
+            // a smearing peer deviates for another reason
+            ObservablePeerState::Observable {
+                statistics,
+                reachability,
+                address,
+                smearing: None,
+                ..
+            } if reachability.is_reachable() => Some((
+                address,
+                statistics.offset.to_seconds(),
+                statistics.delay.to_seconds(),
+            )),
+            _ => None,
+        })
+        .collect();
+
+    // With fewer peers there is no meaningful consensus
+    if peers.len() < 3 {
+        return vec![];
+    }
+
+    let mut offsets: Vec<f64> = peers.iter().map(|(_, offset, _)| *offset).collect();
+    offsets.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let median = offsets[offsets.len() / 2];
+
+    peers
+        .into_iter()
+        .filter(|(_, offset, delay)| {
+            let deviation = (offset - median).abs();
+            deviation > MIN_ASYMMETRY && deviation > delay / 4.0
+        })
+        .map(|(address, offset, delay)| {
+            Finding::new(
+                Severity::Warning,
+                format!(
+                    "Peer {} deviates {:.3}ms from the other peers with a delay of {:.3}ms",
+                    address,
+                    (offset - median) * 1e3,
+                    delay * 1e3
+                ),
+                "The network path to this peer is likely asymmetric, which shows up as an \
+                 offset. Prefer peers on a shorter or more symmetric route.",
+            )
+        })
+        .collect()
+}
+
+fn check_steps(state: &ObservableState) -> Vec<Finding> {
+    let steps = state.system.accumulated_steps.to_seconds();
+
+    match state.system.accumulated_steps_threshold {
+        Some(threshold) if steps > threshold.to_seconds() / 2.0 => vec![Finding::new(
+            Severity::Warning,
+            format!(
+                "The clock was stepped by {:.3}s in total, out of an allowed {:.3}s",
+                steps,
+                threshold.to_seconds()
+            ),
+            "Once the threshold is crossed, the daemon refuses further steps and exits. Find out \
+             why the clock keeps jumping, e.g. another program setting the time.",
+        )],
+        _ if steps > 0.0 => vec![Finding::new(
+            Severity::Hint,
+            format!("The clock was stepped by {:.3}s in total", steps),
+            "Steps are expected at startup, but repeated steps suggest that another program is \
+             also adjusting the clock.",
+        )],
+        _ => vec![],
+    }
+}
+
+fn check_servers(state: &ObservableState) -> Vec<Finding> {
+    state
+        .servers
+        .iter()
+        .filter(|server| server.stats.received_packets.get() == 0)
+        .map(|server| {
+            Finding::new(
+                Severity::Hint,
+                format!(
+                    "The server on {} has not received any requests",
+                    SocketAddr::from(server.address)
+                ),
+                "If clients are expected, incoming UDP traffic on this port may be blocked by a \
+                 firewall.",
+            )
+        })
+        .collect()
+}
+
+/// The daemon keeps retrying to open its server sockets. If we can bind to
+/// the address ourselves, the daemon is not listening on it.
+fn check_server_sockets(state: &ObservableState) -> Vec<Finding> {
+    state
+        .servers
+        .iter()
+        .map(|server| SocketAddr::from(server.address))
+        .filter(|addr| UdpSocket::bind(addr).is_ok())
+        .map(|addr| {
+            Finding::new(
+                Severity::Problem,
+                format!("The daemon is not listening on {}", addr),
+                "It could not open the server socket. Ports below 1024 need elevated privileges, \
+                 and the address must be configured on one of the interfaces.",
+            )
+        })
+        .collect()
+}
+
+fn check_configuration_socket(path: &Path) -> Option<Finding> {
+    // Connecting would make the daemon wait for a configuration update, so
+    // instead the socket is opened as a file. That always fails, but only
+    // reports a lack of permission when we would not be allowed to connect.
+    let error = std::fs::OpenOptions::new().write(true).open(path).err()?;
+
+    match error.kind() {
+        ErrorKind::PermissionDenied => Some(Finding::new(
+            Severity::Hint,
+            format!(
+                "The current user may not access the configuration socket at {}",
+                path.display()
+            ),
+            "`ntp-ctl config` will not work. Add the user to the group of the daemon to allow \
+             changing its configuration.",
+        )),
+        ErrorKind::NotFound => Some(Finding::new(
+            Severity::Hint,
+            format!("There is no configuration socket at {}", path.display()),
+            "`ntp-ctl config` will not work. Enable the configuration socket in the \
+             configuration of the daemon if needed.",
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_daemon::observer::ObservableServerState;
+    use ntp_proto::{NtpDuration, NtpLeapIndicator, PeerStatistics, PollInterval, SystemSnapshot};
+
+    use super::*;
+
+    fn peer(address: &str, offset: f64, delay: f64, reach: u8) -> ObservablePeerState {
+        ObservablePeerState::Observable {
+            statistics: PeerStatistics {
+                offset: NtpDuration::from_seconds(offset),
+                delay: NtpDuration::from_seconds(delay),
+                dispersion: NtpDuration::from_seconds(0.001),
+                jitter: 0.0001,
+            },
+            reachability: serde_json::from_value(reach.into()).unwrap(),
+            uptime: std::time::Duration::from_secs(100),
+            poll_interval: PollInterval::default(),
+            peer_id: serde_json::from_value(0.into()).unwrap(),
+            address: address.into(),
+            local_address: None,
+            quality: 90,
+            rejections: Default::default(),
+            unreachable: None,
+            standby: false,
+            failures: 0,
+            refclock: None,
+            smearing: None,
+            version: None,
+            nonconforming: 0,
+        }
+    }
+
+    fn state(peers: Vec<ObservablePeerState>) -> ObservableState {
+        ObservableState {
+            system: SystemSnapshot {
+                leap_indicator: NtpLeapIndicator::NoWarning,
+                ..Default::default()
+            },
+            peers,
+            servers: vec![],
+            selection: Default::default(),
+            intervals: Default::default(),
+            history: Default::default(),
+            clock_mode: Default::default(),
+            features: Default::default(),
+        }
+    }
+
+    fn summaries(findings: &[Finding]) -> Vec<&str> {
+        findings.iter().map(|f| f.summary.as_str()).collect()
+    }
+
+    #[test]
+    fn test_healthy() {
+        let state = state(vec![
+            peer("a", 0.0001, 0.010, 0xff),
+            peer("b", 0.0002, 0.010, 0xff),
+            peer("c", 0.0003, 0.010, 0xff),
+        ]);
+
+        assert_eq!(check_state(&state), vec![]);
+    }
+
+    #[test]
+    fn test_no_peers() {
+        let findings = check_state(&state(vec![]));
+        assert_eq!(summaries(&findings), vec!["No time sources are configured"]);
+        assert_eq!(findings[0].severity, Severity::Problem);
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let findings = check_state(&state(vec![
+            peer("a", 0.0, 0.010, 0),
+            ObservablePeerState::Nothing,
+        ]));
+        assert_eq!(
+            summaries(&findings),
+            vec!["Peer a is unreachable", "None of the peers are reachable"]
+        );
+
+        let findings = check_state(&state(vec![
+            peer("a", 0.0, 0.010, 0),
+            peer("b", 0.0, 0.010, 1),
+        ]));
+        assert_eq!(summaries(&findings), vec!["Peer a is unreachable"]);
+        assert!(findings[0].advice.contains("None of the last 8 polls"));
+
+        // the network told us why
+        let mut refusing = peer("a", 0.0, 0.010, 0);
+        if let ObservablePeerState::Observable { unreachable, .. } = &mut refusing {
+            *unreachable = Some(Unreachable::NotListening);
+        }
+        let findings = check_state(&state(vec![refusing, peer("b", 0.0, 0.010, 1)]));
+        assert_eq!(summaries(&findings), vec!["Peer a is unreachable"]);
+        assert!(findings[0].advice.contains("no NTP server listens"));
+
+        // the other address of a dual-stack peer is only probed
+        let mut standby = peer("a", 0.0, 0.010, 0);
+        if let ObservablePeerState::Observable { standby, .. } = &mut standby {
+            *standby = true;
+        }
+        let findings = check_state(&state(vec![standby, peer("a", 0.0, 0.010, 1)]));
+        assert!(findings.is_empty());
+    }
+
+    #[test]
+    fn test_unsynchronized() {
+        let mut state = state(vec![peer("a", 0.0, 0.010, 0xff)]);
+        state.system.leap_indicator = NtpLeapIndicator::Unknown;
+
+        assert_eq!(
+            summaries(&check_state(&state)),
+            vec!["The clock is not synchronized"]
+        );
+
+        state.selection = SelectionStatus::InsufficientConsensus {
+            agreeing: 1,
+            required: 3,
+        };
+        assert_eq!(
+            summaries(&check_state(&state)),
+            vec!["Insufficient consensus: 1 sources agree on the time, 3 required"]
+        );
+
+        state.selection = SelectionStatus::InsufficientAuthenticated {
+            authenticated: 0,
+            required: 1,
+        };
+        assert_eq!(
+            summaries(&check_state(&state)),
+            vec!["Insufficient authenticated sources: 0 of the agreeing sources are authenticated, 1 required"]
+        );
+    }
+
+    #[test]
+    fn test_asymmetry() {
+        let findings = check_state(&state(vec![
+            peer("a", 0.0001, 0.010, 0xff),
+            peer("b", 0.0002, 0.010, 0xff),
+            peer("c", 0.0040, 0.010, 0xff),
+        ]));
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].summary.starts_with("Peer c deviates"));
+    }
+
+    #[test]
+    fn test_smearing() {
+        let mut smearing = peer("c", 0.0400, 0.010, 0xff);
+        if let ObservablePeerState::Observable { smearing, .. } = &mut smearing {
+            *smearing = Some(SmearEvidence::Drift);
+        }
+        let findings = check_state(&state(vec![
+            peer("a", 0.0001, 0.010, 0xff),
+            peer("b", 0.0002, 0.010, 0xff),
+            smearing,
+        ]));
+        assert_eq!(
+            summaries(&findings),
+            vec!["Peer c appears to smear leap seconds"]
+        );
+        assert!(findings[0].advice.contains("did not announce"));
+    }
+
+    #[test]
+    fn test_steps() {
+        let mut state = state(vec![peer("a", 0.0, 0.010, 0xff)]);
+
+        state.system.accumulated_steps = NtpDuration::from_seconds(2.0);
+        assert_eq!(check_state(&state)[0].severity, Severity::Hint);
+
+        state.system.accumulated_steps_threshold = Some(NtpDuration::from_seconds(3.0));
+        assert_eq!(check_state(&state)[0].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn test_monitor_only() {
+        let mut state = state(vec![
+            peer("a", 0.0001, 0.010, 0xff),
+            peer("b", 0.0002, 0.010, 0xff),
+            peer("c", 0.0003, 0.010, 0xff),
+        ]);
+
+        state.clock_mode = ClockMode::MonitorOnly;
+        assert_eq!(check_state(&state)[0].severity, Severity::Hint);
+
+        state.clock_mode = ClockMode::MonitorOnlyFallback;
+        assert_eq!(
+            summaries(&check_state(&state)),
+            vec!["The clock is only monitored, as the daemon may not adjust it"]
+        );
+
+        state.clock_mode = ClockMode::Measuring;
+        state.selection = SelectionStatus::Measuring;
+        assert_eq!(
+            summaries(&check_state(&state)),
+            vec!["The sources are only measured"]
+        );
+    }
+
+    #[test]
+    fn test_idle_server() {
+        let mut state = state(vec![peer("a", 0.0, 0.010, 0xff)]);
+        state.servers.push(ObservableServerState {
+            address: "127.0.0.1:123".parse::<SocketAddr>().unwrap().into(),
+            stats: Default::default(),
+            clients: vec![],
+        });
+
+        assert_eq!(
+            summaries(&check_state(&state)),
+            vec!["The server on 127.0.0.1:123 has not received any requests"]
+        );
+    }
+
+    #[test]
+    fn test_configuration_socket() {
+        let finding = check_configuration_socket(Path::new("/does/not/exist")).unwrap();
+        assert!(finding
+            .summary
+            .starts_with("There is no configuration socket"));
+    }
+}
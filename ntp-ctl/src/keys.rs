@@ -0,0 +1,138 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::{Args, Subcommand, ValueEnum};
+use ntp_daemon::keys::{KeysError, KeysFile};
+use ntp_proto::KeyAlgorithm;
+
+#[derive(Args)]
+pub struct KeysCommand {
+    /// The keys file, by default the one in the configuration
+    #[arg(short, long)]
+    file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    action: KeysAction,
+}
+
+#[derive(Subcommand)]
+enum KeysAction {
+    #[command(about = "Add a new random key to the keys file")]
+    Generate {
+        /// Id of the key, by default one more than the highest id in use
+        #[arg(long)]
+        id: Option<u32>,
+        #[arg(short, long, value_enum, default_value_t = Algorithm::Aes128Cmac)]
+        algorithm: Algorithm,
+        /// Unix time from which the key is used
+        #[arg(long)]
+        valid_from: Option<i64>,
+        /// Unix time until which the key is used
+        #[arg(long)]
+        valid_until: Option<i64>,
+    },
+    #[command(about = "Replace a key by a new random key with the same id")]
+    Rotate {
+        #[arg(long)]
+        id: u32,
+        /// Seconds from now after which the new key is used, which should be
+        /// enough to copy the keys file to the peers
+        #[arg(long, default_value_t = 3600)]
+        activate_in: i64,
+        /// Seconds that the old key is still accepted after the new key is used
+        #[arg(long, default_value_t = 3600)]
+        overlap: i64,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Algorithm {
+    Md5,
+    Sha1,
+    #[value(name = "aes-128-cmac")]
+    Aes128Cmac,
+}
+
+impl From<Algorithm> for KeyAlgorithm {
+    fn from(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Md5 => KeyAlgorithm::Md5,
+            Algorithm::Sha1 => KeyAlgorithm::Sha1,
+            Algorithm::Aes128Cmac => KeyAlgorithm::Aes128Cmac,
+        }
+    }
+}
+
+/// A missing keys file is treated as one without keys, so `generate` creates it
+fn read(path: &Path) -> Result<KeysFile, KeysError> {
+    match KeysFile::read(path) {
+        Err(KeysError::Io(error)) if error.kind() == ErrorKind::NotFound => Ok(KeysFile::default()),
+        result => result,
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn change(command: KeysCommand, default_file: Option<PathBuf>) -> Result<String, KeysError> {
+    let path = match command.file.or(default_file) {
+        Some(path) => path,
+        None => {
+            return Err(KeysError::Io(std::io::Error::new(
+                ErrorKind::NotFound,
+                "no keys file given, and none in the configuration",
+            )))
+        }
+    };
+
+    let mut file = read(&path)?;
+    let message = match command.action {
+        KeysAction::Generate {
+            id,
+            algorithm,
+            valid_from,
+            valid_until,
+        } => {
+            let id = file.generate(id, algorithm.into(), valid_from, valid_until);
+            format!("generated key {id} in {}", path.display())
+        }
+        KeysAction::Rotate {
+            id,
+            activate_in,
+            overlap,
+        } => {
+            let activate_at = unix_now() + activate_in;
+            file.rotate(id, activate_at, overlap)?;
+            format!(
+                "rotated key {id} in {}, the new key is used from unix time {activate_at}",
+                path.display()
+            )
+        }
+    };
+
+    // check that the daemon will accept the result before replacing the file
+    file.key_set()?;
+    file.write(&path)?;
+    Ok(message)
+}
+
+/// Change the keys file, returning the exit code
+pub fn run(command: KeysCommand, default_file: Option<PathBuf>) -> i32 {
+    match change(command, default_file) {
+        Ok(message) => {
+            println!("{message}");
+            0
+        }
+        Err(error) => {
+            eprintln!("Could not change the keys file: {error}");
+            1
+        }
+    }
+}
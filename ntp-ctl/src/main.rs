@@ -1,6 +1,13 @@
 #![forbid(unsafe_code)]
 
+mod bench;
+mod decode;
+mod doctor;
+mod keys;
+mod migrate;
 mod prometheus;
+mod selection;
+mod sourcestats;
 
 use std::path::PathBuf;
 
@@ -33,18 +40,56 @@ enum Command {
     Peers,
     #[command(about = "Information about the state of the daemon itself")]
     System,
+    #[command(
+        about = "Features of the host the daemon can use, such as hardware timestamping, as found at startup"
+    )]
+    Features,
     #[command(
         about = "Information about the state of the daemon and peers in the prometheus export format"
     )]
     Prometheus,
+    #[command(about = "Clients recently seen by the servers of the daemon")]
+    Clients,
+    #[command(about = "Trends in the recent measurements of the peers and updates of the clock")]
+    Sourcestats,
+    #[command(
+        about = "Correctness intervals of the peers in the latest round of clock selection, and where they intersect"
+    )]
+    Selection,
     #[command(about = "Adjust configuration (e.g. loglevel) of the daemon")]
     Config(ConfigUpdate),
+    #[command(about = "Look for common problems with the daemon and its environment")]
+    Doctor,
+    #[command(about = "Generate and rotate the keys in the keys file")]
+    Keys(keys::KeysCommand),
+    #[command(about = "Translate a chrony.conf or ntp.conf into a configuration for this daemon")]
+    MigrateConfig(migrate::MigrateCommand),
+    #[command(about = "Show the fields of an NTP packet given in hexadecimal or in a pcap file")]
+    Decode(decode::DecodeCommand),
+    #[command(
+        about = "Send a burst of requests to a server and show the quality of its responses"
+    )]
+    Bench(bench::BenchCommand),
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
+    // the translated configuration is written to stdout, before any warnings
+    // about our own configuration
+    if let Command::MigrateConfig(command) = &cli.command {
+        std::process::exit(migrate::run(command));
+    }
+
+    if let Command::Decode(command) = &cli.command {
+        std::process::exit(decode::run(command));
+    }
+
+    if let Command::Bench(command) = &cli.command {
+        std::process::exit(bench::run(command));
+    }
+
     let config = Config::from_args(cli.config, vec![], vec![]).await;
 
     if let Err(ref e) = config {
@@ -70,8 +115,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     let socket_path = match cli.command {
-        Command::Peers | Command::System | Command::Prometheus => &observation,
+        Command::Peers
+        | Command::System
+        | Command::Features
+        | Command::Clients
+        | Command::Sourcestats
+        | Command::Selection
+        | Command::Prometheus => &observation,
         Command::Config(_) => &configuration,
+        Command::Doctor => {
+            let exit_code = doctor::run(&observation, &configuration).await;
+            std::process::exit(exit_code);
+        }
+        Command::Keys(command) => {
+            let exit_code = keys::run(command, config.keys.path);
+            std::process::exit(exit_code);
+        }
+        Command::MigrateConfig(_) => unreachable!("the configuration is translated before"),
+        Command::Decode(_) => unreachable!("packets are decoded before"),
+        Command::Bench(_) => unreachable!("the server is probed before"),
     };
 
     let mut stream = match tokio::net::UnixStream::connect(socket_path).await {
@@ -115,6 +177,82 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
         }
+        Command::Features => {
+            let mut msg = Vec::with_capacity(16 * 1024);
+            match ntp_daemon::sockets::read_json::<ObservableState>(&mut stream, &mut msg).await {
+                Ok(output) => {
+                    // Unwrap here is fine as our serializer is infallible.
+                    println!(
+                        "{}",
+                        serde_json::to_string_pretty(&output.features).unwrap()
+                    );
+
+                    0
+                }
+                Err(e) => {
+                    eprintln!("Failed to read state from observation socket: {}", e);
+
+                    1
+                }
+            }
+        }
+        Command::Clients => {
+            let mut msg = Vec::with_capacity(16 * 1024);
+            match ntp_daemon::sockets::read_json::<ObservableState>(&mut stream, &mut msg).await {
+                Ok(output) => {
+                    let clients: Vec<_> = output
+                        .servers
+                        .iter()
+                        .map(|server| {
+                            serde_json::json!({
+                                "address": server.address,
+                                "clients": server.clients,
+                            })
+                        })
+                        .collect();
+
+                    // Unwrap here is fine as our serializer is infallible.
+                    println!("{}", serde_json::to_string_pretty(&clients).unwrap());
+
+                    0
+                }
+                Err(e) => {
+                    eprintln!("Failed to read state from observation socket: {}", e);
+
+                    1
+                }
+            }
+        }
+        Command::Sourcestats => {
+            let mut msg = Vec::with_capacity(16 * 1024);
+            match ntp_daemon::sockets::read_json::<ObservableState>(&mut stream, &mut msg).await {
+                Ok(output) => {
+                    print!("{}", sourcestats::format(&output.history));
+
+                    0
+                }
+                Err(e) => {
+                    eprintln!("Failed to read state from observation socket: {}", e);
+
+                    1
+                }
+            }
+        }
+        Command::Selection => {
+            let mut msg = Vec::with_capacity(16 * 1024);
+            match ntp_daemon::sockets::read_json::<ObservableState>(&mut stream, &mut msg).await {
+                Ok(output) => {
+                    print!("{}", selection::format(&output));
+
+                    0
+                }
+                Err(e) => {
+                    eprintln!("Failed to read state from observation socket: {}", e);
+
+                    1
+                }
+            }
+        }
         Command::Prometheus => {
             let mut stream = tokio::net::UnixStream::connect(observation).await?;
 
@@ -152,6 +290,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
         }
+        Command::Doctor => unreachable!("the doctor does not use a single socket"),
+        Command::Keys(_) => unreachable!("the keys file is changed without the daemon"),
+        Command::MigrateConfig(_) => unreachable!("the configuration is translated before"),
+        Command::Decode(_) => unreachable!("packets are decoded before"),
+        Command::Bench(_) => unreachable!("the server is probed before"),
     };
 
     std::process::exit(exit_code);
@@ -0,0 +1,577 @@
+use std::{
+    fmt::Write,
+    net::{IpAddr, Ipv6Addr},
+    path::PathBuf,
+};
+
+use clap::Args;
+
+#[derive(Args)]
+pub struct MigrateCommand {
+    /// The chrony.conf or ntp.conf file to translate
+    file: PathBuf,
+}
+
+/// Range of poll intervals of the daemon, as exponents of two seconds
+const MIN_POLL: i64 = 4;
+const MAX_POLL: i64 = 17;
+
+/// Number of servers used of a pool, as chrony does by default
+const DEFAULT_POOL_PEERS: usize = 4;
+
+/// Where the translated configuration keeps its state, as the drift file of
+/// chrony or ntpd has another format
+const STATE_PATH: &str = "/var/lib/ntpd-rs/state.json";
+
+/// Options of `server`, `pool` and `peer` that are followed by a value, and
+/// that are not translated
+const UNSUPPORTED_VALUE_OPTIONS: &[&str] = &[
+    "maxdelay",
+    "maxdelayratio",
+    "maxdelaydevratio",
+    "mindelay",
+    "asymmetry",
+    "offset",
+    "minstratum",
+    "polltarget",
+    "version",
+    "presend",
+    "minsamples",
+    "maxsamples",
+    "filter",
+    "certset",
+    "ntsport",
+    "extfield",
+    "mode",
+    "ttl",
+    "bias",
+];
+
+/// Directives that are not translated, and why
+const UNSUPPORTED_DIRECTIVES: &[(&str, &str)] = &[
+    ("refclock", "reference clocks are configured in `[[refclocks]]` sections, with their own options"),
+    ("fudge", "reference clocks are configured in `[[refclocks]]` sections, with their own options"),
+    ("restrict", "access to a server is configured with the `allowlist`, `denylist` and `acl` of a `[[server]]` section. Unlike ntpd, clients are only answered on the addresses of `[[server]]` sections"),
+    ("keyfile", "keys are kept in a keys file of another format, which is created with `ntp-ctl keys generate`"),
+    ("keys", "keys are kept in a keys file of another format, which is created with `ntp-ctl keys generate`"),
+    ("trustedkey", "every key in the keys file is trusted"),
+    ("rtcsync", "the daemon updates the RTC itself when the `rtc` section has a `device`"),
+    ("rtcfile", "the drift of the RTC is kept in the `drift-file` of the `rtc` section"),
+    ("logdir", "statistics files are configured in the `statistics` section"),
+    ("log", "statistics files are configured in the `statistics` section"),
+    ("statsdir", "statistics files are configured in the `statistics` section"),
+    ("statistics", "statistics files are configured in the `statistics` section"),
+    ("filegen", "statistics files are configured in the `statistics` section"),
+    ("leapsectz", "leap seconds are taken from the leap indicator of the sources"),
+    ("leapfile", "leap seconds are taken from the leap indicator of the sources"),
+    ("local", "serving time from the undisciplined local clock is not supported"),
+    ("cmdport", "the daemon is managed through the `observe` and `configure` sockets"),
+    ("bindcmdaddress", "the daemon is managed through the `observe` and `configure` sockets"),
+    ("cmdallow", "the daemon is managed through the `observe` and `configure` sockets"),
+    ("initstepslew", "the clock is stepped at startup when needed, within the `startup-panic-threshold`"),
+    ("include", "included files are not followed, translate them separately"),
+    ("includefile", "included files are not followed, translate them separately"),
+    ("confdir", "included files are not followed, translate them separately"),
+    ("sourcedir", "included files are not followed, translate them separately"),
+    ("ntsserverkey", "NTS is not supported yet"),
+    ("ntsservercert", "NTS is not supported yet"),
+    ("ntsdumpdir", "NTS is not supported yet"),
+];
+
+/// A directive that was not, or not completely, translated
+#[derive(Debug, PartialEq, Eq)]
+struct Remark {
+    line: usize,
+    message: String,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Source {
+    addr: String,
+    pool: bool,
+    symmetric: bool,
+    max_peers: Option<usize>,
+    min_poll: Option<i64>,
+    max_poll: Option<i64>,
+    key: Option<u32>,
+}
+
+#[derive(Debug, Default)]
+struct Migration {
+    sources: Vec<Source>,
+    initial_burst: bool,
+    panic_threshold: Option<String>,
+    startup_steps_unlimited: bool,
+    state: bool,
+    serve: bool,
+    server_port: Option<u16>,
+    allow_all: bool,
+    allowlist: Vec<String>,
+    denylist: Vec<String>,
+    notes: Vec<Remark>,
+    unsupported: Vec<Remark>,
+}
+
+impl Migration {
+    fn note(&mut self, line: usize, message: impl Into<String>) {
+        self.notes.push(Remark {
+            line,
+            message: message.into(),
+        });
+    }
+
+    fn unsupported(&mut self, line: usize, message: impl Into<String>) {
+        self.unsupported.push(Remark {
+            line,
+            message: message.into(),
+        });
+    }
+
+    fn source(&mut self, line: usize, directive: &str, args: &[&str]) {
+        let host = match args.first() {
+            Some(host) => *host,
+            None => return self.unsupported(line, format!("`{directive}` without an address")),
+        };
+        if host.starts_with("127.127.") {
+            return self.unsupported(
+                line,
+                "ntpd reference clock drivers are not supported, configure `[[refclocks]]` instead",
+            );
+        }
+
+        let mut source = Source {
+            pool: directive == "pool",
+            symmetric: directive == "peer",
+            ..Default::default()
+        };
+        let mut port = 123;
+
+        let mut options = args[1..].iter();
+        while let Some(&option) = options.next() {
+            match option {
+                "minpoll" | "maxpoll" | "port" | "key" | "maxsources" => {
+                    let value = match options.next().and_then(|value| value.parse::<i64>().ok()) {
+                        Some(value) => value,
+                        None => {
+                            self.unsupported(line, format!("`{option}` of {host} needs a number"));
+                            continue;
+                        }
+                    };
+                    match option {
+                        "minpoll" => source.min_poll = Some(self.poll(line, option, value)),
+                        "maxpoll" => source.max_poll = Some(self.poll(line, option, value)),
+                        "port" => match u16::try_from(value) {
+                            Ok(value) => port = value,
+                            Err(_) => self.unsupported(line, format!("invalid port of {host}")),
+                        },
+                        "key" if source.pool => self.note(
+                            line,
+                            format!("the key of pool {host} is dropped, as servers of a pool are not authenticated"),
+                        ),
+                        "key" => match u32::try_from(value) {
+                            Ok(id) if id > 0 => {
+                                source.key = Some(id);
+                                self.note(
+                                    line,
+                                    format!("key {id} of {host} must be added to the keys file, see `ntp-ctl keys`"),
+                                );
+                            }
+                            _ => self.unsupported(line, format!("invalid key of {host}")),
+                        },
+                        _ => match usize::try_from(value) {
+                            Ok(count) if count > 0 && source.pool => source.max_peers = Some(count),
+                            _ => self.unsupported(line, format!("invalid `maxsources` of {host}")),
+                        },
+                    }
+                }
+                "iburst" => self.initial_burst = true,
+                option if UNSUPPORTED_VALUE_OPTIONS.contains(&option) => {
+                    options.next();
+                    self.unsupported(
+                        line,
+                        format!("option `{option}` of {host} is not supported"),
+                    );
+                }
+                option => self.unsupported(
+                    line,
+                    format!("option `{option}` of {host} is not supported"),
+                ),
+            }
+        }
+
+        source.addr = match host.parse::<Ipv6Addr>() {
+            Ok(_) => format!("[{host}]:{port}"),
+            Err(_) => format!("{host}:{port}"),
+        };
+        if source.pool {
+            source.max_peers = Some(source.max_peers.unwrap_or(DEFAULT_POOL_PEERS));
+        }
+        self.sources.push(source);
+    }
+
+    /// A poll interval, limited to the range of the daemon
+    fn poll(&mut self, line: usize, option: &str, value: i64) -> i64 {
+        let limited = value.clamp(MIN_POLL, MAX_POLL);
+        if limited != value {
+            self.note(
+                line,
+                format!("`{option} {value}` is changed to {limited}, as poll intervals are between {MIN_POLL} and {MAX_POLL}"),
+            );
+        }
+        limited
+    }
+
+    fn makestep(&mut self, line: usize, args: &[&str]) {
+        let threshold = args.first().and_then(|value| value.parse::<f64>().ok());
+        let limit = args.get(1).and_then(|value| value.parse::<i64>().ok());
+        let (threshold, limit) = match (threshold, limit) {
+            (Some(threshold), Some(limit)) => (threshold, limit),
+            _ => return self.unsupported(line, "`makestep` needs a threshold and a limit"),
+        };
+
+        if limit < 0 {
+            // the clock may be stepped at any time
+            self.panic_threshold = Some("\"inf\"".into());
+        } else {
+            // the clock is only stepped at startup
+            self.panic_threshold = Some("0".into());
+            self.startup_steps_unlimited = true;
+        }
+        self.note(
+            line,
+            format!("offsets above 125 ms are stepped, the threshold of {threshold} s is not used"),
+        );
+    }
+
+    fn tinker(&mut self, line: usize, args: &[&str]) {
+        match args {
+            ["panic", value] => match value.parse::<f64>() {
+                Ok(0.0) => self.panic_threshold = Some("\"inf\"".into()),
+                Ok(value) if value > 0.0 => self.panic_threshold = Some(value.to_string()),
+                _ => self.unsupported(line, "`tinker panic` needs a threshold"),
+            },
+            _ => self.unsupported(line, "only `tinker panic` is supported"),
+        }
+    }
+
+    fn access(&mut self, line: usize, directive: &str, args: &[&str]) {
+        if directive == "allow" {
+            self.serve = true;
+        }
+
+        let subnets: Vec<&str> = args.iter().copied().filter(|arg| *arg != "all").collect();
+        if subnets.is_empty() {
+            match directive {
+                "allow" => self.allow_all = true,
+                _ => self.unsupported(
+                    line,
+                    "denying all clients is done by not configuring a server",
+                ),
+            }
+            return;
+        }
+
+        for arg in subnets {
+            match subnet(arg) {
+                Some(subnet) if directive == "allow" => self.allowlist.push(subnet),
+                Some(subnet) => self.denylist.push(subnet),
+                None => self.unsupported(
+                    line,
+                    format!("`{arg}` is not a subnet, names are not supported"),
+                ),
+            }
+        }
+    }
+
+    fn directive(&mut self, line: usize, directive: &str, args: &[&str]) {
+        match directive {
+            "server" | "pool" | "peer" => self.source(line, directive, args),
+            "makestep" => self.makestep(line, args),
+            "tinker" => self.tinker(line, args),
+            "driftfile" => {
+                self.state = true;
+                self.note(
+                    line,
+                    format!("the frequency correction is kept in the state file {STATE_PATH}, the existing drift file is not read"),
+                );
+            }
+            "allow" | "deny" => self.access(line, directive, args),
+            "port" => match args.first().and_then(|port| port.parse::<u16>().ok()) {
+                Some(0) => self.note(
+                    line,
+                    "`port 0` disables the server, which is translated as no server",
+                ),
+                Some(port) => self.server_port = Some(port),
+                None => self.unsupported(line, "`port` needs a port number"),
+            },
+            _ => {
+                let explanation = UNSUPPORTED_DIRECTIVES
+                    .iter()
+                    .find(|(name, _)| *name == directive)
+                    .map(|(_, explanation)| *explanation)
+                    .unwrap_or("not supported by ntpd-rs");
+                self.unsupported(line, format!("`{directive}`: {explanation}"));
+            }
+        }
+    }
+
+    fn to_toml(&self, origin: &str) -> String {
+        let mut out = format!("# Translated from {origin} by ntp-ctl migrate-config\n");
+
+        for source in &self.sources {
+            out.push_str("\n[[peers]]\n");
+            let _ = writeln!(out, "addr = {:?}", source.addr);
+            if source.pool {
+                out.push_str("mode = \"Pool\"\n");
+            }
+            if let Some(max_peers) = source.max_peers {
+                let _ = writeln!(out, "max_peers = {max_peers}");
+            }
+            if source.symmetric {
+                out.push_str("association = \"symmetric-active\"\n");
+            }
+            if let Some(min_poll) = source.min_poll {
+                let _ = writeln!(out, "min-poll = {min_poll}");
+            }
+            if let Some(max_poll) = source.max_poll {
+                let _ = writeln!(out, "max-poll = {max_poll}");
+            }
+            if let Some(key) = source.key {
+                let _ = writeln!(out, "key = {key}");
+            }
+        }
+
+        if self.serve && self.server_port != Some(0) {
+            out.push_str("\n[[server]]\n");
+            let _ = writeln!(out, "addr = \"[::]:{}\"", self.server_port.unwrap_or(123));
+            if !self.allow_all {
+                let _ = writeln!(out, "allowlist = {:?}", self.allowlist);
+                out.push_str("allowlist-action = \"Ignore\"\n");
+            }
+            if !self.denylist.is_empty() {
+                let _ = writeln!(out, "denylist = {:?}", self.denylist);
+                out.push_str("denylist-action = \"Ignore\"\n");
+            }
+        }
+
+        if self.initial_burst || self.panic_threshold.is_some() {
+            out.push_str("\n[system]\n");
+            if self.initial_burst {
+                out.push_str("initial-burst = 4\n");
+            }
+            if let Some(panic_threshold) = &self.panic_threshold {
+                let _ = writeln!(out, "panic-threshold = {panic_threshold}");
+            }
+            if self.startup_steps_unlimited {
+                out.push_str(
+                    "startup-panic-threshold = { forward = \"inf\", backward = \"inf\" }\n",
+                );
+            }
+        }
+
+        if self.state {
+            let _ = writeln!(out, "\n[state]\npath = {STATE_PATH:?}");
+        }
+
+        out
+    }
+}
+
+/// A subnet in the notation of the configuration, from an address, a subnet,
+/// or the leading octets of an IPv4 subnet as chrony allows them
+fn subnet(value: &str) -> Option<String> {
+    let (addr, prefix) = match value.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().ok()?)),
+        None => (value, None),
+    };
+
+    let (addr, implied_prefix) = match addr.parse::<IpAddr>() {
+        Ok(addr @ IpAddr::V4(_)) => (addr, 32),
+        Ok(addr @ IpAddr::V6(_)) => (addr, 128),
+        Err(_) => {
+            let octets: Vec<u8> = addr
+                .split('.')
+                .map(|octet| octet.parse().ok())
+                .collect::<Option<_>>()?;
+            if octets.is_empty() || octets.len() > 3 {
+                return None;
+            }
+            let mut padded = [0; 4];
+            padded[..octets.len()].copy_from_slice(&octets);
+            (IpAddr::from(padded), 8 * octets.len() as u8)
+        }
+    };
+
+    let prefix = prefix.unwrap_or(implied_prefix);
+    if prefix > if addr.is_ipv4() { 32 } else { 128 } {
+        return None;
+    }
+    Some(format!("{addr}/{prefix}"))
+}
+
+fn migrate(contents: &str) -> Migration {
+    let mut migration = Migration::default();
+    for (index, line) in contents.lines().enumerate() {
+        // chrony also starts comments with these characters
+        if line.trim_start().starts_with(['!', ';', '%']) {
+            continue;
+        }
+        let line_content = line.split('#').next().unwrap_or_default();
+        let words: Vec<&str> = line_content.split_whitespace().collect();
+        if let Some((directive, args)) = words.split_first() {
+            migration.directive(index + 1, &directive.to_ascii_lowercase(), args);
+        }
+    }
+    migration
+}
+
+/// Translate the configuration file, returning the exit code
+pub fn run(command: &MigrateCommand) -> i32 {
+    let contents = match std::fs::read_to_string(&command.file) {
+        Ok(contents) => contents,
+        Err(error) => {
+            eprintln!("Could not read {}: {error}", command.file.display());
+            return 1;
+        }
+    };
+
+    let migration = migrate(&contents);
+    print!("{}", migration.to_toml(&command.file.display().to_string()));
+
+    // the configuration goes to stdout, so that it can be redirected to a file
+    if !migration.notes.is_empty() {
+        eprintln!("Check these translations:");
+        for Remark { line, message } in &migration.notes {
+            eprintln!("  line {line}: {message}");
+        }
+    }
+    if !migration.unsupported.is_empty() {
+        eprintln!("Not translated:");
+        for Remark { line, message } in &migration.unsupported {
+            eprintln!("  line {line}: {message}");
+        }
+    }
+
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_daemon::{config::PeerConfig, Config};
+    use ntp_proto::{AssociationMode, NtpDuration};
+
+    use super::*;
+
+    #[test]
+    fn test_subnet() {
+        assert_eq!(subnet("192.0.2.0/24").as_deref(), Some("192.0.2.0/24"));
+        assert_eq!(subnet("192.0.2.1").as_deref(), Some("192.0.2.1/32"));
+        assert_eq!(subnet("192.168").as_deref(), Some("192.168.0.0/16"));
+        assert_eq!(subnet("10").as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(subnet("2001:db8::/32").as_deref(), Some("2001:db8::/32"));
+        assert_eq!(subnet("192.0.2.0/33"), None);
+        assert_eq!(subnet("ntp.example.com"), None);
+        assert_eq!(subnet("1.2.3.4.5"), None);
+    }
+
+    #[test]
+    fn test_chrony() {
+        let migration = migrate(
+            "! chrony.conf
+            pool 2.pool.ntp.org iburst maxsources 3
+            server ntp.example.com minpoll 2 maxpoll 8 key 5 prefer
+            server 2001:db8::1 port 1123 maxdelay 0.1
+            peer 192.0.2.7
+            makestep 1.0 3
+            driftfile /var/lib/chrony/drift
+            allow 192.168
+            allow 2001:db8::/32
+            deny 192.168.7.0/24
+            rtcsync
+            hwtimestamp eth0 # not for us
+            ",
+        );
+
+        let toml = migration.to_toml("chrony.conf");
+        let config: Config = toml::from_str(&toml).unwrap();
+
+        assert_eq!(config.peers.len(), 4);
+        match &config.peers[0] {
+            PeerConfig::Pool(pool) => {
+                assert_eq!(pool.addr.as_str(), "2.pool.ntp.org:123");
+                assert_eq!(pool.max_peers, 3);
+            }
+            _ => panic!("expected a pool"),
+        }
+        match &config.peers[1] {
+            PeerConfig::Standard(peer) => {
+                assert_eq!(peer.addr.as_str(), "ntp.example.com:123");
+                assert_eq!(peer.poll.min_poll.map(|poll| poll.as_log()), Some(4));
+                assert_eq!(peer.poll.max_poll.map(|poll| poll.as_log()), Some(8));
+                assert_eq!(peer.key, Some(5));
+            }
+            _ => panic!("expected a server"),
+        }
+        match &config.peers[2] {
+            PeerConfig::Standard(peer) => assert_eq!(peer.addr.as_str(), "[2001:db8::1]:1123"),
+            _ => panic!("expected a server"),
+        }
+        assert_eq!(
+            config.peers[3].association(),
+            AssociationMode::SymmetricActive
+        );
+
+        assert_eq!(config.system.initial_burst, 4);
+        assert_eq!(
+            config.system.panic_threshold.forward,
+            Some(NtpDuration::ZERO)
+        );
+        assert_eq!(config.system.startup_panic_threshold.backward, None);
+        assert_eq!(config.state.path, Some(PathBuf::from(STATE_PATH)));
+
+        assert_eq!(config.servers.len(), 1);
+        assert_eq!(config.servers[0].addr.port(), 123);
+        assert!(toml.contains("allowlist = [\"192.168.0.0/16\", \"2001:db8::/32\"]"));
+        assert!(toml.contains("denylist = [\"192.168.7.0/24\"]"));
+
+        let unsupported: Vec<usize> = migration
+            .unsupported
+            .iter()
+            .map(|remark| remark.line)
+            .collect();
+        assert_eq!(unsupported, [3, 4, 11, 12]);
+        assert!(migration.unsupported[2].message.contains("`rtc` section"));
+        assert!(migration
+            .notes
+            .iter()
+            .any(|note| note.message.contains("`minpoll 2`")));
+    }
+
+    #[test]
+    fn test_ntpd() {
+        let migration = migrate(
+            "# ntp.conf
+            driftfile /var/lib/ntp/ntp.drift
+            server 0.debian.pool.ntp.org iburst
+            pool pool.ntp.org
+            server 127.127.1.0
+            fudge 127.127.1.0 stratum 10
+            restrict default kod nomodify notrap nopeer noquery
+            tinker panic 0
+            ",
+        );
+
+        let config: Config = toml::from_str(&migration.to_toml("ntp.conf")).unwrap();
+        assert_eq!(config.peers.len(), 2);
+        assert!(config.servers.is_empty());
+        assert_eq!(config.system.panic_threshold.forward, None);
+        assert_eq!(config.system.initial_burst, 4);
+
+        let unsupported: Vec<usize> = migration
+            .unsupported
+            .iter()
+            .map(|remark| remark.line)
+            .collect();
+        assert_eq!(unsupported, [5, 6, 7]);
+    }
+}
@@ -1,4 +1,7 @@
-use ntp_daemon::{observer::WrappedSocketAddr, ObservablePeerState, ObservableState};
+use ntp_daemon::{
+    observer::{ClockMode, LockState, RejectionReason, SelectionStatus, WrappedSocketAddr},
+    ObservablePeerState, ObservableState,
+};
 use prometheus_client::{
     encoding::text::{Encode, SendSyncEncodeMetric},
     metrics::{
@@ -14,11 +17,30 @@ struct PeerLabels {
     address: String,
 }
 
+#[derive(Clone, PartialEq, Eq, Hash, Encode)]
+struct PeerRejectionLabels {
+    address: String,
+    reason: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Encode)]
+struct RefClockDiscardLabels {
+    address: String,
+    reason: String,
+}
+
 #[derive(Clone, PartialEq, Eq, Hash, Encode)]
 struct ServerLabels {
     listen_address: WrappedSocketAddr,
 }
 
+#[derive(Clone, PartialEq, Eq, Hash, Encode)]
+struct AclRuleLabels {
+    listen_address: WrappedSocketAddr,
+    subnet: String,
+    action: String,
+}
+
 #[derive(Default)]
 pub(crate) struct Metrics {
     system_poll_interval: Gauge<f64>,
@@ -27,6 +49,9 @@ pub(crate) struct Metrics {
     system_accumulated_steps: Gauge<f64>,
     system_accumulated_steps_threshold: Gauge<f64>,
     system_leap_indicator: Gauge,
+    system_insufficient_consensus: Gauge,
+    system_monitor_only: Gauge,
+    system_measure_only: Gauge,
     peer_uptime: Family<PeerLabels, Gauge>,
     peer_poll_interval: Family<PeerLabels, Gauge<f64>>,
     peer_poll_interval_exp: Family<PeerLabels, Gauge<f64>>,
@@ -35,11 +60,28 @@ pub(crate) struct Metrics {
     peer_delay: Family<PeerLabels, Gauge<f64>>,
     peer_dispersion: Family<PeerLabels, Gauge<f64>>,
     peer_jitter: Family<PeerLabels, Gauge<f64>>,
+    peer_quality: Family<PeerLabels, Gauge>,
+    peer_rejections: Family<PeerRejectionLabels, Counter>,
+    peer_nonconforming_packets: Family<PeerLabels, Counter>,
+    refclock_locked: Family<PeerLabels, Gauge>,
+    refclock_sample_rate: Family<PeerLabels, Gauge<f64>>,
+    refclock_spread: Family<PeerLabels, Gauge<f64>>,
+    refclock_samples: Family<PeerLabels, Counter>,
+    refclock_discarded_samples: Family<RefClockDiscardLabels, Counter>,
     server_received_packets: Family<ServerLabels, Counter>,
     server_accepted_packets: Family<ServerLabels, Counter>,
     server_denied_packets: Family<ServerLabels, Counter>,
     server_rate_limited_packets: Family<ServerLabels, Counter>,
     server_response_send_errors: Family<ServerLabels, Counter>,
+    server_malformed_packets: Family<ServerLabels, Counter>,
+    server_unsupported_version_packets: Family<ServerLabels, Counter>,
+    server_nonconforming_packets: Family<ServerLabels, Counter>,
+    server_ignored_packets: Family<ServerLabels, Counter>,
+    server_kiss_codes_sent: Family<ServerLabels, Counter>,
+    server_drained_packets: Family<ServerLabels, Counter>,
+    server_receive_batches: Family<ServerLabels, Counter>,
+    server_full_receive_batches: Family<ServerLabels, Counter>,
+    server_acl_matches: Family<AclRuleLabels, Counter>,
 }
 
 impl Metrics {
@@ -60,14 +102,28 @@ impl Metrics {
         );
         self.system_leap_indicator
             .set(data.system.leap_indicator as u64);
+        self.system_insufficient_consensus.set(matches!(
+            data.selection,
+            SelectionStatus::InsufficientConsensus { .. }
+        ) as u64);
+        self.system_monitor_only
+            .set(data.clock_mode.is_monitor_only() as u64);
+        self.system_measure_only
+            .set((data.clock_mode == ClockMode::Measuring) as u64);
 
         for peer in &data.peers {
+            // the standby address of a dual-stack peer would have the same labels
             if let ObservablePeerState::Observable {
                 statistics,
                 reachability,
                 uptime,
                 poll_interval,
                 address,
+                quality,
+                rejections,
+                standby: false,
+                refclock,
+                nonconforming,
                 ..
             } = peer
             {
@@ -98,6 +154,54 @@ impl Metrics {
                 self.peer_jitter
                     .get_or_create(&labels)
                     .set(statistics.jitter);
+                self.peer_quality
+                    .get_or_create(&labels)
+                    .set(*quality as u64);
+
+                for reason in RejectionReason::ALL {
+                    let labels = PeerRejectionLabels {
+                        address: address.clone(),
+                        reason: reason.to_string(),
+                    };
+                    self.peer_rejections
+                        .get_or_create(&labels)
+                        .inner()
+                        .set(rejections.count(reason));
+                }
+                self.peer_nonconforming_packets
+                    .get_or_create(&labels)
+                    .inner()
+                    .set(*nonconforming);
+
+                if let Some(refclock) = refclock {
+                    self.refclock_locked
+                        .get_or_create(&labels)
+                        .set((refclock.lock == LockState::Locked) as u64);
+                    self.refclock_sample_rate
+                        .get_or_create(&labels)
+                        .set(refclock.sample_rate);
+                    self.refclock_spread
+                        .get_or_create(&labels)
+                        .set(refclock.spread);
+                    self.refclock_samples
+                        .get_or_create(&labels)
+                        .inner()
+                        .set(refclock.samples);
+                    let discarded = [
+                        ("outlier", refclock.outliers),
+                        ("unsynchronized", refclock.unsynchronized),
+                    ];
+                    for (reason, count) in discarded {
+                        let labels = RefClockDiscardLabels {
+                            address: address.clone(),
+                            reason: reason.to_string(),
+                        };
+                        self.refclock_discarded_samples
+                            .get_or_create(&labels)
+                            .inner()
+                            .set(count);
+                    }
+                }
             }
         }
 
@@ -126,6 +230,50 @@ impl Metrics {
                 .get_or_create(&labels)
                 .inner()
                 .set(server.stats.response_send_errors.get());
+            self.server_malformed_packets
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.malformed_packets.get());
+            self.server_unsupported_version_packets
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.unsupported_version_packets.get());
+            self.server_nonconforming_packets
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.nonconforming_packets.get());
+            self.server_ignored_packets
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.ignored_packets.get());
+            self.server_kiss_codes_sent
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.kiss_codes_sent.get());
+            self.server_drained_packets
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.drained_packets.get());
+            self.server_receive_batches
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.receive_batches.get());
+            self.server_full_receive_batches
+                .get_or_create(&labels)
+                .inner()
+                .set(server.stats.full_receive_batches.get());
+
+            for rule in &server.stats.acl_matches {
+                let labels = AclRuleLabels {
+                    listen_address: server.address,
+                    subnet: rule.subnet.clone(),
+                    action: rule.action.to_string(),
+                };
+                self.server_acl_matches
+                    .get_or_create(&labels)
+                    .inner()
+                    .set(rule.matches.get());
+            }
         }
     }
 }
@@ -169,6 +317,21 @@ pub(crate) fn create_registry(metrics: &Metrics) -> Registry<Box<dyn SendSyncEnc
         "Indicates that a leap second will take place",
         Box::new(metrics.system_leap_indicator.clone()),
     );
+    system.register(
+        "insufficient_consensus",
+        "Indicates that too few sources agree on the time for the clock to be adjusted",
+        Box::new(metrics.system_insufficient_consensus.clone()),
+    );
+    system.register(
+        "monitor_only",
+        "Indicates that the clock is measured, but never adjusted",
+        Box::new(metrics.system_monitor_only.clone()),
+    );
+    system.register(
+        "measure_only",
+        "Indicates that the sources are only measured, without clock selection or adjusting the clock",
+        Box::new(metrics.system_measure_only.clone()),
+    );
 
     let peer = registry.sub_registry_with_prefix("peer");
 
@@ -226,6 +389,53 @@ pub(crate) fn create_registry(metrics: &Metrics) -> Registry<Box<dyn SendSyncEnc
         Box::new(metrics.peer_jitter.clone()),
     );
 
+    peer.register(
+        "quality",
+        "Summary of the health of the peer, from 0 (useless) to 100 (excellent)",
+        Box::new(metrics.peer_quality.clone()),
+    );
+
+    peer.register(
+        "rejections",
+        "Number of packets and clock selection rounds in which the peer was rejected, per reason",
+        Box::new(metrics.peer_rejections.clone()),
+    );
+
+    peer.register(
+        "nonconforming_packets",
+        "Number of packets from the peer that deviate from RFC 5905",
+        Box::new(metrics.peer_nonconforming_packets.clone()),
+    );
+
+    let refclock = registry.sub_registry_with_prefix("refclock");
+
+    refclock.register(
+        "locked",
+        "Whether the samples of the reference clock were used in the last poll interval",
+        Box::new(metrics.refclock_locked.clone()),
+    );
+    refclock.register(
+        "sample_rate",
+        "Samples per second the driver produced during the last poll interval",
+        Box::new(metrics.refclock_sample_rate.clone()),
+    );
+    refclock.register_with_unit(
+        "spread",
+        "Standard deviation of the samples used in the last poll interval",
+        Unit::Seconds,
+        Box::new(metrics.refclock_spread.clone()),
+    );
+    refclock.register(
+        "samples",
+        "Number of samples produced by the driver",
+        Box::new(metrics.refclock_samples.clone()),
+    );
+    refclock.register(
+        "discarded_samples",
+        "Number of samples of the driver that were not used, per reason",
+        Box::new(metrics.refclock_discarded_samples.clone()),
+    );
+
     let server = registry.sub_registry_with_prefix("server");
 
     server.register(
@@ -258,5 +468,59 @@ pub(crate) fn create_registry(metrics: &Metrics) -> Registry<Box<dyn SendSyncEnc
         Box::new(metrics.server_response_send_errors.clone()),
     );
 
+    server.register(
+        "malformed_packets",
+        "Number of packets that could not be parsed",
+        Box::new(metrics.server_malformed_packets.clone()),
+    );
+
+    server.register(
+        "unsupported_version_packets",
+        "Number of packets of an NTP version that is not supported or not allowed by the policy",
+        Box::new(metrics.server_unsupported_version_packets.clone()),
+    );
+
+    server.register(
+        "nonconforming_packets",
+        "Number of packets that deviate from RFC 5905",
+        Box::new(metrics.server_nonconforming_packets.clone()),
+    );
+
+    server.register(
+        "ignored_packets",
+        "Number of packets dropped without response by filters, access control or their mode",
+        Box::new(metrics.server_ignored_packets.clone()),
+    );
+
+    server.register(
+        "kiss_codes_sent",
+        "Number of kiss-o'-death responses sent to denied and rate limited clients",
+        Box::new(metrics.server_kiss_codes_sent.clone()),
+    );
+
+    server.register(
+        "drained_packets",
+        "Number of time requests answered with a kiss code or ignored while the server is drained",
+        Box::new(metrics.server_drained_packets.clone()),
+    );
+
+    server.register(
+        "receive_batches",
+        "Number of batches of packets received at once",
+        Box::new(metrics.server_receive_batches.clone()),
+    );
+
+    server.register(
+        "full_receive_batches",
+        "Number of batches of packets received at once that filled the whole batch",
+        Box::new(metrics.server_full_receive_batches.clone()),
+    );
+
+    server.register(
+        "acl_matches",
+        "Number of packets matching each access control rule",
+        Box::new(metrics.server_acl_matches.clone()),
+    );
+
     registry
 }
@@ -0,0 +1,156 @@
+//! The correctness intervals of the sources in the latest round of clock
+//! selection, which show why a source was tagged a falseticker.
+
+use std::fmt::Write;
+
+use ntp_daemon::{observer::ObservablePeerState, ObservableState};
+use ntp_proto::ReferenceId;
+
+/// Address of the source with this id, or the id itself when the source is
+/// gone
+fn source_name(state: &ObservableState, id: ReferenceId) -> String {
+    let address = state.peers.iter().find_map(|peer| match peer {
+        ObservablePeerState::Observable {
+            peer_id, address, ..
+        } if *peer_id == id => Some(address.clone()),
+        _ => None,
+    });
+
+    match address {
+        Some(address) => address,
+        None => {
+            let [a, b, c, d] = id.to_bytes();
+            format!("{a:02x}{b:02x}{c:02x}{d:02x}")
+        }
+    }
+}
+
+/// Render the intervals as a table of the sources, in order of offset,
+/// followed by the intersection
+pub fn format(state: &ObservableState) -> String {
+    let intervals = &state.intervals;
+    let mut output = String::new();
+
+    // writing to a string can not fail
+    let _ = writeln!(
+        output,
+        "{:<32} {:>12} {:>12} {:>12}  Status",
+        "Address", "Offset (ms)", "Low (ms)", "High (ms)"
+    );
+    for interval in &intervals.candidates {
+        let status = match interval.truechimer {
+            true => "truechimer",
+            false => "falseticker",
+        };
+        let _ = writeln!(
+            output,
+            "{:<32} {:>12.3} {:>12.3} {:>12.3}  {}",
+            source_name(state, interval.peer_id),
+            interval.offset.to_seconds() * 1e3,
+            interval.low.to_seconds() * 1e3,
+            interval.high.to_seconds() * 1e3,
+            status,
+        );
+    }
+
+    let _ = match intervals.intersection {
+        Some((low, high)) => writeln!(
+            output,
+            "\nIntersection: {:.3} ms to {:.3} ms",
+            low.to_seconds() * 1e3,
+            high.to_seconds() * 1e3,
+        ),
+        None if intervals.candidates.is_empty() => {
+            writeln!(output, "\nNo sources are acceptable for synchronization")
+        }
+        None => writeln!(
+            output,
+            "\nNo intersection: the intervals of no majority of the sources overlap"
+        ),
+    };
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::{
+        CorrectnessInterval, NtpDuration, PeerStatistics, PollInterval, SelectionIntervals,
+    };
+
+    use super::*;
+
+    fn peer(address: &str, peer_id: ReferenceId) -> ObservablePeerState {
+        ObservablePeerState::Observable {
+            statistics: PeerStatistics::default(),
+            reachability: Default::default(),
+            uptime: std::time::Duration::from_secs(100),
+            poll_interval: PollInterval::default(),
+            peer_id,
+            address: address.into(),
+            local_address: None,
+            quality: 90,
+            rejections: Default::default(),
+            unreachable: None,
+            standby: false,
+            failures: 0,
+            refclock: None,
+            smearing: None,
+            version: None,
+            nonconforming: 0,
+        }
+    }
+
+    fn interval(peer_id: ReferenceId, offset: f64, truechimer: bool) -> CorrectnessInterval {
+        CorrectnessInterval {
+            peer_id,
+            offset: NtpDuration::from_seconds(offset),
+            low: NtpDuration::from_seconds(offset - 0.010),
+            high: NtpDuration::from_seconds(offset + 0.010),
+            truechimer,
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        let good = ReferenceId::from_bytes([192, 0, 2, 1]);
+        let bad = ReferenceId::from_bytes([192, 0, 2, 2]);
+        let gone = ReferenceId::from_bytes([192, 0, 2, 3]);
+        let mut state = ObservableState {
+            system: Default::default(),
+            peers: vec![peer("192.0.2.1:123", good), peer("192.0.2.2:123", bad)],
+            servers: vec![],
+            selection: Default::default(),
+            intervals: SelectionIntervals {
+                candidates: vec![
+                    interval(good, 0.001, true),
+                    interval(gone, 0.002, true),
+                    interval(bad, 0.5, false),
+                ],
+                intersection: Some((
+                    NtpDuration::from_seconds(-0.008),
+                    NtpDuration::from_seconds(0.011),
+                )),
+            },
+            history: Default::default(),
+            clock_mode: Default::default(),
+            features: Default::default(),
+        };
+
+        let output = format(&state);
+        let lines: Vec<_> = output.lines().collect();
+        assert!(lines[1].starts_with("192.0.2.1:123"));
+        assert!(lines[1].ends_with("truechimer"));
+        assert!(lines[2].starts_with("c0000203"));
+        assert!(lines[3].starts_with("192.0.2.2:123"));
+        assert!(lines[3].contains("490.000"));
+        assert!(lines[3].ends_with("falseticker"));
+        assert_eq!(lines[5], "Intersection: -8.000 ms to 11.000 ms");
+
+        state.intervals.intersection = None;
+        assert!(format(&state).ends_with("no majority of the sources overlap\n"));
+
+        state.intervals.candidates.clear();
+        assert!(format(&state).ends_with("No sources are acceptable for synchronization\n"));
+    }
+}
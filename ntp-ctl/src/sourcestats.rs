@@ -0,0 +1,202 @@
+//! Trends in the recent measurements of the sources and updates of the clock,
+//! as kept in memory by the daemon.
+
+use std::fmt::Write;
+
+use ntp_daemon::observer::{ClockSample, ObservableHistory, PeerSample};
+
+/// Summary of a series of offsets
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Trend {
+    samples: usize,
+    /// Time between the first and the last sample, in seconds
+    span: f64,
+    /// Change of the offset over time, in parts per million, when the
+    /// samples span some time
+    frequency: Option<f64>,
+    /// Mean offset, in seconds
+    offset: f64,
+    /// Standard deviation of the offsets, in seconds
+    deviation: f64,
+}
+
+impl Trend {
+    /// The trend of `(time, offset)` pairs, which must be in order of time
+    fn new(points: &[(f64, f64)]) -> Option<Self> {
+        let (first, last) = (points.first()?, points.last()?);
+        let n = points.len() as f64;
+
+        let mean_time = points.iter().map(|(time, _)| time).sum::<f64>() / n;
+        let offset = points.iter().map(|(_, offset)| offset).sum::<f64>() / n;
+
+        let time_variance: f64 = points
+            .iter()
+            .map(|(time, _)| (time - mean_time).powi(2))
+            .sum();
+        let covariance: f64 = points
+            .iter()
+            .map(|(time, value)| (time - mean_time) * (value - offset))
+            .sum();
+        let frequency = (time_variance > 0.0).then(|| covariance / time_variance * 1e6);
+
+        let deviation = if points.len() < 2 {
+            0.0
+        } else {
+            let variance: f64 = points
+                .iter()
+                .map(|(_, value)| (value - offset).powi(2))
+                .sum();
+            (variance / (n - 1.0)).sqrt()
+        };
+
+        Some(Trend {
+            samples: points.len(),
+            span: last.0 - first.0,
+            frequency,
+            offset,
+            deviation,
+        })
+    }
+
+    fn of_peer(samples: &[PeerSample]) -> Option<Self> {
+        let points: Vec<_> = samples
+            .iter()
+            .map(|sample| (sample.time, sample.offset))
+            .collect();
+        Self::new(&points)
+    }
+}
+
+/// The frequency corrections of the clock, as a trend
+fn clock_frequency(samples: &[ClockSample]) -> Option<Trend> {
+    let points: Vec<_> = samples
+        .iter()
+        .map(|sample| (sample.time, sample.frequency))
+        .collect();
+    Trend::new(&points)
+}
+
+/// Render the history as a table of the sources, followed by a summary of
+/// the clock
+pub fn format(history: &ObservableHistory) -> String {
+    let mut output = String::new();
+
+    // writing to a string can not fail
+    let _ = writeln!(
+        output,
+        "{:<32} {:>4} {:>8} {:>12} {:>12} {:>12}",
+        "Address", "NP", "Span (s)", "Freq (ppm)", "Offset (ms)", "Std Dev (ms)"
+    );
+    for peer in &history.peers {
+        let trend = match Trend::of_peer(&peer.samples) {
+            Some(trend) => trend,
+            None => {
+                let _ = writeln!(output, "{:<32} {:>4}", peer.address, 0);
+                continue;
+            }
+        };
+        let frequency = match trend.frequency {
+            Some(frequency) => format!("{frequency:.3}"),
+            None => "-".into(),
+        };
+        let _ = writeln!(
+            output,
+            "{:<32} {:>4} {:>8.0} {:>12} {:>12.3} {:>12.3}",
+            peer.address,
+            trend.samples,
+            trend.span,
+            frequency,
+            trend.offset * 1e3,
+            trend.deviation * 1e3,
+        );
+    }
+
+    if let (Some(frequency), Some(last)) = (clock_frequency(&history.clock), history.clock.last()) {
+        let _ = writeln!(
+            output,
+            "\nClock: {} updates over {:.0} s, frequency {:.3} ppm (std dev {:.3} ppm), last offset {:.3} ms",
+            frequency.samples,
+            frequency.span,
+            last.frequency,
+            frequency.deviation,
+            last.offset * 1e3,
+        );
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_daemon::observer::PeerHistory;
+
+    use super::*;
+
+    fn peer_sample(time: f64, offset: f64) -> PeerSample {
+        PeerSample {
+            time,
+            offset,
+            delay: 0.01,
+            jitter: 0.0001,
+        }
+    }
+
+    #[test]
+    fn test_trend() {
+        // drifting away at 10 ppm
+        let samples: Vec<_> = (0..5)
+            .map(|i| peer_sample(1000.0 + 64.0 * i as f64, 0.001 + 10e-6 * 64.0 * i as f64))
+            .collect();
+        let trend = Trend::of_peer(&samples).unwrap();
+
+        assert_eq!(trend.samples, 5);
+        assert_eq!(trend.span, 256.0);
+        assert!((trend.frequency.unwrap() - 10.0).abs() < 1e-6);
+        assert!((trend.offset - 0.00228).abs() < 1e-9);
+        assert!(trend.deviation > 0.0);
+
+        let single = Trend::of_peer(&samples[..1]).unwrap();
+        assert_eq!(single.frequency, None);
+        assert_eq!(single.deviation, 0.0);
+
+        assert_eq!(Trend::of_peer(&[]), None);
+    }
+
+    #[test]
+    fn test_format() {
+        let history = ObservableHistory {
+            clock: vec![
+                ClockSample {
+                    time: 1000.0,
+                    offset: 0.0002,
+                    frequency: 12.0,
+                    jitter: 0.0001,
+                },
+                ClockSample {
+                    time: 1016.0,
+                    offset: 0.0001,
+                    frequency: 12.5,
+                    jitter: 0.0001,
+                },
+            ],
+            peers: vec![
+                PeerHistory {
+                    address: "192.0.2.1:123".into(),
+                    samples: vec![peer_sample(1000.0, 0.001), peer_sample(1016.0, 0.002)],
+                },
+                PeerHistory {
+                    address: "192.0.2.2:123".into(),
+                    samples: vec![],
+                },
+            ],
+        };
+        let output = format(&history);
+        let lines: Vec<_> = output.lines().collect();
+
+        assert!(lines[1].starts_with("192.0.2.1:123"));
+        assert!(lines[1].contains("62.500"));
+        assert!(lines[1].contains("1.500"));
+        assert!(lines[2].starts_with("192.0.2.2:123"));
+        assert!(lines[4].starts_with("Clock: 2 updates over 16 s, frequency 12.500 ppm"));
+    }
+}
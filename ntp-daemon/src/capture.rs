@@ -0,0 +1,139 @@
+//! Logging of the bytes of received packets that were rejected, because they
+//! could not be parsed or failed validation. Users can attach these lines to
+//! reports of interoperability problems, and `ntp-ctl decode` shows them field
+//! by field. A flood of bogus packets must not flood the logs, so only a
+//! configured number of packets is logged per minute.
+
+use std::{
+    fmt::{Display, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    logged: u32,
+    /// Packets that were not logged as the limit was reached
+    suppressed: u64,
+}
+
+/// Handle to the log of rejected packets. Cheap to clone, and does nothing
+/// when no packets are to be logged.
+#[derive(Debug, Clone, Default)]
+pub struct PacketCapture {
+    limit: u32,
+    window: Option<Arc<Mutex<Window>>>,
+}
+
+impl PacketCapture {
+    /// Log at most `per_minute` rejected packets per minute, none at all when
+    /// it is 0
+    pub fn new(per_minute: u32) -> Self {
+        if per_minute == 0 {
+            return Self::default();
+        }
+
+        PacketCapture {
+            limit: per_minute,
+            window: Some(Arc::new(Mutex::new(Window {
+                start: Instant::now(),
+                logged: 0,
+                suppressed: 0,
+            }))),
+        }
+    }
+
+    fn lock(window: &Mutex<Window>) -> MutexGuard<'_, Window> {
+        // the window is only counters, a panic halfway does not corrupt it
+        match window.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Whether a packet rejected at `now` is logged, with the number of
+    /// packets that were not logged in the previous minute when it is the
+    /// first one of a new minute
+    fn admit(&self, now: Instant) -> Option<u64> {
+        let mut window = Self::lock(self.window.as_ref()?);
+
+        let mut suppressed = 0;
+        if now.saturating_duration_since(window.start) >= WINDOW {
+            suppressed = window.suppressed;
+            *window = Window {
+                start: now,
+                logged: 0,
+                suppressed: 0,
+            };
+        }
+
+        if window.logged >= self.limit {
+            window.suppressed += 1;
+            return None;
+        }
+        window.logged += 1;
+
+        Some(suppressed)
+    }
+
+    /// Log a received packet that was rejected, unless the limit of packets
+    /// for this minute was reached
+    pub(crate) fn rejected(&self, remote: SocketAddr, data: &[u8], reason: impl Display) {
+        let suppressed = match self.admit(Instant::now()) {
+            Some(suppressed) => suppressed,
+            None => return,
+        };
+
+        if suppressed > 0 {
+            info!(
+                suppressed,
+                "not all rejected packets of the previous minute were logged"
+            );
+        }
+
+        let mut packet = String::with_capacity(2 * data.len());
+        for byte in data {
+            let _ = write!(packet, "{byte:02x}");
+        }
+        info!(%remote, %reason, %packet, "rejected packet");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit() {
+        let capture = PacketCapture::new(2);
+        let start = Instant::now();
+
+        assert_eq!(capture.admit(start), Some(0));
+        assert_eq!(capture.admit(start + Duration::from_secs(1)), Some(0));
+        assert_eq!(capture.admit(start + Duration::from_secs(2)), None);
+        assert_eq!(capture.admit(start + Duration::from_secs(3)), None);
+
+        // a new minute reports what was not logged in the previous one
+        let next = start + Duration::from_secs(61);
+        assert_eq!(capture.admit(next), Some(2));
+        assert_eq!(capture.admit(next), Some(0));
+        assert_eq!(capture.admit(next), None);
+
+        // the clones share the limit
+        let clone = capture.clone();
+        assert_eq!(clone.admit(next), None);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let capture = PacketCapture::new(0);
+        assert_eq!(capture.admit(Instant::now()), None);
+        assert_eq!(PacketCapture::default().admit(Instant::now()), None);
+    }
+}
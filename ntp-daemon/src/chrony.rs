@@ -0,0 +1,713 @@
+//! Compatibility with the monitoring commands of chrony.
+//!
+//! This answers the `tracking`, `sources` and `sourcestats` commands of
+//! `chronyc` over the unix socket protocol of chronyd (protocol version 6),
+//! such that existing tooling keeps working after migrating from chrony.
+//! Values that we do not track (such as the estimated frequency error)
+//! are reported as zero. All other commands are rejected.
+
+use std::{
+    net::IpAddr,
+    os::unix::fs::PermissionsExt,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ntp_proto::{NtpClock, NtpDuration, NtpLeapIndicator, ReferenceId, SystemSnapshot};
+use tokio::task::JoinHandle;
+use tracing::{debug, error};
+
+use crate::{
+    config::ChronyConfig, control::Association, peer_manager::Peers,
+    sockets::create_unix_datagram_socket,
+};
+
+const PROTOCOL_VERSION: u8 = 6;
+const PKT_TYPE_CMD_REQUEST: u8 = 1;
+const PKT_TYPE_CMD_REPLY: u8 = 2;
+
+const REQUEST_HEADER_LENGTH: usize = 20;
+const MAX_PACKET_SIZE: usize = 1024;
+
+const REQ_NULL: u16 = 0;
+const REQ_N_SOURCES: u16 = 14;
+const REQ_SOURCE_DATA: u16 = 15;
+const REQ_TRACKING: u16 = 33;
+const REQ_SOURCESTATS: u16 = 34;
+
+const RPY_NULL: u16 = 1;
+const RPY_N_SOURCES: u16 = 2;
+const RPY_SOURCE_DATA: u16 = 3;
+const RPY_TRACKING: u16 = 5;
+const RPY_SOURCESTATS: u16 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Success = 0,
+    Invalid = 3,
+    NoSuchSource = 4,
+    BadPacketVersion = 18,
+    BadPacketLength = 19,
+}
+
+/// Selection state of a source, as shown by `chronyc sources`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SourceState {
+    /// `*`, the source used to synchronize to
+    Selected = 0,
+    /// `?`, not (yet) usable
+    NonSelectable = 1,
+    /// `+`, combined with the selected source
+    Unselected = 4,
+    /// `-`, usable but not combined
+    Selectable = 5,
+}
+
+const MODE_CLIENT: u16 = 0;
+const MODE_REFCLOCK: u16 = 2;
+
+const IPADDR_UNSPEC: u16 = 0;
+const IPADDR_INET4: u16 = 1;
+const IPADDR_INET6: u16 = 2;
+const IPADDR_ID: u16 = 3;
+
+const FLOAT_EXP_BITS: i32 = 7;
+const FLOAT_COEF_BITS: i32 = 32 - FLOAT_EXP_BITS;
+const FLOAT_EXP_MIN: i32 = -(1 << (FLOAT_EXP_BITS - 1));
+const FLOAT_EXP_MAX: i32 = -FLOAT_EXP_MIN - 1;
+const FLOAT_COEF_MAX: i32 = (1 << (FLOAT_COEF_BITS - 1)) - 1;
+
+/// Encode a value in the floating point format of chrony, with a 7 bit
+/// exponent and a 25 bit coefficient
+fn encode_float(value: f64) -> u32 {
+    let (x, neg) = if value < 0.0 {
+        (-value, 1)
+    } else if value >= 0.0 {
+        (value, 0)
+    } else {
+        // NaN is sent as zero
+        (0.0, 0)
+    };
+
+    let (mut exp, mut coef) = if x < 1.0e-100 {
+        (0, 0)
+    } else if x > 1.0e100 {
+        (FLOAT_EXP_MAX, FLOAT_COEF_MAX + neg)
+    } else {
+        let mut exp = x.log2() as i32 + 1;
+        let mut coef = (x * 2f64.powi(FLOAT_COEF_BITS - exp) + 0.5) as i64;
+
+        // we may need to shift up to two bits down
+        while coef > (FLOAT_COEF_MAX + neg) as i64 {
+            coef >>= 1;
+            exp += 1;
+        }
+
+        (exp, coef as i32)
+    };
+
+    if exp > FLOAT_EXP_MAX {
+        exp = FLOAT_EXP_MAX;
+        coef = FLOAT_COEF_MAX + neg;
+    } else if exp < FLOAT_EXP_MIN {
+        if exp + FLOAT_COEF_BITS >= FLOAT_EXP_MIN {
+            coef >>= FLOAT_EXP_MIN - exp;
+            exp = FLOAT_EXP_MIN;
+        } else {
+            exp = 0;
+            coef = 0;
+        }
+    }
+
+    let coef = if neg == 1 { -coef } else { coef } as u32 & ((1 << FLOAT_COEF_BITS) - 1);
+
+    ((exp as u32) << FLOAT_COEF_BITS) | coef
+}
+
+#[cfg(test)]
+fn decode_float(value: u32) -> f64 {
+    let mut exp = (value >> FLOAT_COEF_BITS) as i32;
+    if exp >= 1 << (FLOAT_EXP_BITS - 1) {
+        exp -= 1 << FLOAT_EXP_BITS;
+    }
+
+    let mut coef = (value % (1 << FLOAT_COEF_BITS)) as i32;
+    if coef >= 1 << (FLOAT_COEF_BITS - 1) {
+        coef -= 1 << FLOAT_COEF_BITS;
+    }
+
+    coef as f64 * 2f64.powi(exp - FLOAT_COEF_BITS)
+}
+
+#[derive(Debug, Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn float(&mut self, value: f64) {
+        self.u32(encode_float(value));
+    }
+
+    fn duration(&mut self, value: NtpDuration) {
+        self.float(value.to_seconds());
+    }
+
+    fn timespec(&mut self, time: Option<SystemTime>) {
+        let since_epoch = time
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let seconds = since_epoch.as_secs();
+        self.u32((seconds >> 32) as u32);
+        self.u32(seconds as u32);
+        self.u32(since_epoch.subsec_nanos());
+    }
+
+    fn address(&mut self, address: Option<IpAddr>, reference_id: ReferenceId) {
+        let (family, bytes) = match address {
+            None => (IPADDR_UNSPEC, [0; 16]),
+            Some(IpAddr::V4(addr)) if is_refclock(addr.into()) => {
+                let mut bytes = [0; 16];
+                bytes[..4].copy_from_slice(&reference_id.to_bytes());
+                (IPADDR_ID, bytes)
+            }
+            Some(IpAddr::V4(addr)) => {
+                let mut bytes = [0; 16];
+                bytes[..4].copy_from_slice(&addr.octets());
+                (IPADDR_INET4, bytes)
+            }
+            Some(IpAddr::V6(addr)) => (IPADDR_INET6, addr.octets()),
+        };
+
+        self.0.extend_from_slice(&bytes);
+        self.u16(family);
+        self.u16(0);
+    }
+
+    /// Every reply ends with an (unused) end-of-record marker
+    fn finish(mut self) -> Vec<u8> {
+        self.u32(0);
+        self.0
+    }
+}
+
+/// Reference clocks use the `127.127.<type>.<unit>` addresses of ntpd, which
+/// chrony shows by their reference id instead
+fn is_refclock(address: IpAddr) -> bool {
+    matches!(address, IpAddr::V4(addr) if addr.octets()[..2] == [127, 127])
+}
+
+fn leap_status(leap_indicator: NtpLeapIndicator) -> u16 {
+    match leap_indicator {
+        NtpLeapIndicator::NoWarning => 0,
+        NtpLeapIndicator::Leap61 => 1,
+        NtpLeapIndicator::Leap59 => 2,
+        NtpLeapIndicator::Unknown => 3,
+    }
+}
+
+/// The association through which the system is synchronized, if any
+fn system_peer<'a>(
+    system: &SystemSnapshot,
+    associations: &'a [Association],
+) -> Option<(&'a Association, ntp_proto::PeerSnapshot)> {
+    if !system.leap_indicator.is_synchronized() {
+        return None;
+    }
+
+    associations
+        .iter()
+        .find_map(|association| match association.snapshot {
+            Some(snapshot) if association.selected && snapshot.peer_id == system.reference_id => {
+                Some((association, snapshot))
+            }
+            _ => None,
+        })
+}
+
+fn reference_id(association: &Association) -> ReferenceId {
+    match association.snapshot {
+        Some(snapshot) if is_refclock(association.address.ip()) => snapshot.reference_id,
+        _ => ReferenceId::from_ip(association.address.ip()),
+    }
+}
+
+fn tracking(system: &SystemSnapshot, associations: &[Association]) -> Vec<u8> {
+    let peer = system_peer(system, associations);
+
+    let mut writer = Writer::default();
+    writer.0.extend_from_slice(&system.reference_id.to_bytes());
+    match peer {
+        Some((association, snapshot)) => {
+            writer.address(Some(association.address.ip()), snapshot.reference_id)
+        }
+        None => writer.address(None, system.reference_id),
+    }
+    writer.u16(system.stratum as u16);
+    writer.u16(leap_status(system.leap_indicator));
+    writer.timespec(peer.map(|(_, snapshot)| SystemTime::now() - snapshot.time.elapsed()));
+    // current correction
+    writer.float(0.0);
+    // chrony reports how far the local clock is ahead, the opposite of our offset
+    let statistics = peer.map(|(_, snapshot)| snapshot.statistics);
+    writer.float(statistics.map(|s| -s.offset.to_seconds()).unwrap_or(0.0));
+    writer.float(statistics.map(|s| s.jitter).unwrap_or(0.0));
+    // frequency, residual frequency and skew
+    writer.float(0.0);
+    writer.float(0.0);
+    writer.float(0.0);
+    writer.duration(system.root_delay);
+    writer.duration(system.root_dispersion);
+    writer.duration(system.poll_interval.as_duration());
+    writer.finish()
+}
+
+fn source_data(system: &SystemSnapshot, association: &Association) -> Vec<u8> {
+    let state = match association.snapshot {
+        Some(snapshot) if association.selected => {
+            if system.leap_indicator.is_synchronized() && snapshot.peer_id == system.reference_id {
+                SourceState::Selected
+            } else {
+                SourceState::Unselected
+            }
+        }
+        Some(snapshot) if snapshot.reach.is_reachable() => SourceState::Selectable,
+        _ => SourceState::NonSelectable,
+    };
+
+    let mode = if is_refclock(association.address.ip()) {
+        MODE_REFCLOCK
+    } else {
+        MODE_CLIENT
+    };
+
+    let mut writer = Writer::default();
+    writer.address(Some(association.address.ip()), reference_id(association));
+    match association.snapshot {
+        Some(snapshot) => {
+            let statistics = snapshot.statistics;
+            let offset = -statistics.offset.to_seconds();
+
+            writer.u16(snapshot.poll_interval.as_log() as u16);
+            writer.u16(snapshot.stratum as u16);
+            writer.u16(state as u16);
+            writer.u16(mode);
+            // flags
+            writer.u16(0);
+            writer.u16(snapshot.reach.bits() as u16);
+            writer.u32(snapshot.time.elapsed().as_secs().min(u32::MAX as u64) as u32);
+            writer.float(offset);
+            writer.float(offset);
+            writer.duration(statistics.delay / 2i64 + statistics.dispersion);
+        }
+        None => {
+            writer.u16(0);
+            writer.u16(0);
+            writer.u16(state as u16);
+            writer.u16(mode);
+            writer.u16(0);
+            writer.u16(0);
+            writer.u32(u32::MAX);
+            writer.float(0.0);
+            writer.float(0.0);
+            writer.float(0.0);
+        }
+    }
+    writer.finish()
+}
+
+fn sourcestats(association: &Association) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer
+        .0
+        .extend_from_slice(&reference_id(association).to_bytes());
+    writer.address(Some(association.address.ip()), reference_id(association));
+    match association.snapshot {
+        Some(snapshot) => {
+            // we only know how many of the recent polls were answered
+            let samples = snapshot.reach.bits().count_ones();
+            let span = samples as u64 * snapshot.poll_interval.as_system_duration().as_secs();
+
+            writer.u32(samples);
+            // runs
+            writer.u32(0);
+            writer.u32(span.min(u32::MAX as u64) as u32);
+            writer.float(snapshot.statistics.jitter);
+            // residual frequency and skew
+            writer.float(0.0);
+            writer.float(0.0);
+            writer.float(-snapshot.statistics.offset.to_seconds());
+            writer.duration(snapshot.statistics.dispersion);
+        }
+        None => {
+            for _ in 0..3 {
+                writer.u32(0);
+            }
+            for _ in 0..5 {
+                writer.float(0.0);
+            }
+        }
+    }
+    writer.finish()
+}
+
+fn reply(request: &[u8], reply: u16, status: Status, data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(28 + data.len());
+    packet.extend_from_slice(&[PROTOCOL_VERSION, PKT_TYPE_CMD_REPLY, 0, 0]);
+    // command
+    packet.extend_from_slice(&request[4..6]);
+    packet.extend_from_slice(&reply.to_be_bytes());
+    packet.extend_from_slice(&(status as u16).to_be_bytes());
+    packet.extend_from_slice(&[0; 6]);
+    // sequence
+    packet.extend_from_slice(&request[8..12]);
+    packet.extend_from_slice(&[0; 8]);
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// Answer a single chronyc request. Returns `None` for packets which are not
+/// requests at all.
+fn respond(
+    request: &[u8],
+    system: &SystemSnapshot,
+    associations: &[Association],
+) -> Option<Vec<u8>> {
+    if request.len() < REQUEST_HEADER_LENGTH || request[1] != PKT_TYPE_CMD_REQUEST {
+        return None;
+    }
+
+    if request[0] != PROTOCOL_VERSION {
+        return Some(reply(request, RPY_NULL, Status::BadPacketVersion, &[]));
+    }
+
+    let command = u16::from_be_bytes([request[4], request[5]]);
+    let data = &request[REQUEST_HEADER_LENGTH..];
+
+    let index = || match data.get(..4) {
+        Some(bytes) => Ok(u32::from_be_bytes(bytes.try_into().unwrap()) as usize),
+        None => Err(Status::BadPacketLength),
+    };
+
+    let source = |index: usize| associations.get(index).ok_or(Status::NoSuchSource);
+
+    let result = match command {
+        REQ_NULL => Ok((RPY_NULL, vec![])),
+        REQ_TRACKING => Ok((RPY_TRACKING, tracking(system, associations))),
+        REQ_N_SOURCES => {
+            let mut writer = Writer::default();
+            writer.u32(associations.len() as u32);
+            Ok((RPY_N_SOURCES, writer.finish()))
+        }
+        REQ_SOURCE_DATA => index()
+            .and_then(source)
+            .map(|association| (RPY_SOURCE_DATA, source_data(system, association))),
+        REQ_SOURCESTATS => index()
+            .and_then(source)
+            .map(|association| (RPY_SOURCESTATS, sourcestats(association))),
+        _ => Err(Status::Invalid),
+    };
+
+    Some(match result {
+        Ok((code, data)) => reply(request, code, Status::Success, &data),
+        Err(status) => reply(request, RPY_NULL, status, &[]),
+    })
+}
+
+pub async fn spawn<C: NtpClock + Sync + Send + 'static>(
+    config: &ChronyConfig,
+    peers_reader: Arc<tokio::sync::RwLock<Peers<C>>>,
+    system_reader: Arc<tokio::sync::RwLock<SystemSnapshot>>,
+) -> JoinHandle<std::io::Result<()>> {
+    let config = config.clone();
+    tokio::spawn(async move {
+        let result = chrony_socket(config, peers_reader, system_reader).await;
+        if let Err(ref e) = result {
+            error!("Abnormal termination of chrony socket: {}", e);
+        }
+        result
+    })
+}
+
+async fn chrony_socket<C: NtpClock>(
+    config: ChronyConfig,
+    peers_reader: Arc<tokio::sync::RwLock<Peers<C>>>,
+    system_reader: Arc<tokio::sync::RwLock<SystemSnapshot>>,
+) -> std::io::Result<()> {
+    let path = match config.path {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let socket = create_unix_datagram_socket(&path)?;
+
+    // like the observe socket, monitoring should not require elevated permissions
+    let permissions: std::fs::Permissions = PermissionsExt::from_mode(config.mode);
+    std::fs::set_permissions(&path, permissions)?;
+
+    let mut buf = [0; MAX_PACKET_SIZE];
+    loop {
+        let (length, addr) = socket.recv_from(&mut buf).await?;
+
+        // chronyc binds its own socket, replies can only go to named sockets
+        let client = match addr.as_pathname() {
+            Some(client) => client.to_owned(),
+            None => continue,
+        };
+
+        let system = *system_reader.read().await;
+        let associations = peers_reader.read().await.associations();
+
+        if let Some(response) = respond(&buf[..length], &system, &associations) {
+            if let Err(error) = socket.send_to(&response, &client).await {
+                debug!(?error, "could not reply to chrony client");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::{peer_snapshot, NtpInstant, NtpTimestamp, PeerStatistics, PollInterval};
+    use tokio::net::UnixDatagram;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Default)]
+    struct TestClock {}
+
+    impl NtpClock for TestClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> std::result::Result<NtpTimestamp, Self::Error> {
+            Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+        }
+
+        fn set_freq(&self, _freq: f64) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn update_clock(
+            &self,
+            _offset: NtpDuration,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+            _poll_interval: PollInterval,
+            _leap_status: NtpLeapIndicator,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_float() {
+        assert_eq!(encode_float(1.0), 0x04800000);
+        assert_eq!(encode_float(0.0), 0);
+        assert_eq!(encode_float(f64::NAN), 0);
+
+        for value in [1.0, -1.0, 0.5, 123.456, -0.000_012_5, 1e-9, 86400.0] {
+            let decoded = decode_float(encode_float(value));
+            assert!(
+                ((decoded - value) / value).abs() < 1e-7,
+                "{} {}",
+                value,
+                decoded
+            );
+        }
+
+        // out of range values are clamped
+        assert!(decode_float(encode_float(1e200)) > 1e18);
+    }
+
+    fn request(command: u16, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![PROTOCOL_VERSION, PKT_TYPE_CMD_REQUEST, 0, 0];
+        packet.extend_from_slice(&command.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0xde, 0xad, 0xbe, 0xef]);
+        packet.extend_from_slice(&[0; 8]);
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    fn state() -> (SystemSnapshot, Vec<Association>) {
+        let snapshot = peer_snapshot(
+            PeerStatistics {
+                offset: NtpDuration::from_seconds(0.001),
+                delay: NtpDuration::from_seconds(0.01),
+                dispersion: NtpDuration::from_seconds(0.001),
+                jitter: 0.0001,
+            },
+            NtpInstant::now(),
+            NtpDuration::ZERO,
+            NtpDuration::ZERO,
+        );
+
+        let system = SystemSnapshot {
+            leap_indicator: NtpLeapIndicator::NoWarning,
+            stratum: 2,
+            reference_id: snapshot.peer_id,
+            ..Default::default()
+        };
+
+        let associations = vec![
+            Association {
+                id: 1,
+                address: "192.0.2.1:123".parse().unwrap(),
+                snapshot: Some(snapshot),
+                selected: true,
+            },
+            Association {
+                id: 2,
+                address: "127.127.20.0:0".parse().unwrap(),
+                snapshot: None,
+                selected: false,
+            },
+        ];
+
+        (system, associations)
+    }
+
+    fn status(reply: &[u8]) -> u16 {
+        u16::from_be_bytes([reply[8], reply[9]])
+    }
+
+    #[test]
+    fn test_tracking() {
+        let (system, associations) = state();
+        let reply = respond(&request(REQ_TRACKING, &[]), &system, &associations).unwrap();
+
+        assert_eq!(&reply[..4], &[6, 2, 0, 0]);
+        assert_eq!(&reply[4..6], &REQ_TRACKING.to_be_bytes());
+        assert_eq!(&reply[6..8], &RPY_TRACKING.to_be_bytes());
+        assert_eq!(status(&reply), 0);
+        assert_eq!(&reply[16..20], &[0xde, 0xad, 0xbe, 0xef]);
+
+        let data = &reply[28..];
+        assert_eq!(data.len(), 4 + 20 + 2 + 2 + 12 + 9 * 4 + 4);
+        assert_eq!(&data[..4], &system.reference_id.to_bytes());
+        assert_eq!(&data[4..8], &[192, 0, 2, 1]);
+        assert_eq!(&data[20..22], &IPADDR_INET4.to_be_bytes());
+        // stratum and leap status
+        assert_eq!(&data[24..28], &[0, 2, 0, 0]);
+
+        let last_offset = decode_float(u32::from_be_bytes(data[44..48].try_into().unwrap()));
+        assert!((last_offset + 0.001).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_sources() {
+        let (system, associations) = state();
+
+        let reply = respond(&request(REQ_N_SOURCES, &[]), &system, &associations).unwrap();
+        assert_eq!(&reply[28..32], &[0, 0, 0, 2]);
+
+        let reply = respond(
+            &request(REQ_SOURCE_DATA, &[0, 0, 0, 0]),
+            &system,
+            &associations,
+        )
+        .unwrap();
+        assert_eq!(status(&reply), 0);
+        let data = &reply[28..];
+        assert_eq!(&data[..4], &[192, 0, 2, 1]);
+        // state and mode
+        assert_eq!(&data[24..28], &[0, SourceState::Selected as u8, 0, 0]);
+
+        let reply = respond(
+            &request(REQ_SOURCE_DATA, &[0, 0, 0, 1]),
+            &system,
+            &associations,
+        )
+        .unwrap();
+        let data = &reply[28..];
+        assert_eq!(&data[16..18], &IPADDR_ID.to_be_bytes());
+        assert_eq!(
+            &data[24..28],
+            &[0, SourceState::NonSelectable as u8, 0, MODE_REFCLOCK as u8]
+        );
+
+        let reply = respond(
+            &request(REQ_SOURCESTATS, &[0, 0, 0, 0]),
+            &system,
+            &associations,
+        )
+        .unwrap();
+        assert_eq!(&reply[6..8], &RPY_SOURCESTATS.to_be_bytes());
+        assert_eq!(reply.len(), 28 + 4 + 20 + 3 * 4 + 5 * 4 + 4);
+    }
+
+    #[test]
+    fn test_errors() {
+        let (system, associations) = state();
+
+        let reply = respond(
+            &request(REQ_SOURCE_DATA, &[0, 0, 0, 2]),
+            &system,
+            &associations,
+        )
+        .unwrap();
+        assert_eq!(status(&reply), Status::NoSuchSource as u16);
+
+        let reply = respond(&request(REQ_SOURCE_DATA, &[]), &system, &associations).unwrap();
+        assert_eq!(status(&reply), Status::BadPacketLength as u16);
+
+        // e.g. settime
+        let reply = respond(&request(56, &[]), &system, &associations).unwrap();
+        assert_eq!(status(&reply), Status::Invalid as u16);
+
+        let mut old = request(REQ_TRACKING, &[]);
+        old[0] = 5;
+        let reply = respond(&old, &system, &associations).unwrap();
+        assert_eq!(status(&reply), Status::BadPacketVersion as u16);
+
+        // replies are never answered
+        let mut packet = request(REQ_TRACKING, &[]);
+        packet[1] = PKT_TYPE_CMD_REPLY;
+        assert!(respond(&packet, &system, &associations).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_socket() {
+        // be careful with copying: tests run concurrently and should use a unique socket name!
+        let path = std::env::temp_dir().join("ntp-test-chrony-1");
+        let client_path = std::env::temp_dir().join("ntp-test-chrony-1-client");
+        let config = ChronyConfig {
+            path: Some(path.clone()),
+            mode: 0o700,
+        };
+
+        let peers_reader = Arc::new(tokio::sync::RwLock::new(Peers::from_statuslist(
+            &[],
+            &[],
+            TestClock {},
+        )));
+        let system_reader = Arc::new(tokio::sync::RwLock::new(SystemSnapshot::default()));
+
+        let handle = spawn(&config, peers_reader, system_reader).await;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        if client_path.exists() {
+            std::fs::remove_file(&client_path).unwrap();
+        }
+        let client = UnixDatagram::bind(&client_path).unwrap();
+        client
+            .send_to(&request(REQ_N_SOURCES, &[]), &path)
+            .await
+            .unwrap();
+
+        let mut buf = [0; 128];
+        let length = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[28..length], &[0, 0, 0, 0, 0, 0, 0, 0]);
+
+        handle.abort();
+    }
+}
@@ -0,0 +1,439 @@
+//! The clock the daemon disciplines, as chosen by the `[clock]` section.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use ntp_os_clock::{has_capability, sysfs_read_only, Capability, NamespaceClock, UnixNtpClock};
+use ntp_proto::{NtpClock, NtpDuration, NtpLeapIndicator, NtpTimestamp, PollInterval};
+use tracing::{info, warn};
+
+use crate::{
+    config::{ClockBackend, ClockConfig, Timescale},
+    observer::ClockMode,
+    tai::LeapSecondsList,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClockError {
+    #[error(transparent)]
+    Os(#[from] ntp_os_clock::Error),
+    #[error("the TAI timescale needs the leap seconds file {0:?}")]
+    LeapSeconds(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+enum Backend {
+    /// The clock of the system, through the kernel
+    System(UnixNtpClock),
+    /// A clock of the daemon, for running inside a time namespace
+    Namespace(NamespaceClock),
+    /// The clock of the system, which is read but never adjusted. Whether
+    /// this is a fallback for a clock that may not be adjusted is kept for
+    /// reporting.
+    Monitor { clock: UnixNtpClock, fallback: bool },
+    /// The clock of the system, which is only read to timestamp the
+    /// measurements of the sources
+    Measure(UnixNtpClock),
+}
+
+/// How the time of the clock relates to the UTC of NTP
+#[derive(Debug, Clone)]
+enum LocalTimescale {
+    Utc,
+    Tai(LeapSecondsList),
+    UtcOffset(NtpDuration, OffsetLeap),
+}
+
+impl LocalTimescale {
+    /// The offset of the clock to UTC, when the clock reads `local`
+    fn offset(&self, local: NtpTimestamp) -> NtpDuration {
+        match self {
+            LocalTimescale::Utc => NtpDuration::ZERO,
+            LocalTimescale::Tai(leap_seconds) => leap_seconds.offset_at_tai(local),
+            LocalTimescale::UtcOffset(offset, _) => *offset,
+        }
+    }
+
+    fn to_utc(&self, local: NtpTimestamp) -> NtpTimestamp {
+        local - self.offset(local)
+    }
+}
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// Leap seconds of a system clock that keeps UTC plus an offset. The kernel
+/// would insert or delete them at midnight of the clock, which is not
+/// midnight UTC, so it is not told about them. Instead, the clock is stepped
+/// at midnight UTC. Clones share the leap second.
+#[derive(Debug, Clone, Default)]
+struct OffsetLeap(Arc<Mutex<OffsetLeapState>>);
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct OffsetLeapState {
+    /// The announced leap second, with the end of the UTC day at which the
+    /// clock is stepped, in seconds since the unix epoch
+    pending: Option<(NtpLeapIndicator, i64)>,
+    /// The end of the day of the last step. Sources may still announce that
+    /// leap second for a while after it.
+    last: Option<i64>,
+}
+
+impl OffsetLeapState {
+    /// Take the leap indicator of a clock update at `now`, in seconds of UTC
+    /// since the unix epoch. Returns the end of the day at which to step,
+    /// when no step is planned for it yet.
+    fn update(&mut self, leap_status: NtpLeapIndicator, now: i64) -> Option<i64> {
+        if !matches!(
+            leap_status,
+            NtpLeapIndicator::Leap61 | NtpLeapIndicator::Leap59
+        ) {
+            self.pending = None;
+            return None;
+        }
+
+        let midnight = (now.div_euclid(SECONDS_PER_DAY) + 1) * SECONDS_PER_DAY;
+        if self.last == Some(midnight - SECONDS_PER_DAY)
+            || self.pending == Some((leap_status, midnight))
+        {
+            return None;
+        }
+
+        self.pending = Some((leap_status, midnight));
+        Some(midnight)
+    }
+
+    /// Whether the leap second is still announced when `midnight` arrives,
+    /// in which case the clock is stepped
+    fn due(&mut self, leap_status: NtpLeapIndicator, midnight: i64) -> bool {
+        if self.pending != Some((leap_status, midnight)) {
+            return false;
+        }
+
+        self.pending = None;
+        self.last = Some(midnight);
+        true
+    }
+}
+
+impl OffsetLeap {
+    fn state(&self) -> MutexGuard<'_, OffsetLeapState> {
+        // the state stays consistent, a panic can not leave it halfway
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Step `clock`, which is ahead of UTC by `offset`, at the end of the
+    /// UTC day when a leap second is announced for it
+    fn update(&self, clock: &UnixNtpClock, offset: NtpDuration, leap_status: NtpLeapIndicator) {
+        let utc_now = match clock.now() {
+            Ok(local) => local - offset,
+            Err(_) => return,
+        };
+        let (seconds, _) = utc_now.to_unix_seconds_nanos();
+        let midnight = match self.state().update(leap_status, seconds) {
+            Some(midnight) => midnight,
+            None => return,
+        };
+
+        let leap = self.clone();
+        let clock = clock.clone();
+        let result = std::thread::Builder::new()
+            .name("leap-second".into())
+            .spawn(move || {
+                // the time left is measured again and again, in case the
+                // clock is adjusted in the meantime
+                let end_of_day = NtpTimestamp::from_unix_seconds_nanos(midnight, 0);
+                loop {
+                    let remaining = match clock.now() {
+                        Ok(local) => end_of_day - (local - offset),
+                        Err(_) => return,
+                    };
+                    if remaining <= NtpDuration::ZERO {
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_secs_f64(remaining.to_seconds().min(60.0)));
+                }
+
+                if !leap.state().due(leap_status, midnight) {
+                    return;
+                }
+
+                let step = match leap_status {
+                    NtpLeapIndicator::Leap59 => NtpDuration::from_seconds(1.0),
+                    _ => NtpDuration::from_seconds(-1.0),
+                };
+                match clock.step_clock(step) {
+                    Ok(()) => info!(?leap_status, "stepped the clock for the leap second"),
+                    Err(error) => warn!(%error, "could not step the clock for the leap second"),
+                }
+            });
+
+        if let Err(error) = result {
+            warn!(?error, "could not start the leap second thread");
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DaemonClock {
+    backend: Backend,
+    timescale: LocalTimescale,
+}
+
+impl DaemonClock {
+    pub fn new(config: &ClockConfig) -> Result<Self, ClockError> {
+        let backend = match config.backend {
+            ClockBackend::System => Backend::System(UnixNtpClock::new()),
+            ClockBackend::Namespace => {
+                match ntp_os_clock::time_namespace_boottime_offset() {
+                    Ok(Some(offset)) => info!(?offset, "using the clock of the time namespace"),
+                    Ok(None) => {
+                        warn!("the kernel has no time namespaces, using the boot time clock")
+                    }
+                    Err(error) => warn!(?error, "could not read the time namespace offsets"),
+                }
+
+                Backend::Namespace(NamespaceClock::new()?)
+            }
+            ClockBackend::Monitor => {
+                info!("Monitor-only mode: the clock is measured, but never adjusted");
+                Backend::Monitor {
+                    clock: UnixNtpClock::new(),
+                    fallback: false,
+                }
+            }
+            ClockBackend::Measure => {
+                info!("Measurement mode: the sources are measured, without clock selection, and the clock is never adjusted");
+                Backend::Measure(UnixNtpClock::new())
+            }
+        };
+
+        let timescale = match config.timescale {
+            Timescale::Utc => LocalTimescale::Utc,
+            Timescale::Tai => match LeapSecondsList::load(&config.leap_seconds_file) {
+                Some(leap_seconds) => {
+                    info!("the clock keeps TAI, which is converted to UTC for NTP");
+                    LocalTimescale::Tai(leap_seconds)
+                }
+                None => return Err(ClockError::LeapSeconds(config.leap_seconds_file.clone())),
+            },
+            Timescale::UtcOffset(offset) => {
+                info!(?offset, "the clock keeps UTC plus a fixed offset");
+                LocalTimescale::UtcOffset(offset, OffsetLeap::default())
+            }
+        };
+
+        Ok(DaemonClock { backend, timescale })
+    }
+
+    /// Only monitor the system clock when the daemon may not adjust it, when
+    /// the configuration allows that. Done after dropping privileges, as that
+    /// decides the capabilities that remain.
+    pub fn or_monitor(self, config: &ClockConfig) -> Self {
+        let clock = match self.backend {
+            Backend::System(clock) if config.monitor_fallback => clock,
+            _ => return self,
+        };
+
+        // when in doubt, adjusting the clock is tried, and its errors logged
+        if has_capability(Capability::SysTime).unwrap_or(true) {
+            return DaemonClock {
+                backend: Backend::System(clock),
+                ..self
+            };
+        }
+
+        if sysfs_read_only() {
+            warn!("Monitor-only mode: the daemon lacks CAP_SYS_TIME and /sys is read-only, so it probably runs in a container whose host owns the clock. The clock is measured, but never adjusted.");
+        } else {
+            warn!("Monitor-only mode: the daemon lacks CAP_SYS_TIME to adjust the clock. The clock is measured, but never adjusted.");
+        }
+
+        DaemonClock {
+            backend: Backend::Monitor {
+                clock,
+                fallback: true,
+            },
+            ..self
+        }
+    }
+
+    pub fn mode(&self) -> ClockMode {
+        match self.backend {
+            Backend::System(_) | Backend::Namespace(_) => ClockMode::Steering,
+            Backend::Monitor {
+                fallback: false, ..
+            } => ClockMode::MonitorOnly,
+            Backend::Monitor { fallback: true, .. } => ClockMode::MonitorOnlyFallback,
+            Backend::Measure(_) => ClockMode::Measuring,
+        }
+    }
+
+    /// The clock of the system, when that is the one disciplined
+    pub fn system(&self) -> Option<&UnixNtpClock> {
+        match &self.backend {
+            Backend::System(clock) => Some(clock),
+            Backend::Namespace(_) | Backend::Monitor { .. } | Backend::Measure(_) => None,
+        }
+    }
+
+    /// The list of leap seconds of a clock that keeps TAI, which must be
+    /// kept up to date
+    pub fn leap_seconds(&self) -> Option<LeapSecondsList> {
+        match &self.timescale {
+            LocalTimescale::Tai(leap_seconds) => Some(leap_seconds.clone()),
+            LocalTimescale::Utc | LocalTimescale::UtcOffset(..) => None,
+        }
+    }
+}
+
+impl Default for DaemonClock {
+    fn default() -> Self {
+        DaemonClock {
+            backend: Backend::System(UnixNtpClock::new()),
+            timescale: LocalTimescale::Utc,
+        }
+    }
+}
+
+impl NtpClock for DaemonClock {
+    type Error = ntp_os_clock::Error;
+
+    fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+        let local = match &self.backend {
+            Backend::System(clock) => clock.now(),
+            Backend::Namespace(clock) => clock.now(),
+            Backend::Monitor { clock, .. } | Backend::Measure(clock) => clock.now(),
+        }?;
+
+        Ok(self.timescale.to_utc(local))
+    }
+
+    fn set_freq(&self, freq: f64) -> Result<(), Self::Error> {
+        match &self.backend {
+            Backend::System(clock) => clock.set_freq(freq),
+            Backend::Namespace(clock) => clock.set_freq(freq),
+            // adjustments of a monitored clock are dropped, as a safeguard
+            Backend::Monitor { .. } | Backend::Measure(_) => Ok(()),
+        }
+    }
+
+    fn step_clock(&self, offset: NtpDuration) -> Result<(), Self::Error> {
+        match &self.backend {
+            Backend::System(clock) => clock.step_clock(offset),
+            Backend::Namespace(clock) => clock.step_clock(offset),
+            Backend::Monitor { .. } | Backend::Measure(_) => Ok(()),
+        }
+    }
+
+    fn update_clock(
+        &self,
+        offset: NtpDuration,
+        est_error: NtpDuration,
+        max_error: NtpDuration,
+        poll_interval: PollInterval,
+        leap_status: NtpLeapIndicator,
+    ) -> Result<(), Self::Error> {
+        let leap_status = match &self.timescale {
+            LocalTimescale::Utc => leap_status,
+            // TAI has no leap seconds, the offset to UTC grows instead
+            LocalTimescale::Tai(_) => NtpLeapIndicator::NoWarning,
+            // the kernel would insert the leap second at midnight of the
+            // clock, so the daemon steps the clock at midnight UTC itself
+            LocalTimescale::UtcOffset(offset, leap) => {
+                if let Backend::System(clock) = &self.backend {
+                    leap.update(clock, *offset, leap_status);
+                }
+                NtpLeapIndicator::NoWarning
+            }
+        };
+
+        match &self.backend {
+            Backend::System(clock) => {
+                clock.update_clock(offset, est_error, max_error, poll_interval, leap_status)
+            }
+            Backend::Namespace(clock) => {
+                clock.update_clock(offset, est_error, max_error, poll_interval, leap_status)
+            }
+            Backend::Monitor { .. } | Backend::Measure(_) => Ok(()),
+        }
+    }
+
+    fn adjust_system_timestamp(&self, system_time: NtpTimestamp) -> NtpTimestamp {
+        let local = match &self.backend {
+            Backend::System(clock) => clock.adjust_system_timestamp(system_time),
+            Backend::Namespace(clock) => clock.adjust_system_timestamp(system_time),
+            Backend::Monitor { clock, .. } | Backend::Measure(clock) => {
+                clock.adjust_system_timestamp(system_time)
+            }
+        };
+
+        self.timescale.to_utc(local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timescale_to_utc() {
+        let local = NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0);
+
+        assert_eq!(LocalTimescale::Utc.to_utc(local), local);
+
+        let offset =
+            LocalTimescale::UtcOffset(NtpDuration::from_seconds(-3600.0), OffsetLeap::default());
+        let utc = NtpTimestamp::from_unix_seconds_nanos(1_700_003_600, 0);
+        assert_eq!(offset.to_utc(local), utc);
+
+        let path = std::env::temp_dir().join("ntp-test-timescale-leap-seconds.list");
+        std::fs::write(
+            &path,
+            "3644697600 36 # 1 Jul 2015\n3692217600 37 # 1 Jan 2017\n",
+        )
+        .unwrap();
+        let tai = LocalTimescale::Tai(LeapSecondsList::load(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        // around the leap second at the end of 2016, of which UTC repeats
+        // the last second (1483228799)
+        let at = |seconds| NtpTimestamp::from_unix_seconds_nanos(seconds, 0);
+        assert_eq!(tai.to_utc(at(1_483_228_835)), at(1_483_228_799));
+        assert_eq!(tai.to_utc(at(1_483_228_836)), at(1_483_228_799));
+        assert_eq!(tai.to_utc(at(1_483_228_837)), at(1_483_228_800));
+        assert_eq!(tai.to_utc(local), at(1_700_000_000 - 37));
+    }
+
+    #[test]
+    fn test_offset_leap() {
+        let mut state = OffsetLeapState::default();
+        // 2016-12-31 12:00 UTC
+        let noon = 1_483_185_600;
+        let midnight = 1_483_228_800;
+
+        assert_eq!(state.update(NtpLeapIndicator::NoWarning, noon), None);
+        assert_eq!(state.update(NtpLeapIndicator::Leap61, noon), Some(midnight));
+        // the step is planned once
+        assert_eq!(state.update(NtpLeapIndicator::Leap61, noon + 60), None);
+
+        // a leap second that is no longer announced is not applied
+        assert_eq!(state.update(NtpLeapIndicator::NoWarning, noon + 120), None);
+        assert!(!state.due(NtpLeapIndicator::Leap61, midnight));
+
+        assert_eq!(
+            state.update(NtpLeapIndicator::Leap61, noon + 180),
+            Some(midnight)
+        );
+        assert!(state.due(NtpLeapIndicator::Leap61, midnight));
+        assert!(!state.due(NtpLeapIndicator::Leap61, midnight));
+
+        // sources that still announce it after midnight do not cause
+        // another step the next day
+        assert_eq!(state.update(NtpLeapIndicator::Leap61, midnight + 10), None);
+    }
+}
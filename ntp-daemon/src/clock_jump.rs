@@ -0,0 +1,138 @@
+//! Detection of steps of the system clock made by something else than us,
+//! and of suspends of the system.
+//!
+//! Another time daemon, or an administrator running `date -s`, may step the
+//! system clock while we are running. The measurements of the peers and the
+//! state of the clock controller then describe a clock that no longer exists,
+//! and would be slowly "corrected" against. Such steps are detected by
+//! comparing how far the system clock (`CLOCK_REALTIME`) advanced with how far
+//! the monotonic clock advanced, as the latter is never stepped.
+//!
+//! The monotonic clock also stops while the system is suspended, unlike
+//! `CLOCK_BOOTTIME`, so a growing difference between the two reveals a
+//! suspend, after which the measurements are stale as well.
+
+use std::time::{Duration, Instant};
+
+use ntp_proto::{NtpDuration, NtpTimestamp};
+
+/// How often the system clock is compared with the monotonic clock
+pub(crate) const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Smallest difference between the clocks that is considered a step, in
+/// seconds. Offsets below this are slewed rather than stepped by the daemon.
+const JUMP_THRESHOLD: f64 = 0.125;
+
+/// Largest rate at which the kernel slews the system clock, in seconds per
+/// second
+const MAX_SLEW_RATE: f64 = 500e-6;
+
+/// Readings of the clocks, taken right after each other
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ClockReading {
+    pub(crate) monotonic: Instant,
+    pub(crate) realtime: NtpTimestamp,
+    /// Total time the system spent suspended since it booted
+    pub(crate) suspended: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ClockEvent {
+    /// The system clock was stepped by the given amount
+    Jump(NtpDuration),
+    /// The system resumed after being suspended for the given time
+    Resume(Duration),
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct JumpDetector {
+    last: Option<ClockReading>,
+}
+
+impl JumpDetector {
+    /// Compare a reading of the clocks with the previous one, returning
+    /// whether the system clock was stepped or the system was suspended in
+    /// between
+    pub(crate) fn check(&mut self, reading: ClockReading) -> Option<ClockEvent> {
+        let last = self.last.replace(reading)?;
+
+        let suspended = reading.suspended.saturating_sub(last.suspended);
+        if suspended.as_secs_f64() > JUMP_THRESHOLD {
+            return Some(ClockEvent::Resume(suspended));
+        }
+
+        // the system clock keeps running while suspended
+        let elapsed = reading.monotonic.saturating_duration_since(last.monotonic) + suspended;
+        let jump = (reading.realtime - last.realtime) - NtpDuration::from_system_duration(elapsed);
+        let threshold = JUMP_THRESHOLD + elapsed.as_secs_f64() * MAX_SLEW_RATE;
+
+        (jump.abs().to_seconds() > threshold).then_some(ClockEvent::Jump(jump))
+    }
+
+    /// Forget the previous reading, after we changed the clock ourselves
+    pub(crate) fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jump_detection() {
+        let mut detector = JumpDetector::default();
+        let mut reading = ClockReading {
+            monotonic: Instant::now(),
+            realtime: NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0),
+            suspended: Duration::ZERO,
+        };
+
+        assert_eq!(detector.check(reading), None);
+
+        // slewing, at the maximum rate
+        reading.monotonic += Duration::from_secs(10);
+        reading.realtime += NtpDuration::from_seconds(10.005);
+        assert_eq!(detector.check(reading), None);
+
+        // someone set the clock back an hour
+        reading.monotonic += Duration::from_secs(1);
+        reading.realtime += NtpDuration::from_seconds(1.0 - 3600.0);
+        match detector.check(reading) {
+            Some(ClockEvent::Jump(jump)) => assert!((jump.to_seconds() + 3600.0).abs() < 1e-6),
+            other => panic!("expected a jump, got {other:?}"),
+        }
+
+        // our own steps are not reported
+        detector.reset();
+        reading.realtime += NtpDuration::from_seconds(3600.0);
+        assert_eq!(detector.check(reading), None);
+        reading.monotonic += Duration::from_secs(1);
+        reading.realtime += NtpDuration::from_seconds(1.0);
+        assert_eq!(detector.check(reading), None);
+    }
+
+    #[test]
+    fn test_resume_detection() {
+        let mut detector = JumpDetector::default();
+        let mut reading = ClockReading {
+            monotonic: Instant::now(),
+            realtime: NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0),
+            suspended: Duration::from_secs(60),
+        };
+        assert_eq!(detector.check(reading), None);
+
+        // suspended for an hour, during which the system clock kept running
+        reading.monotonic += Duration::from_secs(1);
+        reading.realtime += NtpDuration::from_seconds(3601.0);
+        reading.suspended += Duration::from_secs(3600);
+        assert_eq!(
+            detector.check(reading),
+            Some(ClockEvent::Resume(Duration::from_secs(3600)))
+        );
+
+        reading.monotonic += Duration::from_secs(1);
+        reading.realtime += NtpDuration::from_seconds(1.0);
+        assert_eq!(detector.check(reading), None);
+    }
+}
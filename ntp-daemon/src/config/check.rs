@@ -0,0 +1,689 @@
+use std::{cmp::Reverse, collections::HashMap, fmt, net::SocketAddr, path::PathBuf};
+
+use ntp_proto::NtpDuration;
+
+use super::{ClockBackend, Config, PeerConfig, RefClockConfig, ServerPolicy, Timescale};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The daemon runs, but probably not as intended
+    Warning,
+    /// The daemon can not work with this configuration
+    Error,
+}
+
+/// A problem found in the configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The option or section the problem is about, such as
+    /// `system.panic-threshold` or `peers[1]`
+    pub location: String,
+    /// What is wrong, and what to do about it
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn warning(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+
+    fn error(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{severity}[{}]: {}", self.location, self.message)
+    }
+}
+
+impl PeerConfig {
+    fn address(&self) -> &str {
+        match self {
+            PeerConfig::Standard(config) => config.addr.as_str(),
+            PeerConfig::Pool(config) => config.addr.as_str(),
+        }
+    }
+
+    /// Number of servers this configuration gives at most
+    fn max_servers(&self) -> usize {
+        match self {
+            PeerConfig::Standard(_) => 1,
+            PeerConfig::Pool(config) => config.max_peers,
+        }
+    }
+}
+
+/// The host of an address with a port, without the brackets of IPv6
+fn host(address: &str) -> &str {
+    let host = match address.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => address,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+impl Config {
+    /// Problems in the configuration that can be found without looking
+    /// anything up
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = vec![];
+
+        self.check_sources(&mut diagnostics);
+        self.check_source_groups(&mut diagnostics);
+        self.check_thresholds(&mut diagnostics);
+        self.check_servers(&mut diagnostics);
+        self.check_paths(&mut diagnostics);
+        self.check_clock(&mut diagnostics);
+
+        for steered in &self.steered_phcs {
+            let used_as_source = self.refclocks.iter().any(|r| match r {
+                RefClockConfig::Phc(phc) => phc.device == steered.device,
+                _ => false,
+            });
+
+            if used_as_source {
+                diagnostics.push(Diagnostic::warning(
+                    "steered-phcs",
+                    format!("PHC {:?} is both steered and used as a reference clock. This creates a feedback loop.", steered.device),
+                ));
+            }
+        }
+
+        diagnostics
+    }
+
+    /// All problems in the configuration, also resolving the addresses of the
+    /// peers when `resolve` is set, to find peers that can not be resolved
+    /// and servers that are configured more than once under different names
+    pub async fn diagnose(&self, resolve: bool) -> Vec<Diagnostic> {
+        let mut diagnostics = self.diagnostics();
+        if resolve {
+            self.check_resolved(&mut diagnostics).await;
+        }
+        diagnostics.sort_by_key(|diagnostic| Reverse(diagnostic.severity));
+        diagnostics
+    }
+
+    fn check_sources(&self, diagnostics: &mut Vec<Diagnostic>) {
+        if self.peers.is_empty() && self.refclocks.is_empty() {
+            diagnostics.push(Diagnostic::warning(
+                "peers",
+                "No peers configured. Daemon will not do anything.",
+            ));
+        }
+
+        let max_sources: usize = self
+            .peers
+            .iter()
+            .map(PeerConfig::max_servers)
+            .sum::<usize>()
+            + self.refclocks.len();
+        // measured sources need not agree on the time
+        let selecting = self.clock.backend != ClockBackend::Measure;
+        if selecting && max_sources < self.system.min_intersection_survivors {
+            diagnostics.push(Diagnostic::warning(
+                "system.min-intersection-survivors",
+                format!("Fewer peers configured ({max_sources}) than are required to agree on the current time ({}). Daemon will not do anything. Add peers, or lower min-intersection-survivors.", self.system.min_intersection_survivors),
+            ));
+        }
+
+        if selecting && self.system.max_candidates < self.system.min_intersection_survivors {
+            diagnostics.push(Diagnostic::error(
+                "system.max-candidates",
+                format!("At most {} sources take part in clock selection, fewer than are required to agree on the current time ({}). The clock will never be adjusted. Raise max-candidates.", self.system.max_candidates, self.system.min_intersection_survivors),
+            ));
+        }
+
+        if self.refclocks.iter().any(|r| r.needs_time_source())
+            && self.peers.is_empty()
+            && self.refclocks.iter().all(|r| r.needs_time_source())
+        {
+            diagnostics.push(Diagnostic::warning(
+                "refclocks",
+                "PPS reference clocks configured without another source of time. They will not be used.",
+            ));
+        }
+
+        for (index, refclock) in self.refclocks.iter().enumerate() {
+            if refclock.common().stratum >= 16 {
+                diagnostics.push(Diagnostic::error(
+                    format!("refclocks[{index}].stratum"),
+                    "Stratum 16 and above means unsynchronized. Use a stratum of at most 15.",
+                ));
+            }
+            if refclock.common().require_corroboration && self.peers.is_empty() {
+                diagnostics.push(Diagnostic::warning(
+                    format!("refclocks[{index}].require-corroboration"),
+                    "Corroboration is required, but there are no peers to corroborate the reference clock. It will not be used.",
+                ));
+            }
+        }
+
+        if self.keys.path.is_none() {
+            for (index, peer) in self.peers.iter().enumerate() {
+                if let Some(key) = peer.key() {
+                    diagnostics.push(Diagnostic::error(
+                        format!("peers[{index}]"),
+                        format!("Key {key} is used, but there is no keys file to take it from. Set keys.path."),
+                    ));
+                }
+            }
+        }
+
+        for (index, peer) in self.peers.iter().enumerate() {
+            let nat = &peer.bind().nat;
+            if (nat.keepalive.is_some() || nat.reopen_after.is_some())
+                && !nat.persistent(self.network.client_sockets)
+            {
+                diagnostics.push(Diagnostic::warning(
+                    format!("peers[{index}].nat"),
+                    "keepalive and reopen-after have no effect with a new socket for every request. Set client-sockets = \"persistent\" for this peer.",
+                ));
+            }
+        }
+
+        // the same host configured twice is used twice, and so gets double
+        // weight in clock selection
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        for (index, peer) in self.peers.iter().enumerate() {
+            let host = host(peer.address());
+            match seen.get(host) {
+                Some(first) => diagnostics.push(Diagnostic::warning(
+                    format!("peers[{index}]"),
+                    format!("{host} is also configured as peers[{first}], so its servers may be used twice. Remove one of them."),
+                )),
+                None => {
+                    seen.insert(host, index);
+                }
+            }
+        }
+    }
+
+    fn check_source_groups(&self, diagnostics: &mut Vec<Diagnostic>) {
+        for (index, group) in self.source_groups.iter().enumerate() {
+            if self.source_groups[..index]
+                .iter()
+                .any(|other| other.name == group.name)
+            {
+                diagnostics.push(Diagnostic::error(
+                    format!("source-groups[{index}].name"),
+                    format!("Source group {:?} is configured more than once. Give it a name of its own.", group.name),
+                ));
+            }
+        }
+
+        let known = |name: &str| self.source_groups.iter().any(|group| group.name == name);
+        let peers = self
+            .peers
+            .iter()
+            .enumerate()
+            .map(|(index, peer)| (format!("peers[{index}]"), peer.source_group()));
+        let refclocks = self
+            .refclocks
+            .iter()
+            .enumerate()
+            .map(|(index, refclock)| (format!("refclocks[{index}]"), refclock.source_group()));
+        for (location, name) in peers.chain(refclocks) {
+            match name {
+                Some(name) if !known(name) => diagnostics.push(Diagnostic::error(
+                    format!("{location}.source-group"),
+                    format!("There is no source group named {name:?}. Add it to source-groups."),
+                )),
+                _ => {}
+            }
+        }
+    }
+
+    fn check_thresholds(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let panic_threshold = self.system.panic_threshold;
+        for (direction, threshold) in [
+            ("forward", panic_threshold.forward),
+            ("backward", panic_threshold.backward),
+        ] {
+            match threshold {
+                // zero has a meaning of its own
+                Some(threshold)
+                    if threshold > NtpDuration::ZERO && threshold < NtpDuration::STEP_THRESHOLD =>
+                {
+                    diagnostics.push(Diagnostic::error(
+                        "system.panic-threshold",
+                        format!("The {direction} panic threshold of {threshold} is below the step threshold of {}, so offsets that would be slewed make the daemon exit. Raise the panic threshold.", NtpDuration::STEP_THRESHOLD),
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        // the panic threshold is checked first, so it decides what happens
+        // to large offsets
+        let lowest_panic_threshold = [panic_threshold.forward, panic_threshold.backward]
+            .into_iter()
+            .flatten()
+            .filter(|threshold| *threshold > NtpDuration::ZERO)
+            .min();
+        if let (Some(max_correction), Some(lowest)) =
+            (self.system.max_correction, lowest_panic_threshold)
+        {
+            if max_correction >= lowest {
+                diagnostics.push(Diagnostic::warning(
+                    "system.max-correction",
+                    format!("The maximum correction of {max_correction} is not below the panic threshold of {lowest}, so offsets it would limit make the daemon exit instead. Lower max-correction."),
+                ));
+            }
+        }
+    }
+
+    fn check_servers(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let mut seen: HashMap<SocketAddr, usize> = HashMap::new();
+        for (index, server) in self.servers.iter().enumerate() {
+            if server.policy == ServerPolicy::Strict && self.keys.path.is_none() {
+                diagnostics.push(Diagnostic::error(
+                    format!("servers[{index}].policy"),
+                    format!("Server {} has the strict policy, which only answers requests signed with a key, but there is no keys file to take keys from. Set keys.path, or use the standard policy.", server.addr),
+                ));
+            }
+
+            if server.control && server.policy == ServerPolicy::Strict {
+                diagnostics.push(Diagnostic::warning(
+                    format!("servers[{index}]"),
+                    format!("Control messages are enabled on server {}, which has the strict policy that drops them.", server.addr),
+                ));
+            }
+
+            match seen.get(&server.addr) {
+                Some(first) => diagnostics.push(Diagnostic::error(
+                    format!("servers[{index}]"),
+                    format!("{} is also the address of servers[{first}], and can only be bound once. Remove one of them.", server.addr),
+                )),
+                None => {
+                    seen.insert(server.addr, index);
+                }
+            }
+        }
+    }
+
+    fn check_paths(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let paths: [(&str, &Option<PathBuf>); 5] = [
+            ("observe.path", &self.observe.path),
+            ("configure.path", &self.configure.path),
+            ("chrony.path", &self.chrony.path),
+            ("export.path", &self.export.path),
+            ("state.path", &self.state.path),
+        ];
+
+        for (index, (location, path)) in paths.iter().enumerate() {
+            let path = match path {
+                Some(path) => path,
+                None => continue,
+            };
+
+            let earlier = paths[..index]
+                .iter()
+                .find(|(_, other)| other.as_ref() == Some(path));
+            if let Some((other, _)) = earlier {
+                diagnostics.push(Diagnostic::error(
+                    *location,
+                    format!("{path:?} is also used for {other}. Use a path of its own."),
+                ));
+            }
+        }
+    }
+
+    fn check_clock(&self, diagnostics: &mut Vec<Diagnostic>) {
+        self.check_timescale(diagnostics);
+
+        if self.clock.backend == ClockBackend::Measure {
+            self.check_measure(diagnostics);
+            return;
+        }
+
+        if self.clock.backend != ClockBackend::Namespace {
+            return;
+        }
+
+        // these compare against the system clock, which is not the one disciplined
+        for (index, refclock) in self.refclocks.iter().enumerate() {
+            if let RefClockConfig::Pps(_) | RefClockConfig::Phc(_) = refclock {
+                diagnostics.push(Diagnostic::error(
+                    format!("refclocks[{index}]"),
+                    format!("{} measures the system clock, which the namespace clock backend does not discipline.", refclock.description()),
+                ));
+            }
+        }
+
+        if !self.steered_phcs.is_empty() {
+            diagnostics.push(Diagnostic::warning(
+                "steered-phcs",
+                "PHCs are steered towards the system clock, which the namespace clock backend does not discipline.",
+            ));
+        }
+
+        if self.rtc.device.is_some() {
+            diagnostics.push(Diagnostic::warning(
+                "rtc.device",
+                "The RTC is set to the system clock, which the namespace clock backend does not discipline.",
+            ));
+        }
+
+        if self.clock.maintain_tai {
+            diagnostics.push(Diagnostic::warning(
+                "clock.maintain-tai",
+                "The TAI offset belongs to the system clock, and is not maintained with the namespace clock backend.",
+            ));
+        }
+    }
+
+    fn check_timescale(&self, diagnostics: &mut Vec<Diagnostic>) {
+        if self.clock.timescale == Timescale::Utc {
+            return;
+        }
+
+        if self.clock.timescale == Timescale::Tai && self.clock.maintain_tai {
+            diagnostics.push(Diagnostic::warning(
+                "clock.maintain-tai",
+                "A clock that keeps TAI has no offset to TAI, so it is not maintained.",
+            ));
+        }
+
+        if self.rtc.device.is_some() {
+            diagnostics.push(Diagnostic::warning(
+                "rtc.device",
+                "The RTC is set to the system clock, which does not keep UTC with this timescale.",
+            ));
+        }
+    }
+
+    fn check_measure(&self, diagnostics: &mut Vec<Diagnostic>) {
+        for index in 0..self.servers.len() {
+            diagnostics.push(Diagnostic::warning(
+                format!("servers[{index}]"),
+                "The measure clock backend never synchronizes the clock, so this server answers as unsynchronized.",
+            ));
+        }
+
+        if self.clock.maintain_tai {
+            diagnostics.push(Diagnostic::warning(
+                "clock.maintain-tai",
+                "The TAI offset is not maintained with the measure clock backend, which leaves the clock alone.",
+            ));
+        }
+    }
+
+    async fn check_resolved(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let mut seen: HashMap<SocketAddr, usize> = HashMap::new();
+        for (index, peer) in self.peers.iter().enumerate() {
+            let addresses: Vec<_> = match tokio::net::lookup_host(peer.address()).await {
+                Ok(addresses) => addresses.collect(),
+                Err(error) => {
+                    diagnostics.push(Diagnostic::error(
+                        format!("peers[{index}]"),
+                        format!("Could not resolve {}: {error}", peer.address()),
+                    ));
+                    continue;
+                }
+            };
+
+            // servers of a pool change all the time, so only a server that
+            // is in the answer right now is reported
+            let duplicate = addresses
+                .iter()
+                .find_map(|address| seen.get(address).map(|first| (address, first)));
+            match duplicate {
+                Some((address, first))
+                    if host(peer.address()) != host(self.peers[*first].address()) =>
+                {
+                    diagnostics.push(Diagnostic::warning(
+                        format!("peers[{index}]"),
+                        format!("{} resolves to {address}, as does peers[{first}], so that server may be used twice. Remove one of them.", peer.address()),
+                    ));
+                }
+                _ => {}
+            }
+
+            for address in addresses {
+                seen.entry(address).or_insert(index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostics(toml: &str) -> Vec<Diagnostic> {
+        let config: Config = toml::from_str(toml).unwrap();
+        config.diagnostics()
+    }
+
+    #[test]
+    fn test_host() {
+        assert_eq!(host("pool.ntp.org:123"), "pool.ntp.org");
+        assert_eq!(host("[2001:db8::1]:123"), "2001:db8::1");
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let peers = r#"
+            [[peers]]
+            addr = "0.example.com"
+            [[peers]]
+            addr = "1.example.com"
+            [[peers]]
+            addr = "2.example.com"
+        "#;
+        assert_eq!(diagnostics(peers), vec![]);
+
+        // a pool counts for all of its servers
+        let pool = "[[peers]]\nmode = \"pool\"\naddr = \"pool.example.com\"\nmax_peers = 4";
+        assert_eq!(diagnostics(pool), vec![]);
+
+        let found = diagnostics(&format!(
+            "{pool}\n[[peers]]\naddr = \"pool.example.com:123\""
+        ));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].severity, Severity::Warning);
+        assert_eq!(found[0].location, "peers[1]");
+
+        let found = diagnostics(&format!("{pool}\n[[peers]]\naddr = \"192.0.2.1\"\nkey = 1"));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].severity, Severity::Error);
+
+        let found = diagnostics(&format!("{peers}\n[system]\npanic-threshold = 0.1"));
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|d| d.severity == Severity::Error));
+        assert_eq!(found[0].location, "system.panic-threshold");
+
+        let found = diagnostics(&format!("{peers}\n[system]\nmax-candidates = 2"));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].severity, Severity::Error);
+        assert_eq!(found[0].location, "system.max-candidates");
+
+        // disabled in one direction, and zero, are fine
+        let found = diagnostics(&format!(
+            "{peers}\n[system.panic-threshold]\nforward = \"inf\"\nbackward = 0"
+        ));
+        assert_eq!(found, vec![]);
+
+        let found = diagnostics(&format!(
+            "{peers}\n[observe]\npath = \"/run/ntpd-rs/socket\"\n[configure]\npath = \"/run/ntpd-rs/socket\""
+        ));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].location, "configure.path");
+        assert_eq!(
+            found[0].to_string(),
+            "error[configure.path]: \"/run/ntpd-rs/socket\" is also used for observe.path. Use a path of its own."
+        );
+
+        let refclock = "[[refclocks]]\ndriver = \"nmea\"\ndevice = \"/dev/ttyS0\"";
+        let found = diagnostics(&format!(
+            "peers = []\n{refclock}\nstratum = 16\nrequire-corroboration = true"
+        ));
+        let locations: Vec<_> = found.iter().map(|d| d.location.as_str()).collect();
+        assert_eq!(
+            locations,
+            [
+                "system.min-intersection-survivors",
+                "refclocks[0].stratum",
+                "refclocks[0].require-corroboration"
+            ]
+        );
+        assert_eq!(
+            diagnostics(&format!(
+                "{refclock}\nrequire-corroboration = true\n{peers}"
+            )),
+            vec![]
+        );
+
+        let nat = "[[peers]]\naddr = \"3.example.com\"\nnat = { keepalive = 20 }";
+        assert_eq!(diagnostics(&format!("{peers}\n{nat}")), vec![]);
+        let found = diagnostics(&format!(
+            "{peers}\n{nat}\n[network]\nclient-sockets = \"per-request\""
+        ));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].location, "peers[3].nat");
+
+        // a strict server answers nothing without keys
+        let strict = "[[server]]\naddr = \"0.0.0.0:123\"\npolicy = \"strict\"";
+        let found = diagnostics(&format!("{peers}\n{strict}"));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].severity, Severity::Error);
+        assert_eq!(found[0].location, "servers[0].policy");
+        let keys = "[keys]\npath = \"/etc/ntpd-rs/keys.toml\"";
+        assert_eq!(diagnostics(&format!("{peers}\n{strict}\n{keys}")), vec![]);
+    }
+
+    #[test]
+    fn test_diagnostics_clock() {
+        let config = r#"
+            [[peers]]
+            addr = "0.example.com"
+            [[peers]]
+            addr = "1.example.com"
+            [[peers]]
+            addr = "2.example.com"
+            [[refclocks]]
+            driver = "pps"
+            device = "/dev/pps0"
+            [clock]
+            maintain-tai = true
+        "#;
+        assert_eq!(diagnostics(config), vec![]);
+
+        let found = diagnostics(&format!(
+            "{config}
+backend = \"namespace\""
+        ));
+        let locations: Vec<_> = found
+            .iter()
+            .map(|d| (d.severity, d.location.as_str()))
+            .collect();
+        assert_eq!(
+            locations,
+            [
+                (Severity::Error, "refclocks[0]"),
+                (Severity::Warning, "clock.maintain-tai")
+            ]
+        );
+
+        // measured sources need not agree, but servers have no time to serve
+        let found = diagnostics(
+            "[[peers]]\naddr = \"0.example.com\"\n[[server]]\naddr = \"0.0.0.0:123\"\n[clock]\nbackend = \"measure\"\nmaintain-tai = true",
+        );
+        let locations: Vec<_> = found.iter().map(|d| d.location.as_str()).collect();
+        assert_eq!(locations, ["servers[0]", "clock.maintain-tai"]);
+
+        // a clock that keeps TAI has no offset to maintain, nor an RTC in UTC
+        let found = diagnostics(&format!(
+            "[rtc]\ndevice = \"/dev/rtc0\"\n{config}\ntimescale = \"tai\""
+        ));
+        let locations: Vec<_> = found.iter().map(|d| d.location.as_str()).collect();
+        assert_eq!(locations, ["clock.maintain-tai", "rtc.device"]);
+    }
+
+    #[test]
+    fn test_diagnostics_source_groups() {
+        let groups = r#"
+            [[source-groups]]
+            name = "internal"
+            min-survivors = 2
+            [[source-groups]]
+            name = "public"
+        "#;
+        let peers = r#"
+            [[peers]]
+            addr = "0.example.com"
+            source-group = "internal"
+            [[peers]]
+            addr = "1.example.com"
+            source-group = "internal"
+            [[peers]]
+            mode = "pool"
+            addr = "pool.example.com"
+            max_peers = 4
+            source-group = "public"
+        "#;
+        assert_eq!(
+            diagnostics(&format!(
+                "{peers}
+{groups}"
+            )),
+            vec![]
+        );
+
+        let found = diagnostics(&format!(
+            "{peers}
+{groups}
+[[source-groups]]
+name = \"internal\"
+[[refclocks]]
+driver = \"pps\"
+device = \"/dev/pps0\"
+source-group = \"gnss\""
+        ));
+        let locations: Vec<_> = found.iter().map(|d| d.location.as_str()).collect();
+        assert_eq!(
+            locations,
+            ["source-groups[2].name", "refclocks[0].source-group"]
+        );
+        assert!(found.iter().all(|d| d.severity == Severity::Error));
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_resolved() {
+        let config: Config = toml::from_str(
+            r#"
+            [[peers]]
+            addr = "127.0.0.1"
+            [[peers]]
+            addr = "localhost:123"
+            [[peers]]
+            addr = "127.0.0.2"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.diagnose(false).await, vec![]);
+
+        // localhost may also resolve to ::1, but resolves to 127.0.0.1 as well
+        let found = config.diagnose(true).await;
+        if !found.is_empty() {
+            assert_eq!(found[0].location, "peers[1]");
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::path::PathBuf;
+
+use ntp_proto::NtpDuration;
+use serde::Deserialize;
+
+fn default_leap_seconds_file() -> PathBuf {
+    PathBuf::from("/usr/share/zoneinfo/leap-seconds.list")
+}
+
+fn default_monitor_fallback() -> bool {
+    true
+}
+
+/// The clock that is disciplined
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClockBackend {
+    /// The clock of the system, `CLOCK_REALTIME`, through the kernel
+    #[default]
+    System,
+    /// A clock of the daemon itself, on top of `CLOCK_BOOTTIME` of the time
+    /// namespace it runs in. The clock of the system is left alone.
+    Namespace,
+    /// The clock of the system, which is measured but never adjusted
+    Monitor,
+    /// No clock at all: the sources are measured, without clock selection,
+    /// for monitoring the sources themselves
+    Measure,
+}
+
+/// The timescale of the clock, while NTP itself always carries UTC
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Timescale {
+    #[default]
+    Utc,
+    /// International atomic time, ahead of UTC by the leap seconds so far,
+    /// as taken from the leap seconds file. The clock does not insert leap
+    /// seconds.
+    Tai,
+    /// UTC plus a fixed offset. Leap seconds are applied at midnight UTC by
+    /// stepping the clock, as the kernel would insert them at midnight of the
+    /// clock.
+    UtcOffset(NtpDuration),
+}
+
+/// Which clocks are kept
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ClockConfig {
+    #[serde(default)]
+    pub backend: ClockBackend,
+    /// Keep the offset of TAI to UTC in the kernel, and with it
+    /// `CLOCK_TAI`, up to date from the leap seconds file
+    #[serde(default)]
+    pub maintain_tai: bool,
+    /// The list of leap seconds as published by the IERS, in the format of
+    /// `leap-seconds.list`
+    #[serde(default = "default_leap_seconds_file")]
+    pub leap_seconds_file: PathBuf,
+    /// Only monitor the system clock when the daemon may not adjust it
+    #[serde(default = "default_monitor_fallback")]
+    pub monitor_fallback: bool,
+    /// The timescale the clock keeps
+    #[serde(default)]
+    pub timescale: Timescale,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            backend: ClockBackend::default(),
+            maintain_tai: false,
+            leap_seconds_file: default_leap_seconds_file(),
+            monitor_fallback: default_monitor_fallback(),
+            timescale: Timescale::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    struct TestConfig {
+        clock: ClockConfig,
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let test: TestConfig = toml::from_str(
+            r#"
+            [clock]
+            backend = "namespace"
+            maintain-tai = true
+            leap-seconds-file = "/etc/leap-seconds.list"
+            monitor-fallback = false
+            timescale = "tai"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.clock,
+            ClockConfig {
+                backend: ClockBackend::Namespace,
+                maintain_tai: true,
+                leap_seconds_file: PathBuf::from("/etc/leap-seconds.list"),
+                monitor_fallback: false,
+                timescale: Timescale::Tai,
+            }
+        );
+
+        let test: TestConfig = toml::from_str("[clock]").unwrap();
+        assert_eq!(test.clock, ClockConfig::default());
+
+        let test: TestConfig = toml::from_str("[clock]\nbackend = \"monitor\"").unwrap();
+        assert_eq!(test.clock.backend, ClockBackend::Monitor);
+
+        let test: TestConfig = toml::from_str("[clock]\nbackend = \"measure\"").unwrap();
+        assert_eq!(test.clock.backend, ClockBackend::Measure);
+
+        assert!(toml::from_str::<TestConfig>("[clock]\nbackend = \"tai\"").is_err());
+
+        let test: TestConfig =
+            toml::from_str("[clock]\ntimescale = { utc-offset = -3600 }").unwrap();
+        assert_eq!(
+            test.clock.timescale,
+            Timescale::UtcOffset(NtpDuration::from_seconds(-3600.0))
+        );
+    }
+}
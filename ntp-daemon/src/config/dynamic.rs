@@ -1,7 +1,7 @@
+use crate::server::{Drain, DrainMode};
 use crate::sockets::create_unix_socket;
 use crate::tracing::ReloadHandle;
 use ntp_proto::{NtpDuration, StepThreshold, SystemConfig};
-use std::os::unix::fs::PermissionsExt;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
@@ -36,6 +36,13 @@ pub struct ConfigUpdate {
     /// during startup, use startup_panic_threshold
     #[arg(long)]
     pub panic_threshold: Option<f64>,
+
+    /// Drain the servers on anycast addresses before their route is removed,
+    /// by answering with kiss codes or by no longer answering. `off` serves
+    /// as usual again
+    #[arg(long, value_enum)]
+    #[serde(default)]
+    pub drain: Option<DrainMode>,
 }
 
 // Deal with reloading not being possible during testing.
@@ -52,10 +59,11 @@ impl LogReloader for ReloadHandle {
 pub async fn spawn<H: LogReloader + Send + 'static>(
     config: ConfigureConfig,
     system_config: Arc<RwLock<SystemConfig>>,
+    drain: Drain,
     log_reload_handle: H,
 ) -> JoinHandle<std::io::Result<()>> {
     tokio::spawn(async move {
-        let result = dynamic_configuration(config, system_config, log_reload_handle).await;
+        let result = dynamic_configuration(config, system_config, drain, log_reload_handle).await;
         if let Err(ref e) = result {
             error!("Abnormal termination of dynamic configurator: {}", e);
         }
@@ -66,6 +74,7 @@ pub async fn spawn<H: LogReloader + Send + 'static>(
 async fn dynamic_configuration<H: LogReloader>(
     config: ConfigureConfig,
     system_config: Arc<RwLock<SystemConfig>>,
+    drain: Drain,
     log_reload_handle: H,
 ) -> std::io::Result<()> {
     let path = match config.path {
@@ -73,13 +82,7 @@ async fn dynamic_configuration<H: LogReloader>(
         None => return Ok(()),
     };
 
-    let peers_listener = create_unix_socket(&path)?;
-
-    // this binary needs to run as root to be able to adjust the system clock.
-    // by default, the socket inherits root permissions, but the client should not need
-    // elevated permissions to read from the socket. So we explicitly set the permissions
-    let permissions: std::fs::Permissions = PermissionsExt::from_mode(config.mode);
-    std::fs::set_permissions(&path, permissions)?;
+    let peers_listener = create_unix_socket(&path, config.mode)?;
 
     let mut msg = Vec::with_capacity(16 * 1024);
 
@@ -94,6 +97,11 @@ async fn dynamic_configuration<H: LogReloader>(
             log_reload_handle.update_log(EnvFilter::new(filter));
         }
 
+        if let Some(mode) = operation.drain {
+            tracing::info!(?mode, "changed drain mode of the anycast servers");
+            drain.set(mode);
+        }
+
         let mut config = system_config.write().await;
 
         if let Some(panic_threshold) = operation.panic_threshold {
@@ -129,7 +137,8 @@ mod tests {
             mode: 0o700,
         };
 
-        let handle = spawn(config, system_config, TestLogReloader {}).await;
+        let drain = Drain::default();
+        let handle = spawn(config, system_config, drain.clone(), TestLogReloader {}).await;
 
         // Ensure client has started.
         tokio::time::sleep(Duration::from_millis(10)).await;
@@ -141,6 +150,7 @@ mod tests {
             &ConfigUpdate {
                 log_filter: Some("info".into()),
                 panic_threshold: Some(600.),
+                drain: Some(DrainMode::Withdraw),
             },
         )
         .await
@@ -153,6 +163,7 @@ mod tests {
             system_config_test.read().await.panic_threshold.forward,
             Some(NtpDuration::from_seconds(600.))
         );
+        assert_eq!(drain.mode(), DrainMode::Withdraw);
 
         handle.abort();
     }
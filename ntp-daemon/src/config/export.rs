@@ -0,0 +1,54 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+/// Format of the exported measurements
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportFormat {
+    /// One JSON object per line
+    #[default]
+    JsonLines,
+    /// Comma separated values, with a header line
+    Csv,
+}
+
+/// Where to export the timestamps of every exchange with a peer
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ExportConfig {
+    /// File, FIFO or unix stream socket to write to. Disabled when `None`
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    struct TestConfig {
+        export: ExportConfig,
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let test: TestConfig = toml::from_str("[export]").unwrap();
+        assert_eq!(test.export, ExportConfig::default());
+
+        let test: TestConfig =
+            toml::from_str("[export]\npath = \"/run/ntpd-rs/exchanges\"\nformat = \"csv\"")
+                .unwrap();
+        assert_eq!(
+            test.export,
+            ExportConfig {
+                path: Some(PathBuf::from("/run/ntpd-rs/exchanges")),
+                format: ExportFormat::Csv,
+            }
+        );
+
+        assert!(toml::from_str::<TestConfig>("[export]\nformat = \"xml\"").is_err());
+    }
+}
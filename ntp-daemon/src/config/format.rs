@@ -2,7 +2,7 @@ use std::str::FromStr;
 
 use serde::Deserialize;
 use thiserror::Error;
-use tracing::Subscriber;
+use tracing::{Level, Subscriber};
 use tracing_subscriber::{
     field::RecordFields,
     fmt::{
@@ -86,6 +86,43 @@ where
     }
 }
 
+/// Event format of the daemon: the configured [`LogFormat`], optionally
+/// prefixed with the syslog priority of the event as understood by the
+/// systemd journal (see `sd-daemon(3)`).
+#[derive(Debug, Clone)]
+pub struct EventFormat {
+    pub format: LogFormat,
+    pub journald: bool,
+}
+
+fn syslog_priority(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for EventFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &tracing_subscriber::fmt::FmtContext<'_, S, N>,
+        mut writer: tracing_subscriber::fmt::format::Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        if self.journald {
+            write!(writer, "<{}>", syslog_priority(event.metadata().level()))?;
+        }
+
+        self.format.format_event(ctx, writer, event)
+    }
+}
+
 pub enum LogFormatFields {
     Json(JsonFields),
     Pretty(PrettyFields),
@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+
+use serde::{de, Deserialize, Deserializer};
+use tracing_subscriber::filter::Directive;
+
+fn deserialize_targets<'de, D>(deserializer: D) -> Result<Vec<Directive>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let targets: BTreeMap<String, String> = Deserialize::deserialize(deserializer)?;
+
+    targets
+        .iter()
+        .map(|(target, level)| {
+            format!("{}={}", target, level)
+                .parse()
+                .map_err(de::Error::custom)
+        })
+        .collect()
+}
+
+/// Logging options on top of the `log-filter` and `log-format`
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Levels for specific targets (modules) or spans, added to the log filter
+    #[serde(deserialize_with = "deserialize_targets", default)]
+    pub targets: Vec<Directive>,
+    /// Prefix log lines with their syslog priority, for when the output is
+    /// read by the systemd journal
+    #[serde(default)]
+    pub journald: bool,
+    /// Log the bytes of at most this many rejected packets per minute, none
+    /// at all when 0
+    #[serde(default)]
+    pub rejected_packets: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    struct TestConfig {
+        logging: LoggingConfig,
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let test: TestConfig = toml::from_str("[logging]").unwrap();
+        assert!(test.logging.targets.is_empty());
+        assert!(!test.logging.journald);
+        assert_eq!(test.logging.rejected_packets, 0);
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [logging]
+            journald = true
+            rejected-packets = 10
+            targets = { "ntp_proto::peer" = "debug", "[peer{addr=192.0.2.1:123}]" = "trace" }
+            "#,
+        )
+        .unwrap();
+        assert!(test.logging.journald);
+        assert_eq!(test.logging.rejected_packets, 10);
+        let targets: Vec<_> = test.logging.targets.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            targets,
+            ["[peer{addr=192.0.2.1:123}]=trace", "ntp_proto::peer=debug"]
+        );
+
+        assert!(toml::from_str::<TestConfig>("[logging.targets]\nntp_proto = \"loud\"").is_err());
+        assert!(toml::from_str::<TestConfig>("[logging]\nsyslog = true").is_err());
+    }
+}
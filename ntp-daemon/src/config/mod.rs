@@ -1,23 +1,43 @@
+mod check;
+mod clock;
 pub mod dynamic;
+mod export;
 pub mod format;
+mod logging;
 mod peer;
+mod refclock;
+mod roughtime;
+mod rtc;
 mod server;
+mod statistics;
+mod steering;
 pub mod subnet;
 
+pub use check::*;
+pub use clock::*;
+pub use export::*;
+pub use logging::*;
 pub use peer::*;
+pub use refclock::*;
+pub use roughtime::*;
+pub use rtc::*;
 pub use server::*;
+pub use statistics::*;
+pub use steering::*;
 
 use clap::Parser;
-use ntp_proto::SystemConfig;
+use ntp_proto::{NtpDuration, SystemConfig};
 use serde::{de, Deserialize, Deserializer};
 use std::{
     io::ErrorKind,
+    net::SocketAddr,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 use thiserror::Error;
 use tokio::{fs::read_to_string, io};
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 use tracing_subscriber::filter::EnvFilter;
 
 use self::format::LogFormat;
@@ -97,6 +117,19 @@ pub struct CmdArgs {
         help = "Override the servers to run from the configuration file"
     )]
     pub servers: Vec<ServerConfig>,
+
+    #[arg(
+        long,
+        help = "Check the configuration for problems and exit, with a nonzero exit code on errors"
+    )]
+    pub check_config: bool,
+
+    #[arg(
+        long,
+        requires = "check_config",
+        help = "Also resolve the addresses of the peers when checking the configuration"
+    )]
+    pub resolve: bool,
 }
 
 #[derive(Deserialize, Debug, Default)]
@@ -106,19 +139,59 @@ pub struct Config {
     pub peers: Vec<PeerConfig>,
     #[serde(alias = "server", default)]
     pub servers: Vec<ServerConfig>,
+    #[serde(alias = "refclock", default)]
+    pub refclocks: Vec<RefClockConfig>,
+    /// Groups of sources in order of preference
+    #[serde(alias = "source-group", default)]
+    pub source_groups: Vec<SourceGroupConfig>,
+    #[serde(alias = "steered-phc", default)]
+    pub steered_phcs: Vec<PhcSteeringConfig>,
     #[serde(default)]
     pub system: SystemConfig,
     #[serde(deserialize_with = "deserialize_option_env_filter", default)]
     pub log_filter: Option<EnvFilter>,
     #[serde(default)]
     pub log_format: LogFormat,
+    #[serde(default)]
+    pub logging: LoggingConfig,
     #[cfg(feature = "sentry")]
     #[serde(default)]
     pub sentry: SentryConfig,
+    #[cfg(feature = "otlp")]
+    #[serde(default)]
+    pub otlp: OtlpConfig,
     #[serde(default)]
     pub observe: ObserveConfig,
     #[serde(default)]
     pub configure: ConfigureConfig,
+    #[serde(default)]
+    pub chrony: ChronyConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub statistics: StatisticsConfig,
+    #[serde(default)]
+    pub export: ExportConfig,
+    #[serde(default)]
+    pub state: StateConfig,
+    #[serde(default)]
+    pub keys: KeysConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub systemd: SystemdConfig,
+    #[serde(default)]
+    pub privileges: PrivilegesConfig,
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+    #[serde(default)]
+    pub network: NetworkConfig,
+    #[serde(default)]
+    pub roughtime: RoughtimeConfig,
+    #[serde(default)]
+    pub rtc: RtcConfig,
+    #[serde(default)]
+    pub clock: ClockConfig,
 }
 
 const fn default_observe_permissions() -> u32 {
@@ -163,6 +236,236 @@ impl Default for ConfigureConfig {
     }
 }
 
+const fn default_chrony_permissions() -> u32 {
+    0o777
+}
+
+/// Socket on which chrony monitoring commands are answered
+#[derive(Clone, Deserialize, Debug)]
+pub struct ChronyConfig {
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default = "default_chrony_permissions")]
+    pub mode: u32,
+}
+
+impl Default for ChronyConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            mode: default_chrony_permissions(),
+        }
+    }
+}
+
+/// HTTP endpoints for liveness and readiness probes
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HealthConfig {
+    #[serde(default)]
+    pub listen: Option<SocketAddr>,
+    /// Largest offset of the clock at which the daemon is ready
+    #[serde(default = "default_health_max_offset")]
+    pub max_offset: NtpDuration,
+    /// Largest root distance at which the daemon is ready
+    #[serde(default = "default_health_max_root_distance")]
+    pub max_root_distance: NtpDuration,
+}
+
+fn default_health_max_offset() -> NtpDuration {
+    NtpDuration::from_seconds(0.1)
+}
+
+fn default_health_max_root_distance() -> NtpDuration {
+    NtpDuration::from_seconds(1.5)
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            max_offset: default_health_max_offset(),
+            max_root_distance: default_health_max_root_distance(),
+        }
+    }
+}
+
+/// State that is kept across restarts of the daemon
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct StateConfig {
+    /// File to keep the state in. Nothing is kept when not set
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+/// Symmetric keys with which peers and clients are authenticated
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct KeysConfig {
+    /// The keys file, which is read again when it changes
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+/// What the daemon does when it stops
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Keep the clock running at its current frequency, without the kernel
+    /// discipline
+    #[serde(default)]
+    pub hold_frequency: bool,
+}
+
+const fn default_min_survivors() -> usize {
+    1
+}
+
+/// A named group of sources. The sources of a group are only used when fewer
+/// sources of the groups before it survive clock selection than that group
+/// requires
+#[derive(Clone, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SourceGroupConfig {
+    pub name: String,
+    /// Fewest survivors of clock selection among the sources of this group
+    /// and the groups before it, below which the next group is used as well
+    #[serde(default = "default_min_survivors")]
+    pub min_survivors: usize,
+}
+
+fn deserialize_seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let seconds: f64 = Deserialize::deserialize(deserializer)?;
+    if seconds.is_finite() && seconds >= 0.0 {
+        Ok(Duration::from_secs_f64(seconds))
+    } else {
+        Err(de::Error::invalid_value(
+            de::Unexpected::Float(seconds),
+            &"a non-negative number of seconds",
+        ))
+    }
+}
+
+const fn default_ready_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Interaction with systemd, when built with the `systemd` feature
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SystemdConfig {
+    /// Report readiness after this time, even when the clock is not yet synchronized
+    #[serde(
+        deserialize_with = "deserialize_seconds",
+        default = "default_ready_timeout"
+    )]
+    pub ready_timeout: Duration,
+}
+
+impl Default for SystemdConfig {
+    fn default() -> Self {
+        Self {
+            ready_timeout: default_ready_timeout(),
+        }
+    }
+}
+
+/// Unprivileged user and group to run as once the daemon has started
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PrivilegesConfig {
+    /// Name of the user to change to. Privileges are only dropped when set
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Name of the group to change to, by default the primary group of the user
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+/// What to do with syscalls outside of those needed by the daemon
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SandboxMode {
+    /// Do not install a syscall filter
+    #[default]
+    Disabled,
+    /// Allow the syscalls, but log them to the kernel audit log
+    Log,
+    /// Kill the daemon
+    Enforce,
+}
+
+/// Restriction of the daemon to the syscalls it needs, with seccomp
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SandboxConfig {
+    #[serde(default)]
+    pub mode: SandboxMode,
+}
+
+fn deserialize_dscp<'de, D>(deserializer: D) -> Result<Option<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let dscp: Option<u8> = Deserialize::deserialize(deserializer)?;
+    match dscp {
+        Some(dscp) if dscp > 63 => Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(dscp as u64),
+            &"a DSCP value from 0 to 63",
+        )),
+        dscp => Ok(dscp),
+    }
+}
+
+/// How peers use their sockets
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientSockets {
+    /// A single socket, and so a single source port, for the lifetime of the peer
+    #[default]
+    Persistent,
+    /// A new socket, with a new random source port, for every request
+    PerRequest,
+}
+
+/// Marking of the packets sent by peers and servers, for quality of service,
+/// and the sockets used by peers
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// DSCP value of outgoing packets, left to the system when not set
+    #[serde(default, deserialize_with = "deserialize_dscp")]
+    pub dscp: Option<u8>,
+    /// Priority of outgoing packets within the host (`SO_PRIORITY`)
+    #[serde(default)]
+    pub priority: Option<u32>,
+    /// Whether peers keep their socket, or open a new one for every request
+    #[serde(default)]
+    pub client_sockets: ClientSockets,
+}
+
+impl NetworkConfig {
+    /// Apply the marking to a socket. Failures are logged, as the socket is
+    /// still usable without the marking.
+    pub fn apply(&self, socket: &ntp_udp::UdpSocket) {
+        if let Some(dscp) = self.dscp {
+            if let Err(error) = socket.set_dscp(dscp) {
+                warn!(?error, dscp, "Could not set the DSCP value of a socket");
+            }
+        }
+
+        if let Some(priority) = self.priority {
+            if let Err(error) = socket.set_priority(priority) {
+                warn!(?error, priority, "Could not set the priority of a socket");
+            }
+        }
+    }
+}
+
 #[cfg(feature = "sentry")]
 #[derive(Deserialize, Debug, Default)]
 #[serde(rename_all = "kebab-case")]
@@ -177,6 +480,40 @@ fn default_sample_rate() -> f32 {
     0.0
 }
 
+#[cfg(feature = "otlp")]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// Base url of the OTLP/HTTP receiver of the collector, e.g. `http://localhost:4318`
+    pub endpoint: Option<String>,
+    /// Seconds between pushes
+    #[serde(default = "default_otlp_interval")]
+    pub interval: u64,
+    #[serde(default = "default_otlp_service_name")]
+    pub service_name: String,
+}
+
+#[cfg(feature = "otlp")]
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        OtlpConfig {
+            endpoint: None,
+            interval: default_otlp_interval(),
+            service_name: default_otlp_service_name(),
+        }
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn default_otlp_interval() -> u64 {
+    60
+}
+
+#[cfg(feature = "otlp")]
+fn default_otlp_service_name() -> String {
+    "ntpd-rs".into()
+}
+
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("io error while reading config: {0}")]
@@ -245,19 +582,21 @@ impl Config {
         Ok(config)
     }
 
-    /// Check that the config is reasonable. This function may panic if the
-    /// configuration is egregious, although it doesn't do so currently.
+    /// Check that the config is reasonable, logging the problems found. The
+    /// daemon runs regardless; `--check-config` reports the same problems
+    /// without starting it.
     pub fn check(&self) {
         // Note: since we only check once logging is fully configured,
-        // using those fields should always work. This is also
-        // probably a good policy in general (config should always work
-        // but we may panic here to protect the user from themselves)
-        if self.peers.is_empty() {
-            warn!("No peers configured. Daemon will not do anything.");
-        }
-
-        if self.peers.len() < self.system.min_intersection_survivors {
-            warn!("Fewer peers configured than are required to agree on the current time. Daemon will not do anything.");
+        // using those fields should always work.
+        for diagnostic in self.diagnostics() {
+            match diagnostic.severity {
+                Severity::Warning => {
+                    warn!(option = diagnostic.location, "{}", diagnostic.message)
+                }
+                Severity::Error => {
+                    error!(option = diagnostic.location, "{}", diagnostic.message)
+                }
+            }
         }
     }
 }
@@ -277,6 +616,16 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
             })]
         );
 
@@ -287,6 +636,16 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
             })]
         );
 
@@ -297,6 +656,16 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
             })]
         );
 
@@ -307,6 +676,16 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
             })]
         );
         assert_eq!(
@@ -326,11 +705,69 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
             })]
         );
         assert!(config.system.panic_threshold.forward.is_none());
         assert!(config.system.panic_threshold.backward.is_none());
 
+        let config: Config = toml::from_str(
+            "[[peers]]\naddr = \"example.com\"\n[system]\nfrequency-tolerance = 2.5",
+        )
+        .unwrap();
+        assert_eq!(config.system.frequency_tolerance.to_ppm(), 2.5);
+        assert_eq!(config.system.filter_max_age, None);
+
+        let config: Config =
+            toml::from_str("[[peers]]\naddr = \"example.com\"\n[system]\nfilter-max-age = 7200")
+                .unwrap();
+        assert_eq!(
+            config.system.filter_max_age,
+            Some(NtpDuration::from_seconds(7200.))
+        );
+
+        // durations can also be given with a unit
+        let config: Config = toml::from_str(
+            "[[peers]]\naddr = \"example.com\"\n[system]\nfilter-max-age = \"7200s\"\npanic-threshold = \"500ms\"",
+        )
+        .unwrap();
+        assert_eq!(
+            config.system.filter_max_age,
+            Some(NtpDuration::from_seconds(7200.))
+        );
+        assert_eq!(
+            config.system.panic_threshold.forward,
+            Some("0.5".parse().unwrap())
+        );
+
+        assert_eq!(
+            config.system.max_reference_age.for_stratum(2),
+            Some(NtpDuration::from_seconds(3600.))
+        );
+        let config: Config = toml::from_str(
+            "[[peers]]\naddr = \"example.com\"\n[system.max-reference-age]\nprimary = 172800\nsecondary = 0",
+        )
+        .unwrap();
+        assert_eq!(
+            config.system.max_reference_age.for_stratum(1),
+            Some(NtpDuration::from_seconds(172800.))
+        );
+        assert_eq!(config.system.max_reference_age.for_stratum(2), None);
+
+        assert!(toml::from_str::<Config>(
+            "[[peers]]\naddr = \"example.com\"\n[system]\nfrequency-tolerance = -1",
+        )
+        .is_err());
+
         let config: Config = toml::from_str(
             r#"
             log-filter = "info"
@@ -360,10 +797,104 @@ mod tests {
             config.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("example.com:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
             })]
         );
     }
 
+    #[test]
+    fn test_health_config() {
+        let config: Config = toml::from_str("[[peers]]\naddr = \"example.com\"").unwrap();
+        assert_eq!(config.health, HealthConfig::default());
+
+        let config: Config = toml::from_str(
+            "[[peers]]\naddr = \"example.com\"\n[health]\nlisten = \"[::]:8080\"\nmax-offset = 0.01",
+        )
+        .unwrap();
+        assert_eq!(config.health.listen, Some("[::]:8080".parse().unwrap()));
+        assert_eq!(config.health.max_offset, NtpDuration::from_seconds(0.01));
+        assert_eq!(
+            config.health.max_root_distance,
+            NtpDuration::from_seconds(1.5)
+        );
+    }
+
+    #[test]
+    fn test_state_config() {
+        let config: Config = toml::from_str("[[peers]]\naddr = \"example.com\"").unwrap();
+        assert_eq!(config.state.path, None);
+
+        let config: Config = toml::from_str(
+            "[[peers]]\naddr = \"example.com\"\n[state]\npath = \"/var/lib/ntpd-rs/state.json\"",
+        )
+        .unwrap();
+        assert_eq!(
+            config.state.path,
+            Some(PathBuf::from("/var/lib/ntpd-rs/state.json"))
+        );
+    }
+
+    #[test]
+    fn test_keys_config() {
+        let config: Config = toml::from_str(
+            "[[peers]]\naddr = \"example.com\"\nkey = 3\n[keys]\npath = \"/etc/ntpd-rs/keys.toml\"",
+        )
+        .unwrap();
+        assert_eq!(config.peers[0].key(), Some(3));
+        assert_eq!(
+            config.keys.path,
+            Some(PathBuf::from("/etc/ntpd-rs/keys.toml"))
+        );
+
+        // key id 0 is reserved, and pools are not authenticated
+        assert!(toml::from_str::<Config>("[[peers]]\naddr = \"example.com\"\nkey = 0").is_err());
+        assert!(toml::from_str::<Config>(
+            "[[peers]]\nmode = \"pool\"\naddr = \"pool.example.com\"\nkey = 3"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_shutdown_config() {
+        let config: Config = toml::from_str("[[peers]]\naddr = \"example.com\"").unwrap();
+        assert!(!config.shutdown.hold_frequency);
+
+        let config: Config =
+            toml::from_str("[[peers]]\naddr = \"example.com\"\n[shutdown]\nhold-frequency = true")
+                .unwrap();
+        assert!(config.shutdown.hold_frequency);
+    }
+
+    #[test]
+    fn test_network_config() {
+        let config: Config = toml::from_str("[[peers]]\naddr = \"example.com\"").unwrap();
+        assert_eq!(config.network, NetworkConfig::default());
+
+        let config: Config =
+            toml::from_str("[[peers]]\naddr = \"example.com\"\n[network]\ndscp = 46\npriority = 6")
+                .unwrap();
+        assert_eq!(config.network.dscp, Some(46));
+        assert_eq!(config.network.priority, Some(6));
+        assert_eq!(config.network.client_sockets, ClientSockets::Persistent);
+
+        let config: Config = toml::from_str(
+            "[[peers]]\naddr = \"example.com\"\n[network]\nclient-sockets = \"per-request\"",
+        )
+        .unwrap();
+        assert_eq!(config.network.client_sockets, ClientSockets::PerRequest);
+
+        assert!(toml::from_str::<Config>("[network]\ndscp = 64").is_err());
+    }
+
     #[cfg(feature = "sentry")]
     #[test]
     fn test_sentry_config() {
@@ -382,6 +913,25 @@ mod tests {
         assert!((config.sentry.sample_rate - 0.5).abs() < 1e-9);
     }
 
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_otlp_config() {
+        let config: Config = toml::from_str("[[peers]]\naddr = \"example.com\"").unwrap();
+        assert!(config.otlp.endpoint.is_none());
+        assert_eq!(config.otlp.interval, 60);
+
+        let config: Config = toml::from_str(
+            "[[peers]]\naddr = \"example.com\"\n[otlp]\nendpoint = \"http://localhost:4318\"\ninterval = 10",
+        )
+        .unwrap();
+        assert_eq!(
+            config.otlp.endpoint.as_deref(),
+            Some("http://localhost:4318")
+        );
+        assert_eq!(config.otlp.interval, 10);
+        assert_eq!(config.otlp.service_name, "ntpd-rs");
+    }
+
     #[tokio::test]
     async fn test_file_config() {
         let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -492,6 +1042,16 @@ mod tests {
             parsed_empty.peers,
             vec![PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("foo.nl:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
             })]
         );
         assert!(parsed_empty.config.is_none());
@@ -505,9 +1065,29 @@ mod tests {
             vec![
                 PeerConfig::Standard(StandardPeerConfig {
                     addr: NormalizedAddress::new_unchecked("foo.rs:123"),
+                    bind: Default::default(),
+                    max_offset: None,
+                    poll: Default::default(),
+                    delay: Default::default(),
+                    version: Default::default(),
+                    parsing: Default::default(),
+                    association: Default::default(),
+                    dual_stack: false,
+                    key: None,
+                    source_group: None,
                 }),
                 PeerConfig::Standard(StandardPeerConfig {
                     addr: NormalizedAddress::new_unchecked("spam.nl:123"),
+                    bind: Default::default(),
+                    max_offset: None,
+                    poll: Default::default(),
+                    delay: Default::default(),
+                    version: Default::default(),
+                    parsing: Default::default(),
+                    association: Default::default(),
+                    dual_stack: false,
+                    key: None,
+                    source_group: None,
                 }),
             ]
         );
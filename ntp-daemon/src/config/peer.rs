@@ -1,10 +1,21 @@
-use std::{fmt, net::SocketAddr};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use ntp_proto::{
+    AssociationMode, NtpDuration, NtpVersion, ParsingPolicy, PollInterval, PollIntervalLimits,
+};
 use serde::{
     de::{self, MapAccess, Visitor},
     Deserialize, Deserializer,
 };
 
+use super::{deserialize_seconds, subnet::IpSubnet, ClientSockets};
+
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
 pub enum PeerHostMode {
     #[serde(alias = "server")]
@@ -19,18 +30,295 @@ impl Default for PeerHostMode {
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+/// The local end of the connection to a peer, chosen by the system when not set
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct PeerBindConfig {
+    /// Network interface to send and receive on (`SO_BINDTODEVICE`)
+    pub interface: Option<String>,
+    /// Local address to send from
+    pub source_address: Option<IpAddr>,
+    /// SOCKS5 proxy that relays the packets to and from the peer
+    pub socks5: Option<Socks5Config>,
+    /// Network namespace to open the socket in, such as a management network
+    /// on a router
+    pub network_namespace: Option<PathBuf>,
+    /// How the connection is kept up through NATs
+    #[serde(default)]
+    pub nat: PeerNatConfig,
+}
+
+impl PeerBindConfig {
+    /// Whether packets from the local address can reach the peer address
+    pub fn matches_family(&self, peer: &SocketAddr) -> bool {
+        match (self.source_address, &self.socks5) {
+            // the proxy reaches the peer, whatever the family of its relay
+            (_, Some(_)) => true,
+            (Some(source), None) => source.is_ipv4() == peer.is_ipv4(),
+            (None, None) => true,
+        }
+    }
+}
+
+/// How the connection to a peer is kept up through NATs, which may forget
+/// the mapping of a UDP flow after a short idle time
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PeerNatConfig {
+    /// Whether the peer keeps its socket, replacing `network.client-sockets`
+    #[serde(default)]
+    pub client_sockets: Option<ClientSockets>,
+    /// Whether the socket is connected to the peer, such that only packets
+    /// from its address and port are received
+    #[serde(default = "default_connected")]
+    pub connected: bool,
+    /// Longest time without sending to the peer, after which an empty packet
+    /// is sent to keep the mapping of the NAT
+    #[serde(default, deserialize_with = "deserialize_keepalive")]
+    pub keepalive: Option<Duration>,
+    /// Number of requests in a row that went unanswered, after which the
+    /// socket is replaced and the peer polls in a burst
+    #[serde(default)]
+    pub reopen_after: Option<u32>,
+}
+
+fn default_connected() -> bool {
+    true
+}
+
+fn deserialize_keepalive<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let keepalive = deserialize_seconds(deserializer)?;
+    if keepalive.is_zero() {
+        Err(de::Error::invalid_value(
+            de::Unexpected::Float(0.0),
+            &"a positive number of seconds",
+        ))
+    } else {
+        Ok(Some(keepalive))
+    }
+}
+
+impl Default for PeerNatConfig {
+    fn default() -> Self {
+        PeerNatConfig {
+            client_sockets: None,
+            connected: true,
+            keepalive: None,
+            reopen_after: None,
+        }
+    }
+}
+
+impl PeerNatConfig {
+    /// Whether the peer keeps using one socket, with `network.client-sockets`
+    /// as the default
+    pub fn persistent(&self, default: ClientSockets) -> bool {
+        self.client_sockets.unwrap_or(default) == ClientSockets::Persistent
+    }
+
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.reopen_after == Some(0) {
+            return Err("reopen-after must be at least 1");
+        }
+
+        let per_request = self.client_sockets == Some(ClientSockets::PerRequest);
+        if per_request && (self.keepalive.is_some() || self.reopen_after.is_some()) {
+            return Err("keepalive and reopen-after need a persistent client socket");
+        }
+
+        Ok(())
+    }
+}
+
+/// Where `ip netns` keeps the namespaces it names
+const NAMED_NETWORK_NAMESPACES: &str = "/run/netns";
+
+/// The file of a network namespace, given by name or by path
+fn network_namespace_path(namespace: &str) -> Option<PathBuf> {
+    let path = Path::new(namespace);
+    if path.is_absolute() {
+        Some(path.to_path_buf())
+    } else if !namespace.is_empty()
+        && !namespace.contains(['/', '\0'])
+        && !matches!(namespace, "." | "..")
+    {
+        Some(Path::new(NAMED_NETWORK_NAMESPACES).join(namespace))
+    } else {
+        None
+    }
+}
+
+/// A SOCKS5 proxy (RFC 1928), through which packets are relayed with UDP associate
+#[derive(Deserialize, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Socks5Config {
+    /// Host and port of the proxy
+    pub addr: String,
+    /// Username and password (RFC 1929), when the proxy requires them
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Socks5Config {
+    fn validate(&self) -> Result<(), &'static str> {
+        let has_port = matches!(
+            self.addr.rsplit_once(':'),
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok()
+        );
+        if !has_port {
+            return Err("the address of a SOCKS5 proxy must include its port");
+        }
+
+        match (&self.username, &self.password) {
+            (None, None) => Ok(()),
+            (Some(username), Some(password)) => {
+                let valid = |value: &String| (1..=255).contains(&value.len());
+                if valid(username) && valid(password) {
+                    Ok(())
+                } else {
+                    Err("a SOCKS5 username and password must be between 1 and 255 bytes long")
+                }
+            }
+            _ => Err("a SOCKS5 username must come with a password"),
+        }
+    }
+}
+
+// the password must not end up in the logs
+impl fmt::Debug for Socks5Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Socks5Config")
+            .field("addr", &self.addr)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<hidden>"))
+            .finish()
+    }
+}
+
+/// Poll interval settings of a peer, as exponents of two seconds, replacing
+/// those of the system configuration when set
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct PeerPollConfig {
+    pub min_poll: Option<PollInterval>,
+    pub max_poll: Option<PollInterval>,
+    pub initial_poll: Option<PollInterval>,
+}
+
+/// Limits on the round trip delay of the measurements of a peer, beyond which
+/// measurements are discarded before they reach the clock filter
+#[derive(Deserialize, Debug, Default, PartialEq, Clone, Copy)]
+pub struct PeerDelayConfig {
+    pub max_delay: Option<NtpDuration>,
+    /// Largest delay as a multiple of the smallest delay among the recent
+    /// measurements of the peer
+    pub max_delay_ratio: Option<f64>,
+}
+
+impl PeerPollConfig {
+    /// The poll limits of the peer, completed with the limits of the system.
+    /// `None` when the peer uses the limits of the system.
+    pub fn limits(&self, system: PollIntervalLimits) -> Option<PollIntervalLimits> {
+        match (self.min_poll, self.max_poll) {
+            (None, None) => None,
+            (Some(min), max) => Some(PollIntervalLimits {
+                min,
+                max: max.unwrap_or(system.max).max(min),
+            }),
+            (None, Some(max)) => Some(PollIntervalLimits {
+                min: system.min.min(max),
+                max,
+            }),
+        }
+    }
+
+    fn validate(&self) -> Result<(), &'static str> {
+        let in_range = |poll: Option<PollInterval>| match poll {
+            Some(poll) => (PollInterval::PROTOCOL_MIN..=PollInterval::PROTOCOL_MAX).contains(&poll),
+            None => true,
+        };
+        if !in_range(self.min_poll) || !in_range(self.max_poll) || !in_range(self.initial_poll) {
+            return Err("poll intervals must be between 4 (16 s) and 17 (36 h)");
+        }
+
+        let min = self.min_poll.unwrap_or(PollInterval::PROTOCOL_MIN);
+        let max = self.max_poll.unwrap_or(PollInterval::PROTOCOL_MAX);
+        if min > max {
+            return Err("min-poll must not exceed max-poll");
+        }
+        if matches!(self.initial_poll, Some(poll) if poll < min || poll > max) {
+            return Err("initial-poll must be between min-poll and max-poll");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
 pub struct StandardPeerConfig {
     pub addr: NormalizedAddress,
+    #[serde(default)]
+    pub bind: PeerBindConfig,
+    /// Measurements with a larger offset are discarded
+    #[serde(default)]
+    pub max_offset: Option<NtpDuration>,
+    #[serde(default)]
+    pub poll: PeerPollConfig,
+    #[serde(default)]
+    pub delay: PeerDelayConfig,
+    /// Highest NTP version to send requests in
+    #[serde(default)]
+    pub version: NtpVersion,
+    /// Whether responses that deviate from RFC 5905 are used
+    #[serde(default)]
+    pub parsing: ParsingPolicy,
+    /// Whether we only take time from the peer, or exchange time with it
+    #[serde(default)]
+    pub association: AssociationMode,
+    /// Also probe the other address family when the address resolves to both
+    /// IPv4 and IPv6 addresses, and use whichever performs better
+    #[serde(default)]
+    pub dual_stack: bool,
+    /// Id of the symmetric key with which requests and responses are
+    /// authenticated
+    #[serde(default)]
+    pub key: Option<u32>,
+    /// Source group the peer belongs to
+    #[serde(default)]
+    pub source_group: Option<String>,
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Deserialize, Debug, PartialEq, Clone)]
 pub struct PoolPeerConfig {
     pub addr: NormalizedAddress,
+    /// Number of servers of the pool that are used
     pub max_peers: usize,
+    /// Named sets of subnets, such as those of a country or of an anycast
+    /// cluster, from each of which at most one server is used
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<IpSubnet>>,
+    #[serde(default)]
+    pub bind: PeerBindConfig,
+    /// Measurements with a larger offset are discarded
+    #[serde(default)]
+    pub max_offset: Option<NtpDuration>,
+    #[serde(default)]
+    pub poll: PeerPollConfig,
+    #[serde(default)]
+    pub delay: PeerDelayConfig,
+    /// Highest NTP version to send requests in
+    #[serde(default)]
+    pub version: NtpVersion,
+    /// Whether responses that deviate from RFC 5905 are used
+    #[serde(default)]
+    pub parsing: ParsingPolicy,
+    /// Source group the servers of the pool belong to
+    #[serde(default)]
+    pub source_group: Option<String>,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum PeerConfig {
     Standard(StandardPeerConfig),
     Pool(PoolPeerConfig),
@@ -41,6 +329,72 @@ impl PeerConfig {
     pub(crate) fn try_from_str(value: &str) -> Result<Self, std::io::Error> {
         Self::try_from(value)
     }
+
+    pub fn bind(&self) -> &PeerBindConfig {
+        match self {
+            PeerConfig::Standard(config) => &config.bind,
+            PeerConfig::Pool(config) => &config.bind,
+        }
+    }
+
+    pub fn max_offset(&self) -> Option<NtpDuration> {
+        match self {
+            PeerConfig::Standard(config) => config.max_offset,
+            PeerConfig::Pool(config) => config.max_offset,
+        }
+    }
+
+    pub fn poll(&self) -> PeerPollConfig {
+        match self {
+            PeerConfig::Standard(config) => config.poll,
+            PeerConfig::Pool(config) => config.poll,
+        }
+    }
+
+    pub fn delay(&self) -> PeerDelayConfig {
+        match self {
+            PeerConfig::Standard(config) => config.delay,
+            PeerConfig::Pool(config) => config.delay,
+        }
+    }
+
+    pub fn version(&self) -> NtpVersion {
+        match self {
+            PeerConfig::Standard(config) => config.version,
+            PeerConfig::Pool(config) => config.version,
+        }
+    }
+
+    pub fn parsing(&self) -> ParsingPolicy {
+        match self {
+            PeerConfig::Standard(config) => config.parsing,
+            PeerConfig::Pool(config) => config.parsing,
+        }
+    }
+
+    /// Servers of a pool are always polled as a client
+    pub fn association(&self) -> AssociationMode {
+        match self {
+            PeerConfig::Standard(config) => config.association,
+            PeerConfig::Pool(_) => AssociationMode::Client,
+        }
+    }
+
+    /// Servers of a pool are not authenticated, as they have no keys in
+    /// common with us
+    pub fn key(&self) -> Option<u32> {
+        match self {
+            PeerConfig::Standard(config) => config.key,
+            PeerConfig::Pool(_) => None,
+        }
+    }
+
+    pub fn source_group(&self) -> Option<&str> {
+        match self {
+            PeerConfig::Standard(config) => config.source_group.as_deref(),
+            PeerConfig::Pool(config) => config.source_group.as_deref(),
+        }
+    }
 }
 
 /// A normalized address has a host and a port part. However, the host may be
@@ -105,6 +459,16 @@ impl TryFrom<&str> for StandardPeerConfig {
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         Ok(Self {
             addr: NormalizedAddress::from_string(value.to_string())?,
+            bind: PeerBindConfig::default(),
+            max_offset: None,
+            poll: PeerPollConfig::default(),
+            delay: PeerDelayConfig::default(),
+            version: NtpVersion::default(),
+            parsing: ParsingPolicy::default(),
+            association: AssociationMode::Client,
+            dual_stack: false,
+            key: None,
+            source_group: None,
         })
     }
 }
@@ -141,6 +505,21 @@ impl<'de> Deserialize<'de> for PeerConfig {
                 let mut addr = None;
                 let mut mode = None;
                 let mut max_peers = None;
+                let mut groups = None;
+                let mut interface = None;
+                let mut source_address = None;
+                let mut socks5 = None;
+                let mut network_namespace = None;
+                let mut nat = None;
+                let mut max_offset = None;
+                let mut poll = PeerPollConfig::default();
+                let mut delay = PeerDelayConfig::default();
+                let mut version = None;
+                let mut parsing = None;
+                let mut association = None;
+                let mut dual_stack = None;
+                let mut key_id = None;
+                let mut source_group = None;
                 while let Some(key) = map.next_key::<&str>()? {
                     match key {
                         "addr" => {
@@ -167,30 +546,313 @@ impl<'de> Deserialize<'de> for PeerConfig {
                             }
                             max_peers = Some(map.next_value()?);
                         }
+                        "groups" => {
+                            if groups.is_some() {
+                                return Err(de::Error::duplicate_field("groups"));
+                            }
+                            groups = Some(map.next_value()?);
+                        }
+                        "interface" => {
+                            if interface.is_some() {
+                                return Err(de::Error::duplicate_field("interface"));
+                            }
+                            let name: String = map.next_value()?;
+                            // IFNAMSIZ includes the terminating nul byte
+                            if name.is_empty() || name.len() >= 16 || name.contains('\0') {
+                                return Err(de::Error::invalid_value(
+                                    de::Unexpected::Str(&name),
+                                    &"a network interface name",
+                                ));
+                            }
+                            interface = Some(name);
+                        }
+                        "source-address" => {
+                            if source_address.is_some() {
+                                return Err(de::Error::duplicate_field("source-address"));
+                            }
+                            source_address = Some(map.next_value()?);
+                        }
+                        "socks5" => {
+                            if socks5.is_some() {
+                                return Err(de::Error::duplicate_field("socks5"));
+                            }
+                            let config: Socks5Config = map.next_value()?;
+                            config.validate().map_err(de::Error::custom)?;
+                            socks5 = Some(config);
+                        }
+                        "network-namespace" => {
+                            if network_namespace.is_some() {
+                                return Err(de::Error::duplicate_field("network-namespace"));
+                            }
+                            let namespace: String = map.next_value()?;
+                            let path = network_namespace_path(&namespace).ok_or_else(|| {
+                                de::Error::invalid_value(
+                                    de::Unexpected::Str(&namespace),
+                                    &"a network namespace name or absolute path",
+                                )
+                            })?;
+                            network_namespace = Some(path);
+                        }
+                        "nat" => {
+                            if nat.is_some() {
+                                return Err(de::Error::duplicate_field("nat"));
+                            }
+                            let config: PeerNatConfig = map.next_value()?;
+                            config.validate().map_err(de::Error::custom)?;
+                            nat = Some(config);
+                        }
+                        "max-offset" => {
+                            if max_offset.is_some() {
+                                return Err(de::Error::duplicate_field("max-offset"));
+                            }
+                            let offset: NtpDuration = map.next_value()?;
+                            if offset <= NtpDuration::ZERO {
+                                return Err(de::Error::invalid_value(
+                                    de::Unexpected::Float(offset.to_seconds()),
+                                    &"a positive duration",
+                                ));
+                            }
+                            max_offset = Some(offset);
+                        }
+                        "max-delay" => {
+                            if delay.max_delay.is_some() {
+                                return Err(de::Error::duplicate_field("max-delay"));
+                            }
+                            let max_delay: NtpDuration = map.next_value()?;
+                            if max_delay <= NtpDuration::ZERO {
+                                return Err(de::Error::invalid_value(
+                                    de::Unexpected::Float(max_delay.to_seconds()),
+                                    &"a positive duration",
+                                ));
+                            }
+                            delay.max_delay = Some(max_delay);
+                        }
+                        "max-delay-ratio" => {
+                            if delay.max_delay_ratio.is_some() {
+                                return Err(de::Error::duplicate_field("max-delay-ratio"));
+                            }
+                            let ratio: f64 = map.next_value()?;
+                            if ratio.is_nan() || ratio < 1.0 {
+                                return Err(de::Error::invalid_value(
+                                    de::Unexpected::Float(ratio),
+                                    &"a ratio of at least 1",
+                                ));
+                            }
+                            delay.max_delay_ratio = Some(ratio);
+                        }
+                        "min-poll" => {
+                            if poll.min_poll.is_some() {
+                                return Err(de::Error::duplicate_field("min-poll"));
+                            }
+                            poll.min_poll = Some(map.next_value()?);
+                        }
+                        "max-poll" => {
+                            if poll.max_poll.is_some() {
+                                return Err(de::Error::duplicate_field("max-poll"));
+                            }
+                            poll.max_poll = Some(map.next_value()?);
+                        }
+                        "initial-poll" => {
+                            if poll.initial_poll.is_some() {
+                                return Err(de::Error::duplicate_field("initial-poll"));
+                            }
+                            poll.initial_poll = Some(map.next_value()?);
+                        }
+                        "version" => {
+                            if version.is_some() {
+                                return Err(de::Error::duplicate_field("version"));
+                            }
+                            version = Some(map.next_value()?);
+                        }
+                        "parsing" => {
+                            if parsing.is_some() {
+                                return Err(de::Error::duplicate_field("parsing"));
+                            }
+                            parsing = Some(map.next_value()?);
+                        }
+                        "association" => {
+                            if association.is_some() {
+                                return Err(de::Error::duplicate_field("association"));
+                            }
+                            association = Some(map.next_value()?);
+                        }
+                        "dual-stack" => {
+                            if dual_stack.is_some() {
+                                return Err(de::Error::duplicate_field("dual-stack"));
+                            }
+                            dual_stack = Some(map.next_value()?);
+                        }
+                        "key" => {
+                            if key_id.is_some() {
+                                return Err(de::Error::duplicate_field("key"));
+                            }
+                            let id: u32 = map.next_value()?;
+                            if id == 0 {
+                                return Err(de::Error::invalid_value(
+                                    de::Unexpected::Unsigned(0),
+                                    &"a key id of 1 or more",
+                                ));
+                            }
+                            key_id = Some(id);
+                        }
+                        "source-group" => {
+                            if source_group.is_some() {
+                                return Err(de::Error::duplicate_field("source-group"));
+                            }
+                            source_group = Some(map.next_value()?);
+                        }
                         _ => {
                             return Err(de::Error::unknown_field(
                                 key,
-                                &["addr", "mode", "max_peers"],
+                                &[
+                                    "addr",
+                                    "mode",
+                                    "max_peers",
+                                    "groups",
+                                    "interface",
+                                    "source-address",
+                                    "socks5",
+                                    "network-namespace",
+                                    "nat",
+                                    "max-offset",
+                                    "max-delay",
+                                    "max-delay-ratio",
+                                    "min-poll",
+                                    "max-poll",
+                                    "initial-poll",
+                                    "version",
+                                    "parsing",
+                                    "association",
+                                    "dual-stack",
+                                    "key",
+                                    "source-group",
+                                ],
                             ));
                         }
                     }
                 }
 
                 let addr = addr.ok_or_else(|| de::Error::missing_field("addr"))?;
+                poll.validate().map_err(de::Error::custom)?;
                 let mode = mode.unwrap_or_default();
+                if socks5.is_some() && network_namespace.is_some() {
+                    return Err(de::Error::custom(
+                        "a peer cannot use both a SOCKS5 proxy and a network namespace",
+                    ));
+                }
+                let nat: PeerNatConfig = nat.unwrap_or_default();
+                if socks5.is_some() && !nat.connected {
+                    return Err(de::Error::custom(
+                        "the socket to a SOCKS5 proxy is always connected",
+                    ));
+                }
+                let bind = PeerBindConfig {
+                    interface,
+                    source_address,
+                    socks5,
+                    network_namespace,
+                    nat,
+                };
 
                 match mode {
                     PeerHostMode::Server => {
-                        if max_peers.is_some() {
-                            Err(de::Error::unknown_field("max_peers", &["addr", "mode"]))
+                        let unexpected = if max_peers.is_some() {
+                            Some("max_peers")
+                        } else if groups.is_some() {
+                            Some("groups")
+                        } else {
+                            None
+                        };
+                        if let Some(field) = unexpected {
+                            Err(de::Error::unknown_field(
+                                field,
+                                &[
+                                    "addr",
+                                    "mode",
+                                    "interface",
+                                    "source-address",
+                                    "socks5",
+                                    "network-namespace",
+                                    "nat",
+                                    "max-offset",
+                                    "max-delay",
+                                    "max-delay-ratio",
+                                    "min-poll",
+                                    "max-poll",
+                                    "initial-poll",
+                                    "version",
+                                    "parsing",
+                                    "association",
+                                    "dual-stack",
+                                    "key",
+                                    "source-group",
+                                ],
+                            ))
                         } else {
-                            Ok(PeerConfig::Standard(StandardPeerConfig { addr }))
+                            Ok(PeerConfig::Standard(StandardPeerConfig {
+                                addr,
+                                bind,
+                                max_offset,
+                                poll,
+                                delay,
+                                version: version.unwrap_or_default(),
+                                parsing: parsing.unwrap_or_default(),
+                                association: association.unwrap_or_default(),
+                                dual_stack: dual_stack.unwrap_or_default(),
+                                key: key_id,
+                                source_group,
+                            }))
                         }
                     }
                     PeerHostMode::Pool => {
+                        let unexpected = if association.is_some() {
+                            Some("association")
+                        } else if dual_stack.is_some() {
+                            Some("dual-stack")
+                        } else if key_id.is_some() {
+                            Some("key")
+                        } else {
+                            None
+                        };
+                        if let Some(field) = unexpected {
+                            return Err(de::Error::unknown_field(
+                                field,
+                                &[
+                                    "addr",
+                                    "mode",
+                                    "max_peers",
+                                    "groups",
+                                    "interface",
+                                    "source-address",
+                                    "socks5",
+                                    "network-namespace",
+                                    "nat",
+                                    "max-offset",
+                                    "max-delay",
+                                    "max-delay-ratio",
+                                    "min-poll",
+                                    "max-poll",
+                                    "initial-poll",
+                                    "version",
+                                    "parsing",
+                                    "source-group",
+                                ],
+                            ));
+                        }
                         let max_peers = max_peers.unwrap_or(1);
 
-                        Ok(PeerConfig::Pool(PoolPeerConfig { addr, max_peers }))
+                        Ok(PeerConfig::Pool(PoolPeerConfig {
+                            addr,
+                            max_peers,
+                            groups: groups.unwrap_or_default(),
+                            bind,
+                            max_offset,
+                            poll,
+                            delay,
+                            version: version.unwrap_or_default(),
+                            parsing: parsing.unwrap_or_default(),
+                            source_group,
+                        }))
                     }
                 }
             }
@@ -272,7 +934,401 @@ mod tests {
         if let PeerConfig::Pool(config) = test.peer {
             assert_eq!(config.addr.as_str(), "example.com:123");
             assert_eq!(config.max_peers, 42);
+            assert!(config.groups.is_empty());
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            mode = "Pool"
+            max_peers = 4
+            groups = { anycast = ["192.0.2.0/24", "2001:db8::/32"] }
+            "#,
+        )
+        .unwrap();
+        if let PeerConfig::Pool(config) = test.peer {
+            assert_eq!(config.groups["anycast"].len(), 2);
+        } else {
+            panic!("expected a pool");
+        }
+
+        assert!(toml::from_str::<TestConfig>(
+            "[peer]\naddr = \"example.com\"\ngroups = { anycast = [\"192.0.2.0/24\"] }"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_deserialize_peer_bind() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig = toml::from_str("peer = \"example.com\"").unwrap();
+        assert_eq!(test.peer.bind(), &PeerBindConfig::default());
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            interface = "eth1"
+            source-address = "192.0.2.1"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.peer.bind().interface.as_deref(), Some("eth1"));
+        assert_eq!(
+            test.peer.bind().source_address,
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert!(test
+            .peer
+            .bind()
+            .matches_family(&"198.51.100.1:123".parse().unwrap()));
+        assert!(!test
+            .peer
+            .bind()
+            .matches_family(&"[2001:db8::1]:123".parse().unwrap()));
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            mode = "Pool"
+            interface = "vrf-mgmt"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.peer.bind().interface.as_deref(), Some("vrf-mgmt"));
+        assert_eq!(test.peer.bind().source_address, None);
+
+        assert!(toml::from_str::<TestConfig>(
+            "[peer]\naddr = \"example.com\"\ninterface = \"a-name-that-is-too-long\""
+        )
+        .is_err());
+        assert!(toml::from_str::<TestConfig>(
+            "[peer]\naddr = \"example.com\"\nsource-address = \"example.org\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_deserialize_nat() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig = toml::from_str("peer = \"example.com\"").unwrap();
+        let nat = test.peer.bind().nat;
+        assert!(nat.connected);
+        assert!(nat.persistent(ClientSockets::Persistent));
+        assert!(!nat.persistent(ClientSockets::PerRequest));
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            nat = { client-sockets = "persistent", connected = false, keepalive = 20, reopen-after = 3 }
+            "#,
+        )
+        .unwrap();
+        let nat = test.peer.bind().nat;
+        assert!(!nat.connected);
+        assert!(nat.persistent(ClientSockets::PerRequest));
+        assert_eq!(nat.keepalive, Some(Duration::from_secs(20)));
+        assert_eq!(nat.reopen_after, Some(3));
+
+        for nat in [
+            "{ keepalive = 0 }",
+            "{ reopen-after = 0 }",
+            "{ client-sockets = \"per-request\", keepalive = 20 }",
+            "{ client-sockets = \"per-request\", reopen-after = 3 }",
+            "{ unknown = true }",
+        ] {
+            assert!(toml::from_str::<TestConfig>(&format!(
+                "[peer]\naddr = \"example.com\"\nnat = {nat}"
+            ))
+            .is_err());
+        }
+        assert!(toml::from_str::<TestConfig>(
+            "[peer]\naddr = \"example.com\"\nnat = { connected = false }\nsocks5 = { addr = \"proxy.example.com:1080\" }"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_deserialize_network_namespace() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "192.0.2.10"
+            network-namespace = "mgmt"
+            interface = "eth0"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.peer.bind().network_namespace.as_deref(),
+            Some(Path::new("/run/netns/mgmt"))
+        );
+        assert_eq!(test.peer.bind().interface.as_deref(), Some("eth0"));
+
+        let test: TestConfig = toml::from_str(
+            "[peer]\naddr = \"example.com\"\nmode = \"Pool\"\nnetwork-namespace = \"/proc/1/ns/net\"",
+        )
+        .unwrap();
+        assert_eq!(
+            test.peer.bind().network_namespace.as_deref(),
+            Some(Path::new("/proc/1/ns/net"))
+        );
+
+        for namespace in ["", "..", "a/b"] {
+            assert!(toml::from_str::<TestConfig>(&format!(
+                "[peer]\naddr = \"example.com\"\nnetwork-namespace = \"{namespace}\""
+            ))
+            .is_err());
+        }
+        assert!(toml::from_str::<TestConfig>(
+            "[peer]\naddr = \"example.com\"\nnetwork-namespace = \"mgmt\"\nsocks5 = { addr = \"proxy.example.com:1080\" }"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_deserialize_socks5() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [peer]
+            addr = "example.com"
+            source-address = "192.0.2.1"
+            socks5 = { addr = "proxy.example.com:1080", username = "ntp", password = "secret" }
+            "#,
+        )
+        .unwrap();
+        let socks5 = test.peer.bind().socks5.as_ref().unwrap();
+        assert_eq!(socks5.addr, "proxy.example.com:1080");
+        assert_eq!(socks5.username.as_deref(), Some("ntp"));
+        assert_eq!(socks5.password.as_deref(), Some("secret"));
+        assert!(!format!("{socks5:?}").contains("secret"));
+        // the proxy reaches peers of either family
+        assert!(test
+            .peer
+            .bind()
+            .matches_family(&"[2001:db8::1]:123".parse().unwrap()));
+
+        let test: TestConfig = toml::from_str(
+            "[peer]\naddr = \"example.com\"\nmode = \"Pool\"\nsocks5 = { addr = \"[2001:db8::2]:1080\" }",
+        )
+        .unwrap();
+        assert_eq!(test.peer.bind().socks5.as_ref().unwrap().username, None);
+
+        for socks5 in [
+            r#"{ addr = "proxy.example.com" }"#,
+            r#"{ addr = ":1080" }"#,
+            r#"{ addr = "proxy.example.com:1080", username = "ntp" }"#,
+            r#"{ addr = "proxy.example.com:1080", username = "", password = "secret" }"#,
+            r#"{ addr = "proxy.example.com:1080", port = 1080 }"#,
+        ] {
+            assert!(toml::from_str::<TestConfig>(&format!(
+                "[peer]\naddr = \"example.com\"\nsocks5 = {socks5}"
+            ))
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_deserialize_max_offset() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig =
+            toml::from_str("[peer]\naddr = \"example.com\"\nmax-offset = 5").unwrap();
+        assert_eq!(test.peer.max_offset(), Some(NtpDuration::from_seconds(5.0)));
+
+        let test: TestConfig =
+            toml::from_str("[peer]\naddr = \"example.com\"\nmode = \"Pool\"\nmax-offset = 0.5")
+                .unwrap();
+        assert_eq!(test.peer.max_offset(), Some(NtpDuration::from_seconds(0.5)));
+
+        let test: TestConfig = toml::from_str("peer = \"example.com\"").unwrap();
+        assert_eq!(test.peer.max_offset(), None);
+
+        assert!(
+            toml::from_str::<TestConfig>("[peer]\naddr = \"example.com\"\nmax-offset = -1")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_deserialize_max_delay() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig =
+            toml::from_str("[peer]\naddr = \"example.com\"\nmax-delay = 0.05\nmax-delay-ratio = 3")
+                .unwrap();
+        assert_eq!(
+            test.peer.delay(),
+            PeerDelayConfig {
+                max_delay: Some(NtpDuration::from_seconds(0.05)),
+                max_delay_ratio: Some(3.0),
+            }
+        );
+
+        let test: TestConfig = toml::from_str(
+            "[peer]\naddr = \"example.com\"\nmode = \"Pool\"\nmax-delay-ratio = 2.5",
+        )
+        .unwrap();
+        assert_eq!(test.peer.delay().max_delay_ratio, Some(2.5));
+
+        let test: TestConfig = toml::from_str("peer = \"example.com\"").unwrap();
+        assert_eq!(test.peer.delay(), PeerDelayConfig::default());
+
+        assert!(
+            toml::from_str::<TestConfig>("[peer]\naddr = \"example.com\"\nmax-delay = 0").is_err()
+        );
+        assert!(toml::from_str::<TestConfig>(
+            "[peer]\naddr = \"example.com\"\nmax-delay-ratio = 0.5"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_deserialize_poll() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let system = PollIntervalLimits::default();
+        let test: TestConfig = toml::from_str(
+            "[peer]\naddr = \"example.com\"\nmin-poll = 6\nmax-poll = 8\ninitial-poll = 7",
+        )
+        .unwrap();
+        let poll = test.peer.poll();
+        let limits = poll.limits(system).unwrap();
+        assert_eq!(limits.min.as_log(), 6);
+        assert_eq!(limits.max.as_log(), 8);
+        assert_eq!(poll.initial_poll.map(PollInterval::as_log), Some(7));
+
+        // missing limits are taken from the system, keeping min below max
+        let test: TestConfig =
+            toml::from_str("[peer]\naddr = \"example.com\"\nmin-poll = 12").unwrap();
+        let limits = test.peer.poll().limits(system).unwrap();
+        assert_eq!(limits.min.as_log(), 12);
+        assert_eq!(limits.max.as_log(), 12);
+
+        let test: TestConfig = toml::from_str("peer = \"example.com\"").unwrap();
+        assert_eq!(test.peer.poll().limits(system), None);
+
+        for invalid in [
+            "min-poll = 3",
+            "max-poll = 18",
+            "min-poll = 8\nmax-poll = 6",
+            "max-poll = 6\ninitial-poll = 7",
+        ] {
+            let config = format!("[peer]\naddr = \"example.com\"\n{invalid}");
+            assert!(toml::from_str::<TestConfig>(&config).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_deserialize_association() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig = toml::from_str(
+            "[peer]\naddr = \"backup.example.com\"\nassociation = \"symmetric-active\"",
+        )
+        .unwrap();
+        assert_eq!(test.peer.association(), AssociationMode::SymmetricActive);
+
+        let test: TestConfig = toml::from_str("peer = \"example.com\"").unwrap();
+        assert_eq!(test.peer.association(), AssociationMode::Client);
+
+        // the servers of a pool cannot all be backups of us
+        assert!(toml::from_str::<TestConfig>(
+            "[peer]\naddr = \"pool.example.com\"\nmode = \"Pool\"\nassociation = \"client\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_deserialize_version() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let test: TestConfig =
+            toml::from_str("[peer]\naddr = \"appliance.example.com\"\nversion = 3").unwrap();
+        assert_eq!(test.peer.version(), NtpVersion::V3);
+
+        let test: TestConfig = toml::from_str("peer = \"example.com\"").unwrap();
+        assert_eq!(test.peer.version(), NtpVersion::V4);
+
+        // NTPv5 is not supported yet
+        assert!(
+            toml::from_str::<TestConfig>("[peer]\naddr = \"example.com\"\nversion = 5").is_err()
+        );
+    }
+
+    #[test]
+    fn test_deserialize_parsing() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
         }
+
+        let test: TestConfig =
+            toml::from_str("[peer]\naddr = \"example.com\"\nparsing = \"strict\"").unwrap();
+        assert_eq!(test.peer.parsing(), ParsingPolicy::Strict);
+
+        let test: TestConfig =
+            toml::from_str("[peer]\nmode = \"pool\"\naddr = \"pool.example.com\"").unwrap();
+        assert_eq!(test.peer.parsing(), ParsingPolicy::Lenient);
+    }
+
+    #[test]
+    fn test_deserialize_dual_stack() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            peer: PeerConfig,
+        }
+
+        let dual_stack = |config: &str| match toml::from_str::<TestConfig>(config).unwrap().peer {
+            PeerConfig::Standard(config) => config.dual_stack,
+            PeerConfig::Pool(_) => panic!("expected a standard peer"),
+        };
+        assert!(dual_stack(
+            "[peer]\naddr = \"example.com\"\ndual-stack = true"
+        ));
+        assert!(!dual_stack("peer = \"example.com\""));
+
+        // a pool uses a single address of each of its servers
+        assert!(toml::from_str::<TestConfig>(
+            "[peer]\naddr = \"pool.example.com\"\nmode = \"Pool\"\ndual-stack = true"
+        )
+        .is_err());
     }
 
     #[test]
@@ -0,0 +1,363 @@
+use std::{net::Ipv4Addr, path::PathBuf};
+
+use ntp_proto::{NtpDuration, ReferenceId};
+use serde::Deserialize;
+
+use super::server::deserialize_reference_id;
+
+/// Options of every reference clock, whatever its driver
+#[derive(Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct RefClockCommon {
+    /// Reference id of the system while the reference clock is its system
+    /// peer, instead of the one of the driver
+    #[serde(default, deserialize_with = "deserialize_reference_id")]
+    pub reference_id: Option<ReferenceId>,
+    /// Stratum of the reference clock, the system operating one above it
+    #[serde(default)]
+    pub stratum: u8,
+    /// Only use the reference clock when a network peer agrees with it
+    #[serde(default)]
+    pub require_corroboration: bool,
+    /// Source group the reference clock belongs to
+    #[serde(default)]
+    pub source_group: Option<String>,
+}
+
+fn default_nmea_baud_rate() -> u32 {
+    9600
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct NmeaRefClockConfig {
+    /// Serial device the GPS receiver is attached to
+    pub device: PathBuf,
+    #[serde(default = "default_nmea_baud_rate")]
+    pub baud_rate: u32,
+    /// Constant correction added to every sample, compensating for the
+    /// delay between the start of the second and the arrival of the sentence
+    #[serde(default)]
+    pub fudge: NtpDuration,
+    #[serde(flatten)]
+    pub common: RefClockCommon,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PpsRefClockConfig {
+    /// PPS device implementing the Linux PPS API, e.g. `/dev/pps0`
+    pub device: PathBuf,
+    /// Constant correction added to every sample, compensating for cable
+    /// and receiver delays
+    #[serde(default)]
+    pub fudge: NtpDuration,
+    /// Let the kernel discipline the clock using the pulses directly
+    #[serde(default)]
+    pub hardpps: bool,
+    #[serde(flatten)]
+    pub common: RefClockCommon,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PhcRefClockConfig {
+    /// PTP hardware clock device, e.g. `/dev/ptp0`
+    pub device: PathBuf,
+    /// Constant correction added to every sample. PTP clocks usually run on
+    /// TAI, in which case this should be minus the current TAI-UTC offset
+    #[serde(default)]
+    pub fudge: NtpDuration,
+    #[serde(flatten)]
+    pub common: RefClockCommon,
+}
+
+fn default_ptp_interface() -> Ipv4Addr {
+    Ipv4Addr::UNSPECIFIED
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PtpRefClockConfig {
+    /// Address of the interface on which the PTP multicast group is joined,
+    /// any when unspecified
+    #[serde(default = "default_ptp_interface")]
+    pub interface: Ipv4Addr,
+    /// PTP domain of the masters to listen to
+    #[serde(default)]
+    pub domain: u8,
+    /// Constant correction added to every sample, compensating for an
+    /// asymmetric network path
+    #[serde(default)]
+    pub fudge: NtpDuration,
+    #[serde(flatten)]
+    pub common: RefClockCommon,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(tag = "driver", rename_all = "kebab-case")]
+pub enum RefClockConfig {
+    Nmea(NmeaRefClockConfig),
+    Pps(PpsRefClockConfig),
+    Phc(PhcRefClockConfig),
+    Ptp(PtpRefClockConfig),
+}
+
+impl RefClockConfig {
+    /// Human readable description of the reference clock, used in logging
+    /// and observability output
+    pub fn description(&self) -> String {
+        match self {
+            RefClockConfig::Nmea(config) => format!("nmea:{}", config.device.display()),
+            RefClockConfig::Pps(config) => format!("pps:{}", config.device.display()),
+            RefClockConfig::Phc(config) => format!("phc:{}", config.device.display()),
+            RefClockConfig::Ptp(config) => {
+                format!("ptp:{} domain {}", config.interface, config.domain)
+            }
+        }
+    }
+
+    /// Options shared by all drivers
+    pub fn common(&self) -> &RefClockCommon {
+        match self {
+            RefClockConfig::Nmea(config) => &config.common,
+            RefClockConfig::Pps(config) => &config.common,
+            RefClockConfig::Phc(config) => &config.common,
+            RefClockConfig::Ptp(config) => &config.common,
+        }
+    }
+
+    /// Reference id of the reference clock, which the system takes over when
+    /// it is the system peer
+    pub fn reference_id(&self) -> ReferenceId {
+        let default = match self {
+            RefClockConfig::Nmea(_) => ReferenceId::GPS,
+            RefClockConfig::Pps(_) => ReferenceId::PPS,
+            RefClockConfig::Phc(_) | RefClockConfig::Ptp(_) => ReferenceId::PTP,
+        };
+        self.common().reference_id.unwrap_or(default)
+    }
+
+    pub fn source_group(&self) -> Option<&str> {
+        self.common().source_group.as_deref()
+    }
+
+    /// Whether the reference clock only provides the fraction of the second,
+    /// and thus needs another source to tell it which second it is in
+    pub fn needs_time_source(&self) -> bool {
+        matches!(self, RefClockConfig::Pps(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    struct TestConfig {
+        refclock: RefClockConfig,
+    }
+
+    #[test]
+    fn test_deserialize_nmea() {
+        let test: TestConfig = toml::from_str(
+            r#"
+            [refclock]
+            driver = "nmea"
+            device = "/dev/ttyS0"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.refclock,
+            RefClockConfig::Nmea(NmeaRefClockConfig {
+                device: PathBuf::from("/dev/ttyS0"),
+                baud_rate: 9600,
+                fudge: NtpDuration::ZERO,
+                common: RefClockCommon::default(),
+            })
+        );
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [refclock]
+            driver = "nmea"
+            device = "/dev/ttyUSB0"
+            baud-rate = 115200
+            fudge = 0.5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.refclock,
+            RefClockConfig::Nmea(NmeaRefClockConfig {
+                device: PathBuf::from("/dev/ttyUSB0"),
+                baud_rate: 115200,
+                fudge: NtpDuration::from_seconds(0.5),
+                common: RefClockCommon::default(),
+            })
+        );
+        assert_eq!(test.refclock.description(), "nmea:/dev/ttyUSB0");
+    }
+
+    #[test]
+    fn test_deserialize_pps() {
+        let test: TestConfig = toml::from_str(
+            r#"
+            [refclock]
+            driver = "pps"
+            device = "/dev/pps0"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.refclock,
+            RefClockConfig::Pps(PpsRefClockConfig {
+                device: PathBuf::from("/dev/pps0"),
+                fudge: NtpDuration::ZERO,
+                hardpps: false,
+                common: RefClockCommon::default(),
+            })
+        );
+        assert!(test.refclock.needs_time_source());
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [refclock]
+            driver = "pps"
+            device = "/dev/pps1"
+            fudge = -0.000001
+            hardpps = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.refclock,
+            RefClockConfig::Pps(PpsRefClockConfig {
+                device: PathBuf::from("/dev/pps1"),
+                fudge: NtpDuration::from_seconds(-0.000001),
+                hardpps: true,
+                common: RefClockCommon::default(),
+            })
+        );
+        assert_eq!(test.refclock.description(), "pps:/dev/pps1");
+    }
+
+    #[test]
+    fn test_deserialize_phc() {
+        let test: TestConfig = toml::from_str(
+            r#"
+            [refclock]
+            driver = "phc"
+            device = "/dev/ptp0"
+            fudge = -37
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.refclock,
+            RefClockConfig::Phc(PhcRefClockConfig {
+                device: PathBuf::from("/dev/ptp0"),
+                fudge: NtpDuration::from_seconds(-37.0),
+                common: RefClockCommon::default(),
+            })
+        );
+        assert!(!test.refclock.needs_time_source());
+        assert_eq!(test.refclock.description(), "phc:/dev/ptp0");
+        assert_eq!(test.refclock.reference_id(), ReferenceId::PTP);
+    }
+
+    #[test]
+    fn test_deserialize_ptp() {
+        let test: TestConfig = toml::from_str(
+            r#"
+            [refclock]
+            driver = "ptp"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.refclock,
+            RefClockConfig::Ptp(PtpRefClockConfig {
+                interface: Ipv4Addr::UNSPECIFIED,
+                domain: 0,
+                fudge: NtpDuration::ZERO,
+                common: RefClockCommon::default(),
+            })
+        );
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [refclock]
+            driver = "ptp"
+            interface = "192.0.2.10"
+            domain = 24
+            "#,
+        )
+        .unwrap();
+        assert!(!test.refclock.needs_time_source());
+        assert_eq!(test.refclock.description(), "ptp:192.0.2.10 domain 24");
+        assert_eq!(test.refclock.reference_id(), ReferenceId::PTP);
+    }
+
+    #[test]
+    fn test_deserialize_selection() {
+        let test: TestConfig = toml::from_str(
+            r#"
+            [refclock]
+            driver = "nmea"
+            device = "/dev/ttyS0"
+            reference-id = "GNSS"
+            stratum = 1
+            require-corroboration = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.refclock.reference_id(),
+            ReferenceId::from_bytes(*b"GNSS")
+        );
+        assert_eq!(test.refclock.common().stratum, 1);
+        assert!(test.refclock.common().require_corroboration);
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [refclock]
+            driver = "pps"
+            device = "/dev/pps0"
+            reference-id = "TOOLONG"
+            "#,
+        );
+        assert!(test.is_err());
+    }
+
+    #[test]
+    fn test_deserialize_invalid() {
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [refclock]
+            driver = "nmea"
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [refclock]
+            driver = "sundial"
+            device = "/dev/ttyS0"
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [refclock]
+            driver = "nmea"
+            device = "/dev/ttyS0"
+            baud = 9600
+            "#,
+        );
+        assert!(test.is_err());
+    }
+}
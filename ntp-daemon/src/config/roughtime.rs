@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use base64::Engine;
+use serde::{de, Deserialize, Deserializer};
+
+use super::deserialize_seconds;
+
+fn deserialize_public_key<'de, D>(deserializer: D) -> Result<[u8; 32], D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let encoded: String = Deserialize::deserialize(deserializer)?;
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| {
+            de::Error::invalid_value(
+                de::Unexpected::Str(&encoded),
+                &"a base64 encoded Ed25519 public key of 32 bytes",
+            )
+        })
+}
+
+/// A Roughtime server, identified by its long-term public key
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RoughtimeServerConfig {
+    /// Host and port of the server, e.g. `roughtime.example.com:2002`
+    pub addr: String,
+    #[serde(deserialize_with = "deserialize_public_key")]
+    pub public_key: [u8; 32],
+}
+
+const fn default_roughtime_timeout() -> Duration {
+    Duration::from_secs(2)
+}
+
+/// Validation of the system clock with Roughtime servers, before any NTP
+/// sources are trusted
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RoughtimeConfig {
+    #[serde(alias = "server", default)]
+    pub servers: Vec<RoughtimeServerConfig>,
+    /// How long to wait for the response of a single server
+    #[serde(
+        deserialize_with = "deserialize_seconds",
+        default = "default_roughtime_timeout"
+    )]
+    pub timeout: Duration,
+    /// Refuse to start when the time cannot be validated
+    #[serde(default)]
+    pub required: bool,
+}
+
+impl Default for RoughtimeConfig {
+    fn default() -> Self {
+        Self {
+            servers: vec![],
+            timeout: default_roughtime_timeout(),
+            required: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    struct TestConfig {
+        roughtime: RoughtimeConfig,
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let test: TestConfig = toml::from_str(
+            r#"
+            [roughtime]
+            required = true
+            [[roughtime.servers]]
+            addr = "roughtime.example.com:2002"
+            public-key = "AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA="
+            "#,
+        )
+        .unwrap();
+
+        let mut public_key = [0; 32];
+        for (i, byte) in public_key.iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+
+        assert_eq!(
+            test.roughtime,
+            RoughtimeConfig {
+                servers: vec![RoughtimeServerConfig {
+                    addr: "roughtime.example.com:2002".into(),
+                    public_key,
+                }],
+                timeout: Duration::from_secs(2),
+                required: true,
+            }
+        );
+
+        let result = toml::from_str::<TestConfig>(
+            r#"
+            [[roughtime.servers]]
+            addr = "roughtime.example.com:2002"
+            public-key = "AQID"
+            "#,
+        );
+        assert!(result.is_err());
+    }
+}
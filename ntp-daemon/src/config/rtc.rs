@@ -0,0 +1,73 @@
+use std::{path::PathBuf, time::Duration};
+
+use serde::Deserialize;
+
+use super::deserialize_seconds;
+
+const fn default_sync_interval() -> Duration {
+    // the interval of the kernel's own RTC synchronization
+    Duration::from_secs(660)
+}
+
+/// Use of the battery backed real-time clock
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RtcConfig {
+    /// RTC device, e.g. `/dev/rtc0`. The RTC is not used when not set
+    #[serde(default)]
+    pub device: Option<PathBuf>,
+    /// How often the RTC is set to the system clock while it is synchronized
+    #[serde(
+        deserialize_with = "deserialize_seconds",
+        default = "default_sync_interval"
+    )]
+    pub sync_interval: Duration,
+    /// File in which the drift of the RTC is kept across restarts. The drift
+    /// is neither measured nor compensated when not set
+    #[serde(default)]
+    pub drift_file: Option<PathBuf>,
+}
+
+impl Default for RtcConfig {
+    fn default() -> Self {
+        Self {
+            device: None,
+            sync_interval: default_sync_interval(),
+            drift_file: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    struct TestConfig {
+        rtc: RtcConfig,
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let test: TestConfig = toml::from_str(
+            r#"
+            [rtc]
+            device = "/dev/rtc0"
+            sync-interval = 3600
+            drift-file = "/var/lib/ntpd-rs/rtc-drift"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.rtc,
+            RtcConfig {
+                device: Some(PathBuf::from("/dev/rtc0")),
+                sync_interval: Duration::from_secs(3600),
+                drift_file: Some(PathBuf::from("/var/lib/ntpd-rs/rtc-drift")),
+            }
+        );
+
+        let test: TestConfig = toml::from_str("[rtc]").unwrap();
+        assert_eq!(test.rtc, RtcConfig::default());
+    }
+}
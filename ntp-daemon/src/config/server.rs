@@ -1,23 +1,137 @@
 use std::{
     fmt,
-    net::{AddrParseError, SocketAddr},
+    net::{AddrParseError, IpAddr, SocketAddr},
     str::FromStr,
     time::Duration,
 };
 
+use ntp_proto::{ExtensionFieldLimits, ParsingPolicy, ReferenceId, SystemSnapshot};
+use ntp_udp::MAX_BATCH_SIZE;
 use serde::{
     de::{self, MapAccess, Visitor},
-    Deserialize, Deserializer,
+    Deserialize, Deserializer, Serialize,
 };
 
 use crate::{config::subnet::IpSubnet, ipfilter::IpFilter};
 
+/// Enough for the extension fields of an NTS request: a unique identifier,
+/// a cookie with seven placeholders, and an authenticator
+const DEFAULT_MAX_EXTENSION_FIELDS: usize = 16;
+const DEFAULT_MAX_EXTENSION_FIELD_LENGTH: usize = 512;
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize)]
 pub enum FilterAction {
     Ignore,
     Deny,
 }
 
+/// Which requests a server is willing to answer at all, independent of the
+/// client filters. Checked before the `parsing` policy, so a strict server
+/// still answers nonconforming NTPv4 client requests unless `parsing` is
+/// strict as well.
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServerPolicy {
+    /// Answer client requests of all supported NTP versions
+    #[default]
+    Standard,
+    /// Only answer NTPv4 client (mode 3) requests signed with one of the
+    /// symmetric keys, silently dropping unauthenticated requests, older
+    /// versions, symmetric, control (mode 6) and private (mode 7) packets.
+    /// NTS is not implemented, so without keys everything is dropped.
+    Strict,
+}
+
+/// How a server treats the clients matching an access control rule
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AclAction {
+    /// Answer time requests, and control queries when enabled
+    #[default]
+    Serve,
+    /// Answer time requests without keeping any state for the client: it is
+    /// not entered into the rate limiting table, and control queries are ignored
+    ServeStateless,
+    /// Drop all packets before they are parsed
+    Ignore,
+}
+
+impl fmt::Display for AclAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AclAction::Serve => "serve",
+            AclAction::ServeStateless => "serve-stateless",
+            AclAction::Ignore => "ignore",
+        };
+        f.write_str(name)
+    }
+}
+
+/// An access control rule, matching the clients in a subnet
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AclRule {
+    pub subnet: IpSubnet,
+    pub action: AclAction,
+}
+
+/// Settings for a server on an anycast address, which a fleet of servers
+/// shares. Successive packets of a client can reach different servers of the
+/// fleet, which is why they present themselves alike.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AnycastConfig {
+    /// Answer the symmetric active requests of the symmetric peers. A peer
+    /// would associate with whichever server its packets reach, so they are
+    /// ignored by default
+    #[serde(default)]
+    pub allow_symmetric: bool,
+    /// Reference id in responses, instead of that of the time source
+    #[serde(default, deserialize_with = "deserialize_reference_id")]
+    pub reference_id: Option<ReferenceId>,
+    /// Stratum in responses, unless the stratum of the system is higher
+    #[serde(default)]
+    pub stratum: Option<u8>,
+}
+
+impl AnycastConfig {
+    /// The system as presented to clients
+    pub(crate) fn advertise(&self, mut system: SystemSnapshot) -> SystemSnapshot {
+        if let Some(reference_id) = self.reference_id {
+            system.reference_id = reference_id;
+        }
+        if let Some(stratum) = self.stratum {
+            system.stratum = system.stratum.max(stratum);
+        }
+        system
+    }
+}
+
+/// A reference id is given as an IPv4 address, or as up to four ASCII
+/// characters, as used for the clock sources of stratum 1 servers
+pub(super) fn deserialize_reference_id<'de, D>(
+    deserializer: D,
+) -> Result<Option<ReferenceId>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    if let Ok(addr) = value.parse::<std::net::Ipv4Addr>() {
+        return Ok(Some(ReferenceId::from_ip(addr.into())));
+    }
+
+    if value.is_empty() || value.len() > 4 || !value.is_ascii() {
+        return Err(de::Error::invalid_value(
+            de::Unexpected::Str(&value),
+            &"an IPv4 address, or one to four ASCII characters",
+        ));
+    }
+
+    let mut bytes = [0; 4];
+    bytes[..value.len()].copy_from_slice(value.as_bytes());
+    Ok(Some(ReferenceId::from_bytes(bytes)))
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct ServerConfig {
     pub addr: SocketAddr,
@@ -27,6 +141,83 @@ pub struct ServerConfig {
     pub allowlist_action: FilterAction,
     pub rate_limiting_cache_size: usize,
     pub rate_limiting_cutoff: Duration,
+    pub policy: ServerPolicy,
+    /// Answer read-only NTP control (mode 6) requests, as sent by `ntpq`
+    pub control: bool,
+    /// Access control rules, of which the one with the longest matching
+    /// prefix applies
+    pub acl: Vec<AclRule>,
+    /// Action for clients that match none of the access control rules
+    pub acl_default: AclAction,
+    /// Number of recently seen clients to keep track of, 0 to disable
+    pub mru_size: usize,
+    /// Number of sockets receiving on the address, each with its own task,
+    /// sharing the address with `SO_REUSEPORT`. 0 for one per available core
+    pub workers: usize,
+    /// Maximum number of packets received with a single `recvmmsg`, and
+    /// answered with a single `sendmmsg`, at most 64. 1 receives packets one
+    /// at a time
+    pub batch_size: usize,
+    /// Servers that may poll us in symmetric active mode, which are answered
+    /// in symmetric passive mode. Their requests are otherwise ignored
+    pub symmetric_peers: IpFilter,
+    /// Requests with more extension fields are dropped unanswered
+    pub max_extension_fields: usize,
+    /// Requests with a longer extension field, in bytes, are dropped unanswered
+    pub max_extension_field_length: usize,
+    /// Whether requests that deviate from RFC 5905 are answered
+    pub parsing: ParsingPolicy,
+    /// The address is an anycast address, shared with other servers
+    pub anycast: Option<AnycastConfig>,
+}
+
+impl ServerConfig {
+    pub(crate) fn extension_field_limits(&self) -> ExtensionFieldLimits {
+        ExtensionFieldLimits {
+            max_count: self.max_extension_fields,
+            max_length: self.max_extension_field_length,
+        }
+    }
+
+    /// Whether symmetric active requests from `addr` are answered
+    pub(crate) fn answers_symmetric(&self, addr: &IpAddr) -> bool {
+        let allowed = match self.anycast {
+            Some(anycast) => anycast.allow_symmetric,
+            None => true,
+        };
+        allowed && self.symmetric_peers.is_in(addr)
+    }
+
+    /// The number of sockets and tasks to serve with
+    pub(crate) fn worker_count(&self) -> usize {
+        match self.workers {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            workers => workers,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The index of the access control rule that applies to the address, and
+    /// its action. With multiple rules of the same length, the first applies.
+    pub(crate) fn acl_lookup(&self, addr: &IpAddr) -> (Option<usize>, AclAction) {
+        let mut best: Option<(usize, &AclRule)> = None;
+        for (index, rule) in self.acl.iter().enumerate() {
+            let more_specific = match best {
+                Some((_, best)) => rule.subnet.mask > best.subnet.mask,
+                None => true,
+            };
+
+            if more_specific && rule.subnet.contains(addr) {
+                best = Some((index, rule));
+            }
+        }
+
+        match best {
+            Some((index, rule)) => (Some(index), rule.action),
+            None => (None, self.acl_default),
+        }
+    }
 }
 
 impl ServerConfig {
@@ -47,6 +238,18 @@ impl TryFrom<&str> for ServerConfig {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cache_size: Default::default(),
             rate_limiting_cutoff: Default::default(),
+            policy: Default::default(),
+            control: false,
+            acl: vec![],
+            acl_default: Default::default(),
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: DEFAULT_MAX_EXTENSION_FIELDS,
+            max_extension_field_length: DEFAULT_MAX_EXTENSION_FIELD_LENGTH,
+            parsing: Default::default(),
+            anycast: None,
         })
     }
 }
@@ -79,6 +282,18 @@ impl<'de> Deserialize<'de> for ServerConfig {
                 let mut allowlist_action = None;
                 let mut denylist = None;
                 let mut denylist_action = None;
+                let mut policy = None;
+                let mut control = None;
+                let mut acl = None;
+                let mut acl_default = None;
+                let mut mru_size = None;
+                let mut workers = None;
+                let mut batch_size = None;
+                let mut symmetric_peers = None;
+                let mut max_extension_fields = None;
+                let mut max_extension_field_length = None;
+                let mut parsing = None;
+                let mut anycast = None;
                 while let Some(key) = map.next_key::<&str>()? {
                     match key {
                         "addr" => {
@@ -127,6 +342,105 @@ impl<'de> Deserialize<'de> for ServerConfig {
 
                             rate_limiting_cutoff = Some(Duration::from_millis(map.next_value()?));
                         }
+                        "policy" => {
+                            if policy.is_some() {
+                                return Err(de::Error::duplicate_field("policy"));
+                            }
+
+                            policy = Some(map.next_value::<ServerPolicy>()?);
+                        }
+                        "control" => {
+                            if control.is_some() {
+                                return Err(de::Error::duplicate_field("control"));
+                            }
+
+                            control = Some(map.next_value::<bool>()?);
+                        }
+                        "acl" => {
+                            if acl.is_some() {
+                                return Err(de::Error::duplicate_field("acl"));
+                            }
+
+                            acl = Some(map.next_value::<Vec<AclRule>>()?);
+                        }
+                        "acl-default" => {
+                            if acl_default.is_some() {
+                                return Err(de::Error::duplicate_field("acl-default"));
+                            }
+
+                            acl_default = Some(map.next_value::<AclAction>()?);
+                        }
+                        "mru-size" => {
+                            if mru_size.is_some() {
+                                return Err(de::Error::duplicate_field("mru-size"));
+                            }
+
+                            mru_size = Some(map.next_value::<usize>()?);
+                        }
+                        "workers" => {
+                            if workers.is_some() {
+                                return Err(de::Error::duplicate_field("workers"));
+                            }
+
+                            workers = Some(map.next_value::<usize>()?);
+                        }
+                        "batch-size" => {
+                            if batch_size.is_some() {
+                                return Err(de::Error::duplicate_field("batch-size"));
+                            }
+
+                            let size = map.next_value::<usize>()?;
+                            if size == 0 || size > MAX_BATCH_SIZE {
+                                return Err(de::Error::invalid_value(
+                                    de::Unexpected::Unsigned(size as u64),
+                                    &"a batch size between 1 and 64",
+                                ));
+                            }
+                            batch_size = Some(size);
+                        }
+                        "symmetric-peers" => {
+                            if symmetric_peers.is_some() {
+                                return Err(de::Error::duplicate_field("symmetric-peers"));
+                            }
+                            let list: Vec<IpSubnet> = map.next_value()?;
+                            symmetric_peers = Some(IpFilter::new(&list));
+                        }
+                        "max-extension-fields" => {
+                            if max_extension_fields.is_some() {
+                                return Err(de::Error::duplicate_field("max-extension-fields"));
+                            }
+                            max_extension_fields = Some(map.next_value::<usize>()?);
+                        }
+                        "max-extension-field-length" => {
+                            if max_extension_field_length.is_some() {
+                                return Err(de::Error::duplicate_field(
+                                    "max-extension-field-length",
+                                ));
+                            }
+                            max_extension_field_length = Some(map.next_value::<usize>()?);
+                        }
+                        "parsing" => {
+                            if parsing.is_some() {
+                                return Err(de::Error::duplicate_field("parsing"));
+                            }
+                            parsing = Some(map.next_value::<ParsingPolicy>()?);
+                        }
+                        "anycast" => {
+                            if anycast.is_some() {
+                                return Err(de::Error::duplicate_field("anycast"));
+                            }
+
+                            let config = map.next_value::<AnycastConfig>()?;
+                            if let Some(stratum) = config.stratum {
+                                if !(1..=15).contains(&stratum) {
+                                    return Err(de::Error::invalid_value(
+                                        de::Unexpected::Unsigned(stratum as u64),
+                                        &"a stratum between 1 and 15",
+                                    ));
+                                }
+                            }
+                            anycast = Some(config);
+                        }
                         _ => {
                             return Err(de::Error::unknown_field(
                                 key,
@@ -138,6 +452,18 @@ impl<'de> Deserialize<'de> for ServerConfig {
                                     "denylist-action",
                                     "rate-limiting-cache-size",
                                     "rate-limiting-cutoff-ms",
+                                    "policy",
+                                    "control",
+                                    "acl",
+                                    "acl-default",
+                                    "mru-size",
+                                    "workers",
+                                    "batch-size",
+                                    "symmetric-peers",
+                                    "max-extension-fields",
+                                    "max-extension-field-length",
+                                    "parsing",
+                                    "anycast",
                                 ],
                             ));
                         }
@@ -164,6 +490,19 @@ impl<'de> Deserialize<'de> for ServerConfig {
 
                 let rate_limiting_cache_size = rate_limiting_cache_size.unwrap_or_default();
                 let rate_limiting_cutoff = rate_limiting_cutoff.unwrap_or_default();
+                let policy = policy.unwrap_or_default();
+                let control = control.unwrap_or_default();
+                let acl = acl.unwrap_or_default();
+                let acl_default = acl_default.unwrap_or_default();
+                let mru_size = mru_size.unwrap_or_default();
+                let workers = workers.unwrap_or(1);
+                let batch_size = batch_size.unwrap_or(1);
+                let symmetric_peers = symmetric_peers.unwrap_or_else(IpFilter::none);
+                let max_extension_fields =
+                    max_extension_fields.unwrap_or(DEFAULT_MAX_EXTENSION_FIELDS);
+                let max_extension_field_length =
+                    max_extension_field_length.unwrap_or(DEFAULT_MAX_EXTENSION_FIELD_LENGTH);
+                let parsing = parsing.unwrap_or_default();
 
                 Ok(ServerConfig {
                     addr,
@@ -173,6 +512,18 @@ impl<'de> Deserialize<'de> for ServerConfig {
                     denylist_action,
                     rate_limiting_cache_size,
                     rate_limiting_cutoff,
+                    policy,
+                    control,
+                    acl,
+                    acl_default,
+                    mru_size,
+                    workers,
+                    batch_size,
+                    symmetric_peers,
+                    max_extension_fields,
+                    max_extension_field_length,
+                    parsing,
+                    anycast,
                 })
             }
         }
@@ -200,6 +551,10 @@ mod tests {
         )
         .unwrap();
         assert_eq!(test.server.addr, "0.0.0.0:123".parse().unwrap());
+        assert_eq!(test.server.workers, 1);
+        assert_eq!(test.server.batch_size, 1);
+        assert_eq!(test.server.max_extension_fields, 16);
+        assert_eq!(test.server.parsing, ParsingPolicy::Lenient);
 
         let test: TestConfig = toml::from_str(
             r#"
@@ -207,14 +562,208 @@ mod tests {
             addr = "127.0.0.1:123"
             rate-limiting-cutoff-ms = 1000
             rate-limiting-cache-size = 32
+            mru-size = 600
+            workers = 4
+            batch-size = 32
+            symmetric-peers = ["192.0.2.0/24"]
+            max-extension-fields = 0
+            max-extension-field-length = 128
+            parsing = "strict"
             "#,
         )
         .unwrap();
         assert_eq!(test.server.addr, "127.0.0.1:123".parse().unwrap());
         assert_eq!(test.server.rate_limiting_cache_size, 32);
+        assert_eq!(test.server.mru_size, 600);
+        assert_eq!(test.server.workers, 4);
+        assert_eq!(test.server.worker_count(), 4);
+        assert_eq!(test.server.batch_size, 32);
+        assert_eq!(
+            test.server.extension_field_limits(),
+            ExtensionFieldLimits {
+                max_count: 0,
+                max_length: 128
+            }
+        );
+        assert_eq!(test.server.parsing, ParsingPolicy::Strict);
+        assert!(test
+            .server
+            .symmetric_peers
+            .is_in(&"192.0.2.7".parse().unwrap()));
+        assert!(!test
+            .server
+            .symmetric_peers
+            .is_in(&"198.51.100.1".parse().unwrap()));
         assert_eq!(
             test.server.rate_limiting_cutoff,
             Duration::from_millis(1000)
         );
+        assert_eq!(test.server.policy, ServerPolicy::Standard);
+        assert!(!test.server.control);
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "127.0.0.1:123"
+            policy = "strict"
+            control = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(test.server.policy, ServerPolicy::Strict);
+        assert!(test.server.control);
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [server]
+            addr = "127.0.0.1:123"
+            policy = "lenient"
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [server]
+            addr = "127.0.0.1:123"
+            batch-size = 0
+            "#,
+        );
+        assert!(test.is_err());
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [server]
+            addr = "127.0.0.1:123"
+            batch-size = 65
+            "#,
+        );
+        assert!(test.is_err());
+    }
+
+    #[test]
+    fn test_anycast() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            server: ServerConfig,
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "192.0.2.123:123"
+            symmetric-peers = ["198.51.100.0/24"]
+
+            [server.anycast]
+            reference-id = "ANYC"
+            stratum = 3
+            "#,
+        )
+        .unwrap();
+        let anycast = test.server.anycast.unwrap();
+        assert_eq!(
+            anycast.reference_id,
+            Some(ReferenceId::from_bytes(*b"ANYC"))
+        );
+        assert!(!test
+            .server
+            .answers_symmetric(&"198.51.100.1".parse().unwrap()));
+
+        // the stratum is a lower bound, the reference id is always replaced
+        let system = SystemSnapshot {
+            stratum: 2,
+            ..Default::default()
+        };
+        assert_eq!(anycast.advertise(system).stratum, 3);
+        assert_eq!(
+            anycast.advertise(system).reference_id,
+            ReferenceId::from_bytes(*b"ANYC")
+        );
+        let system = SystemSnapshot {
+            stratum: 16,
+            ..Default::default()
+        };
+        assert_eq!(anycast.advertise(system).stratum, 16);
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "192.0.2.123:123"
+            symmetric-peers = ["198.51.100.0/24"]
+            anycast = { allow-symmetric = true, reference-id = "192.0.2.1" }
+            "#,
+        )
+        .unwrap();
+        let anycast = test.server.anycast.unwrap();
+        assert_eq!(
+            anycast.reference_id,
+            Some(ReferenceId::from_ip("192.0.2.1".parse().unwrap()))
+        );
+        assert_eq!(anycast.stratum, None);
+        assert!(test
+            .server
+            .answers_symmetric(&"198.51.100.1".parse().unwrap()));
+
+        for anycast in [
+            r#"{ reference-id = "TOOLONG" }"#,
+            r#"{ reference-id = "" }"#,
+            "{ stratum = 0 }",
+            "{ stratum = 16 }",
+        ] {
+            let test: Result<TestConfig, _> = toml::from_str(&format!(
+                r#"
+                [server]
+                addr = "192.0.2.123:123"
+                anycast = {anycast}
+                "#
+            ));
+            assert!(test.is_err(), "{anycast}");
+        }
+    }
+
+    #[test]
+    fn test_acl() {
+        #[derive(Deserialize, Debug)]
+        struct TestConfig {
+            server: ServerConfig,
+        }
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [server]
+            addr = "[::]:123"
+            acl-default = "ignore"
+            acl = [
+                { subnet = "192.0.2.0/24", action = "serve" },
+                { subnet = "192.0.2.128/25", action = "serve-stateless" },
+                { subnet = "192.0.2.200/32", action = "ignore" },
+                { subnet = "2001:db8::/32", action = "serve" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let server = test.server;
+        assert_eq!(server.acl.len(), 4);
+        assert_eq!(server.acl_default, AclAction::Ignore);
+
+        let lookup = |addr: &str| server.acl_lookup(&addr.parse().unwrap());
+        assert_eq!(lookup("192.0.2.1"), (Some(0), AclAction::Serve));
+        assert_eq!(lookup("192.0.2.129"), (Some(1), AclAction::ServeStateless));
+        assert_eq!(
+            lookup("::ffff:192.0.2.129"),
+            (Some(1), AclAction::ServeStateless)
+        );
+        assert_eq!(lookup("192.0.2.200"), (Some(2), AclAction::Ignore));
+        assert_eq!(lookup("2001:db8::5"), (Some(3), AclAction::Serve));
+        assert_eq!(lookup("198.51.100.1"), (None, AclAction::Ignore));
+
+        let test: Result<TestConfig, _> = toml::from_str(
+            r#"
+            [server]
+            addr = "[::]:123"
+            acl = [{ subnet = "192.0.2.0/24", action = "allow" }]
+            "#,
+        );
+        assert!(test.is_err());
     }
 }
@@ -0,0 +1,80 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+fn default_directory() -> PathBuf {
+    PathBuf::from("/var/log/ntpd-rs")
+}
+
+/// Which statistics files to write, in the formats used by ntpd
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct StatisticsConfig {
+    /// Directory in which the statistics files are created
+    #[serde(default = "default_directory")]
+    pub directory: PathBuf,
+    /// Log every update of the system clock
+    #[serde(default)]
+    pub loopstats: bool,
+    /// Log every new measurement of a peer or reference clock
+    #[serde(default)]
+    pub peerstats: bool,
+    /// Log the timestamps of every packet received from a peer
+    #[serde(default)]
+    pub rawstats: bool,
+}
+
+impl StatisticsConfig {
+    pub fn any_enabled(&self) -> bool {
+        self.loopstats || self.peerstats || self.rawstats
+    }
+}
+
+impl Default for StatisticsConfig {
+    fn default() -> Self {
+        Self {
+            directory: default_directory(),
+            loopstats: false,
+            peerstats: false,
+            rawstats: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    struct TestConfig {
+        statistics: StatisticsConfig,
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let test: TestConfig = toml::from_str("[statistics]").unwrap();
+        assert_eq!(test.statistics, StatisticsConfig::default());
+        assert!(!test.statistics.any_enabled());
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [statistics]
+            directory = "/tmp/stats"
+            peerstats = true
+            rawstats = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.statistics,
+            StatisticsConfig {
+                directory: PathBuf::from("/tmp/stats"),
+                loopstats: false,
+                peerstats: true,
+                rawstats: true,
+            }
+        );
+
+        assert!(toml::from_str::<TestConfig>("[statistics]\nclockstats = true").is_err());
+    }
+}
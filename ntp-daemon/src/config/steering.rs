@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+use ntp_proto::NtpDuration;
+use serde::Deserialize;
+
+fn default_step_threshold() -> NtpDuration {
+    NtpDuration::from_seconds(0.001)
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PhcSteeringConfig {
+    /// PTP hardware clock device to steer, e.g. `/dev/ptp0`
+    pub device: PathBuf,
+    /// Desired PHC time minus system clock time. PTP clocks usually run on
+    /// TAI, in which case this should be the current TAI-UTC offset
+    #[serde(default)]
+    pub offset: NtpDuration,
+    /// Offsets larger than this are corrected by stepping the PHC instead of
+    /// adjusting its frequency
+    #[serde(default = "default_step_threshold")]
+    pub step_threshold: NtpDuration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    struct TestConfig {
+        phc: PhcSteeringConfig,
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let test: TestConfig = toml::from_str(
+            r#"
+            [phc]
+            device = "/dev/ptp0"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.phc,
+            PhcSteeringConfig {
+                device: PathBuf::from("/dev/ptp0"),
+                offset: NtpDuration::ZERO,
+                step_threshold: NtpDuration::from_seconds(0.001),
+            }
+        );
+
+        let test: TestConfig = toml::from_str(
+            r#"
+            [phc]
+            device = "/dev/ptp1"
+            offset = 37
+            step-threshold = 0.1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            test.phc,
+            PhcSteeringConfig {
+                device: PathBuf::from("/dev/ptp1"),
+                offset: NtpDuration::from_seconds(37.0),
+                step_threshold: NtpDuration::from_seconds(0.1),
+            }
+        );
+    }
+}
@@ -1,5 +1,8 @@
 use serde::{de, Deserialize, Deserializer};
-use std::net::{AddrParseError, IpAddr};
+use std::{
+    fmt,
+    net::{AddrParseError, IpAddr},
+};
 use thiserror::Error;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -36,6 +39,41 @@ impl std::str::FromStr for IpSubnet {
     }
 }
 
+impl IpSubnet {
+    /// Whether the address is part of this subnet. IPv4 addresses mapped into
+    /// IPv6, as received on dual stack sockets, match IPv4 subnets.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => match v6.segments() {
+                [0, 0, 0, 0, 0, 0xffff, _, _] => {
+                    let [.., a, b, c, d] = v6.octets();
+                    IpAddr::from([a, b, c, d])
+                }
+                _ => *addr,
+            },
+            IpAddr::V4(_) => *addr,
+        };
+
+        match (self.addr, addr) {
+            (IpAddr::V4(subnet), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.mask as u32).unwrap_or(0);
+                u32::from(subnet) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(subnet), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.mask as u32).unwrap_or(0);
+                u128::from(subnet) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IpSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.mask)
+    }
+}
+
 impl<'de> Deserialize<'de> for IpSubnet {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -55,4 +93,21 @@ mod tests {
         let a = "0.0.0.0/0".parse::<IpSubnet>().unwrap();
         assert_eq!(a.mask, 0);
     }
+
+    #[test]
+    fn test_subnet_contains() {
+        let subnet = "192.0.2.0/24".parse::<IpSubnet>().unwrap();
+        assert!(subnet.contains(&"192.0.2.17".parse().unwrap()));
+        assert!(subnet.contains(&"::ffff:192.0.2.17".parse().unwrap()));
+        assert!(!subnet.contains(&"192.0.3.1".parse().unwrap()));
+        assert!(!subnet.contains(&"2001:db8::1".parse().unwrap()));
+        assert_eq!(subnet.to_string(), "192.0.2.0/24");
+
+        let subnet = "2001:db8::/32".parse::<IpSubnet>().unwrap();
+        assert!(subnet.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!subnet.contains(&"2001:db9::1".parse().unwrap()));
+
+        let all = "0.0.0.0/0".parse::<IpSubnet>().unwrap();
+        assert!(all.contains(&"203.0.113.5".parse().unwrap()));
+    }
 }
@@ -0,0 +1,227 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, RwLock},
+};
+
+use ntp_proto::{
+    peer_status, system_status, ControlError, ControlMessage, ControlOpcode, ControlResponse,
+    ControlVariables, PeerSelection, PeerSnapshot, SystemSnapshot,
+};
+
+use crate::config::RefClockConfig;
+
+/// Read-only view of the peers and reference clocks, shared with the servers
+/// to answer control (mode 6) requests.
+pub(crate) type Associations = Arc<RwLock<Vec<Association>>>;
+
+/// A single peer or reference clock, as seen through the control protocol
+#[derive(Debug, Clone)]
+pub(crate) struct Association {
+    /// Association id, unique and never 0
+    pub id: u16,
+    pub address: SocketAddr,
+    pub snapshot: Option<PeerSnapshot>,
+    /// Whether the source survived the latest round of clock selection
+    pub selected: bool,
+}
+
+impl Association {
+    fn selection(&self, system: &SystemSnapshot) -> PeerSelection {
+        match self.snapshot {
+            Some(snapshot) if self.selected => {
+                if system.leap_indicator.is_synchronized()
+                    && snapshot.peer_id == system.reference_id
+                {
+                    PeerSelection::SystemPeer
+                } else {
+                    PeerSelection::Candidate
+                }
+            }
+            _ => PeerSelection::Rejected,
+        }
+    }
+
+    pub(crate) fn status(&self, system: &SystemSnapshot) -> u16 {
+        let reachable = self
+            .snapshot
+            .map(|snapshot| snapshot.reach.is_reachable())
+            .unwrap_or(false);
+        peer_status(reachable, self.selection(system))
+    }
+
+    fn variables(&self) -> ControlVariables {
+        let mut variables = match self.snapshot {
+            Some(snapshot) => ControlVariables::for_peer(&snapshot),
+            None => ControlVariables::new(),
+        };
+        variables.push("srcadr", self.address.ip());
+        variables.push("srcport", self.address.port());
+        variables
+    }
+}
+
+/// Address under which a reference clock is shown, following the
+/// `127.127.<type>.<unit>` convention of ntpd. Types match those of the
+/// ntpd drivers for the same kind of device, where such a driver exists.
+pub(crate) fn refclock_address(config: &RefClockConfig, unit: u8) -> SocketAddr {
+    let driver_type = match config {
+        RefClockConfig::Nmea(_) => 20,
+        RefClockConfig::Pps(_) => 22,
+        RefClockConfig::Phc(_) | RefClockConfig::Ptp(_) => 0,
+    };
+
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 127, driver_type, unit)), 0)
+}
+
+/// Answer a control request from the current state of the daemon
+pub(crate) fn respond(
+    request: &ControlMessage,
+    system: &SystemSnapshot,
+    associations: &[Association],
+) -> ControlResponse {
+    let association = match request.association_id {
+        0 => None,
+        id => match associations.iter().find(|a| a.id == id) {
+            Some(association) => Some(association),
+            None => return ControlResponse::error(request, ControlError::BadAssociation),
+        },
+    };
+
+    match (request.opcode, association) {
+        (ControlOpcode::ReadStatus, None) => {
+            let data = associations
+                .iter()
+                .flat_map(|a| {
+                    let [id_hi, id_lo] = a.id.to_be_bytes();
+                    let [status_hi, status_lo] = a.status(system).to_be_bytes();
+                    [id_hi, id_lo, status_hi, status_lo]
+                })
+                .collect();
+
+            ControlResponse::new(request, system_status(system.leap_indicator), data)
+        }
+        (ControlOpcode::ReadStatus, Some(association)) => {
+            ControlResponse::new(request, association.status(system), vec![])
+        }
+        (ControlOpcode::ReadVariables, None) => {
+            let mut variables = ControlVariables::for_system(system);
+            variables.push_quoted("version", concat!("ntpd-rs ", env!("CARGO_PKG_VERSION")));
+            if let Some(peer) = associations
+                .iter()
+                .find(|a| a.selection(system) == PeerSelection::SystemPeer)
+            {
+                variables.push("peer", peer.id);
+            }
+
+            ControlResponse::new(
+                request,
+                system_status(system.leap_indicator),
+                variables.encode(&request.requested_variables()),
+            )
+        }
+        (ControlOpcode::ReadVariables, Some(association)) => ControlResponse::new(
+            request,
+            association.status(system),
+            association
+                .variables()
+                .encode(&request.requested_variables()),
+        ),
+        (ControlOpcode::Unsupported(_), _) => {
+            ControlResponse::error(request, ControlError::BadOpcode)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::{peer_snapshot, NtpDuration, NtpInstant, NtpLeapIndicator, PeerStatistics};
+
+    use super::*;
+
+    fn request(opcode: u8, association_id: u16, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x16, opcode, 0, 1, 0, 0];
+        packet.extend_from_slice(&association_id.to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    fn associations() -> (SystemSnapshot, Vec<Association>) {
+        let snapshot = peer_snapshot(
+            PeerStatistics {
+                offset: NtpDuration::from_seconds(0.001),
+                delay: NtpDuration::from_seconds(0.01),
+                dispersion: NtpDuration::from_seconds(0.001),
+                jitter: 0.0001,
+            },
+            NtpInstant::now(),
+            NtpDuration::ZERO,
+            NtpDuration::ZERO,
+        );
+
+        let system = SystemSnapshot {
+            leap_indicator: NtpLeapIndicator::NoWarning,
+            reference_id: snapshot.peer_id,
+            ..Default::default()
+        };
+
+        let associations = vec![
+            Association {
+                id: 1,
+                address: "192.0.2.1:123".parse().unwrap(),
+                snapshot: Some(snapshot),
+                selected: true,
+            },
+            Association {
+                id: 2,
+                address: "192.0.2.2:123".parse().unwrap(),
+                snapshot: None,
+                selected: false,
+            },
+        ];
+
+        (system, associations)
+    }
+
+    fn respond_to(packet: &[u8]) -> Vec<Vec<u8>> {
+        let (system, associations) = associations();
+        let request = ControlMessage::deserialize(packet).unwrap();
+        respond(&request, &system, &associations).serialize_fragments()
+    }
+
+    #[test]
+    fn test_read_status() {
+        let response = respond_to(&request(1, 0, &[]));
+        assert_eq!(response.len(), 1);
+        assert_eq!(&response[0][4..6], &[0x06, 0x00]);
+        assert_eq!(&response[0][10..12], &[0, 8]);
+        assert_eq!(&response[0][12..], &[0, 1, 0x96, 0x00, 0, 2, 0x80, 0x00]);
+    }
+
+    #[test]
+    fn test_read_variables() {
+        let response = respond_to(&request(2, 1, b"srcadr,offset,rec"));
+        assert_eq!(&response[0][4..8], &[0x96, 0x00, 0, 1]);
+        let count = u16::from_be_bytes([response[0][10], response[0][11]]) as usize;
+        assert_eq!(
+            &response[0][12..12 + count],
+            b"offset=1.000, srcadr=192.0.2.1\r\n"
+        );
+
+        let response = respond_to(&request(2, 0, b"peer"));
+        let count = u16::from_be_bytes([response[0][10], response[0][11]]) as usize;
+        assert_eq!(&response[0][12..12 + count], b"peer=1\r\n");
+    }
+
+    #[test]
+    fn test_errors() {
+        let response = respond_to(&request(2, 5, &[]));
+        assert_eq!(response[0][1], 0xc2);
+        assert_eq!(response[0][4], ControlError::BadAssociation as u8);
+
+        let response = respond_to(&request(8, 0, &[]));
+        assert_eq!(response[0][1], 0xc8);
+        assert_eq!(response[0][4], ControlError::BadOpcode as u8);
+    }
+}
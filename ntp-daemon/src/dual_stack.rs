@@ -0,0 +1,98 @@
+//! Choice between the addresses of a dual-stack peer.
+//!
+//! When the host name of a peer with `dual-stack` enabled resolves to both
+//! IPv4 and IPv6 addresses, both addresses are polled. Only one of them, the
+//! active address, is used for synchronization; the other one is a standby
+//! that is only probed, so that the peer still counts as a single source in
+//! clock selection. The address preferred by the resolver starts out active,
+//! and the standby takes over when the active address stops answering, loses
+//! clearly more packets, or has a clearly larger delay.
+
+use ntp_proto::PeerSnapshot;
+
+/// Number of answered polls (out of the last 8) the standby address needs
+/// before it can take over, so that both addresses had a chance to answer
+const MIN_ANSWERED: u32 = 2;
+
+/// How many more of the last 8 polls the standby address must have answered
+/// to take over because of packet loss
+const LOSS_MARGIN: u32 = 2;
+
+/// Fraction of the delay of the active address that the delay of the standby
+/// address must be below to take over
+const DELAY_RATIO: f64 = 0.75;
+
+/// Reachability and delay of one of the addresses of a peer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct AddressStats {
+    /// Number of the last 8 polls that were answered
+    pub(crate) answered: u32,
+    /// Delay of the address, in seconds
+    pub(crate) delay: f64,
+}
+
+impl From<&PeerSnapshot> for AddressStats {
+    fn from(snapshot: &PeerSnapshot) -> Self {
+        AddressStats {
+            answered: snapshot.reach.answered_polls(),
+            delay: snapshot.statistics.delay.to_seconds(),
+        }
+    }
+}
+
+/// Whether the standby address performs clearly better than the active one.
+/// Addresses without a snapshot in the current reset epoch are not compared.
+pub(crate) fn should_switch(active: Option<AddressStats>, standby: Option<AddressStats>) -> bool {
+    let (active, standby) = match (active, standby) {
+        (Some(active), Some(standby)) => (active, standby),
+        _ => return false,
+    };
+
+    if standby.answered < MIN_ANSWERED {
+        false
+    } else if active.answered == 0 || standby.answered >= active.answered + LOSS_MARGIN {
+        true
+    } else if standby.answered < active.answered {
+        false
+    } else {
+        standby.delay < active.delay * DELAY_RATIO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(answered: u32, delay: f64) -> Option<AddressStats> {
+        Some(AddressStats { answered, delay })
+    }
+
+    #[test]
+    fn test_failover() {
+        // the active address stopped answering
+        assert!(should_switch(stats(0, 0.010), stats(8, 0.050)));
+        // but the standby must have answered a few times itself
+        assert!(!should_switch(stats(0, 0.010), stats(1, 0.050)));
+        assert!(!should_switch(stats(0, 0.010), stats(0, 0.050)));
+        // nothing is known after a reset
+        assert!(!should_switch(None, stats(8, 0.010)));
+        assert!(!should_switch(stats(8, 0.010), None));
+    }
+
+    #[test]
+    fn test_loss() {
+        assert!(should_switch(stats(5, 0.010), stats(8, 0.020)));
+        assert!(should_switch(stats(6, 0.010), stats(8, 0.020)));
+        assert!(!should_switch(stats(7, 0.010), stats(8, 0.020)));
+        // a lossier standby never takes over, even when it is faster
+        assert!(!should_switch(stats(8, 0.100), stats(7, 0.010)));
+    }
+
+    #[test]
+    fn test_delay() {
+        assert!(should_switch(stats(8, 0.100), stats(8, 0.070)));
+        assert!(!should_switch(stats(8, 0.100), stats(8, 0.080)));
+        assert!(!should_switch(stats(8, 0.070), stats(8, 0.100)));
+        assert!(!should_switch(stats(8, 0.070), stats(8, 0.070)));
+    }
+}
@@ -0,0 +1,314 @@
+//! Export of the timestamps of every exchange with a peer, for offline
+//! analysis.
+//!
+//! Exchanges are handed to a dedicated thread that writes them to a file, a
+//! FIFO or a unix stream socket. Writing may block, for instance while a FIFO
+//! has no reader, but the peers must never wait for it: the queue to the
+//! writer is bounded, and exchanges that do not fit are dropped and counted.
+
+use std::{
+    fs::OpenOptions,
+    io::{LineWriter, Write},
+    net::SocketAddr,
+    os::unix::{fs::FileTypeExt, net::UnixStream},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+};
+
+use ntp_proto::{NtpDuration, NtpPacket, NtpTimestamp};
+use tracing::{error, warn};
+
+use crate::config::{ExportConfig, ExportFormat};
+
+/// Number of exchanges that can wait for the writer
+const QUEUE_SIZE: usize = 1024;
+
+const CSV_HEADER: &str = "peer,t1,t2,t3,t4,offset,delay,authenticated";
+
+/// The timestamps of a request and its response, and what they measure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exchange {
+    pub(crate) peer: SocketAddr,
+    /// Transmit time of the request, by our clock
+    pub(crate) t1: NtpTimestamp,
+    /// Receive time of the request, by the clock of the peer
+    pub(crate) t2: NtpTimestamp,
+    /// Transmit time of the response, by the clock of the peer
+    pub(crate) t3: NtpTimestamp,
+    /// Receive time of the response, by our clock
+    pub(crate) t4: NtpTimestamp,
+    pub(crate) offset: NtpDuration,
+    pub(crate) delay: NtpDuration,
+    pub(crate) authenticated: bool,
+}
+
+impl Exchange {
+    pub(crate) fn new(
+        peer: SocketAddr,
+        send_timestamp: NtpTimestamp,
+        recv_timestamp: NtpTimestamp,
+        packet: &NtpPacket,
+        authenticated: bool,
+    ) -> Self {
+        Self::from_timestamps(
+            peer,
+            [
+                send_timestamp,
+                packet.receive_timestamp(),
+                packet.transmit_timestamp(),
+                recv_timestamp,
+            ],
+            authenticated,
+        )
+    }
+
+    pub(crate) fn from_timestamps(
+        peer: SocketAddr,
+        [t1, t2, t3, t4]: [NtpTimestamp; 4],
+        authenticated: bool,
+    ) -> Self {
+        Exchange {
+            peer,
+            t1,
+            t2,
+            t3,
+            t4,
+            offset: ((t2 - t1) + (t3 - t4)) / 2i64,
+            delay: (t4 - t1) - (t3 - t2),
+            authenticated,
+        }
+    }
+
+    fn format(&self, format: ExportFormat) -> String {
+        let offset = self.offset.to_seconds();
+        let delay = self.delay.to_seconds();
+        // timestamps are written in full, as floating point numbers can not
+        // hold them with nanosecond precision
+        match format {
+            ExportFormat::JsonLines => format!(
+                r#"{{"peer":"{}","t1":{},"t2":{},"t3":{},"t4":{},"offset":{:.9},"delay":{:.9},"authenticated":{}}}"#,
+                self.peer, self.t1, self.t2, self.t3, self.t4, offset, delay, self.authenticated
+            ),
+            ExportFormat::Csv => format!(
+                "{},{},{},{},{},{:.9},{:.9},{}",
+                self.peer, self.t1, self.t2, self.t3, self.t4, offset, delay, self.authenticated
+            ),
+        }
+    }
+}
+
+/// Handle to the exporter. Cheap to clone, and does nothing when the export
+/// is not enabled.
+#[derive(Debug, Clone, Default)]
+pub struct MeasurementExport {
+    sender: Option<SyncSender<Exchange>>,
+    dropped: Arc<AtomicU64>,
+    /// Exchanges are also pushed as spans to an OpenTelemetry collector
+    #[cfg(feature = "otlp")]
+    spans: Option<tokio::sync::mpsc::Sender<Exchange>>,
+}
+
+impl MeasurementExport {
+    /// Start exporting to the destination in the configuration
+    pub fn spawn(config: &ExportConfig) -> Self {
+        let path = match &config.path {
+            Some(path) => path.clone(),
+            None => return Self::default(),
+        };
+
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let format = config.format;
+        let counter = dropped.clone();
+
+        let result = std::thread::Builder::new()
+            .name("export".into())
+            .spawn(move || write_exchanges(&path, format, receiver, &counter));
+
+        if let Err(error) = result {
+            error!(?error, "could not start export thread");
+            return Self::default();
+        }
+
+        MeasurementExport {
+            sender: Some(sender),
+            dropped,
+            #[cfg(feature = "otlp")]
+            spans: None,
+        }
+    }
+
+    /// Also hand every exchange to the OpenTelemetry exporter
+    #[cfg(feature = "otlp")]
+    pub(crate) fn with_spans(self, spans: tokio::sync::mpsc::Sender<Exchange>) -> Self {
+        MeasurementExport {
+            spans: Some(spans),
+            ..self
+        }
+    }
+
+    /// Queue an exchange for export, dropping it when the writer falls behind
+    pub(crate) fn exchange(&self, exchange: Exchange) {
+        #[cfg(feature = "otlp")]
+        if let Some(spans) = &self.spans {
+            // spans that do not fit before the next push are dropped
+            let _ = spans.try_send(exchange);
+        }
+
+        if let Some(sender) = &self.sender {
+            match sender.try_send(exchange) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                // the writer only stops when the daemon does
+                Err(TrySendError::Disconnected(_)) => {}
+            }
+        }
+    }
+}
+
+/// Open the destination: a unix stream socket is connected to, anything else
+/// is appended to. Opening a FIFO blocks until it has a reader.
+fn open(path: &Path, format: ExportFormat) -> std::io::Result<LineWriter<Box<dyn Write + Send>>> {
+    let is_socket = std::fs::metadata(path)
+        .map(|metadata| metadata.file_type().is_socket())
+        .unwrap_or(false);
+
+    let destination: Box<dyn Write + Send> = if is_socket {
+        Box::new(UnixStream::connect(path)?)
+    } else {
+        Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+    };
+
+    let mut writer = LineWriter::new(destination);
+    if format == ExportFormat::Csv {
+        writeln!(writer, "{CSV_HEADER}")?;
+    }
+
+    Ok(writer)
+}
+
+fn write_exchanges(
+    path: &Path,
+    format: ExportFormat,
+    receiver: Receiver<Exchange>,
+    dropped: &AtomicU64,
+) {
+    let mut destination = None;
+    // only report the first of a series of failures
+    let mut failing = false;
+
+    for exchange in receiver {
+        let count = dropped.swap(0, Ordering::Relaxed);
+        if count > 0 {
+            warn!(count, "dropped exchanges, as the export could not keep up");
+        }
+
+        if destination.is_none() {
+            match open(path, format) {
+                Ok(writer) => destination = Some(writer),
+                Err(error) => {
+                    if !failing {
+                        warn!(?error, ?path, "could not open export destination");
+                    }
+                    failing = true;
+                    continue;
+                }
+            }
+        }
+
+        if let Some(writer) = &mut destination {
+            match writeln!(writer, "{}", exchange.format(format)) {
+                Ok(()) => failing = false,
+                Err(error) => {
+                    if !failing {
+                        warn!(?error, ?path, "could not write to export destination");
+                    }
+                    failing = true;
+                    // a reader of a FIFO or socket may have gone away, open again
+                    destination = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange() -> Exchange {
+        let timestamp =
+            |nanos| NtpTimestamp::from_seconds_nanos_since_ntp_era(3_900_000_000, nanos);
+
+        Exchange::from_timestamps(
+            "192.0.2.1:123".parse().unwrap(),
+            [
+                timestamp(0),
+                timestamp(250_000_000),
+                timestamp(500_000_000),
+                timestamp(875_000_000),
+            ],
+            false,
+        )
+    }
+
+    #[test]
+    fn test_measurement() {
+        let exchange = exchange();
+        assert!((exchange.offset.to_seconds() + 0.0625).abs() < 1e-9);
+        assert!((exchange.delay.to_seconds() - 0.625).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_format() {
+        let exchange = exchange();
+
+        assert_eq!(
+            exchange.format(ExportFormat::Csv),
+            "192.0.2.1:123,3900000000.000000000,3900000000.250000000,3900000000.500000000,3900000000.875000000,-0.062500000,0.625000000,false"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&exchange.format(ExportFormat::JsonLines)).unwrap();
+        assert_eq!(json["peer"], "192.0.2.1:123");
+        assert_eq!(json["authenticated"], false);
+        assert!((json["delay"].as_f64().unwrap() - 0.625).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_export_to_file() {
+        let path = std::env::temp_dir().join("ntpd-rs-test-export.csv");
+        std::fs::remove_file(&path).ok();
+
+        let export = MeasurementExport::spawn(&ExportConfig {
+            path: Some(path.clone()),
+            format: ExportFormat::Csv,
+        });
+        export.exchange(exchange());
+        export.exchange(exchange());
+
+        // the writer thread stops once all handles are gone
+        drop(export);
+        let mut contents = String::new();
+        for _ in 0..100 {
+            contents = std::fs::read_to_string(&path).unwrap_or_default();
+            if contents.lines().count() == 3 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], CSV_HEADER);
+        assert!(lines[1].starts_with("192.0.2.1:123,"));
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,205 @@
+//! Which features of the host the daemon can use, probed once at startup.
+//!
+//! The accuracy of the clock depends on more than the configuration: on the
+//! privileges of the daemon, on what the kernel offers and on the hardware.
+//! These are logged at startup and kept for the observation socket, so that
+//! it is easy to see why the clock is not as accurate as expected.
+
+use std::path::{Path, PathBuf};
+
+use ntp_os_clock::{has_capability, kernel_pll_available, Capability, PhcDevice, PpsDevice};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+/// What the host offers the daemon, as found at startup
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostFeatures {
+    /// Whether the daemon may adjust the system clock, which needs
+    /// `CAP_SYS_TIME`, if that could be determined
+    pub sys_time: Option<bool>,
+    /// Whether the clock discipline of the kernel can be used
+    pub kernel_pll: bool,
+    /// Timestamping support of the network interfaces
+    pub interfaces: Vec<InterfaceFeatures>,
+    /// PPS devices, `/dev/ppsN`
+    pub pps_devices: Vec<DeviceFeature>,
+    /// PTP hardware clocks, `/dev/ptpN`
+    pub phc_devices: Vec<DeviceFeature>,
+    /// TLS implementation used for NTS key exchange. NTS is not implemented
+    /// yet, so there is none.
+    pub nts_tls_backend: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterfaceFeatures {
+    pub interface: String,
+    /// Timestamps taken by the kernel
+    pub software_timestamping: bool,
+    /// Timestamps taken by the network card
+    pub hardware_timestamping: bool,
+    /// The PTP hardware clock of the network card, if it has one
+    pub phc: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceFeature {
+    pub path: PathBuf,
+    /// Whether the daemon may open the device
+    pub accessible: bool,
+}
+
+/// Find out which features the host offers, and log them. Done after
+/// dropping privileges, so that only what the daemon may still use is
+/// reported, and before installing the syscall filter.
+pub fn probe() -> HostFeatures {
+    let interfaces = match ntp_udp::interface_timestamping() {
+        Ok(interfaces) => interfaces
+            .into_iter()
+            .map(|interface| InterfaceFeatures {
+                interface: interface.interface,
+                software_timestamping: interface.software,
+                hardware_timestamping: interface.hardware,
+                phc: interface
+                    .phc_index
+                    .map(|index| PathBuf::from(format!("/dev/ptp{index}"))),
+            })
+            .collect(),
+        Err(error) => {
+            warn!(
+                ?error,
+                "could not list the timestamping support of the network interfaces"
+            );
+            vec![]
+        }
+    };
+
+    let features = HostFeatures {
+        sys_time: has_capability(Capability::SysTime).ok(),
+        kernel_pll: kernel_pll_available(),
+        interfaces,
+        pps_devices: devices(Path::new("/dev"), "pps", |path| {
+            PpsDevice::open(path).is_ok()
+        }),
+        phc_devices: devices(Path::new("/dev"), "ptp", |path| {
+            PhcDevice::open(path).is_ok()
+        }),
+        nts_tls_backend: None,
+    };
+
+    features.log();
+    features
+}
+
+impl HostFeatures {
+    fn log(&self) {
+        let hardware_timestamping: Vec<_> = self
+            .interfaces
+            .iter()
+            .filter(|interface| interface.hardware_timestamping)
+            .map(|interface| interface.interface.as_str())
+            .collect();
+        let pps_devices: Vec<_> = self.pps_devices.iter().map(|d| &d.path).collect();
+        let phc_devices: Vec<_> = self.phc_devices.iter().map(|d| &d.path).collect();
+
+        info!(
+            sys_time = ?self.sys_time,
+            kernel_pll = self.kernel_pll,
+            ?hardware_timestamping,
+            ?pps_devices,
+            ?phc_devices,
+            nts = self.nts_tls_backend.as_deref().unwrap_or("not available"),
+            "Features of the host"
+        );
+
+        for interface in &self.interfaces {
+            debug!(
+                interface = interface.interface,
+                software = interface.software_timestamping,
+                hardware = interface.hardware_timestamping,
+                phc = ?interface.phc,
+                "timestamping support"
+            );
+        }
+
+        for device in self.pps_devices.iter().chain(&self.phc_devices) {
+            if !device.accessible {
+                warn!(path = ?device.path, "the daemon may not open this device");
+            }
+        }
+
+        if !self.kernel_pll {
+            warn!("the clock discipline of the kernel can not be used");
+        }
+    }
+}
+
+/// The devices in `directory` of which the name is `prefix` followed by a
+/// number, in the order of that number
+fn devices(
+    directory: &Path,
+    prefix: &str,
+    accessible: impl Fn(&Path) -> bool,
+) -> Vec<DeviceFeature> {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(error) => {
+            debug!(?error, ?directory, "could not list the devices");
+            return vec![];
+        }
+    };
+
+    let mut numbered: Vec<(u32, PathBuf)> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let number = path
+                .file_name()?
+                .to_str()?
+                .strip_prefix(prefix)?
+                .parse()
+                .ok()?;
+            Some((number, path))
+        })
+        .collect();
+    numbered.sort();
+
+    numbered
+        .into_iter()
+        .map(|(_, path)| DeviceFeature {
+            accessible: accessible(&path),
+            path,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_devices() {
+        let directory = std::env::temp_dir().join("ntp-test-features-devices");
+        std::fs::create_dir_all(&directory).unwrap();
+        for name in ["pps10", "pps2", "pps", "ppsa", "ptp0", "ttyS0"] {
+            std::fs::write(directory.join(name), b"").unwrap();
+        }
+
+        let found = devices(&directory, "pps", |path| path.ends_with("pps2"));
+        assert_eq!(
+            found,
+            vec![
+                DeviceFeature {
+                    path: directory.join("pps2"),
+                    accessible: true,
+                },
+                DeviceFeature {
+                    path: directory.join("pps10"),
+                    accessible: false,
+                },
+            ]
+        );
+
+        assert!(devices(&directory.join("missing"), "ptp", |_| true).is_empty());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+//! Liveness and readiness endpoints for container orchestration.
+//!
+//! A minimal HTTP listener answers `GET /livez` as long as the daemon runs,
+//! and `GET /readyz` only while the clock is synchronized within the
+//! configured bounds on its offset and root distance, such that probes can
+//! hold back workloads that depend on the correct time.
+
+use std::{sync::Arc, time::Duration};
+
+use ntp_proto::{NtpClock, NtpDuration, SystemSnapshot};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use tracing::{debug, error};
+
+use crate::{config::HealthConfig, observer::ClockSample, peer_manager::Peers};
+
+/// Limit on the size of a request, which is only a request line and headers
+const MAX_REQUEST_SIZE: usize = 4096;
+
+/// Time a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why the daemon is not ready
+#[derive(Debug, Error, Clone, Copy, PartialEq)]
+pub(crate) enum NotReady {
+    #[error("clock is not synchronized")]
+    Unsynchronized,
+    #[error("offset of {0:.6} s exceeds the limit")]
+    Offset(f64),
+    #[error("root distance of {0:.6} s exceeds the limit")]
+    RootDistance(f64),
+}
+
+/// Whether the clock is synchronized within the bounds of the configuration
+pub(crate) fn readiness(
+    config: &HealthConfig,
+    system: &SystemSnapshot,
+    last_update: Option<ClockSample>,
+) -> Result<(), NotReady> {
+    if !system.leap_indicator.is_synchronized() {
+        return Err(NotReady::Unsynchronized);
+    }
+
+    if let Some(update) = last_update {
+        if update.offset.abs() > config.max_offset.to_seconds() {
+            return Err(NotReady::Offset(update.offset));
+        }
+    }
+
+    let root_distance: NtpDuration = system.root_delay / 2i64 + system.root_dispersion;
+    if root_distance > config.max_root_distance {
+        return Err(NotReady::RootDistance(root_distance.to_seconds()));
+    }
+
+    Ok(())
+}
+
+pub async fn spawn<C: NtpClock + Sync + Send + 'static>(
+    config: &HealthConfig,
+    peers_reader: Arc<tokio::sync::RwLock<Peers<C>>>,
+    system_reader: Arc<tokio::sync::RwLock<SystemSnapshot>>,
+) -> JoinHandle<std::io::Result<()>> {
+    let config = config.clone();
+    tokio::spawn(async move {
+        let listen = match config.listen {
+            Some(listen) => listen,
+            None => return Ok(()),
+        };
+
+        let result = match TcpListener::bind(listen).await {
+            Ok(listener) => serve(listener, config, peers_reader, system_reader).await,
+            Err(e) => Err(e),
+        };
+        if let Err(ref e) = result {
+            error!("Abnormal termination of health endpoints: {}", e);
+        }
+        result
+    })
+}
+
+async fn serve<C: NtpClock + Sync + Send + 'static>(
+    listener: TcpListener,
+    config: HealthConfig,
+    peers_reader: Arc<tokio::sync::RwLock<Peers<C>>>,
+    system_reader: Arc<tokio::sync::RwLock<SystemSnapshot>>,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, _addr) = listener.accept().await?;
+
+        let config = config.clone();
+        let peers_reader = peers_reader.clone();
+        let system_reader = system_reader.clone();
+        tokio::spawn(async move {
+            let result = tokio::time::timeout(
+                REQUEST_TIMEOUT,
+                handle(stream, &config, &peers_reader, &system_reader),
+            )
+            .await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(error)) => debug!(?error, "could not answer health request"),
+                Err(_) => debug!("health request timed out"),
+            }
+        });
+    }
+}
+
+/// The method and path of a request
+fn parse_request(request: &[u8]) -> Option<(&str, &str)> {
+    let request = std::str::from_utf8(request).ok()?;
+    let mut parts = request.lines().next()?.split(' ');
+    let method = parts.next()?;
+    let path = parts.next()?;
+    parts
+        .next()?
+        .starts_with("HTTP/1.")
+        .then_some((method, path))
+}
+
+fn response(status: u16, reason: &str, body: &str, head: bool) -> String {
+    let mut response = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    if !head {
+        response.push_str(body);
+    }
+    response
+}
+
+async fn handle<C: NtpClock>(
+    mut stream: TcpStream,
+    config: &HealthConfig,
+    peers_reader: &tokio::sync::RwLock<Peers<C>>,
+    system_reader: &tokio::sync::RwLock<SystemSnapshot>,
+) -> std::io::Result<()> {
+    let mut buf = vec![0; MAX_REQUEST_SIZE];
+    let mut length = 0;
+    while !buf[..length].windows(4).any(|window| window == b"\r\n\r\n") {
+        if length == buf.len() {
+            break;
+        }
+        match stream.read(&mut buf[length..]).await? {
+            0 => break,
+            n => length += n,
+        }
+    }
+
+    let response = match parse_request(&buf[..length]) {
+        None => response(400, "Bad Request", "bad request\n", false),
+        Some((method, path)) if method == "GET" || method == "HEAD" => {
+            let head = method == "HEAD";
+            match path {
+                "/livez" => response(200, "OK", "ok\n", head),
+                "/readyz" => {
+                    let system = *system_reader.read().await;
+                    let last_update = peers_reader.read().await.last_clock_update();
+                    match readiness(config, &system, last_update) {
+                        Ok(()) => response(200, "OK", "ok\n", head),
+                        Err(reason) => {
+                            response(503, "Service Unavailable", &format!("{reason}\n"), head)
+                        }
+                    }
+                }
+                _ => response(404, "Not Found", "not found\n", head),
+            }
+        }
+        Some(_) => response(405, "Method Not Allowed", "method not allowed\n", false),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_os_clock::UnixNtpClock;
+    use ntp_proto::NtpLeapIndicator;
+
+    use crate::{config::NetworkConfig, peer::PeerChannels};
+
+    use super::*;
+
+    fn synchronized() -> SystemSnapshot {
+        SystemSnapshot {
+            stratum: 2,
+            leap_indicator: NtpLeapIndicator::NoWarning,
+            root_delay: NtpDuration::from_seconds(0.02),
+            root_dispersion: NtpDuration::from_seconds(0.01),
+            ..Default::default()
+        }
+    }
+
+    fn update(offset: f64) -> Option<ClockSample> {
+        Some(ClockSample {
+            time: 1_700_000_000.0,
+            offset,
+            frequency: 0.0,
+            jitter: 0.0001,
+        })
+    }
+
+    #[test]
+    fn test_readiness() {
+        let config = HealthConfig::default();
+
+        assert_eq!(
+            readiness(&config, &SystemSnapshot::default(), None),
+            Err(NotReady::Unsynchronized)
+        );
+        assert_eq!(readiness(&config, &synchronized(), None), Ok(()));
+        assert_eq!(readiness(&config, &synchronized(), update(-0.05)), Ok(()));
+        assert_eq!(
+            readiness(&config, &synchronized(), update(-0.5)),
+            Err(NotReady::Offset(-0.5))
+        );
+
+        let system = SystemSnapshot {
+            root_dispersion: NtpDuration::from_seconds(2.0),
+            ..synchronized()
+        };
+        assert!(matches!(
+            readiness(&config, &system, update(0.0)),
+            Err(NotReady::RootDistance(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            parse_request(b"GET /readyz HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            Some(("GET", "/readyz"))
+        );
+        assert_eq!(parse_request(b"GET /readyz\r\n\r\n"), None);
+        assert_eq!(parse_request(b"\xff\xfe"), None);
+    }
+
+    async fn request(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_endpoints() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let peers = Arc::new(tokio::sync::RwLock::new(Peers::new(
+            PeerChannels::test(),
+            UnixNtpClock::new(),
+            NetworkConfig::default(),
+        )));
+        let system = Arc::new(tokio::sync::RwLock::new(SystemSnapshot::default()));
+        let handle = tokio::spawn(serve(
+            listener,
+            HealthConfig::default(),
+            peers,
+            system.clone(),
+        ));
+
+        let live = request(addr, "GET /livez HTTP/1.1\r\n\r\n").await;
+        assert!(live.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let ready = request(addr, "GET /readyz HTTP/1.1\r\n\r\n").await;
+        assert!(ready.starts_with("HTTP/1.1 503 "));
+        assert!(ready.ends_with("clock is not synchronized\n"));
+
+        *system.write().await = synchronized();
+        let ready = request(addr, "GET /readyz HTTP/1.1\r\n\r\n").await;
+        assert!(ready.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let head = request(addr, "HEAD /readyz HTTP/1.1\r\n\r\n").await;
+        assert!(head.ends_with("\r\n\r\n"));
+
+        let missing = request(addr, "GET /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(missing.starts_with("HTTP/1.1 404 "));
+
+        handle.abort();
+    }
+}
@@ -0,0 +1,158 @@
+//! Recent clock updates and measurements of the sources, kept in memory.
+//!
+//! The statistics files show how the clock and the sources behaved over a
+//! long time, but have to be enabled up front and read from disk. The most
+//! recent samples are also kept in fixed-size ring buffers, and handed to the
+//! management client with the rest of the observable state, so that trends
+//! in the offsets and the frequency can be shown at any time.
+
+use std::collections::VecDeque;
+
+use ntp_proto::{NtpDuration, NtpTimestamp, PeerSnapshot};
+use serde::{Deserialize, Serialize};
+
+/// Number of updates of the system clock that are kept
+pub(crate) const CLOCK_HISTORY: usize = 1024;
+
+/// Number of measurements that are kept per source
+pub(crate) const PEER_HISTORY: usize = 64;
+
+/// The most recent samples, up to a fixed number. Adding a sample to a full
+/// history drops the oldest one.
+#[derive(Debug, Clone)]
+pub(crate) struct History<T> {
+    samples: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T: Clone> History<T> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        History {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub(crate) fn push(&mut self, sample: T) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// The most recent sample
+    pub(crate) fn last(&self) -> Option<&T> {
+        self.samples.back()
+    }
+
+    /// The samples, oldest first
+    pub(crate) fn samples(&self) -> Vec<T> {
+        self.samples.iter().cloned().collect()
+    }
+}
+
+/// Seconds since the unix epoch
+fn unix_seconds(time: NtpTimestamp) -> f64 {
+    let (seconds, nanos) = time.to_unix_seconds_nanos();
+    seconds as f64 + nanos as f64 * 1e-9
+}
+
+/// An update of the system clock
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClockSample {
+    /// Time of the update, in seconds since the unix epoch
+    pub time: f64,
+    /// Estimated offset of the clock, in seconds
+    pub offset: f64,
+    /// Frequency correction of the clock, in parts per million
+    pub frequency: f64,
+    /// Estimated jitter of the clock, in seconds
+    pub jitter: f64,
+}
+
+impl ClockSample {
+    pub(crate) fn new(
+        time: NtpTimestamp,
+        offset: NtpDuration,
+        frequency: f64,
+        jitter: NtpDuration,
+    ) -> Self {
+        ClockSample {
+            time: unix_seconds(time),
+            offset: offset.to_seconds(),
+            frequency: frequency * 1e6,
+            jitter: jitter.to_seconds(),
+        }
+    }
+}
+
+/// A measurement of a source
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PeerSample {
+    /// Time of the measurement, in seconds since the unix epoch
+    pub time: f64,
+    /// Offset of the source, in seconds
+    pub offset: f64,
+    /// Round-trip delay to the source, in seconds
+    pub delay: f64,
+    /// Jitter of the offset of the source, in seconds
+    pub jitter: f64,
+}
+
+impl PeerSample {
+    pub(crate) fn new(time: NtpTimestamp, snapshot: &PeerSnapshot) -> Self {
+        PeerSample {
+            time: unix_seconds(time),
+            offset: snapshot.statistics.offset.to_seconds(),
+            delay: snapshot.statistics.delay.to_seconds(),
+            jitter: snapshot.statistics.jitter,
+        }
+    }
+}
+
+/// The recent measurements of a single source
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerHistory {
+    pub address: String,
+    pub samples: Vec<PeerSample>,
+}
+
+/// The recent clock updates and measurements, as shown by the management
+/// client
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ObservableHistory {
+    pub clock: Vec<ClockSample>,
+    pub peers: Vec<PeerHistory>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounded() {
+        let mut history = History::new(3);
+        assert!(history.samples().is_empty());
+        assert_eq!(history.last(), None);
+
+        for sample in 0..5 {
+            history.push(sample);
+        }
+        assert_eq!(history.samples(), vec![2, 3, 4]);
+        assert_eq!(history.last(), Some(&4));
+    }
+
+    #[test]
+    fn test_clock_sample_units() {
+        let sample = ClockSample::new(
+            NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 500_000_000),
+            NtpDuration::from_seconds(0.001),
+            12e-6,
+            NtpDuration::from_seconds(0.0001),
+        );
+
+        assert!((sample.time - 1_700_000_000.5).abs() < 1e-6);
+        assert!((sample.offset - 0.001).abs() < 1e-9);
+        assert!((sample.frequency - 12.0).abs() < 1e-9);
+    }
+}
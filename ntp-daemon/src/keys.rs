@@ -0,0 +1,336 @@
+//! The keys file, with the symmetric keys with which peers and clients are
+//! authenticated.
+//!
+//! The file is TOML, with a `[[keys]]` table per key:
+//!
+//! ```toml
+//! [[keys]]
+//! id = 1
+//! algorithm = "aes-128-cmac"
+//! key = "8d5fb4c0c8a6ef2dbc2fa6d3d9c3e0a1"
+//! valid-from = 1760572800
+//! valid-until = 1763251200
+//! ```
+//!
+//! The key is hex encoded, and the optional bounds of its validity are unix
+//! timestamps. Keys can share an id when their validity periods follow each
+//! other, which is how `ntp-ctl keys rotate` replaces a key. The file is read
+//! again when it changes, such that a new key is picked up without a restart.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use ntp_proto::{KeyAlgorithm, KeyError, KeySet, NtpTimestamp, SymmetricKey};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::config::KeysConfig;
+
+/// How often the keys file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum KeysError {
+    #[error("io error while accessing keys file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("keys file is not valid TOML: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("keys file can be accessed by others than its owner (mode {0:o}), make it readable by its owner only")]
+    Permissions(u32),
+    #[error("key {0} is not valid hex")]
+    Hex(u32),
+    #[error("key {0}: {1}")]
+    Key(u32, KeyError),
+    #[error("there is no key with id {0}")]
+    UnknownId(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct KeyEntry {
+    pub id: u32,
+    pub algorithm: KeyAlgorithm,
+    /// The key, hex encoded
+    pub key: String,
+    /// Unix time from which the key is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_from: Option<i64>,
+    /// Unix time until which the key is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_until: Option<i64>,
+}
+
+impl KeyEntry {
+    fn symmetric_key(&self) -> Result<SymmetricKey, KeysError> {
+        let key = decode_hex(&self.key).ok_or(KeysError::Hex(self.id))?;
+        let to_timestamp = |seconds| NtpTimestamp::from_unix_seconds_nanos(seconds, 0);
+
+        Ok(SymmetricKey::new(self.id, self.algorithm, key)
+            .map_err(|error| KeysError::Key(self.id, error))?
+            .valid_from(self.valid_from.map(to_timestamp))
+            .valid_until(self.valid_until.map(to_timestamp)))
+    }
+}
+
+/// Contents of the keys file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeysFile {
+    #[serde(default)]
+    pub keys: Vec<KeyEntry>,
+}
+
+impl KeysFile {
+    /// Read the keys file, which must only be accessible by its owner
+    pub fn read(path: &Path) -> Result<Self, KeysError> {
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(KeysError::Permissions(mode & 0o777));
+        }
+
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// Write the keys file, only accessible by its owner. The file is
+    /// replaced as a whole, so the daemon never reads half of it.
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let contents = toml::to_string(self)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temporary)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+
+        std::fs::rename(&temporary, path)
+    }
+
+    pub fn key_set(&self) -> Result<KeySet, KeysError> {
+        let keys = self
+            .keys
+            .iter()
+            .map(KeyEntry::symmetric_key)
+            .collect::<Result<_, _>>()?;
+        Ok(KeySet::new(keys))
+    }
+
+    /// Add a new random key, with the given id or else the next free one,
+    /// and return its id
+    pub fn generate(
+        &mut self,
+        id: Option<u32>,
+        algorithm: KeyAlgorithm,
+        valid_from: Option<i64>,
+        valid_until: Option<i64>,
+    ) -> u32 {
+        let id = id.unwrap_or_else(|| self.keys.iter().map(|key| key.id).max().unwrap_or(0) + 1);
+        self.keys.push(KeyEntry {
+            id,
+            algorithm,
+            key: random_key(algorithm),
+            valid_from,
+            valid_until,
+        });
+        id
+    }
+
+    /// Replace the key with `id` by a new random key that is used from
+    /// `activate_at` on. The keys it replaces stay valid for `overlap` after
+    /// that, so that packets signed by a peer that does not have the new key
+    /// yet are still accepted.
+    pub fn rotate(&mut self, id: u32, activate_at: i64, overlap: i64) -> Result<(), KeysError> {
+        let algorithm = self
+            .keys
+            .iter()
+            .filter(|key| key.id == id)
+            .max_by_key(|key| key.valid_from)
+            .map(|key| key.algorithm)
+            .ok_or(KeysError::UnknownId(id))?;
+
+        let expiry = activate_at + overlap;
+        for key in self.keys.iter_mut().filter(|key| key.id == id) {
+            if key.valid_until.map(|until| until > expiry).unwrap_or(true) {
+                key.valid_until = Some(expiry);
+            }
+        }
+
+        self.generate(Some(id), algorithm, Some(activate_at), None);
+        Ok(())
+    }
+}
+
+fn random_key(algorithm: KeyAlgorithm) -> String {
+    let mut key = vec![0u8; algorithm.key_size()];
+    thread_rng().fill(&mut key[..]);
+    key.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 == 1 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Handle to the keys that are in use. Cheap to clone, and holds no keys
+/// when there is no keys file in the configuration.
+#[derive(Debug, Clone, Default)]
+pub struct Keys {
+    keys: Arc<ArcSwap<KeySet>>,
+}
+
+impl Keys {
+    /// Read the keys file in the configuration. The daemon must not start
+    /// when this fails, as it would run without authentication.
+    pub fn load(config: &KeysConfig) -> Result<Self, KeysError> {
+        let keys = Keys::default();
+        if let Some(path) = &config.path {
+            let key_set = KeysFile::read(path)?.key_set()?;
+            info!(?path, keys = key_set.keys().len(), "loaded keys");
+            keys.keys.store(Arc::new(key_set));
+        }
+
+        Ok(keys)
+    }
+
+    pub(crate) fn current(&self) -> Arc<KeySet> {
+        self.keys.load_full()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_key_set(key_set: KeySet) -> Self {
+        Keys {
+            keys: Arc::new(ArcSwap::from_pointee(key_set)),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Read the keys file again whenever it changes. A file that can no longer be
+/// read leaves the keys as they were.
+pub fn spawn(config: &KeysConfig, keys: Keys) -> JoinHandle<()> {
+    let path: Option<PathBuf> = config.path.clone();
+    tokio::spawn(async move {
+        let path = match path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let mut last_modified = modified(&path);
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let current = modified(&path);
+            if current == last_modified {
+                continue;
+            }
+            last_modified = current;
+
+            match KeysFile::read(&path).and_then(|file| file.key_set()) {
+                Ok(key_set) => {
+                    info!(?path, keys = key_set.keys().len(), "reloaded keys");
+                    keys.keys.store(Arc::new(key_set));
+                }
+                Err(error) => {
+                    warn!(%error, ?path, "could not reload keys file, keeping the current keys")
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(decode_hex("00ff7a"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex("0"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("é0"), None);
+        assert_eq!(random_key(KeyAlgorithm::Sha1).len(), 40);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let mut file = KeysFile::default();
+        assert_eq!(file.generate(None, KeyAlgorithm::Aes128Cmac, None, None), 1);
+        assert_eq!(file.generate(None, KeyAlgorithm::Md5, None, None), 2);
+
+        file.rotate(1, 1_000, 100).unwrap();
+        assert_eq!(file.keys.len(), 3);
+        assert_eq!(file.keys[0].valid_until, Some(1_100));
+        assert_eq!(file.keys[1].valid_until, None);
+        assert_eq!(file.keys[2].id, 1);
+        assert_eq!(file.keys[2].algorithm, KeyAlgorithm::Aes128Cmac);
+        assert_eq!(file.keys[2].valid_from, Some(1_000));
+        assert_ne!(file.keys[2].key, file.keys[0].key);
+
+        let key_set = file.key_set().unwrap();
+        let at = |seconds| NtpTimestamp::from_unix_seconds_nanos(seconds, 0);
+        let key = |index: usize| file.keys[index].symmetric_key().unwrap();
+        assert_eq!(key_set.signing_key(1, at(999)), Some(&key(0)));
+        assert_eq!(key_set.signing_key(1, at(1_050)), Some(&key(2)));
+
+        assert!(matches!(file.rotate(3, 0, 0), Err(KeysError::UnknownId(3))));
+    }
+
+    #[test]
+    fn test_keys_file() {
+        let path = std::env::temp_dir().join("ntpd-rs-test-keys.toml");
+        std::fs::remove_file(&path).ok();
+
+        let mut file = KeysFile::default();
+        file.generate(Some(5), KeyAlgorithm::Sha1, Some(1_700_000_000), None);
+        file.write(&path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(KeysFile::read(&path).unwrap(), file);
+
+        let config = KeysConfig {
+            path: Some(path.clone()),
+        };
+        let keys = Keys::load(&config).unwrap();
+        assert_eq!(keys.current().keys()[0].id(), 5);
+
+        // a file that others can read is refused
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(
+            Keys::load(&config),
+            Err(KeysError::Permissions(0o644))
+        ));
+
+        std::fs::remove_file(&path).ok();
+        assert!(Keys::load(&KeysConfig::default())
+            .unwrap()
+            .current()
+            .is_empty());
+    }
+}
@@ -1,19 +1,50 @@
 //#![forbid(unsafe_code)]
 
+pub mod capture;
+pub mod chrony;
+pub mod clock;
+mod clock_jump;
 pub mod config;
+mod control;
+mod dual_stack;
+pub mod export;
+pub mod features;
+pub mod health;
+mod history;
 mod ipfilter;
+pub mod keys;
+mod mru;
 pub mod observer;
+#[cfg(feature = "otlp")]
+pub mod otlp;
 mod peer;
 mod peer_manager;
+pub mod persistence;
+mod pool;
+pub mod privileges;
+mod quality;
+mod refclock;
+mod rejection;
+pub mod roughtime;
+pub mod rtc;
+pub mod sandbox;
 mod server;
+mod smear;
 pub mod sockets;
+mod socks5;
+mod statistics;
+pub mod steering;
 mod system;
+mod systemd;
+pub mod tai;
 pub mod tracing;
+mod transport;
 
 pub use config::dynamic::ConfigUpdate;
 pub use config::Config;
 pub use observer::{ObservablePeerState, ObservableState};
 pub use peer_manager::Peers;
+pub use server::{Drain, DrainMode};
 pub use system::spawn;
 //#[cfg(fuzz)]
 pub use ipfilter::fuzz::fuzz_ipfilter;
@@ -1,16 +1,23 @@
 #![forbid(unsafe_code)]
 
 use clap::Parser;
-use ntp_daemon::config::{CmdArgs, Config};
+use ntp_daemon::{
+    clock::DaemonClock,
+    config::{CmdArgs, Config, Diagnostic, Severity},
+    features::HostFeatures,
+    keys::Keys,
+    tracing::TracingState,
+};
 use std::{error::Error, sync::Arc};
-use tracing::debug;
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::EnvFilter;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> Result<(), Box<dyn Error>> {
     let args = CmdArgs::parse();
     let has_log_override = args.log_filter.is_some();
     let has_format_override = args.log_format.is_some();
+    let check_config = args.check_config;
+    let resolve = args.resolve;
     let log_filter = args
         .log_filter
         // asserts that the arc is not shared. There is no reason it would be,
@@ -23,7 +30,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let finish_tracing_init =
         ntp_daemon::tracing::init(log_filter, args.log_format.unwrap_or_default());
 
-    let mut config = match Config::from_args(args.config, args.peers, args.servers).await {
+    // Capabilities belong to a thread, so everything up to dropping privileges
+    // runs on this thread only. Worker threads of a multi-threaded runtime
+    // would otherwise keep running as root, or lose all their capabilities.
+    let startup = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let config = startup.block_on(Config::from_args(args.config, args.peers, args.servers));
+
+    let mut config = match config {
         Ok(c) => c,
         Err(e) => {
             // print to stderr because tracing is not yet setup
@@ -32,6 +47,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     };
 
+    if check_config {
+        let diagnostics = startup.block_on(config.diagnose(resolve));
+        std::process::exit(report(&diagnostics));
+    }
+    drop(startup);
+
     // Sentry has a guard we need to keep alive, so store it.
     // The compiler will optimize this away when not using sentry.
     let tracing_state =
@@ -48,18 +69,177 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // tracing setup to ensure logging is fully configured.
     config.check();
 
+    // the keys file is only readable by its owner, which may be a user the
+    // daemon no longer is after dropping privileges
+    let keys = match Keys::load(&config.keys) {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("Could not load keys: {}", e);
+            std::process::exit(exitcode::CONFIG);
+        }
+    };
+
+    let clock = match DaemonClock::new(&config.clock) {
+        Ok(clock) => clock,
+        Err(e) => {
+            error!("Could not set up the clock: {}", e);
+            std::process::exit(exitcode::OSERR);
+        }
+    };
+
+    if let Err(e) = ntp_daemon::privileges::drop_privileges(&config) {
+        error!("Could not drop privileges: {}", e);
+        std::process::exit(exitcode::NOPERM);
+    }
+    ntp_daemon::privileges::check(&config);
+    let clock = clock.or_monitor(&config.clock);
+    let features = ntp_daemon::features::probe();
+
+    if let Err(e) = ntp_daemon::sandbox::install(&config) {
+        error!("Could not install syscall filter: {}", e);
+        std::process::exit(exitcode::OSERR);
+    }
+
+    ntp_daemon::rtc::startup(&config.rtc, &clock);
+
+    tokio::runtime::Runtime::new()?.block_on(run(config, clock, features, keys, tracing_state))
+}
+
+/// Print the problems found in the configuration, and the exit code for them
+fn report(diagnostics: &[Diagnostic]) -> i32 {
+    for diagnostic in diagnostics {
+        println!("{diagnostic}");
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+    println!("configuration checked: {errors} error(s), {warnings} warning(s)");
+
+    if errors > 0 {
+        exitcode::CONFIG
+    } else {
+        exitcode::OK
+    }
+}
+
+async fn run(
+    config: Config,
+    clock: DaemonClock,
+    features: HostFeatures,
+    keys: Keys,
+    tracing_state: TracingState,
+) -> Result<(), Box<dyn Error>> {
+    ntp_daemon::roughtime::startup(&config.roughtime, &clock).await?;
+
     debug!("Configuration loaded, spawning daemon jobs");
-    let (main_loop_handle, channels) =
-        ntp_daemon::spawn(config.system, &config.peers, &config.servers).await?;
+    let export = ntp_daemon::export::MeasurementExport::spawn(&config.export);
+    #[cfg(feature = "otlp")]
+    let (export, spans) = ntp_daemon::otlp::spans(&config.otlp, export);
+
+    let state = ntp_daemon::persistence::PersistentState::load(&config.state, &clock);
+
+    let (main_loop_handle, channels) = ntp_daemon::spawn(
+        config.system,
+        &config.peers,
+        &config.refclocks,
+        &config.source_groups,
+        &config.servers,
+        &config.statistics,
+        export,
+        state.clone(),
+        keys.clone(),
+        ntp_daemon::capture::PacketCapture::new(config.logging.rejected_packets),
+        &config.systemd,
+        &config.network,
+        clock.clone(),
+    )
+    .await?;
 
-    ntp_daemon::observer::spawn(&config.observe, channels.peers, channels.system).await;
+    ntp_daemon::persistence::spawn(state, clock.clone());
+    ntp_daemon::tai::spawn(&config.clock, clock.leap_seconds());
+    ntp_daemon::keys::spawn(&config.keys, keys);
+
+    // stop in an orderly way on SIGTERM, as sent by service managers, and SIGINT
+    let shutdown = channels.shutdown.clone();
+    tokio::spawn(async move {
+        match termination().await {
+            Ok(()) => shutdown.notify_one(),
+            Err(error) => warn!(?error, "could not listen for termination signals"),
+        }
+    });
+
+    ntp_daemon::steering::spawn(&config.steered_phcs, channels.system.clone());
+
+    ntp_daemon::rtc::spawn(&config.rtc, channels.system.clone());
+
+    ntp_daemon::chrony::spawn(
+        &config.chrony,
+        channels.peers.clone(),
+        channels.system.clone(),
+    )
+    .await;
+
+    #[cfg(feature = "otlp")]
+    ntp_daemon::otlp::spawn(
+        &config.otlp,
+        spans,
+        channels.peers.clone(),
+        channels.system.clone(),
+    );
+
+    ntp_daemon::health::spawn(
+        &config.health,
+        channels.peers.clone(),
+        channels.system.clone(),
+    )
+    .await;
+
+    let drain = channels.peers.read().await.drain();
+    ntp_daemon::observer::spawn(
+        &config.observe,
+        channels.peers,
+        channels.system,
+        clock.mode(),
+        features,
+    )
+    .await;
 
     ntp_daemon::config::dynamic::spawn(
         config.configure,
         channels.config,
+        drain,
         tracing_state.reload_handle,
     )
     .await;
 
-    Ok(main_loop_handle.await??)
+    main_loop_handle.await??;
+
+    if config.shutdown.hold_frequency {
+        // the clock of a time namespace stops with the daemon
+        if let Some(clock) = clock.system() {
+            match clock.hold_frequency() {
+                Ok(()) => info!("Holding the clock at its current frequency"),
+                Err(error) => warn!(%error, "could not hold the clock frequency"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn termination() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+
+    Ok(())
 }
@@ -0,0 +1,353 @@
+//! Tracking of the most recently seen clients of a server, in bounded memory.
+//!
+//! Clients first enter a probation segment, and only move on to the protected
+//! segment when they are seen again. A flood of packets from spoofed (random)
+//! addresses therefore only churns the probation segment, and cannot push out
+//! the clients that send repeatedly, which are the ones of interest when
+//! looking for abuse. Both segments are allocated up front and evict their
+//! least recently used entry when full. Lookups use the randomly keyed hashing
+//! of the standard library, so that collisions cannot be forced either.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Marks the absence of a neighbour in the linked lists of the segments
+const NONE: usize = usize::MAX;
+
+/// Everything known about a single client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ClientRecord {
+    addr: IpAddr,
+    packets: u64,
+    first_seen: Instant,
+    last_seen: Instant,
+}
+
+impl ClientRecord {
+    fn new(addr: IpAddr, now: Instant) -> Self {
+        ClientRecord {
+            addr,
+            packets: 1,
+            first_seen: now,
+            last_seen: now,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    record: ClientRecord,
+    newer: usize,
+    older: usize,
+}
+
+/// A fixed capacity least recently used list
+#[derive(Debug)]
+struct LruSegment {
+    nodes: Vec<Node>,
+    index: HashMap<IpAddr, usize>,
+    free: Vec<usize>,
+    newest: usize,
+    oldest: usize,
+    capacity: usize,
+}
+
+impl LruSegment {
+    fn new(capacity: usize) -> Self {
+        LruSegment {
+            nodes: Vec::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
+            free: Vec::with_capacity(capacity),
+            newest: NONE,
+            oldest: NONE,
+            capacity,
+        }
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let (newer, older) = (self.nodes[i].newer, self.nodes[i].older);
+        match newer {
+            NONE => self.newest = older,
+            newer => self.nodes[newer].older = older,
+        }
+        match older {
+            NONE => self.oldest = newer,
+            older => self.nodes[older].newer = newer,
+        }
+    }
+
+    fn link_newest(&mut self, i: usize) {
+        self.nodes[i].newer = NONE;
+        self.nodes[i].older = self.newest;
+        match self.newest {
+            NONE => self.oldest = i,
+            newest => self.nodes[newest].newer = i,
+        }
+        self.newest = i;
+    }
+
+    /// The record of the address, which becomes the most recently used
+    fn get_mut(&mut self, addr: &IpAddr) -> Option<&mut ClientRecord> {
+        let i = *self.index.get(addr)?;
+        self.unlink(i);
+        self.link_newest(i);
+        Some(&mut self.nodes[i].record)
+    }
+
+    fn remove(&mut self, addr: &IpAddr) -> Option<ClientRecord> {
+        let i = self.index.remove(addr)?;
+        self.unlink(i);
+        self.free.push(i);
+        Some(self.nodes[i].record)
+    }
+
+    /// Insert a record of an address that is not in the segment yet,
+    /// returning the record that was evicted to make room for it
+    fn insert(&mut self, record: ClientRecord) -> Option<ClientRecord> {
+        if self.capacity == 0 {
+            return Some(record);
+        }
+
+        let evicted = if self.index.len() == self.capacity {
+            let oldest = self.nodes[self.oldest].record.addr;
+            self.remove(&oldest)
+        } else {
+            None
+        };
+
+        let i = match self.free.pop() {
+            Some(i) => {
+                self.nodes[i].record = record;
+                i
+            }
+            None => {
+                self.nodes.push(Node {
+                    record,
+                    newer: NONE,
+                    older: NONE,
+                });
+                self.nodes.len() - 1
+            }
+        };
+
+        self.link_newest(i);
+        self.index.insert(record.addr, i);
+        evicted
+    }
+
+    /// All records, from most to least recently used
+    fn iter(&self) -> impl Iterator<Item = &ClientRecord> + '_ {
+        let mut i = self.newest;
+        std::iter::from_fn(move || match i {
+            NONE => None,
+            current => {
+                i = self.nodes[current].older;
+                Some(&self.nodes[current].record)
+            }
+        })
+    }
+}
+
+/// The clients of a server, as a segmented least recently used list
+#[derive(Debug)]
+struct ClientTracker {
+    probation: LruSegment,
+    protected: LruSegment,
+}
+
+impl ClientTracker {
+    fn new(size: usize) -> Self {
+        // half of the entries is used for clients seen only once
+        let probation = match size {
+            0 => 0,
+            size => (size / 2).max(1),
+        };
+
+        ClientTracker {
+            probation: LruSegment::new(probation),
+            protected: LruSegment::new(size - probation),
+        }
+    }
+
+    fn record(&mut self, addr: IpAddr, now: Instant) {
+        if let Some(record) = self.protected.get_mut(&addr) {
+            record.packets += 1;
+            record.last_seen = now;
+            return;
+        }
+
+        match self.probation.remove(&addr) {
+            Some(mut record) => {
+                record.packets += 1;
+                record.last_seen = now;
+
+                // clients pushed out of the protected segment get another chance
+                if let Some(demoted) = self.protected.insert(record) {
+                    self.probation.insert(demoted);
+                }
+            }
+            None => {
+                self.probation.insert(ClientRecord::new(addr, now));
+            }
+        }
+    }
+}
+
+/// A recently seen client, as exposed through the observation socket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObservableClient {
+    pub address: IpAddr,
+    /// Packets received from the client since it was first seen
+    pub packets: u64,
+    /// Time since the last packet of the client
+    pub last_seen: Duration,
+    /// Average time between the packets of the client
+    pub average_interval: Option<Duration>,
+}
+
+/// Handle to the recently seen clients of a server, which is cheap to clone.
+/// Tracking is disabled when the size is 0, which is the default.
+#[derive(Debug, Clone, Default)]
+pub struct ClientList(Option<Arc<Mutex<ClientTracker>>>);
+
+impl ClientList {
+    pub fn new(size: usize) -> Self {
+        match size {
+            0 => ClientList(None),
+            size => ClientList(Some(Arc::new(Mutex::new(ClientTracker::new(size))))),
+        }
+    }
+
+    fn lock(tracker: &Mutex<ClientTracker>) -> std::sync::MutexGuard<'_, ClientTracker> {
+        // recording never panics halfway, so a poisoned lock still holds a consistent tracker
+        match tracker.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Record a packet received from the client
+    pub fn record(&self, addr: IpAddr, now: Instant) {
+        if let Some(tracker) = &self.0 {
+            Self::lock(tracker).record(addr, now);
+        }
+    }
+
+    /// The tracked clients, from most to least recently seen
+    pub fn observe(&self, now: Instant) -> Vec<ObservableClient> {
+        let tracker = match &self.0 {
+            Some(tracker) => Self::lock(tracker),
+            None => return vec![],
+        };
+
+        let mut clients: Vec<_> = tracker
+            .protected
+            .iter()
+            .chain(tracker.probation.iter())
+            .map(|record| ObservableClient {
+                address: record.addr,
+                packets: record.packets,
+                last_seen: now.saturating_duration_since(record.last_seen),
+                average_interval: match record.packets {
+                    0 | 1 => None,
+                    packets => {
+                        let span = record.last_seen - record.first_seen;
+                        Some(span / (packets - 1).min(u32::MAX as u64) as u32)
+                    }
+                },
+            })
+            .collect();
+
+        clients.sort_by_key(|client| client.last_seen);
+        clients
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(i: u32) -> IpAddr {
+        IpAddr::from((0xc000_0200 + i).to_be_bytes())
+    }
+
+    #[test]
+    fn test_lru_segment() {
+        let now = Instant::now();
+        let mut segment = LruSegment::new(2);
+
+        assert_eq!(segment.insert(ClientRecord::new(addr(1), now)), None);
+        assert_eq!(segment.insert(ClientRecord::new(addr(2), now)), None);
+        assert!(segment.get_mut(&addr(1)).is_some());
+
+        // 2 is now the least recently used
+        let evicted = segment.insert(ClientRecord::new(addr(3), now));
+        assert_eq!(evicted.map(|r| r.addr), Some(addr(2)));
+
+        let order: Vec<_> = segment.iter().map(|r| r.addr).collect();
+        assert_eq!(order, vec![addr(3), addr(1)]);
+
+        assert!(segment.remove(&addr(3)).is_some());
+        assert_eq!(segment.insert(ClientRecord::new(addr(4), now)), None);
+        assert!(segment.nodes.len() <= 2);
+    }
+
+    #[test]
+    fn test_client_statistics() {
+        let list = ClientList::new(8);
+        let start = Instant::now();
+
+        for i in 0..5 {
+            list.record(addr(1), start + Duration::from_secs(16 * i));
+        }
+        list.record(addr(2), start + Duration::from_secs(70));
+
+        let clients = list.observe(start + Duration::from_secs(80));
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0].address, addr(2));
+        assert_eq!(clients[0].packets, 1);
+        assert_eq!(clients[0].average_interval, None);
+        assert_eq!(clients[1].address, addr(1));
+        assert_eq!(clients[1].packets, 5);
+        assert_eq!(clients[1].last_seen, Duration::from_secs(16));
+        assert_eq!(clients[1].average_interval, Some(Duration::from_secs(16)));
+    }
+
+    #[test]
+    fn test_spoofing_flood() {
+        let list = ClientList::new(16);
+        let now = Instant::now();
+
+        // regular clients, seen more than once
+        for i in 0..8 {
+            list.record(addr(i), now);
+            list.record(addr(i), now);
+        }
+
+        // a flood of packets, each from a different address
+        for i in 1000..100_000 {
+            list.record(addr(i), now);
+        }
+
+        let clients = list.observe(now);
+        assert_eq!(clients.len(), 16);
+        for i in 0..8 {
+            assert!(clients
+                .iter()
+                .any(|c| c.address == addr(i) && c.packets == 2));
+        }
+    }
+
+    #[test]
+    fn test_disabled() {
+        let list = ClientList::default();
+        list.record(addr(1), Instant::now());
+        assert!(list.observe(Instant::now()).is_empty());
+    }
+}
@@ -1,11 +1,20 @@
+use crate::features::HostFeatures;
+pub use crate::history::{ClockSample, ObservableHistory, PeerHistory, PeerSample};
+pub use crate::mru::ObservableClient;
+pub use crate::refclock::{LockState, RefClockStatistics};
+pub use crate::rejection::{RejectionReason, Rejections};
 use crate::server::ServerStats;
+pub use crate::smear::SmearEvidence;
 use crate::Peers;
 use crate::{peer_manager::ServerData, sockets::create_unix_socket};
-use ntp_proto::{NtpClock, PeerStatistics, PollInterval, Reach, ReferenceId, SystemSnapshot};
+use ntp_proto::{
+    NtpClock, NtpVersion, PeerStatistics, PollInterval, Reach, ReferenceId, SelectionError,
+    SelectionIntervals, SystemSnapshot,
+};
+use ntp_udp::IcmpError;
 use prometheus_client::encoding::text::Encode;
 use std::io::Write;
 use std::net::SocketAddr;
-use std::os::unix::fs::PermissionsExt;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
 use tracing::error;
@@ -17,12 +26,93 @@ pub struct ObservableState {
     pub system: SystemSnapshot,
     pub peers: Vec<ObservablePeerState>,
     pub servers: Vec<ObservableServerState>,
+    /// Outcome of the latest round of clock selection
+    #[serde(default)]
+    pub selection: SelectionStatus,
+    /// Correctness intervals of the sources in the latest round of clock
+    /// selection, and their intersection
+    #[serde(default)]
+    pub intervals: SelectionIntervals,
+    /// Recent clock updates and measurements of the sources
+    #[serde(default)]
+    pub history: ObservableHistory,
+    /// Whether the clock is adjusted
+    #[serde(default)]
+    pub clock_mode: ClockMode,
+    /// What the host offers the daemon, as found at startup
+    #[serde(default)]
+    pub features: HostFeatures,
+}
+
+/// Whether the daemon adjusts the clock
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClockMode {
+    /// The clock follows the sources
+    #[default]
+    Steering,
+    /// The clock is measured but never adjusted, as configured
+    MonitorOnly,
+    /// The clock is measured but never adjusted, as the daemon may not adjust
+    /// it, such as in a container where the host owns the clock
+    MonitorOnlyFallback,
+    /// Only the sources are measured, without clock selection, and the
+    /// clock is never adjusted, as configured
+    Measuring,
+}
+
+impl ClockMode {
+    pub fn is_monitor_only(self) -> bool {
+        matches!(
+            self,
+            ClockMode::MonitorOnly | ClockMode::MonitorOnlyFallback
+        )
+    }
+}
+
+/// Outcome of a round of clock selection
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SelectionStatus {
+    /// No round of clock selection happened yet
+    #[default]
+    Pending,
+    /// Enough sources agree on the time, and the clock follows the survivors
+    Synchronizing { survivors: usize },
+    /// Fewer sources agree on the time than required, so the clock is left alone
+    InsufficientConsensus { agreeing: usize, required: usize },
+    /// Fewer of the agreeing sources are authenticated than the authentication
+    /// policy requires, so the clock is left alone
+    InsufficientAuthenticated {
+        authenticated: usize,
+        required: usize,
+    },
+    /// There is no clock selection, as the daemon only measures its sources
+    Measuring,
+}
+
+impl From<SelectionError> for SelectionStatus {
+    fn from(error: SelectionError) -> Self {
+        match error {
+            SelectionError::InsufficientConsensus { agreeing, required } => {
+                SelectionStatus::InsufficientConsensus { agreeing, required }
+            }
+            SelectionError::InsufficientAuthenticated {
+                authenticated,
+                required,
+            } => SelectionStatus::InsufficientAuthenticated {
+                authenticated,
+                required,
+            },
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ObservableServerState {
     pub address: WrappedSocketAddr,
     pub stats: ServerStats,
+    /// Recently seen clients, when tracked
+    #[serde(default)]
+    pub clients: Vec<ObservableClient>,
 }
 
 impl From<ServerData> for ObservableServerState {
@@ -30,6 +120,7 @@ impl From<ServerData> for ObservableServerState {
         ObservableServerState {
             address: data.config.addr.into(),
             stats: data.stats,
+            clients: data.clients.observe(std::time::Instant::now()),
         }
     }
 }
@@ -43,13 +134,49 @@ impl From<SocketAddr> for WrappedSocketAddr {
     }
 }
 
+impl From<WrappedSocketAddr> for SocketAddr {
+    fn from(s: WrappedSocketAddr) -> Self {
+        s.0
+    }
+}
+
 impl Encode for WrappedSocketAddr {
     fn encode(&self, writer: &mut dyn Write) -> Result<(), std::io::Error> {
         writer.write_all(self.0.to_string().as_bytes())
     }
 }
 
+/// Why a peer does not answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Unreachable {
+    /// The requests went unanswered, without an error from the network
+    Timeout,
+    /// The host of the peer reported that no server listens on the port
+    NotListening,
+    /// The network reported that the host of the peer can not be reached
+    HostUnreachable,
+    /// The network reported that there is no route to the peer
+    NetworkUnreachable,
+    /// Requests to the peer are administratively prohibited, usually by a firewall
+    Prohibited,
+    /// The network reported another error for the requests
+    NetworkError,
+}
+
+impl From<IcmpError> for Unreachable {
+    fn from(error: IcmpError) -> Self {
+        match error {
+            IcmpError::PortUnreachable => Unreachable::NotListening,
+            IcmpError::HostUnreachable => Unreachable::HostUnreachable,
+            IcmpError::NetworkUnreachable => Unreachable::NetworkUnreachable,
+            IcmpError::Prohibited => Unreachable::Prohibited,
+            IcmpError::Other { .. } => Unreachable::NetworkError,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)] // only built to be serialized for the management client
 pub enum ObservablePeerState {
     Nothing,
     Observable {
@@ -59,6 +186,39 @@ pub enum ObservablePeerState {
         poll_interval: PollInterval,
         peer_id: ReferenceId,
         address: String,
+        /// Local address the peer is contacted from, if it is a remote peer
+        #[serde(default)]
+        local_address: Option<SocketAddr>,
+        /// Summary of the health of the peer, from 0 (useless) to 100 (excellent)
+        quality: u8,
+        /// Why packets and measurements of the peer were not used
+        #[serde(default)]
+        rejections: Rejections,
+        /// Why the peer does not answer, while it is unreachable
+        #[serde(default)]
+        unreachable: Option<Unreachable>,
+        /// Whether this is the other address of a dual-stack peer, which is
+        /// probed but not used for synchronization
+        #[serde(default)]
+        standby: bool,
+        /// Consecutive requests that got no usable response
+        #[serde(default)]
+        failures: u32,
+        /// What became of the samples of the driver, if it is a reference
+        /// clock
+        #[serde(default)]
+        refclock: Option<RefClockStatistics>,
+        /// Why the peer appears to smear leap seconds, if it does
+        #[serde(default)]
+        smearing: Option<SmearEvidence>,
+        /// NTP version the server answers in, which is lower than configured
+        /// for servers that only speak an older version
+        #[serde(default)]
+        version: Option<NtpVersion>,
+        /// Packets received from the peer that do not conform to RFC 5905,
+        /// whether the parsing policy let them be used or not
+        #[serde(default)]
+        nonconforming: u64,
     },
 }
 
@@ -66,10 +226,12 @@ pub async fn spawn<C: NtpClock + Sync + Send + 'static>(
     config: &crate::config::ObserveConfig,
     peers_reader: Arc<tokio::sync::RwLock<Peers<C>>>,
     system_reader: Arc<tokio::sync::RwLock<SystemSnapshot>>,
+    clock_mode: ClockMode,
+    features: HostFeatures,
 ) -> JoinHandle<std::io::Result<()>> {
     let config = config.clone();
     tokio::spawn(async move {
-        let result = observer(config, peers_reader, system_reader).await;
+        let result = observer(config, peers_reader, system_reader, clock_mode, features).await;
         if let Err(ref e) = result {
             error!("Abnormal termination of state observer: {}", e);
         }
@@ -81,19 +243,15 @@ async fn observer<C: NtpClock>(
     config: crate::config::ObserveConfig,
     peers_reader: Arc<tokio::sync::RwLock<Peers<C>>>,
     system_reader: Arc<tokio::sync::RwLock<SystemSnapshot>>,
+    clock_mode: ClockMode,
+    features: HostFeatures,
 ) -> std::io::Result<()> {
     let path = match config.path {
         Some(path) => path,
         None => return Ok(()),
     };
 
-    let peers_listener = create_unix_socket(&path)?;
-
-    // this binary needs to run as root to be able to adjust the system clock.
-    // by default, the socket inherits root permissions, but the client should not need
-    // elevated permissions to read from the socket. So we explicitly set the permissions
-    let permissions: std::fs::Permissions = PermissionsExt::from_mode(config.mode);
-    std::fs::set_permissions(&path, permissions)?;
+    let peers_listener = create_unix_socket(&path, config.mode)?;
 
     loop {
         let (mut stream, _addr) = peers_listener.accept().await?;
@@ -107,6 +265,11 @@ async fn observer<C: NtpClock>(
                 .servers()
                 .map(|s| s.into())
                 .collect(),
+            selection: peers_reader.read().await.selection(),
+            intervals: peers_reader.read().await.intervals(),
+            history: peers_reader.read().await.history(),
+            clock_mode,
+            features: features.clone(),
         };
 
         crate::sockets::write_json(&mut stream, &observe).await?;
@@ -118,8 +281,8 @@ mod tests {
     use std::time::Duration;
 
     use ntp_proto::{
-        NtpDuration, NtpInstant, NtpLeapIndicator, NtpTimestamp, PeerSnapshot, PeerStatistics,
-        PollInterval, PollIntervalLimits, Reach, ReferenceId,
+        NtpDuration, NtpInstant, NtpLeapIndicator, NtpTimestamp, NtpVersion, PeerSnapshot,
+        PeerStatistics, PollInterval, PollIntervalLimits, Reach, ReferenceId, SourceKind,
     };
     use tokio::{io::AsyncReadExt, net::UnixStream};
 
@@ -190,18 +353,52 @@ mod tests {
                 leap_indicator: NtpLeapIndicator::NoWarning,
                 root_delay: NtpDuration::from_seconds(0.2),
                 root_dispersion: NtpDuration::from_seconds(0.02),
+                authenticated: false,
+                failures: 0,
+                kind: SourceKind::Network,
+                version: Some(NtpVersion::V4),
             }),
         ];
 
         let peer_configs = [
             PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("127.0.0.1:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
             }),
             PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("127.0.0.2:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
             }),
             PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("127.0.0.3:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
             }),
         ];
 
@@ -218,13 +415,27 @@ mod tests {
             root_delay: NtpDuration::ZERO,
             root_dispersion: NtpDuration::ZERO,
             reference_id: ReferenceId::NONE,
+            reference_timestamp: NtpTimestamp::default(),
             leap_indicator: NtpLeapIndicator::Leap59,
             accumulated_steps: NtpDuration::ZERO,
             accumulated_steps_threshold: None,
         }));
 
+        let features = HostFeatures {
+            kernel_pll: true,
+            ..Default::default()
+        };
+
         let handle = tokio::spawn(async move {
-            observer(config, peers_reader, system_reader).await.unwrap();
+            observer(
+                config,
+                peers_reader,
+                system_reader,
+                ClockMode::MonitorOnly,
+                features,
+            )
+            .await
+            .unwrap();
         });
 
         tokio::time::sleep(Duration::from_millis(10)).await;
@@ -243,6 +454,8 @@ mod tests {
             }
         }
         assert_eq!(count, 1);
+        assert_eq!(result.clock_mode, ClockMode::MonitorOnly);
+        assert!(result.features.kernel_pll);
 
         handle.abort();
     }
@@ -277,18 +490,52 @@ mod tests {
                 leap_indicator: NtpLeapIndicator::NoWarning,
                 root_delay: NtpDuration::from_seconds(0.2),
                 root_dispersion: NtpDuration::from_seconds(0.02),
+                authenticated: false,
+                failures: 0,
+                kind: SourceKind::Network,
+                version: Some(NtpVersion::V4),
             }),
         ];
 
         let peer_configs = [
             PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("127.0.0.1:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
             }),
             PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("127.0.0.2:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
             }),
             PeerConfig::Standard(StandardPeerConfig {
                 addr: NormalizedAddress::new_unchecked("127.0.0.3:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
             }),
         ];
 
@@ -305,6 +552,7 @@ mod tests {
             stratum: 1,
             precision: NtpDuration::from_seconds(1e-3),
             reference_id: ReferenceId::NONE,
+            reference_timestamp: NtpTimestamp::default(),
             root_delay: NtpDuration::ZERO,
             root_dispersion: NtpDuration::ZERO,
             leap_indicator: NtpLeapIndicator::Leap59,
@@ -315,7 +563,15 @@ mod tests {
         let system_writer = system_reader.clone();
 
         let handle = tokio::spawn(async move {
-            observer(config, peers_reader, system_reader).await.unwrap();
+            observer(
+                config,
+                peers_reader,
+                system_reader,
+                ClockMode::Steering,
+                Default::default(),
+            )
+            .await
+            .unwrap();
         });
 
         tokio::time::sleep(Duration::from_millis(10)).await;
@@ -0,0 +1,348 @@
+//! Push of metrics and spans to an OpenTelemetry collector.
+//!
+//! Both are sent with OTLP over HTTP, in its JSON encoding. The metrics are
+//! gauges of the state of the clock and the peers at the time of the push.
+//! Every exchange with a peer becomes a span, from sending the request until
+//! receiving the response, so that it can be correlated with the traces of
+//! other services.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ntp_proto::{NtpClock, NtpTimestamp, SystemSnapshot};
+use rand::{thread_rng, Rng};
+use serde_json::{json, Value};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::warn;
+
+use crate::{
+    config::OtlpConfig,
+    export::{Exchange, MeasurementExport},
+    observer::{ObservableHistory, ObservablePeerState},
+    Peers,
+};
+
+/// Number of exchanges that are kept for the next push
+const SPAN_QUEUE_SIZE: usize = 4096;
+
+/// The exchanges to push as spans
+pub struct Spans(Option<mpsc::Receiver<Exchange>>);
+
+/// Hand the exchanges of the peers to the exporter as well. This has to happen
+/// before the peers are started.
+pub fn spans(config: &OtlpConfig, export: MeasurementExport) -> (MeasurementExport, Spans) {
+    if config.endpoint.is_none() {
+        return (export, Spans(None));
+    }
+
+    let (sender, receiver) = mpsc::channel(SPAN_QUEUE_SIZE);
+    (export.with_spans(sender), Spans(Some(receiver)))
+}
+
+pub fn spawn<C: NtpClock + Sync + Send + 'static>(
+    config: &OtlpConfig,
+    spans: Spans,
+    peers_reader: Arc<tokio::sync::RwLock<Peers<C>>>,
+    system_reader: Arc<tokio::sync::RwLock<SystemSnapshot>>,
+) -> JoinHandle<()> {
+    let config = config.clone();
+    tokio::spawn(async move {
+        let endpoint = match &config.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_owned(),
+            None => return,
+        };
+
+        let client = reqwest::Client::new();
+        let mut spans = spans.0;
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+        // only report the first of a series of failures
+        let mut failing = false;
+
+        loop {
+            interval.tick().await;
+
+            let metrics = {
+                let peers = peers_reader.read().await;
+                let system = *system_reader.read().await;
+                metrics(
+                    &config.service_name,
+                    unix_nanos_now(),
+                    &system,
+                    peers.observe_peers(),
+                    &peers.history(),
+                )
+            };
+            let mut pushes = vec![(format!("{endpoint}/v1/metrics"), metrics)];
+
+            let exchanges = drain(&mut spans);
+            if !exchanges.is_empty() {
+                let traces = traces(&config.service_name, &exchanges);
+                pushes.push((format!("{endpoint}/v1/traces"), traces));
+            }
+
+            for (url, body) in pushes {
+                let result = client
+                    .post(&url)
+                    .header("content-type", "application/json")
+                    .body(body.to_string())
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+
+                match result {
+                    Ok(_) => failing = false,
+                    Err(error) => {
+                        if !failing {
+                            warn!(?error, url, "could not push to OpenTelemetry collector");
+                        }
+                        failing = true;
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn drain(spans: &mut Option<mpsc::Receiver<Exchange>>) -> Vec<Exchange> {
+    let mut exchanges = Vec::new();
+    if let Some(receiver) = spans {
+        while let Ok(exchange) = receiver.try_recv() {
+            exchanges.push(exchange);
+        }
+    }
+    exchanges
+}
+
+fn unix_nanos_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+fn unix_nanos(timestamp: NtpTimestamp) -> u64 {
+    let (seconds, nanos) = timestamp.to_unix_seconds_nanos();
+    (seconds as u64) * 1_000_000_000 + nanos as u64
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+fn resource(service_name: &str) -> Value {
+    json!({ "attributes": [attribute("service.name", json!({ "stringValue": service_name }))] })
+}
+
+fn scope() -> Value {
+    json!({ "name": "ntpd-rs", "version": env!("CARGO_PKG_VERSION") })
+}
+
+/// A gauge with a data point per entry of `points`, given as the value and
+/// the attributes of the point
+fn gauge(name: &str, unit: &str, time: u64, points: Vec<(f64, Vec<Value>)>) -> Value {
+    let data_points: Vec<_> = points
+        .into_iter()
+        .map(|(value, attributes)| {
+            json!({
+                // 64 bit integers are strings in the JSON encoding
+                "timeUnixNano": time.to_string(),
+                "asDouble": value,
+                "attributes": attributes,
+            })
+        })
+        .collect();
+
+    json!({ "name": name, "unit": unit, "gauge": { "dataPoints": data_points } })
+}
+
+fn metrics(
+    service_name: &str,
+    time: u64,
+    system: &SystemSnapshot,
+    peers: impl Iterator<Item = ObservablePeerState>,
+    history: &ObservableHistory,
+) -> Value {
+    let system_gauge = |name, unit, value| gauge(name, unit, time, vec![(value, vec![])]);
+
+    let mut metrics = vec![
+        system_gauge("ntp.system.stratum", "1", system.stratum as f64),
+        system_gauge("ntp.system.root_delay", "s", system.root_delay.to_seconds()),
+        system_gauge(
+            "ntp.system.root_dispersion",
+            "s",
+            system.root_dispersion.to_seconds(),
+        ),
+    ];
+    if let Some(last) = history.clock.last() {
+        metrics.push(system_gauge("ntp.system.offset", "s", last.offset));
+        metrics.push(system_gauge(
+            "ntp.system.frequency",
+            "[ppm]",
+            last.frequency,
+        ));
+    }
+
+    let mut offset = vec![];
+    let mut delay = vec![];
+    let mut dispersion = vec![];
+    let mut jitter = vec![];
+    let mut reachable = vec![];
+    let mut quality = vec![];
+    for peer in peers {
+        if let ObservablePeerState::Observable {
+            statistics,
+            reachability,
+            address,
+            quality: peer_quality,
+            ..
+        } = peer
+        {
+            let attributes = vec![attribute("peer.address", json!({ "stringValue": address }))];
+            offset.push((statistics.offset.to_seconds(), attributes.clone()));
+            delay.push((statistics.delay.to_seconds(), attributes.clone()));
+            dispersion.push((statistics.dispersion.to_seconds(), attributes.clone()));
+            jitter.push((statistics.jitter, attributes.clone()));
+            let is_reachable = if reachability.is_reachable() {
+                1.0
+            } else {
+                0.0
+            };
+            reachable.push((is_reachable, attributes.clone()));
+            quality.push((peer_quality as f64, attributes));
+        }
+    }
+    metrics.extend([
+        gauge("ntp.peer.offset", "s", time, offset),
+        gauge("ntp.peer.delay", "s", time, delay),
+        gauge("ntp.peer.dispersion", "s", time, dispersion),
+        gauge("ntp.peer.jitter", "s", time, jitter),
+        gauge("ntp.peer.reachable", "1", time, reachable),
+        gauge("ntp.peer.quality", "1", time, quality),
+    ]);
+
+    json!({
+        "resourceMetrics": [{
+            "resource": resource(service_name),
+            "scopeMetrics": [{ "scope": scope(), "metrics": metrics }],
+        }]
+    })
+}
+
+fn hex_id<const N: usize>() -> String {
+    let mut bytes = [0u8; N];
+    thread_rng().fill(&mut bytes[..]);
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn traces(service_name: &str, exchanges: &[Exchange]) -> Value {
+    let spans: Vec<_> = exchanges
+        .iter()
+        .map(|exchange| {
+            json!({
+                "traceId": hex_id::<16>(),
+                "spanId": hex_id::<8>(),
+                "name": "ntp.exchange",
+                // client
+                "kind": 3,
+                "startTimeUnixNano": unix_nanos(exchange.t1).to_string(),
+                "endTimeUnixNano": unix_nanos(exchange.t4).to_string(),
+                "attributes": [
+                    attribute("server.address", json!({ "stringValue": exchange.peer.ip().to_string() })),
+                    attribute("server.port", json!({ "intValue": exchange.peer.port().to_string() })),
+                    attribute("ntp.offset", json!({ "doubleValue": exchange.offset.to_seconds() })),
+                    attribute("ntp.delay", json!({ "doubleValue": exchange.delay.to_seconds() })),
+                    attribute("ntp.authenticated", json!({ "boolValue": exchange.authenticated })),
+                ],
+            })
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": resource(service_name),
+            "scopeSpans": [{ "scope": scope(), "spans": spans }],
+        }]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::NtpDuration;
+
+    use crate::observer::ClockSample;
+
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let system = SystemSnapshot {
+            stratum: 2,
+            root_delay: NtpDuration::from_seconds(0.25),
+            ..Default::default()
+        };
+        let history = ObservableHistory {
+            clock: vec![ClockSample {
+                time: 1_700_000_000.0,
+                offset: 0.001,
+                frequency: 12.5,
+                jitter: 0.0001,
+            }],
+            peers: vec![],
+        };
+
+        let value = metrics("ntpd-rs", 42, &system, std::iter::empty(), &history);
+        let resource = &value["resourceMetrics"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0]["value"]["stringValue"],
+            "ntpd-rs"
+        );
+
+        let metrics = resource["scopeMetrics"][0]["metrics"].as_array().unwrap();
+        let find = |name: &str| {
+            metrics
+                .iter()
+                .find(|metric| metric["name"] == name)
+                .unwrap()
+                .clone()
+        };
+
+        let stratum = find("ntp.system.stratum");
+        assert_eq!(stratum["gauge"]["dataPoints"][0]["asDouble"], 2.0);
+        assert_eq!(stratum["gauge"]["dataPoints"][0]["timeUnixNano"], "42");
+        assert_eq!(
+            find("ntp.system.frequency")["gauge"]["dataPoints"][0]["asDouble"],
+            12.5
+        );
+        assert!(find("ntp.peer.offset")["gauge"]["dataPoints"]
+            .as_array()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_traces() {
+        let timestamp = |nanos| NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, nanos);
+        let exchange = Exchange::from_timestamps(
+            "192.0.2.1:123".parse().unwrap(),
+            [
+                timestamp(0),
+                timestamp(250_000_000),
+                timestamp(500_000_000),
+                timestamp(750_000_000),
+            ],
+            true,
+        );
+
+        let value = traces("ntpd-rs", &[exchange]);
+        let span = &value["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+
+        assert_eq!(span["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(span["spanId"].as_str().unwrap().len(), 16);
+        assert_eq!(span["startTimeUnixNano"], "1700000000000000000");
+        assert_eq!(span["endTimeUnixNano"], "1700000000750000000");
+        assert_eq!(span["attributes"][0]["value"]["stringValue"], "192.0.2.1");
+        assert_eq!(span["attributes"][4]["value"]["boolValue"], true);
+    }
+}
@@ -1,26 +1,35 @@
-use std::{
-    future::Future,
-    io::Cursor,
-    marker::PhantomData,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
-    pin::Pin,
-    sync::Arc,
-};
+use std::{future::Future, marker::PhantomData, net::SocketAddr, pin::Pin, sync::Arc};
 
 use ntp_proto::{
-    IgnoreReason, NtpClock, NtpInstant, NtpPacket, NtpTimestamp, Peer, PeerSnapshot, ReferenceId,
-    SystemConfig, SystemSnapshot, Update,
+    AssociationMode, IgnoreReason, NtpClock, NtpDuration, NtpInstant, NtpPacket, NtpTimestamp,
+    NtpVersion, ParsingPolicy, Peer, PeerAction, PeerActions, PeerBuilder, PeerEvent, PeerSnapshot,
+    PollInterval, SystemConfig, SystemSnapshot, Update,
 };
-use ntp_udp::UdpSocket;
+use ntp_udp::IcmpError;
 use rand::{thread_rng, Rng};
-use tracing::{debug, error, instrument, warn, Instrument, Span};
+use tracing::{debug, error, info, instrument, warn, Instrument, Span};
 
 use tokio::{
     sync::watch,
     time::{Instant, Sleep},
 };
 
-use crate::peer_manager::PeerIndex;
+use crate::{
+    capture::PacketCapture,
+    config::{
+        NetworkConfig, PeerBindConfig, PeerConfig, PeerDelayConfig, PeerNatConfig, PeerPollConfig,
+    },
+    export::{Exchange, MeasurementExport},
+    keys::Keys,
+    peer_manager::PeerIndex,
+    persistence::PersistentState,
+    statistics::StatsLogger,
+    transport::{Transport, UdpTransport},
+};
+
+/// Size of the largest packet that is sent or received: the header, and a MAC
+/// of at most 20 bytes with its key id
+pub(crate) const MAX_NTP_PACKET_SIZE: usize = 48 + 4 + 20;
 
 /// Trait needed to allow injecting of futures other than tokio::time::Sleep for testing
 pub trait Wait: Future<Output = ()> {
@@ -38,14 +47,32 @@ impl Wait for Sleep {
 /// epoch, thereby indicating to the system that the reset was successful and the peer's messages
 /// are valid measurements again.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
-pub struct ResetEpoch(u64);
+pub struct ResetEpoch {
+    epoch: u64,
+    /// The reset is due to a resume from suspend, after which peers poll right away
+    resumed: bool,
+}
 
 impl ResetEpoch {
     #[must_use]
-    pub const fn inc(mut self) -> Self {
-        self.0 = self.0.wrapping_add(1);
+    pub const fn inc(self) -> Self {
+        ResetEpoch {
+            epoch: self.epoch.wrapping_add(1),
+            resumed: false,
+        }
+    }
 
-        self
+    /// The next epoch, for a reset after the system resumed from suspend
+    #[must_use]
+    pub const fn inc_resumed(self) -> Self {
+        ResetEpoch {
+            epoch: self.epoch.wrapping_add(1),
+            resumed: true,
+        }
+    }
+
+    pub const fn is_resumed(self) -> bool {
+        self.resumed
     }
 }
 
@@ -55,12 +82,24 @@ pub enum MsgForSystem {
     MustDemobilize(PeerIndex),
     /// Experienced a network issue and must be restarted
     NetworkIssue(PeerIndex),
+    /// Failed to get usable responses too often in a row, and must be
+    /// replaced by a freshly resolved peer
+    Unresponsive(PeerIndex),
+    /// Opened a socket to the peer, sending from this local address
+    Connected(PeerIndex, SocketAddr),
     /// Received an acceptable packet and made a new peer snapshot
     /// A new measurement should try to trigger a clock select
     NewMeasurement(PeerIndex, ResetEpoch, PeerSnapshot),
     /// A snapshot may have been updated, but this should not
     /// trigger a clock select in System
     UpdatedSnapshot(PeerIndex, ResetEpoch, PeerSnapshot),
+    /// Ignored a packet received from the peer
+    PacketIgnored(PeerIndex, IgnoreReason),
+    /// The network reported that requests to the peer can not be answered
+    Unreachable(PeerIndex, IcmpError),
+    /// Received a packet that does not conform to RFC 5905, whether it was
+    /// used or not
+    Nonconforming(PeerIndex),
 }
 
 #[derive(Debug, Clone)]
@@ -69,6 +108,11 @@ pub struct PeerChannels {
     pub system_snapshots: Arc<tokio::sync::RwLock<SystemSnapshot>>,
     pub system_config: Arc<tokio::sync::RwLock<SystemConfig>>,
     pub reset: watch::Receiver<ResetEpoch>,
+    pub statistics: StatsLogger,
+    pub export: MeasurementExport,
+    pub state: PersistentState,
+    pub keys: Keys,
+    pub capture: PacketCapture,
 }
 
 impl PeerChannels {
@@ -81,17 +125,57 @@ impl PeerChannels {
             system_snapshots: Arc::new(tokio::sync::RwLock::new(SystemSnapshot::default())),
             system_config: Arc::new(tokio::sync::RwLock::new(SystemConfig::default())),
             reset: rx,
+            statistics: Default::default(),
+            export: Default::default(),
+            state: Default::default(),
+            keys: Default::default(),
+            capture: Default::default(),
         }
     }
 }
 
-pub(crate) struct PeerTask<C: 'static + NtpClock + Send, T: Wait> {
+/// The options of a peer that its task works with
+#[derive(Debug, Clone, Default)]
+pub struct PeerTaskConfig {
+    pub bind: PeerBindConfig,
+    pub max_offset: Option<NtpDuration>,
+    pub poll: PeerPollConfig,
+    pub delay: PeerDelayConfig,
+    pub version: NtpVersion,
+    pub parsing: ParsingPolicy,
+    pub association: AssociationMode,
+    pub key: Option<u32>,
+}
+
+impl From<&PeerConfig> for PeerTaskConfig {
+    fn from(config: &PeerConfig) -> Self {
+        PeerTaskConfig {
+            bind: config.bind().clone(),
+            max_offset: config.max_offset(),
+            poll: config.poll(),
+            delay: config.delay(),
+            version: config.version(),
+            parsing: config.parsing(),
+            association: config.association(),
+            key: config.key(),
+        }
+    }
+}
+
+pub(crate) struct PeerTask<C: 'static + NtpClock + Send, T: Wait, S: Transport> {
     _wait: PhantomData<T>,
     index: PeerIndex,
     clock: C,
-    socket: UdpSocket,
+    transport: S,
     channels: PeerChannels,
 
+    addr: SocketAddr,
+
+    /// Id of the key with which packets to and from the peer are signed
+    key: Option<u32>,
+    /// Whether responses that deviate from RFC 5905 are used
+    parsing: ParsingPolicy,
+
     peer: Peer,
 
     // we don't store the real origin timestamp in the packet, because that would leak our
@@ -104,33 +188,37 @@ pub(crate) struct PeerTask<C: 'static + NtpClock + Send, T: Wait> {
     /// Instant last poll message was sent (used for timing the wait)
     last_poll_sent: Instant,
 
+    /// How the connection is kept up through NATs
+    nat: PeerNatConfig,
+    /// Instant the last keepalive was sent
+    last_keepalive_sent: Instant,
+    /// Number of polls in a row at which the previous request was unanswered
+    unanswered: u32,
+    /// Whether the socket was replaced for unanswered requests, without any
+    /// request being answered since
+    reopened: bool,
+
     /// Number of resets that this peer has performed
     reset_epoch: ResetEpoch,
 }
 
+/// How the task continues after performing the actions of the peer
 #[derive(Debug)]
-enum PollResult {
-    Ok,
+enum ActionResult {
+    Continue,
     NetworkGone,
-}
-
-#[derive(Debug)]
-enum PacketResult {
-    Ok,
     Demobilize,
 }
 
-impl<C, T> PeerTask<C, T>
+impl<C, T, S> PeerTask<C, T, S>
 where
     C: 'static + NtpClock + Send,
     T: Wait,
+    S: Transport,
 {
-    /// Set the next deadline for the poll interval based on current state
-    fn update_poll_wait(&self, poll_wait: &mut Pin<&mut T>, system_snapshot: SystemSnapshot) {
-        let poll_interval = self
-            .peer
-            .current_poll_interval(system_snapshot)
-            .as_system_duration();
+    /// Set the next deadline for the poll interval, counting from the last poll
+    fn update_poll_wait(&self, poll_wait: &mut Pin<&mut T>, poll_interval: PollInterval) {
+        let poll_interval = poll_interval.as_system_duration();
 
         // randomize the poll interval a little to make it harder to predict poll requests
         let poll_interval = poll_interval.mul_f64(thread_rng().gen_range(1.01..=1.05));
@@ -140,21 +228,104 @@ where
             .reset(self.last_poll_sent + poll_interval);
     }
 
-    async fn handle_poll(&mut self, poll_wait: &mut Pin<&mut T>) -> PollResult {
-        let system_snapshot = *self.channels.system_snapshots.read().await;
-        let config_snapshot = *self.channels.system_config.read().await;
-        let packet = self
-            .peer
-            .generate_poll_message(system_snapshot, &config_snapshot);
+    /// Perform the actions that the peer asked for in response to an event
+    /// Perform the actions of the peer. `received` is the data of the packet
+    /// that caused them, if any.
+    async fn perform(
+        &mut self,
+        poll_wait: &mut Pin<&mut T>,
+        actions: PeerActions,
+        received: &[u8],
+    ) -> ActionResult {
+        for action in actions {
+            match action {
+                PeerAction::Send(packet) => {
+                    if let ActionResult::NetworkGone = self.send(packet).await {
+                        return ActionResult::NetworkGone;
+                    }
+                }
+                PeerAction::Update(update) => {
+                    // NOTE: fitness check is not performed here, but by System
+                    let msg = match update {
+                        Update::BareUpdate(update) => {
+                            MsgForSystem::UpdatedSnapshot(self.index, self.reset_epoch, update)
+                        }
+                        Update::NewMeasurement(update) => {
+                            MsgForSystem::NewMeasurement(self.index, self.reset_epoch, update)
+                        }
+                    };
+                    self.channels.msg_for_system_sender.send(msg).await.ok();
+                }
+                PeerAction::SetTimer(poll_interval) => {
+                    self.update_poll_wait(poll_wait, poll_interval);
+                }
+                PeerAction::Ignore(ignore_reason) => {
+                    debug!(%ignore_reason, "packet ignored");
+                    // packets that are valid but not useful right now are not
+                    // interesting for finding interoperability problems
+                    let invalid = matches!(
+                        ignore_reason,
+                        IgnoreReason::InvalidMode
+                            | IgnoreReason::InvalidVersion
+                            | IgnoreReason::InvalidStratum
+                            | IgnoreReason::InvalidResponse(_)
+                    );
+                    if invalid && !received.is_empty() {
+                        self.channels
+                            .capture
+                            .rejected(self.addr, received, ignore_reason);
+                    }
+                    let msg = MsgForSystem::PacketIgnored(self.index, ignore_reason);
+                    self.channels.msg_for_system_sender.send(msg).await.ok();
+                }
+                PeerAction::Demobilize => {
+                    warn!("Demobilizing peer connection on request of remote.");
+                    let msg = MsgForSystem::MustDemobilize(self.index);
+                    self.channels.msg_for_system_sender.send(msg).await.ok();
 
-        // Sent a poll, so update waiting to match deadline of next
-        self.last_poll_sent = Instant::now();
-        self.update_poll_wait(poll_wait, system_snapshot);
+                    return ActionResult::Demobilize;
+                }
+                PeerAction::Replace => {
+                    let msg = MsgForSystem::Unresponsive(self.index);
+                    self.channels.msg_for_system_sender.send(msg).await.ok();
 
-        // NOTE: fitness check is not performed here, but by System
-        let snapshot = PeerSnapshot::from_peer(&self.peer);
-        let msg = MsgForSystem::UpdatedSnapshot(self.index, self.reset_epoch, snapshot);
-        self.channels.msg_for_system_sender.send(msg).await.ok();
+                    return ActionResult::Demobilize;
+                }
+            }
+        }
+
+        ActionResult::Continue
+    }
+
+    /// Replace the connection to the peer by a new one, which for instance
+    /// sets up a new association when packets go through a proxy
+    async fn reconnect(&mut self) -> ActionResult {
+        match self.transport.reopen().await {
+            Ok(()) => {
+                // responses to earlier requests are no longer received
+                if let Ok(local_addr) = self.transport.local_addr() {
+                    let msg = MsgForSystem::Connected(self.index, local_addr);
+                    self.channels.msg_for_system_sender.send(msg).await.ok();
+                }
+                ActionResult::Continue
+            }
+            Err(error) => {
+                warn!(?error, "Could not open a new socket");
+                ActionResult::NetworkGone
+            }
+        }
+    }
+
+    async fn send(&mut self, packet: NtpPacket<'static>) -> ActionResult {
+        // this happens before the send timestamp is taken, so setting up a
+        // new association with a proxy does not count as network delay
+        if self.transport.reopen_per_request() {
+            if let ActionResult::NetworkGone = self.reconnect().await {
+                return ActionResult::NetworkGone;
+            }
+        }
+
+        self.last_poll_sent = Instant::now();
 
         match self.clock.now() {
             Err(e) => {
@@ -169,17 +340,31 @@ where
             }
         }
 
-        let mut buf = Cursor::new([0; 48]);
-        if let Err(error) = packet.serialize(&mut buf) {
-            error!(?error, "poll message could not be serialized");
-            return PollResult::Ok;
+        let mut packet = packet;
+        if let Some(id) = self.key {
+            let time = self.last_send_timestamp.unwrap_or_default();
+            match self.channels.keys.current().signing_key(id, time) {
+                Some(key) => packet.sign(key),
+                None => {
+                    warn!(
+                        key = id,
+                        "no valid key to sign the poll message with, not sending it"
+                    );
+                    return ActionResult::Continue;
+                }
+            }
         }
 
-        match self
-            .socket
-            .send(&buf.get_ref()[..buf.position() as usize])
-            .await
-        {
+        let mut buf = [0; MAX_NTP_PACKET_SIZE];
+        let len = match packet.serialize_into(&mut buf) {
+            Ok(len) => len,
+            Err(error) => {
+                error!(?error, "poll message could not be serialized");
+                return ActionResult::Continue;
+            }
+        };
+
+        match self.transport.send(&buf[..len]).await {
             Err(error) => {
                 warn!(?error, "poll message could not be sent");
 
@@ -187,142 +372,341 @@ where
                     Some(libc::EHOSTDOWN)
                     | Some(libc::EHOSTUNREACH)
                     | Some(libc::ENETDOWN)
-                    | Some(libc::ENETUNREACH) => return PollResult::NetworkGone,
+                    | Some(libc::ENETUNREACH) => return ActionResult::NetworkGone,
                     _ => {}
                 }
             }
-            Ok((_written, opt_send_timestamp)) => {
+            Ok(opt_send_timestamp) => {
                 // update the last_send_timestamp with the one given by the kernel, if available
-                self.last_send_timestamp = opt_send_timestamp.or(self.last_send_timestamp);
+                let send_timestamp =
+                    opt_send_timestamp.map(|ts| self.clock.adjust_system_timestamp(ts));
+                self.last_send_timestamp = send_timestamp.or(self.last_send_timestamp);
             }
         }
 
-        PollResult::Ok
+        ActionResult::Continue
     }
 
-    async fn handle_packet<'a>(
+    async fn handle_event(
         &mut self,
         poll_wait: &mut Pin<&mut T>,
-        packet: NtpPacket<'a>,
-        send_timestamp: NtpTimestamp,
-        recv_timestamp: NtpTimestamp,
-    ) -> PacketResult {
-        let ntp_instant = NtpInstant::now();
+        event: PeerEvent<'_>,
+    ) -> ActionResult {
+        let actions = self.peer_actions(event).await;
+        self.perform(poll_wait, actions, &[]).await
+    }
 
+    /// Let the peer handle an event, and publish its new state
+    async fn peer_actions(&mut self, event: PeerEvent<'_>) -> PeerActions {
         let system_snapshot = *self.channels.system_snapshots.read().await;
         let system_config = *self.channels.system_config.read().await;
-        let result = self.peer.handle_incoming(
-            system_snapshot,
-            &system_config,
-            packet,
-            ntp_instant,
-            send_timestamp,
-            recv_timestamp,
-        );
-
-        // Handle incoming may have changed poll interval based on message, respect that change
-        self.update_poll_wait(poll_wait, system_snapshot);
+        let actions = self
+            .peer
+            .handle_event(system_snapshot, &system_config, event);
+        self.channels
+            .state
+            .update_peer(self.addr, self.peer.state(NtpInstant::now()));
 
-        match result {
-            Ok(update) => {
-                debug!("packet accepted");
+        actions
+    }
 
-                // NOTE: fitness check is not performed here, but by System
+    #[instrument(level = "debug", name = "poll", skip_all)]
+    async fn handle_poll(&mut self, poll_wait: &mut Pin<&mut T>) -> ActionResult {
+        let now = NtpInstant::now();
+        // failing to read the clock is handled when sending the request
+        let time = self.clock.now().unwrap_or_default();
+
+        if self.peer.request_in_flight() {
+            self.unanswered += 1;
+        } else {
+            self.unanswered = 0;
+            self.reopened = false;
+        }
 
-                let msg = match update {
-                    Update::BareUpdate(update) => {
-                        MsgForSystem::UpdatedSnapshot(self.index, self.reset_epoch, update)
-                    }
-                    Update::NewMeasurement(update) => {
-                        MsgForSystem::NewMeasurement(self.index, self.reset_epoch, update)
-                    }
-                };
-                self.channels.msg_for_system_sender.send(msg).await.ok();
+        // a NAT on the path may have forgotten the mapping of the socket, which
+        // a new socket gets anew. That is tried once until a request is
+        // answered again, as the peer may just be down.
+        let reopen = matches!(self.nat.reopen_after, Some(limit) if self.unanswered >= limit);
+        if reopen && !self.reopened && !self.transport.reopen_per_request() {
+            info!(
+                unanswered = self.unanswered,
+                "requests went unanswered, opening a new socket in case a NAT forgot its mapping"
+            );
+            self.reopened = true;
+            if let ActionResult::NetworkGone = self.reconnect().await {
+                return ActionResult::NetworkGone;
             }
-            Err(IgnoreReason::KissDemobilize) => {
-                warn!("Demobilizing peer connection on request of remote.");
-                let msg = MsgForSystem::MustDemobilize(self.index);
-                self.channels.msg_for_system_sender.send(msg).await.ok();
+            return self
+                .handle_event(poll_wait, PeerEvent::Reconnect { now, time })
+                .await;
+        }
 
-                return PacketResult::Demobilize;
-            }
-            Err(ignore_reason) => {
-                debug!(?ignore_reason, "packet ignored");
-            }
+        self.handle_event(poll_wait, PeerEvent::PollTimer { now, time })
+            .await
+    }
+
+    /// When the next keepalive is due, if keepalives are sent at all
+    fn keepalive_deadline(&self) -> Option<Instant> {
+        if self.transport.reopen_per_request() {
+            return None;
         }
 
-        PacketResult::Ok
+        let last_sent = Ord::max(self.last_poll_sent, self.last_keepalive_sent);
+        self.nat.keepalive.map(|interval| last_sent + interval)
+    }
+
+    /// Send an empty packet, which servers ignore, such that a NAT on the
+    /// path keeps the mapping of the socket
+    async fn send_keepalive(&mut self) {
+        self.last_keepalive_sent = Instant::now();
+        if let Err(error) = self.transport.send(&[]).await {
+            debug!(?error, "could not send keepalive");
+        }
+    }
+
+    #[instrument(level = "debug", name = "poll", skip_all)]
+    async fn handle_resume(&mut self, poll_wait: &mut Pin<&mut T>) -> ActionResult {
+        let now = NtpInstant::now();
+        // failing to read the clock is handled when sending the request
+        let time = self.clock.now().unwrap_or_default();
+        self.handle_event(poll_wait, PeerEvent::Resume { now, time })
+            .await
+    }
+
+    async fn handle_icmp_error(
+        &mut self,
+        poll_wait: &mut Pin<&mut T>,
+        error: IcmpError,
+    ) -> ActionResult {
+        warn!(%error, "the network reported that the peer can not be reached");
+        let msg = MsgForSystem::Unreachable(self.index, error);
+        self.channels.msg_for_system_sender.send(msg).await.ok();
+
+        // routing problems may be temporary, but a closed port stays closed
+        if error.is_hard() {
+            self.handle_event(poll_wait, PeerEvent::Unreachable).await
+        } else {
+            ActionResult::Continue
+        }
+    }
+
+    #[instrument(level = "debug", name = "packet", skip_all)]
+    async fn handle_packet<'a>(
+        &mut self,
+        poll_wait: &mut Pin<&mut T>,
+        packet: NtpPacket<'a>,
+        data: &[u8],
+        send_timestamp: NtpTimestamp,
+        recv_timestamp: NtpTimestamp,
+    ) -> ActionResult {
+        let now = NtpInstant::now();
+
+        if let Ok(destination) = self.transport.local_addr() {
+            self.channels.statistics.packet(
+                self.addr,
+                destination,
+                send_timestamp,
+                recv_timestamp,
+                &packet,
+            );
+        }
+
+        self.channels.export.exchange(Exchange::new(
+            self.addr,
+            send_timestamp,
+            recv_timestamp,
+            &packet,
+            self.peer.is_authenticated(),
+        ));
+
+        let event = PeerEvent::Packet {
+            packet,
+            now,
+            send_time: send_timestamp,
+            recv_time: recv_timestamp,
+        };
+        let actions = self.peer_actions(event).await;
+        self.perform(poll_wait, actions, data).await
     }
 
     async fn run(&mut self, mut poll_wait: Pin<&mut T>) {
         loop {
-            let mut buf = [0_u8; 48];
-
-            tokio::select! {
-                () = &mut poll_wait => {
-                    match self.handle_poll(&mut poll_wait).await {
-                        PollResult::Ok => {},
-                        PollResult::NetworkGone => {
-                            self.channels.msg_for_system_sender.send(MsgForSystem::NetworkIssue(self.index)).await.ok();
-                            break;
-                        }
-                    }
+            let mut buf = [0_u8; MAX_NTP_PACKET_SIZE];
+            let keepalive = self.keepalive_deadline();
+
+            let result = tokio::select! {
+                () = &mut poll_wait => self.handle_poll(&mut poll_wait).await,
+                () = sleep_until(keepalive) => {
+                    self.send_keepalive().await;
+                    ActionResult::Continue
                 },
                 result = (self.channels.reset.changed()), if self.channels.reset.has_changed().is_ok() => {
-                    if let Ok(()) = result {
-                        // reset the measurement state (as if this association was just created).
-                        // crucially, this sets `self.next_expected_origin = None`, meaning that
-                        // in-flight requests are ignored
-                        self.peer.reset_measurements();
-
-                        // our next measurement will have the new reset epoch
-                        self.reset_epoch = *self.channels.reset.borrow_and_update();
+                    match result {
+                        Ok(()) => self.handle_reset(&mut poll_wait).await,
+                        Err(_) => ActionResult::Continue,
                     }
+                },
+                result = self.transport.recv(&mut buf) => {
+                    self.handle_receive(&mut poll_wait, result, &buf).await
+                },
+            };
+
+            match result {
+                ActionResult::Continue => {}
+                ActionResult::NetworkGone => {
+                    let msg = MsgForSystem::NetworkIssue(self.index);
+                    self.channels.msg_for_system_sender.send(msg).await.ok();
+                    break;
+                }
+                ActionResult::Demobilize => break,
+            }
+        }
+    }
+
+    /// Take over the new reset epoch of the system
+    async fn handle_reset(&mut self, poll_wait: &mut Pin<&mut T>) -> ActionResult {
+        let reset_epoch = *self.channels.reset.borrow_and_update();
+        if reset_epoch.is_resumed() {
+            // the resume resets the measurement state too, but also polls, and
+            // the resulting messages must have the new reset epoch
+            self.reset_epoch = reset_epoch;
+            self.handle_resume(poll_wait).await
+        } else {
+            // reset the measurement state (as if this association was just created).
+            // crucially, this sets `self.next_expected_origin = None`, meaning that
+            // in-flight requests are ignored
+            self.handle_event(poll_wait, PeerEvent::Reset).await;
+
+            // our next measurement will have the new reset epoch
+            self.reset_epoch = reset_epoch;
+            ActionResult::Continue
+        }
+    }
+
+    /// Handle the outcome of receiving from the socket of the peer
+    async fn handle_receive(
+        &mut self,
+        poll_wait: &mut Pin<&mut T>,
+        result: std::io::Result<(usize, Option<NtpTimestamp>)>,
+        buf: &[u8],
+    ) -> ActionResult {
+        // a failed receive may be explained by an ICMP error for our request
+        let icmp_error = if result.is_err() {
+            self.transport.take_icmp_error().unwrap_or_else(|error| {
+                warn!(?error, "could not read the socket error queue");
+                None
+            })
+        } else {
+            None
+        };
+        if let Some(error) = icmp_error {
+            return self.handle_icmp_error(poll_wait, error).await;
+        }
+
+        let result = result.map(|(size, timestamp)| {
+            (
+                size,
+                timestamp.map(|ts| self.clock.adjust_system_timestamp(ts)),
+            )
+        });
+        match accept_packet(result, buf, &self.channels.capture, self.addr, self.parsing) {
+            AcceptResult::Accept(packet, data, recv_timestamp) => {
+                if packet.nonconformance().is_some() {
+                    let msg = MsgForSystem::Nonconforming(self.index);
+                    self.channels.msg_for_system_sender.send(msg).await.ok();
                 }
-                result = self.socket.recv(&mut buf) => {
-                    match accept_packet(result, &buf) {
-                        AcceptResult::Accept(packet, recv_timestamp) => {
-                            let send_timestamp = match self.last_send_timestamp {
-                                Some(ts) => ts,
-                                None => {
-                                    warn!("we received a message without having sent one; discarding");
-                                    continue;
-                                }
-                            };
-
-                            match self.handle_packet(&mut poll_wait, packet, send_timestamp, recv_timestamp).await {
-                                PacketResult::Ok => {},
-                                PacketResult::Demobilize => break,
-                            }
-                        },
-                        AcceptResult::NetworkGone => {
-                            self.channels.msg_for_system_sender.send(MsgForSystem::NetworkIssue(self.index)).await.ok();
-                            break;
-                        },
-                        AcceptResult::Ignore => {},
+
+                if !self.authentic(&packet, data, recv_timestamp) {
+                    return ActionResult::Continue;
+                }
+
+                let send_timestamp = match self.last_send_timestamp {
+                    Some(ts) => ts,
+                    None => {
+                        warn!("we received a message without having sent one; discarding");
+                        return ActionResult::Continue;
                     }
-                },
+                };
+
+                self.handle_packet(poll_wait, packet, data, send_timestamp, recv_timestamp)
+                    .await
+            }
+            AcceptResult::NetworkGone => ActionResult::NetworkGone,
+            AcceptResult::Reopen => self.reconnect().await,
+            AcceptResult::Nonconforming => {
+                let msg = MsgForSystem::Nonconforming(self.index);
+                self.channels.msg_for_system_sender.send(msg).await.ok();
+                ActionResult::Continue
+            }
+            AcceptResult::Ignore => ActionResult::Continue,
+        }
+    }
+
+    /// Whether a response is signed with the key of the peer, if it has one
+    fn authentic(&self, packet: &NtpPacket, data: &[u8], recv_timestamp: NtpTimestamp) -> bool {
+        let id = match self.key {
+            Some(id) => id,
+            None => return true,
+        };
+
+        match self
+            .channels
+            .keys
+            .current()
+            .verify(packet, data, recv_timestamp)
+        {
+            Ok(key) if key.id() == id => true,
+            Ok(key) => {
+                warn!(
+                    expected = id,
+                    actual = key.id(),
+                    "packet is signed with the wrong key; discarding"
+                );
+                let reason = format!("signed with key {}, expected {}", key.id(), id);
+                self.channels.capture.rejected(self.addr, data, reason);
+                false
+            }
+            Err(error) => {
+                warn!(%error, "packet is not authentic; discarding");
+                self.channels.capture.rejected(self.addr, data, error);
+                false
             }
         }
     }
 }
 
-impl<C> PeerTask<C, Sleep>
+impl<C> PeerTask<C, Sleep, UdpTransport>
 where
     C: 'static + NtpClock + Send,
 {
-    #[instrument(skip(clock, channels))]
+    #[instrument(
+        name = "peer",
+        skip(clock, network_wait_period, network, config, channels)
+    )]
     pub fn spawn(
         index: PeerIndex,
         addr: SocketAddr,
         clock: C,
         network_wait_period: std::time::Duration,
+        network: NetworkConfig,
+        config: PeerTaskConfig,
         mut channels: PeerChannels,
     ) -> tokio::task::JoinHandle<()> {
         tokio::spawn(
             (async move {
-                let socket = match UdpSocket::client(unspecified_for(addr), addr).await {
-                    Ok(socket) => socket,
+                let PeerTaskConfig {
+                    bind,
+                    max_offset,
+                    poll,
+                    delay,
+                    version,
+                    parsing,
+                    association,
+                    key,
+                } = config;
+                let nat = bind.nat;
+                let transport = match UdpTransport::open(addr, &bind, network).await {
+                    Ok(transport) => transport,
                     Err(error) => {
                         warn!(?error, "Could not open socket");
                         tokio::time::sleep(network_wait_period).await;
@@ -334,15 +718,41 @@ where
                         return;
                     }
                 };
-                // Unwrap should be safe because we know the socket was bound to a local addres just before
-                let our_id = ReferenceId::from_ip(socket.as_ref().local_addr().unwrap().ip());
 
-                // Unwrap should be safe because we know the socket was connected to a remote peer just before
-                let peer_id = ReferenceId::from_ip(socket.as_ref().peer_addr().unwrap().ip());
+                // Unwrap should be safe because we know the socket was bound to a local addres just before
+                let local_addr = transport.local_addr().unwrap();
+                channels
+                    .msg_for_system_sender
+                    .send(MsgForSystem::Connected(index, local_addr))
+                    .await
+                    .ok();
 
                 let local_clock_time = NtpInstant::now();
                 let config_snapshot = *channels.system_config.read().await;
-                let peer = Peer::new(our_id, peer_id, local_clock_time, &config_snapshot);
+                let mut builder = PeerBuilder::from_addresses(local_addr.ip(), addr.ip())
+                    .mode(association)
+                    .version(version)
+                    .authenticated(key.is_some());
+                if let Some(max_offset) = max_offset {
+                    builder = builder.max_offset(max_offset);
+                }
+                if let Some(max_delay) = delay.max_delay {
+                    builder = builder.max_delay(max_delay);
+                }
+                if let Some(ratio) = delay.max_delay_ratio {
+                    builder = builder.max_delay_ratio(ratio);
+                }
+                if let Some(limits) = poll.limits(config_snapshot.poll_limits) {
+                    builder = builder.poll_limits(limits);
+                }
+                if let Some(initial_poll) = poll.initial_poll {
+                    builder = builder.initial_poll_interval(initial_poll);
+                }
+                let mut peer = builder.build(local_clock_time, &config_snapshot);
+                if let Some((state, downtime)) = channels.state.take_peer(addr) {
+                    debug!(?downtime, "continuing from the state of the previous run");
+                    peer.restore(&state, local_clock_time, downtime);
+                }
 
                 let poll_wait = tokio::time::sleep(std::time::Duration::default());
                 tokio::pin!(poll_wait);
@@ -356,10 +766,17 @@ where
                     index,
                     clock,
                     channels,
-                    socket,
+                    transport,
+                    addr,
+                    key,
+                    parsing,
                     peer,
                     last_send_timestamp: None,
                     last_poll_sent: Instant::now(),
+                    nat,
+                    last_keepalive_sent: Instant::now(),
+                    unanswered: 0,
+                    reopened: false,
                     reset_epoch,
                 };
 
@@ -370,49 +787,77 @@ where
     }
 }
 
+/// Wait until the deadline, or forever without one
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
 #[derive(Debug)]
 enum AcceptResult<'a> {
-    Accept(NtpPacket<'a>, NtpTimestamp),
+    /// The packet, and the data it was parsed from
+    Accept(NtpPacket<'a>, &'a [u8], NtpTimestamp),
+    /// The packet does not conform to RFC 5905, and the parsing policy is
+    /// strict
+    Nonconforming,
     Ignore,
     NetworkGone,
+    /// The transport must be reopened
+    Reopen,
 }
 
-fn unspecified_for(addr: SocketAddr) -> SocketAddr {
-    match addr {
-        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
-        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
-    }
-}
-
-fn accept_packet(
-    result: Result<(usize, SocketAddr, Option<NtpTimestamp>), std::io::Error>,
-    buf: &[u8; 48],
-) -> AcceptResult {
+fn accept_packet<'a>(
+    result: Result<(usize, Option<NtpTimestamp>), std::io::Error>,
+    buf: &'a [u8],
+    capture: &PacketCapture,
+    remote: SocketAddr,
+    parsing: ParsingPolicy,
+) -> AcceptResult<'a> {
     match result {
-        Ok((size, _, Some(recv_timestamp))) => {
+        Ok((size, Some(recv_timestamp))) => {
             // Note: packets are allowed to be bigger when including extensions.
             // we don't expect them, but the server may still send them. The
             // extra bytes are guaranteed safe to ignore. `recv` truncates the messages.
             // Messages of fewer than 48 bytes are skipped entirely
             if size < 48 {
                 warn!(expected = 48, actual = size, "received packet is too small");
+                capture.rejected(remote, &buf[..size.min(buf.len())], "packet is too small");
 
                 AcceptResult::Ignore
             } else {
-                match NtpPacket::deserialize(buf) {
-                    Ok(packet) => AcceptResult::Accept(packet, recv_timestamp),
+                let data = &buf[..size.min(buf.len())];
+                match NtpPacket::deserialize(data) {
+                    Ok(packet) => match packet.nonconformance() {
+                        Some(nonconformance) if parsing == ParsingPolicy::Strict => {
+                            warn!("received nonconforming packet: {}", nonconformance);
+                            capture.rejected(remote, data, nonconformance);
+                            AcceptResult::Nonconforming
+                        }
+                        _ => AcceptResult::Accept(packet, data, recv_timestamp),
+                    },
                     Err(e) => {
                         warn!("received invalid packet: {}", e);
+                        capture.rejected(remote, data, e);
                         AcceptResult::Ignore
                     }
                 }
             }
         }
-        Ok((size, _, None)) => {
+        Ok((size, None)) => {
             warn!(?size, "received a packet without a timestamp");
 
             AcceptResult::Ignore
         }
+        Err(receive_error) if receive_error.kind() == std::io::ErrorKind::ConnectionAborted => {
+            warn!(
+                ?receive_error,
+                "connection to the peer was lost, opening a new one"
+            );
+
+            AcceptResult::Reopen
+        }
         Err(receive_error) => {
             warn!(?receive_error, "could not receive packet");
 
@@ -428,20 +873,23 @@ fn accept_packet(
 }
 
 #[cfg(test)]
-mod tests {
-    use std::time::Duration;
+pub(crate) mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
 
-    use ntp_proto::{NtpDuration, NtpLeapIndicator, PollInterval};
+    use ntp_proto::{NtpDuration, NtpLeapIndicator, ReferenceId};
+    use ntp_udp::UdpSocket;
     use tokio::sync::{mpsc, watch, RwLock};
 
+    use crate::config::ClientSockets;
+
     use super::*;
 
-    struct TestWaitSender {
+    pub(crate) struct TestWaitSender {
         state: Arc<std::sync::Mutex<TestWaitState>>,
     }
 
     impl TestWaitSender {
-        fn notify(&self) {
+        pub(crate) fn notify(&self) {
             let mut state = self.state.lock().unwrap();
             state.pending = true;
             if let Some(waker) = state.waker.take() {
@@ -450,7 +898,7 @@ mod tests {
         }
     }
 
-    struct TestWait {
+    pub(crate) struct TestWait {
         state: Arc<std::sync::Mutex<TestWaitState>>,
     }
 
@@ -490,7 +938,7 @@ mod tests {
     }
 
     impl TestWait {
-        fn new() -> (TestWait, TestWaitSender) {
+        pub(crate) fn new() -> (TestWait, TestWaitSender) {
             let state = Arc::new(std::sync::Mutex::new(TestWaitState {
                 waker: None,
                 pending: false,
@@ -546,7 +994,7 @@ mod tests {
     async fn test_startup<T: Wait>(
         port_base: u16,
     ) -> (
-        PeerTask<TestClock, T>,
+        PeerTask<TestClock, T, UdpTransport>,
         UdpSocket,
         mpsc::Receiver<MsgForSystem>,
         watch::Sender<ResetEpoch>,
@@ -565,8 +1013,23 @@ mod tests {
         )
         .await
         .unwrap();
-        let our_id = ReferenceId::from_ip(socket.as_ref().local_addr().unwrap().ip());
-        let peer_id = ReferenceId::from_ip(socket.as_ref().peer_addr().unwrap().ip());
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port_base + 1));
+        let transport = UdpTransport::from_socket(socket, addr);
+        let (process, msg_for_system_receiver, reset_send) = test_task(transport, addr).await;
+
+        (process, test_socket, msg_for_system_receiver, reset_send)
+    }
+
+    async fn test_task<T: Wait, S: Transport>(
+        transport: S,
+        addr: SocketAddr,
+    ) -> (
+        PeerTask<TestClock, T, S>,
+        mpsc::Receiver<MsgForSystem>,
+        watch::Sender<ResetEpoch>,
+    ) {
+        let our_id = ReferenceId::from_ip(transport.local_addr().unwrap().ip());
+        let peer_id = ReferenceId::from_ip(addr.ip());
 
         let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
         let system_config = Arc::new(RwLock::new(SystemConfig::default()));
@@ -590,15 +1053,27 @@ mod tests {
                 system_snapshots,
                 system_config,
                 reset,
+                statistics: Default::default(),
+                export: Default::default(),
+                state: Default::default(),
+                keys: Default::default(),
+                capture: Default::default(),
             },
-            socket,
+            transport,
+            addr,
+            key: None,
+            parsing: Default::default(),
             peer,
             last_send_timestamp: None,
             last_poll_sent: Instant::now(),
+            nat: Default::default(),
+            last_keepalive_sent: Instant::now(),
+            unanswered: 0,
+            reopened: false,
             reset_epoch: ResetEpoch::default(),
         };
 
-        (process, test_socket, msg_for_system_receiver, reset_send)
+        (process, msg_for_system_receiver, reset_send)
     }
 
     #[tokio::test]
@@ -622,14 +1097,28 @@ mod tests {
             SocketAddr::from((Ipv4Addr::LOCALHOST, 8003)),
             TestClock {},
             std::time::Duration::from_secs(60),
+            Default::default(),
+            Default::default(),
             PeerChannels {
                 msg_for_system_sender,
                 system_snapshots,
                 system_config,
                 reset,
+                statistics: Default::default(),
+                export: Default::default(),
+                state: Default::default(),
+                keys: Default::default(),
+                capture: Default::default(),
             },
         );
 
+        let local_addr = match msg_for_system_receiver.recv().await.unwrap() {
+            MsgForSystem::Connected(_, local_addr) => local_addr,
+            _ => panic!("Unexpected message"),
+        };
+
+        assert_eq!(local_addr.ip(), Ipv4Addr::LOCALHOST);
+
         let peer_epoch = match msg_for_system_receiver.recv().await.unwrap() {
             MsgForSystem::UpdatedSnapshot(_, peer_epoch, _) => peer_epoch,
             _ => panic!("Unexpected message"),
@@ -783,4 +1272,293 @@ mod tests {
 
         handle.abort();
     }
+
+    #[tokio::test]
+    async fn test_port_unreachable() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let (mut process, socket, mut msg_recv, _reset) = test_startup(8016).await;
+        // nothing listens on the port of the peer anymore
+        drop(socket);
+
+        let (poll_wait, poll_send) = TestWait::new();
+
+        let handle = tokio::spawn(async move {
+            tokio::pin!(poll_wait);
+            process.run(poll_wait).await;
+        });
+
+        poll_send.notify();
+
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _, _)));
+
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(
+            msg,
+            MsgForSystem::Unreachable(_, IcmpError::PortUnreachable)
+        ));
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_per_request_sockets() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let (mut process, _socket, mut msg_recv, _reset) = test_startup(8012).await;
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:8014").await.unwrap();
+        process.addr = server.local_addr().unwrap();
+        let network = NetworkConfig {
+            client_sockets: ClientSockets::PerRequest,
+            ..Default::default()
+        };
+        process.transport = UdpTransport::open(process.addr, &Default::default(), network)
+            .await
+            .unwrap();
+
+        let (poll_wait, poll_send) = TestWait::new();
+
+        let handle = tokio::spawn(async move {
+            tokio::pin!(poll_wait);
+            process.run(poll_wait).await;
+        });
+
+        let mut ports = vec![];
+        for _ in 0..2 {
+            poll_send.notify();
+
+            let local_addr = match msg_recv.recv().await.unwrap() {
+                MsgForSystem::Connected(_, local_addr) => local_addr,
+                _ => panic!("Unexpected message"),
+            };
+
+            let mut buf = [0; 48];
+            let (size, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(size, 48);
+            assert_eq!(from.port(), local_addr.port());
+            ports.push(from.port());
+
+            let msg = msg_recv.recv().await.unwrap();
+            assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _, _)));
+        }
+
+        assert_ne!(ports[0], ports[1]);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_nat_reopen_and_keepalive() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let (mut process, _socket, mut msg_recv, _reset) = test_startup(8020).await;
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:8022").await.unwrap();
+        process.addr = server.local_addr().unwrap();
+        let bind = PeerBindConfig {
+            nat: PeerNatConfig {
+                connected: false,
+                keepalive: Some(Duration::from_millis(50)),
+                reopen_after: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        process.nat = bind.nat;
+        process.transport = UdpTransport::open(process.addr, &bind, Default::default())
+            .await
+            .unwrap();
+
+        let (poll_wait, poll_send) = TestWait::new();
+
+        let handle = tokio::spawn(async move {
+            tokio::pin!(poll_wait);
+            process.run(poll_wait).await;
+        });
+
+        let mut buf = [0; 48];
+        let mut ports = vec![];
+        for poll in 0..3 {
+            poll_send.notify();
+
+            // the third poll finds two requests in a row unanswered
+            if poll == 2 {
+                let msg = msg_recv.recv().await.unwrap();
+                assert!(matches!(msg, MsgForSystem::Connected(_, _)));
+            }
+            let msg = msg_recv.recv().await.unwrap();
+            assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _, _)));
+
+            let (size, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(size, 48);
+            ports.push(from.port());
+        }
+
+        assert_eq!(ports[0], ports[1]);
+        assert_ne!(ports[1], ports[2]);
+
+        // nothing was sent for a while, so a keepalive follows
+        let (size, from) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(size, 0);
+        assert_eq!(from.port(), ports[2]);
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_proxy() {
+        // Note: Ports must be unique among tests to deal with parallelism
+        let (mut process, _socket, mut msg_recv, _reset) = test_startup(8018).await;
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        process.addr = server.local_addr().unwrap();
+
+        let proxy =
+            crate::socks5::tests::spawn_proxy(Some(("ntp", "secret")), Duration::from_secs(60))
+                .await;
+        let bind = PeerBindConfig {
+            socks5: Some(crate::config::Socks5Config {
+                addr: proxy.to_string(),
+                username: Some("ntp".into()),
+                password: Some("secret".into()),
+            }),
+            ..Default::default()
+        };
+        process.transport = UdpTransport::open(process.addr, &bind, Default::default())
+            .await
+            .unwrap();
+
+        let (poll_wait, poll_send) = TestWait::new();
+        let clock = TestClock {};
+
+        let handle = tokio::spawn(async move {
+            tokio::pin!(poll_wait);
+            process.run(poll_wait).await;
+        });
+
+        poll_send.notify();
+
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _, _)));
+
+        // the server gets the bare request, from the relay of the proxy
+        let mut buf = [0; 48];
+        let (size, relay) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+
+        let rec_packet = NtpPacket::deserialize(&buf).unwrap();
+        let send_packet = NtpPacket::timestamp_response(
+            &SystemSnapshot::default(),
+            rec_packet,
+            clock.now().unwrap(),
+            &clock,
+        );
+        let mut pdata = vec![];
+        send_packet.serialize(&mut pdata).unwrap();
+        server.send_to(&pdata, relay).await.unwrap();
+
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::NewMeasurement(_, _, _)));
+
+        handle.abort();
+    }
+
+    /// A transport that hands the requests to the test, and receives the
+    /// responses the test gives it
+    struct ChannelTransport {
+        requests: mpsc::UnboundedSender<Vec<u8>>,
+        responses: mpsc::UnboundedReceiver<Vec<u8>>,
+    }
+
+    impl Transport for ChannelTransport {
+        async fn send(&mut self, packet: &[u8]) -> std::io::Result<Option<NtpTimestamp>> {
+            self.requests.send(packet.to_vec()).ok();
+            Ok(None)
+        }
+
+        async fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, Option<NtpTimestamp>)> {
+            match self.responses.recv().await {
+                Some(packet) => {
+                    let len = packet.len().min(buf.len());
+                    buf[..len].copy_from_slice(&packet[..len]);
+                    Ok((packet.len(), Some(TestClock {}.now().unwrap())))
+                }
+                None => std::future::pending().await,
+            }
+        }
+
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, 123)))
+        }
+
+        async fn reopen(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transport() {
+        let (requests, mut request_recv) = mpsc::unbounded_channel();
+        let (response_send, responses) = mpsc::unbounded_channel();
+        let transport = ChannelTransport {
+            requests,
+            responses,
+        };
+        let addr = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 123));
+        let (mut process, mut msg_recv, _reset) = test_task(transport, addr).await;
+
+        let (poll_wait, poll_send) = TestWait::new();
+        let clock = TestClock {};
+
+        let handle = tokio::spawn(async move {
+            tokio::pin!(poll_wait);
+            process.run(poll_wait).await;
+        });
+
+        poll_send.notify();
+
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _, _)));
+
+        let request = request_recv.recv().await.unwrap();
+        let rec_packet = NtpPacket::deserialize(&request).unwrap();
+        let send_packet = NtpPacket::timestamp_response(
+            &SystemSnapshot::default(),
+            rec_packet,
+            clock.now().unwrap(),
+            &clock,
+        );
+        let mut pdata = vec![];
+        send_packet.serialize(&mut pdata).unwrap();
+        response_send.send(pdata).unwrap();
+
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::NewMeasurement(_, _, _)));
+
+        handle.abort();
+    }
+
+    #[test]
+    fn test_accept_packet_parsing() {
+        let clock = TestClock {};
+        let (request, _) = NtpPacket::poll_message(PollInterval::default());
+        let response = NtpPacket::timestamp_response(
+            &SystemSnapshot::default(),
+            request,
+            clock.now().unwrap(),
+            &clock,
+        );
+        let mut buf = vec![];
+        response.serialize(&mut buf).unwrap();
+        // stratum 17 is not a stratum of RFC 5905
+        buf[1] = 17;
+
+        let remote = SocketAddr::from((Ipv4Addr::LOCALHOST, 123));
+        let capture = PacketCapture::default();
+        let result = || Ok((buf.len(), Some(clock.now().unwrap())));
+        assert!(matches!(
+            accept_packet(result(), &buf, &capture, remote, ParsingPolicy::Lenient),
+            AcceptResult::Accept(..)
+        ));
+        assert!(matches!(
+            accept_packet(result(), &buf, &capture, remote, ParsingPolicy::Strict),
+            AcceptResult::Nonconforming
+        ));
+    }
 }
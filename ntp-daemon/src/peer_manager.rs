@@ -1,14 +1,33 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 use crate::{
-    config::{PeerConfig, PoolPeerConfig, ServerConfig, StandardPeerConfig},
-    observer::ObservablePeerState,
-    peer::{MsgForSystem, PeerChannels, PeerTask, ResetEpoch},
-    server::{ServerStats, ServerTask},
+    config::{
+        NetworkConfig, PeerConfig, PoolPeerConfig, RefClockConfig, ServerConfig, SourceGroupConfig,
+        StandardPeerConfig,
+    },
+    control::{refclock_address, Association, Associations},
+    dual_stack::{self, AddressStats},
+    history::{
+        ClockSample, History, ObservableHistory, PeerHistory, PeerSample, CLOCK_HISTORY,
+        PEER_HISTORY,
+    },
+    mru::ClientList,
+    observer::{ObservablePeerState, SelectionStatus, Unreachable},
+    peer::{MsgForSystem, PeerChannels, PeerTask, PeerTaskConfig, ResetEpoch},
+    pool,
+    quality::QualityTracker,
+    refclock::{RefClockTask, SharedRefClockStatistics},
+    rejection::{RejectionReason, Rejections},
+    server::{Drain, ServerStats, ServerTask},
+    smear::SmearEvidence,
 };
-use ntp_proto::{NtpClock, PeerSnapshot};
+use ntp_proto::{
+    AcceptSynchronizationError, NtpClock, PeerSnapshot, ReferenceId, SelectionError,
+    SelectionIntervals,
+};
+use ntp_udp::IcmpError;
 use tokio::task::JoinHandle;
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 
 const NETWORK_WAIT_PERIOD: std::time::Duration = std::time::Duration::from_secs(1);
 
@@ -32,6 +51,11 @@ pub struct PeerIndex {
 }
 
 impl PeerIndex {
+    /// Id of the association in the control protocol, in the range 1..=65535
+    fn association_id(&self) -> u16 {
+        (self.index % u16::MAX as usize) as u16 + 1
+    }
+
     #[cfg(test)]
     pub fn from_inner(index: usize) -> Self {
         PeerIndex { index }
@@ -54,57 +78,119 @@ impl PeerIndexIssuer {
 #[derive(Debug)]
 struct PeerData {
     status: PeerStatus,
+    quality: QualityTracker,
+    selected: bool,
+    rejections: Rejections,
+    addr: SocketAddr,
+    /// Local address of the socket to the peer, once opened
+    local_addr: Option<SocketAddr>,
+    /// Latest error reported by the network for requests to the peer, until
+    /// it answers again
+    icmp_error: Option<IcmpError>,
+    /// The other address of a dual-stack peer
+    sibling: Option<PeerIndex>,
+    /// Whether this address of a dual-stack peer is only probed, while its
+    /// sibling is used for synchronization
+    standby: bool,
+    /// Why the peer appears to smear leap seconds, if it does
+    smearing: Option<SmearEvidence>,
+    /// Packets received from the peer that do not conform to RFC 5905
+    nonconforming: u64,
+    history: History<PeerSample>,
     config: Arc<PeerConfig>,
 }
 
+impl PeerData {
+    /// Address of the peer as shown by the management client
+    fn observed_address(&self) -> String {
+        match &*self.config {
+            PeerConfig::Standard(StandardPeerConfig { addr, .. }) => addr.as_str().to_string(),
+            // the servers of a pool are told apart by their address
+            PeerConfig::Pool(_) => self.addr.to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RefClockData {
+    status: PeerStatus,
+    quality: QualityTracker,
+    selected: bool,
+    rejections: Rejections,
+    history: History<PeerSample>,
+    config: Arc<RefClockConfig>,
+    statistics: SharedRefClockStatistics,
+}
+
 #[derive(Debug, Clone)]
 pub struct ServerData {
     pub stats: ServerStats,
+    pub clients: ClientList,
     pub config: ServerConfig,
 }
 
 #[derive(Debug)]
 pub struct Peers<C: NtpClock> {
     peers: HashMap<PeerIndex, PeerData>,
+    refclocks: HashMap<PeerIndex, RefClockData>,
     servers: Vec<ServerData>,
+    /// Drain mode of the servers on anycast addresses
+    drain: Drain,
     indexer: PeerIndexIssuer,
+    associations: Associations,
+    selection: SelectionStatus,
+    intervals: SelectionIntervals,
+    clock_history: History<ClockSample>,
 
     channels: PeerChannels,
     clock: C,
+    network: NetworkConfig,
 }
 
 impl<C: NtpClock> Peers<C> {
-    pub fn new(channels: PeerChannels, clock: C) -> Self {
+    pub fn new(channels: PeerChannels, clock: C, network: NetworkConfig) -> Self {
         Peers {
             peers: Default::default(),
+            refclocks: Default::default(),
             servers: Default::default(),
+            drain: Default::default(),
             indexer: Default::default(),
+            associations: Default::default(),
+            selection: Default::default(),
+            intervals: Default::default(),
+            clock_history: History::new(CLOCK_HISTORY),
             channels,
             clock,
+            network,
         }
     }
 
-    async fn add_peer_internal(&mut self, config: Arc<PeerConfig>) -> JoinHandle<()> {
-        let index = self.indexer.get();
-        let addr = loop {
-            let host = match &*config {
+    async fn add_peer_internal(&mut self, config: Arc<PeerConfig>) {
+        let bind = config.bind().clone();
+        let addresses = loop {
+            // with a source address, only addresses of the same family can be reached
+            let host: std::io::Result<Vec<_>> = match &*config {
                 PeerConfig::Standard(StandardPeerConfig { addr, .. }) => {
                     debug!(unresolved = ?&addr, "lookup host");
-                    addr.lookup_host().await.map(|mut i| i.next())
+                    addr.lookup_host()
+                        .await
+                        .map(|i| i.filter(|addr| bind.matches_family(addr)).collect())
                 }
 
                 PeerConfig::Pool(PoolPeerConfig { addr, .. }) => {
                     debug!(unresolved = ?&addr, "lookup host");
-                    addr.lookup_host().await.map(|mut i| i.next())
+                    addr.lookup_host()
+                        .await
+                        .map(|i| i.filter(|addr| bind.matches_family(addr)).collect())
                 }
             };
 
             match host {
-                Ok(Some(addr)) => {
-                    debug!(resolved=?addr, "resolved peer");
-                    break addr;
+                Ok(addresses) if !addresses.is_empty() => {
+                    debug!(resolved=?addresses, "resolved peer");
+                    break addresses;
                 }
-                Ok(None) => {
+                Ok(_) => {
                     warn!("Could not resolve peer address, retrying");
                     tokio::time::sleep(NETWORK_WAIT_PERIOD).await
                 }
@@ -114,38 +200,146 @@ impl<C: NtpClock> Peers<C> {
                 }
             }
         };
+
+        match &*config {
+            PeerConfig::Standard(StandardPeerConfig { dual_stack, .. }) => {
+                let index = self.indexer.get();
+                // the address preferred by the resolver is used first
+                let addr = addresses[0];
+                let other = if *dual_stack {
+                    addresses
+                        .iter()
+                        .find(|other| other.is_ipv4() != addr.is_ipv4())
+                        .copied()
+                } else {
+                    None
+                };
+
+                let sibling = other.map(|other| {
+                    let sibling = self.indexer.get();
+                    debug!(resolved=?other, "probing other address family of dual-stack peer");
+                    self.spawn_peer(sibling, other, config.clone(), Some(index), true);
+                    sibling
+                });
+
+                self.spawn_peer(index, addr, config.clone(), sibling, false);
+            }
+            PeerConfig::Pool(PoolPeerConfig {
+                max_peers, groups, ..
+            }) => {
+                let in_use: Vec<_> = self
+                    .peers
+                    .values()
+                    .filter(|data| Arc::ptr_eq(&data.config, &config))
+                    .map(|data| data.addr)
+                    .collect();
+                let wanted = max_peers.saturating_sub(in_use.len());
+                let servers = pool::select_servers(&addresses, &in_use, groups, wanted);
+                if servers.len() < wanted {
+                    warn!(
+                        wanted,
+                        found = servers.len(),
+                        "pool resolved to too few servers in distinct subnets and groups"
+                    );
+                }
+
+                for addr in servers {
+                    let index = self.indexer.get();
+                    self.spawn_peer(index, addr, config.clone(), None, false);
+                }
+            }
+        }
+    }
+
+    fn spawn_peer(
+        &mut self,
+        index: PeerIndex,
+        addr: SocketAddr,
+        config: Arc<PeerConfig>,
+        sibling: Option<PeerIndex>,
+        standby: bool,
+    ) {
+        let task_config = PeerTaskConfig::from(&*config);
         self.peers.insert(
             index,
             PeerData {
                 status: PeerStatus::NoMeasurement,
+                quality: QualityTracker::default(),
+                selected: false,
+                rejections: Rejections::default(),
+                addr,
+                local_addr: None,
+                icmp_error: None,
+                sibling,
+                standby,
+                smearing: None,
+                nonconforming: 0,
+                history: History::new(PEER_HISTORY),
                 config,
             },
         );
+        self.publish_associations();
         PeerTask::spawn(
             index,
             addr,
             self.clock.clone(),
             NETWORK_WAIT_PERIOD,
+            self.network,
+            task_config,
             self.channels.clone(),
-        )
+        );
     }
 
-    pub async fn add_peer(&mut self, config: PeerConfig) -> JoinHandle<()> {
+    pub async fn add_peer(&mut self, config: PeerConfig) {
         self.add_peer_internal(Arc::new(config)).await
     }
 
+    pub fn add_refclock(&mut self, config: RefClockConfig) -> JoinHandle<()> {
+        let index = self.indexer.get();
+        let config = Arc::new(config);
+        let statistics = SharedRefClockStatistics::default();
+        self.refclocks.insert(
+            index,
+            RefClockData {
+                status: PeerStatus::NoMeasurement,
+                quality: QualityTracker::default(),
+                selected: false,
+                rejections: Rejections::default(),
+                history: History::new(PEER_HISTORY),
+                config: config.clone(),
+                statistics: statistics.clone(),
+            },
+        );
+        self.publish_associations();
+        RefClockTask::spawn(
+            index,
+            &config,
+            self.clock.clone(),
+            self.channels.clone(),
+            statistics,
+        )
+    }
+
     pub async fn add_server(&mut self, config: ServerConfig) -> JoinHandle<()> {
-        let stats = ServerStats::default();
+        let stats = ServerStats::new(&config);
+        let clients = ClientList::new(config.mru_size);
         self.servers.push(ServerData {
             stats: stats.clone(),
+            clients: clients.clone(),
             config: config.clone(),
         });
         ServerTask::spawn(
             config,
             stats,
+            clients,
             self.channels.system_snapshots.clone(),
+            self.associations.clone(),
+            self.channels.keys.clone(),
+            self.channels.capture.clone(),
+            self.drain.clone(),
             self.clock.clone(),
             NETWORK_WAIT_PERIOD,
+            self.network,
         )
     }
 
@@ -162,6 +356,17 @@ impl<C: NtpClock> Peers<C> {
                 index,
                 PeerData {
                     status: status.to_owned(),
+                    quality: QualityTracker::default(),
+                    selected: false,
+                    rejections: Rejections::default(),
+                    addr: "127.0.0.1:123".parse().unwrap(),
+                    local_addr: None,
+                    icmp_error: None,
+                    sibling: None,
+                    standby: false,
+                    smearing: None,
+                    nonconforming: 0,
+                    history: History::new(PEER_HISTORY),
                     config: Arc::new(raw_configs[i].clone()),
                 },
             );
@@ -169,85 +374,493 @@ impl<C: NtpClock> Peers<C> {
 
         Self {
             peers,
+            refclocks: HashMap::new(),
             servers: vec![],
+            drain: Default::default(),
             indexer,
+            associations: Default::default(),
+            selection: Default::default(),
+            intervals: Default::default(),
+            clock_history: History::new(CLOCK_HISTORY),
             channels: PeerChannels::test(),
             clock,
+            network: Default::default(),
         }
     }
 
     pub fn size(&self) -> usize {
-        self.peers.len()
+        self.peers.len() + self.refclocks.len()
     }
 
     pub fn observe_peers(&self) -> impl Iterator<Item = ObservablePeerState> + '_ {
-        self.peers.iter().map(|(_, data)| match data.status {
-            PeerStatus::NoMeasurement => ObservablePeerState::Nothing,
-            PeerStatus::Measurement(snapshot) => ObservablePeerState::Observable {
-                statistics: snapshot.statistics,
-                reachability: snapshot.reach,
-                uptime: snapshot.time.elapsed(),
-                poll_interval: snapshot.poll_interval,
-                peer_id: snapshot.peer_id,
-                address: match &*data.config {
-                    PeerConfig::Standard(StandardPeerConfig { addr, .. }) => {
-                        addr.as_str().to_string()
-                    }
-                    PeerConfig::Pool(PoolPeerConfig { addr, .. }) => addr.as_str().to_string(),
-                },
+        let peers = self.peers.values().map(|data| {
+            let address = data.observed_address();
+            let source = (data.status, &data.quality, data.rejections);
+            let network = (
+                data.local_addr,
+                data.icmp_error,
+                data.standby,
+                data.smearing,
+                data.nonconforming,
+            );
+            (source, address, network, None)
+        });
+        let refclocks = self.refclocks.values().map(|data| {
+            let source = (data.status, &data.quality, data.rejections);
+            let address = data.config.description();
+            (
+                source,
+                address,
+                (None, None, false, None, 0),
+                Some(data.statistics.get()),
+            )
+        });
+
+        peers.chain(refclocks).map(
+            |(
+                (status, quality, rejections),
+                address,
+                (local_address, icmp_error, standby, smearing, nonconforming),
+                refclock,
+            )| {
+                match status {
+                    PeerStatus::NoMeasurement => ObservablePeerState::Nothing,
+                    PeerStatus::Measurement(snapshot) => ObservablePeerState::Observable {
+                        statistics: snapshot.statistics,
+                        reachability: snapshot.reach,
+                        uptime: snapshot.time.elapsed(),
+                        poll_interval: snapshot.poll_interval,
+                        peer_id: snapshot.peer_id,
+                        address,
+                        local_address,
+                        quality: quality.score(&snapshot),
+                        rejections,
+                        unreachable: (!snapshot.reach.is_reachable())
+                            .then(|| icmp_error.map_or(Unreachable::Timeout, Unreachable::from)),
+                        standby,
+                        failures: snapshot.failures,
+                        refclock,
+                        smearing,
+                        version: snapshot.version,
+                        nonconforming,
+                    },
+                }
             },
-        })
+        )
     }
 
     pub fn servers(&self) -> impl Iterator<Item = ServerData> + '_ {
         self.servers.iter().cloned()
     }
 
+    /// Handle to the drain mode of the servers on anycast addresses
+    pub fn drain(&self) -> Drain {
+        self.drain.clone()
+    }
+
     pub fn valid_snapshots(&self) -> impl Iterator<Item = PeerSnapshot> + '_ {
-        self.peers.iter().filter_map(|(_, data)| match data.status {
+        self.ranked_snapshots(&[]).map(|(_, snapshot)| snapshot)
+    }
+
+    /// The valid snapshots, with the position of the source group of their
+    /// source in `groups`. Sources without a group, or with a group that is
+    /// not in `groups`, rank after all groups.
+    pub fn ranked_snapshots<'a>(
+        &'a self,
+        groups: &'a [SourceGroupConfig],
+    ) -> impl Iterator<Item = (usize, PeerSnapshot)> + 'a {
+        let rank = move |name: Option<&str>| {
+            name.and_then(|name| groups.iter().position(|group| group.name == name))
+                .unwrap_or(groups.len())
+        };
+
+        // the standby address of a dual-stack peer would count the peer twice
+        let peers = self
+            .peers
+            .values()
+            .filter(|data| !data.standby)
+            .map(move |data| (rank(data.config.source_group()), data.status));
+        let refclocks = self
+            .refclocks
+            .values()
+            .map(move |data| (rank(data.config.source_group()), data.status));
+
+        peers
+            .chain(refclocks)
+            .filter_map(|(rank, status)| match status {
+                PeerStatus::NoMeasurement => None,
+                PeerStatus::Measurement(snapshot) => Some((rank, snapshot)),
+            })
+    }
+
+    fn source_mut(
+        &mut self,
+        index: &PeerIndex,
+    ) -> (
+        &mut PeerStatus,
+        &mut QualityTracker,
+        &mut History<PeerSample>,
+    ) {
+        match self.peers.get_mut(index) {
+            Some(data) => (&mut data.status, &mut data.quality, &mut data.history),
+            None => {
+                let data = self.refclocks.get_mut(index).unwrap();
+                (&mut data.status, &mut data.quality, &mut data.history)
+            }
+        }
+    }
+
+    /// Remember an update of the system clock
+    pub fn record_clock_update(&mut self, sample: ClockSample) {
+        self.clock_history.push(sample);
+    }
+
+    /// The latest update of the system clock
+    pub fn last_clock_update(&self) -> Option<ClockSample> {
+        self.clock_history.last().copied()
+    }
+
+    /// The recent clock updates, and the recent measurements of every source
+    pub fn history(&self) -> ObservableHistory {
+        let peers = self.peers.values().map(|data| PeerHistory {
+            address: data.observed_address(),
+            samples: data.history.samples(),
+        });
+        let refclocks = self.refclocks.values().map(|data| PeerHistory {
+            address: data.config.description(),
+            samples: data.history.samples(),
+        });
+
+        ObservableHistory {
+            clock: self.clock_history.samples(),
+            peers: peers.chain(refclocks).collect(),
+        }
+    }
+
+    /// Outcome of the latest round of clock selection
+    pub fn selection(&self) -> SelectionStatus {
+        self.selection
+    }
+
+    /// Correctness intervals of the sources in the latest round of clock selection
+    pub fn intervals(&self) -> SelectionIntervals {
+        self.intervals.clone()
+    }
+
+    pub fn record_intervals(&mut self, intervals: SelectionIntervals) {
+        self.intervals = intervals;
+    }
+
+    /// Keep track of which sources survived the latest round of clock selection,
+    /// and why the sources that were not acceptable for synchronization were rejected
+    pub fn record_selection(
+        &mut self,
+        selection: Result<&[ReferenceId], SelectionError>,
+        rejected: &[(ReferenceId, AcceptSynchronizationError)],
+    ) {
+        let status = match selection {
+            Ok(survivors) => SelectionStatus::Synchronizing {
+                survivors: survivors.len(),
+            },
+            Err(error) => error.into(),
+        };
+        self.record_round(status, selection.unwrap_or(&[]), rejected);
+    }
+
+    /// Keep track of which sources were acceptable for synchronization, when
+    /// the sources are only measured. For the source quality, being
+    /// acceptable counts as surviving clock selection, but no source is
+    /// marked as selected.
+    pub fn record_measurement(
+        &mut self,
+        acceptable: &[ReferenceId],
+        rejected: &[(ReferenceId, AcceptSynchronizationError)],
+    ) {
+        self.record_round(SelectionStatus::Measuring, acceptable, rejected);
+    }
+
+    fn record_round(
+        &mut self,
+        status: SelectionStatus,
+        survivors: &[ReferenceId],
+        rejected: &[(ReferenceId, AcceptSynchronizationError)],
+    ) {
+        self.selection = status;
+        let selecting = status != SelectionStatus::Measuring;
+
+        let sources = self
+            .peers
+            .values_mut()
+            .filter(|data| !data.standby)
+            .map(|data| {
+                (
+                    &data.status,
+                    &mut data.quality,
+                    &mut data.selected,
+                    &mut data.rejections,
+                )
+            })
+            .chain(self.refclocks.values_mut().map(|data| {
+                (
+                    &data.status,
+                    &mut data.quality,
+                    &mut data.selected,
+                    &mut data.rejections,
+                )
+            }));
+
+        for (status, quality, selected, rejections) in sources {
+            let survived = match status {
+                PeerStatus::NoMeasurement => false,
+                PeerStatus::Measurement(snapshot) => {
+                    let id = snapshot.peer_id;
+                    if let Some((_, reason)) = rejected.iter().find(|(peer_id, _)| *peer_id == id) {
+                        rejections.record(*reason);
+                    }
+                    survivors.contains(&id)
+                }
+            };
+            quality.record_selection(survived);
+            *selected = selecting && survived;
+        }
+
+        self.publish_associations();
+    }
+
+    /// Keep track of the peers that appear to smear leap seconds, which were
+    /// left out of clock selection when `excluded`
+    pub(crate) fn record_smearing(
+        &mut self,
+        smearing: &[(ReferenceId, SmearEvidence)],
+        excluded: bool,
+    ) {
+        for data in self.peers.values_mut() {
+            let evidence = match data.status {
+                PeerStatus::NoMeasurement => None,
+                PeerStatus::Measurement(snapshot) => smearing
+                    .iter()
+                    .find(|(peer_id, _)| *peer_id == snapshot.peer_id)
+                    .map(|(_, evidence)| *evidence),
+            };
+
+            if evidence.is_some() && data.smearing.is_none() {
+                warn!(address = %data.addr, ?evidence, "peer appears to smear leap seconds");
+            }
+            if evidence.is_some() && excluded {
+                data.rejections.record(RejectionReason::Smearing);
+            }
+            data.smearing = evidence;
+        }
+    }
+
+    /// Current view of the sources, as also used to answer control requests
+    pub(crate) fn associations(&self) -> Vec<Association> {
+        match self.associations.read() {
+            Ok(guard) => guard.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Update the view of the sources used to answer control requests
+    fn publish_associations(&self) {
+        let snapshot = |status: PeerStatus| match status {
             PeerStatus::NoMeasurement => None,
             PeerStatus::Measurement(snapshot) => Some(snapshot),
-        })
+        };
+
+        let mut associations: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(index, data)| Association {
+                id: index.association_id(),
+                address: data.addr,
+                snapshot: snapshot(data.status),
+                selected: data.selected,
+            })
+            .collect();
+
+        let mut refclocks: Vec<_> = self.refclocks.iter().collect();
+        refclocks.sort_by_key(|(index, _)| index.index);
+        let mut units: HashMap<SocketAddr, u8> = HashMap::new();
+        for (index, data) in refclocks {
+            let unit = units.entry(refclock_address(&data.config, 0)).or_default();
+            associations.push(Association {
+                id: index.association_id(),
+                address: refclock_address(&data.config, *unit),
+                snapshot: snapshot(data.status),
+                selected: data.selected,
+            });
+            *unit = unit.wrapping_add(1);
+        }
+
+        associations.sort_by_key(|association| association.id);
+
+        // A poisoned lock only means a reader panicked, the data is replaced anyway
+        let mut guard = match self.associations.write() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *guard = associations;
     }
 
     pub async fn update(&mut self, msg: MsgForSystem, current_reset_epoch: ResetEpoch) {
         match msg {
             MsgForSystem::MustDemobilize(index) => {
-                self.peers.remove(&index);
+                let sibling = match self.peers.remove(&index) {
+                    Some(data) => {
+                        self.channels.state.forget_peer(data.addr);
+                        data.sibling
+                    }
+                    None => None,
+                };
+                // the other address of a dual-stack peer is all that is left of it
+                if let Some(data) = sibling.and_then(|sibling| self.peers.get_mut(&sibling)) {
+                    data.sibling = None;
+                    data.standby = false;
+                }
             }
             MsgForSystem::NewMeasurement(index, msg_reset_epoch, snapshot) => {
+                if let Some(data) = self.peers.get_mut(&index) {
+                    data.icmp_error = None;
+                }
+
+                let time = self.clock.now().unwrap_or_default();
+                let (status, quality, history) = self.source_mut(&index);
+                quality.record_measurement(&snapshot);
+                history.push(PeerSample::new(time, &snapshot));
                 if current_reset_epoch == msg_reset_epoch {
-                    self.peers.get_mut(&index).unwrap().status = PeerStatus::Measurement(snapshot);
+                    *status = PeerStatus::Measurement(snapshot);
+                    self.choose_address(index);
+                    self.publish_associations();
+                    self.log_measurement(index, snapshot).await;
                 }
             }
             MsgForSystem::UpdatedSnapshot(index, msg_reset_epoch, snapshot) => {
                 if current_reset_epoch == msg_reset_epoch {
-                    self.peers.get_mut(&index).unwrap().status = PeerStatus::Measurement(snapshot);
+                    *self.source_mut(&index).0 = PeerStatus::Measurement(snapshot);
+                    self.choose_address(index);
+                }
+            }
+            MsgForSystem::PacketIgnored(index, reason) => {
+                if let Some(data) = self.peers.get_mut(&index) {
+                    data.rejections.record(reason);
                 }
             }
-            MsgForSystem::NetworkIssue(index) => {
+            MsgForSystem::Nonconforming(index) => {
+                if let Some(data) = self.peers.get_mut(&index) {
+                    data.nonconforming += 1;
+                }
+            }
+            MsgForSystem::Unreachable(index, error) => {
+                if let Some(data) = self.peers.get_mut(&index) {
+                    data.icmp_error = Some(error);
+                }
+            }
+            MsgForSystem::Connected(index, local_addr) => {
+                if let Some(data) = self.peers.get_mut(&index) {
+                    data.local_addr = Some(local_addr);
+                }
+            }
+            MsgForSystem::NetworkIssue(index) | MsgForSystem::Unresponsive(index) => {
                 // Restart the peer reusing its configuration.
-                let config = self.peers.remove(&index).unwrap().config;
-                self.add_peer_internal(config).await;
+                let data = self.peers.remove(&index).unwrap();
+                self.channels.state.forget_peer(data.addr);
+                match data.sibling {
+                    // the other address of a dual-stack peer is still running,
+                    // so only this address is restarted
+                    Some(sibling) => {
+                        let new_index = self.indexer.get();
+                        if let Some(other) = self.peers.get_mut(&sibling) {
+                            other.sibling = Some(new_index);
+                        }
+                        self.spawn_peer(
+                            new_index,
+                            data.addr,
+                            data.config,
+                            Some(sibling),
+                            data.standby,
+                        );
+                    }
+                    None => {
+                        self.add_peer_internal(data.config).await;
+                    }
+                }
             }
         }
+
+        self.publish_associations();
+    }
+
+    /// Switch to the other address of a dual-stack peer when it performs
+    /// clearly better than the one in use
+    fn choose_address(&mut self, index: PeerIndex) {
+        let (active, standby) = match self.peers.get(&index) {
+            Some(PeerData {
+                sibling: Some(sibling),
+                standby,
+                ..
+            }) if *standby => (*sibling, index),
+            Some(PeerData {
+                sibling: Some(sibling),
+                ..
+            }) => (index, *sibling),
+            _ => return,
+        };
+
+        let stats = |index| match self.peers.get(&index).map(|data| data.status) {
+            Some(PeerStatus::Measurement(snapshot)) => Some(AddressStats::from(&snapshot)),
+            _ => None,
+        };
+        if !dual_stack::should_switch(stats(active), stats(standby)) {
+            return;
+        }
+
+        if let Some(data) = self.peers.get_mut(&active) {
+            data.standby = true;
+            data.selected = false;
+            info!(addr = ?data.addr, "no longer using address of dual-stack peer");
+        }
+        if let Some(data) = self.peers.get_mut(&standby) {
+            data.standby = false;
+            info!(addr = ?data.addr, "using address of dual-stack peer");
+        }
+    }
+
+    async fn log_measurement(&self, index: PeerIndex, snapshot: PeerSnapshot) {
+        let id = index.association_id();
+        if let Some(association) = self.associations().iter().find(|a| a.id == id) {
+            let system = *self.channels.system_snapshots.read().await;
+            self.channels.statistics.peer_measurement(
+                association.address,
+                association.status(&system),
+                snapshot.statistics,
+            );
+        }
     }
 
     pub fn reset_all(&mut self) {
         for (_, data) in self.peers.iter_mut() {
             data.status = PeerStatus::NoMeasurement;
         }
+        for (_, data) in self.refclocks.iter_mut() {
+            data.status = PeerStatus::NoMeasurement;
+        }
+
+        self.publish_associations();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use ntp_proto::{
-        peer_snapshot, NtpDuration, NtpInstant, NtpLeapIndicator, NtpTimestamp, PeerStatistics,
-        PollInterval,
+        peer_snapshot, IgnoreReason, NtpDuration, NtpInstant, NtpLeapIndicator, NtpTimestamp,
+        PeerStatistics, PollInterval,
     };
 
-    use crate::config::{NormalizedAddress, StandardPeerConfig};
+    use crate::{
+        config::{NormalizedAddress, StandardPeerConfig},
+        rejection::RejectionReason,
+    };
 
     use super::*;
 
@@ -292,6 +905,16 @@ mod tests {
                 .map(|i| {
                     PeerConfig::Standard(StandardPeerConfig {
                         addr: NormalizedAddress::new_unchecked(&format!("127.0.0.{i}:123")),
+                        bind: Default::default(),
+                        max_offset: None,
+                        poll: Default::default(),
+                        delay: Default::default(),
+                        version: Default::default(),
+                        parsing: Default::default(),
+                        association: Default::default(),
+                        dual_stack: false,
+                        key: None,
+                        source_group: None,
                     })
                 })
                 .collect::<Vec<_>>(),
@@ -395,4 +1018,284 @@ mod tests {
         peers.reset_all();
         assert_eq!(peers.valid_snapshots().count(), 0);
     }
+
+    #[tokio::test]
+    async fn test_rejections() {
+        let statistics = PeerStatistics {
+            delay: NtpDuration::from_seconds(0.1),
+            offset: NtpDuration::from_seconds(0.),
+            dispersion: NtpDuration::from_seconds(0.05),
+            jitter: 0.05,
+        };
+        let mut snapshot = peer_snapshot(
+            statistics,
+            NtpInstant::now(),
+            NtpDuration::from_seconds(0.1),
+            NtpDuration::from_seconds(0.05),
+        );
+        snapshot.peer_id = ReferenceId::from_ip("127.0.0.1".parse().unwrap());
+
+        let mut peers = Peers::from_statuslist(
+            &[PeerStatus::Measurement(snapshot)],
+            &[PeerConfig::Standard(StandardPeerConfig {
+                addr: NormalizedAddress::new_unchecked("127.0.0.1:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
+            })],
+            TestClock {},
+        );
+
+        let rejected = [(snapshot.peer_id, AcceptSynchronizationError::Unsynchronized)];
+        let insufficient = SelectionError::InsufficientConsensus {
+            agreeing: 0,
+            required: 3,
+        };
+        peers.record_selection(Err(insufficient), &rejected);
+        peers.record_selection(Err(insufficient), &rejected);
+        assert_eq!(
+            peers.selection(),
+            SelectionStatus::InsufficientConsensus {
+                agreeing: 0,
+                required: 3
+            }
+        );
+
+        peers.record_selection(Ok(&[snapshot.peer_id]), &[]);
+        assert_eq!(
+            peers.selection(),
+            SelectionStatus::Synchronizing { survivors: 1 }
+        );
+        assert!(peers.associations()[0].selected);
+
+        // measured sources are never selected
+        peers.record_measurement(&[snapshot.peer_id], &[]);
+        assert_eq!(peers.selection(), SelectionStatus::Measuring);
+        assert!(!peers.associations()[0].selected);
+
+        peers
+            .update(
+                MsgForSystem::PacketIgnored(
+                    PeerIndex { index: 0 },
+                    IgnoreReason::NoOutstandingRequest,
+                ),
+                ResetEpoch::default(),
+            )
+            .await;
+
+        let rejections = match peers.observe_peers().next() {
+            Some(ObservablePeerState::Observable { rejections, .. }) => rejections,
+            _ => panic!("expected an observable peer"),
+        };
+        assert_eq!(rejections.unsynchronized, 2);
+        assert_eq!(rejections.duplicate, 1);
+        assert_eq!(rejections.total(), 3);
+        assert_eq!(rejections.last_reason, Some(RejectionReason::Duplicate));
+
+        // nonconforming packets are counted whether they were used or not
+        let nonconforming = MsgForSystem::Nonconforming(PeerIndex { index: 0 });
+        peers.update(nonconforming, ResetEpoch::default()).await;
+        assert!(matches!(
+            peers.observe_peers().next(),
+            Some(ObservablePeerState::Observable {
+                nonconforming: 1,
+                ..
+            })
+        ));
+
+        // smearing is only a rejection when the peer is left out
+        let smearing = [(snapshot.peer_id, SmearEvidence::Drift)];
+        peers.record_smearing(&smearing, false);
+        peers.record_smearing(&smearing, true);
+        match peers.observe_peers().next() {
+            Some(ObservablePeerState::Observable {
+                rejections,
+                smearing,
+                ..
+            }) => {
+                assert_eq!(rejections.smearing, 1);
+                assert_eq!(smearing, Some(SmearEvidence::Drift));
+            }
+            _ => panic!("expected an observable peer"),
+        }
+
+        peers.record_smearing(&[], false);
+        assert!(matches!(
+            peers.observe_peers().next(),
+            Some(ObservablePeerState::Observable { smearing: None, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable() {
+        let statistics = PeerStatistics {
+            delay: NtpDuration::from_seconds(0.1),
+            offset: NtpDuration::from_seconds(0.),
+            dispersion: NtpDuration::from_seconds(0.05),
+            jitter: 0.05,
+        };
+        let reachable = peer_snapshot(
+            statistics,
+            NtpInstant::now(),
+            NtpDuration::from_seconds(0.1),
+            NtpDuration::from_seconds(0.05),
+        );
+        let unreachable = PeerSnapshot {
+            reach: Default::default(),
+            ..reachable
+        };
+
+        let mut peers = Peers::from_statuslist(
+            &[PeerStatus::Measurement(reachable)],
+            &[PeerConfig::Standard(StandardPeerConfig {
+                addr: NormalizedAddress::new_unchecked("127.0.0.1:123"),
+                bind: Default::default(),
+                max_offset: None,
+                poll: Default::default(),
+                delay: Default::default(),
+                version: Default::default(),
+                parsing: Default::default(),
+                association: Default::default(),
+                dual_stack: false,
+                key: None,
+                source_group: None,
+            })],
+            TestClock {},
+        );
+        let index = PeerIndex { index: 0 };
+
+        let observe = |peers: &Peers<TestClock>| match peers.observe_peers().next() {
+            Some(ObservablePeerState::Observable { unreachable, .. }) => unreachable,
+            _ => panic!("expected an observable peer"),
+        };
+        assert_eq!(observe(&peers), None);
+
+        let epoch = ResetEpoch::default();
+        let msg = MsgForSystem::UpdatedSnapshot(index, epoch, unreachable);
+        peers.update(msg, epoch).await;
+        assert_eq!(observe(&peers), Some(Unreachable::Timeout));
+
+        let msg = MsgForSystem::Unreachable(index, IcmpError::PortUnreachable);
+        peers.update(msg, epoch).await;
+        assert_eq!(observe(&peers), Some(Unreachable::NotListening));
+
+        // the error is forgotten once the peer answers
+        let msg = MsgForSystem::NewMeasurement(index, epoch, reachable);
+        peers.update(msg, epoch).await;
+        assert_eq!(observe(&peers), None);
+
+        let msg = MsgForSystem::UpdatedSnapshot(index, epoch, unreachable);
+        peers.update(msg, epoch).await;
+        assert_eq!(observe(&peers), Some(Unreachable::Timeout));
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack() {
+        let statistics = PeerStatistics {
+            delay: NtpDuration::from_seconds(0.1),
+            offset: NtpDuration::from_seconds(0.),
+            dispersion: NtpDuration::from_seconds(0.05),
+            jitter: 0.05,
+        };
+        let reachable = PeerSnapshot {
+            reach: serde_json::from_value(0xff.into()).unwrap(),
+            ..peer_snapshot(
+                statistics,
+                NtpInstant::now(),
+                NtpDuration::from_seconds(0.1),
+                NtpDuration::from_seconds(0.05),
+            )
+        };
+        let unreachable = PeerSnapshot {
+            reach: Default::default(),
+            ..reachable
+        };
+
+        let config = PeerConfig::Standard(StandardPeerConfig {
+            addr: NormalizedAddress::new_unchecked("example.com:123"),
+            bind: Default::default(),
+            max_offset: None,
+            poll: Default::default(),
+            delay: Default::default(),
+            version: Default::default(),
+            parsing: Default::default(),
+            association: Default::default(),
+            dual_stack: true,
+            key: None,
+            source_group: None,
+        });
+        let mut peers = Peers::from_statuslist(
+            &[
+                PeerStatus::Measurement(reachable),
+                PeerStatus::Measurement(reachable),
+            ],
+            &[config.clone(), config],
+            TestClock {},
+        );
+        let active = PeerIndex { index: 0 };
+        let standby = PeerIndex { index: 1 };
+        peers.peers.get_mut(&active).unwrap().sibling = Some(standby);
+        let data = peers.peers.get_mut(&standby).unwrap();
+        data.sibling = Some(active);
+        data.standby = true;
+
+        let standby_flags = |peers: &Peers<TestClock>| {
+            let flag = |index| peers.peers[&index].standby;
+            (flag(active), flag(standby))
+        };
+
+        // the peer is a single source
+        assert_eq!(peers.valid_snapshots().count(), 1);
+
+        let epoch = ResetEpoch::default();
+        let msg = MsgForSystem::UpdatedSnapshot(standby, epoch, reachable);
+        peers.update(msg, epoch).await;
+        assert_eq!(standby_flags(&peers), (false, true));
+
+        // failover once the active address stops answering
+        let msg = MsgForSystem::UpdatedSnapshot(active, epoch, unreachable);
+        peers.update(msg, epoch).await;
+        assert_eq!(standby_flags(&peers), (true, false));
+        assert_eq!(peers.valid_snapshots().count(), 1);
+        assert!(peers
+            .observe_peers()
+            .any(|state| matches!(state, ObservablePeerState::Observable { standby: true, .. })));
+
+        // what remains of a peer is used, whichever address it has
+        let msg = MsgForSystem::MustDemobilize(standby);
+        peers.update(msg, epoch).await;
+        assert!(!peers.peers[&active].standby);
+        assert_eq!(peers.peers[&active].sibling, None);
+    }
+
+    #[tokio::test]
+    async fn test_pool_servers() {
+        let mut peers = Peers::new(PeerChannels::test(), TestClock {}, Default::default());
+        let config = Arc::new(PeerConfig::Pool(PoolPeerConfig {
+            addr: NormalizedAddress::new_unchecked("127.0.0.1:9123"),
+            max_peers: 3,
+            groups: Default::default(),
+            bind: Default::default(),
+            max_offset: None,
+            poll: Default::default(),
+            delay: Default::default(),
+            version: Default::default(),
+            parsing: Default::default(),
+            source_group: None,
+        }));
+
+        peers.add_peer_internal(config.clone()).await;
+        assert_eq!(peers.peers.len(), 1);
+
+        // the pool has no other servers, in another subnet or otherwise
+        peers.add_peer_internal(config).await;
+        assert_eq!(peers.peers.len(), 1);
+    }
 }
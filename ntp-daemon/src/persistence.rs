@@ -0,0 +1,342 @@
+//! State that is kept across restarts of the daemon.
+//!
+//! The frequency correction of the clock, and the clock filter, reachability
+//! and poll intervals of the peers are written to a state file at a regular
+//! interval. At startup, they are restored from it, such that the daemon
+//! continues where it left off instead of measuring the frequency and filling
+//! the clock filters all over again.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use ntp_proto::{NtpClock, NtpInstant, NtpTimestamp, PeerState};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::config::StateConfig;
+
+/// How often the state is written to the state file
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The state of the peers is not restored after a longer downtime, as their
+/// measurements and reachability say little about the present by then
+const MAX_PEER_STATE_AGE: Duration = Duration::from_secs(3600);
+
+/// Largest frequency correction that is restored, in seconds per second. This
+/// is the limit of the frequency correction of the kernel.
+const MAX_FREQUENCY: f64 = 500e-6;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedPeer {
+    address: SocketAddr,
+    #[serde(flatten)]
+    state: PeerState,
+}
+
+/// Contents of the state file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    /// Time at which the state was saved, by the system clock
+    time: NtpTimestamp,
+    /// Frequency correction of the clock, in seconds per second
+    frequency: Option<f64>,
+    peers: Vec<SavedPeer>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// State of the peers of the previous run that has not been handed to a
+    /// peer of this run yet
+    restored: HashMap<SocketAddr, PeerState>,
+    /// Time between saving the restored state and loading it
+    downtime: Duration,
+    loaded_at: Option<NtpInstant>,
+    restored_frequency: Option<f64>,
+
+    peers: HashMap<SocketAddr, PeerState>,
+    frequency: Option<f64>,
+}
+
+/// Handle to the state that is kept across restarts. Cheap to clone, and
+/// does nothing when there is no state file in the configuration.
+#[derive(Debug, Clone, Default)]
+pub struct PersistentState {
+    path: Option<PathBuf>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl PersistentState {
+    /// Read the state that the previous run left in the state file
+    pub fn load<C: NtpClock>(config: &StateConfig, clock: &C) -> Self {
+        let path = match &config.path {
+            Some(path) => path.clone(),
+            None => return Self::default(),
+        };
+
+        let state = PersistentState {
+            path: Some(path.clone()),
+            inner: Default::default(),
+        };
+
+        let file = match read(&path) {
+            Ok(Some(file)) => file,
+            Ok(None) => {
+                debug!(?path, "no state file, starting afresh");
+                return state;
+            }
+            Err(error) => {
+                warn!(?error, ?path, "ignoring unreadable state file");
+                return state;
+            }
+        };
+
+        let now = clock.now().unwrap_or_default();
+        state.lock().restore(file, now, NtpInstant::now());
+        state
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // the state is replaced as a whole, a panic halfway does not corrupt it
+        match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// The state that the previous run had for the peer at `address`, and the
+    /// time since it was saved. The state is only handed out once.
+    pub(crate) fn take_peer(&self, address: SocketAddr) -> Option<(PeerState, Duration)> {
+        let mut inner = self.lock();
+        let state = inner.restored.remove(&address)?;
+        let since_load = inner
+            .loaded_at
+            .map(|loaded_at| loaded_at.elapsed())
+            .unwrap_or_default();
+
+        Some((state, inner.downtime + since_load))
+    }
+
+    pub(crate) fn update_peer(&self, address: SocketAddr, state: PeerState) {
+        if self.path.is_some() {
+            self.lock().peers.insert(address, state);
+        }
+    }
+
+    /// Stop keeping the state of a peer that was removed
+    pub(crate) fn forget_peer(&self, address: SocketAddr) {
+        self.lock().peers.remove(&address);
+    }
+
+    /// The frequency correction of the previous run, only handed out once
+    pub(crate) fn take_frequency(&self) -> Option<f64> {
+        self.lock().restored_frequency.take()
+    }
+
+    pub(crate) fn update_frequency(&self, frequency: f64) {
+        self.lock().frequency = Some(frequency);
+    }
+
+    /// Write the current state to the state file
+    pub fn save<C: NtpClock>(&self, clock: &C) -> std::io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let file = self.lock().file(clock.now().unwrap_or_default());
+        write(path, &file)
+    }
+}
+
+impl Inner {
+    fn restore(&mut self, file: StateFile, now: NtpTimestamp, loaded_at: NtpInstant) {
+        self.restored_frequency = file
+            .frequency
+            .filter(|frequency| frequency.is_finite() && frequency.abs() <= MAX_FREQUENCY);
+        // until a new frequency is known, the restored one is the best there is
+        self.frequency = self.restored_frequency;
+
+        // the state of the peers is only of use when the clock was set in
+        // between, and not too long ago
+        let downtime = match Duration::try_from(now - file.time) {
+            Ok(downtime) if downtime <= MAX_PEER_STATE_AGE => downtime,
+            _ => {
+                info!("not restoring the state of the peers, as it is outdated");
+                return;
+            }
+        };
+
+        self.downtime = downtime;
+        self.loaded_at = Some(loaded_at);
+        self.restored = file
+            .peers
+            .into_iter()
+            .map(|peer| (peer.address, peer.state))
+            .collect();
+
+        info!(
+            peers = self.restored.len(),
+            frequency = ?self.restored_frequency,
+            "restored state of the previous run"
+        );
+    }
+
+    fn file(&self, now: NtpTimestamp) -> StateFile {
+        StateFile {
+            time: now,
+            frequency: self.frequency,
+            peers: self
+                .peers
+                .iter()
+                .map(|(address, state)| SavedPeer {
+                    address: *address,
+                    state: state.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn read(path: &Path) -> std::io::Result<Option<StateFile>> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+
+    Ok(Some(serde_json::from_slice(&data)?))
+}
+
+/// Write the file next to the state file first, such that the state file is
+/// replaced as a whole, even when the daemon stops halfway
+fn write(path: &Path, file: &StateFile) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+
+    std::fs::write(&temporary, serde_json::to_vec(file)?)?;
+    std::fs::rename(&temporary, path)
+}
+
+/// Save the state at a regular interval
+pub fn spawn<C: NtpClock + Send + 'static>(state: PersistentState, clock: C) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if state.path.is_none() {
+            return;
+        }
+
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        // the first tick completes right away, and there is nothing new yet
+        interval.tick().await;
+        // only report the first of a series of failures
+        let mut failing = false;
+
+        loop {
+            interval.tick().await;
+
+            match state.save(&clock) {
+                Ok(()) => failing = false,
+                Err(error) => {
+                    if !failing {
+                        warn!(?error, path = ?state.path, "could not write state file");
+                    }
+                    failing = true;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::{NtpDuration, PollInterval, Reach};
+
+    use super::*;
+
+    fn peer_state() -> PeerState {
+        PeerState {
+            measurements: vec![],
+            reach: Reach::default(),
+            backoff_interval: PollInterval::default(),
+            remote_min_poll_interval: PollInterval::default(),
+        }
+    }
+
+    #[test]
+    fn test_restore() {
+        let saved_at = NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0);
+        let address: SocketAddr = "192.0.2.1:123".parse().unwrap();
+        let file = || StateFile {
+            time: saved_at,
+            frequency: Some(12e-6),
+            peers: vec![SavedPeer {
+                address,
+                state: peer_state(),
+            }],
+        };
+
+        let state = PersistentState::default();
+        let later = saved_at + NtpDuration::from_seconds(30.0);
+        state.lock().restore(file(), later, NtpInstant::now());
+
+        assert_eq!(state.take_frequency(), Some(12e-6));
+        assert_eq!(state.take_frequency(), None);
+
+        let (peer, downtime) = state.take_peer(address).unwrap();
+        assert_eq!(peer, peer_state());
+        assert!(downtime >= Duration::from_secs(30));
+        assert!(state.take_peer(address).is_none());
+
+        // a peer state from long ago, or from the future, is of no use
+        for now in [
+            saved_at + NtpDuration::from_seconds(7200.0),
+            saved_at - NtpDuration::from_seconds(30.0),
+        ] {
+            let state = PersistentState::default();
+            state.lock().restore(file(), now, NtpInstant::now());
+            assert_eq!(state.take_frequency(), Some(12e-6));
+            assert!(state.take_peer(address).is_none());
+        }
+
+        let state = PersistentState::default();
+        let file = StateFile {
+            frequency: Some(0.01),
+            ..file()
+        };
+        state.lock().restore(file, later, NtpInstant::now());
+        assert_eq!(state.take_frequency(), None);
+    }
+
+    #[test]
+    fn test_state_file() {
+        let path = std::env::temp_dir().join("ntpd-rs-test-state.json");
+        std::fs::remove_file(&path).ok();
+
+        let address: SocketAddr = "192.0.2.1:123".parse().unwrap();
+        let state = PersistentState {
+            path: Some(path.clone()),
+            inner: Default::default(),
+        };
+        state.update_frequency(-3e-6);
+        state.update_peer(address, peer_state());
+        state.update_peer("192.0.2.2:123".parse().unwrap(), peer_state());
+        state.forget_peer("192.0.2.2:123".parse().unwrap());
+
+        let file = state.lock().file(NtpTimestamp::default());
+        write(&path, &file).unwrap();
+
+        let saved = read(&path).unwrap().unwrap();
+        assert_eq!(saved.frequency, Some(-3e-6));
+        assert_eq!(saved.peers.len(), 1);
+        assert_eq!(saved.peers[0].address, address);
+        assert_eq!(saved.peers[0].state, peer_state());
+
+        std::fs::remove_file(&path).ok();
+        assert!(matches!(read(&path), Ok(None)));
+    }
+}
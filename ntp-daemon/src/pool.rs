@@ -0,0 +1,129 @@
+//! Choice of the servers of a pool.
+//!
+//! The addresses a pool resolves to may belong to only a few operators, or
+//! may all be reached through a single route that can be hijacked. To keep a
+//! single operator or network from supplying most of our sources, no two
+//! servers of a pool are taken from the same /24 (IPv4) or /48 (IPv6) subnet.
+//! Subnets that are known to belong together, such as those of a country or
+//! of an anycast cluster, can be configured as a group, of which at most one
+//! server is used as well.
+
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+};
+
+use crate::config::subnet::IpSubnet;
+
+/// Length of the prefix of the IPv4 subnets from which a single server is used
+const IPV4_PREFIX: u8 = 24;
+
+/// Length of the prefix of the IPv6 subnets from which a single server is used
+const IPV6_PREFIX: u8 = 48;
+
+fn same_subnet(a: IpAddr, b: IpAddr) -> bool {
+    let mask = match a {
+        IpAddr::V4(_) => IPV4_PREFIX,
+        IpAddr::V6(_) => IPV6_PREFIX,
+    };
+    IpSubnet { addr: a, mask }.contains(&b)
+}
+
+fn group_of(groups: &BTreeMap<String, Vec<IpSubnet>>, addr: IpAddr) -> Option<&str> {
+    groups
+        .iter()
+        .find(|(_, subnets)| subnets.iter().any(|subnet| subnet.contains(&addr)))
+        .map(|(name, _)| name.as_str())
+}
+
+/// Pick up to `count` of the candidate addresses, in order, that are neither
+/// in the same subnet nor in the same group as each other or as one of the
+/// servers of the pool that are already in use
+pub(crate) fn select_servers(
+    candidates: &[SocketAddr],
+    in_use: &[SocketAddr],
+    groups: &BTreeMap<String, Vec<IpSubnet>>,
+    count: usize,
+) -> Vec<SocketAddr> {
+    let conflicts = |a: &SocketAddr, b: &SocketAddr| {
+        let group = group_of(groups, a.ip());
+        same_subnet(a.ip(), b.ip()) || (group.is_some() && group == group_of(groups, b.ip()))
+    };
+
+    let mut selected: Vec<SocketAddr> = Vec::with_capacity(count);
+    for candidate in candidates {
+        if selected.len() == count {
+            break;
+        }
+
+        if !in_use
+            .iter()
+            .chain(&selected)
+            .any(|other| conflicts(candidate, other))
+        {
+            selected.push(*candidate);
+        }
+    }
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_subnet_diversity() {
+        let candidates = addrs(&[
+            "192.0.2.1:123",
+            "192.0.2.200:123",
+            "198.51.100.1:123",
+            "[2001:db8:1:1::1]:123",
+            "[2001:db8:1:2::1]:123",
+            "[2001:db8:2::1]:123",
+        ]);
+        let none = BTreeMap::new();
+
+        assert_eq!(
+            select_servers(&candidates, &[], &none, 8),
+            addrs(&[
+                "192.0.2.1:123",
+                "198.51.100.1:123",
+                "[2001:db8:1:1::1]:123",
+                "[2001:db8:2::1]:123",
+            ])
+        );
+        assert_eq!(
+            select_servers(&candidates, &[], &none, 2),
+            addrs(&["192.0.2.1:123", "198.51.100.1:123"])
+        );
+
+        // servers of the pool that are in use count as well
+        assert_eq!(
+            select_servers(&candidates, &addrs(&["198.51.100.7:123"]), &none, 2),
+            addrs(&["192.0.2.1:123", "[2001:db8:1:1::1]:123"])
+        );
+    }
+
+    #[test]
+    fn test_group_diversity() {
+        let candidates = addrs(&["192.0.2.1:123", "198.51.100.1:123", "203.0.113.1:123"]);
+        let mut groups = BTreeMap::new();
+        groups.insert(
+            "anycast".to_string(),
+            vec![
+                "192.0.2.0/24".parse().unwrap(),
+                "198.51.100.0/24".parse().unwrap(),
+            ],
+        );
+
+        assert_eq!(
+            select_servers(&candidates, &[], &groups, 8),
+            addrs(&["192.0.2.1:123", "203.0.113.1:123"])
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Running with as few privileges as possible.
+//!
+//! When a user is configured, the daemon changes to that user at startup and
+//! keeps only the capabilities needed by its configuration. Independently of
+//! that, it reports at startup which configured features lack the privileges
+//! they need, rather than failing later on with an unclear error.
+
+use ntp_os_clock::{has_capability, Capability, PrivilegeError};
+use tracing::{debug, info, warn};
+
+use crate::config::{ClockBackend, Config, RefClockConfig};
+
+/// A feature of the daemon, and the capability it needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Requirement {
+    capability: Capability,
+    feature: &'static str,
+    /// Whether the feature is used by the configuration
+    used: bool,
+}
+
+fn requirements(config: &Config) -> [Requirement; 5] {
+    [
+        Requirement {
+            capability: Capability::SysTime,
+            feature: "stepping and steering the system clock, and maintaining its TAI offset",
+            used: config.clock.backend == ClockBackend::System
+                && (!config.peers.is_empty()
+                    || !config.refclocks.is_empty()
+                    || config.clock.maintain_tai),
+        },
+        Requirement {
+            capability: Capability::NetBindService,
+            feature: "servers on port 123 or other ports below 1024, and PTP clients",
+            used: config.servers.iter().any(|s| s.addr.port() < 1024)
+                || matches!(config.health.listen, Some(addr) if addr.port() < 1024)
+                || config
+                    .refclocks
+                    .iter()
+                    .any(|r| matches!(r, RefClockConfig::Ptp(_))),
+        },
+        Requirement {
+            capability: Capability::NetAdmin,
+            feature: "hardware timestamping",
+            // not enabled by the daemon yet
+            used: false,
+        },
+        Requirement {
+            capability: Capability::NetAdmin,
+            feature: "packet priorities above 6",
+            used: matches!(config.network.priority, Some(priority) if priority > 6),
+        },
+        Requirement {
+            capability: Capability::SysAdmin,
+            feature: "peers in other network namespaces",
+            used: config
+                .peers
+                .iter()
+                .any(|peer| peer.bind().network_namespace.is_some()),
+        },
+    ]
+}
+
+/// Capabilities needed by the features used in the configuration
+fn required_capabilities(config: &Config) -> Vec<Capability> {
+    let mut capabilities: Vec<_> = requirements(config)
+        .iter()
+        .filter(|r| r.used)
+        .map(|r| r.capability)
+        .collect();
+
+    // controlling the clock is what the daemon is for, so that is always
+    // kept, even when the configuration has no sources yet. The clock of a
+    // time namespace is kept by the daemon itself.
+    if config.clock.backend == ClockBackend::System && !capabilities.contains(&Capability::SysTime)
+    {
+        capabilities.insert(0, Capability::SysTime);
+    }
+
+    capabilities
+}
+
+/// Change to the configured user and group, if any, keeping only the
+/// capabilities that the configuration needs.
+///
+/// This must be called before any threads are started, in particular before
+/// the multi-threaded tokio runtime is created.
+pub fn drop_privileges(config: &Config) -> Result<(), PrivilegeError> {
+    let user = match &config.privileges.user {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+
+    let retain = required_capabilities(config);
+    ntp_os_clock::drop_privileges(user, config.privileges.group.as_deref(), &retain)?;
+
+    info!(
+        user,
+        group = config.privileges.group.as_deref(),
+        capabilities = ?retain.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "dropped privileges"
+    );
+
+    Ok(())
+}
+
+/// Report which of the configured features lack the privileges they need
+pub fn check(config: &Config) {
+    for requirement in requirements(config) {
+        match has_capability(requirement.capability) {
+            Ok(true) => {}
+            Ok(false) if requirement.used => warn!(
+                "Missing {}, which is needed for {}. This will fail.",
+                requirement.capability, requirement.feature
+            ),
+            Ok(false) => debug!(
+                "Missing {}, which would be needed for {}",
+                requirement.capability, requirement.feature
+            ),
+            Err(error) => warn!(
+                ?error,
+                "Could not determine whether {} is available", requirement.capability
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ServerConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_required_capabilities() {
+        let mut config = Config::default();
+        assert_eq!(required_capabilities(&config), vec![Capability::SysTime]);
+
+        config.servers = vec![ServerConfig::try_from("0.0.0.0:4123").unwrap()];
+        assert_eq!(required_capabilities(&config), vec![Capability::SysTime]);
+
+        config
+            .servers
+            .push(ServerConfig::try_from("[::]:123").unwrap());
+        assert_eq!(
+            required_capabilities(&config),
+            vec![Capability::SysTime, Capability::NetBindService]
+        );
+
+        config.network.priority = Some(7);
+        assert_eq!(
+            required_capabilities(&config),
+            vec![
+                Capability::SysTime,
+                Capability::NetBindService,
+                Capability::NetAdmin
+            ]
+        );
+
+        config.clock.backend = ClockBackend::Namespace;
+        config.clock.maintain_tai = true;
+        assert_eq!(
+            required_capabilities(&config),
+            vec![Capability::NetBindService, Capability::NetAdmin]
+        );
+
+        config.peers =
+            vec![toml::from_str("addr = \"192.0.2.10\"\nnetwork-namespace = \"mgmt\"").unwrap()];
+        assert_eq!(
+            required_capabilities(&config),
+            vec![
+                Capability::NetBindService,
+                Capability::NetAdmin,
+                Capability::SysAdmin
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_user() {
+        // without a configured user, nothing changes
+        assert!(drop_privileges(&Config::default()).is_ok());
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+
+use ntp_proto::PeerSnapshot;
+
+/// Number of delay measurements used to judge the stability of the delay
+const DELAY_HISTORY: usize = 8;
+
+/// Weight of the most recent clock selection round in the selection ratio
+const SELECTION_WEIGHT: f64 = 1.0 / 8.0;
+
+// Contribution of each of the components to the total score of 100
+const REACH_POINTS: f64 = 30.0;
+const JITTER_POINTS: f64 = 25.0;
+const DELAY_STABILITY_POINTS: f64 = 20.0;
+const SELECTION_POINTS: f64 = 25.0;
+
+/// Keeps the history needed to summarize the health of a single time source
+/// into one "source quality" score, from 0 (useless) to 100 (excellent).
+///
+/// The score is meant for quick triage by operators, it plays no role in
+/// the selection of sources. It combines
+///  - reachability: how many of the last 8 polls were answered
+///  - jitter: the variation in measured offsets
+///  - delay stability: the variation in measured round-trip delays
+///  - selection: how often the source recently survived clock selection
+///
+/// Authentication is not part of the score, as none of the sources
+/// currently supported are authenticated.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct QualityTracker {
+    delays: VecDeque<f64>,
+    selection_ratio: f64,
+}
+
+impl QualityTracker {
+    pub(crate) fn record_measurement(&mut self, snapshot: &PeerSnapshot) {
+        if self.delays.len() == DELAY_HISTORY {
+            self.delays.pop_front();
+        }
+        self.delays
+            .push_back(snapshot.statistics.delay.to_seconds());
+    }
+
+    pub(crate) fn record_selection(&mut self, survived: bool) {
+        let value = if survived { 1.0 } else { 0.0 };
+        self.selection_ratio += SELECTION_WEIGHT * (value - self.selection_ratio);
+    }
+
+    /// Standard deviation of the recent delays, if enough are known
+    fn delay_deviation(&self) -> Option<f64> {
+        if self.delays.len() < 2 {
+            return None;
+        }
+
+        let n = self.delays.len() as f64;
+        let mean = self.delays.iter().sum::<f64>() / n;
+        let variance = self.delays.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / (n - 1.0);
+
+        Some(variance.sqrt())
+    }
+
+    pub(crate) fn score(&self, snapshot: &PeerSnapshot) -> u8 {
+        let reach = snapshot.reach.answered_polls() as f64 / 8.0;
+        let jitter = log_scale(snapshot.statistics.jitter, 1e-3, 1e-1);
+        let delay_stability = self
+            .delay_deviation()
+            .map(|deviation| log_scale(deviation, 1e-4, 1e-2))
+            .unwrap_or(0.0);
+
+        let score = REACH_POINTS * reach
+            + JITTER_POINTS * jitter
+            + DELAY_STABILITY_POINTS * delay_stability
+            + SELECTION_POINTS * self.selection_ratio;
+
+        score.round().clamp(0.0, 100.0) as u8
+    }
+}
+
+/// Map a value onto [0, 1] on a logarithmic scale, where values at or below
+/// `good` score 1 and values at or above `bad` score 0.
+fn log_scale(value: f64, good: f64, bad: f64) -> f64 {
+    if value <= good {
+        1.0
+    } else if value >= bad {
+        0.0
+    } else {
+        (bad.log10() - value.log10()) / (bad.log10() - good.log10())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::{peer_snapshot, NtpDuration, NtpInstant, PeerStatistics};
+
+    use super::*;
+
+    fn snapshot(delay: f64, jitter: f64) -> PeerSnapshot {
+        peer_snapshot(
+            PeerStatistics {
+                delay: NtpDuration::from_seconds(delay),
+                offset: NtpDuration::ZERO,
+                dispersion: NtpDuration::from_seconds(0.001),
+                jitter,
+            },
+            NtpInstant::now(),
+            NtpDuration::ZERO,
+            NtpDuration::ZERO,
+        )
+    }
+
+    #[test]
+    fn test_log_scale() {
+        assert_eq!(log_scale(1e-4, 1e-3, 1e-1), 1.0);
+        assert_eq!(log_scale(1.0, 1e-3, 1e-1), 0.0);
+        assert!((log_scale(1e-2, 1e-3, 1e-1) - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_selection_ratio() {
+        let mut tracker = QualityTracker::default();
+        for _ in 0..100 {
+            tracker.record_selection(true);
+        }
+        assert!(tracker.selection_ratio > 0.99);
+
+        tracker.record_selection(false);
+        assert!(tracker.selection_ratio < 0.9);
+    }
+
+    #[test]
+    fn test_score() {
+        let mut good = QualityTracker::default();
+        let mut bad = QualityTracker::default();
+
+        for i in 0..8 {
+            good.record_measurement(&snapshot(0.010, 0.0005));
+            good.record_selection(true);
+
+            bad.record_measurement(&snapshot(0.010 + 0.05 * (i % 2) as f64, 0.2));
+            bad.record_selection(false);
+        }
+
+        let good_score = good.score(&snapshot(0.010, 0.0005));
+        let bad_score = bad.score(&snapshot(0.060, 0.2));
+
+        assert!(good_score > 50, "{}", good_score);
+        assert!(bad_score < 10, "{}", bad_score);
+        assert!(good_score <= 100);
+    }
+}
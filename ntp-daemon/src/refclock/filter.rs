@@ -0,0 +1,142 @@
+//! Reference clock drivers take many samples per poll interval, which are far
+//! less noisy than network measurements, but of which a few can be far off: a
+//! pulse that was missed or caught twice, or a sentence delayed by a busy
+//! serial port. Instead of the latest sample, the average of the samples
+//! closest to their median is handed to the clock filter.
+
+use ntp_proto::NtpDuration;
+
+use super::RefClockSample;
+
+/// Samples kept per poll interval, beyond which the oldest are dropped
+const MAX_SAMPLES: usize = 64;
+
+/// Fraction of the samples of a poll interval that is used, the others being
+/// furthest from the median
+const USED_FRACTION: f64 = 0.6;
+
+/// The samples of a poll interval, combined into one
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Filtered {
+    pub sample: RefClockSample,
+    /// Number of samples that were furthest from the median, and not used
+    pub outliers: usize,
+    /// Standard deviation of the used samples, in seconds
+    pub spread: f64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SampleFilter {
+    samples: Vec<RefClockSample>,
+}
+
+impl SampleFilter {
+    pub fn push(&mut self, sample: RefClockSample) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.remove(0);
+        }
+        self.samples.push(sample);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Combine the samples taken since the previous call, leaving the filter
+    /// empty. The combined sample has the time of the latest sample.
+    pub fn take(&mut self) -> Option<Filtered> {
+        let time = self.samples.last()?.time;
+
+        self.samples.sort_by_key(|sample| sample.offset);
+        let median = self.samples[self.samples.len() / 2].offset;
+        self.samples
+            .sort_by_key(|sample| (sample.offset - median).abs());
+
+        let used = ((self.samples.len() as f64 * USED_FRACTION).ceil() as usize).max(1);
+        let kept = &self.samples[..used];
+        let offset = kept
+            .iter()
+            .fold(NtpDuration::ZERO, |sum, sample| sum + sample.offset)
+            / used as i64;
+        let variance = kept
+            .iter()
+            .map(|sample| (sample.offset - offset).to_seconds().powi(2))
+            .sum::<f64>()
+            / used as f64;
+
+        let filtered = Filtered {
+            sample: RefClockSample { offset, time },
+            outliers: self.samples.len() - used,
+            spread: variance.sqrt(),
+        };
+        self.samples.clear();
+
+        Some(filtered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::NtpInstant;
+
+    use super::*;
+
+    fn sample(offset: f64, time: NtpInstant) -> RefClockSample {
+        RefClockSample {
+            offset: NtpDuration::from_seconds(offset),
+            time,
+        }
+    }
+
+    #[test]
+    fn test_outliers() {
+        let base = NtpInstant::now();
+        let mut filter = SampleFilter::default();
+        assert!(filter.take().is_none());
+
+        // a missed pulse shows up as a sample that is a second off
+        let offsets = [0.0002, 0.0002, -1.0, 0.0002, 0.0005];
+        for (index, offset) in offsets.into_iter().enumerate() {
+            filter.push(sample(
+                offset,
+                base + std::time::Duration::from_secs(index as u64),
+            ));
+        }
+        assert_eq!(filter.len(), 5);
+
+        let filtered = filter.take().unwrap();
+        assert_eq!(filtered.outliers, 2);
+        assert!((filtered.sample.offset.to_seconds() - 0.0002).abs() < 1e-9);
+        assert_eq!(
+            filtered.sample.time,
+            base + std::time::Duration::from_secs(4)
+        );
+        assert!(filtered.spread < 1e-9);
+        assert_eq!(filter.len(), 0);
+
+        // a single sample is used as is
+        filter.push(sample(0.5, base));
+        let filtered = filter.take().unwrap();
+        assert_eq!(filtered.outliers, 0);
+        assert_eq!(filtered.sample.offset, NtpDuration::from_seconds(0.5));
+    }
+
+    #[test]
+    fn test_max_samples() {
+        let base = NtpInstant::now();
+        let mut filter = SampleFilter::default();
+
+        for index in 0..(2 * MAX_SAMPLES) {
+            filter.push(sample(index as f64, base));
+        }
+        assert_eq!(filter.len(), MAX_SAMPLES);
+
+        // only the most recent samples are left
+        let filtered = filter.take().unwrap();
+        assert!(filtered.sample.offset >= NtpDuration::from_seconds(MAX_SAMPLES as f64));
+    }
+}
@@ -0,0 +1,454 @@
+mod filter;
+mod nmea;
+mod phc;
+mod pps;
+mod ptp;
+
+use std::{
+    marker::PhantomData,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use ntp_proto::{NtpClock, NtpDuration, NtpInstant, RefClock, SystemSnapshot, Update};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc,
+    time::{Instant, Sleep},
+};
+use tracing::{debug, instrument, warn, Instrument, Span};
+
+use self::filter::SampleFilter;
+
+use crate::{
+    config::RefClockConfig,
+    peer::{MsgForSystem, PeerChannels, ResetEpoch, Wait},
+    peer_manager::PeerIndex,
+};
+
+/// How long a driver waits before trying to reopen a device that failed
+const DEVICE_WAIT_PERIOD: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// A single measurement taken by a reference clock driver
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RefClockSample {
+    /// Reference time minus local clock time, including any fudge
+    pub offset: NtpDuration,
+    /// Monotonic time at which the sample was taken
+    pub time: NtpInstant,
+}
+
+/// Whether a reference clock currently provides usable samples
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockState {
+    /// Samples of the last poll interval were used
+    Locked,
+    /// The driver produced no samples during the last poll interval
+    #[default]
+    NoSamples,
+    /// Samples are discarded until another source has set the time
+    WaitingForTimeSource,
+}
+
+/// What became of the samples of a reference clock driver
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RefClockStatistics {
+    pub lock: LockState,
+    /// Samples per second the driver produced during the last poll interval
+    pub sample_rate: f64,
+    /// Standard deviation of the samples used in the last poll interval, in
+    /// seconds
+    pub spread: f64,
+    /// Samples produced by the driver
+    pub samples: u64,
+    /// Samples not used for being furthest from the median of their poll
+    /// interval
+    pub outliers: u64,
+    /// Samples discarded while waiting for another source to set the time
+    pub unsynchronized: u64,
+}
+
+/// Handle to the statistics of a reference clock, which is cheap to clone
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SharedRefClockStatistics(Arc<Mutex<RefClockStatistics>>);
+
+impl SharedRefClockStatistics {
+    pub fn get(&self) -> RefClockStatistics {
+        // setting never panics halfway, so a poisoned lock still holds consistent statistics
+        match self.0.lock() {
+            Ok(guard) => *guard,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+
+    fn set(&self, statistics: RefClockStatistics) {
+        match self.0.lock() {
+            Ok(mut guard) => *guard = statistics,
+            Err(poisoned) => *poisoned.into_inner() = statistics,
+        }
+    }
+}
+
+/// Drives the [`RefClock`] state for a single reference clock.
+///
+/// The driver itself runs on a dedicated thread (device reads are blocking)
+/// and sends its samples to this task. Drivers typically produce samples
+/// much more often than we want to update the clock, so the samples of a poll
+/// interval are combined by a median filter, and handed to the clock filter
+/// once every poll interval.
+pub(crate) struct RefClockTask<T: Wait> {
+    _wait: PhantomData<T>,
+    index: PeerIndex,
+    channels: PeerChannels,
+
+    refclock: RefClock,
+    samples: mpsc::Receiver<RefClockSample>,
+    filter: SampleFilter,
+    /// Samples are only valid once the local clock was set by another source
+    needs_time_source: bool,
+
+    statistics: RefClockStatistics,
+    shared_statistics: SharedRefClockStatistics,
+    /// Samples produced by the driver during the current poll interval
+    interval_samples: u64,
+
+    /// Instant of the last poll (used for timing the wait)
+    last_poll: Instant,
+
+    /// Number of resets that this reference clock has performed
+    reset_epoch: ResetEpoch,
+}
+
+impl<T: Wait> RefClockTask<T> {
+    fn update_poll_wait(&self, poll_wait: &mut Pin<&mut T>, system_snapshot: SystemSnapshot) {
+        let poll_interval = self
+            .refclock
+            .current_poll_interval(system_snapshot)
+            .as_system_duration();
+
+        poll_wait.as_mut().reset(self.last_poll + poll_interval);
+    }
+
+    async fn handle_poll(&mut self, poll_wait: &mut Pin<&mut T>) {
+        let system_snapshot = *self.channels.system_snapshots.read().await;
+        let system_config = *self.channels.system_config.read().await;
+
+        let interval = self.last_poll.elapsed().as_secs_f64();
+        self.refclock.poll(system_snapshot);
+        self.last_poll = Instant::now();
+        self.update_poll_wait(poll_wait, system_snapshot);
+
+        self.statistics.sample_rate = match interval {
+            interval if interval > 0.0 => self.interval_samples as f64 / interval,
+            _ => 0.0,
+        };
+        self.interval_samples = 0;
+
+        let waiting = self.needs_time_source && !system_snapshot.leap_indicator.is_synchronized();
+        let filtered = if waiting {
+            if self.filter.len() > 0 {
+                debug!("discarding samples, waiting for another source to set the time");
+            }
+            self.statistics.unsynchronized += self.filter.len() as u64;
+            self.filter.clear();
+            None
+        } else {
+            self.filter.take()
+        };
+
+        let msg = match filtered {
+            Some(filtered) => {
+                let sample = filtered.sample;
+                self.statistics.lock = LockState::Locked;
+                self.statistics.outliers += filtered.outliers as u64;
+                self.statistics.spread = filtered.spread;
+                match self.refclock.handle_sample(
+                    system_snapshot,
+                    &system_config,
+                    sample.offset,
+                    sample.time,
+                ) {
+                    Update::BareUpdate(snapshot) => {
+                        MsgForSystem::UpdatedSnapshot(self.index, self.reset_epoch, snapshot)
+                    }
+                    Update::NewMeasurement(snapshot) => {
+                        MsgForSystem::NewMeasurement(self.index, self.reset_epoch, snapshot)
+                    }
+                }
+            }
+            None => {
+                self.statistics.lock = if waiting {
+                    LockState::WaitingForTimeSource
+                } else {
+                    debug!("no sample from reference clock during last poll interval");
+                    LockState::NoSamples
+                };
+                MsgForSystem::UpdatedSnapshot(
+                    self.index,
+                    self.reset_epoch,
+                    self.refclock.snapshot(),
+                )
+            }
+        };
+
+        self.shared_statistics.set(self.statistics);
+        self.channels.msg_for_system_sender.send(msg).await.ok();
+    }
+
+    async fn run(&mut self, mut poll_wait: Pin<&mut T>) {
+        loop {
+            tokio::select! {
+                () = &mut poll_wait => {
+                    self.handle_poll(&mut poll_wait).await;
+                },
+                result = (self.channels.reset.changed()), if self.channels.reset.has_changed().is_ok() => {
+                    if let Ok(()) = result {
+                        // samples taken before the reset are relative to the old clock
+                        self.refclock.reset_measurements();
+                        self.filter.clear();
+
+                        self.reset_epoch = *self.channels.reset.borrow_and_update();
+                    }
+                }
+                sample = self.samples.recv() => {
+                    match sample {
+                        Some(sample) => {
+                            self.statistics.samples += 1;
+                            self.interval_samples += 1;
+                            self.filter.push(sample);
+                        }
+                        None => {
+                            warn!("reference clock driver stopped");
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl RefClockTask<Sleep> {
+    #[instrument(name = "refclock", skip(config, clock, channels, shared_statistics), fields(refclock = config.description()))]
+    pub fn spawn<C: NtpClock>(
+        index: PeerIndex,
+        config: &RefClockConfig,
+        clock: C,
+        mut channels: PeerChannels,
+        shared_statistics: SharedRefClockStatistics,
+    ) -> tokio::task::JoinHandle<()> {
+        let (sample_sender, samples) = mpsc::channel(16);
+
+        let precision = match config {
+            RefClockConfig::Nmea(config) => {
+                nmea::spawn(config.clone(), clock, sample_sender, Span::current());
+                nmea::precision()
+            }
+            RefClockConfig::Pps(config) => {
+                pps::spawn(config.clone(), sample_sender, Span::current());
+                pps::precision()
+            }
+            RefClockConfig::Phc(config) => {
+                phc::spawn(config.clone(), sample_sender, Span::current());
+                phc::precision()
+            }
+            RefClockConfig::Ptp(config) => {
+                ptp::spawn(config.clone(), clock, sample_sender, Span::current());
+                ptp::precision()
+            }
+        };
+        let refclock = RefClock::new(
+            config.reference_id(),
+            precision,
+            config.common().stratum,
+            config.common().require_corroboration,
+            NtpInstant::now(),
+        );
+        let needs_time_source = config.needs_time_source();
+
+        tokio::spawn(
+            (async move {
+                let poll_wait = tokio::time::sleep(std::time::Duration::default());
+                tokio::pin!(poll_wait);
+
+                let reset_epoch = *channels.reset.borrow_and_update();
+
+                let mut process = RefClockTask {
+                    _wait: PhantomData,
+                    index,
+                    channels,
+                    refclock,
+                    samples,
+                    filter: SampleFilter::default(),
+                    needs_time_source,
+                    statistics: RefClockStatistics::default(),
+                    shared_statistics,
+                    interval_samples: 0,
+                    last_poll: Instant::now(),
+                    reset_epoch,
+                };
+
+                process.run(poll_wait).await
+            })
+            .instrument(Span::current()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ntp_proto::{NtpLeapIndicator, ReferenceId, SystemConfig};
+    use tokio::sync::{watch, RwLock};
+
+    use super::*;
+    use crate::peer::tests::TestWait;
+
+    #[tokio::test]
+    async fn test_refclock_task() {
+        let (msg_for_system_sender, mut msg_recv) = mpsc::channel(1);
+        let (_reset_send, reset) = watch::channel(ResetEpoch::default());
+        let (sample_sender, samples) = mpsc::channel(1);
+
+        let mut process = RefClockTask {
+            _wait: PhantomData,
+            index: PeerIndex::from_inner(0),
+            channels: PeerChannels {
+                msg_for_system_sender,
+                system_snapshots: Arc::new(RwLock::new(SystemSnapshot::default())),
+                system_config: Arc::new(RwLock::new(SystemConfig::default())),
+                reset,
+                statistics: Default::default(),
+                export: Default::default(),
+                state: Default::default(),
+                keys: Default::default(),
+                capture: Default::default(),
+            },
+            refclock: RefClock::new(
+                ReferenceId::GPS,
+                nmea::precision(),
+                0,
+                false,
+                NtpInstant::now(),
+            ),
+            samples,
+            filter: SampleFilter::default(),
+            needs_time_source: false,
+            statistics: RefClockStatistics::default(),
+            shared_statistics: Default::default(),
+            interval_samples: 0,
+            last_poll: Instant::now(),
+            reset_epoch: ResetEpoch::default(),
+        };
+
+        let (poll_wait, poll_send) = TestWait::new();
+
+        let handle = tokio::spawn(async move {
+            tokio::pin!(poll_wait);
+            process.run(poll_wait).await;
+        });
+
+        // without a sample, a poll only updates the snapshot
+        poll_send.notify();
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _, _)));
+
+        sample_sender
+            .send(RefClockSample {
+                offset: NtpDuration::from_seconds(0.5),
+                time: NtpInstant::now(),
+            })
+            .await
+            .unwrap();
+
+        // Not foolproof, but hopefully this ensures the sample is processed first
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        poll_send.notify();
+        let snapshot = match msg_recv.recv().await.unwrap() {
+            MsgForSystem::NewMeasurement(_, _, snapshot) => snapshot,
+            msg => panic!("Unexpected message {:?}", msg),
+        };
+        assert_eq!(snapshot.stratum, 0);
+        assert_eq!(snapshot.statistics.offset, NtpDuration::from_seconds(0.5));
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_refclock_task_needs_time_source() {
+        let (msg_for_system_sender, mut msg_recv) = mpsc::channel(1);
+        let (_reset_send, reset) = watch::channel(ResetEpoch::default());
+        let (sample_sender, samples) = mpsc::channel(1);
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+        let statistics = SharedRefClockStatistics::default();
+
+        let mut process = RefClockTask {
+            _wait: PhantomData,
+            index: PeerIndex::from_inner(0),
+            channels: PeerChannels {
+                msg_for_system_sender,
+                system_snapshots: system_snapshots.clone(),
+                system_config: Arc::new(RwLock::new(SystemConfig::default())),
+                reset,
+                statistics: Default::default(),
+                export: Default::default(),
+                state: Default::default(),
+                keys: Default::default(),
+                capture: Default::default(),
+            },
+            refclock: RefClock::new(
+                ReferenceId::PPS,
+                pps::precision(),
+                0,
+                false,
+                NtpInstant::now(),
+            ),
+            samples,
+            filter: SampleFilter::default(),
+            needs_time_source: true,
+            statistics: RefClockStatistics::default(),
+            shared_statistics: statistics.clone(),
+            interval_samples: 0,
+            last_poll: Instant::now(),
+            reset_epoch: ResetEpoch::default(),
+        };
+
+        let (poll_wait, poll_send) = TestWait::new();
+
+        let handle = tokio::spawn(async move {
+            tokio::pin!(poll_wait);
+            process.run(poll_wait).await;
+        });
+
+        let sample = RefClockSample {
+            offset: NtpDuration::from_seconds(0.000002),
+            time: NtpInstant::now(),
+        };
+
+        // while the system clock is not synchronized, samples are discarded
+        sample_sender.send(sample).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        poll_send.notify();
+        let msg = msg_recv.recv().await.unwrap();
+        assert!(matches!(msg, MsgForSystem::UpdatedSnapshot(_, _, _)));
+        assert_eq!(statistics.get().lock, LockState::WaitingForTimeSource);
+        assert_eq!(statistics.get().unsynchronized, 1);
+
+        system_snapshots.write().await.leap_indicator = NtpLeapIndicator::NoWarning;
+
+        sample_sender.send(sample).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        poll_send.notify();
+        let snapshot = match msg_recv.recv().await.unwrap() {
+            MsgForSystem::NewMeasurement(_, _, snapshot) => snapshot,
+            msg => panic!("Unexpected message {:?}", msg),
+        };
+        assert_eq!(snapshot.peer_id, ReferenceId::PPS);
+        assert_eq!(statistics.get().lock, LockState::Locked);
+        assert_eq!(statistics.get().samples, 2);
+
+        handle.abort();
+    }
+}
@@ -0,0 +1,272 @@
+use std::io::{BufRead, BufReader, Read};
+
+use ntp_os_clock::open_serial;
+use ntp_proto::{NmeaFix, NmeaParsingError, NtpClock, NtpDuration, NtpInstant, NtpTimestamp};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error, info, trace, warn, Span};
+
+use super::{RefClockSample, DEVICE_WAIT_PERIOD};
+use crate::config::NmeaRefClockConfig;
+
+/// NMEA sentences are only loosely aligned with the start of the second they
+/// describe, their timing is only good to a few milliseconds at best.
+pub(super) fn precision() -> NtpDuration {
+    NtpDuration::from_exponent(-7)
+}
+
+/// Longest sentence we accept. The NMEA standard limits sentences to 82
+/// characters, but some receivers are known to be a little more verbose.
+const MAX_SENTENCE_LENGTH: u64 = 256;
+
+#[derive(Debug)]
+enum ReadResult {
+    /// The device failed and needs to be reopened
+    DeviceError(std::io::Error),
+    /// Nobody is listening for samples anymore
+    ChannelClosed,
+}
+
+/// Start reading from the GPS receiver on a dedicated thread, sending the
+/// samples to `sender`. When the device fails it is reopened periodically.
+pub(super) fn spawn<C: NtpClock>(
+    config: NmeaRefClockConfig,
+    clock: C,
+    sender: mpsc::Sender<RefClockSample>,
+    span: Span,
+) {
+    let result = std::thread::Builder::new()
+        .name("nmea-refclock".into())
+        .spawn(move || {
+            let _enter = span.enter();
+
+            loop {
+                match open_serial(&config.device, config.baud_rate) {
+                    Ok(device) => {
+                        info!("opened NMEA device");
+                        let reader = BufReader::new(device);
+                        match read_sentences(reader, &config, &clock, &sender) {
+                            ReadResult::ChannelClosed => return,
+                            ReadResult::DeviceError(error) => {
+                                warn!(?error, "error while reading from NMEA device")
+                            }
+                        }
+                    }
+                    Err(error) => warn!(?error, "could not open NMEA device"),
+                }
+
+                if sender.is_closed() {
+                    return;
+                }
+
+                std::thread::sleep(DEVICE_WAIT_PERIOD);
+            }
+        });
+
+    if let Err(error) = result {
+        error!(?error, "could not start NMEA reader thread");
+    }
+}
+
+fn read_sentences<R: BufRead, C: NtpClock>(
+    mut reader: R,
+    config: &NmeaRefClockConfig,
+    clock: &C,
+    sender: &mpsc::Sender<RefClockSample>,
+) -> ReadResult {
+    let mut line = Vec::new();
+    let mut last_fix_timestamp = None;
+
+    loop {
+        line.clear();
+        match reader
+            .by_ref()
+            .take(MAX_SENTENCE_LENGTH)
+            .read_until(b'\n', &mut line)
+        {
+            Ok(0) => {
+                return ReadResult::DeviceError(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            Ok(_) => {}
+            Err(error) => return ReadResult::DeviceError(error),
+        }
+
+        // Take the timestamps as soon as the sentence is complete
+        let time = NtpInstant::now();
+        let now = match clock.now() {
+            Ok(now) => now,
+            Err(error) => {
+                error!(?error, "There was an error retrieving the current time");
+                // report as no permissions, since this seems the most likely
+                std::process::exit(exitcode::NOPERM);
+            }
+        };
+
+        if line.last() != Some(&b'\n') {
+            // an overlong line, or one cut short by the device
+            debug!("discarding incomplete NMEA sentence");
+            continue;
+        }
+
+        let sentence = String::from_utf8_lossy(&line);
+        let fix = match NmeaFix::parse(&sentence) {
+            Ok(fix) => fix,
+            Err(NmeaParsingError::UnsupportedSentence) => {
+                trace!(%sentence, "ignoring NMEA sentence");
+                continue;
+            }
+            Err(error) => {
+                warn!(%error, %sentence, "received invalid NMEA sentence");
+                continue;
+            }
+        };
+
+        if !fix.valid {
+            debug!("GPS receiver has no fix");
+            continue;
+        }
+
+        // Receivers typically send several sentences for the same second, the
+        // first of which is the most accurate as it suffers the least delay
+        let fix_timestamp = fix.timestamp(now);
+        if last_fix_timestamp == Some(fix_timestamp) {
+            continue;
+        }
+        last_fix_timestamp = Some(fix_timestamp);
+
+        let sample = sample_from_fix(fix_timestamp, now, time, config.fudge);
+        trace!(offset = ?sample.offset, "NMEA sample");
+
+        match sender.try_send(sample) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => debug!("dropping NMEA sample, task is busy"),
+            Err(TrySendError::Closed(_)) => return ReadResult::ChannelClosed,
+        }
+    }
+}
+
+fn sample_from_fix(
+    fix_timestamp: NtpTimestamp,
+    now: NtpTimestamp,
+    time: NtpInstant,
+    fudge: NtpDuration,
+) -> RefClockSample {
+    RefClockSample {
+        offset: (fix_timestamp - now) + fudge,
+        time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, path::PathBuf};
+
+    use ntp_proto::{NtpLeapIndicator, PollInterval};
+
+    use super::*;
+
+    // 2002-07-04 20:15:30 UTC
+    const FIX_SECONDS: u32 = 3234802530;
+
+    #[derive(Debug, Clone, Default)]
+    struct TestClock {}
+
+    impl NtpClock for TestClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+            Ok(NtpTimestamp::from_seconds_nanos_since_ntp_era(
+                FIX_SECONDS,
+                250_000_000,
+            ))
+        }
+
+        fn set_freq(&self, _freq: f64) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by refclock");
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by refclock");
+        }
+
+        fn update_clock(
+            &self,
+            _offset: NtpDuration,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+            _poll_interval: PollInterval,
+            _leap_status: NtpLeapIndicator,
+        ) -> Result<(), Self::Error> {
+            panic!("Shouldn't be called by refclock");
+        }
+    }
+
+    fn test_config() -> NmeaRefClockConfig {
+        NmeaRefClockConfig {
+            device: PathBuf::from("/dev/null"),
+            baud_rate: 9600,
+            fudge: NtpDuration::from_seconds(0.125),
+            common: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_sample_from_fix() {
+        let fix = NtpTimestamp::from_seconds_nanos_since_ntp_era(FIX_SECONDS, 0);
+        let now = NtpTimestamp::from_seconds_nanos_since_ntp_era(FIX_SECONDS, 250_000_000);
+        let sample = sample_from_fix(
+            fix,
+            now,
+            NtpInstant::now(),
+            NtpDuration::from_seconds(0.125),
+        );
+        assert!((sample.offset.to_seconds() + 0.125).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_read_sentences() {
+        let input = concat!(
+            // valid
+            "$GPZDA,201530.00,04,07,2002,00,00*60\r\n",
+            // bad checksum
+            "$GPZDA,201530.00,04,07,2002,00,00*61\r\n",
+            // unsupported
+            "$GPGSV,3,1,11,03,03,111,00,04,15,270,00,06,01,010,00,13,06,292,00*74\r\n",
+            // no fix
+            "$GPGGA,123519,,,,,0,00,,,M,,M,,*6B\r\n",
+            // incomplete
+            "$GPZDA,2015",
+        );
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let result = read_sentences(Cursor::new(input), &test_config(), &TestClock {}, &sender);
+        assert!(matches!(result, ReadResult::DeviceError(_)));
+
+        let sample = receiver.try_recv().unwrap();
+        assert!((sample.offset.to_seconds() + 0.125).abs() < 1e-6);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_read_sentences_same_second() {
+        let input = concat!(
+            "$GPZDA,201530.00,04,07,2002,00,00*60\r\n",
+            "$GPZDA,201530.00,04,07,2002,00,00*60\r\n",
+        );
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        read_sentences(Cursor::new(input), &test_config(), &TestClock {}, &sender);
+
+        assert!(receiver.try_recv().is_ok());
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_read_sentences_closed() {
+        let input = "$GPZDA,201530.00,04,07,2002,00,00*60\r\n";
+
+        let (sender, receiver) = mpsc::channel(16);
+        drop(receiver);
+        let result = read_sentences(Cursor::new(input), &test_config(), &TestClock {}, &sender);
+        assert!(matches!(result, ReadResult::ChannelClosed));
+    }
+}
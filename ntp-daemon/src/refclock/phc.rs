@@ -0,0 +1,98 @@
+use ntp_os_clock::{PhcDevice, PhcOffset};
+use ntp_proto::{NtpDuration, NtpInstant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error, info, trace, warn, Span};
+
+use super::{RefClockSample, DEVICE_WAIT_PERIOD};
+use crate::config::PhcRefClockConfig;
+
+/// Comparing a PHC to the system clock is typically good to a few tens of
+/// nanoseconds with cross timestamping, and somewhat worse without.
+pub(super) fn precision() -> NtpDuration {
+    NtpDuration::from_exponent(-24)
+}
+
+/// How often the PHC is compared to the system clock
+const SAMPLE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Start comparing the PHC to the system clock on a dedicated thread, sending
+/// the samples to `sender`. When the device fails it is reopened periodically.
+pub(super) fn spawn(config: PhcRefClockConfig, sender: mpsc::Sender<RefClockSample>, span: Span) {
+    let result = std::thread::Builder::new()
+        .name("phc-refclock".into())
+        .spawn(move || {
+            let _enter = span.enter();
+
+            loop {
+                match PhcDevice::open(&config.device) {
+                    Ok(device) => {
+                        info!(method = ?device.method(), "opened PHC device");
+
+                        loop {
+                            let phc_offset = match device.offset() {
+                                Ok(phc_offset) => phc_offset,
+                                Err(error) => {
+                                    warn!(?error, "error while reading from PHC device");
+                                    break;
+                                }
+                            };
+
+                            let sample = sample_from_offset(phc_offset, NtpInstant::now(), config.fudge);
+                            trace!(offset = ?sample.offset, uncertainty = ?phc_offset.uncertainty, "PHC sample");
+
+                            match sender.try_send(sample) {
+                                Ok(()) => {}
+                                Err(TrySendError::Full(_)) => {
+                                    debug!("dropping PHC sample, task is busy")
+                                }
+                                Err(TrySendError::Closed(_)) => return,
+                            }
+
+                            std::thread::sleep(SAMPLE_INTERVAL);
+                        }
+                    }
+                    Err(error) => warn!(?error, "could not open PHC device"),
+                }
+
+                if sender.is_closed() {
+                    return;
+                }
+
+                std::thread::sleep(DEVICE_WAIT_PERIOD);
+            }
+        });
+
+    if let Err(error) = result {
+        error!(?error, "could not start PHC reader thread");
+    }
+}
+
+fn sample_from_offset(
+    phc_offset: PhcOffset,
+    time: NtpInstant,
+    fudge: NtpDuration,
+) -> RefClockSample {
+    RefClockSample {
+        offset: phc_offset.offset + fudge,
+        time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_from_offset() {
+        let phc_offset = PhcOffset {
+            offset: NtpDuration::from_seconds(37.000001),
+            uncertainty: NtpDuration::ZERO,
+        };
+        let sample = sample_from_offset(
+            phc_offset,
+            NtpInstant::now(),
+            NtpDuration::from_seconds(-37.0),
+        );
+        assert!((sample.offset.to_seconds() - 0.000001).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,142 @@
+use ntp_os_clock::{PpsDevice, PpsEdge};
+use ntp_proto::{NtpDuration, NtpInstant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error, info, trace, warn, Span};
+
+use super::{RefClockSample, DEVICE_WAIT_PERIOD};
+use crate::config::PpsRefClockConfig;
+
+/// The kernel timestamps pulses in the interrupt handler, which is typically
+/// good to about a microsecond.
+pub(super) fn precision() -> NtpDuration {
+    NtpDuration::from_exponent(-20)
+}
+
+/// How long to wait for a single pulse. Kept short so that we notice in a
+/// timely manner that nobody is interested in the samples anymore.
+const FETCH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+#[derive(Debug)]
+enum ReadResult {
+    /// The device failed and needs to be reopened
+    DeviceError(std::io::Error),
+    /// Nobody is listening for samples anymore
+    ChannelClosed,
+}
+
+/// Start waiting for pulses on a dedicated thread, sending the samples to
+/// `sender`. When the device fails it is reopened periodically.
+pub(super) fn spawn(config: PpsRefClockConfig, sender: mpsc::Sender<RefClockSample>, span: Span) {
+    let result = std::thread::Builder::new()
+        .name("pps-refclock".into())
+        .spawn(move || {
+            let _enter = span.enter();
+
+            loop {
+                match PpsDevice::open(&config.device) {
+                    Ok(device) => {
+                        info!("opened PPS device");
+
+                        if config.hardpps {
+                            match device.enable_hardpps() {
+                                Ok(()) => info!("enabled kernel PPS discipline"),
+                                Err(error) => {
+                                    warn!(?error, "could not enable kernel PPS discipline")
+                                }
+                            }
+                        }
+
+                        match read_pulses(&device, &config, &sender) {
+                            ReadResult::ChannelClosed => return,
+                            ReadResult::DeviceError(error) => {
+                                warn!(?error, "error while reading from PPS device")
+                            }
+                        }
+                    }
+                    Err(error) => warn!(?error, "could not open PPS device"),
+                }
+
+                if sender.is_closed() {
+                    return;
+                }
+
+                std::thread::sleep(DEVICE_WAIT_PERIOD);
+            }
+        });
+
+    if let Err(error) = result {
+        error!(?error, "could not start PPS reader thread");
+    }
+}
+
+fn read_pulses(
+    device: &PpsDevice,
+    config: &PpsRefClockConfig,
+    sender: &mpsc::Sender<RefClockSample>,
+) -> ReadResult {
+    let mut last_sequence = None;
+
+    loop {
+        let edge = match device.fetch(FETCH_TIMEOUT) {
+            Ok(Some(edge)) => edge,
+            Ok(None) => {
+                if sender.is_closed() {
+                    return ReadResult::ChannelClosed;
+                }
+                debug!("no pulse received");
+                continue;
+            }
+            Err(error) => return ReadResult::DeviceError(error),
+        };
+
+        // PPS_FETCH returns the most recent edge, which we may have seen already
+        // when woken up without a new pulse
+        if last_sequence == Some(edge.sequence) {
+            continue;
+        }
+        last_sequence = Some(edge.sequence);
+
+        let sample = sample_from_edge(edge, NtpInstant::now(), config.fudge);
+        trace!(offset = ?sample.offset, sequence = edge.sequence, "PPS sample");
+
+        match sender.try_send(sample) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => debug!("dropping PPS sample, task is busy"),
+            Err(TrySendError::Closed(_)) => return ReadResult::ChannelClosed,
+        }
+    }
+}
+
+/// A pulse marks the start of a second, so its offset is the distance from
+/// the edge's timestamp to the nearest whole second. This is only correct
+/// when the local clock is already within half a second of the true time.
+fn sample_from_edge(edge: PpsEdge, time: NtpInstant, fudge: NtpDuration) -> RefClockSample {
+    RefClockSample {
+        offset: edge.timestamp.offset_to_nearest_second() + fudge,
+        time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::NtpTimestamp;
+
+    use super::*;
+
+    #[test]
+    fn test_sample_from_edge() {
+        let edge = PpsEdge {
+            sequence: 1,
+            timestamp: NtpTimestamp::from_seconds_nanos_since_ntp_era(3234802530, 999_998_000),
+        };
+        let sample = sample_from_edge(edge, NtpInstant::now(), NtpDuration::ZERO);
+        assert!((sample.offset.to_seconds() - 0.000002).abs() < 1e-9);
+
+        let edge = PpsEdge {
+            sequence: 2,
+            timestamp: NtpTimestamp::from_seconds_nanos_since_ntp_era(3234802531, 3_000),
+        };
+        let sample = sample_from_edge(edge, NtpInstant::now(), NtpDuration::from_seconds(0.000001));
+        assert!((sample.offset.to_seconds() + 0.000002).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,193 @@
+use std::net::SocketAddr;
+
+use ntp_proto::{
+    ptp::{
+        ClockIdentity, PtpClient, PtpMeasurement, PtpUpdate, EVENT_PORT, GENERAL_PORT,
+        PRIMARY_MULTICAST_V4,
+    },
+    NtpClock, NtpDuration, NtpInstant,
+};
+use ntp_udp::UdpSocket;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, info, trace, warn, Instrument, Span};
+
+use super::{RefClockSample, DEVICE_WAIT_PERIOD};
+use crate::config::PtpRefClockConfig;
+
+/// Without hardware timestamps, and with the delay requests timestamped
+/// before they are sent, PTP is good to some microseconds on a quiet network.
+pub(super) fn precision() -> NtpDuration {
+    NtpDuration::from_exponent(-17)
+}
+
+/// PTP messages are small, this leaves room for the TLVs some masters append
+const MAX_MESSAGE_SIZE: usize = 512;
+
+#[derive(Debug)]
+enum RunResult {
+    /// The sockets failed and need to be reopened
+    SocketError(std::io::Error),
+    /// Nobody is listening for samples anymore
+    ChannelClosed,
+}
+
+impl From<std::io::Error> for RunResult {
+    fn from(error: std::io::Error) -> Self {
+        RunResult::SocketError(error)
+    }
+}
+
+/// Start the PTP client on a task of its own, sending the measurements to
+/// `sender`. When the sockets fail they are reopened periodically.
+pub(super) fn spawn<C: NtpClock>(
+    config: PtpRefClockConfig,
+    clock: C,
+    sender: mpsc::Sender<RefClockSample>,
+    span: Span,
+) {
+    tokio::spawn(
+        async move {
+            let mut client = PtpClient::new(ClockIdentity::random(), config.domain);
+
+            loop {
+                match run(&config, clock.clone(), &sender, &mut client).await {
+                    RunResult::ChannelClosed => return,
+                    RunResult::SocketError(error) => warn!(?error, "error on PTP socket"),
+                }
+
+                if sender.is_closed() {
+                    return;
+                }
+
+                tokio::time::sleep(DEVICE_WAIT_PERIOD).await;
+            }
+        }
+        .instrument(span),
+    );
+}
+
+async fn run<C: NtpClock>(
+    config: &PtpRefClockConfig,
+    // owned, as clocks need not be `Sync`
+    clock: C,
+    sender: &mpsc::Sender<RefClockSample>,
+    client: &mut PtpClient,
+) -> RunResult {
+    let multicast = SocketAddr::from((PRIMARY_MULTICAST_V4, EVENT_PORT));
+    let event =
+        match UdpSocket::multicast_v4(EVENT_PORT, PRIMARY_MULTICAST_V4, config.interface).await {
+            Ok(socket) => socket,
+            Err(error) => return error.into(),
+        };
+    let general =
+        match UdpSocket::multicast_v4(GENERAL_PORT, PRIMARY_MULTICAST_V4, config.interface).await {
+            Ok(socket) => socket,
+            Err(error) => return error.into(),
+        };
+    info!(domain = config.domain, "listening for PTP messages");
+
+    let mut event_buf = [0; MAX_MESSAGE_SIZE];
+    let mut general_buf = [0; MAX_MESSAGE_SIZE];
+
+    loop {
+        let (on_event_port, size, timestamp) = tokio::select! {
+            result = event.recv(&mut event_buf) => match result {
+                Ok((size, _, timestamp)) => (true, size, timestamp),
+                Err(error) => return error.into(),
+            },
+            result = general.recv(&mut general_buf) => match result {
+                Ok((size, _, timestamp)) => (false, size, timestamp),
+                Err(error) => return error.into(),
+            },
+        };
+        let data = match on_event_port {
+            true => &event_buf[..size],
+            false => &general_buf[..size],
+        };
+
+        // the kernel timestamps every packet, reading the clock is a fallback
+        let timestamp = timestamp.map(|timestamp| clock.adjust_system_timestamp(timestamp));
+        let recv_time = match timestamp.ok_or(()).or_else(|()| clock.now()) {
+            Ok(recv_time) => recv_time,
+            Err(error) => {
+                warn!(?error, "could not read the local clock");
+                continue;
+            }
+        };
+
+        match client.handle_message(data, recv_time, NtpInstant::now()) {
+            // other PTP traffic, such as management messages, is common
+            Err(error) => trace!(%error, "ignoring PTP message"),
+            Ok(PtpUpdate::None) => {}
+            Ok(PtpUpdate::DelayRequestDue) => {
+                let send_time = match clock.now() {
+                    Ok(send_time) => send_time,
+                    Err(error) => {
+                        warn!(?error, "could not read the local clock");
+                        continue;
+                    }
+                };
+
+                if let Some(request) = client.delay_request(send_time, NtpInstant::now()) {
+                    if let Err(error) = event.send_to(&request, multicast).await {
+                        return error.into();
+                    }
+                }
+            }
+            Ok(PtpUpdate::Measurement(measurement)) => {
+                let sample = sample_from_measurement(measurement, NtpInstant::now(), config.fudge);
+                trace!(
+                    offset = ?sample.offset,
+                    delay = ?measurement.delay,
+                    grandmaster = %measurement.grandmaster,
+                    "PTP sample"
+                );
+
+                match sender.try_send(sample) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => debug!("dropping PTP sample, task is busy"),
+                    Err(TrySendError::Closed(_)) => return RunResult::ChannelClosed,
+                }
+            }
+        }
+    }
+}
+
+fn sample_from_measurement(
+    measurement: PtpMeasurement,
+    time: NtpInstant,
+    fudge: NtpDuration,
+) -> RefClockSample {
+    RefClockSample {
+        offset: measurement.offset + fudge,
+        time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::ptp::ClockQuality;
+
+    use super::*;
+
+    #[test]
+    fn test_sample_from_measurement() {
+        let measurement = PtpMeasurement {
+            offset: NtpDuration::from_seconds(0.000_150),
+            delay: NtpDuration::from_seconds(0.000_400),
+            grandmaster: ClockIdentity([1; 8]),
+            steps_removed: 1,
+            quality: ClockQuality {
+                class: 6,
+                accuracy: 0x21,
+                offset_scaled_log_variance: 0x4e5d,
+            },
+        };
+        let sample = sample_from_measurement(
+            measurement,
+            NtpInstant::now(),
+            NtpDuration::from_seconds(-0.000_050),
+        );
+        assert!((sample.offset.to_seconds() - 0.000_100).abs() < 1e-9);
+    }
+}
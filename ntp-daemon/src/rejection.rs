@@ -0,0 +1,230 @@
+//! Counting why the packets and measurements of a source were not used.
+//!
+//! Packets are rejected by the peer itself, while measurements are rejected
+//! by clock selection, every round in which the source is not acceptable.
+//! Both are counted per source, and together answer the question of why a
+//! source is not being used.
+
+use ntp_proto::{AcceptSynchronizationError, IgnoreReason, ResponseValidationError};
+use serde::{Deserialize, Serialize};
+
+/// Why a packet or measurement of a source was not used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectionReason {
+    /// No recent packets were received from the source
+    Unreachable,
+    /// The source synchronizes to us
+    Loop,
+    /// The root distance of the source exceeds the distance threshold
+    Distance,
+    /// The stratum of the source is too high
+    Stratum,
+    /// The source signals that its clock is not synchronized
+    Unsynchronized,
+    /// A packet with an unsupported mode or version
+    Invalid,
+    /// A packet while no request was outstanding, or a replay of an earlier response
+    Duplicate,
+    /// A packet with timestamps that do not match our request, or are zero
+    Bogus,
+    /// A Kiss-o'-Death packet
+    KissOfDeath,
+    /// A measurement with an offset beyond the maximum offset of the source
+    Offset,
+    /// A measurement with a delay beyond the maximum delay of the source, or
+    /// too far above its smallest recent delay
+    Delay,
+    /// A packet of a source that did not update its own clock for too long
+    Stale,
+    /// A source that appears to smear leap seconds, while others do not
+    Smearing,
+}
+
+impl RejectionReason {
+    pub const ALL: [RejectionReason; 13] = [
+        RejectionReason::Unreachable,
+        RejectionReason::Loop,
+        RejectionReason::Distance,
+        RejectionReason::Stratum,
+        RejectionReason::Unsynchronized,
+        RejectionReason::Invalid,
+        RejectionReason::Duplicate,
+        RejectionReason::Bogus,
+        RejectionReason::KissOfDeath,
+        RejectionReason::Offset,
+        RejectionReason::Delay,
+        RejectionReason::Stale,
+        RejectionReason::Smearing,
+    ];
+
+    /// Name of the reason, as used for labels in metrics
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RejectionReason::Unreachable => "unreachable",
+            RejectionReason::Loop => "loop",
+            RejectionReason::Distance => "distance",
+            RejectionReason::Stratum => "stratum",
+            RejectionReason::Unsynchronized => "unsynchronized",
+            RejectionReason::Invalid => "invalid",
+            RejectionReason::Duplicate => "duplicate",
+            RejectionReason::Bogus => "bogus",
+            RejectionReason::KissOfDeath => "kiss_of_death",
+            RejectionReason::Offset => "offset",
+            RejectionReason::Delay => "delay",
+            RejectionReason::Stale => "stale",
+            RejectionReason::Smearing => "smearing",
+        }
+    }
+}
+
+impl std::fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<AcceptSynchronizationError> for RejectionReason {
+    fn from(error: AcceptSynchronizationError) -> Self {
+        match error {
+            AcceptSynchronizationError::ServerUnreachable => RejectionReason::Unreachable,
+            AcceptSynchronizationError::Loop => RejectionReason::Loop,
+            AcceptSynchronizationError::Distance => RejectionReason::Distance,
+            AcceptSynchronizationError::Stratum => RejectionReason::Stratum,
+            AcceptSynchronizationError::Unsynchronized => RejectionReason::Unsynchronized,
+        }
+    }
+}
+
+impl From<IgnoreReason> for RejectionReason {
+    fn from(reason: IgnoreReason) -> Self {
+        match reason {
+            IgnoreReason::InvalidMode | IgnoreReason::InvalidVersion => RejectionReason::Invalid,
+            IgnoreReason::InvalidStratum => RejectionReason::Stratum,
+            IgnoreReason::NoOutstandingRequest
+            | IgnoreReason::InvalidResponse(ResponseValidationError::DuplicateTransmit) => {
+                RejectionReason::Duplicate
+            }
+            IgnoreReason::InvalidResponse(_) | IgnoreReason::TooOld => RejectionReason::Bogus,
+            IgnoreReason::KissIgnore | IgnoreReason::KissDemobilize => RejectionReason::KissOfDeath,
+            IgnoreReason::OffsetTooLarge => RejectionReason::Offset,
+            IgnoreReason::DelayTooLarge => RejectionReason::Delay,
+            IgnoreReason::StaleReference => RejectionReason::Stale,
+        }
+    }
+}
+
+/// The number of rejections of a source per reason, and the latest reason
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rejections {
+    pub unreachable: u64,
+    pub loops: u64,
+    pub distance: u64,
+    pub stratum: u64,
+    pub unsynchronized: u64,
+    pub invalid: u64,
+    pub duplicate: u64,
+    pub bogus: u64,
+    pub kiss_of_death: u64,
+    pub offset: u64,
+    pub delay: u64,
+    pub stale: u64,
+    pub smearing: u64,
+    pub last_reason: Option<RejectionReason>,
+}
+
+impl Rejections {
+    pub(crate) fn record(&mut self, reason: impl Into<RejectionReason>) {
+        let reason = reason.into();
+        *self.count_mut(reason) += 1;
+        self.last_reason = Some(reason);
+    }
+
+    fn count_mut(&mut self, reason: RejectionReason) -> &mut u64 {
+        match reason {
+            RejectionReason::Unreachable => &mut self.unreachable,
+            RejectionReason::Loop => &mut self.loops,
+            RejectionReason::Distance => &mut self.distance,
+            RejectionReason::Stratum => &mut self.stratum,
+            RejectionReason::Unsynchronized => &mut self.unsynchronized,
+            RejectionReason::Invalid => &mut self.invalid,
+            RejectionReason::Duplicate => &mut self.duplicate,
+            RejectionReason::Bogus => &mut self.bogus,
+            RejectionReason::KissOfDeath => &mut self.kiss_of_death,
+            RejectionReason::Offset => &mut self.offset,
+            RejectionReason::Delay => &mut self.delay,
+            RejectionReason::Stale => &mut self.stale,
+            RejectionReason::Smearing => &mut self.smearing,
+        }
+    }
+
+    pub fn count(&self, reason: RejectionReason) -> u64 {
+        match reason {
+            RejectionReason::Unreachable => self.unreachable,
+            RejectionReason::Loop => self.loops,
+            RejectionReason::Distance => self.distance,
+            RejectionReason::Stratum => self.stratum,
+            RejectionReason::Unsynchronized => self.unsynchronized,
+            RejectionReason::Invalid => self.invalid,
+            RejectionReason::Duplicate => self.duplicate,
+            RejectionReason::Bogus => self.bogus,
+            RejectionReason::KissOfDeath => self.kiss_of_death,
+            RejectionReason::Offset => self.offset,
+            RejectionReason::Delay => self.delay,
+            RejectionReason::Stale => self.stale,
+            RejectionReason::Smearing => self.smearing,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        RejectionReason::ALL
+            .iter()
+            .map(|reason| self.count(*reason))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut rejections = Rejections::default();
+        assert_eq!(rejections.total(), 0);
+        assert_eq!(rejections.last_reason, None);
+
+        rejections.record(AcceptSynchronizationError::Unsynchronized);
+        rejections.record(AcceptSynchronizationError::Unsynchronized);
+        rejections.record(IgnoreReason::InvalidResponse(
+            ResponseValidationError::OriginMismatch,
+        ));
+
+        assert_eq!(rejections.unsynchronized, 2);
+        assert_eq!(rejections.count(RejectionReason::Bogus), 1);
+        assert_eq!(rejections.total(), 3);
+        assert_eq!(rejections.last_reason, Some(RejectionReason::Bogus));
+
+        rejections.record(IgnoreReason::InvalidResponse(
+            ResponseValidationError::DuplicateTransmit,
+        ));
+        assert_eq!(rejections.duplicate, 1);
+
+        rejections.record(IgnoreReason::OffsetTooLarge);
+        assert_eq!(rejections.offset, 1);
+        assert_eq!(rejections.last_reason, Some(RejectionReason::Offset));
+
+        rejections.record(IgnoreReason::DelayTooLarge);
+        assert_eq!(rejections.delay, 1);
+
+        rejections.record(IgnoreReason::StaleReference);
+        assert_eq!(rejections.stale, 1);
+    }
+
+    #[test]
+    fn test_deserialize_missing_fields() {
+        let rejections: Rejections = serde_json::from_str(r#"{"stratum":4}"#).unwrap();
+        assert_eq!(rejections.total(), 4);
+        assert_eq!(rejections.last_reason, None);
+    }
+}
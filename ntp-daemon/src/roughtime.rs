@@ -0,0 +1,229 @@
+//! Validation of the system clock with Roughtime at startup.
+//!
+//! Devices without a real-time clock boot with a time that can be off by
+//! years, and NTP by itself cannot tell a correct server from one that is
+//! impersonated. Roughtime responses are signed, so before any NTP source is
+//! trusted, the clock is checked against the configured Roughtime servers and
+//! stepped into the interval they agree on when it lies outside of it.
+
+use std::time::Instant;
+
+use ntp_proto::{
+    NtpClock, NtpDuration, NtpTimestamp, RoughtimeRequest, RoughtimeTime, ROUGHTIME_REQUEST_SIZE,
+};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+use crate::config::{RoughtimeConfig, RoughtimeServerConfig};
+
+#[derive(Debug, Error)]
+pub enum RoughtimeStartupError {
+    #[error("none of the Roughtime servers provided a verified time")]
+    NoVerifiedTime,
+    #[error("the Roughtime servers do not agree on the time")]
+    Disagreement,
+    #[error("could not read or step the clock: {0}")]
+    Clock(String),
+}
+
+/// The offset of the true time from the local clock, according to a single
+/// server, lies between `min` and `max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct OffsetInterval {
+    min: NtpDuration,
+    max: NtpDuration,
+}
+
+impl OffsetInterval {
+    fn new(time: RoughtimeTime, local: NtpTimestamp, round_trip: NtpDuration) -> Self {
+        // the response may have been signed at any moment during the round trip
+        let offset = time.midpoint - local;
+        let uncertainty = time.radius + round_trip;
+        OffsetInterval {
+            min: offset - uncertainty,
+            max: offset + uncertainty,
+        }
+    }
+}
+
+/// The interval that all servers agree on, if any
+fn intersect(intervals: &[OffsetInterval]) -> Option<OffsetInterval> {
+    let mut intervals = intervals.iter();
+    let mut result = *intervals.next()?;
+    for interval in intervals {
+        result.min = result.min.max(interval.min);
+        result.max = result.max.min(interval.max);
+    }
+
+    match result.min <= result.max {
+        true => Some(result),
+        false => None,
+    }
+}
+
+async fn query<C: NtpClock>(
+    server: &RoughtimeServerConfig,
+    clock: &C,
+) -> Result<OffsetInterval, Box<dyn std::error::Error>> {
+    let addr = tokio::net::lookup_host(&server.addr)
+        .await?
+        .next()
+        .ok_or("address did not resolve")?;
+
+    let bind_addr: std::net::SocketAddr = match addr {
+        std::net::SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+        std::net::SocketAddr::V6(_) => "[::]:0".parse()?,
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+
+    let request = RoughtimeRequest::new();
+    let sent = Instant::now();
+    socket.send(&request.serialize()).await?;
+
+    let mut buf = [0; ROUGHTIME_REQUEST_SIZE];
+    loop {
+        let n = socket.recv(&mut buf).await?;
+        let round_trip = NtpDuration::from_system_duration(sent.elapsed());
+        let local = clock.now().map_err(|e| e.to_string())?;
+
+        // keep waiting when something else than the response arrives
+        match request.verify_response(&buf[..n], &server.public_key) {
+            Ok(time) => return Ok(OffsetInterval::new(time, local, round_trip)),
+            Err(error) => warn!(addr = server.addr, %error, "ignoring invalid Roughtime response"),
+        }
+    }
+}
+
+/// Check the clock against the configured Roughtime servers, stepping it
+/// into the interval that they agree on when it is outside of it
+pub async fn validate_clock<C: NtpClock>(
+    config: &RoughtimeConfig,
+    clock: &C,
+) -> Result<(), RoughtimeStartupError> {
+    if config.servers.is_empty() {
+        return Ok(());
+    }
+
+    let mut intervals = vec![];
+    for server in &config.servers {
+        match tokio::time::timeout(config.timeout, query(server, clock)).await {
+            Ok(Ok(interval)) => intervals.push(interval),
+            Ok(Err(error)) => warn!(addr = server.addr, %error, "Roughtime query failed"),
+            Err(_) => warn!(
+                addr = server.addr,
+                "Roughtime server did not respond in time"
+            ),
+        }
+    }
+
+    if intervals.is_empty() {
+        return Err(RoughtimeStartupError::NoVerifiedTime);
+    }
+
+    let agreed = intersect(&intervals).ok_or(RoughtimeStartupError::Disagreement)?;
+    if agreed.min <= NtpDuration::ZERO && NtpDuration::ZERO <= agreed.max {
+        info!(servers = intervals.len(), "clock validated by Roughtime");
+        return Ok(());
+    }
+
+    let offset = agreed.min + (agreed.max - agreed.min) / 2;
+    warn!(
+        servers = intervals.len(),
+        offset = offset.to_seconds(),
+        "clock outside of the time given by Roughtime, stepping it"
+    );
+    clock
+        .step_clock(offset)
+        .map_err(|e| RoughtimeStartupError::Clock(e.to_string()))
+}
+
+/// Validate the clock as configured, only failing when validation is required
+pub async fn startup<C: NtpClock>(
+    config: &RoughtimeConfig,
+    clock: &C,
+) -> Result<(), RoughtimeStartupError> {
+    match validate_clock(config, clock).await {
+        Ok(()) => Ok(()),
+        Err(error) if config.required => Err(error),
+        Err(error) => {
+            warn!(%error, "could not validate the clock with Roughtime, continuing without");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interval(min: f64, max: f64) -> OffsetInterval {
+        OffsetInterval {
+            min: NtpDuration::from_seconds(min),
+            max: NtpDuration::from_seconds(max),
+        }
+    }
+
+    #[test]
+    fn test_offset_interval() {
+        let local = NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0);
+        let time = RoughtimeTime {
+            midpoint: NtpTimestamp::from_unix_seconds_nanos(1_700_000_010, 0),
+            radius: NtpDuration::from_seconds(1.0),
+        };
+
+        let result = OffsetInterval::new(time, local, NtpDuration::from_seconds(0.5));
+        assert!((result.min.to_seconds() - 8.5).abs() < 1e-6);
+        assert!((result.max.to_seconds() - 11.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_intersect() {
+        assert_eq!(intersect(&[]), None);
+        assert_eq!(
+            intersect(&[interval(-1.0, 2.0), interval(0.5, 3.0), interval(-4.0, 1.0)]),
+            Some(interval(0.5, 1.0))
+        );
+        assert_eq!(intersect(&[interval(-1.0, 0.0), interval(1.0, 2.0)]), None);
+    }
+
+    #[tokio::test]
+    async fn test_no_servers() {
+        #[derive(Clone)]
+        struct NoClock;
+
+        impl NtpClock for NoClock {
+            type Error = std::io::Error;
+
+            fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+                panic!("Shouldn't be called by roughtime");
+            }
+
+            fn set_freq(&self, _freq: f64) -> Result<(), Self::Error> {
+                panic!("Shouldn't be called by roughtime");
+            }
+
+            fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
+                panic!("Shouldn't be called by roughtime");
+            }
+
+            fn update_clock(
+                &self,
+                _offset: NtpDuration,
+                _est_error: NtpDuration,
+                _max_error: NtpDuration,
+                _poll_interval: ntp_proto::PollInterval,
+                _leap_status: ntp_proto::NtpLeapIndicator,
+            ) -> Result<(), Self::Error> {
+                panic!("Shouldn't be called by roughtime");
+            }
+        }
+
+        let config = RoughtimeConfig {
+            required: true,
+            ..Default::default()
+        };
+        assert!(startup(&config, &NoClock).await.is_ok());
+    }
+}
@@ -0,0 +1,275 @@
+//! Use of the battery backed real-time clock (RTC).
+//!
+//! At startup, the system clock is set from the RTC when they differ by more
+//! than a second, so that a reboot starts close to the true time even before
+//! any source is reachable. While the system clock is synchronized, the RTC
+//! is periodically set to it. With a drift file, the rate at which the RTC
+//! drifts in between is measured, and taken into account at the next start.
+
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ntp_os_clock::RtcDevice;
+use ntp_proto::{NtpClock, NtpDuration, SystemSnapshot};
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, warn};
+
+use crate::config::RtcConfig;
+
+/// Differences between the system clock and the RTC up to this many seconds
+/// are left alone at startup
+const STARTUP_STEP_THRESHOLD: f64 = 1.0;
+
+/// Shortest time over which the drift of the RTC is measured, as shorter
+/// periods give too imprecise an estimate
+const MIN_DRIFT_PERIOD: i64 = 3600;
+
+/// What is known about the RTC since it was last set
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DriftState {
+    /// Time at which the RTC was last set, in seconds since the unix epoch
+    last_set: i64,
+    /// RTC time minus system clock time right after it was set, in seconds
+    baseline: f64,
+    /// Drift of the RTC in parts per million, once measured
+    drift: Option<f64>,
+}
+
+impl DriftState {
+    fn parse(data: &str) -> Option<Self> {
+        let mut parts = data.split_whitespace();
+        let state = DriftState {
+            last_set: parts.next()?.parse().ok()?,
+            baseline: parts.next()?.parse().ok()?,
+            drift: match parts.next() {
+                Some(drift) => Some(drift.parse().ok()?),
+                None => None,
+            },
+        };
+
+        match parts.next() {
+            Some(_) => None,
+            None => Some(state),
+        }
+    }
+
+    fn format(&self) -> String {
+        match self.drift {
+            Some(drift) => format!("{} {} {}\n", self.last_set, self.baseline, drift),
+            None => format!("{} {}\n", self.last_set, self.baseline),
+        }
+    }
+
+    /// RTC time minus true time that is expected at the given time
+    fn expected_offset(&self, at: i64) -> f64 {
+        let elapsed = (at - self.last_set) as f64;
+        self.baseline + self.drift.unwrap_or(0.0) * 1e-6 * elapsed
+    }
+
+    /// Include a measurement of the RTC minus true time in the drift
+    /// estimate, which is averaged with earlier estimates
+    fn measure(&mut self, offset: f64, at: i64) {
+        let elapsed = at - self.last_set;
+        if elapsed < MIN_DRIFT_PERIOD {
+            return;
+        }
+
+        let measured = (offset - self.baseline) / elapsed as f64 * 1e6;
+        self.drift = Some(match self.drift {
+            Some(drift) => (drift + measured) / 2.0,
+            None => measured,
+        });
+    }
+}
+
+fn load_state(path: Option<&Path>) -> Option<DriftState> {
+    let path = path?;
+    match std::fs::read_to_string(path) {
+        Ok(data) => {
+            let state = DriftState::parse(&data);
+            if state.is_none() {
+                warn!(?path, "ignoring invalid RTC drift file");
+            }
+            state
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => None,
+        Err(error) => {
+            warn!(?path, ?error, "could not read RTC drift file");
+            None
+        }
+    }
+}
+
+fn save_state(path: Option<&Path>, state: DriftState) {
+    if let Some(path) = path {
+        if let Err(error) = std::fs::write(path, state.format()) {
+            warn!(?path, ?error, "could not write RTC drift file");
+        }
+    }
+}
+
+fn unix_seconds() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(error) => -(error.duration().as_secs() as i64),
+    }
+}
+
+/// Set the system clock from the RTC when they differ by more than a second.
+/// This blocks for up to a second, while waiting for the RTC to tick.
+pub fn startup<C: NtpClock>(config: &RtcConfig, clock: &C) {
+    let path = match &config.device {
+        Some(path) => path,
+        None => return,
+    };
+
+    let device = match RtcDevice::open(path) {
+        Ok(device) => device,
+        Err(error) => {
+            warn!(?path, ?error, "could not open RTC device");
+            return;
+        }
+    };
+
+    let reading = device
+        .offset()
+        .and_then(|offset| Ok((offset, device.read()?)));
+    let (offset, rtc_time) = match reading {
+        Ok(reading) => reading,
+        Err(error) => {
+            warn!(?path, ?error, "could not read RTC device");
+            return;
+        }
+    };
+
+    let expected = load_state(config.drift_file.as_deref())
+        .map(|state| state.expected_offset(rtc_time))
+        .unwrap_or(0.0);
+
+    // the true time according to the RTC, minus the system clock time
+    let correction = offset.to_seconds() - expected;
+    if correction.abs() <= STARTUP_STEP_THRESHOLD {
+        debug!(correction, "system clock agrees with the RTC");
+        return;
+    }
+
+    info!(correction, "setting the system clock from the RTC");
+    if let Err(error) = clock.step_clock(NtpDuration::from_seconds(correction)) {
+        error!(%error, "could not set the system clock from the RTC");
+    }
+}
+
+/// Periodically set the RTC to the system clock, while it is synchronized
+pub fn spawn(config: &RtcConfig, system: Arc<RwLock<SystemSnapshot>>) {
+    if config.device.is_none() {
+        return;
+    }
+
+    let config = config.clone();
+    let result = std::thread::Builder::new()
+        .name("rtc-sync".into())
+        .spawn(move || sync(config, system));
+
+    if let Err(error) = result {
+        error!(?error, "could not start RTC synchronization thread");
+    }
+}
+
+fn sync(config: RtcConfig, system: Arc<RwLock<SystemSnapshot>>) {
+    let path = match &config.device {
+        Some(path) => path,
+        None => return,
+    };
+    let drift_file = config.drift_file.as_deref();
+    let mut state = load_state(drift_file);
+
+    loop {
+        std::thread::sleep(config.sync_interval);
+
+        if !system.blocking_read().leap_indicator.is_synchronized() {
+            continue;
+        }
+
+        let result = RtcDevice::open(path).and_then(|device| {
+            // how far the RTC drifted since it was last set
+            if let Some(state) = &mut state {
+                let offset = device.offset()?;
+                state.measure(offset.to_seconds(), unix_seconds());
+            }
+
+            device.set_to_system_time()?;
+            let baseline = device.offset()?;
+            Ok(baseline)
+        });
+
+        match result {
+            Ok(baseline) => {
+                debug!(?baseline, "set the RTC to the system clock");
+
+                // without a drift file, there is no use in measuring the drift
+                if drift_file.is_some() {
+                    let new_state = DriftState {
+                        last_set: unix_seconds(),
+                        baseline: baseline.to_seconds(),
+                        drift: state.and_then(|state| state.drift),
+                    };
+                    save_state(drift_file, new_state);
+                    state = Some(new_state);
+                }
+            }
+            Err(error) => warn!(?path, ?error, "could not set the RTC"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drift_state_file() {
+        let state = DriftState {
+            last_set: 1_700_000_000,
+            baseline: -0.25,
+            drift: None,
+        };
+        assert_eq!(DriftState::parse(&state.format()), Some(state));
+
+        let state = DriftState {
+            drift: Some(12.5),
+            ..state
+        };
+        assert_eq!(DriftState::parse(&state.format()), Some(state));
+
+        assert_eq!(DriftState::parse(""), None);
+        assert_eq!(DriftState::parse("1700000000"), None);
+        assert_eq!(DriftState::parse("1700000000 0.1 2 3"), None);
+        assert_eq!(DriftState::parse("1700000000 zero"), None);
+    }
+
+    #[test]
+    fn test_drift_measurement() {
+        let mut state = DriftState {
+            last_set: 0,
+            baseline: 0.5,
+            drift: None,
+        };
+
+        // too short to measure
+        state.measure(0.6, 600);
+        assert_eq!(state.drift, None);
+        assert_eq!(state.expected_offset(600), 0.5);
+
+        // 20 ppm, the RTC gains 72 ms per hour
+        state.measure(0.572, 3600);
+        assert!((state.drift.unwrap() - 20.0).abs() < 1e-6);
+        assert!((state.expected_offset(7200) - 0.644).abs() < 1e-6);
+
+        // 10 ppm, averaged with the earlier estimate
+        state.measure(0.536, 3600);
+        assert!((state.drift.unwrap() - 15.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,242 @@
+//! Restriction of the daemon to the syscalls it needs, with seccomp.
+//!
+//! The allowed syscalls are collected per feature, and only the features that
+//! are enabled in the configuration contribute to the filter. The filter is
+//! installed once at startup and applies to all threads.
+
+use ntp_os_clock::SeccompAction;
+use tracing::info;
+
+use crate::config::{Config, SandboxMode};
+
+/// Runtime, memory management, threads, signals and logging
+const BASE: &[libc::c_long] = &[
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_pread64,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_close,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_openat,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_futex,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_membarrier,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_set_tid_address,
+    libc::SYS_prctl,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_getrandom,
+    libc::SYS_uname,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_gettimeofday,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_eventfd2,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+];
+
+/// Older variants of the above, still used by glibc on x86_64
+#[cfg(target_arch = "x86_64")]
+const BASE_X86_64: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_readlink,
+    libc::SYS_access,
+    libc::SYS_poll,
+    libc::SYS_epoll_wait,
+    libc::SYS_arch_prctl,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const BASE_X86_64: &[libc::c_long] = &[];
+
+/// Reading and steering the system clock
+const CLOCK: &[libc::c_long] = &[
+    libc::SYS_adjtimex,
+    libc::SYS_clock_adjtime,
+    libc::SYS_clock_settime,
+];
+
+/// UDP sockets of peers and servers, name resolution, and notifications to
+/// the service manager
+const NETWORK: &[libc::c_long] = &[
+    libc::SYS_socket,
+    libc::SYS_bind,
+    libc::SYS_connect,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_shutdown,
+];
+
+/// Batched receiving and sending of servers
+#[cfg(feature = "io-uring")]
+const IO_URING: &[libc::c_long] = &[
+    libc::SYS_io_uring_setup,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+];
+
+/// Observation, configuration and chrony sockets, and the health endpoints
+const UNIX_SOCKETS: &[libc::c_long] = &[
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_unlinkat,
+    libc::SYS_fchmodat,
+];
+
+#[cfg(target_arch = "x86_64")]
+const UNIX_SOCKETS_X86_64: &[libc::c_long] = &[libc::SYS_unlink, libc::SYS_chmod];
+#[cfg(not(target_arch = "x86_64"))]
+const UNIX_SOCKETS_X86_64: &[libc::c_long] = &[];
+
+/// Replacing the state file
+const STATE_FILE: &[libc::c_long] = &[libc::SYS_renameat, libc::SYS_renameat2];
+
+#[cfg(target_arch = "x86_64")]
+const STATE_FILE_X86_64: &[libc::c_long] = &[libc::SYS_rename];
+#[cfg(not(target_arch = "x86_64"))]
+const STATE_FILE_X86_64: &[libc::c_long] = &[];
+
+/// Entering network namespaces, for peers in another namespace. Creating the
+/// thread that enters the namespace is part of the base set.
+const NETWORK_NAMESPACES: &[libc::c_long] = &[libc::SYS_setns];
+
+/// Syscalls needed by the features enabled in the configuration
+fn allowed_syscalls(config: &Config) -> Vec<libc::c_long> {
+    let mut allowed = Vec::new();
+    allowed.extend_from_slice(BASE);
+    allowed.extend_from_slice(BASE_X86_64);
+    allowed.extend_from_slice(CLOCK);
+    allowed.extend_from_slice(NETWORK);
+
+    // devices of reference clocks and steered PHCs, and statistics files, only
+    // need the file syscalls that are part of the base set
+
+    #[cfg(feature = "io-uring")]
+    if !config.servers.is_empty() {
+        allowed.extend_from_slice(IO_URING);
+    }
+
+    let unix_sockets = [
+        &config.observe.path,
+        &config.configure.path,
+        &config.chrony.path,
+    ];
+    if unix_sockets.iter().any(|path| path.is_some()) || config.health.listen.is_some() {
+        allowed.extend_from_slice(UNIX_SOCKETS);
+        allowed.extend_from_slice(UNIX_SOCKETS_X86_64);
+    }
+
+    if config.state.path.is_some() {
+        allowed.extend_from_slice(STATE_FILE);
+        allowed.extend_from_slice(STATE_FILE_X86_64);
+    }
+
+    if config
+        .peers
+        .iter()
+        .any(|peer| peer.bind().network_namespace.is_some())
+    {
+        allowed.extend_from_slice(NETWORK_NAMESPACES);
+    }
+
+    allowed.sort_unstable();
+    allowed.dedup();
+    allowed
+}
+
+/// Install the syscall filter when enabled in the configuration. As this
+/// also prevents changing users, it must come after dropping privileges.
+pub fn install(config: &Config) -> std::io::Result<()> {
+    let action = match config.sandbox.mode {
+        SandboxMode::Disabled => return Ok(()),
+        SandboxMode::Log => SeccompAction::Log,
+        SandboxMode::Enforce => SeccompAction::Kill,
+    };
+
+    let allowed = allowed_syscalls(config);
+    ntp_os_clock::install_syscall_filter(&allowed, action)?;
+
+    info!(mode = ?config.sandbox.mode, syscalls = allowed.len(), "installed syscall filter");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_allowed_syscalls() {
+        let mut config = Config::default();
+        let allowed = allowed_syscalls(&config);
+        assert!(allowed.contains(&libc::SYS_clock_adjtime));
+        assert!(allowed.contains(&libc::SYS_recvmsg));
+        assert!(!allowed.contains(&libc::SYS_accept4));
+        assert!(!allowed.contains(&libc::SYS_execve));
+
+        config.observe.path = Some(PathBuf::from("/run/ntpd-rs/observe"));
+        let allowed = allowed_syscalls(&config);
+        assert!(allowed.contains(&libc::SYS_accept4));
+        assert!(!allowed.contains(&libc::SYS_execve));
+
+        let mut config = Config::default();
+        config.health.listen = Some("127.0.0.1:8080".parse().unwrap());
+        assert!(allowed_syscalls(&config).contains(&libc::SYS_listen));
+
+        let mut config = Config::default();
+        assert!(!allowed_syscalls(&config).contains(&libc::SYS_renameat2));
+        config.state.path = Some(PathBuf::from("/var/lib/ntpd-rs/state.json"));
+        assert!(allowed_syscalls(&config).contains(&libc::SYS_renameat2));
+
+        let mut config = Config::default();
+        assert!(!allowed_syscalls(&config).contains(&libc::SYS_setns));
+        config.peers =
+            vec![toml::from_str("addr = \"192.0.2.10\"\nnetwork-namespace = \"mgmt\"").unwrap()];
+        assert!(allowed_syscalls(&config).contains(&libc::SYS_setns));
+    }
+}
@@ -1,18 +1,41 @@
 use std::{
-    io::Cursor,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
-use ntp_proto::{NtpAssociationMode, NtpClock, NtpPacket, NtpTimestamp, SystemSnapshot};
-use ntp_udp::UdpSocket;
+use arc_swap::ArcSwap;
+use ntp_proto::{
+    ControlMessage, NtpAssociationMode, NtpClock, NtpPacket, NtpTimestamp, PacketParsingError,
+    ParsingPolicy, SymmetricKey, SystemSnapshot,
+};
+use ntp_udp::{UdpSocket, MAX_BATCH_SIZE};
 use prometheus_client::metrics::{counter::Counter, gauge::Atomic};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tokio::{sync::RwLock, task::JoinHandle};
-use tracing::{error, info, instrument, trace, warn};
+use tracing::{error, instrument, trace, warn};
+
+use crate::{
+    capture::PacketCapture,
+    config::{AclAction, FilterAction, NetworkConfig, ServerConfig, ServerPolicy},
+    control::{self, Associations},
+    keys::Keys,
+    mru::ClientList,
+    peer::MAX_NTP_PACKET_SIZE,
+    systemd,
+};
+
+/// Large enough for control requests, which are not limited to 48 bytes
+const MAX_PACKET_SIZE: usize = 1024;
+
+/// Number of responses handed to a single `send_batch`
+const SEND_CHUNK_SIZE: usize = 16;
 
-use crate::config::{FilterAction, ServerConfig};
+/// How often the servers pick up changes of the system snapshot
+const SNAPSHOT_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct ServerStats {
@@ -21,6 +44,60 @@ pub struct ServerStats {
     pub denied_packets: WrappedCounter,
     pub rate_limited_packets: WrappedCounter,
     pub response_send_errors: WrappedCounter,
+    /// Packets that could not be parsed, including those that are too short
+    #[serde(default)]
+    pub malformed_packets: WrappedCounter,
+    /// Packets of an NTP version that is not supported, or not answered
+    /// because of the server policy
+    #[serde(default)]
+    pub unsupported_version_packets: WrappedCounter,
+    /// Packets that deviate from RFC 5905, which are only answered with the
+    /// lenient parsing policy
+    #[serde(default)]
+    pub nonconforming_packets: WrappedCounter,
+    /// Packets dropped without response by the allow and deny lists, access
+    /// control rules, or because of their mode
+    #[serde(default)]
+    pub ignored_packets: WrappedCounter,
+    /// Kiss-o'-death responses sent, for denied and rate limited clients
+    #[serde(default)]
+    pub kiss_codes_sent: WrappedCounter,
+    /// Time requests answered with a kiss code or ignored because the
+    /// server is drained
+    #[serde(default)]
+    pub drained_packets: WrappedCounter,
+    /// Batches received with `recvmmsg`, and how many of them were full
+    #[serde(default)]
+    pub receive_batches: WrappedCounter,
+    #[serde(default)]
+    pub full_receive_batches: WrappedCounter,
+    /// Matches of every access control rule, in the order of the configuration
+    #[serde(default)]
+    pub acl_matches: Vec<AclRuleStats>,
+}
+
+impl ServerStats {
+    pub fn new(config: &ServerConfig) -> Self {
+        ServerStats {
+            acl_matches: config
+                .acl
+                .iter()
+                .map(|rule| AclRuleStats {
+                    subnet: rule.subnet.to_string(),
+                    action: rule.action,
+                    matches: Default::default(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AclRuleStats {
+    pub subnet: String,
+    pub action: AclAction,
+    pub matches: WrappedCounter,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -55,46 +132,216 @@ impl std::ops::Deref for WrappedCounter {
     }
 }
 
+/// How the servers on anycast addresses answer while they are drained, before
+/// the anycast route to them is removed for maintenance
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum DrainMode {
+    /// Serve as usual
+    #[default]
+    Off,
+    /// Answer time requests with a RATE kiss code, so that clients back off
+    /// until the route leads to another server
+    Kod,
+    /// Leave time requests unanswered, so that health checks of the route fail
+    Withdraw,
+}
+
+/// Handle to the drain mode of the servers, cheap to clone
+#[derive(Debug, Clone, Default)]
+pub struct Drain(Arc<AtomicU8>);
+
+impl Drain {
+    pub fn mode(&self) -> DrainMode {
+        match self.0.load(Ordering::Relaxed) {
+            1 => DrainMode::Kod,
+            2 => DrainMode::Withdraw,
+            _ => DrainMode::Off,
+        }
+    }
+
+    pub fn set(&self, mode: DrainMode) {
+        let value = match mode {
+            DrainMode::Off => 0,
+            DrainMode::Kod => 1,
+            DrainMode::Withdraw => 2,
+        };
+        self.0.store(value, Ordering::Relaxed);
+    }
+}
+
 pub struct ServerTask<C: 'static + NtpClock + Send> {
     config: ServerConfig,
     network_wait_period: std::time::Duration,
-    system: Arc<RwLock<SystemSnapshot>>,
+    network: NetworkConfig,
+    /// Whether the socket shares its address with those of the other workers
+    reuse_port: bool,
+    system: Arc<ArcSwap<SystemSnapshot>>,
+    /// Whether to try receiving through io_uring, until it turns out to be unavailable
+    #[cfg(feature = "io-uring")]
+    io_uring: bool,
+    associations: Associations,
+    /// Keys with which signed requests are verified, and their responses signed
+    keys: Keys,
+    /// Where the bytes of rejected requests are logged
+    capture: PacketCapture,
+    /// Only applies to servers on anycast addresses
+    drain: Drain,
     client_cache: TimestampedCache<SocketAddr>,
     clock: C,
     stats: ServerStats,
+    clients: ClientList,
+}
+
+/// Stops the workers of a server when the server itself is stopped
+struct AbortOnDrop(Vec<JoinHandle<()>>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for handle in &self.0 {
+            handle.abort();
+        }
+    }
+}
+
+/// Take the transmit timestamp of a serialized time response again, as close
+/// as possible to the moment it is sent
+fn stamp_transmit<C: NtpClock>(clock: &C, buf: &mut [u8]) {
+    match clock.now() {
+        Ok(now) => NtpPacket::stamp_transmit_timestamp(buf, now),
+        // the timestamp taken when the response was built is still valid
+        Err(error) => warn!(?error, "Could not read the clock to timestamp a response"),
+    }
+}
+
+/// What to send back for a received packet
+enum Response {
+    None,
+    Ntp([u8; MAX_NTP_PACKET_SIZE], usize, SocketAddr),
+    /// A time response, of which the transmit timestamp is taken again right
+    /// before it is sent
+    Timestamp([u8; MAX_NTP_PACKET_SIZE], usize, SocketAddr),
+    Control(Vec<Vec<u8>>, SocketAddr),
+    NetworkGone,
+}
+
+impl Response {
+    /// The packets to send, with their destination
+    fn packets(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        let (single, fragments, peer_addr) = match self {
+            Response::Ntp(buf, len, peer_addr) | Response::Timestamp(buf, len, peer_addr) => {
+                (Some(&buf[..*len]), &[][..], Some(*peer_addr))
+            }
+            Response::Control(fragments, peer_addr) => (None, &fragments[..], Some(*peer_addr)),
+            Response::NetworkGone | Response::None => (None, &[][..], None),
+        };
+
+        let fragments = fragments.iter().map(Vec::as_slice);
+        single
+            .into_iter()
+            .chain(fragments)
+            .filter_map(move |packet| Some((packet, peer_addr?)))
+    }
+}
+
+/// Fill `chunk` with the packets of `responses`, from `position` on, which is
+/// the index of a response and of a packet within it. Returns the number of
+/// packets in the chunk, 0 once all have been handed out.
+fn fill_chunk<'a>(
+    responses: &'a [Response],
+    position: &mut (usize, usize),
+    chunk: &mut [(&'a [u8], SocketAddr)],
+) -> usize {
+    let mut len = 0;
+    while len < chunk.len() && position.0 < responses.len() {
+        match responses[position.0].packets().nth(position.1) {
+            Some(packet) => {
+                chunk[len] = packet;
+                len += 1;
+                position.1 += 1;
+            }
+            None => *position = (position.0 + 1, 0),
+        }
+    }
+
+    len
 }
 
 #[derive(Debug)]
 enum AcceptResult<'a> {
-    Accept(NtpPacket<'a>, SocketAddr, NtpTimestamp),
+    /// A time request, and the key it is signed with
+    Accept(
+        NtpPacket<'a>,
+        SocketAddr,
+        NtpTimestamp,
+        Option<SymmetricKey>,
+    ),
     Ignore,
     Deny(NtpPacket<'a>, SocketAddr),
     RateLimit(NtpPacket<'a>, SocketAddr),
+    /// A time request while the server is drained with kiss codes
+    Drain(NtpPacket<'a>, SocketAddr),
+    Control(ControlMessage<'a>, SocketAddr),
     NetworkGone,
 }
 
 impl<C: 'static + NtpClock + Send> ServerTask<C> {
+    #[allow(clippy::too_many_arguments)]
     pub fn spawn(
         config: ServerConfig,
         stats: ServerStats,
+        clients: ClientList,
         system: Arc<RwLock<SystemSnapshot>>,
+        associations: Associations,
+        keys: Keys,
+        capture: PacketCapture,
+        drain: Drain,
         clock: C,
         network_wait_period: Duration,
+        network: NetworkConfig,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
             let rate_limiting_cutoff = config.rate_limiting_cutoff;
             let rate_limiting_cache_size = config.rate_limiting_cache_size;
-
-            let mut process = ServerTask {
-                config,
-                network_wait_period,
-                system,
-                clock,
-                client_cache: TimestampedCache::new(rate_limiting_cache_size),
-                stats,
-            };
-
-            process.serve(rate_limiting_cutoff).await
+            let workers = config.worker_count();
+
+            // the workers read the system snapshot without taking a lock, from a
+            // copy that is kept up to date below
+            let snapshot = Arc::new(ArcSwap::from_pointee(*system.read().await));
+
+            // every worker has its own socket and rate limiting state. The kernel
+            // sends all packets of a client to the same socket, so rate limiting
+            // still sees all of them.
+            let handles = (0..workers)
+                .map(|_| {
+                    let mut process = ServerTask {
+                        config: config.clone(),
+                        network_wait_period,
+                        network,
+                        reuse_port: workers > 1,
+                        system: snapshot.clone(),
+                        #[cfg(feature = "io-uring")]
+                        io_uring: true,
+                        associations: associations.clone(),
+                        keys: keys.clone(),
+                        capture: capture.clone(),
+                        drain: drain.clone(),
+                        clock: clock.clone(),
+                        client_cache: TimestampedCache::new(rate_limiting_cache_size),
+                        stats: stats.clone(),
+                        clients: clients.clone(),
+                    };
+
+                    tokio::spawn(async move { process.serve(rate_limiting_cutoff).await })
+                })
+                .collect();
+            let _workers = AbortOnDrop(handles);
+
+            let mut refresh = tokio::time::interval(SNAPSHOT_REFRESH_INTERVAL);
+            loop {
+                refresh.tick().await;
+                snapshot.store(Arc::new(*system.read().await));
+            }
         })
     }
 
@@ -110,6 +357,15 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
         }
     }
 
+    /// The access control action for a client, counting the match
+    fn acl(&self, addr: &IpAddr) -> AclAction {
+        let (index, action) = self.config.acl_lookup(addr);
+        if let Some(rule) = index.and_then(|index| self.stats.acl_matches.get(index)) {
+            rule.matches.inc();
+        }
+        action
+    }
+
     #[instrument(level = "debug", skip(self), fields(
         addr = debug(self.config.addr),
     ))]
@@ -120,8 +376,23 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
                 socket
             } else {
                 cur_socket = Some(loop {
-                    match UdpSocket::server(self.config.addr).await {
-                        Ok(socket) => break socket,
+                    let socket = if self.reuse_port {
+                        UdpSocket::server_reuse_port(self.config.addr).await
+                    } else {
+                        UdpSocket::server(self.config.addr).await
+                    };
+
+                    match socket {
+                        Ok(socket) => {
+                            self.network.apply(&socket);
+                            // sockets that share their address with those of
+                            // other workers would take part of the requests
+                            // while stored
+                            if !self.reuse_port {
+                                systemd::store_socket(socket.as_ref());
+                            }
+                            break socket;
+                        }
                         Err(error) => {
                             warn!(?error, "Could not open server socket");
                             tokio::time::sleep(self.network_wait_period).await;
@@ -131,72 +402,270 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
                 cur_socket.as_ref().unwrap()
             };
 
-            let mut buf = [0_u8; 48];
-            let recv_res = socket.recv(&mut buf).await;
-            self.stats.received_packets.inc();
-            let accept_result = self.accept_packet(rate_limiting_cutoff, recv_res, &buf);
-
-            match accept_result {
-                AcceptResult::Accept(packet, peer_addr, recv_timestamp) => {
-                    self.stats.accepted_packets.inc();
-                    let system = *self.system.read().await;
-
-                    let response =
-                        NtpPacket::timestamp_response(&system, packet, recv_timestamp, &self.clock);
-                    let mut cursor = Cursor::new([0; 48]);
-                    if let Err(serialize_err) = response.serialize(&mut cursor) {
-                        error!(error=?serialize_err, "Could not serialize response");
+            #[cfg(feature = "io-uring")]
+            if self.io_uring {
+                match self.serve_io_uring(socket, rate_limiting_cutoff).await {
+                    Ok(()) => {
+                        error!("Server connection gone");
+                        cur_socket = None;
                         continue;
                     }
+                    Err(error) => {
+                        warn!(
+                            ?error,
+                            "Could not use io_uring, falling back to regular sockets"
+                        );
+                        self.io_uring = false;
+                    }
+                }
+            }
 
-                    if let Err(send_err) = socket
-                        .send_to(&cursor.get_ref()[0..cursor.position() as usize], peer_addr)
-                        .await
-                    {
+            if self.config.batch_size > 1 {
+                self.serve_batched(socket, rate_limiting_cutoff).await;
+                error!("Server connection gone");
+                cur_socket = None;
+                continue;
+            }
+
+            let mut buf = [0_u8; MAX_PACKET_SIZE];
+            let recv_res = socket.recv(&mut buf).await.map(|(size, addr, timestamp)| {
+                let timestamp = timestamp.map(|ts| self.clock.adjust_system_timestamp(ts));
+                (size, addr, timestamp)
+            });
+            self.stats.received_packets.inc();
+            let accept_result = self.accept_packet(rate_limiting_cutoff, recv_res, &buf);
+
+            match self.response(accept_result) {
+                Response::Ntp(buf, len, peer_addr) => {
+                    if let Err(send_err) = socket.send_to(&buf[..len], peer_addr).await {
                         self.stats.response_send_errors.inc();
                         warn!(error=?send_err, "Could not send response packet");
                     }
                 }
-                AcceptResult::Deny(packet, peer_addr) => {
-                    self.stats.denied_packets.inc();
-                    let response = NtpPacket::deny_response(packet);
-                    let mut cursor = Cursor::new([0; 48]);
-                    if let Err(serialize_err) = response.serialize(&mut cursor) {
-                        self.stats.response_send_errors.inc();
-                        error!(error=?serialize_err, "Could not serialize response");
-                        continue;
-                    }
-                    if let Err(send_err) = socket
-                        .send_to(&cursor.get_ref()[0..cursor.position() as usize], peer_addr)
-                        .await
+                Response::Timestamp(mut buf, len, peer_addr) => {
+                    let clock = self.clock.clone();
+                    let stamp = move |buf: &mut [u8]| stamp_transmit(&clock, buf);
+                    if let Err(send_err) =
+                        socket.send_to_with(&mut buf[..len], peer_addr, stamp).await
                     {
                         self.stats.response_send_errors.inc();
-                        warn!(error=?send_err, "Could not send deny packet");
+                        warn!(error=?send_err, "Could not send response packet");
+                    }
+                }
+                Response::Control(fragments, peer_addr) => {
+                    for fragment in fragments {
+                        if let Err(send_err) = socket.send_to(&fragment, peer_addr).await {
+                            self.stats.response_send_errors.inc();
+                            warn!(error=?send_err, "Could not send control response");
+                            break;
+                        }
                     }
                 }
-                AcceptResult::NetworkGone => {
+                Response::NetworkGone => {
                     error!("Server connection gone");
                     cur_socket = None;
+                }
+                Response::None => {}
+            }
+        }
+    }
+
+    /// Serve packets in batches, receiving all available packets up to the
+    /// batch size with a single system call, and sending all responses with
+    /// another. Returns when the network is gone.
+    async fn serve_batched(&mut self, socket: &UdpSocket, rate_limiting_cutoff: Duration) {
+        let batch_size = self.config.batch_size.min(MAX_BATCH_SIZE);
+        let mut bufs = vec![[0_u8; MAX_PACKET_SIZE]; batch_size];
+        let mut received = Vec::with_capacity(batch_size);
+        let mut responses = Vec::with_capacity(batch_size);
+
+        loop {
+            received.clear();
+            match socket.recv_batch(&mut bufs, &mut received).await {
+                Ok(count) => {
+                    self.stats.receive_batches.inc();
+                    if count == batch_size {
+                        self.stats.full_receive_batches.inc();
+                    }
+                }
+                Err(receive_error) => {
+                    self.stats.received_packets.inc();
+                    let accept_result =
+                        self.accept_packet(rate_limiting_cutoff, Err(receive_error), &[]);
+                    if let AcceptResult::NetworkGone = accept_result {
+                        return;
+                    }
                     continue;
                 }
-                AcceptResult::RateLimit(packet, peer_addr) => {
-                    self.stats.rate_limited_packets.inc();
-                    let response = NtpPacket::rate_limit_response(packet);
-                    let mut cursor = Cursor::new([0; 48]);
-                    if let Err(serialize_err) = response.serialize(&mut cursor) {
-                        self.stats.response_send_errors.inc();
-                        error!(error=?serialize_err, "Could not serialize response");
-                        continue;
+            }
+
+            responses.clear();
+            for (recv_res, buf) in received.iter().zip(bufs.iter()) {
+                self.stats.received_packets.inc();
+                let accept_result = self.accept_packet(rate_limiting_cutoff, Ok(*recv_res), buf);
+                responses.push(self.response(accept_result));
+            }
+
+            // all time responses of the batch are sent with a single system
+            // call, so they are timestamped right before it
+            for response in &mut responses {
+                if let Response::Timestamp(buf, len, _) = response {
+                    stamp_transmit(&self.clock, &mut buf[..*len]);
+                }
+            }
+
+            // send in chunks that are kept on the stack, such that serving
+            // the batch does not allocate
+            let mut position = (0, 0);
+            loop {
+                let mut chunk = [(&[][..], SocketAddr::from(([0; 4], 0))); SEND_CHUNK_SIZE];
+                let len = fill_chunk(&responses, &mut position, &mut chunk);
+                if len == 0 {
+                    break;
+                }
+
+                let mut unsent = &chunk[..len];
+                while !unsent.is_empty() {
+                    match socket.send_batch(unsent).await {
+                        Ok(sent) => unsent = &unsent[sent..],
+                        Err(send_err) => {
+                            // skip the packet that could not be sent
+                            self.stats.response_send_errors.inc();
+                            warn!(error=?send_err, "Could not send response packet");
+                            unsent = &unsent[1..];
+                        }
                     }
-                    if let Err(send_err) = socket
-                        .send_to(&cursor.get_ref()[0..cursor.position() as usize], peer_addr)
-                        .await
-                    {
+                }
+            }
+        }
+    }
+
+    /// Serve batches of packets through io_uring. Returns when the network is
+    /// gone, and with an error when io_uring cannot be used for the socket.
+    #[cfg(feature = "io-uring")]
+    async fn serve_io_uring(
+        &mut self,
+        socket: &UdpSocket,
+        rate_limiting_cutoff: Duration,
+    ) -> std::io::Result<()> {
+        let mut uring = ntp_udp::UringSocket::new(socket)?;
+        let mut network_gone = false;
+
+        while !network_gone {
+            let send_errors = uring
+                .recv_batch(|recv_res, buf, sends| {
+                    self.stats.received_packets.inc();
+                    let accept_result = self.accept_packet(rate_limiting_cutoff, recv_res, buf);
+
+                    let send_res = match self.response(accept_result) {
+                        Response::Ntp(buf, len, peer_addr) => sends.send_to(&buf[..len], peer_addr),
+                        Response::Timestamp(mut buf, len, peer_addr) => {
+                            stamp_transmit(&self.clock, &mut buf[..len]);
+                            sends.send_to(&buf[..len], peer_addr)
+                        }
+                        Response::Control(fragments, peer_addr) => fragments
+                            .iter()
+                            .try_for_each(|fragment| sends.send_to(fragment, peer_addr)),
+                        Response::NetworkGone => {
+                            network_gone = true;
+                            Ok(())
+                        }
+                        Response::None => Ok(()),
+                    };
+
+                    if let Err(send_err) = send_res {
                         self.stats.response_send_errors.inc();
                         warn!(error=?send_err, "Could not send response packet");
                     }
+                })
+                .await?;
+
+            if send_errors > 0 {
+                self.stats.response_send_errors.inc_by(send_errors as u64);
+                warn!(send_errors, "Could not send response packets");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The packets to send back for a received packet
+    fn response(&mut self, accept_result: AcceptResult<'_>) -> Response {
+        match accept_result {
+            AcceptResult::Accept(packet, peer_addr, recv_timestamp, key) => {
+                self.stats.accepted_packets.inc();
+                let system = match self.config.anycast {
+                    Some(anycast) => anycast.advertise(**self.system.load()),
+                    None => **self.system.load(),
+                };
+
+                let response =
+                    NtpPacket::timestamp_response(&system, packet, recv_timestamp, &self.clock);
+                match self.serialize(response, key.as_ref(), peer_addr) {
+                    // the transmit timestamp is covered by the MAC, so a signed
+                    // response is sent as it is
+                    Response::Ntp(buf, len, peer_addr) if key.is_none() => {
+                        Response::Timestamp(buf, len, peer_addr)
+                    }
+                    response => response,
                 }
-                AcceptResult::Ignore => {}
+            }
+            AcceptResult::Deny(packet, peer_addr) => {
+                self.stats.denied_packets.inc();
+                self.stats.kiss_codes_sent.inc();
+                let response = NtpPacket::deny_response(packet);
+                self.serialize(response, None, peer_addr)
+            }
+            AcceptResult::RateLimit(packet, peer_addr) => {
+                self.stats.rate_limited_packets.inc();
+                self.stats.kiss_codes_sent.inc();
+                let response = NtpPacket::rate_limit_response(packet);
+                self.serialize(response, None, peer_addr)
+            }
+            AcceptResult::Drain(packet, peer_addr) => {
+                self.stats.drained_packets.inc();
+                self.stats.kiss_codes_sent.inc();
+                let response = NtpPacket::rate_limit_response(packet);
+                self.serialize(response, None, peer_addr)
+            }
+            AcceptResult::Control(request, peer_addr) => {
+                self.stats.accepted_packets.inc();
+                let system = **self.system.load();
+
+                let response = {
+                    // A poisoned lock only means a writer panicked, the
+                    // view itself is always complete
+                    let associations = match self.associations.read() {
+                        Ok(guard) => guard,
+                        Err(poisoned) => poisoned.into_inner(),
+                    };
+                    control::respond(&request, &system, &associations)
+                };
+
+                Response::Control(response.serialize_fragments(), peer_addr)
+            }
+            AcceptResult::NetworkGone => Response::NetworkGone,
+            AcceptResult::Ignore => Response::None,
+        }
+    }
+
+    fn serialize(
+        &self,
+        packet: NtpPacket,
+        key: Option<&SymmetricKey>,
+        peer_addr: SocketAddr,
+    ) -> Response {
+        let mut buf = [0; MAX_NTP_PACKET_SIZE];
+        let result = match key {
+            Some(key) => packet.serialize_signed_into(&mut buf, key),
+            None => packet.serialize_into(&mut buf),
+        };
+        match result {
+            Ok(len) => Response::Ntp(buf, len, peer_addr),
+            Err(serialize_err) => {
+                self.stats.response_send_errors.inc();
+                error!(error=?serialize_err, "Could not serialize response");
+                Response::None
             }
         }
     }
@@ -205,43 +674,83 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
         &'b mut self,
         rate_limiting_cutoff: Duration,
         result: Result<(usize, SocketAddr, Option<NtpTimestamp>), std::io::Error>,
-        buf: &'a [u8; 48],
+        buf: &'a [u8],
     ) -> AcceptResult<'a> {
+        // access control comes before anything else is done with the packet
+        let stateless = match &result {
+            Ok((_, peer_addr, _)) => match self.acl(&peer_addr.ip()) {
+                AclAction::Serve => {
+                    self.clients.record(peer_addr.ip(), Instant::now());
+                    false
+                }
+                AclAction::ServeStateless => true,
+                AclAction::Ignore => {
+                    trace!("packet ignored from {} by access control", peer_addr);
+                    self.stats.ignored_packets.inc();
+                    return AcceptResult::Ignore;
+                }
+            },
+            Err(_) => false,
+        };
+
         match result {
+            Ok((size, peer_addr, Some(_)))
+                if ControlMessage::is_control(&buf[..size.min(buf.len())]) =>
+            {
+                if stateless {
+                    self.stats.ignored_packets.inc();
+                    return AcceptResult::Ignore;
+                }
+                self.accept_control(rate_limiting_cutoff, &buf[..size.min(buf.len())], peer_addr)
+            }
             Ok((size, peer_addr, Some(recv_timestamp))) if size >= 48 => {
                 // Note: packets are allowed to be bigger when including extensions.
-                // we don't expect them, but the client may still send them. The
-                // extra bytes are guaranteed safe to ignore. `recv` truncates the messages.
+                // `recv` truncates them to the buffer, and parsing stops at the
+                // extension field limits, so the work per packet is bounded.
                 // Messages of fewer than 48 bytes are skipped entirely
-                match self.filter(&peer_addr.ip()) {
+                let buf = &buf[..size.min(buf.len())];
+                let accept_result = match self.filter(&peer_addr.ip()) {
                     Some(FilterAction::Deny) => {
                         match self.accept_data(buf, peer_addr, recv_timestamp) {
                             // We should send deny messages only to reasonable requests
                             // otherwise two servers could end up in a loop of sending
                             // deny's to each other.
-                            AcceptResult::Accept(packet, addr, _) => {
+                            AcceptResult::Accept(packet, addr, _, _) => {
                                 AcceptResult::Deny(packet, addr)
                             }
                             v => v,
                         }
                     }
-                    Some(FilterAction::Ignore) => AcceptResult::Ignore,
+                    Some(FilterAction::Ignore) => {
+                        self.stats.ignored_packets.inc();
+                        AcceptResult::Ignore
+                    }
                     None => {
                         let timestamp = Instant::now();
                         let cutoff = rate_limiting_cutoff;
-                        let too_soon = !self.client_cache.is_allowed(peer_addr, timestamp, cutoff);
+                        let too_soon = !stateless
+                            && !self.client_cache.is_allowed(peer_addr, timestamp, cutoff);
 
                         match self.accept_data(buf, peer_addr, recv_timestamp) {
-                            AcceptResult::Accept(packet, _, _) if too_soon => {
+                            AcceptResult::Accept(packet, _, _, _) if too_soon => {
                                 AcceptResult::RateLimit(packet, peer_addr)
                             }
                             accept_result => accept_result,
                         }
                     }
-                }
+                };
+
+                self.drained(accept_result)
             }
-            Ok((size, _, Some(_))) => {
-                info!(expected = 48, actual = size, "received packet is too small");
+            Ok((size, peer_addr, Some(_))) => {
+                // logging every bogus packet would make a flood of them expensive
+                trace!(expected = 48, actual = size, "received packet is too small");
+                self.stats.malformed_packets.inc();
+                self.capture.rejected(
+                    peer_addr,
+                    &buf[..size.min(buf.len())],
+                    "packet is too small",
+                );
 
                 AcceptResult::Ignore
             }
@@ -269,33 +778,180 @@ impl<C: 'static + NtpClock + Send> ServerTask<C> {
         }
     }
 
+    fn accept_control<'a>(
+        &mut self,
+        rate_limiting_cutoff: Duration,
+        buf: &'a [u8],
+        peer_addr: SocketAddr,
+    ) -> AcceptResult<'a> {
+        if !self.config.control || self.config.policy == ServerPolicy::Strict {
+            trace!("control message ignored from {}", peer_addr);
+            self.stats.ignored_packets.inc();
+            return AcceptResult::Ignore;
+        }
+
+        // Responses to control messages can be larger than the requests, so
+        // rather than sending deny or rate limit responses they are ignored
+        if self.filter(&peer_addr.ip()).is_some()
+            || !self
+                .client_cache
+                .is_allowed(peer_addr, Instant::now(), rate_limiting_cutoff)
+        {
+            self.stats.ignored_packets.inc();
+            return AcceptResult::Ignore;
+        }
+
+        match ControlMessage::deserialize(buf) {
+            Ok(request) => {
+                trace!("control message accepted from {}", peer_addr);
+                AcceptResult::Control(request, peer_addr)
+            }
+            Err(e) => {
+                trace!("received invalid control message: {}", e);
+                self.count_parsing_error(e);
+                AcceptResult::Ignore
+            }
+        }
+    }
+
     fn accept_data<'a, 'b>(
         &'b self,
-        buf: &'a [u8; 48],
+        buf: &'a [u8],
         peer_addr: SocketAddr,
         recv_timestamp: NtpTimestamp,
     ) -> AcceptResult<'a> {
-        match NtpPacket::deserialize(buf) {
-            Ok(packet) => match packet.mode() {
-                NtpAssociationMode::Client => {
-                    trace!("NTP client request accepted from {}", peer_addr);
-                    AcceptResult::Accept(packet, peer_addr, recv_timestamp)
+        match NtpPacket::deserialize_with_limits(buf, self.config.extension_field_limits()) {
+            Ok(packet) if self.config.policy == ServerPolicy::Strict && packet.version() < 4 => {
+                trace!(
+                    "NTPv{} packet ignored from {} by strict policy",
+                    packet.version(),
+                    peer_addr
+                );
+                self.stats.unsupported_version_packets.inc();
+                AcceptResult::Ignore
+            }
+            Ok(packet)
+                if self.config.policy == ServerPolicy::Strict
+                    && packet.mode() != NtpAssociationMode::Client =>
+            {
+                trace!(
+                    "NTP packet with mode {:?} ignored from {} by strict policy",
+                    packet.mode(),
+                    peer_addr
+                );
+                self.stats.ignored_packets.inc();
+                AcceptResult::Ignore
+            }
+            Ok(packet) => {
+                if let Some(nonconformance) = packet.nonconformance() {
+                    self.stats.nonconforming_packets.inc();
+                    if self.config.parsing == ParsingPolicy::Strict {
+                        trace!(
+                            "nonconforming packet ignored from {}: {}",
+                            peer_addr,
+                            nonconformance
+                        );
+                        self.capture.rejected(peer_addr, buf, nonconformance);
+                        return AcceptResult::Ignore;
+                    }
                 }
-                _ => {
-                    trace!(
-                        "NTP packet with unkown mode {:?} ignored from {}",
-                        packet.mode(),
-                        peer_addr
-                    );
-                    AcceptResult::Ignore
+
+                match packet.mode() {
+                    NtpAssociationMode::Client => {
+                        let key = self.authenticate(&packet, buf, peer_addr, recv_timestamp);
+                        if self.config.policy == ServerPolicy::Strict && key.is_none() {
+                            trace!(
+                                "unauthenticated NTP client request ignored from {} by strict policy",
+                                peer_addr
+                            );
+                            self.stats.ignored_packets.inc();
+                            return AcceptResult::Ignore;
+                        }
+
+                        trace!("NTP client request accepted from {}", peer_addr);
+                        AcceptResult::Accept(packet, peer_addr, recv_timestamp, key)
+                    }
+                    NtpAssociationMode::SymmetricActive
+                        if self.config.answers_symmetric(&peer_addr.ip()) =>
+                    {
+                        trace!("NTP symmetric active request accepted from {}", peer_addr);
+                        let key = self.authenticate(&packet, buf, peer_addr, recv_timestamp);
+                        AcceptResult::Accept(packet, peer_addr, recv_timestamp, key)
+                    }
+                    _ => {
+                        trace!(
+                            "NTP packet with unkown mode {:?} ignored from {}",
+                            packet.mode(),
+                            peer_addr
+                        );
+                        self.stats.ignored_packets.inc();
+                        self.capture.rejected(
+                            peer_addr,
+                            buf,
+                            format_args!("unexpected mode {:?}", packet.mode()),
+                        );
+                        AcceptResult::Ignore
+                    }
                 }
-            },
+            }
             Err(e) => {
-                info!("received invalid packet: {}", e);
+                trace!("received invalid packet: {}", e);
+                self.capture.rejected(peer_addr, buf, e);
+                self.count_parsing_error(e);
                 AcceptResult::Ignore
             }
         }
     }
+
+    /// The answer to a time request while the server is drained. Servers
+    /// that are not on an anycast address are never drained.
+    fn drained<'a>(&self, accept_result: AcceptResult<'a>) -> AcceptResult<'a> {
+        if self.config.anycast.is_none() {
+            return accept_result;
+        }
+
+        match (self.drain.mode(), accept_result) {
+            (DrainMode::Kod, AcceptResult::Accept(packet, peer_addr, _, _)) => {
+                AcceptResult::Drain(packet, peer_addr)
+            }
+            (
+                DrainMode::Withdraw,
+                AcceptResult::Accept(..) | AcceptResult::Deny(..) | AcceptResult::RateLimit(..),
+            ) => {
+                self.stats.drained_packets.inc();
+                AcceptResult::Ignore
+            }
+            (_, accept_result) => accept_result,
+        }
+    }
+
+    /// The key with which a signed request was made, to sign the response
+    /// with. A request of which the MAC does not check out is answered without
+    /// one, which its client does not accept.
+    fn authenticate(
+        &self,
+        packet: &NtpPacket,
+        data: &[u8],
+        peer_addr: SocketAddr,
+        recv_timestamp: NtpTimestamp,
+    ) -> Option<SymmetricKey> {
+        packet.key_id()?;
+        match self.keys.current().verify(packet, data, recv_timestamp) {
+            Ok(key) => Some(key.clone()),
+            Err(error) => {
+                trace!(%error, "request is not authentic");
+                self.capture.rejected(peer_addr, data, error);
+                None
+            }
+        }
+    }
+
+    fn count_parsing_error(&self, error: PacketParsingError) {
+        match error {
+            PacketParsingError::InvalidVersion(_) => self.stats.unsupported_version_packets.inc(),
+            _ => self.stats.malformed_packets.inc(),
+        };
+    }
 }
 
 /// A size-bounded cache where each entry is timestamped.
@@ -368,9 +1024,12 @@ impl<T: std::hash::Hash + Eq> TimestampedCache<T> {
 mod tests {
     use std::time::Duration;
 
-    use ntp_proto::{NtpDuration, NtpLeapIndicator, PollInterval, PollIntervalLimits, ReferenceId};
+    use ntp_proto::{
+        KeyAlgorithm, KeySet, NtpDuration, NtpLeapIndicator, PollInterval, PollIntervalLimits,
+        ReferenceId,
+    };
 
-    use crate::ipfilter::IpFilter;
+    use crate::{config::AclRule, ipfilter::IpFilter};
 
     use super::*;
 
@@ -422,6 +1081,18 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
         };
         let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
         let clock = TestClock {};
@@ -429,9 +1100,15 @@ mod tests {
         let server = ServerTask::spawn(
             config,
             Default::default(),
+            Default::default(),
             system_snapshots,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
             clock,
             Duration::from_secs(1),
+            Default::default(),
         );
 
         let mut socket = UdpSocket::client(
@@ -457,6 +1134,74 @@ mod tests {
         server.abort();
     }
 
+    #[tokio::test]
+    async fn test_server_symmetric_passive() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9040".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 32,
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::new(&["127.0.0.1/32".parse().unwrap()]),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
+        };
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+        let clock = TestClock {};
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            Default::default(),
+            system_snapshots.clone(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            clock,
+            Duration::from_secs(1),
+            Default::default(),
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9041".parse().unwrap(),
+            "127.0.0.1:9040".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let (packet, id) = NtpPacket::symmetric_poll_message_at(
+            &*system_snapshots.read().await,
+            PollIntervalLimits::default().min,
+            NtpTimestamp::default(),
+            64,
+        );
+        let mut pdata = vec![];
+        packet.serialize(&mut pdata).unwrap();
+
+        socket.send(&pdata).await.unwrap();
+        let mut buf = [0; 48];
+        tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = NtpPacket::deserialize(&buf).unwrap();
+        assert_eq!(packet.mode(), NtpAssociationMode::SymmetricPassive);
+        assert!(packet.valid_server_response(id));
+
+        server.abort();
+    }
+
     #[tokio::test]
     async fn test_server_filter_allow_deny() {
         let config = ServerConfig {
@@ -467,6 +1212,18 @@ mod tests {
             allowlist_action: FilterAction::Deny,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
         };
         let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
         let clock = TestClock {};
@@ -474,9 +1231,15 @@ mod tests {
         let server = ServerTask::spawn(
             config,
             Default::default(),
+            Default::default(),
             system_snapshots,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
             clock,
             Duration::from_secs(1),
+            Default::default(),
         );
 
         let mut socket = UdpSocket::client(
@@ -513,6 +1276,18 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
         };
         let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
         let clock = TestClock {};
@@ -520,9 +1295,15 @@ mod tests {
         let server = ServerTask::spawn(
             config,
             Default::default(),
+            Default::default(),
             system_snapshots,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
             clock,
             Duration::from_secs(1),
+            Default::default(),
         );
 
         let mut socket = UdpSocket::client(
@@ -553,6 +1334,18 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
         };
         let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
         let clock = TestClock {};
@@ -560,9 +1353,15 @@ mod tests {
         let server = ServerTask::spawn(
             config,
             Default::default(),
+            Default::default(),
             system_snapshots,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
             clock,
             Duration::from_secs(1),
+            Default::default(),
         );
 
         let mut socket = UdpSocket::client(
@@ -598,6 +1397,18 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
         };
         let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
         let clock = TestClock {};
@@ -605,9 +1416,15 @@ mod tests {
         let server = ServerTask::spawn(
             config,
             Default::default(),
+            Default::default(),
             system_snapshots,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
             clock,
             Duration::from_secs(1),
+            Default::default(),
         );
 
         let mut socket = UdpSocket::client(
@@ -644,6 +1461,18 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_secs(1),
             rate_limiting_cache_size: 32,
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
         };
         let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
         let clock = TestClock {};
@@ -651,10 +1480,16 @@ mod tests {
         let server = ServerTask::spawn(
             config,
             Default::default(),
+            Default::default(),
             system_snapshots,
-            clock,
-            Duration::from_secs(1),
-        );
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            clock,
+            Duration::from_secs(1),
+            Default::default(),
+        );
 
         let mut socket = UdpSocket::client(
             "127.0.0.1:9011".parse().unwrap(),
@@ -674,6 +1509,102 @@ mod tests {
         server.abort();
     }
 
+    #[tokio::test]
+    async fn test_server_acl() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9020".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 32,
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![
+                AclRule {
+                    subnet: "127.0.0.0/8".parse().unwrap(),
+                    action: AclAction::Ignore,
+                },
+                AclRule {
+                    subnet: "127.0.0.1/32".parse().unwrap(),
+                    action: AclAction::ServeStateless,
+                },
+            ],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
+        };
+        let stats = ServerStats::new(&config);
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+        let clock = TestClock {};
+
+        let server = ServerTask::spawn(
+            config,
+            stats.clone(),
+            Default::default(),
+            system_snapshots,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            clock,
+            Duration::from_secs(1),
+            Default::default(),
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9021".parse().unwrap(),
+            "127.0.0.1:9020".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // give the server time to open its socket
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // stateless clients are never rate limited
+        for _ in 0..2 {
+            let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+            let mut pdata = vec![];
+            packet.serialize(&mut pdata).unwrap();
+            socket.send(&pdata).await.unwrap();
+            let mut buf = [0; 48];
+            tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = NtpPacket::deserialize(&buf).unwrap();
+            assert_ne!(packet.stratum(), 0);
+            assert!(packet.valid_server_response(id));
+        }
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.2:9022".parse().unwrap(),
+            "127.0.0.1:9020".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let mut pdata = vec![];
+        packet.serialize(&mut pdata).unwrap();
+        socket.send(&pdata).await.unwrap();
+        let mut buf = [0; 48];
+        let res = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf)).await;
+        assert!(res.is_err());
+
+        assert_eq!(stats.acl_matches[0].matches.get(), 1);
+        assert_eq!(stats.acl_matches[1].matches.get(), 2);
+
+        server.abort();
+    }
+
     #[tokio::test]
     async fn test_server_rate_limit() {
         let config = ServerConfig {
@@ -684,6 +1615,18 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::from_millis(100),
             rate_limiting_cache_size: 32,
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
         };
         let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
         let clock = TestClock {};
@@ -691,9 +1634,15 @@ mod tests {
         let server = ServerTask::spawn(
             config,
             Default::default(),
+            Default::default(),
             system_snapshots,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
             clock,
             Duration::from_secs(1),
+            Default::default(),
         );
 
         let mut socket = UdpSocket::client(
@@ -758,6 +1707,18 @@ mod tests {
             allowlist_action: FilterAction::Ignore,
             rate_limiting_cutoff: Duration::default(),
             rate_limiting_cache_size: Default::default(),
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
         };
         let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
         let clock = TestClock {};
@@ -765,9 +1726,15 @@ mod tests {
         let server = ServerTask::spawn(
             config,
             Default::default(),
+            Default::default(),
             system_snapshots,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
             clock,
             Duration::from_secs(1),
+            Default::default(),
         );
 
         let mut socket = UdpSocket::client(
@@ -792,6 +1759,823 @@ mod tests {
 
         server.abort();
     }
+
+    #[tokio::test]
+    async fn test_server_strict_policy() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9016".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::default(),
+            rate_limiting_cache_size: Default::default(),
+            policy: ServerPolicy::Strict,
+            control: true,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::all(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
+        };
+        let key = SymmetricKey::new(1, KeyAlgorithm::Aes128Cmac, vec![7; 16]).unwrap();
+        let keys = Keys::from_key_set(KeySet::new(vec![key.clone()]));
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+        let clock = TestClock {};
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            Default::default(),
+            system_snapshots,
+            Default::default(),
+            keys,
+            Default::default(),
+            Default::default(),
+            clock,
+            Duration::from_secs(1),
+            Default::default(),
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9017".parse().unwrap(),
+            "127.0.0.1:9016".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // an NTPv3 request is dropped
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let mut pdata = vec![];
+        packet.serialize(&mut pdata).unwrap();
+        pdata[0] = (pdata[0] & !0x38) | (3 << 3);
+        socket.send(&pdata).await.unwrap();
+        let mut buf = [0; MAX_NTP_PACKET_SIZE];
+        let res = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf)).await;
+        assert!(res.is_err());
+
+        // as are unsigned NTPv4 requests, and those with a MAC that does not
+        // check out
+        let other = SymmetricKey::new(1, KeyAlgorithm::Aes128Cmac, vec![8; 16]).unwrap();
+        for signing_key in [None, Some(&other)] {
+            let (mut packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+            if let Some(signing_key) = signing_key {
+                packet.sign(signing_key);
+            }
+            let mut pdata = vec![];
+            packet.serialize(&mut pdata).unwrap();
+            socket.send(&pdata).await.unwrap();
+            let res = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf)).await;
+            assert!(res.is_err());
+        }
+
+        // but a signed NTPv4 request is answered
+        let (mut packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        packet.sign(&key);
+        let mut pdata = vec![];
+        packet.serialize(&mut pdata).unwrap();
+        socket.send(&pdata).await.unwrap();
+        let (size, _, _) = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = NtpPacket::deserialize(&buf[..size]).unwrap();
+        assert_eq!(packet.version(), 4);
+        assert!(packet.valid_server_response(id));
+        assert_eq!(packet.key_id(), Some(1));
+
+        // control messages are dropped, even when enabled
+        socket
+            .send(&[0x16, 0x01, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let res = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf)).await;
+        assert!(res.is_err());
+
+        // as are symmetric active requests, even from allowed peers, and
+        // private mode packets
+        for mode in [1, 7] {
+            let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+            let mut pdata = vec![];
+            packet.serialize(&mut pdata).unwrap();
+            pdata[0] = (pdata[0] & !0x07) | mode;
+            socket.send(&pdata).await.unwrap();
+            let res = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf)).await;
+            assert!(res.is_err());
+        }
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_control() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9018".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::default(),
+            rate_limiting_cache_size: Default::default(),
+            policy: ServerPolicy::Standard,
+            control: true,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
+        };
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+        let associations: Associations = Default::default();
+        associations
+            .write()
+            .unwrap()
+            .push(crate::control::Association {
+                id: 1,
+                address: "192.0.2.1:123".parse().unwrap(),
+                snapshot: None,
+                selected: false,
+            });
+        let clock = TestClock {};
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            Default::default(),
+            system_snapshots,
+            associations,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            clock,
+            Duration::from_secs(1),
+            Default::default(),
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9019".parse().unwrap(),
+            "127.0.0.1:9018".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // read status of the system, which lists the associations. The server
+        // might not be listening yet, so retry a few times
+        let mut buf = [0; 48];
+        let mut size = 0;
+        for _ in 0..10 {
+            socket
+                .send(&[0x16, 0x01, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            if let Ok(Ok((n, _, _))) =
+                tokio::time::timeout(Duration::from_millis(100), socket.recv(&mut buf)).await
+            {
+                size = n;
+                break;
+            }
+        }
+        assert_eq!(size, 16);
+        assert_eq!(buf[1], 0x81);
+        assert_eq!(&buf[12..16], &[0, 1, 0x80, 0]);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_workers() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9023".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::default(),
+            rate_limiting_cache_size: Default::default(),
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 3,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
+        };
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+        let stats = ServerStats::new(&config);
+        let clock = TestClock {};
+
+        let server = ServerTask::spawn(
+            config,
+            stats.clone(),
+            Default::default(),
+            system_snapshots.clone(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            clock,
+            Duration::from_secs(1),
+            Default::default(),
+        );
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        /// The stratum in the response to a request
+        async fn request(port: u16) -> u8 {
+            let mut socket = UdpSocket::client(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                "127.0.0.1:9023".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+            let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+            let mut pdata = vec![];
+            packet.serialize(&mut pdata).unwrap();
+
+            socket.send(&pdata).await.unwrap();
+            let mut buf = [0; 48];
+            tokio::time::timeout(Duration::from_millis(100), socket.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = NtpPacket::deserialize(&buf).unwrap();
+            assert!(packet.valid_server_response(id));
+            packet.stratum()
+        }
+
+        // clients are spread over the workers, which all answer
+        for port in 9024..9028 {
+            request(port).await;
+        }
+        assert_eq!(stats.accepted_packets.get(), 4);
+
+        // changes of the system are picked up by the workers
+        system_snapshots.write().await.stratum = 3;
+        tokio::time::sleep(SNAPSHOT_REFRESH_INTERVAL * 3).await;
+        assert_eq!(request(9028).await, 3);
+
+        // stopping the server stops all workers, freeing the address
+        server.abort();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(UdpSocket::server("127.0.0.1:9023".parse().unwrap())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_server_batch() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9029".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::default(),
+            rate_limiting_cache_size: Default::default(),
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 4,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
+        };
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+        let stats = ServerStats::new(&config);
+        let clock = TestClock {};
+
+        let server = ServerTask::spawn(
+            config,
+            stats.clone(),
+            Default::default(),
+            system_snapshots,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            clock,
+            Duration::from_secs(1),
+            Default::default(),
+        );
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // more requests than fit in a single batch, sent before any is answered
+        let mut clients = vec![];
+        for port in 9030..9036 {
+            let mut socket = UdpSocket::client(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                "127.0.0.1:9029".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+            let (packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+            let mut pdata = vec![];
+            packet.serialize(&mut pdata).unwrap();
+            socket.send(&pdata).await.unwrap();
+            clients.push((socket, id));
+        }
+
+        for (socket, id) in clients {
+            let mut buf = [0; 48];
+            tokio::time::timeout(Duration::from_millis(100), socket.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = NtpPacket::deserialize(&buf).unwrap();
+            assert!(packet.valid_server_response(id));
+        }
+
+        assert_eq!(stats.received_packets.get(), 6);
+        assert_eq!(stats.accepted_packets.get(), 6);
+        if !cfg!(feature = "io-uring") {
+            // io_uring takes precedence when available
+            let batches = stats.receive_batches.get();
+            assert!((2..=6).contains(&batches));
+            assert!(stats.full_receive_batches.get() <= 1);
+        }
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_packet_counters() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9042".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 32,
+            policy: ServerPolicy::Strict,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
+        };
+        let key = SymmetricKey::new(1, KeyAlgorithm::Aes128Cmac, vec![7; 16]).unwrap();
+        let keys = Keys::from_key_set(KeySet::new(vec![key.clone()]));
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+        let stats = ServerStats::new(&config);
+        let clock = TestClock {};
+
+        let server = ServerTask::spawn(
+            config,
+            stats.clone(),
+            Default::default(),
+            system_snapshots,
+            Default::default(),
+            keys,
+            Default::default(),
+            Default::default(),
+            clock,
+            Duration::from_secs(1),
+            Default::default(),
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9043".parse().unwrap(),
+            "127.0.0.1:9042".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        // the strict policy only answers signed requests
+        let (mut packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        packet.sign(&key);
+        let mut request = vec![];
+        packet.serialize(&mut request).unwrap();
+
+        socket.send(&request).await.unwrap();
+        let mut buf = [0; MAX_NTP_PACKET_SIZE];
+        tokio::time::timeout(Duration::from_millis(100), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(NtpPacket::deserialize(&buf)
+            .unwrap()
+            .valid_server_response(id));
+
+        // too short
+        socket.send(&request[..20]).await.unwrap();
+        // NTPv5 is not supported
+        let mut unsupported = request.clone();
+        unsupported[0] = (unsupported[0] & !0x38) | (5 << 3);
+        socket.send(&unsupported).await.unwrap();
+        // NTPv3 is not answered with the strict policy
+        let mut old = request.clone();
+        old[0] = (old[0] & !0x38) | (3 << 3);
+        socket.send(&old).await.unwrap();
+        // a server does not answer broadcasts
+        let mut broadcast = request.clone();
+        broadcast[0] = (broadcast[0] & !0x07) | 5;
+        socket.send(&broadcast).await.unwrap();
+
+        // too soon after the first request, and the only other one that is
+        // answered, so all others were handled by then
+        socket.send(&request).await.unwrap();
+        tokio::time::timeout(Duration::from_millis(100), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(NtpPacket::deserialize(&buf).unwrap().is_kiss_rate());
+
+        assert_eq!(stats.received_packets.get(), 6);
+        assert_eq!(stats.malformed_packets.get(), 1);
+        assert_eq!(stats.unsupported_version_packets.get(), 2);
+        assert_eq!(stats.ignored_packets.get(), 1);
+        assert_eq!(stats.rate_limited_packets.get(), 1);
+        assert_eq!(stats.kiss_codes_sent.get(), 1);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_parsing_policy() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9048".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::ZERO,
+            rate_limiting_cache_size: 32,
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: ParsingPolicy::Strict,
+            anycast: None,
+        };
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+        let stats = ServerStats::new(&config);
+        let clock = TestClock {};
+
+        let server = ServerTask::spawn(
+            config,
+            stats.clone(),
+            Default::default(),
+            system_snapshots,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            clock,
+            Duration::from_secs(1),
+            Default::default(),
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9049".parse().unwrap(),
+            "127.0.0.1:9048".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let mut request = vec![];
+        packet.serialize(&mut request).unwrap();
+
+        // a precision of 2 seconds is not a precision of a clock
+        let mut nonconforming = request.clone();
+        nonconforming[3] = 1;
+        socket.send(&nonconforming).await.unwrap();
+
+        let mut buf = [0; 48];
+        socket.send(&request).await.unwrap();
+        tokio::time::timeout(Duration::from_millis(100), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(stats.received_packets.get(), 2);
+        assert_eq!(stats.nonconforming_packets.get(), 1);
+        assert_eq!(stats.accepted_packets.get(), 1);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_authentication() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9044".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::ZERO,
+            rate_limiting_cache_size: 32,
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 0,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
+        };
+        let key = SymmetricKey::new(1, KeyAlgorithm::Aes128Cmac, vec![7; 16]).unwrap();
+        let keys = KeySet::new(vec![key.clone()]);
+        let system_snapshots = Arc::new(RwLock::new(SystemSnapshot::default()));
+
+        let server = ServerTask::spawn(
+            config,
+            Default::default(),
+            Default::default(),
+            system_snapshots,
+            Default::default(),
+            Keys::from_key_set(keys.clone()),
+            Default::default(),
+            Default::default(),
+            TestClock {},
+            Duration::from_secs(1),
+            Default::default(),
+        );
+
+        let mut socket = UdpSocket::client(
+            "127.0.0.1:9045".parse().unwrap(),
+            "127.0.0.1:9044".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        // a signed request gets a response signed with the same key
+        let (mut packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        packet.sign(&key);
+        let mut pdata = vec![];
+        packet.serialize(&mut pdata).unwrap();
+        socket.send(&pdata).await.unwrap();
+
+        let mut buf = [0; MAX_NTP_PACKET_SIZE];
+        let (size, _, _) = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = NtpPacket::deserialize(&buf[..size]).unwrap();
+        assert!(response.valid_server_response(id));
+        assert_eq!(
+            keys.verify(&response, &buf[..size], NtpTimestamp::default()),
+            Ok(&key)
+        );
+
+        // a request with a MAC that does not check out gets an unsigned one
+        let other = SymmetricKey::new(1, KeyAlgorithm::Aes128Cmac, vec![8; 16]).unwrap();
+        let (mut packet, id) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        packet.sign(&other);
+        let mut pdata = vec![];
+        packet.serialize(&mut pdata).unwrap();
+        socket.send(&pdata).await.unwrap();
+
+        let (size, _, _) = tokio::time::timeout(Duration::from_millis(10), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = NtpPacket::deserialize(&buf[..size]).unwrap();
+        assert!(response.valid_server_response(id));
+        assert_eq!(size, 48);
+        assert_eq!(response.key_id(), None);
+
+        server.abort();
+    }
+
+    /// A server that is not bound to a socket, to hand packets to directly
+    fn server_task(config: ServerConfig, keys: Keys, drain: Drain) -> ServerTask<TestClock> {
+        ServerTask {
+            client_cache: TimestampedCache::new(config.rate_limiting_cache_size),
+            clients: ClientList::new(config.mru_size),
+            config,
+            network_wait_period: Duration::from_secs(1),
+            network: NetworkConfig::default(),
+            reuse_port: false,
+            system: Arc::new(ArcSwap::from_pointee(SystemSnapshot::default())),
+            #[cfg(feature = "io-uring")]
+            io_uring: false,
+            associations: Default::default(),
+            keys,
+            capture: Default::default(),
+            drain,
+            clock: TestClock {},
+            stats: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_anycast_drain() {
+        let mut config = ServerConfig::try_from("127.0.0.1:9047").unwrap();
+        config.symmetric_peers = IpFilter::new(&["127.0.0.1/32".parse().unwrap()]);
+        config.anycast = Some(Default::default());
+        let drain = Drain::default();
+        let mut server = server_task(config.clone(), Keys::default(), drain.clone());
+
+        let client: SocketAddr = "127.0.0.1:1230".parse().unwrap();
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let mut request = vec![];
+        packet.serialize(&mut request).unwrap();
+        let (packet, _) = NtpPacket::symmetric_poll_message_at(
+            &SystemSnapshot::default(),
+            PollIntervalLimits::default().min,
+            NtpTimestamp::default(),
+            64,
+        );
+        let mut symmetric = vec![];
+        packet.serialize(&mut symmetric).unwrap();
+
+        let exchange = |server: &mut ServerTask<TestClock>, request: &[u8]| {
+            let recv_timestamp = TestClock {}.now().unwrap();
+            let accept_result = server.accept_packet(
+                Duration::ZERO,
+                Ok((request.len(), client, Some(recv_timestamp))),
+                request,
+            );
+            match server.response(accept_result) {
+                Response::Ntp(buf, len, _) | Response::Timestamp(buf, len, _) => {
+                    Some(NtpPacket::deserialize(&buf[..len]).unwrap().into_owned())
+                }
+                _ => None,
+            }
+        };
+
+        // symmetric peers are not answered on an anycast address
+        assert!(!exchange(&mut server, &request).unwrap().is_kiss());
+        assert!(exchange(&mut server, &symmetric).is_none());
+
+        drain.set(DrainMode::Kod);
+        assert!(exchange(&mut server, &request).unwrap().is_kiss_rate());
+
+        drain.set(DrainMode::Withdraw);
+        assert!(exchange(&mut server, &request).is_none());
+        assert_eq!(server.stats.drained_packets.get(), 2);
+
+        drain.set(DrainMode::Off);
+        assert!(!exchange(&mut server, &request).unwrap().is_kiss());
+
+        // servers on other addresses are not drained
+        config.anycast = None;
+        let mut server = server_task(config, Keys::default(), drain.clone());
+        drain.set(DrainMode::Withdraw);
+        assert!(!exchange(&mut server, &request).unwrap().is_kiss());
+        assert!(exchange(&mut server, &symmetric).is_some());
+    }
+
+    /// Counts the allocations of the current thread, such that the other
+    /// tests running alongside do not interfere
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    // Safety: all allocation is left to the system allocator
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            unsafe { std::alloc::System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            unsafe { std::alloc::System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// A request with extension fields, as an NTS request has them
+    fn request_with_extension_fields(count: usize, length: usize) -> Vec<u8> {
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let mut data = vec![];
+        packet.serialize(&mut data).unwrap();
+        for index in 0..count {
+            data.extend_from_slice(&0x0104u16.to_be_bytes());
+            data.extend_from_slice(&(length as u16).to_be_bytes());
+            data.resize(data.len() + length - 4, index as u8);
+        }
+        data
+    }
+
+    #[test]
+    fn test_serving_does_not_allocate() {
+        let config = ServerConfig {
+            addr: "127.0.0.1:9046".parse().unwrap(),
+            denylist: IpFilter::none(),
+            denylist_action: FilterAction::Ignore,
+            allowlist: IpFilter::all(),
+            allowlist_action: FilterAction::Ignore,
+            rate_limiting_cutoff: Duration::from_secs(1),
+            rate_limiting_cache_size: 32,
+            policy: ServerPolicy::Standard,
+            control: false,
+            acl: vec![],
+            acl_default: AclAction::Serve,
+            mru_size: 16,
+            workers: 1,
+            batch_size: 1,
+            symmetric_peers: IpFilter::none(),
+            max_extension_fields: 16,
+            max_extension_field_length: 512,
+            parsing: Default::default(),
+            anycast: None,
+        };
+        let key = SymmetricKey::new(1, KeyAlgorithm::Aes128Cmac, vec![7; 16]).unwrap();
+        let keys = Keys::from_key_set(KeySet::new(vec![key.clone()]));
+        let mut server = server_task(config, keys, Default::default());
+
+        let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+        let mut valid = vec![];
+        packet.serialize(&mut valid).unwrap();
+
+        let mut signed = vec![0; MAX_NTP_PACKET_SIZE];
+        let len = packet.serialize_signed_into(&mut signed, &key).unwrap();
+        signed.truncate(len);
+
+        // bytes from a linear congruential generator, behind a header that
+        // makes them look like a request
+        let mut state = 0x2545f491u32;
+        let mut garbage: Vec<u8> = (0..MAX_PACKET_SIZE)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state >> 24) as u8
+            })
+            .collect();
+        garbage[0] = 0x23;
+
+        let requests = [
+            valid,
+            signed,
+            garbage,
+            vec![0x23; 20],
+            request_with_extension_fields(15, 64),
+            request_with_extension_fields(17, 16),
+            request_with_extension_fields(1, 600),
+            request_with_extension_fields(1, 4),
+        ];
+
+        // clients from several addresses, some of which are rate limited
+        let clients = (0..4).map(|port| SocketAddr::from(([127, 0, 0, 1], 1230 + port)));
+        let mut received = vec![];
+        for request in &requests {
+            for client in clients.clone() {
+                received.push((request, client));
+            }
+        }
+
+        // the first round lets lazily initialized state settle
+        for round in 0..2 {
+            for (request, client) in &received {
+                let recv_timestamp = TestClock {}.now().unwrap();
+                ALLOCATIONS.with(|count| count.set(0));
+
+                let accept_result = server.accept_packet(
+                    Duration::from_secs(1),
+                    Ok((request.len(), *client, Some(recv_timestamp))),
+                    request,
+                );
+                let response = server.response(accept_result);
+                let allocations = ALLOCATIONS.with(|count| count.get());
+                drop(response);
+
+                if round == 1 {
+                    assert_eq!(allocations, 0, "request of {} bytes", request.len());
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
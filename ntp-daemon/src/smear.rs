@@ -0,0 +1,244 @@
+//! Recognizing sources that smear leap seconds.
+//!
+//! Instead of inserting a leap second, some servers slow down their clock
+//! over many hours around it, such that their time is off from UTC by up to
+//! a second during the smear. Combining such sources with sources that do
+//! insert the leap second pulls the clock somewhere in between, so sources
+//! that appear to smear are told apart from the others by
+//!  - their reference id: ntpd uses 254.x.y.z while it smears, an address
+//!    that is reserved and thus never that of a real upstream
+//!  - their offset, which drifts away from that of the other sources around
+//!    a leap second, when they did not announce the leap second while most
+//!    other sources did, as smearing servers hide it
+//!
+//! Drift is only looked for from the first announcement of a leap second by
+//! most sources until a day after the last, and sources found smearing are
+//! remembered for as long.
+
+use std::{collections::HashMap, time::Duration};
+
+use ntp_proto::{NtpDuration, NtpInstant, NtpLeapIndicator, PeerSnapshot, ReferenceId, SourceKind};
+use serde::{Deserialize, Serialize};
+
+/// How long after the last announcement of a leap second sources may still
+/// be smearing. Common smears end at most 18 hours after the leap second.
+const SMEAR_WINDOW: Duration = Duration::from_secs(86400);
+
+/// Change of the offset of a source relative to the others, in seconds, that
+/// counts as drift. A smear over 24 hours drifts this far in 15 minutes.
+const DRIFT_THRESHOLD: f64 = 0.010;
+
+/// Fewest sources needed to tell what the others do
+const MIN_SOURCES: usize = 3;
+
+/// Why a source appears to smear leap seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SmearEvidence {
+    /// The reference id that ntpd uses while smearing
+    ReferenceId,
+    /// The offset drifted away from that of the other sources around a leap
+    /// second, which the source did not announce
+    Drift,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct SourceState {
+    /// Offset in seconds relative to the median of all sources, when the
+    /// source was first seen around the leap second
+    baseline: f64,
+    /// Whether the source announced the leap second
+    announced: bool,
+    drifted: bool,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SmearDetector {
+    /// When most sources last announced a leap second
+    last_announcement: Option<NtpInstant>,
+    sources: HashMap<ReferenceId, SourceState>,
+}
+
+fn announces_leap(snapshot: &PeerSnapshot) -> bool {
+    matches!(
+        snapshot.leap_indicator,
+        NtpLeapIndicator::Leap61 | NtpLeapIndicator::Leap59
+    )
+}
+
+fn smearing_reference_id(snapshot: &PeerSnapshot) -> bool {
+    // at stratum 1 the reference id names a kind of reference clock instead
+    snapshot.stratum > 1 && snapshot.reference_id.to_bytes()[0] == 254
+}
+
+impl SmearDetector {
+    /// The sources among `snapshots`, which are acceptable for
+    /// synchronization, that appear to smear leap seconds. Reference clocks
+    /// are never taken to smear.
+    pub(crate) fn detect(
+        &mut self,
+        now: NtpInstant,
+        snapshots: &[PeerSnapshot],
+    ) -> Vec<(ReferenceId, SmearEvidence)> {
+        let announcing = snapshots.iter().filter(|s| announces_leap(s)).count();
+        if snapshots.len() >= MIN_SOURCES && 2 * announcing > snapshots.len() {
+            self.last_announcement = Some(now);
+        }
+
+        let around_leap = match self.last_announcement {
+            Some(last) => now.abs_diff(last) < NtpDuration::from_system_duration(SMEAR_WINDOW),
+            None => false,
+        };
+        if !around_leap {
+            self.last_announcement = None;
+            self.sources.clear();
+        } else if snapshots.len() >= MIN_SOURCES {
+            self.track(snapshots);
+        }
+
+        snapshots
+            .iter()
+            .filter(|snapshot| snapshot.kind == SourceKind::Network)
+            .filter_map(|snapshot| {
+                let drifted = matches!(
+                    self.sources.get(&snapshot.peer_id),
+                    Some(state) if state.drifted
+                );
+
+                if smearing_reference_id(snapshot) {
+                    Some((snapshot.peer_id, SmearEvidence::ReferenceId))
+                } else if drifted {
+                    Some((snapshot.peer_id, SmearEvidence::Drift))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Follow the offsets of the sources relative to each other
+    fn track(&mut self, snapshots: &[PeerSnapshot]) {
+        let mut offsets: Vec<f64> = snapshots
+            .iter()
+            .map(|snapshot| snapshot.statistics.offset.to_seconds())
+            .collect();
+        offsets.sort_by(f64::total_cmp);
+        let median = offsets[offsets.len() / 2];
+
+        for snapshot in snapshots {
+            let relative = snapshot.statistics.offset.to_seconds() - median;
+            let state = self.sources.entry(snapshot.peer_id).or_insert(SourceState {
+                baseline: relative,
+                ..Default::default()
+            });
+
+            // a source that announces the leap second inserts it
+            state.announced |= announces_leap(snapshot);
+            state.drifted |=
+                !state.announced && (relative - state.baseline).abs() > DRIFT_THRESHOLD;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::peer_snapshot;
+
+    use super::*;
+
+    fn source(id: u8, offset: f64, leap_indicator: NtpLeapIndicator) -> PeerSnapshot {
+        let mut snapshot = peer_snapshot(
+            Default::default(),
+            NtpInstant::now(),
+            NtpDuration::from_seconds(0.001),
+            NtpDuration::from_seconds(0.001),
+        );
+        snapshot.peer_id = ReferenceId::from_bytes([192, 0, 2, id]);
+        snapshot.stratum = 2;
+        snapshot.statistics.offset = NtpDuration::from_seconds(offset);
+        snapshot.leap_indicator = leap_indicator;
+        snapshot
+    }
+
+    #[test]
+    fn test_reference_id() {
+        let mut detector = SmearDetector::default();
+        let mut smearing = source(1, 0.0, NtpLeapIndicator::NoWarning);
+        smearing.reference_id = ReferenceId::from_bytes([254, 0, 1, 2]);
+        let normal = source(2, 0.0, NtpLeapIndicator::NoWarning);
+
+        let found = detector.detect(NtpInstant::now(), &[smearing, normal]);
+        assert_eq!(found, vec![(smearing.peer_id, SmearEvidence::ReferenceId)]);
+
+        // the reference id of a stratum 1 source is the kind of its clock
+        smearing.stratum = 1;
+        assert_eq!(
+            detector.detect(NtpInstant::now(), &[smearing, normal]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_drift() {
+        use NtpLeapIndicator::*;
+
+        let mut detector = SmearDetector::default();
+        let start = NtpInstant::now();
+        let at = |hours: u64| start + Duration::from_secs(hours * 3600);
+
+        // no leap second is announced, so the offsets of the sources do not matter
+        let sources = [
+            source(1, 0.0, NoWarning),
+            source(2, 0.001, NoWarning),
+            source(3, 0.050, NoWarning),
+        ];
+        assert_eq!(detector.detect(at(0), &sources), vec![]);
+        let sources = [
+            source(1, 0.0, NoWarning),
+            source(2, 0.001, NoWarning),
+            source(3, 0.0, NoWarning),
+        ];
+        assert_eq!(detector.detect(at(1), &sources), vec![]);
+
+        // the leap second is announced by most, a source that does not
+        // announce it is fine until its offset drifts
+        let sources = [
+            source(1, 0.0, Leap61),
+            source(2, 0.001, Leap61),
+            source(3, 0.002, NoWarning),
+            source(4, 0.0, Leap61),
+        ];
+        assert_eq!(detector.detect(at(2), &sources), vec![]);
+        let sources = [
+            source(1, 0.0, Leap61),
+            source(2, 0.001, Leap61),
+            source(3, 0.100, NoWarning),
+            source(4, 0.050, Leap61),
+        ];
+        let found = detector.detect(at(12), &sources);
+        assert_eq!(found, vec![(sources[2].peer_id, SmearEvidence::Drift)]);
+
+        // after the leap second, the smear is over and the source agrees
+        // again, but it is remembered for a day after the announcements
+        let sources = [
+            source(1, 0.0, NoWarning),
+            source(2, 0.001, NoWarning),
+            source(3, 0.002, NoWarning),
+            source(4, 0.0, NoWarning),
+        ];
+        let found = detector.detect(at(30), &sources);
+        assert_eq!(found, vec![(sources[2].peer_id, SmearEvidence::Drift)]);
+        assert_eq!(detector.detect(at(40), &sources), vec![]);
+    }
+
+    #[test]
+    fn test_refclocks_do_not_smear() {
+        let mut detector = SmearDetector::default();
+        let mut refclock = source(1, 0.0, NtpLeapIndicator::NoWarning);
+        refclock.reference_id = ReferenceId::from_bytes([254, 0, 0, 0]);
+        refclock.kind = SourceKind::RefClock {
+            needs_corroboration: false,
+        };
+
+        assert_eq!(detector.detect(NtpInstant::now(), &[refclock]), vec![]);
+    }
+}
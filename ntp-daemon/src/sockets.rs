@@ -1,8 +1,11 @@
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixDatagram;
 use tokio::net::UnixListener;
 use tokio::net::UnixStream;
+use tracing::debug;
 
 pub async fn write_json<T>(stream: &mut UnixStream, value: &T) -> std::io::Result<()>
 where
@@ -21,13 +24,49 @@ where
 {
     buffer.clear();
 
-    let n = stream.read_buf(buffer).await?;
-    buffer.truncate(n);
+    // large values may not arrive in a single read, so keep reading until the
+    // buffer holds a complete value, or the other side is done writing
+    loop {
+        let n = stream.read_buf(buffer).await?;
+        if n == 0 || serde_json::from_slice::<serde::de::IgnoredAny>(buffer).is_ok() {
+            break;
+        }
+    }
+
+    serde_json::from_slice(buffer)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Listen on the given path, with the given permissions. When the service
+/// manager passed a socket for this path, that socket is used as is.
+pub fn create_unix_socket(path: &Path, mode: u32) -> std::io::Result<UnixListener> {
+    if let Some(listener) = ntp_udp::activated_unix_listener(path)? {
+        debug!(?path, "using socket of the service manager");
+        listener.set_nonblocking(true)?;
+        return UnixListener::from_std(listener);
+    }
+
+    let listener = bind_unix_socket(path, |path| UnixListener::bind(path))?;
+
+    // this binary needs to run as root to be able to adjust the system clock.
+    // by default, the socket inherits root permissions, but the client should not need
+    // elevated permissions to read from the socket. So we explicitly set the permissions
+    let permissions: std::fs::Permissions = PermissionsExt::from_mode(mode);
+    std::fs::set_permissions(path, permissions)?;
 
-    Ok(serde_json::from_slice(buffer).unwrap())
+    Ok(listener)
 }
 
-pub fn create_unix_socket(path: &Path) -> std::io::Result<UnixListener> {
+/// Create a datagram socket at the given path, for protocols that are not
+/// stream based (such as that of chrony)
+pub fn create_unix_datagram_socket(path: &Path) -> std::io::Result<UnixDatagram> {
+    bind_unix_socket(path, |path| UnixDatagram::bind(path))
+}
+
+fn bind_unix_socket<T>(
+    path: &Path,
+    bind: impl FnOnce(&Path) -> std::io::Result<T>,
+) -> std::io::Result<T> {
     use std::io::{Error, ErrorKind};
 
     // must unlink path before the bind below (otherwise we get "address already in use")
@@ -36,8 +75,8 @@ pub fn create_unix_socket(path: &Path) -> std::io::Result<UnixListener> {
     }
 
     // OS errors are terrible; let's try to do better
-    let error = match UnixListener::bind(&path) {
-        Ok(listener) => return Ok(listener),
+    let error = match bind(path) {
+        Ok(socket) => return Ok(socket),
         Err(e) => e,
     };
 
@@ -45,7 +84,7 @@ pub fn create_unix_socket(path: &Path) -> std::io::Result<UnixListener> {
     if let Some(parent) = path.parent() {
         if !parent.exists() {
             let msg = format!(
-                r"Could not create observe socket at {:?} because its parent directory does not exist",
+                r"Could not create socket at {:?} because its parent directory does not exist",
                 &path
             );
             return Err(Error::new(ErrorKind::Other, msg));
@@ -53,10 +92,7 @@ pub fn create_unix_socket(path: &Path) -> std::io::Result<UnixListener> {
     }
 
     // otherwise, just forward the OS error
-    let msg = format!(
-        "Could not create observe socket at {:?}: {:?}",
-        &path, error
-    );
+    let msg = format!("Could not create socket at {:?}: {:?}", &path, error);
     Err(Error::new(ErrorKind::Other, msg))
 }
 
@@ -92,4 +128,31 @@ mod tests {
         // the logic will automatically grow the buffer to the required size
         assert!(!buf.is_empty());
     }
+
+    #[tokio::test]
+    async fn write_then_read_large() {
+        // be careful with copying: tests run concurrently and should use a unique socket name!
+        let path = std::env::temp_dir().join("ntp-test-stream-5");
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut writer = UnixStream::connect(&path).await.unwrap();
+
+        let (mut reader, _) = listener.accept().await.unwrap();
+
+        // larger than what a single read returns
+        let object: Vec<usize> = (0..100_000).collect();
+
+        let write = tokio::spawn(async move { write_json(&mut writer, &object).await });
+
+        let mut buf = Vec::new();
+        let output = read_json::<Vec<usize>>(&mut reader, &mut buf)
+            .await
+            .unwrap();
+
+        write.await.unwrap().unwrap();
+        assert_eq!(output.len(), 100_000);
+        assert_eq!(output[99_999], 99_999);
+    }
 }
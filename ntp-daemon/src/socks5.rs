@@ -0,0 +1,442 @@
+//! The client side of SOCKS5 UDP associate (RFC 1928), through which the
+//! packets to a peer are relayed when the peer can not be reached directly.
+//!
+//! The association is set up over a TCP connection to the proxy, which must
+//! stay open for as long as packets are relayed. Every relayed packet starts
+//! with a header that holds the address of the peer it is sent to, or that it
+//! was received from.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpSocket, TcpStream},
+};
+
+use crate::config::Socks5Config;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const USERNAME_PASSWORD_VERSION: u8 = 1;
+const UDP_ASSOCIATE: u8 = 0x03;
+const SUCCEEDED: u8 = 0x00;
+const IPV4: u8 = 0x01;
+const DOMAIN_NAME: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+/// Size of the largest header in front of a relayed packet, that with an
+/// IPv6 address
+pub(crate) const MAX_HEADER_SIZE: usize = 4 + 16 + 2;
+
+/// Longest time that setting up an association may take
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A UDP association with a SOCKS5 proxy
+#[derive(Debug)]
+pub(crate) struct Association {
+    control: TcpStream,
+    relay: SocketAddr,
+}
+
+impl Association {
+    /// Set up an association with the proxy, connecting to it from `source`
+    /// when that is given
+    pub(crate) async fn open(config: &Socks5Config, source: Option<IpAddr>) -> io::Result<Self> {
+        match tokio::time::timeout(SETUP_TIMEOUT, Self::set_up(config, source)).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the SOCKS5 proxy did not set up the association in time",
+            )),
+        }
+    }
+
+    async fn set_up(config: &Socks5Config, source: Option<IpAddr>) -> io::Result<Self> {
+        let mut control = connect(&config.addr, source).await?;
+        authenticate(&mut control, config).await?;
+
+        // we do not know the address we will send from yet, which the proxy
+        // is told with an address and port of all zeros
+        control
+            .write_all(&[VERSION, UDP_ASSOCIATE, 0, IPV4, 0, 0, 0, 0, 0, 0])
+            .await?;
+
+        let mut reply = [0; 4];
+        control.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(invalid_data("the proxy does not speak SOCKS5"));
+        }
+        if reply[1] != SUCCEEDED {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!(
+                    "the proxy refused the association: {}",
+                    reply_message(reply[1])
+                ),
+            ));
+        }
+
+        let ip = match reply[3] {
+            IPV4 => {
+                let mut octets = [0; 4];
+                control.read_exact(&mut octets).await?;
+                IpAddr::from(octets)
+            }
+            IPV6 => {
+                let mut octets = [0; 16];
+                control.read_exact(&mut octets).await?;
+                IpAddr::from(octets)
+            }
+            DOMAIN_NAME => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the proxy relays from a domain name instead of an address",
+                ))
+            }
+            _ => {
+                return Err(invalid_data(
+                    "the proxy replied with an unknown address type",
+                ))
+            }
+        };
+        let port = control.read_u16().await?;
+
+        // an unspecified address stands for the address of the proxy itself
+        let ip = match ip.is_unspecified() {
+            true => control.peer_addr()?.ip(),
+            false => ip,
+        };
+
+        Ok(Association {
+            control,
+            relay: SocketAddr::new(ip, port),
+        })
+    }
+
+    /// Address of the proxy that packets are sent to and received from
+    pub(crate) fn relay(&self) -> SocketAddr {
+        self.relay
+    }
+
+    /// Wait until the proxy closes the connection, after which it no longer
+    /// relays packets
+    pub(crate) async fn closed(&mut self) {
+        let mut buf = [0; 64];
+        loop {
+            match self.control.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                // the proxy has nothing to say after its reply
+                Ok(_) => {}
+            }
+        }
+    }
+}
+
+async fn connect(addr: &str, source: Option<IpAddr>) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(source) = source.filter(|source| source.is_ipv4() == addr.is_ipv4()) {
+            socket.bind(SocketAddr::new(source, 0))?;
+        }
+
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(error) => last_error = Some(error),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "the address of the proxy did not resolve",
+        )
+    }))
+}
+
+async fn authenticate(control: &mut TcpStream, config: &Socks5Config) -> io::Result<()> {
+    let credentials = config.username.as_ref().zip(config.password.as_ref());
+    match credentials {
+        Some(_) => {
+            control
+                .write_all(&[VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD])
+                .await?
+        }
+        None => control.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?,
+    }
+
+    let mut choice = [0; 2];
+    control.read_exact(&mut choice).await?;
+    if choice[0] != VERSION {
+        return Err(invalid_data("the proxy does not speak SOCKS5"));
+    }
+
+    match (choice[1], credentials) {
+        (NO_AUTHENTICATION, _) => Ok(()),
+        (USERNAME_PASSWORD, Some((username, password))) => {
+            // the lengths are checked when the configuration is read
+            let mut request = vec![USERNAME_PASSWORD_VERSION, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            control.write_all(&request).await?;
+
+            let mut status = [0; 2];
+            control.read_exact(&mut status).await?;
+            match status[1] {
+                SUCCEEDED => Ok(()),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "the proxy refused the username and password",
+                )),
+            }
+        }
+        (NO_ACCEPTABLE_METHODS, _) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the proxy requires authentication that is not configured",
+        )),
+        _ => Err(invalid_data(
+            "the proxy chose an authentication method that was not offered",
+        )),
+    }
+}
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn reply_message(reply: u8) -> &'static str {
+    match reply {
+        0x01 => "general failure",
+        0x02 => "not allowed by its rules",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown reason",
+    }
+}
+
+/// Write the header with which the proxy relays a packet to `destination` at
+/// the start of `buf`, and return its length. The packet follows the header.
+pub(crate) fn write_header(destination: SocketAddr, buf: &mut [u8]) -> usize {
+    // reserved, and a fragment number of 0 for an unfragmented packet
+    buf[..3].fill(0);
+    let address_end = match destination.ip() {
+        IpAddr::V4(ip) => {
+            buf[3] = IPV4;
+            buf[4..8].copy_from_slice(&ip.octets());
+            8
+        }
+        IpAddr::V6(ip) => {
+            buf[3] = IPV6;
+            buf[4..20].copy_from_slice(&ip.octets());
+            20
+        }
+    };
+    buf[address_end..address_end + 2].copy_from_slice(&destination.port().to_be_bytes());
+    address_end + 2
+}
+
+/// The address a relayed packet was received from, and the length of the
+/// header in front of it. Fragments and packets from domain names, which the
+/// proxy has no reason to send us, are not accepted.
+pub(crate) fn parse_header(data: &[u8]) -> Option<(SocketAddr, usize)> {
+    let (ip, address_end) = match data.get(..4)? {
+        [0, 0, 0, IPV4] => {
+            let octets: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+            (IpAddr::from(Ipv4Addr::from(octets)), 8)
+        }
+        [0, 0, 0, IPV6] => {
+            let octets: [u8; 16] = data.get(4..20)?.try_into().ok()?;
+            (IpAddr::from(Ipv6Addr::from(octets)), 20)
+        }
+        _ => return None,
+    };
+    let port = data.get(address_end..address_end + 2)?;
+    let port = u16::from_be_bytes([port[0], port[1]]);
+
+    Some((SocketAddr::new(ip, port), address_end + 2))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use super::*;
+
+    /// Start a proxy on localhost that relays for `lifetime` after setting up
+    /// an association, and requires `credentials` when they are given
+    pub(crate) async fn spawn_proxy(
+        credentials: Option<(&'static str, &'static str)>,
+        lifetime: Duration,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (control, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let relay = tokio::time::timeout(lifetime, serve(control, credentials));
+                    relay.await.ok();
+                });
+            }
+        });
+        addr
+    }
+
+    async fn serve(mut control: TcpStream, credentials: Option<(&str, &str)>) -> io::Result<()> {
+        let mut greeting = [0; 2];
+        control.read_exact(&mut greeting).await?;
+        let mut methods = vec![0; greeting[1] as usize];
+        control.read_exact(&mut methods).await?;
+
+        let method = match credentials {
+            Some(_) => USERNAME_PASSWORD,
+            None => NO_AUTHENTICATION,
+        };
+        if !methods.contains(&method) {
+            return control.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await;
+        }
+        control.write_all(&[VERSION, method]).await?;
+
+        if let Some((username, password)) = credentials {
+            let mut read_string = [0; 2];
+            control.read_exact(&mut read_string).await?;
+            let mut given_username = vec![0; read_string[1] as usize];
+            control.read_exact(&mut given_username).await?;
+            let mut given_password = vec![0; control.read_u8().await? as usize];
+            control.read_exact(&mut given_password).await?;
+
+            if given_username != username.as_bytes() || given_password != password.as_bytes() {
+                return control.write_all(&[USERNAME_PASSWORD_VERSION, 1]).await;
+            }
+            control.write_all(&[USERNAME_PASSWORD_VERSION, 0]).await?;
+        }
+
+        let mut request = [0; 10];
+        control.read_exact(&mut request).await?;
+        assert_eq!(request[..4], [VERSION, UDP_ASSOCIATE, 0, IPV4]);
+
+        let relay = UdpSocket::bind("127.0.0.1:0").await?;
+        let mut reply = vec![VERSION, SUCCEEDED, 0, IPV4, 127, 0, 0, 1];
+        reply.extend_from_slice(&relay.local_addr()?.port().to_be_bytes());
+        control.write_all(&reply).await?;
+
+        let mut client = None;
+        let mut buf = [0; 1024];
+        let mut relayed = [0; 1024];
+        loop {
+            let (size, from) = tokio::select! {
+                _ = control.read_u8() => return Ok(()),
+                result = relay.recv_from(&mut buf) => result?,
+            };
+
+            if client.is_none() || client == Some(from) {
+                if let Some((destination, header)) = parse_header(&buf[..size]) {
+                    client = Some(from);
+                    relay.send_to(&buf[header..size], destination).await?;
+                }
+            } else if let Some(client) = client {
+                let header = write_header(from, &mut relayed);
+                relayed[header..header + size].copy_from_slice(&buf[..size]);
+                relay.send_to(&relayed[..header + size], client).await?;
+            }
+        }
+    }
+
+    fn config(proxy: SocketAddr, credentials: Option<(&str, &str)>) -> Socks5Config {
+        Socks5Config {
+            addr: proxy.to_string(),
+            username: credentials.map(|(username, _)| username.to_string()),
+            password: credentials.map(|(_, password)| password.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let mut buf = [0; MAX_HEADER_SIZE + 2];
+        for addr in ["192.0.2.1:123", "[2001:db8::1]:1234"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let length = write_header(addr, &mut buf);
+            assert_eq!(parse_header(&buf), Some((addr, length)));
+            assert_eq!(parse_header(&buf[..length - 1]), None);
+        }
+        assert_eq!(
+            write_header("[2001:db8::1]:123".parse().unwrap(), &mut buf),
+            MAX_HEADER_SIZE
+        );
+
+        // fragments are not reassembled
+        buf[2] = 1;
+        assert_eq!(parse_header(&buf), None);
+        assert_eq!(
+            parse_header(&[0, 0, 0, DOMAIN_NAME, 3, b'a', b'.', b'b', 0, 123]),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let credentials = Some(("ntp", "secret"));
+        let proxy = spawn_proxy(credentials, Duration::from_secs(60)).await;
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        let association = Association::open(&config(proxy, credentials), None)
+            .await
+            .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(association.relay()).await.unwrap();
+
+        let mut buf = [0; 64];
+        let header = write_header(peer_addr, &mut buf);
+        buf[header..header + 4].copy_from_slice(b"ping");
+        socket.send(&buf[..header + 4]).await.unwrap();
+
+        let (size, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"ping");
+        peer.send_to(b"pong", from).await.unwrap();
+
+        let size = socket.recv(&mut buf).await.unwrap();
+        let (source, header) = parse_header(&buf[..size]).unwrap();
+        assert_eq!(source, peer_addr);
+        assert_eq!(&buf[header..size], b"pong");
+    }
+
+    #[tokio::test]
+    async fn test_authentication() {
+        let proxy = spawn_proxy(Some(("ntp", "secret")), Duration::from_secs(60)).await;
+
+        let error = Association::open(&config(proxy, Some(("ntp", "wrong"))), None)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        let error = Association::open(&config(proxy, None), None)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let proxy = spawn_proxy(None, Duration::from_millis(100)).await;
+        let mut association = Association::open(&config(proxy, None), None).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), association.closed())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,401 @@
+//! Statistics files in the formats of ntpd.
+//!
+//! Lines are formatted where the event happens, and handed to a dedicated
+//! thread that appends them to one file per category and per (UTC) day, named
+//! like `peerstats.20230131`.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ntp_proto::{format_reference_id, NtpDuration, NtpPacket, NtpTimestamp, PeerStatistics};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+use tracing::{error, warn};
+
+use crate::config::StatisticsConfig;
+
+/// Days between the start of the modified julian date and the unix epoch
+const MJD_UNIX_EPOCH: u64 = 40587;
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Category {
+    Loopstats,
+    Peerstats,
+    Rawstats,
+}
+
+impl Category {
+    fn name(self) -> &'static str {
+        match self {
+            Category::Loopstats => "loopstats",
+            Category::Peerstats => "peerstats",
+            Category::Rawstats => "rawstats",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Record {
+    category: Category,
+    time: SystemTime,
+    line: String,
+}
+
+#[derive(Debug)]
+enum Message {
+    Record(Record),
+    /// Get all records sent before onto disk, and report back when done
+    Flush(oneshot::Sender<()>),
+}
+
+/// Handle to the statistics writer. Cheap to clone, and does nothing for the
+/// categories that are not enabled.
+#[derive(Debug, Clone, Default)]
+pub struct StatsLogger {
+    sender: Option<UnboundedSender<Message>>,
+    loopstats: bool,
+    peerstats: bool,
+    rawstats: bool,
+}
+
+impl StatsLogger {
+    /// Start writing the statistics enabled in the configuration
+    pub fn spawn(config: &StatisticsConfig) -> Self {
+        if !config.any_enabled() {
+            return Self::default();
+        }
+
+        let (sender, receiver) = unbounded_channel();
+        let directory = config.directory.clone();
+
+        let result = std::thread::Builder::new()
+            .name("statistics".into())
+            .spawn(move || write_records(&directory, receiver));
+
+        if let Err(error) = result {
+            error!(?error, "could not start statistics thread");
+            return Self::default();
+        }
+
+        StatsLogger {
+            sender: Some(sender),
+            loopstats: config.loopstats,
+            peerstats: config.peerstats,
+            rawstats: config.rawstats,
+        }
+    }
+
+    fn send(&self, category: Category, line: String) {
+        if let Some(sender) = &self.sender {
+            // the writer only stops when the daemon does
+            let _ = sender.send(Message::Record(Record {
+                category,
+                time: SystemTime::now(),
+                line,
+            }));
+        }
+    }
+
+    /// Wait until everything logged so far is written, for instance before
+    /// the daemon stops
+    pub(crate) async fn flush(&self) {
+        if let Some(sender) = &self.sender {
+            let (done, wait) = oneshot::channel();
+            if sender.send(Message::Flush(done)).is_ok() {
+                let _ = wait.await;
+            }
+        }
+    }
+
+    /// An update of the system clock
+    pub(crate) fn clock_update(
+        &self,
+        offset: NtpDuration,
+        frequency: f64,
+        jitter: NtpDuration,
+        poll_interval: i8,
+    ) {
+        if self.loopstats {
+            self.send(
+                Category::Loopstats,
+                loopstats_line(offset, frequency, jitter, poll_interval),
+            );
+        }
+    }
+
+    /// A new measurement of a peer or reference clock
+    pub(crate) fn peer_measurement(
+        &self,
+        address: SocketAddr,
+        status: u16,
+        statistics: PeerStatistics,
+    ) {
+        if self.peerstats {
+            self.send(
+                Category::Peerstats,
+                peerstats_line(address, status, statistics),
+            );
+        }
+    }
+
+    /// A packet received from a peer, with the local send and receive
+    /// timestamps of the exchange
+    pub(crate) fn packet(
+        &self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        send_timestamp: NtpTimestamp,
+        recv_timestamp: NtpTimestamp,
+        packet: &NtpPacket,
+    ) {
+        if self.rawstats {
+            self.send(
+                Category::Rawstats,
+                rawstats_line(source, destination, send_timestamp, recv_timestamp, packet),
+            );
+        }
+    }
+}
+
+fn loopstats_line(
+    offset: NtpDuration,
+    frequency: f64,
+    jitter: NtpDuration,
+    poll_interval: i8,
+) -> String {
+    // ntpd also logs the wander (stability of the frequency), which we do not estimate
+    format!(
+        "{:.9} {:.3} {:.9} {:.6} {}",
+        offset.to_seconds(),
+        frequency * 1e6,
+        jitter.to_seconds(),
+        0.0,
+        poll_interval
+    )
+}
+
+fn peerstats_line(address: SocketAddr, status: u16, statistics: PeerStatistics) -> String {
+    format!(
+        "{} {:04x} {:.9} {:.9} {:.9} {:.9}",
+        address.ip(),
+        status,
+        statistics.offset.to_seconds(),
+        statistics.delay.to_seconds(),
+        statistics.dispersion.to_seconds(),
+        statistics.jitter
+    )
+}
+
+fn rawstats_line(
+    source: SocketAddr,
+    destination: SocketAddr,
+    send_timestamp: NtpTimestamp,
+    recv_timestamp: NtpTimestamp,
+    packet: &NtpPacket,
+) -> String {
+    format!(
+        "{} {} {} {} {} {} {} {} {} {} {} {} {:.6} {:.6} {}",
+        source.ip(),
+        destination.ip(),
+        send_timestamp,
+        packet.receive_timestamp(),
+        packet.transmit_timestamp(),
+        recv_timestamp,
+        packet.leap().to_bits(),
+        packet.version(),
+        packet.mode().to_bits(),
+        packet.stratum(),
+        packet.poll(),
+        packet.precision(),
+        packet.root_delay().to_seconds(),
+        packet.root_dispersion().to_seconds(),
+        format_reference_id(packet.reference_id(), packet.stratum()),
+    )
+}
+
+/// Convert days since the unix epoch to a (year, month, day) civil date
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
+/// File name for the given category and time, and the line prefix of ntpd
+/// statistics: the modified julian date and the seconds past midnight (UTC)
+fn name_and_prefix(category: Category, time: SystemTime) -> (String, String) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let days = since_epoch.as_secs() / SECONDS_PER_DAY;
+    let seconds =
+        (since_epoch.as_secs() % SECONDS_PER_DAY) as f64 + since_epoch.subsec_nanos() as f64 * 1e-9;
+
+    let (year, month, day) = civil_from_days(days);
+    let name = format!("{}.{:04}{:02}{:02}", category.name(), year, month, day);
+    let prefix = format!("{} {:.3}", days + MJD_UNIX_EPOCH, seconds);
+
+    (name, prefix)
+}
+
+fn open(directory: &Path, name: &str) -> Option<File> {
+    let path: PathBuf = directory.join(name);
+    match OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(file) => Some(file),
+        Err(error) => {
+            warn!(?error, ?path, "could not open statistics file");
+            None
+        }
+    }
+}
+
+fn write_records(directory: &Path, mut receiver: UnboundedReceiver<Message>) {
+    // the currently open file of each category, with its name
+    let mut files: Vec<(Category, String, Option<File>)> = Vec::new();
+
+    while let Some(message) = receiver.blocking_recv() {
+        let record = match message {
+            Message::Record(record) => record,
+            Message::Flush(done) => {
+                for (_, name, file) in &files {
+                    if let Some(Err(error)) = file.as_ref().map(File::sync_data) {
+                        warn!(?error, file = name, "could not flush statistics");
+                    }
+                }
+                let _ = done.send(());
+                continue;
+            }
+        };
+
+        let (name, prefix) = name_and_prefix(record.category, record.time);
+
+        let position = files.iter().position(|(c, _, _)| *c == record.category);
+        let entry = match position {
+            // the day changed, so move on to a new file
+            Some(i) if files[i].1 != name => {
+                files[i] = (record.category, name.clone(), open(directory, &name));
+                &mut files[i]
+            }
+            Some(i) => &mut files[i],
+            None => {
+                files.push((record.category, name.clone(), open(directory, &name)));
+                files.last_mut().unwrap()
+            }
+        };
+
+        if let Some(file) = &mut entry.2 {
+            if let Err(error) = writeln!(file, "{} {}", prefix, record.line) {
+                warn!(?error, file = name, "could not write statistics");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_time_formatting() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_675_166_400_500);
+        let (name, prefix) = name_and_prefix(Category::Peerstats, time);
+        assert_eq!(name, "peerstats.20230131");
+        assert_eq!(prefix, "59975 43200.500");
+
+        let (name, prefix) = name_and_prefix(Category::Loopstats, UNIX_EPOCH);
+        assert_eq!(name, "loopstats.19700101");
+        assert_eq!(prefix, "40587 0.000");
+
+        // leap day
+        assert_eq!(civil_from_days(19416), (2023, 2, 28));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn test_lines() {
+        let line = loopstats_line(
+            NtpDuration::from_seconds(0.000_006),
+            13.778e-6,
+            NtpDuration::from_seconds(0.000_35),
+            6,
+        );
+        assert_eq!(line, "0.000006000 13.778 0.000350000 0.000000 6");
+
+        let line = peerstats_line(
+            "192.0.2.1:123".parse().unwrap(),
+            0x9614,
+            PeerStatistics {
+                offset: NtpDuration::from_seconds(-0.0016),
+                delay: NtpDuration::from_seconds(0.02),
+                dispersion: NtpDuration::from_seconds(0.0014),
+                jitter: 0.00095,
+            },
+        );
+        assert_eq!(
+            line,
+            "192.0.2.1 9614 -0.001600000 0.020000000 0.001400000 0.000950000"
+        );
+
+        let line = rawstats_line(
+            "192.0.2.1:123".parse().unwrap(),
+            "192.0.2.2:45678".parse().unwrap(),
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(3_900_000_000, 0),
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(3_900_000_000, 250_000_000),
+            &NtpPacket::poll_message(Default::default()).0,
+        );
+        let fields: Vec<_> = line.split(' ').collect();
+        assert_eq!(fields.len(), 15);
+        assert_eq!(
+            &fields[..3],
+            &["192.0.2.1", "192.0.2.2", "3900000000.000000000"]
+        );
+        assert_eq!(fields[5], "3900000000.250000000");
+        // leap, version and mode
+        assert_eq!(&fields[6..9], &["0", "4", "3"]);
+    }
+
+    #[test]
+    fn test_write() {
+        let directory = std::env::temp_dir().join("ntp-test-statistics-1");
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let (sender, receiver) = unbounded_channel();
+        let time = UNIX_EPOCH + Duration::from_secs(1_675_166_400);
+        for (offset, line) in [(0, "first"), (10, "second"), (SECONDS_PER_DAY, "third")] {
+            sender
+                .send(Message::Record(Record {
+                    category: Category::Loopstats,
+                    time: time + Duration::from_secs(offset),
+                    line: line.into(),
+                }))
+                .unwrap();
+        }
+        let (done, mut flushed) = oneshot::channel();
+        sender.send(Message::Flush(done)).unwrap();
+        drop(sender);
+
+        write_records(&directory, receiver);
+        assert!(flushed.try_recv().is_ok());
+
+        let first = std::fs::read_to_string(directory.join("loopstats.20230131")).unwrap();
+        assert_eq!(first, "59975 43200.000 first\n59975 43210.000 second\n");
+        let second = std::fs::read_to_string(directory.join("loopstats.20230201")).unwrap();
+        assert_eq!(second, "59976 43200.000 third\n");
+    }
+}
@@ -0,0 +1,213 @@
+use std::sync::Arc;
+
+use ntp_os_clock::PhcDevice;
+use ntp_proto::{NtpDuration, SystemSnapshot};
+use tokio::sync::RwLock;
+use tracing::{error, info, trace, warn, Span};
+
+use crate::config::PhcSteeringConfig;
+
+/// How often the PHC is compared to the system clock and adjusted
+const STEER_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How long to wait before trying to reopen a device that failed
+const DEVICE_WAIT_PERIOD: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Gains of the PI controller, for offsets in seconds and intervals in seconds
+const PROPORTIONAL_GAIN: f64 = 0.7;
+const INTEGRAL_GAIN: f64 = 0.3;
+
+/// Largest frequency adjustment (in seconds per second) the controller will
+/// apply, well within what PHCs support
+const MAX_FREQUENCY: f64 = 500e-6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SteeringAction {
+    /// Leave the PHC alone
+    Nothing,
+    /// Step the PHC by the given offset
+    Step(NtpDuration),
+    /// Set the frequency of the PHC, in seconds per second
+    Frequency(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SteeringState {
+    /// The system clock is not synchronized, so the PHC keeps running at
+    /// its last frequency
+    Holdover,
+    /// The PHC follows the system clock
+    Tracking,
+}
+
+/// Decides how to adjust a single PHC such that it follows the system clock.
+///
+/// Large offsets are stepped away, smaller offsets are corrected through
+/// the frequency of the PHC using a PI controller. While the system clock
+/// itself is not synchronized, the PHC is left running at the last frequency
+/// it was given.
+#[derive(Debug)]
+pub(crate) struct PhcServo {
+    state: SteeringState,
+    /// Integral term of the controller, the frequency correction needed
+    /// to compensate the frequency error of the PHC
+    drift: f64,
+    step_threshold: NtpDuration,
+}
+
+impl PhcServo {
+    pub(crate) fn new(step_threshold: NtpDuration) -> Self {
+        PhcServo {
+            state: SteeringState::Holdover,
+            drift: 0.0,
+            step_threshold,
+        }
+    }
+
+    /// Handle a new measurement of the PHC time minus the desired PHC time,
+    /// taken `interval` seconds after the previous one.
+    pub(crate) fn update(
+        &mut self,
+        offset: NtpDuration,
+        interval: f64,
+        system_synchronized: bool,
+    ) -> SteeringAction {
+        if !system_synchronized {
+            if self.state == SteeringState::Tracking {
+                info!("system clock is not synchronized, PHC enters holdover");
+                self.state = SteeringState::Holdover;
+            }
+            return SteeringAction::Nothing;
+        }
+
+        let was_tracking = self.state == SteeringState::Tracking;
+        self.state = SteeringState::Tracking;
+
+        if offset.abs() > self.step_threshold {
+            if was_tracking {
+                warn!(
+                    ?offset,
+                    "PHC deviates more than the step threshold, stepping"
+                );
+            }
+            return SteeringAction::Step(-offset);
+        }
+
+        if !was_tracking {
+            info!("PHC is tracking the system clock");
+        }
+
+        let offset = offset.to_seconds();
+        self.drift =
+            (self.drift - INTEGRAL_GAIN * offset * interval).clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+        let frequency =
+            (self.drift - PROPORTIONAL_GAIN * offset).clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+
+        SteeringAction::Frequency(frequency)
+    }
+}
+
+/// Start steering each of the configured PHCs towards the system clock, on
+/// a dedicated thread per PHC. The system snapshot tells whether the system
+/// clock itself is synchronized.
+pub fn spawn(configs: &[PhcSteeringConfig], system: Arc<RwLock<SystemSnapshot>>) {
+    for config in configs {
+        let config = config.clone();
+        let system = system.clone();
+        let span = tracing::info_span!("phc steering", device = ?config.device);
+
+        let result = std::thread::Builder::new()
+            .name("phc-steering".into())
+            .spawn(move || steer(config, system, span));
+
+        if let Err(error) = result {
+            error!(?error, "could not start PHC steering thread");
+        }
+    }
+}
+
+fn steer(config: PhcSteeringConfig, system: Arc<RwLock<SystemSnapshot>>, span: Span) {
+    let _enter = span.enter();
+
+    loop {
+        match PhcDevice::open_writable(&config.device) {
+            Ok(device) => {
+                info!(method = ?device.method(), "opened PHC device for steering");
+
+                let mut servo = PhcServo::new(config.step_threshold);
+                loop {
+                    let synchronized = system.blocking_read().leap_indicator.is_synchronized();
+
+                    let offset = match device.offset() {
+                        Ok(phc_offset) => phc_offset.offset - config.offset,
+                        Err(error) => {
+                            warn!(?error, "error while reading from PHC device");
+                            break;
+                        }
+                    };
+
+                    let action = servo.update(offset, STEER_INTERVAL.as_secs_f64(), synchronized);
+                    trace!(?offset, ?action, "PHC steering");
+
+                    let result = match action {
+                        SteeringAction::Nothing => Ok(()),
+                        SteeringAction::Step(step) => device.step(step),
+                        SteeringAction::Frequency(frequency) => device.set_frequency(frequency),
+                    };
+
+                    if let Err(error) = result {
+                        warn!(?error, "error while adjusting PHC device");
+                        break;
+                    }
+
+                    std::thread::sleep(STEER_INTERVAL);
+                }
+            }
+            Err(error) => warn!(?error, "could not open PHC device"),
+        }
+
+        std::thread::sleep(DEVICE_WAIT_PERIOD);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_holdover() {
+        let mut servo = PhcServo::new(NtpDuration::from_seconds(0.001));
+
+        let action = servo.update(NtpDuration::from_seconds(0.5), 1.0, false);
+        assert_eq!(action, SteeringAction::Nothing);
+
+        let offset = NtpDuration::from_seconds(0.5);
+        let action = servo.update(offset, 1.0, true);
+        assert_eq!(action, SteeringAction::Step(-offset));
+
+        let action = servo.update(NtpDuration::from_seconds(0.0001), 1.0, false);
+        assert_eq!(action, SteeringAction::Nothing);
+    }
+
+    #[test]
+    fn test_converges() {
+        let mut servo = PhcServo::new(NtpDuration::from_seconds(0.001));
+
+        // PHC running 20ppm fast, starting 500us ahead
+        let error = 20e-6;
+        let mut offset = 500e-6;
+        let mut frequency = 0.0;
+
+        for _ in 0..60 {
+            match servo.update(NtpDuration::from_seconds(offset), 1.0, true) {
+                SteeringAction::Frequency(f) => frequency = f,
+                action => panic!("unexpected {:?}", action),
+            }
+
+            offset += error + frequency;
+        }
+
+        assert!(offset.abs() < 1e-7, "{}", offset);
+        assert!((frequency + error).abs() < 1e-7, "{}", frequency);
+    }
+}
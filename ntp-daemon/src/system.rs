@@ -1,36 +1,61 @@
 use crate::{
-    config::{PeerConfig, ServerConfig},
+    capture::PacketCapture,
+    clock::DaemonClock,
+    clock_jump::{self, ClockEvent, ClockReading, JumpDetector},
+    config::{
+        NetworkConfig, PeerConfig, RefClockConfig, ServerConfig, SourceGroupConfig,
+        StatisticsConfig, SystemdConfig,
+    },
+    export::MeasurementExport,
+    history::ClockSample,
+    keys::Keys,
+    observer::ClockMode,
     peer::{MsgForSystem, PeerChannels, ResetEpoch},
     peer_manager::Peers,
+    persistence::PersistentState,
+    smear::SmearDetector,
+    statistics::StatsLogger,
+    systemd::Notifier,
 };
-use ntp_os_clock::UnixNtpClock;
 use ntp_proto::{
     ClockController, ClockUpdateResult, FilterAndCombine, NtpClock, NtpInstant, PeerSnapshot,
-    PollInterval, SystemConfig, SystemSnapshot,
+    PollInterval, SelectionError, SelectionIntervals, SmearPolicy, SystemConfig, SystemSnapshot,
 };
-use tracing::{error, info};
+use tracing::{error, info, instrument, warn};
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use tokio::{
-    sync::{mpsc, watch},
+    sync::{mpsc, watch, Notify},
     task::JoinHandle,
+    time::MissedTickBehavior,
 };
 
 pub struct DaemonChannels<C: NtpClock> {
     pub config: Arc<tokio::sync::RwLock<SystemConfig>>,
     pub peers: Arc<tokio::sync::RwLock<Peers<C>>>,
     pub system: Arc<tokio::sync::RwLock<SystemSnapshot>>,
+    /// Notify to stop the daemon, after which the task of the system
+    /// finishes
+    pub shutdown: Arc<Notify>,
 }
 
 /// Spawn the NTP daemon
+#[allow(clippy::too_many_arguments)]
 pub async fn spawn(
     config: SystemConfig,
     peer_configs: &[PeerConfig],
+    refclock_configs: &[RefClockConfig],
+    source_groups: &[SourceGroupConfig],
     server_configs: &[ServerConfig],
-) -> std::io::Result<(
-    JoinHandle<std::io::Result<()>>,
-    DaemonChannels<UnixNtpClock>,
-)> {
+    statistics_config: &StatisticsConfig,
+    export: MeasurementExport,
+    state: PersistentState,
+    keys: Keys,
+    capture: PacketCapture,
+    systemd_config: &SystemdConfig,
+    network_config: &NetworkConfig,
+    clock: DaemonClock,
+) -> std::io::Result<(JoinHandle<std::io::Result<()>>, DaemonChannels<DaemonClock>)> {
     // send the reset signal to all peers
     let reset_epoch: ResetEpoch = ResetEpoch::default();
     let (reset_tx, reset_rx) = watch::channel::<ResetEpoch>(reset_epoch);
@@ -38,14 +63,28 @@ pub async fn spawn(
     // receive peer snapshots from all peers
     let (msg_for_system_tx, msg_for_system_rx) = mpsc::channel::<MsgForSystem>(32);
 
-    // System snapshot
+    let clock_mode = clock.mode();
+
+    // System snapshot. Measured sources are polled at the longest interval,
+    // as it is never lowered by clock updates.
     let system_snapshot = SystemSnapshot {
         stratum: config.local_stratum,
+        poll_interval: match clock_mode {
+            ClockMode::Measuring => config.poll_limits.max,
+            _ => SystemSnapshot::default().poll_interval,
+        },
         ..Default::default()
     };
 
     // Clock controller
-    let controller = ClockController::new(UnixNtpClock::new(), &system_snapshot, &config);
+    let mut controller = ClockController::new(clock.clone(), &system_snapshot, &config);
+    if let Some(frequency) = state.take_frequency() {
+        controller.restore_frequency(frequency);
+    }
+
+    let statistics = StatsLogger::spawn(statistics_config);
+    let notifier = Notifier::from_env();
+    let ready_timeout = systemd_config.ready_timeout;
 
     // Daemon channels
     let system = Arc::new(tokio::sync::RwLock::new(system_snapshot));
@@ -56,36 +95,61 @@ pub async fn spawn(
             system_snapshots: system.clone(),
             reset: reset_rx.clone(),
             system_config: config.clone(),
+            statistics: statistics.clone(),
+            export,
+            state: state.clone(),
+            keys,
+            capture,
         },
-        UnixNtpClock::new(),
+        clock.clone(),
+        *network_config,
     );
     for peer_config in peer_configs.iter() {
         peers.add_peer(peer_config.to_owned()).await;
     }
 
+    for refclock_config in refclock_configs.iter() {
+        peers.add_refclock(refclock_config.to_owned());
+    }
+
     for server_config in server_configs.iter() {
         peers.add_server(server_config.to_owned()).await;
     }
 
     let peers = Arc::new(tokio::sync::RwLock::new(peers));
 
+    let shutdown = Arc::new(Notify::new());
+
     let channels = DaemonChannels {
         config: config.clone(),
         peers: peers.clone(),
         system: system.clone(),
+        shutdown: shutdown.clone(),
     };
 
+    let source_groups = source_groups.to_vec();
     let handle = tokio::spawn(async move {
         let mut system = System {
             config,
             global_system_snapshot: system,
             peers_rwlock: peers,
+            groups_in_use: source_groups.len(),
+            source_groups,
 
             msg_for_system_rx,
             reset_tx,
 
             reset_epoch,
+            clock,
+            clock_mode,
             controller,
+            jump_detector: JumpDetector::default(),
+            smear_detector: SmearDetector::default(),
+            statistics,
+            state,
+            notifier,
+            ready_timeout,
+            shutdown,
         };
 
         system.run().await
@@ -98,19 +162,70 @@ struct System<C: NtpClock> {
     config: Arc<tokio::sync::RwLock<SystemConfig>>,
     global_system_snapshot: Arc<tokio::sync::RwLock<SystemSnapshot>>,
     peers_rwlock: Arc<tokio::sync::RwLock<Peers<C>>>,
+    source_groups: Vec<SourceGroupConfig>,
+    /// Number of source groups whose sources were used by the latest clock
+    /// selection, all of them meaning all sources
+    groups_in_use: usize,
 
     msg_for_system_rx: mpsc::Receiver<MsgForSystem>,
     reset_tx: watch::Sender<ResetEpoch>,
 
     reset_epoch: ResetEpoch,
+    clock: C,
+    /// When the clock is only monitored, the controller is left out
+    clock_mode: ClockMode,
     controller: ClockController<C>,
+    jump_detector: JumpDetector,
+    smear_detector: SmearDetector,
+    statistics: StatsLogger,
+    state: PersistentState,
+    notifier: Notifier,
+    ready_timeout: Duration,
+    shutdown: Arc<Notify>,
 }
 
 impl<C: NtpClock> System<C> {
     async fn run(&mut self) -> std::io::Result<()> {
         let mut snapshots = Vec::with_capacity(self.peers_rwlock.read().await.size());
 
-        while let Some(msg_for_system) = self.msg_for_system_rx.recv().await {
+        // the watchdog is fed from this loop, so that it notices when the loop gets stuck
+        let watchdog_interval = self.notifier.watchdog_interval();
+        let mut watchdog =
+            tokio::time::interval(watchdog_interval.unwrap_or(Duration::from_secs(86400)));
+        let ready_timeout = tokio::time::sleep(self.ready_timeout);
+        tokio::pin!(ready_timeout);
+        let mut jump_check = tokio::time::interval(clock_jump::CHECK_INTERVAL);
+        jump_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        if self.clock_mode == ClockMode::Measuring {
+            self.notifier.measuring();
+        }
+
+        loop {
+            let msg_for_system = tokio::select! {
+                msg_for_system = self.msg_for_system_rx.recv() => match msg_for_system {
+                    Some(msg_for_system) => msg_for_system,
+                    None => break,
+                },
+                _ = watchdog.tick(), if watchdog_interval.is_some() => {
+                    self.notifier.watchdog();
+                    continue;
+                }
+                () = &mut ready_timeout, if self.notifier.waiting_for_ready() => {
+                    info!("Clock not synchronized within the ready timeout, reporting ready anyway");
+                    self.notifier.ready_timeout();
+                    continue;
+                }
+                _ = jump_check.tick() => {
+                    self.check_clock_jump().await;
+                    continue;
+                }
+                () = self.shutdown.notified() => {
+                    self.stop().await;
+                    return Ok(());
+                }
+            };
+
             let ntp_instant = NtpInstant::now();
             let system = *self.global_system_snapshot.read().await;
 
@@ -139,26 +254,119 @@ impl<C: NtpClock> System<C> {
         Ok(())
     }
 
+    /// Leave everything in order for the next start
+    async fn stop(&mut self) {
+        info!("Shutting down");
+        self.notifier.stopping();
+
+        self.statistics.flush().await;
+        if let Err(error) = self.state.save(&self.clock) {
+            warn!(?error, "could not write state file");
+        }
+    }
+
+    #[instrument(level = "debug", name = "clock update", skip_all)]
     async fn recalculate_clock(
         &mut self,
-        snapshots: &mut Vec<PeerSnapshot>,
+        snapshots: &mut Vec<(usize, PeerSnapshot)>,
         config: SystemConfig,
         system: &SystemSnapshot,
         ntp_instant: NtpInstant,
     ) {
         snapshots.clear();
-        snapshots.extend(self.peers_rwlock.read().await.valid_snapshots());
-        let result = FilterAndCombine::run(&config, &*snapshots, ntp_instant, system.poll_interval);
+        snapshots.extend(
+            self.peers_rwlock
+                .read()
+                .await
+                .ranked_snapshots(&self.source_groups),
+        );
+        let mut acceptable = vec![];
+        let mut rejected = vec![];
+        for (_, snapshot) in snapshots.iter() {
+            match snapshot.accept_synchronization(
+                ntp_instant,
+                config.frequency_tolerance,
+                config.distance_threshold,
+                system.poll_interval,
+                config.local_stratum,
+            ) {
+                Ok(()) => acceptable.push(*snapshot),
+                Err(reason) => rejected.push((snapshot.peer_id, reason)),
+            }
+        }
+
+        // smearing sources would pull the clock away from the sources that
+        // insert leap seconds, unless all of them smear
+        let smearing = self.smear_detector.detect(ntp_instant, &acceptable);
+        let exclude = config.smeared_sources == SmearPolicy::Exclude
+            && self.clock_mode != ClockMode::Measuring
+            && !smearing.is_empty()
+            && smearing.len() < acceptable.len();
+        self.peers_rwlock
+            .write()
+            .await
+            .record_smearing(&smearing, exclude);
+        if exclude {
+            snapshots.retain(|(_, snapshot)| {
+                !smearing
+                    .iter()
+                    .any(|(peer_id, _)| *peer_id == snapshot.peer_id)
+            });
+        }
+        let acceptable: Vec<_> = acceptable.iter().map(|snapshot| snapshot.peer_id).collect();
+
+        // measured sources are only judged, not selected
+        if self.clock_mode == ClockMode::Measuring {
+            self.peers_rwlock
+                .write()
+                .await
+                .record_measurement(&acceptable, &rejected);
+            return;
+        }
+
+        let (result, groups_in_use) = select_with_failover(
+            &config,
+            &self.source_groups,
+            snapshots,
+            ntp_instant,
+            system.poll_interval,
+        );
+        self.report_groups_in_use(groups_in_use);
+
+        // the intervals of the sources that took part, to show why a source was
+        // tagged a falseticker
+        let in_use: Vec<_> = snapshots
+            .iter()
+            .filter(|(rank, _)| *rank <= groups_in_use)
+            .map(|(_, snapshot)| *snapshot)
+            .collect();
+        let intervals =
+            SelectionIntervals::new(&config, &in_use, ntp_instant, system.poll_interval);
+        self.peers_rwlock.write().await.record_intervals(intervals);
+
         let clock_select = match result {
-            Some(clock_select) => clock_select,
-            None => {
-                info!("filter and combine did not produce a result");
+            Ok(clock_select) => clock_select,
+            Err(error) => {
+                info!(%error, "filter and combine did not produce a result");
+                self.peers_rwlock
+                    .write()
+                    .await
+                    .record_selection(Err(error), &rejected);
                 return;
             }
         };
+        self.peers_rwlock
+            .write()
+            .await
+            .record_selection(Ok(&clock_select.survivors), &rejected);
         let offset_ms = clock_select.system_offset.to_seconds() * 1000.0;
         let jitter_ms = clock_select.system_jitter.to_seconds() * 1000.0;
         info!(offset_ms, jitter_ms, "Measured offset and jitter");
+        if self.clock_mode.is_monitor_only() {
+            self.report_monitored(&clock_select).await;
+            return;
+        }
+
         let adjust_type = self.controller.update(
             &config,
             system,
@@ -177,20 +385,149 @@ impl<C: NtpClock> System<C> {
                 std::process::exit(exitcode::SOFTWARE);
             }
             ClockUpdateResult::Step => {
+                self.jump_detector.reset();
                 self.reset_peers().await;
             }
             _ => {}
         }
         if adjust_type != ClockUpdateResult::Ignore {
+            self.statistics.clock_update(
+                self.controller.offset(),
+                self.controller.frequency(),
+                self.controller.jitter(),
+                self.controller.preferred_poll_interval().as_log(),
+            );
+            self.state.update_frequency(self.controller.frequency());
+            self.peers_rwlock
+                .write()
+                .await
+                .record_clock_update(ClockSample::new(
+                    self.clock.now().unwrap_or_default(),
+                    self.controller.offset(),
+                    self.controller.frequency(),
+                    self.controller.jitter(),
+                ));
+
             let mut global = self.global_system_snapshot.write().await;
             global.poll_interval = self.controller.preferred_poll_interval();
             global.leap_indicator = clock_select.system_peer_snapshot.leap_indicator;
             global.stratum = clock_select.system_peer_snapshot.stratum.saturating_add(1);
             global.reference_id = clock_select.system_peer_snapshot.peer_id;
+            match self.clock.now() {
+                Ok(now) => global.reference_timestamp = now,
+                Err(error) => warn!(%error, "could not read the system clock"),
+            }
             global.accumulated_steps = self.controller.accumulated_steps();
             global.accumulated_steps_threshold = config.accumulated_threshold;
             global.root_delay = clock_select.system_root_delay;
             global.root_dispersion = clock_select.system_root_dispersion;
+
+            self.notifier
+                .clock_update(&global, self.controller.offset());
+        }
+    }
+
+    /// Report the measured offset of a clock that is only monitored. The
+    /// system is synchronized as far as the sources are concerned, with the
+    /// offset, which is not corrected, added to its dispersion.
+    async fn report_monitored(&mut self, clock_select: &FilterAndCombine) {
+        let now = match self.clock.now() {
+            Ok(now) => Some(now),
+            Err(error) => {
+                warn!(%error, "could not read the system clock");
+                None
+            }
+        };
+        self.peers_rwlock
+            .write()
+            .await
+            .record_clock_update(ClockSample::new(
+                now.unwrap_or_default(),
+                clock_select.system_offset,
+                0.0,
+                clock_select.system_jitter,
+            ));
+
+        let mut global = self.global_system_snapshot.write().await;
+        global.leap_indicator = clock_select.system_peer_snapshot.leap_indicator;
+        global.stratum = clock_select.system_peer_snapshot.stratum.saturating_add(1);
+        global.reference_id = clock_select.system_peer_snapshot.peer_id;
+        if let Some(now) = now {
+            global.reference_timestamp = now;
+        }
+        global.root_delay = clock_select.system_root_delay;
+        global.root_dispersion =
+            clock_select.system_root_dispersion + clock_select.system_offset.abs();
+
+        self.notifier
+            .monitor_update(&global, clock_select.system_offset);
+    }
+
+    fn report_groups_in_use(&mut self, groups_in_use: usize) {
+        if groups_in_use == self.groups_in_use {
+            return;
+        }
+        self.groups_in_use = groups_in_use;
+
+        match self.source_groups.get(groups_in_use) {
+            Some(group) => info!(
+                group = group.name,
+                "Using the sources of the source groups up to and including this one"
+            ),
+            None => info!("Too few survivors in the source groups, using all sources"),
+        }
+    }
+
+    /// Start over with fresh measurements when the clock was stepped by
+    /// someone else, or the system was suspended
+    async fn check_clock_jump(&mut self) {
+        let monotonic = std::time::Instant::now();
+        let realtime = match self.clock.now() {
+            Ok(realtime) => realtime,
+            Err(error) => {
+                warn!(%error, "could not read the system clock");
+                return;
+            }
+        };
+        // without a boot time clock, suspends show up as steps of the clock
+        let suspended = ntp_os_clock::time_suspended().unwrap_or_default();
+
+        let reading = ClockReading {
+            monotonic,
+            realtime,
+            suspended,
+        };
+        match self.jump_detector.check(reading) {
+            None => {}
+            Some(ClockEvent::Jump(jump)) => {
+                warn!(
+                    jump = jump.to_seconds(),
+                    "System clock was stepped by another process, discarding measurements"
+                );
+                self.restart_synchronization().await;
+                self.reset_peers().await;
+            }
+            Some(ClockEvent::Resume(suspended)) => {
+                info!(
+                    suspended = suspended.as_secs_f64(),
+                    "System resumed from suspend, reacquiring the time"
+                );
+                self.restart_synchronization().await;
+                self.peers_rwlock.write().await.reset_all();
+                self.reset_epoch = self.reset_epoch.inc_resumed();
+                self.reset_tx.send_replace(self.reset_epoch);
+            }
+        }
+    }
+
+    /// Let the clock controller and peers start over quickly, as the time
+    /// may be far off
+    async fn restart_synchronization(&mut self) {
+        let config = *self.config.read().await;
+        let mut global = self.global_system_snapshot.write().await;
+        self.controller.reset(&global, &config);
+        if self.clock_mode != ClockMode::Measuring {
+            global.poll_interval = self.controller.preferred_poll_interval();
         }
     }
 
@@ -201,6 +538,49 @@ impl<C: NtpClock> System<C> {
     }
 }
 
+/// Clock selection among the sources of the first source group, to which the
+/// sources of the next group are added for as long as fewer of them survive
+/// than the last added group requires. Sources without a group are added after
+/// the last group. Returns the outcome of the selection together with the
+/// position of the last group that was used.
+fn select_with_failover(
+    config: &SystemConfig,
+    groups: &[SourceGroupConfig],
+    sources: &[(usize, PeerSnapshot)],
+    ntp_instant: NtpInstant,
+    poll_interval: PollInterval,
+) -> (Result<FilterAndCombine, SelectionError>, usize) {
+    let mut snapshots = Vec::with_capacity(sources.len());
+    let mut rank = 0;
+    loop {
+        let added = snapshots.len();
+        snapshots.extend(
+            sources
+                .iter()
+                .filter(|(source_rank, _)| *source_rank == rank)
+                .map(|(_, snapshot)| *snapshot),
+        );
+
+        // a group without valid sources does not change the outcome
+        let last = rank >= groups.len();
+        if last || snapshots.len() > added {
+            let result = FilterAndCombine::run(config, &snapshots, ntp_instant, poll_interval);
+            let enough = match (&result, groups.get(rank)) {
+                (Ok(clock_select), Some(group)) => {
+                    clock_select.survivors.len() >= group.min_survivors
+                }
+                (Err(_), Some(_)) => false,
+                (_, None) => true,
+            };
+            if enough {
+                return (result, rank);
+            }
+        }
+
+        rank += 1;
+    }
+}
+
 fn requires_clock_recalculation(
     msg: MsgForSystem,
     current_reset_epoch: ResetEpoch,
@@ -229,7 +609,7 @@ fn requires_clock_recalculation(
 mod tests {
     use ntp_proto::{
         peer_snapshot, NtpDuration, NtpLeapIndicator, NtpTimestamp, PeerStatistics,
-        PollIntervalLimits,
+        PollIntervalLimits, ReferenceId,
     };
 
     use crate::{
@@ -269,6 +649,95 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_select_with_failover() {
+        let base = NtpInstant::now();
+        let snapshot = |id, offset| PeerSnapshot {
+            peer_id: ReferenceId::from_bytes([0, 0, 0, id]),
+            ..peer_snapshot(
+                PeerStatistics {
+                    delay: NtpDuration::from_seconds(0.01),
+                    offset: NtpDuration::from_seconds(offset),
+                    dispersion: NtpDuration::from_seconds(0.01),
+                    jitter: 0.001,
+                },
+                base,
+                NtpDuration::ZERO,
+                NtpDuration::ZERO,
+            )
+        };
+
+        let config = SystemConfig {
+            min_intersection_survivors: 1,
+            ..Default::default()
+        };
+        let groups = [
+            SourceGroupConfig {
+                name: "internal".into(),
+                min_survivors: 2,
+            },
+            SourceGroupConfig {
+                name: "public".into(),
+                min_survivors: 1,
+            },
+        ];
+        let select = |sources: &[(usize, PeerSnapshot)]| {
+            select_with_failover(
+                &config,
+                &groups,
+                sources,
+                base,
+                PollIntervalLimits::default().min,
+            )
+        };
+
+        // two survivors of the first group are enough
+        let (result, used) = select(&[
+            (0, snapshot(1, 0.0)),
+            (0, snapshot(2, 0.001)),
+            (1, snapshot(3, 0.0)),
+        ]);
+        assert_eq!(used, 0);
+        assert_eq!(result.unwrap().survivors.len(), 2);
+
+        // one is not, so the second group is used as well
+        let (result, used) = select(&[
+            (0, snapshot(1, 0.0)),
+            (1, snapshot(3, 0.0)),
+            (1, snapshot(4, 0.001)),
+        ]);
+        assert_eq!(used, 1);
+        assert_eq!(result.unwrap().survivors.len(), 3);
+
+        // a group without sources is passed over
+        let (result, used) = select(&[(1, snapshot(3, 0.0)), (2, snapshot(5, 0.0))]);
+        assert_eq!(used, 1);
+        assert_eq!(
+            result.unwrap().survivors,
+            [ReferenceId::from_bytes([0, 0, 0, 3])]
+        );
+
+        // sources without a group are only used when all groups fall short
+        let (result, used) = select(&[(2, snapshot(5, 0.0))]);
+        assert_eq!(used, 2);
+        assert_eq!(result.unwrap().survivors.len(), 1);
+
+        let (result, used) = select(&[]);
+        assert_eq!(used, 2);
+        assert!(result.is_err());
+
+        // without groups, all sources are used at once
+        let (result, used) = select_with_failover(
+            &config,
+            &[],
+            &[(0, snapshot(1, 0.0)), (0, snapshot(2, 0.001))],
+            base,
+            PollIntervalLimits::default().min,
+        );
+        assert_eq!(used, 0);
+        assert_eq!(result.unwrap().survivors.len(), 2);
+    }
+
     #[test]
     fn test_requires_clock_recalculation() {
         let base = NtpInstant::now();
@@ -393,15 +862,55 @@ mod tests {
             &[
                 PeerConfig::Standard(StandardPeerConfig {
                     addr: NormalizedAddress::new_unchecked("127.0.0.1:123"),
+                    bind: Default::default(),
+                    max_offset: None,
+                    poll: Default::default(),
+                    delay: Default::default(),
+                    version: Default::default(),
+                    parsing: Default::default(),
+                    association: Default::default(),
+                    dual_stack: false,
+                    key: None,
+                    source_group: None,
                 }),
                 PeerConfig::Standard(StandardPeerConfig {
                     addr: NormalizedAddress::new_unchecked("127.0.0.2:123"),
+                    bind: Default::default(),
+                    max_offset: None,
+                    poll: Default::default(),
+                    delay: Default::default(),
+                    version: Default::default(),
+                    parsing: Default::default(),
+                    association: Default::default(),
+                    dual_stack: false,
+                    key: None,
+                    source_group: None,
                 }),
                 PeerConfig::Standard(StandardPeerConfig {
                     addr: NormalizedAddress::new_unchecked("127.0.0.3:123"),
+                    bind: Default::default(),
+                    max_offset: None,
+                    poll: Default::default(),
+                    delay: Default::default(),
+                    version: Default::default(),
+                    parsing: Default::default(),
+                    association: Default::default(),
+                    dual_stack: false,
+                    key: None,
+                    source_group: None,
                 }),
                 PeerConfig::Standard(StandardPeerConfig {
                     addr: NormalizedAddress::new_unchecked("127.0.0.4:123"),
+                    bind: Default::default(),
+                    max_offset: None,
+                    poll: Default::default(),
+                    delay: Default::default(),
+                    version: Default::default(),
+                    parsing: Default::default(),
+                    association: Default::default(),
+                    dual_stack: false,
+                    key: None,
+                    source_group: None,
                 }),
             ],
             TestClock {},
@@ -414,16 +923,27 @@ mod tests {
                 config,
                 global_system_snapshot,
                 peers_rwlock,
+                source_groups: vec![],
+                groups_in_use: 0,
 
                 msg_for_system_rx,
                 reset_tx,
 
                 reset_epoch,
+                clock: TestClock {},
+                clock_mode: ClockMode::Steering,
+                jump_detector: Default::default(),
+                smear_detector: Default::default(),
                 controller: ClockController::new(
                     TestClock {},
                     &SystemSnapshot::default(),
                     &SystemConfig::default(),
                 ),
+                statistics: Default::default(),
+                state: Default::default(),
+                notifier: Default::default(),
+                ready_timeout: Duration::from_secs(60),
+                shutdown: Default::default(),
             };
 
             system.run().await
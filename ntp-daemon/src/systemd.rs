@@ -0,0 +1,244 @@
+//! Notifications to the service manager, for running as a `Type=notify`
+//! systemd service (see `sd_notify(3)`).
+//!
+//! Readiness is only reported once the clock is synchronized, or when the
+//! configured timeout passes without that happening, such that units ordered
+//! after `time-sync.target` start with a good clock. Notifications are only
+//! sent when the daemon is built with the `systemd` feature and started by a
+//! service manager that asks for them.
+
+use std::{
+    os::unix::{net::UnixDatagram, prelude::AsRawFd},
+    path::PathBuf,
+    time::Duration,
+};
+
+use ntp_proto::{format_reference_id, NtpDuration, SystemSnapshot};
+use tracing::warn;
+
+#[derive(Debug, Default)]
+pub(crate) struct Notifier {
+    socket: Option<(UnixDatagram, PathBuf)>,
+    watchdog: Option<Duration>,
+    ready: bool,
+}
+
+impl Notifier {
+    /// Notifier for the service manager given in the environment, if any
+    pub(crate) fn from_env() -> Self {
+        let path = match notify_socket() {
+            Some(path) => path,
+            None => return Notifier::default(),
+        };
+
+        // the watchdog might be meant for another process of the service
+        let for_us = match std::env::var("WATCHDOG_PID") {
+            Ok(pid) => pid == std::process::id().to_string(),
+            Err(_) => true,
+        };
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .filter(|_| for_us)
+            .and_then(|usec| usec.parse().ok())
+            .map(Duration::from_micros);
+
+        Notifier::new(path, watchdog)
+    }
+
+    fn new(path: PathBuf, watchdog: Option<Duration>) -> Self {
+        match UnixDatagram::unbound() {
+            Ok(socket) => Notifier {
+                socket: Some((socket, path)),
+                watchdog,
+                ready: false,
+            },
+            Err(error) => {
+                warn!(?error, "could not create notification socket");
+                Notifier::default()
+            }
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some((socket, path)) = &self.socket {
+            if let Err(error) = socket.send_to(state.as_bytes(), path) {
+                warn!(?error, "could not notify the service manager");
+            }
+        }
+    }
+
+    /// Interval at which the watchdog must be fed, which is half the
+    /// timeout requested by the service manager
+    pub(crate) fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|timeout| timeout / 2)
+    }
+
+    pub(crate) fn watchdog(&self) {
+        self.notify("WATCHDOG=1");
+    }
+
+    /// Whether readiness still has to be reported
+    pub(crate) fn waiting_for_ready(&self) -> bool {
+        self.socket.is_some() && !self.ready
+    }
+
+    /// Report readiness without a synchronized clock
+    pub(crate) fn ready_timeout(&mut self) {
+        self.ready = true;
+        self.notify("READY=1\nSTATUS=Not synchronized yet");
+    }
+
+    /// Report that the daemon is shutting down
+    pub(crate) fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// Report a clock update, which makes the daemon ready once the clock is
+    /// synchronized
+    pub(crate) fn clock_update(&mut self, system: &SystemSnapshot, offset: NtpDuration) {
+        if !system.leap_indicator.is_synchronized() {
+            return;
+        }
+
+        let status = format!(
+            "STATUS=Synchronized to {} (stratum {}), offset {:.3} ms",
+            format_reference_id(system.reference_id, system.stratum),
+            system.stratum,
+            offset.to_seconds() * 1000.0
+        );
+        self.report(status);
+    }
+
+    /// Report a measurement of a clock that is only monitored, which makes
+    /// the daemon ready once the sources agree on the time
+    pub(crate) fn monitor_update(&mut self, system: &SystemSnapshot, offset: NtpDuration) {
+        if !system.leap_indicator.is_synchronized() {
+            return;
+        }
+
+        let status = format!(
+            "STATUS=Monitoring only, the clock is not adjusted: offset {:.3} ms to {} (stratum {})",
+            offset.to_seconds() * 1000.0,
+            format_reference_id(system.reference_id, system.stratum),
+            system.stratum,
+        );
+        self.report(status);
+    }
+
+    /// Report that the daemon only measures its sources, which makes it ready
+    /// right away, as there is no clock to synchronize
+    pub(crate) fn measuring(&mut self) {
+        self.report("STATUS=Measuring the sources only, the clock is not adjusted".into());
+    }
+
+    fn report(&mut self, status: String) {
+        if self.ready {
+            self.notify(&status);
+        } else {
+            self.ready = true;
+            self.notify(&format!("READY=1\n{}", status));
+        }
+    }
+}
+
+/// Path of the notification socket of the service manager, if any
+fn notify_socket() -> Option<PathBuf> {
+    if !cfg!(feature = "systemd") {
+        return None;
+    }
+
+    let path = PathBuf::from(std::env::var_os("NOTIFY_SOCKET")?);
+    if path.to_string_lossy().starts_with('@') {
+        warn!(?path, "abstract notification sockets are not supported");
+        return None;
+    }
+
+    Some(path)
+}
+
+/// Keep a server socket in the file descriptor store of the service manager,
+/// when the service has one (`FileDescriptorStoreMax=`, announced in
+/// `FDSTORE` since systemd 254). The socket is passed again when the daemon
+/// restarts, such that it stays bound, and requests that arrive in between
+/// are answered after the restart instead of being lost.
+pub(crate) fn store_socket(socket: &impl AsRawFd) {
+    let path = match notify_socket() {
+        Some(path) => path,
+        None => return,
+    };
+
+    let store_size = std::env::var("FDSTORE")
+        .ok()
+        .and_then(|size| size.parse::<u32>().ok())
+        .unwrap_or(0);
+    if store_size == 0 {
+        return;
+    }
+
+    if let Err(error) = ntp_udp::store_sockets(&path, "ntp-server", &[socket.as_raw_fd()]) {
+        warn!(?error, "could not store socket with the service manager");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntp_proto::{NtpLeapIndicator, ReferenceId};
+
+    use super::*;
+
+    #[test]
+    fn test_notifications() {
+        let path = std::env::temp_dir().join("ntp-test-systemd-notify");
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        manager
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let mut notifier = Notifier::new(path.clone(), Some(Duration::from_secs(30)));
+        assert_eq!(notifier.watchdog_interval(), Some(Duration::from_secs(15)));
+        assert!(notifier.waiting_for_ready());
+
+        let mut buf = [0; 256];
+        let mut receive = || {
+            let size = manager.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..size].to_vec()).unwrap()
+        };
+
+        // not synchronized, so nothing to report yet
+        let mut system = SystemSnapshot::default();
+        notifier.clock_update(&system, NtpDuration::ZERO);
+        notifier.watchdog();
+        assert_eq!(receive(), "WATCHDOG=1");
+
+        system.leap_indicator = NtpLeapIndicator::NoWarning;
+        system.stratum = 2;
+        system.reference_id = ReferenceId::from_ip("192.0.2.1".parse().unwrap());
+        notifier.clock_update(&system, NtpDuration::from_seconds(0.00125));
+        assert_eq!(
+            receive(),
+            "READY=1\nSTATUS=Synchronized to 192.0.2.1 (stratum 2), offset 1.250 ms"
+        );
+        assert!(!notifier.waiting_for_ready());
+
+        notifier.clock_update(&system, NtpDuration::from_seconds(-0.0005));
+        assert_eq!(
+            receive(),
+            "STATUS=Synchronized to 192.0.2.1 (stratum 2), offset -0.500 ms"
+        );
+
+        notifier.monitor_update(&system, NtpDuration::from_seconds(0.25));
+        assert_eq!(
+            receive(),
+            "STATUS=Monitoring only, the clock is not adjusted: offset 250.000 ms to 192.0.2.1 (stratum 2)"
+        );
+
+        notifier.measuring();
+        assert_eq!(
+            receive(),
+            "STATUS=Measuring the sources only, the clock is not adjusted"
+        );
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
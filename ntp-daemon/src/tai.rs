@@ -0,0 +1,284 @@
+//! Upkeep of the offset of TAI to UTC in the kernel, which makes
+//! `CLOCK_TAI` usable.
+//!
+//! NTP only announces upcoming leap seconds, not how many there have been,
+//! so the offset is taken from the list of leap seconds that the IERS
+//! publishes, and that is shipped with the time zone database as
+//! `leap-seconds.list`. The kernel changes the offset by itself when it
+//! inserts a leap second, the list is checked periodically to catch leap
+//! seconds that the sources did not announce, and updates of the list.
+//!
+//! The same list converts the time of a machine that keeps TAI to the UTC
+//! of NTP, see [`LeapSecondsList`].
+
+use std::{
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ntp_os_clock::UnixNtpClock;
+use ntp_proto::{NtpDuration, NtpTimestamp};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::config::{ClockBackend, ClockConfig, Timescale};
+
+/// How often the list of leap seconds is read again
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Seconds from the NTP epoch (1900) to the unix epoch (1970)
+const UNIX_TO_NTP: u64 = 2_208_988_800;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LeapSeconds {
+    /// Times in seconds since the NTP epoch at which the offset of TAI to UTC
+    /// changed, with the new offset, in order
+    changes: Vec<(u64, i32)>,
+    /// Time in seconds since the NTP epoch after which the list is not
+    /// known to be complete
+    expires: Option<u64>,
+}
+
+impl LeapSeconds {
+    /// Parse the format of `leap-seconds.list`: lines with a time and an
+    /// offset, and comments starting with `#`, one of which, `#@`, gives the
+    /// expiry of the list
+    fn parse(data: &str) -> Option<Self> {
+        let mut changes: Vec<(u64, i32)> = vec![];
+        let mut expires = None;
+
+        for line in data.lines() {
+            if let Some(expiry) = line.strip_prefix("#@") {
+                expires = Some(expiry.trim().parse().ok()?);
+                continue;
+            }
+
+            let line = match line.split_once('#') {
+                Some((before, _)) => before,
+                None => line,
+            };
+            let mut fields = line.split_whitespace();
+            let time = match fields.next() {
+                Some(time) => time.parse().ok()?,
+                None => continue,
+            };
+            let offset = fields.next()?.parse().ok()?;
+
+            if matches!(changes.last(), Some(&(last, _)) if last >= time) {
+                return None;
+            }
+            changes.push((time, offset));
+        }
+
+        match changes.is_empty() {
+            true => None,
+            false => Some(LeapSeconds { changes, expires }),
+        }
+    }
+
+    /// The offset of TAI to UTC at `time` seconds since the NTP epoch
+    fn offset_at(&self, time: u64) -> Option<i32> {
+        self.changes
+            .iter()
+            .take_while(|&&(change, _)| change <= time)
+            .last()
+            .map(|&(_, offset)| offset)
+    }
+
+    /// The offset of TAI to UTC at `time` seconds since the NTP epoch in
+    /// TAI. During an inserted leap second, UTC repeats the last second of
+    /// the day, as the kernel does.
+    fn offset_at_tai(&self, time: u64) -> Option<i32> {
+        let mut current: Option<i32> = None;
+        for &(change, offset) in &self.changes {
+            let effective = current.map_or(offset, |previous| previous.min(offset));
+            if change as i128 + effective as i128 > time as i128 {
+                break;
+            }
+            current = Some(offset);
+        }
+        current
+    }
+
+    fn expired_at(&self, time: u64) -> bool {
+        matches!(self.expires, Some(expires) if expires < time)
+    }
+}
+
+/// The list of leap seconds for a clock that keeps TAI, which is read again
+/// periodically by the task of [`spawn`]. Clones share the list.
+#[derive(Debug, Clone)]
+pub struct LeapSecondsList(Arc<RwLock<LeapSeconds>>);
+
+impl LeapSecondsList {
+    pub(crate) fn load(path: &Path) -> Option<Self> {
+        read(path).map(|leap_seconds| LeapSecondsList(Arc::new(RwLock::new(leap_seconds))))
+    }
+
+    fn replace(&self, leap_seconds: LeapSeconds) {
+        // the list is replaced as a whole, a panic can not leave it halfway
+        match self.0.write() {
+            Ok(mut guard) => *guard = leap_seconds,
+            Err(poisoned) => *poisoned.into_inner() = leap_seconds,
+        }
+    }
+
+    /// The offset of TAI to UTC at `time`, which is in TAI
+    pub(crate) fn offset_at_tai(&self, time: NtpTimestamp) -> NtpDuration {
+        let (seconds, _) = time.to_unix_seconds_nanos();
+        let seconds = (seconds + UNIX_TO_NTP as i64).max(0) as u64;
+        let offset = match self.0.read() {
+            Ok(guard) => guard.offset_at_tai(seconds),
+            Err(poisoned) => poisoned.into_inner().offset_at_tai(seconds),
+        };
+
+        NtpDuration::from_seconds(offset.unwrap_or(0) as f64)
+    }
+}
+
+fn ntp_seconds_now() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    since_epoch.as_secs() + UNIX_TO_NTP
+}
+
+fn read(path: &Path) -> Option<LeapSeconds> {
+    match std::fs::read_to_string(path) {
+        Ok(data) => {
+            let leap_seconds = LeapSeconds::parse(&data);
+            if leap_seconds.is_none() {
+                warn!(?path, "could not parse the leap seconds file");
+            }
+            leap_seconds
+        }
+        Err(error) => {
+            warn!(?path, ?error, "could not read the leap seconds file");
+            None
+        }
+    }
+}
+
+/// Set the offset of TAI in the kernel when it differs from the list
+fn check(leap_seconds: &LeapSeconds, clock: &UnixNtpClock) {
+    let now = ntp_seconds_now();
+    let offset = match leap_seconds.offset_at(now) {
+        Some(offset) => offset,
+        None => return,
+    };
+
+    match clock.tai_offset() {
+        Ok(current) if current == offset => {}
+        Ok(current) => match clock.set_tai_offset(offset) {
+            Ok(()) => info!(
+                offset,
+                previous = current,
+                "set the TAI offset of the clock"
+            ),
+            Err(error) => warn!(%error, "could not set the TAI offset of the clock"),
+        },
+        Err(error) => warn!(%error, "could not read the TAI offset of the clock"),
+    }
+}
+
+/// Keep the TAI offset of the system clock up to date, when configured, and
+/// the list of leap seconds of a clock that keeps TAI
+pub fn spawn(
+    config: &ClockConfig,
+    leap_seconds: Option<LeapSecondsList>,
+) -> Option<JoinHandle<()>> {
+    // in a time namespace, the system clock belongs to the host, and a system
+    // clock that keeps TAI has no offset to TAI
+    let maintain = config.maintain_tai
+        && config.backend == ClockBackend::System
+        && config.timescale != Timescale::Tai;
+    if !maintain && leap_seconds.is_none() {
+        return None;
+    }
+
+    let path = config.leap_seconds_file.clone();
+    Some(tokio::spawn(async move {
+        let clock = UnixNtpClock::new();
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        let mut warned_expired = false;
+
+        loop {
+            interval.tick().await;
+
+            let parsed = match read(&path) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+
+            let expired = parsed.expired_at(ntp_seconds_now());
+            if expired && !warned_expired {
+                warn!(
+                    ?path,
+                    "leap seconds file has expired, it may miss leap seconds"
+                );
+            }
+            warned_expired = expired;
+
+            if maintain {
+                check(&parsed, &clock);
+            }
+            if let Some(leap_seconds) = &leap_seconds {
+                leap_seconds.replace(parsed);
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIST: &str = "\
+#	This file is an excerpt of leap-seconds.list
+#
+#$	 3913697179
+#@	3960057600
+#
+2272060800	10	# 1 Jan 1972
+2287785600	11	# 1 Jul 1972
+3644697600	36	# 1 Jul 2015
+3692217600	37	# 1 Jan 2017
+#
+#h	16edd0f0 3666784f 37db6bdd e74ced87 59af48f1
+";
+
+    #[test]
+    fn test_parse() {
+        let leap_seconds = LeapSeconds::parse(LIST).unwrap();
+        assert_eq!(leap_seconds.changes.len(), 4);
+        assert_eq!(leap_seconds.changes[3], (3692217600, 37));
+        assert_eq!(leap_seconds.expires, Some(3960057600));
+
+        assert_eq!(LeapSeconds::parse(""), None);
+        assert_eq!(LeapSeconds::parse("# only comments\n"), None);
+        assert_eq!(LeapSeconds::parse("2272060800\n"), None);
+        assert_eq!(LeapSeconds::parse("2272060800 ten\n"), None);
+        // the changes must be in order
+        assert_eq!(LeapSeconds::parse("2287785600 11\n2272060800 10\n"), None);
+    }
+
+    #[test]
+    fn test_offset_at() {
+        let leap_seconds = LeapSeconds::parse(LIST).unwrap();
+        assert_eq!(leap_seconds.offset_at(2272060799), None);
+        assert_eq!(leap_seconds.offset_at(2272060800), Some(10));
+        assert_eq!(leap_seconds.offset_at(3692217599), Some(36));
+        assert_eq!(leap_seconds.offset_at(3692217600), Some(37));
+        assert_eq!(leap_seconds.offset_at(u64::MAX), Some(37));
+
+        // in TAI, the offset changes when TAI reaches the change
+        assert_eq!(leap_seconds.offset_at_tai(3692217600 + 35), Some(36));
+        assert_eq!(leap_seconds.offset_at_tai(3692217600 + 36), Some(37));
+        assert_eq!(leap_seconds.offset_at_tai(2272060800 + 9), None);
+        assert_eq!(leap_seconds.offset_at_tai(2272060800 + 10), Some(10));
+
+        assert!(!leap_seconds.expired_at(3960057600));
+        assert!(leap_seconds.expired_at(3960057601));
+    }
+}
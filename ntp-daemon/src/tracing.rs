@@ -1,5 +1,5 @@
 use crate::config::{
-    format::{LogFormat, LogFormatFields},
+    format::{EventFormat, LogFormat, LogFormatFields},
     Config,
 };
 use tracing::info;
@@ -12,7 +12,7 @@ type GuardType = ();
 
 pub type ReloadHandle = tracing_subscriber::reload::Handle<
     Filtered<
-        tracing_subscriber::fmt::Layer<Registry, LogFormatFields, LogFormat>,
+        tracing_subscriber::fmt::Layer<Registry, LogFormatFields, EventFormat>,
         EnvFilter,
         Registry,
     >,
@@ -37,7 +37,10 @@ pub fn init(
     use tracing_subscriber::prelude::*;
     let layer = tracing_subscriber::fmt::layer()
         .fmt_fields(format.get_format_fields())
-        .event_format(format)
+        .event_format(EventFormat {
+            format: format.clone(),
+            journald: false,
+        })
         .with_filter(filter);
     let (fmt_layer, fmt_handle) = tracing_subscriber::reload::Layer::new(layer);
 
@@ -75,15 +78,21 @@ pub fn init(
             None
         };
 
-        if has_format_override {
+        let format = if has_format_override {
             info!("Log format override from command line arguments is active");
+            format
         } else {
-            fmt_handle.modify(|l| {
-                *l.inner_mut() = tracing_subscriber::fmt::layer()
-                    .fmt_fields(config.log_format.get_format_fields())
-                    .event_format(config.log_format.clone());
-            })?;
-        }
+            config.log_format.clone()
+        };
+
+        // the journal has no use for colors
+        let journald = config.logging.journald;
+        fmt_handle.modify(|l| {
+            *l.inner_mut() = tracing_subscriber::fmt::layer()
+                .fmt_fields(format.get_format_fields())
+                .event_format(EventFormat { format, journald })
+                .with_ansi(!journald);
+        })?;
 
         if let Some(log_filter) = config.log_filter.take() {
             if has_log_override {
@@ -93,6 +102,18 @@ pub fn init(
             }
         }
 
+        let targets = std::mem::take(&mut config.logging.targets);
+        if !targets.is_empty() {
+            if has_log_override {
+                info!("Log filter override from command line arguments is active, ignoring logging targets");
+            } else {
+                fmt_handle.modify(|l| {
+                    let filter = std::mem::take(l.filter_mut());
+                    *l.filter_mut() = targets.into_iter().fold(filter, EnvFilter::add_directive);
+                })?;
+            }
+        }
+
         let state = TracingState {
             guard,
             reload_handle: fmt_handle,
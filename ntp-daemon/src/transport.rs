@@ -0,0 +1,227 @@
+//! How packets are exchanged with a peer. The peer task only deals with whole
+//! NTP packets and their timestamps, such that it can be driven by other
+//! transports than UDP sockets, such as a simulated network.
+
+use std::{
+    future::Future,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use ntp_proto::NtpTimestamp;
+use ntp_udp::{IcmpError, UdpSocket};
+use tracing::debug;
+
+use crate::{
+    config::{NetworkConfig, PeerBindConfig},
+    peer::MAX_NTP_PACKET_SIZE,
+    socks5::{self, Association},
+};
+
+pub(crate) trait Transport: Send {
+    /// Send a packet to the peer, returning the time it was sent when the
+    /// transport knows it more precisely than the time before sending
+    fn send(
+        &mut self,
+        packet: &[u8],
+    ) -> impl Future<Output = io::Result<Option<NtpTimestamp>>> + Send;
+
+    /// Receive a packet from the peer, returning its size and the time it was
+    /// received. Fails with `ConnectionAborted` when the transport must be
+    /// reopened before it can be used again.
+    fn recv(
+        &mut self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, Option<NtpTimestamp>)>> + Send;
+
+    /// The ICMP error that explains a failed receive, if any
+    fn take_icmp_error(&self) -> io::Result<Option<IcmpError>> {
+        Ok(None)
+    }
+
+    /// The local address packets are sent from
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Whether the transport is reopened before every request
+    fn reopen_per_request(&self) -> bool {
+        false
+    }
+
+    /// Replace the connection to the peer by a new one. Packets sent over the
+    /// previous connection are no longer received.
+    fn reopen(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+/// A UDP socket to the peer, or to the relay of a SOCKS5 proxy. Without
+/// `nat.connected` the socket is not connected, and receives from any address.
+pub(crate) struct UdpTransport {
+    socket: UdpSocket,
+    /// Association with the proxy through which packets to the peer are
+    /// relayed, the socket then being connected to the proxy
+    proxy: Option<Association>,
+
+    /// Needed to open new sockets to the peer
+    addr: SocketAddr,
+    bind: PeerBindConfig,
+    network: NetworkConfig,
+}
+
+impl UdpTransport {
+    /// Open a socket to the peer, from the configured local end. With a
+    /// proxy, the socket is connected to the relay of a new association with
+    /// the proxy.
+    pub(crate) async fn open(
+        addr: SocketAddr,
+        bind: &PeerBindConfig,
+        network: NetworkConfig,
+    ) -> io::Result<Self> {
+        let (socket, proxy) = open_socket(addr, bind, network).await?;
+        Ok(UdpTransport {
+            socket,
+            proxy,
+            addr,
+            bind: bind.clone(),
+            network,
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_socket(socket: UdpSocket, addr: SocketAddr) -> Self {
+        UdpTransport {
+            socket,
+            proxy: None,
+            addr,
+            bind: Default::default(),
+            network: Default::default(),
+        }
+    }
+}
+
+impl Transport for UdpTransport {
+    async fn send(&mut self, packet: &[u8]) -> io::Result<Option<NtpTimestamp>> {
+        let mut buf = [0; socks5::MAX_HEADER_SIZE + MAX_NTP_PACKET_SIZE];
+        let data = match self.proxy {
+            Some(_) => {
+                let header = socks5::write_header(self.addr, &mut buf);
+                let len = packet.len().min(buf.len() - header);
+                buf[header..header + len].copy_from_slice(&packet[..len]);
+                &buf[..header + len]
+            }
+            None => packet,
+        };
+
+        if !self.bind.nat.connected {
+            self.socket.send_to(data, self.addr).await?;
+            return Ok(None);
+        }
+
+        let (_written, send_timestamp) = self.socket.send(data).await?;
+        Ok(send_timestamp)
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, Option<NtpTimestamp>)> {
+        let proxy = match &mut self.proxy {
+            Some(proxy) => proxy,
+            None => {
+                let (size, _, timestamp) = self.socket.recv(buf).await?;
+                return Ok((size, timestamp));
+            }
+        };
+
+        let mut relayed = [0; socks5::MAX_HEADER_SIZE + MAX_NTP_PACKET_SIZE];
+        loop {
+            let (size, _, timestamp) = tokio::select! {
+                result = self.socket.recv(&mut relayed) => result?,
+                () = proxy.closed() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "the proxy ended the association",
+                    ))
+                }
+            };
+
+            // strip the header of packets relayed by the proxy
+            let data = &relayed[..size.min(relayed.len())];
+            match socks5::parse_header(data) {
+                Some((source, header)) if source == self.addr => {
+                    let len = (size - header).min(buf.len());
+                    buf[..len].copy_from_slice(&data[header..header + len]);
+                    return Ok((size - header, timestamp));
+                }
+                _ => debug!("ignoring a packet from the proxy that was not relayed from the peer"),
+            }
+        }
+    }
+
+    fn take_icmp_error(&self) -> io::Result<Option<IcmpError>> {
+        self.socket.take_icmp_error()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.as_ref().local_addr()
+    }
+
+    fn reopen_per_request(&self) -> bool {
+        !self.bind.nat.persistent(self.network.client_sockets)
+    }
+
+    async fn reopen(&mut self) -> io::Result<()> {
+        let (socket, proxy) = open_socket(self.addr, &self.bind, self.network).await?;
+        // this closes the previous socket and association
+        self.socket = socket;
+        self.proxy = proxy;
+        Ok(())
+    }
+}
+
+async fn open_socket(
+    addr: SocketAddr,
+    bind: &PeerBindConfig,
+    network: NetworkConfig,
+) -> io::Result<(UdpSocket, Option<Association>)> {
+    let proxy = match &bind.socks5 {
+        Some(config) => Some(Association::open(config, bind.source_address).await?),
+        None => None,
+    };
+    let remote = match &proxy {
+        Some(proxy) => proxy.relay(),
+        None => addr,
+    };
+
+    // the proxy may be of another family than the peer
+    let listen_addr = match bind.source_address {
+        Some(source) if proxy.is_none() || source.is_ipv4() == remote.is_ipv4() => {
+            SocketAddr::new(source, 0)
+        }
+        _ => unspecified_for(remote),
+    };
+    let socket = match (&bind.network_namespace, &bind.interface) {
+        // answers are matched to the request by their origin timestamp only
+        (namespace, interface) if !bind.nat.connected => {
+            UdpSocket::unconnected_client(listen_addr, interface.as_deref(), namespace.as_deref())
+                .await?
+        }
+        (Some(namespace), interface) => {
+            UdpSocket::client_in_network_namespace(
+                listen_addr,
+                remote,
+                interface.as_deref(),
+                namespace,
+            )
+            .await?
+        }
+        (None, Some(interface)) => {
+            UdpSocket::client_on_interface(listen_addr, remote, interface).await?
+        }
+        (None, None) => UdpSocket::client(listen_addr, remote).await?,
+    };
+    network.apply(&socket);
+    Ok((socket, proxy))
+}
+
+fn unspecified_for(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    }
+}
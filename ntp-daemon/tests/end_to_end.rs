@@ -0,0 +1,441 @@
+//! End-to-end tests, running the `ntp-daemon` binary in a separate process
+//! and talking to it over real sockets on localhost.
+//!
+//! Every test uses its own ports and its own directory for the configuration
+//! and unix sockets, so that the tests can run in parallel.
+//!
+//! The daemons never adjust the system clock: either they have no sources to
+//! synchronize to, or they are configured to only monitor the clock.
+
+use std::{
+    ffi::CStr,
+    io::Write,
+    os::unix::io::FromRawFd,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ntp_daemon::{ConfigUpdate, DrainMode, ObservablePeerState, ObservableState};
+use ntp_os_clock::UnixNtpClock;
+use ntp_proto::{NtpClock, NtpDuration, NtpPacket, ReferenceId};
+use tokio::net::{UdpSocket, UnixStream};
+
+/// How long the daemon gets to start up and answer
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A daemon process, killed when dropped
+struct Daemon {
+    child: Child,
+    directory: PathBuf,
+}
+
+impl Daemon {
+    /// Start a daemon with the given configuration. The string `{dir}` in the
+    /// configuration is replaced by a directory private to this daemon.
+    fn spawn(name: &str, config: &str) -> Daemon {
+        let directory = std::env::temp_dir().join(format!("ntp-test-end-to-end-{}", name));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let config = config.replace("{dir}", directory.to_str().unwrap());
+        let config_path = directory.join("ntp.toml");
+        std::fs::write(&config_path, config).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_ntp-daemon"))
+            .arg("--config")
+            .arg(&config_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        Daemon { child, directory }
+    }
+
+    fn is_running(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    async fn observe(&self) -> std::io::Result<ObservableState> {
+        let mut stream = UnixStream::connect(self.directory.join("observe")).await?;
+        let mut msg = Vec::with_capacity(16 * 1024);
+        ntp_daemon::sockets::read_json(&mut stream, &mut msg).await
+    }
+
+    async fn configure(&self, update: &ConfigUpdate) -> std::io::Result<()> {
+        let mut stream = UnixStream::connect(self.directory.join("configure")).await?;
+        ntp_daemon::sockets::write_json(&mut stream, update).await
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
+
+/// A single client request, with the offset of the server as measured from it
+struct Exchange {
+    response: NtpPacket<'static>,
+    offset: NtpDuration,
+    delay: NtpDuration,
+}
+
+async fn client(local: &str, server: &str) -> UdpSocket {
+    let socket = UdpSocket::bind(local).await.unwrap();
+    socket.connect(server).await.unwrap();
+    socket
+}
+
+/// Send client requests on `socket` until the server answers one
+async fn exchange(socket: &UdpSocket) -> Exchange {
+    let clock = UnixNtpClock::new();
+
+    let start = Instant::now();
+    loop {
+        let (request, identifier) = NtpPacket::poll_message(Default::default());
+        let mut buf = vec![];
+        request.serialize(&mut buf).unwrap();
+
+        let send_timestamp = clock.now().unwrap();
+        // the daemon might not be listening yet, which results in an error
+        let _ = socket.send(&buf).await;
+
+        let mut buf = [0; 48];
+        let received = tokio::time::timeout(Duration::from_millis(200), socket.recv(&mut buf));
+        if let Ok(Ok(48)) = received.await {
+            let recv_timestamp = clock.now().unwrap();
+            let response = NtpPacket::deserialize(&buf).unwrap().into_owned();
+            assert!(response.valid_server_response(identifier));
+
+            let offset = ((response.receive_timestamp() - send_timestamp)
+                + (response.transmit_timestamp() - recv_timestamp))
+                / 2i64;
+            let delay = (recv_timestamp - send_timestamp)
+                - (response.transmit_timestamp() - response.receive_timestamp());
+
+            return Exchange {
+                response,
+                offset,
+                delay,
+            };
+        }
+
+        assert!(
+            start.elapsed() < STARTUP_TIMEOUT,
+            "no response from the daemon at {:?}",
+            socket.peer_addr()
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_server_time() {
+    let _daemon = Daemon::spawn(
+        "server-time",
+        r#"
+        peers = []
+
+        [[server]]
+        addr = "127.0.0.1:21230"
+        "#,
+    );
+
+    let socket = client("127.0.0.1:0", "127.0.0.1:21230").await;
+    let exchange = exchange(&socket).await;
+
+    // the server uses the same clock, so up to the delay there is no offset
+    assert!(!exchange.response.is_kiss());
+    assert!(exchange.delay < NtpDuration::from_seconds(0.1));
+    assert!(exchange.offset.abs() <= exchange.delay);
+}
+
+#[tokio::test]
+async fn test_server_deny() {
+    let _daemon = Daemon::spawn(
+        "server-deny",
+        r#"
+        peers = []
+
+        [[server]]
+        addr = "127.0.0.1:21231"
+        denylist = ["127.0.0.2/32"]
+        denylist-action = "Deny"
+        "#,
+    );
+
+    let denied = client("127.0.0.2:0", "127.0.0.1:21231").await;
+    let exchange = self::exchange(&denied).await;
+    assert!(exchange.response.is_kiss_deny());
+
+    let allowed = client("127.0.0.1:0", "127.0.0.1:21231").await;
+    let exchange = self::exchange(&allowed).await;
+    assert!(!exchange.response.is_kiss());
+}
+
+#[tokio::test]
+async fn test_server_rate_limit() {
+    let _daemon = Daemon::spawn(
+        "server-rate-limit",
+        r#"
+        peers = []
+
+        [[server]]
+        addr = "127.0.0.1:21232"
+        rate-limiting-cache-size = 32
+        rate-limiting-cutoff-ms = 60000
+        "#,
+    );
+
+    let socket = client("127.0.0.1:0", "127.0.0.1:21232").await;
+    let exchange = self::exchange(&socket).await;
+    assert!(!exchange.response.is_kiss());
+
+    // too soon after the first request
+    let exchange = self::exchange(&socket).await;
+    assert!(exchange.response.is_kiss_rate());
+}
+
+#[tokio::test]
+async fn test_reload() {
+    let mut daemon = Daemon::spawn(
+        "reload",
+        r#"
+        peers = []
+
+        [[server]]
+        addr = "127.0.0.1:21233"
+
+        [configure]
+        path = "{dir}/configure"
+
+        [observe]
+        path = "{dir}/observe"
+        "#,
+    );
+
+    let socket = client("127.0.0.1:0", "127.0.0.1:21233").await;
+    exchange(&socket).await;
+
+    daemon
+        .configure(&ConfigUpdate {
+            log_filter: Some("debug".into()),
+            panic_threshold: Some(100.0),
+            drain: None,
+        })
+        .await
+        .unwrap();
+
+    // the daemon keeps running and serving time with the new configuration
+    let exchange = exchange(&socket).await;
+    assert!(!exchange.response.is_kiss());
+    assert!(daemon.is_running());
+
+    let state = daemon.observe().await.unwrap();
+    assert_eq!(state.servers.len(), 1);
+}
+
+#[tokio::test]
+async fn test_anycast_drain() {
+    let daemon = Daemon::spawn(
+        "anycast-drain",
+        r#"
+        peers = []
+
+        [[server]]
+        addr = "127.0.0.1:21237"
+
+        [server.anycast]
+        reference-id = "ANYC"
+
+        [configure]
+        path = "{dir}/configure"
+        "#,
+    );
+
+    let socket = client("127.0.0.1:0", "127.0.0.1:21237").await;
+    let exchange = self::exchange(&socket).await;
+    assert!(!exchange.response.is_kiss());
+    assert_eq!(
+        exchange.response.reference_id(),
+        ReferenceId::from_bytes(*b"ANYC")
+    );
+
+    let drain = |mode| ConfigUpdate {
+        log_filter: None,
+        panic_threshold: None,
+        drain: Some(mode),
+    };
+    // the update is handled after the configuration socket is written to
+    let settle = || tokio::time::sleep(Duration::from_millis(100));
+
+    // clients are sent away before the route is removed
+    daemon.configure(&drain(DrainMode::Kod)).await.unwrap();
+    settle().await;
+    let exchange = self::exchange(&socket).await;
+    assert!(exchange.response.is_kiss_rate());
+
+    daemon.configure(&drain(DrainMode::Off)).await.unwrap();
+    settle().await;
+    let exchange = self::exchange(&socket).await;
+    assert!(!exchange.response.is_kiss());
+}
+
+#[tokio::test]
+async fn test_sandbox() {
+    let mut daemon = Daemon::spawn(
+        "sandbox",
+        r#"
+        peers = ["127.0.0.1:21236"]
+
+        [[server]]
+        addr = "127.0.0.1:21235"
+
+        [observe]
+        path = "{dir}/observe"
+
+        [sandbox]
+        mode = "enforce"
+        "#,
+    );
+
+    // any syscall outside of the filter kills the daemon
+    let socket = client("127.0.0.1:0", "127.0.0.1:21235").await;
+    let exchange = exchange(&socket).await;
+    assert!(!exchange.response.is_kiss());
+
+    let state = daemon.observe().await.unwrap();
+    assert_eq!(state.peers.len(), 1);
+    assert!(daemon.is_running());
+}
+
+/// A GPS receiver on a pseudo terminal, which emits a GGA sentence at the
+/// start of every second of the system clock. Returns the path of the
+/// terminal the daemon reads from.
+fn gps_receiver() -> PathBuf {
+    // Safety: the file descriptor is checked before use, and owned by the
+    // file from then on. ptsname_r writes at most buf.len() bytes.
+    let (master, path) = unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        assert!(fd >= 0, "{}", std::io::Error::last_os_error());
+        let master = std::fs::File::from_raw_fd(fd);
+        assert_eq!(libc::grantpt(fd), 0);
+        assert_eq!(libc::unlockpt(fd), 0);
+
+        let mut buf = [0u8; 64];
+        assert_eq!(
+            libc::ptsname_r(fd, buf.as_mut_ptr() as *mut libc::c_char, buf.len()),
+            0
+        );
+        let path = CStr::from_ptr(buf.as_ptr() as *const libc::c_char);
+        (master, PathBuf::from(path.to_str().unwrap()))
+    };
+
+    tokio::spawn(async move {
+        let mut master = master;
+        loop {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            let next_second = now.as_secs() + 1;
+            tokio::time::sleep(Duration::from_secs(next_second) - now).await;
+
+            let seconds_of_day = next_second % 86400;
+            let body = format!(
+                "GPGGA,{:02}{:02}{:02}.00,,,,,1,08,,,M,,M,,",
+                seconds_of_day / 3600,
+                seconds_of_day / 60 % 60,
+                seconds_of_day % 60
+            );
+            let checksum = body.bytes().fold(0, |acc, b| acc ^ b);
+            // nobody reads until the daemon opened the terminal
+            let _ = write!(master, "${}*{:02X}\r\n", body, checksum);
+        }
+    });
+
+    path
+}
+
+/// Wait until the daemon is synchronized, and return its state
+async fn synchronized(daemon: &Daemon, timeout: Duration) -> ObservableState {
+    let start = Instant::now();
+    loop {
+        if let Ok(state) = daemon.observe().await {
+            if state.system.leap_indicator.is_synchronized() {
+                return state;
+            }
+        }
+
+        assert!(start.elapsed() < timeout, "daemon did not synchronize");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_client_synchronizes() {
+    // Neither daemon adjusts the clock, so both keep the time of the system
+    let server = Daemon::spawn(
+        "client-synchronizes-server",
+        &format!(
+            r#"
+            peers = []
+
+            [[server]]
+            addr = "127.0.0.1:21234"
+
+            [[refclocks]]
+            driver = "nmea"
+            device = "{}"
+
+            [system]
+            min-intersection-survivors = 1
+            min-cluster-survivors = 1
+
+            [clock]
+            backend = "monitor"
+
+            [observe]
+            path = "{{dir}}/observe"
+            "#,
+            gps_receiver().display()
+        ),
+    );
+
+    // the server only answers as stratum 1 once the GPS receiver set its time
+    let state = synchronized(&server, Duration::from_secs(120)).await;
+    assert_eq!(state.system.stratum, 1);
+
+    let client = Daemon::spawn(
+        "client-synchronizes-client",
+        r#"
+        peers = ["127.0.0.1:21234"]
+
+        [system]
+        min-intersection-survivors = 1
+        min-cluster-survivors = 1
+        initial-burst = 4
+
+        [clock]
+        backend = "monitor"
+
+        [observe]
+        path = "{dir}/observe"
+        "#,
+    );
+
+    // the client needs a few polls before it trusts the server
+    let state = synchronized(&client, Duration::from_secs(120)).await;
+    let offsets: Vec<_> = state
+        .peers
+        .iter()
+        .filter_map(|peer| match peer {
+            ObservablePeerState::Observable { statistics, .. } => Some(statistics.offset),
+            ObservablePeerState::Nothing => None,
+        })
+        .collect();
+
+    assert_eq!(offsets.len(), 1);
+    assert!(offsets[0].abs() < NtpDuration::from_seconds(0.01));
+    assert_eq!(state.system.stratum, 2);
+}
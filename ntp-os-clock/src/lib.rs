@@ -5,9 +5,27 @@
 // is constructed in such a way that use of the public functions is
 // safe regardless of given arguments.
 
+mod namespace;
+mod phc;
+mod pps;
+mod privileges;
+mod rtc;
+mod seccomp;
+mod serial;
+
+use std::time::Duration;
+
 use ntp_proto::{NtpClock, NtpDuration, NtpLeapIndicator, NtpTimestamp, PollInterval};
 use thiserror::Error as ThisError;
 
+pub use namespace::{time_namespace_boottime_offset, NamespaceClock};
+pub use phc::{PhcDevice, PhcMethod, PhcOffset};
+pub use pps::{PpsDevice, PpsEdge};
+pub use privileges::{drop_privileges, has_capability, Capability, PrivilegeError};
+pub use rtc::RtcDevice;
+pub use seccomp::{install_syscall_filter, SeccompAction};
+pub use serial::open_serial;
+
 #[derive(Debug, Copy, Clone, ThisError)]
 pub enum Error {
     #[error("Insufficient permissions to interact with the clock.")]
@@ -75,6 +93,53 @@ impl UnixNtpClock {
     pub fn new() -> Self {
         Self(())
     }
+
+    /// Leave the clock running at its current frequency, for when it is no
+    /// longer steered: the remaining offset correction is dropped, the kernel
+    /// stops adjusting the frequency itself, and the clock is marked as not
+    /// synchronized.
+    pub fn hold_frequency(&self) -> Result<(), Error> {
+        // the offset is only taken while the kernel discipline is still on,
+        // and with a zero offset, it does not change the frequency
+        let mut ntp_kapi_timex = EMPTY_TIMEX;
+        ntp_kapi_timex.modes = libc::MOD_OFFSET | libc::MOD_NANO;
+        ntp_kapi_timex.offset = 0;
+        if unsafe { libc::ntp_adjtime(&mut ntp_kapi_timex as *mut _) } == -1 {
+            return Err(convert_errno());
+        }
+
+        let mut ntp_kapi_timex = EMPTY_TIMEX;
+        ntp_kapi_timex.modes = libc::MOD_STATUS;
+        ntp_kapi_timex.status = libc::STA_UNSYNC;
+        if unsafe { libc::ntp_adjtime(&mut ntp_kapi_timex as *mut _) } == -1 {
+            return Err(convert_errno());
+        }
+
+        Ok(())
+    }
+
+    /// The offset of TAI to UTC the kernel keeps, used for `CLOCK_TAI`
+    pub fn tai_offset(&self) -> Result<i32, Error> {
+        let mut ntp_kapi_timex = EMPTY_TIMEX;
+        if unsafe { libc::ntp_adjtime(&mut ntp_kapi_timex as *mut _) } == -1 {
+            return Err(convert_errno());
+        }
+
+        Ok(ntp_kapi_timex.tai)
+    }
+
+    /// Set the offset of TAI to UTC, in seconds. The kernel changes it by
+    /// itself when it inserts or deletes a leap second.
+    pub fn set_tai_offset(&self, offset: i32) -> Result<(), Error> {
+        let mut ntp_kapi_timex = EMPTY_TIMEX;
+        ntp_kapi_timex.modes = libc::MOD_TAI;
+        ntp_kapi_timex.constant = offset as libc::c_long;
+        if unsafe { libc::ntp_adjtime(&mut ntp_kapi_timex as *mut _) } == -1 {
+            return Err(convert_errno());
+        }
+
+        Ok(())
+    }
 }
 
 // Convert those error numbers that can occur for the ntp_gettime and ntp_adjtimex calls
@@ -176,6 +241,14 @@ impl NtpClock for UnixNtpClock {
         poll_interval: PollInterval,
         leap_status: NtpLeapIndicator,
     ) -> Result<(), Self::Error> {
+        // Kernel PPS discipline may have been enabled by a PPS reference
+        // clock, which must survive us overwriting the status below.
+        let mut current_timex = EMPTY_TIMEX;
+        if unsafe { libc::ntp_adjtime(&mut current_timex as *mut _) } == -1 {
+            return Err(convert_errno());
+        }
+        let pps_status = current_timex.status & (libc::STA_PPSTIME | libc::STA_PPSFREQ);
+
         let mut ntp_kapi_timex = EMPTY_TIMEX;
         ntp_kapi_timex.modes = libc::MOD_OFFSET
             | libc::MOD_MAXERROR
@@ -188,6 +261,7 @@ impl NtpClock for UnixNtpClock {
         ntp_kapi_timex.maxerror = duration_in_nanos(max_error) / 1000;
         ntp_kapi_timex.constant = poll_interval.as_log() as libc::c_long;
         ntp_kapi_timex.status = libc::STA_PLL
+            | pps_status
             | match leap_status {
                 NtpLeapIndicator::Leap59 => libc::STA_DEL,
                 NtpLeapIndicator::Leap61 => libc::STA_INS,
@@ -204,6 +278,45 @@ impl NtpClock for UnixNtpClock {
     }
 }
 
+fn clock_gettime(clock: libc::clockid_t) -> std::io::Result<Duration> {
+    let mut tp = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(clock, &mut tp as *mut _) } == -1 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(Duration::new(tp.tv_sec as u64, tp.tv_nsec as u32))
+    }
+}
+
+/// Total time the system spent suspended since it booted, the difference
+/// between `CLOCK_BOOTTIME` and `CLOCK_MONOTONIC`
+pub fn time_suspended() -> std::io::Result<Duration> {
+    let monotonic = clock_gettime(libc::CLOCK_MONOTONIC)?;
+    let boottime = clock_gettime(libc::CLOCK_BOOTTIME)?;
+    Ok(boottime.saturating_sub(monotonic))
+}
+
+/// Whether `/sys` is mounted read-only, as container runtimes do, in which
+/// case the clock is usually owned by the host
+pub fn sysfs_read_only() -> bool {
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    let path = b"/sys\0";
+    if unsafe { libc::statvfs(path.as_ptr().cast(), &mut stat as *mut _) } == -1 {
+        return false;
+    }
+
+    stat.f_flag & libc::ST_RDONLY != 0
+}
+
+/// Whether the clock discipline of the kernel can be used, which container
+/// runtimes may block, and which kernels built without NTP support lack
+pub fn kernel_pll_available() -> bool {
+    let mut ntp_kapi_timex = EMPTY_TIMEX;
+    unsafe { libc::ntp_adjtime(&mut ntp_kapi_timex as *mut _) != -1 }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -216,4 +329,22 @@ mod tests {
             NtpTimestamp::from_seconds_nanos_since_ntp_era(0, 0)
         );
     }
+
+    #[test]
+    fn test_tai_offset_does_not_crash() {
+        assert!(UnixNtpClock::new().tai_offset().unwrap() >= 0);
+    }
+
+    #[test]
+    fn test_sysfs_read_only_does_not_crash() {
+        sysfs_read_only();
+    }
+
+    #[test]
+    fn test_time_suspended() {
+        let first = time_suspended().unwrap();
+        let second = time_suspended().unwrap();
+        // the clocks are read one after the other, so allow some slack
+        assert!(second + Duration::from_millis(100) >= first);
+    }
 }
@@ -0,0 +1,304 @@
+//! A clock kept by the daemon itself, for when it must not or cannot steer
+//! the clock of the system, such as inside a Linux time namespace.
+//!
+//! Time namespaces only give a namespace its own `CLOCK_MONOTONIC` and
+//! `CLOCK_BOOTTIME`, `CLOCK_REALTIME` is shared by the whole system. This
+//! clock therefore runs on `CLOCK_BOOTTIME` of the namespace, which also
+//! counts time spent suspended, and corrects its offset and frequency the way
+//! the kernel does for the system clock: with a phase-locked loop that slews
+//! offsets in over a time constant. It starts out at the time of the system
+//! clock.
+
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use ntp_proto::{NtpClock, NtpDuration, NtpLeapIndicator, NtpTimestamp, PollInterval};
+
+use crate::{clock_gettime, Error};
+
+/// Largest offset slewed in at once, as `MAXPHASE` of the kernel
+const MAX_PHASE: f64 = 0.5;
+/// Largest frequency correction, as `MAXFREQ` of the kernel
+const MAX_FREQUENCY: f64 = 500e-6;
+/// Largest time constant, as `MAXTC` of the kernel
+const MAX_TIME_CONSTANT: i32 = 10;
+/// `SHIFT_PLL` of the kernel, which sets the gain of the phase-locked loop
+const SHIFT_PLL: i32 = 2;
+
+#[derive(Debug, Clone, Copy)]
+struct State {
+    /// `CLOCK_BOOTTIME` when the state last changed
+    reference: Duration,
+    /// Our time at `reference`
+    time: NtpTimestamp,
+    /// Correction of the rate of `CLOCK_BOOTTIME`, in seconds per second
+    frequency: f64,
+    /// Offset in seconds that is still to be slewed in at `reference`
+    phase: f64,
+    time_constant: i32,
+    /// `CLOCK_BOOTTIME` of the previous offset update
+    last_update: Option<Duration>,
+}
+
+impl State {
+    fn new(boottime: Duration, time: NtpTimestamp) -> Self {
+        State {
+            reference: boottime,
+            time,
+            frequency: 0.0,
+            phase: 0.0,
+            time_constant: 0,
+            last_update: None,
+        }
+    }
+
+    /// Our time at `boottime`, and the offset still to be slewed in then
+    fn at(&self, boottime: Duration) -> (NtpTimestamp, f64) {
+        let elapsed = boottime.saturating_sub(self.reference);
+        let seconds = elapsed.as_secs_f64();
+
+        // every second the kernel slews in this fraction of the remaining offset
+        let fraction = 2f64.powi(-(SHIFT_PLL + self.time_constant));
+        let remaining = self.phase * (1.0 - fraction).powf(seconds);
+
+        let correction = self.frequency * seconds + (self.phase - remaining);
+        let time = self.time
+            + NtpDuration::from_system_duration(elapsed)
+            + NtpDuration::from_seconds(correction);
+
+        (time, remaining)
+    }
+
+    /// Make `boottime` the reference, such that the offset and frequency can
+    /// be changed from there on
+    fn advance(&mut self, boottime: Duration) {
+        let (time, remaining) = self.at(boottime);
+        self.time = time;
+        self.phase = remaining;
+        self.reference = Ord::max(self.reference, boottime);
+    }
+
+    fn set_frequency(&mut self, boottime: Duration, frequency: f64) {
+        self.advance(boottime);
+        self.frequency = frequency.clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+    }
+
+    fn step(&mut self, boottime: Duration, offset: NtpDuration) {
+        self.advance(boottime);
+        self.time += offset;
+    }
+
+    /// Hand an offset to the phase-locked loop, as `ntp_update_offset` of
+    /// the kernel does
+    fn update(&mut self, boottime: Duration, offset: f64, time_constant: i32) {
+        self.advance(boottime);
+        self.time_constant = time_constant.clamp(0, MAX_TIME_CONSTANT);
+
+        let offset = offset.clamp(-MAX_PHASE, MAX_PHASE);
+
+        // a long time since the previous update lowers the gain, to stay stable
+        let since_update = match self.last_update {
+            Some(last_update) => boottime.saturating_sub(last_update).as_secs_f64(),
+            None => 0.0,
+        };
+        let since_update = since_update.min(2f64.powi(SHIFT_PLL + 1 + self.time_constant));
+        let frequency = self.frequency
+            + offset * since_update / 2f64.powi(2 * (SHIFT_PLL + 2 + self.time_constant));
+
+        self.frequency = frequency.clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+        self.phase = offset;
+        self.last_update = Some(boottime);
+    }
+}
+
+/// A clock of the daemon on top of `CLOCK_BOOTTIME`. Clones share the clock.
+#[derive(Debug, Clone)]
+pub struct NamespaceClock {
+    state: Arc<Mutex<State>>,
+}
+
+fn boottime() -> Result<Duration, Error> {
+    clock_gettime(libc::CLOCK_BOOTTIME).map_err(|_| Error::NotSupported)
+}
+
+fn realtime() -> Result<NtpTimestamp, Error> {
+    let since_epoch = clock_gettime(libc::CLOCK_REALTIME).map_err(|_| Error::NotSupported)?;
+    Ok(NtpTimestamp::from_unix_seconds_nanos(
+        since_epoch.as_secs() as i64,
+        since_epoch.subsec_nanos(),
+    ))
+}
+
+impl NamespaceClock {
+    /// A clock starting at the current time of the system clock
+    pub fn new() -> Result<Self, Error> {
+        Ok(NamespaceClock {
+            state: Arc::new(Mutex::new(State::new(boottime()?, realtime()?))),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // changes to the state do not panic halfway
+        match self.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl NtpClock for NamespaceClock {
+    type Error = Error;
+
+    fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+        let boottime = boottime()?;
+        Ok(self.lock().at(boottime).0)
+    }
+
+    fn set_freq(&self, freq: f64) -> Result<(), Self::Error> {
+        let boottime = boottime()?;
+        self.lock().set_frequency(boottime, freq);
+        Ok(())
+    }
+
+    fn step_clock(&self, offset: NtpDuration) -> Result<(), Self::Error> {
+        let boottime = boottime()?;
+        self.lock().step(boottime, offset);
+        Ok(())
+    }
+
+    fn update_clock(
+        &self,
+        offset: NtpDuration,
+        _est_error: NtpDuration,
+        _max_error: NtpDuration,
+        poll_interval: PollInterval,
+        // leap seconds are not inserted, the sources are followed after one
+        _leap_status: NtpLeapIndicator,
+    ) -> Result<(), Self::Error> {
+        let boottime = boottime()?;
+        self.lock()
+            .update(boottime, offset.to_seconds(), poll_interval.as_log() as i32);
+        Ok(())
+    }
+
+    fn adjust_system_timestamp(&self, system_time: NtpTimestamp) -> NtpTimestamp {
+        match (self.now(), realtime()) {
+            (Ok(now), Ok(system_now)) => system_time + (now - system_now),
+            _ => system_time,
+        }
+    }
+}
+
+/// Offset of `CLOCK_BOOTTIME` in the time namespace of this process to that
+/// of the system, or `None` when the kernel has no time namespaces
+pub fn time_namespace_boottime_offset() -> std::io::Result<Option<NtpDuration>> {
+    match std::fs::read_to_string("/proc/self/timens_offsets") {
+        Ok(offsets) => Ok(parse_boottime_offset(&offsets)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+fn parse_boottime_offset(offsets: &str) -> Option<NtpDuration> {
+    offsets.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        // older kernels show the number of the clock instead of its name
+        let clock = fields.next()?;
+        if clock != "boottime" && clock != libc::CLOCK_BOOTTIME.to_string() {
+            return None;
+        }
+
+        let seconds: i64 = fields.next()?.parse().ok()?;
+        let nanos: i64 = fields.next()?.parse().ok()?;
+        Some(NtpDuration::from_seconds(
+            seconds as f64 + nanos as f64 / 1e9,
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(seconds: f64) -> Duration {
+        Duration::from_secs_f64(seconds)
+    }
+
+    #[test]
+    fn test_runs_with_boottime() {
+        let start = NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0);
+        let mut state = State::new(seconds(100.0), start);
+
+        assert_eq!(state.at(seconds(100.0)).0, start);
+        let later = state.at(seconds(160.0)).0 - start;
+        assert_eq!(later, NtpDuration::from_seconds(60.0));
+
+        state.set_frequency(seconds(160.0), 10e-6);
+        let later = state.at(seconds(1160.0)).0 - start - NtpDuration::from_seconds(1060.0);
+        assert!((later.to_seconds() - 0.01).abs() < 1e-9);
+
+        state.set_frequency(seconds(1160.0), 1.0);
+        assert_eq!(state.frequency, MAX_FREQUENCY);
+
+        state.step(seconds(1160.0), NtpDuration::from_seconds(-2.5));
+        let later = state.at(seconds(1160.0)).0 - start - NtpDuration::from_seconds(1057.5);
+        assert!((later.to_seconds() - 0.01).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_offset_is_slewed() {
+        let start = NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0);
+        let mut state = State::new(seconds(0.0), start);
+
+        state.update(seconds(0.0), 0.010, 4);
+        // the first update has no interval to derive a frequency from
+        assert_eq!(state.frequency, 0.0);
+
+        // the correction, on top of the time that passed
+        let applied = |state: &State, at: f64| {
+            let elapsed = NtpDuration::from_system_duration(seconds(at));
+            (state.at(seconds(at)).0 - start - elapsed).to_seconds()
+        };
+        assert!(applied(&state, 0.0).abs() < 1e-9);
+        // 1/64th of the remainder every second
+        assert!((applied(&state, 1.0) - 0.010 / 64.0).abs() < 1e-9);
+        assert!((applied(&state, 64.0) - 0.010 * (1.0 - (63.0f64 / 64.0).powi(64))).abs() < 1e-9);
+        assert!((applied(&state, 10_000.0) - 0.010).abs() < 1e-9);
+
+        // a later update replaces what remains, and corrects the frequency
+        state.update(seconds(16.0), 0.002, 4);
+        assert!((state.frequency - 0.002 * 16.0 / 2f64.powi(16)).abs() < 1e-15);
+        assert!((state.phase - 0.002).abs() < 1e-15);
+
+        // large offsets are limited
+        state.update(seconds(32.0), 3.0, 4);
+        assert_eq!(state.phase, MAX_PHASE);
+    }
+
+    #[test]
+    fn test_namespace_clock() {
+        let clock = NamespaceClock::new().unwrap();
+        let system = realtime().unwrap();
+        assert!((clock.now().unwrap() - system).abs().to_seconds() < 0.1);
+
+        clock.step_clock(NtpDuration::from_seconds(10.0)).unwrap();
+        let converted = clock.adjust_system_timestamp(system);
+        assert!(((converted - system).to_seconds() - 10.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_parse_boottime_offset() {
+        let offsets = "monotonic           0         0\nboottime        86400 500000000\n";
+        assert_eq!(
+            parse_boottime_offset(offsets),
+            Some(NtpDuration::from_seconds(86400.5))
+        );
+        assert_eq!(
+            parse_boottime_offset("1 0 0\n7 -5 0\n"),
+            Some(NtpDuration::from_seconds(-5.0))
+        );
+        assert_eq!(parse_boottime_offset(""), None);
+    }
+}
@@ -0,0 +1,309 @@
+// Note on unsafe usage.
+//
+// This module uses unsafe code to read PTP hardware clocks (PHCs), either
+// through the PTP ioctls on `/dev/ptpN` devices or through `clock_gettime` on
+// the dynamic clock id of such a device, and to steer them through
+// `clock_adjtime` on that same clock id. The structures passed to these calls
+// mirror those in `linux/ptp_clock.h`, are always fully initialized, and
+// outlive the call they are passed to. The file descriptor is always owned by
+// a live `File`.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::io::AsRawFd,
+    path::Path,
+};
+
+use ntp_proto::NtpDuration;
+
+use crate::EMPTY_TIMEX;
+
+const PTP_MAX_SAMPLES: usize = 25;
+
+/// Number of readings taken when no precise cross timestamp is available
+const OFFSET_SAMPLES: u32 = 5;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct PtpClockTime {
+    sec: i64,
+    nsec: u32,
+    reserved: u32,
+}
+
+impl PtpClockTime {
+    fn nanos(self) -> i128 {
+        self.sec as i128 * 1_000_000_000 + self.nsec as i128
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct PtpSysOffsetPrecise {
+    device: PtpClockTime,
+    sys_realtime: PtpClockTime,
+    sys_monoraw: PtpClockTime,
+    rsv: [u32; 4],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct PtpSysOffset {
+    n_samples: u32,
+    rsv: [u32; 3],
+    ts: [PtpClockTime; 2 * PTP_MAX_SAMPLES + 1],
+}
+
+const fn ptp_ioctl(direction: libc::c_ulong, number: libc::c_ulong, size: usize) -> libc::c_ulong {
+    const IOC_NRSHIFT: libc::c_ulong = 0;
+    const IOC_TYPESHIFT: libc::c_ulong = 8;
+    const IOC_SIZESHIFT: libc::c_ulong = 16;
+    const IOC_DIRSHIFT: libc::c_ulong = 30;
+
+    (direction << IOC_DIRSHIFT)
+        | ((size as libc::c_ulong) << IOC_SIZESHIFT)
+        | ((b'=' as libc::c_ulong) << IOC_TYPESHIFT)
+        | (number << IOC_NRSHIFT)
+}
+
+const IOC_WRITE: libc::c_ulong = 1;
+const IOC_READ: libc::c_ulong = 2;
+
+const PTP_SYS_OFFSET: libc::c_ulong = ptp_ioctl(IOC_WRITE, 5, std::mem::size_of::<PtpSysOffset>());
+const PTP_SYS_OFFSET_PRECISE: libc::c_ulong = ptp_ioctl(
+    IOC_READ | IOC_WRITE,
+    8,
+    std::mem::size_of::<PtpSysOffsetPrecise>(),
+);
+
+/// How the offset between a PHC and the system clock is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhcMethod {
+    /// Hardware cross timestamping (`PTP_SYS_OFFSET_PRECISE`), which captures
+    /// both clocks at exactly the same instant
+    Precise,
+    /// Kernel readings of the PHC bracketed by readings of the system clock
+    /// (`PTP_SYS_OFFSET`)
+    Bracketed,
+    /// Readings of the PHC through `clock_gettime` bracketed by readings of
+    /// the system clock
+    ClockGettime,
+}
+
+/// A single comparison of a PHC with the system clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhcOffset {
+    /// PHC time minus system clock time
+    pub offset: NtpDuration,
+    /// Width of the window in which the PHC was read, zero for precise cross
+    /// timestamps
+    pub uncertainty: NtpDuration,
+}
+
+/// A PTP hardware clock, typically `/dev/ptpN`
+#[derive(Debug)]
+pub struct PhcDevice {
+    file: File,
+    method: PhcMethod,
+}
+
+impl PhcDevice {
+    /// Open the device, and determine the most precise way in which it can be
+    /// compared to the system clock.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::open_with(OpenOptions::new().read(true), path)
+    }
+
+    /// Open the device such that it can also be steered, which requires
+    /// write access to the device.
+    pub fn open_writable(path: &Path) -> io::Result<Self> {
+        Self::open_with(OpenOptions::new().read(true).write(true), path)
+    }
+
+    fn open_with(options: &OpenOptions, path: &Path) -> io::Result<Self> {
+        let file = options.open(path)?;
+        let mut device = PhcDevice {
+            file,
+            method: PhcMethod::Precise,
+        };
+
+        for method in [
+            PhcMethod::Precise,
+            PhcMethod::Bracketed,
+            PhcMethod::ClockGettime,
+        ] {
+            device.method = method;
+            match device.offset() {
+                Ok(_) => return Ok(device),
+                Err(e) if method == PhcMethod::ClockGettime => return Err(e),
+                Err(_) => continue,
+            }
+        }
+
+        unreachable!()
+    }
+
+    pub fn method(&self) -> PhcMethod {
+        self.method
+    }
+
+    /// Compare the PHC to the system clock
+    pub fn offset(&self) -> io::Result<PhcOffset> {
+        match self.method {
+            PhcMethod::Precise => self.offset_precise(),
+            PhcMethod::Bracketed => self.offset_bracketed(),
+            PhcMethod::ClockGettime => self.offset_clock_gettime(),
+        }
+    }
+
+    /// Set the frequency of the PHC relative to its nominal frequency, in
+    /// seconds per second
+    pub fn set_frequency(&self, freq: f64) -> io::Result<()> {
+        let mut timex = EMPTY_TIMEX;
+        timex.modes = libc::ADJ_FREQUENCY;
+        // Same units as for the system clock: 2^-16 ppm
+        timex.freq = (freq * 65536e6) as libc::c_long;
+        self.adjtime(&mut timex)
+    }
+
+    /// Step the PHC by the given offset
+    pub fn step(&self, offset: NtpDuration) -> io::Result<()> {
+        let (secs, nanos) = offset.as_seconds_nanos();
+
+        let mut timex = EMPTY_TIMEX;
+        timex.modes = libc::ADJ_SETOFFSET | libc::ADJ_NANO;
+        timex.time.tv_sec = secs as libc::time_t;
+        // With ADJ_NANO this field holds nanoseconds, which must be positive
+        timex.time.tv_usec = nanos as libc::suseconds_t;
+        self.adjtime(&mut timex)
+    }
+
+    /// FD_TO_CLOCKID from the kernel's posix-timers documentation
+    fn clock_id(&self) -> libc::clockid_t {
+        ((!self.file.as_raw_fd()) << 3) | 3
+    }
+
+    fn adjtime(&self, timex: &mut libc::timex) -> io::Result<()> {
+        if unsafe { libc::clock_adjtime(self.clock_id(), timex as *mut _) } == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn ioctl(&self, request: libc::c_ulong, argument: *mut libc::c_void) -> io::Result<()> {
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, argument) } == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn offset_precise(&self) -> io::Result<PhcOffset> {
+        let mut data = PtpSysOffsetPrecise::default();
+        self.ioctl(
+            PTP_SYS_OFFSET_PRECISE,
+            &mut data as *mut _ as *mut libc::c_void,
+        )?;
+
+        Ok(PhcOffset {
+            offset: nanos_to_duration(data.device.nanos() - data.sys_realtime.nanos()),
+            uncertainty: NtpDuration::ZERO,
+        })
+    }
+
+    fn offset_bracketed(&self) -> io::Result<PhcOffset> {
+        let mut data = PtpSysOffset {
+            n_samples: OFFSET_SAMPLES,
+            rsv: [0; 3],
+            ts: [PtpClockTime::default(); 2 * PTP_MAX_SAMPLES + 1],
+        };
+        self.ioctl(PTP_SYS_OFFSET, &mut data as *mut _ as *mut libc::c_void)?;
+
+        // The readings alternate between the system clock and the PHC,
+        // starting and ending with the system clock
+        let readings = (0..OFFSET_SAMPLES as usize).map(|i| {
+            (
+                data.ts[2 * i].nanos(),
+                data.ts[2 * i + 1].nanos(),
+                data.ts[2 * i + 2].nanos(),
+            )
+        });
+
+        best_bracketed_offset(readings)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no PHC samples"))
+    }
+
+    fn offset_clock_gettime(&self) -> io::Result<PhcOffset> {
+        let clock_id = self.clock_id();
+
+        let read = |clock_id: libc::clockid_t| {
+            let mut tp = libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            };
+            if unsafe { libc::clock_gettime(clock_id, &mut tp as *mut _) } == -1 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(tp.tv_sec as i128 * 1_000_000_000 + tp.tv_nsec as i128)
+            }
+        };
+
+        let mut readings = Vec::with_capacity(OFFSET_SAMPLES as usize);
+        for _ in 0..OFFSET_SAMPLES {
+            let before = read(libc::CLOCK_REALTIME)?;
+            let phc = read(clock_id)?;
+            let after = read(libc::CLOCK_REALTIME)?;
+            readings.push((before, phc, after));
+        }
+
+        best_bracketed_offset(readings.into_iter())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no PHC samples"))
+    }
+}
+
+fn nanos_to_duration(nanos: i128) -> NtpDuration {
+    NtpDuration::from_seconds(nanos as f64 / 1e9)
+}
+
+/// Pick the reading with the narrowest system clock window, as it is least
+/// affected by scheduling and bus delays. Each reading consists of system
+/// clock, PHC and again system clock time, in nanoseconds.
+fn best_bracketed_offset(readings: impl Iterator<Item = (i128, i128, i128)>) -> Option<PhcOffset> {
+    readings
+        .min_by_key(|(before, _, after)| after - before)
+        .map(|(before, phc, after)| PhcOffset {
+            offset: nanos_to_duration(phc - (before + (after - before) / 2)),
+            uncertainty: nanos_to_duration(after - before),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ioctl_numbers() {
+        // values from linux/ptp_clock.h
+        assert_eq!(std::mem::size_of::<PtpSysOffsetPrecise>(), 64);
+        assert_eq!(std::mem::size_of::<PtpSysOffset>(), 832);
+        assert_eq!(PTP_SYS_OFFSET, 0x43403d05);
+        assert_eq!(PTP_SYS_OFFSET_PRECISE, 0xc0403d08);
+    }
+
+    #[test]
+    fn test_best_bracketed_offset() {
+        let readings = [(1000, 38_000_001_500, 2000), (1000, 38_000_001_200, 1400)];
+        let best = best_bracketed_offset(readings.into_iter()).unwrap();
+        assert_eq!(best.offset, nanos_to_duration(38_000_000_000));
+        assert_eq!(best.uncertainty, nanos_to_duration(400));
+
+        assert!(best_bracketed_offset(std::iter::empty()).is_none());
+    }
+
+    #[test]
+    fn test_not_a_phc() {
+        assert!(PhcDevice::open(Path::new("/dev/null")).is_err());
+    }
+}
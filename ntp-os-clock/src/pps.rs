@@ -0,0 +1,224 @@
+// Note on unsafe usage.
+//
+// This module uses unsafe code to interact with the Linux PPS API (RFC 2783)
+// through the ioctls on `/dev/ppsN` devices. The structures passed to these
+// ioctls mirror those in `linux/pps.h`, are always fully initialized, and
+// outlive the call they are passed to. The file descriptor is always owned by
+// a live `File`.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::io::AsRawFd,
+    path::Path,
+    time::Duration,
+};
+
+use ntp_proto::NtpTimestamp;
+
+use crate::{EMPTY_TIMEX, EPOCH_OFFSET};
+
+const PPS_API_VERS_1: libc::c_int = 1;
+
+const PPS_CAPTUREASSERT: libc::c_int = 0x01;
+const PPS_CANWAIT: libc::c_int = 0x100;
+const PPS_TSFMT_TSPEC: libc::c_int = 0x1000;
+
+const PPS_TIME_INVALID: u32 = 1 << 0;
+const PPS_KC_HARDPPS: libc::c_int = 0;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct PpsKTime {
+    sec: i64,
+    nsec: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct PpsKInfo {
+    assert_sequence: u32,
+    clear_sequence: u32,
+    assert_tu: PpsKTime,
+    clear_tu: PpsKTime,
+    current_mode: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct PpsKParams {
+    api_version: libc::c_int,
+    mode: libc::c_int,
+    assert_off_tu: PpsKTime,
+    clear_off_tu: PpsKTime,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct PpsFData {
+    info: PpsKInfo,
+    timeout: PpsKTime,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct PpsBindArgs {
+    tsformat: libc::c_int,
+    edge: libc::c_int,
+    consumer: libc::c_int,
+}
+
+// The kernel headers define these with the size of a pointer rather than the
+// size of the structure, so we have to do the same.
+const fn pps_ioctl(direction: libc::c_ulong, number: libc::c_ulong) -> libc::c_ulong {
+    const IOC_NRSHIFT: libc::c_ulong = 0;
+    const IOC_TYPESHIFT: libc::c_ulong = 8;
+    const IOC_SIZESHIFT: libc::c_ulong = 16;
+    const IOC_DIRSHIFT: libc::c_ulong = 30;
+
+    (direction << IOC_DIRSHIFT)
+        | ((std::mem::size_of::<*const libc::c_void>() as libc::c_ulong) << IOC_SIZESHIFT)
+        | ((b'p' as libc::c_ulong) << IOC_TYPESHIFT)
+        | (number << IOC_NRSHIFT)
+}
+
+const IOC_WRITE: libc::c_ulong = 1;
+const IOC_READ: libc::c_ulong = 2;
+
+const PPS_GETPARAMS: libc::c_ulong = pps_ioctl(IOC_READ, 0xa1);
+const PPS_SETPARAMS: libc::c_ulong = pps_ioctl(IOC_WRITE, 0xa2);
+const PPS_GETCAP: libc::c_ulong = pps_ioctl(IOC_READ, 0xa3);
+const PPS_FETCH: libc::c_ulong = pps_ioctl(IOC_READ | IOC_WRITE, 0xa4);
+const PPS_KC_BIND: libc::c_ulong = pps_ioctl(IOC_WRITE, 0xa5);
+
+/// A single pulse-per-second edge, as timestamped by the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PpsEdge {
+    /// Number of edges seen by the kernel, used to detect missed pulses
+    pub sequence: u32,
+    /// System clock time at which the edge occurred
+    pub timestamp: NtpTimestamp,
+}
+
+/// A device implementing the Linux PPS API, typically `/dev/ppsN`
+#[derive(Debug)]
+pub struct PpsDevice {
+    file: File,
+}
+
+impl PpsDevice {
+    /// Open the device, and configure it to capture the assert edge of
+    /// each pulse.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let device = PpsDevice { file };
+
+        let mut capabilities: libc::c_int = 0;
+        device.ioctl(PPS_GETCAP, &mut capabilities as *mut _ as *mut libc::c_void)?;
+        if capabilities & PPS_CAPTUREASSERT == 0 || capabilities & PPS_CANWAIT == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "PPS device can not capture assert edges",
+            ));
+        }
+
+        let mut params = PpsKParams::default();
+        device.ioctl(PPS_GETPARAMS, &mut params as *mut _ as *mut libc::c_void)?;
+        params.api_version = PPS_API_VERS_1;
+        params.mode |= PPS_CAPTUREASSERT | PPS_TSFMT_TSPEC;
+        device.ioctl(PPS_SETPARAMS, &mut params as *mut _ as *mut libc::c_void)?;
+
+        Ok(device)
+    }
+
+    fn ioctl(&self, request: libc::c_ulong, argument: *mut libc::c_void) -> io::Result<()> {
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, argument) } == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Wait for the next assert edge. Returns `Ok(None)` when no edge
+    /// occurred within the timeout.
+    pub fn fetch(&self, timeout: Duration) -> io::Result<Option<PpsEdge>> {
+        let mut data = PpsFData {
+            timeout: PpsKTime {
+                sec: timeout.as_secs() as i64,
+                nsec: timeout.subsec_nanos() as i32,
+                flags: 0,
+            },
+            ..Default::default()
+        };
+
+        match self.ioctl(PPS_FETCH, &mut data as *mut _ as *mut libc::c_void) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(libc::ETIMEDOUT) => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let time = data.info.assert_tu;
+        if time.flags & PPS_TIME_INVALID != 0 || data.info.assert_sequence == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(PpsEdge {
+            sequence: data.info.assert_sequence,
+            // Negative eras are completely valid, so any wrapping is
+            // perfectly reasonable here.
+            timestamp: NtpTimestamp::from_seconds_nanos_since_ntp_era(
+                (time.sec as u32).wrapping_add(EPOCH_OFFSET),
+                time.nsec as u32,
+            ),
+        }))
+    }
+
+    /// Hand the assert edges of this device to the kernel, which then uses
+    /// them to discipline the system clock directly (hardpps). Only one
+    /// device in the system can be bound in this way.
+    pub fn enable_hardpps(&self) -> io::Result<()> {
+        let mut args = PpsBindArgs {
+            tsformat: PPS_TSFMT_TSPEC,
+            edge: PPS_CAPTUREASSERT,
+            consumer: PPS_KC_HARDPPS,
+        };
+        self.ioctl(PPS_KC_BIND, &mut args as *mut _ as *mut libc::c_void)?;
+
+        let mut ntp_kapi_timex = EMPTY_TIMEX;
+        if unsafe { libc::ntp_adjtime(&mut ntp_kapi_timex as *mut _) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        ntp_kapi_timex.modes = libc::MOD_STATUS;
+        ntp_kapi_timex.status |= libc::STA_PPSTIME | libc::STA_PPSFREQ;
+        if unsafe { libc::ntp_adjtime(&mut ntp_kapi_timex as *mut _) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ioctl_numbers() {
+        // values from linux/pps.h on 64 bit platforms
+        if std::mem::size_of::<*const libc::c_void>() == 8 {
+            assert_eq!(PPS_GETPARAMS, 0x800870a1);
+            assert_eq!(PPS_SETPARAMS, 0x400870a2);
+            assert_eq!(PPS_GETCAP, 0x800870a3);
+            assert_eq!(PPS_FETCH, 0xc00870a4);
+            assert_eq!(PPS_KC_BIND, 0x400870a5);
+        }
+    }
+
+    #[test]
+    fn test_not_a_pps_device() {
+        assert!(PpsDevice::open(Path::new("/dev/null")).is_err());
+    }
+}
@@ -0,0 +1,286 @@
+// Note on unsafe usage.
+//
+// This module uses unsafe code to look up users and groups, and to change the
+// user, groups and capabilities of the process. All pointers passed to these
+// functions point to live buffers and structures of the size the function
+// expects, owned by the calling function.
+
+use std::{
+    ffi::{CStr, CString},
+    fmt::Display,
+    io,
+};
+
+use thiserror::Error;
+
+/// `_LINUX_CAPABILITY_VERSION_3` from linux/capability.h, which uses two
+/// data structures to describe 64 capabilities
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Capabilities that are used by the daemon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Stepping and steering the system clock
+    SysTime,
+    /// Binding to ports below 1024, such as the NTP port 123
+    NetBindService,
+    /// Configuring network interfaces, such as enabling hardware timestamping
+    NetAdmin,
+    /// Administration of the system, such as entering other network namespaces
+    SysAdmin,
+}
+
+impl Capability {
+    /// Number of the capability, from linux/capability.h
+    fn number(self) -> u32 {
+        match self {
+            Capability::NetBindService => 10,
+            Capability::NetAdmin => 12,
+            Capability::SysAdmin => 21,
+            Capability::SysTime => 25,
+        }
+    }
+
+    fn mask(capabilities: &[Capability]) -> u64 {
+        capabilities
+            .iter()
+            .fold(0, |mask, capability| mask | 1 << capability.number())
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Capability::SysTime => "CAP_SYS_TIME",
+            Capability::NetBindService => "CAP_NET_BIND_SERVICE",
+            Capability::NetAdmin => "CAP_NET_ADMIN",
+            Capability::SysAdmin => "CAP_SYS_ADMIN",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PrivilegeError {
+    #[error("unknown user {0}")]
+    UnknownUser(String),
+    #[error("unknown group {0}")]
+    UnknownGroup(String),
+    #[error("only root can change to another user")]
+    NotRoot,
+    #[error("could not {0}: {1}")]
+    System(&'static str, io::Error),
+}
+
+fn cerr(action: &'static str, code: libc::c_int) -> Result<(), PrivilegeError> {
+    if code == -1 {
+        Err(PrivilegeError::System(action, io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+fn capget() -> io::Result<[CapUserData; 2]> {
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapUserData::default(); 2];
+
+    // Safety: the header and both data structures are valid for the duration of the call
+    let code = unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) };
+    if code == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(data)
+    }
+}
+
+fn capset(effective: u64, permitted: u64) -> Result<(), PrivilegeError> {
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [
+        CapUserData {
+            effective: effective as u32,
+            permitted: permitted as u32,
+            inheritable: 0,
+        },
+        CapUserData {
+            effective: (effective >> 32) as u32,
+            permitted: (permitted >> 32) as u32,
+            inheritable: 0,
+        },
+    ];
+
+    // Safety: the header and both data structures are valid for the duration of the call
+    let code = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) };
+    cerr("set capabilities", code as libc::c_int)
+}
+
+/// Whether the calling thread has the capability in its effective set
+pub fn has_capability(capability: Capability) -> io::Result<bool> {
+    let data = capget()?;
+    let effective = data[0].effective as u64 | (data[1].effective as u64) << 32;
+    Ok(effective & Capability::mask(&[capability]) != 0)
+}
+
+/// Look up an entry in the user or group database through one of the
+/// reentrant `get*nam_r` functions, growing the buffer as needed
+fn lookup<T>(
+    lookup: impl Fn(*mut T, *mut libc::c_char, libc::size_t, *mut *mut T) -> libc::c_int,
+) -> io::Result<Option<T>> {
+    let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+
+    loop {
+        // Safety: the entry is plain data for which all zeroes is a valid value
+        let mut entry: T = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+
+        match lookup(&mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) {
+            // the strings in the entry point into the buffer, and are not used
+            0 if result.is_null() => return Ok(None),
+            0 => return Ok(Some(entry)),
+            libc::ERANGE if buffer.len() < 1 << 20 => buffer.resize(buffer.len() * 2, 0),
+            code => return Err(io::Error::from_raw_os_error(code)),
+        }
+    }
+}
+
+/// User id and primary group of the user
+fn user_ids(name: &CStr) -> io::Result<Option<(libc::uid_t, libc::gid_t)>> {
+    let entry = lookup(|entry, buffer, size, result| {
+        // Safety: all pointers are valid for the duration of the call, and
+        // the buffer is at least `size` long
+        unsafe { libc::getpwnam_r(name.as_ptr(), entry, buffer, size, result) }
+    })?;
+
+    Ok(entry.map(|passwd: libc::passwd| (passwd.pw_uid, passwd.pw_gid)))
+}
+
+fn group_id(name: &CStr) -> io::Result<Option<libc::gid_t>> {
+    let entry = lookup(|entry, buffer, size, result| {
+        // Safety: all pointers are valid for the duration of the call, and
+        // the buffer is at least `size` long
+        unsafe { libc::getgrnam_r(name.as_ptr(), entry, buffer, size, result) }
+    })?;
+
+    Ok(entry.map(|group: libc::group| group.gr_gid))
+}
+
+/// Change to the given user, with its supplementary groups and either the
+/// given group or its own primary group, keeping only the given capabilities.
+///
+/// Capabilities are a property of a thread, so this must be called before the
+/// process starts any threads, which would otherwise keep running as root or
+/// lose all their capabilities.
+pub fn drop_privileges(
+    user: &str,
+    group: Option<&str>,
+    retain: &[Capability],
+) -> Result<(), PrivilegeError> {
+    let unknown_user = || PrivilegeError::UnknownUser(user.to_owned());
+    let user_name = CString::new(user).map_err(|_| unknown_user())?;
+    let (uid, primary_gid) = user_ids(&user_name)
+        .map_err(|e| PrivilegeError::System("look up user", e))?
+        .ok_or_else(unknown_user)?;
+
+    let gid = match group {
+        None => primary_gid,
+        Some(group) => {
+            let unknown_group = || PrivilegeError::UnknownGroup(group.to_owned());
+            let group_name = CString::new(group).map_err(|_| unknown_group())?;
+            group_id(&group_name)
+                .map_err(|e| PrivilegeError::System("look up group", e))?
+                .ok_or_else(unknown_group)?
+        }
+    };
+
+    // Safety: geteuid has no preconditions
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(PrivilegeError::NotRoot);
+    }
+
+    // keep the permitted capabilities when leaving root, from which the ones
+    // to retain are selected below
+    // Safety: PR_SET_KEEPCAPS takes a single integer argument
+    cerr("keep capabilities", unsafe {
+        libc::prctl(libc::PR_SET_KEEPCAPS, 1 as libc::c_ulong)
+    })?;
+
+    // Safety: the user name is a valid nul-terminated string
+    cerr("set supplementary groups", unsafe {
+        libc::initgroups(user_name.as_ptr(), gid)
+    })?;
+    // Safety: setgid and setuid have no memory safety preconditions
+    cerr("set group", unsafe { libc::setgid(gid) })?;
+    cerr("set user", unsafe { libc::setuid(uid) })?;
+
+    let mask = Capability::mask(retain);
+    capset(mask, mask)?;
+
+    // Safety: PR_SET_KEEPCAPS takes a single integer argument
+    cerr("reset keep capabilities", unsafe {
+        libc::prctl(libc::PR_SET_KEEPCAPS, 0 as libc::c_ulong)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capability_mask() {
+        assert_eq!(Capability::mask(&[]), 0);
+        assert_eq!(Capability::mask(&[Capability::SysTime]), 1 << 25);
+        assert_eq!(
+            Capability::mask(&[Capability::SysTime, Capability::NetBindService]),
+            (1 << 25) | (1 << 10)
+        );
+        assert_eq!(Capability::SysTime.to_string(), "CAP_SYS_TIME");
+    }
+
+    #[test]
+    fn test_lookup() {
+        let root = CString::new("root").unwrap();
+        assert_eq!(user_ids(&root).unwrap(), Some((0, 0)));
+        assert_eq!(group_id(&root).unwrap(), Some(0));
+
+        let unknown = CString::new("ntpd-rs-no-such-user").unwrap();
+        assert_eq!(user_ids(&unknown).unwrap(), None);
+        assert_eq!(group_id(&unknown).unwrap(), None);
+    }
+
+    #[test]
+    fn test_unknown_user() {
+        assert!(matches!(
+            drop_privileges("ntpd-rs-no-such-user", None, &[]),
+            Err(PrivilegeError::UnknownUser(_))
+        ));
+        assert!(matches!(
+            drop_privileges("root", Some("ntpd-rs-no-such-group"), &[]),
+            Err(PrivilegeError::UnknownGroup(_))
+        ));
+    }
+
+    #[test]
+    fn test_has_capability() {
+        // whether we have it depends on how the tests run, but asking never fails
+        assert!(has_capability(Capability::SysTime).is_ok());
+    }
+}
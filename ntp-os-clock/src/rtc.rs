@@ -0,0 +1,262 @@
+// Note on unsafe usage.
+//
+// This module uses unsafe code to read and set the real-time clock through
+// the RTC ioctls on `/dev/rtcN` devices, and to read the system clock with
+// `clock_gettime`. The structure passed to the ioctls mirrors `struct rtc_time`
+// in `linux/rtc.h`, is always fully initialized, and outlives the call it is
+// passed to. The file descriptor is always owned by a live `File`.
+
+use std::{
+    fs::File,
+    io,
+    os::unix::io::AsRawFd,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use ntp_proto::NtpDuration;
+
+/// How often the RTC is read while waiting for its next second
+const TICK_POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Longest wait for the next second of the RTC, beyond which it is
+/// considered to be stopped
+const TICK_TIMEOUT: Duration = Duration::from_millis(1500);
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct RtcTime {
+    tm_sec: libc::c_int,
+    tm_min: libc::c_int,
+    tm_hour: libc::c_int,
+    tm_mday: libc::c_int,
+    tm_mon: libc::c_int,
+    tm_year: libc::c_int,
+    tm_wday: libc::c_int,
+    tm_yday: libc::c_int,
+    tm_isdst: libc::c_int,
+}
+
+const fn rtc_ioctl(direction: libc::c_ulong, number: libc::c_ulong) -> libc::c_ulong {
+    const IOC_NRSHIFT: libc::c_ulong = 0;
+    const IOC_TYPESHIFT: libc::c_ulong = 8;
+    const IOC_SIZESHIFT: libc::c_ulong = 16;
+    const IOC_DIRSHIFT: libc::c_ulong = 30;
+
+    (direction << IOC_DIRSHIFT)
+        | ((std::mem::size_of::<RtcTime>() as libc::c_ulong) << IOC_SIZESHIFT)
+        | ((b'p' as libc::c_ulong) << IOC_TYPESHIFT)
+        | (number << IOC_NRSHIFT)
+}
+
+const IOC_WRITE: libc::c_ulong = 1;
+const IOC_READ: libc::c_ulong = 2;
+
+const RTC_RD_TIME: libc::c_ulong = rtc_ioctl(IOC_READ, 0x09);
+const RTC_SET_TIME: libc::c_ulong = rtc_ioctl(IOC_WRITE, 0x0a);
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar, with
+/// months and days counting from 1
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The date of a number of days since 1970-01-01, the inverse of
+/// [`days_from_civil`]
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+impl RtcTime {
+    fn to_unix_seconds(self) -> i64 {
+        let days = days_from_civil(
+            self.tm_year as i64 + 1900,
+            self.tm_mon as i64 + 1,
+            self.tm_mday as i64,
+        );
+        days * 86400 + self.tm_hour as i64 * 3600 + self.tm_min as i64 * 60 + self.tm_sec as i64
+    }
+
+    fn from_unix_seconds(seconds: i64) -> Self {
+        let days = seconds.div_euclid(86400);
+        let second_of_day = seconds.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+
+        RtcTime {
+            tm_sec: (second_of_day % 60) as libc::c_int,
+            tm_min: (second_of_day / 60 % 60) as libc::c_int,
+            tm_hour: (second_of_day / 3600) as libc::c_int,
+            tm_mday: day as libc::c_int,
+            tm_mon: (month - 1) as libc::c_int,
+            tm_year: (year - 1900) as libc::c_int,
+            // 1970-01-01 was a thursday
+            tm_wday: (days + 4).rem_euclid(7) as libc::c_int,
+            tm_yday: (days - days_from_civil(year, 1, 1)) as libc::c_int,
+            tm_isdst: 0,
+        }
+    }
+}
+
+fn system_time_nanos() -> io::Result<i128> {
+    let mut tp = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut tp as *mut _) } == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(tp.tv_sec as i128 * 1_000_000_000 + tp.tv_nsec as i128)
+    }
+}
+
+/// A battery backed real-time clock, typically `/dev/rtc0`, which is assumed
+/// to run on UTC. It only has a resolution of a second, but the moment its
+/// second changes can be observed, which gives it a much better precision.
+#[derive(Debug)]
+pub struct RtcDevice {
+    file: File,
+}
+
+impl RtcDevice {
+    /// Open the device. Setting the RTC does not need write access to the
+    /// device, but does need `CAP_SYS_TIME`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let device = RtcDevice {
+            file: File::open(path)?,
+        };
+
+        // fail early on devices that are not an RTC
+        device.read()?;
+
+        Ok(device)
+    }
+
+    fn ioctl(&self, request: libc::c_ulong, argument: *mut RtcTime) -> io::Result<()> {
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, argument) } == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The time of the RTC, in seconds since the unix epoch
+    pub fn read(&self) -> io::Result<i64> {
+        let mut time = RtcTime::default();
+        self.ioctl(RTC_RD_TIME, &mut time as *mut _)?;
+        Ok(time.to_unix_seconds())
+    }
+
+    /// Set the RTC, to a time in seconds since the unix epoch
+    pub fn set(&self, seconds: i64) -> io::Result<()> {
+        let mut time = RtcTime::from_unix_seconds(seconds);
+        self.ioctl(RTC_SET_TIME, &mut time as *mut _)
+    }
+
+    /// RTC time minus system clock time, measured at the moment the second of
+    /// the RTC changes. This blocks for up to a second.
+    pub fn offset(&self) -> io::Result<NtpDuration> {
+        let start = Instant::now();
+        let first = self.read()?;
+
+        loop {
+            std::thread::sleep(TICK_POLL_INTERVAL);
+
+            let current = self.read()?;
+            if current != first {
+                let system = system_time_nanos()?;
+                let nanos = current as i128 * 1_000_000_000 - system;
+                return Ok(NtpDuration::from_seconds(nanos as f64 / 1e9));
+            }
+
+            if start.elapsed() > TICK_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "RTC is not running",
+                ));
+            }
+        }
+    }
+
+    /// Set the RTC to the time of the system clock. The RTC is set at the
+    /// start of a second of the system clock, which blocks for up to a second.
+    pub fn set_to_system_time(&self) -> io::Result<()> {
+        let now = system_time_nanos()?;
+        let next_second = now.div_euclid(1_000_000_000) + 1;
+        let wait = next_second * 1_000_000_000 - now;
+        std::thread::sleep(Duration::from_nanos(wait as u64));
+
+        self.set(next_second as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ioctl_numbers() {
+        // values from linux/rtc.h
+        assert_eq!(std::mem::size_of::<RtcTime>(), 36);
+        assert_eq!(RTC_RD_TIME, 0x80247009);
+        assert_eq!(RTC_SET_TIME, 0x4024700a);
+    }
+
+    #[test]
+    fn test_calendar() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(civil_from_days(11017), (2000, 3, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+
+        for days in -1000..100_000 {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn test_rtc_time() {
+        // 2024-02-29T13:45:30Z, a thursday
+        let time = RtcTime::from_unix_seconds(1_709_214_330);
+        assert_eq!(
+            time,
+            RtcTime {
+                tm_sec: 30,
+                tm_min: 45,
+                tm_hour: 13,
+                tm_mday: 29,
+                tm_mon: 1,
+                tm_year: 124,
+                tm_wday: 4,
+                tm_yday: 59,
+                tm_isdst: 0,
+            }
+        );
+        assert_eq!(time.to_unix_seconds(), 1_709_214_330);
+    }
+
+    #[test]
+    fn test_not_an_rtc() {
+        assert!(RtcDevice::open(Path::new("/dev/null")).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+// Note on unsafe usage.
+//
+// This module uses unsafe code to install a seccomp filter. The filter
+// program is built in safe code, and only handed to the kernel, which
+// verifies it before installing it.
+
+use std::io;
+
+/// `AUDIT_ARCH_*` from linux/audit.h, identifying the syscall table in use
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// Syscalls with this bit set use the x32 ABI, which shares its architecture
+/// with x86_64, and would otherwise bypass the filter
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Offsets of the fields of `struct seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// What happens when a syscall that is not allowed is made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompAction {
+    /// Kill the whole process
+    Kill,
+    /// Allow the syscall, but log it to the kernel audit log
+    Log,
+}
+
+impl SeccompAction {
+    fn return_value(self) -> u32 {
+        match self {
+            SeccompAction::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+            SeccompAction::Log => libc::SECCOMP_RET_LOG,
+        }
+    }
+}
+
+fn statement(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Build a classic BPF program that allows the given syscalls, and applies
+/// `action` to all others
+fn filter_program(
+    arch: u32,
+    allowed: &[libc::c_long],
+    action: SeccompAction,
+) -> Vec<libc::sock_filter> {
+    let denied = action.return_value();
+
+    let mut program = vec![
+        statement(
+            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+            SECCOMP_DATA_ARCH,
+        ),
+        jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, arch, 1, 0),
+        statement(libc::BPF_RET | libc::BPF_K, denied),
+        statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR),
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    program.extend([
+        jump(
+            libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+            X32_SYSCALL_BIT,
+            0,
+            1,
+        ),
+        statement(libc::BPF_RET | libc::BPF_K, denied),
+    ]);
+
+    // a comparison per syscall that skips over the allow when not equal, such
+    // that jumps never need to be longer than a single instruction
+    for &syscall in allowed {
+        program.extend([
+            jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                syscall as u32,
+                0,
+                1,
+            ),
+            statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW),
+        ]);
+    }
+
+    program.push(statement(libc::BPF_RET | libc::BPF_K, denied));
+    program
+}
+
+/// Restrict all threads of the process to the given syscalls. Once installed,
+/// the filter can never be removed, and neither the process nor its children
+/// can gain privileges anymore.
+pub fn install_syscall_filter(allowed: &[libc::c_long], action: SeccompAction) -> io::Result<()> {
+    let arch = AUDIT_ARCH.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "syscall filters are not supported on this architecture",
+        )
+    })?;
+
+    let mut program = filter_program(arch, allowed, action);
+    let fprog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_mut_ptr(),
+    };
+
+    // installing a filter without CAP_SYS_ADMIN requires this
+    // Safety: PR_SET_NO_NEW_PRIVS takes integer arguments only
+    let code = unsafe {
+        libc::prctl(
+            libc::PR_SET_NO_NEW_PRIVS,
+            1 as libc::c_ulong,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+        )
+    };
+    if code == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safety: fprog points to the program, which outlives the call. The kernel
+    // copies the program before returning.
+    let code = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &fprog as *const libc::sock_fprog,
+        )
+    };
+    match code {
+        0 => Ok(()),
+        -1 => Err(io::Error::last_os_error()),
+        // with TSYNC, the id of a thread that could not be synchronized
+        thread => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("could not apply the filter to thread {}", thread),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_program() {
+        let program = filter_program(
+            0xc000_003e,
+            &[libc::SYS_read, libc::SYS_write],
+            SeccompAction::Kill,
+        );
+
+        let header = if cfg!(target_arch = "x86_64") { 6 } else { 4 };
+        assert_eq!(program.len(), header + 2 * 2 + 1);
+
+        // every allowed syscall is followed by an allow, the rest is killed
+        let allow = &program[header..header + 2];
+        assert_eq!(allow[0].k, libc::SYS_read as u32);
+        assert_eq!(allow[0].jf, 1);
+        assert_eq!(allow[1].k, libc::SECCOMP_RET_ALLOW);
+        assert_eq!(program.last().unwrap().k, libc::SECCOMP_RET_KILL_PROCESS);
+
+        let program = filter_program(0xc000_003e, &[], SeccompAction::Log);
+        assert_eq!(program.last().unwrap().k, libc::SECCOMP_RET_LOG);
+    }
+}
@@ -0,0 +1,89 @@
+// Note on unsafe usage.
+//
+// This module uses unsafe code to configure the line settings of serial
+// devices through the termios interface. The file descriptor passed to
+// the termios functions is always owned by a live `File`, and the termios
+// structure is always fully initialized by `tcgetattr` before use.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::Path,
+};
+
+fn baud_rate_constant(baud_rate: u32) -> Option<libc::speed_t> {
+    Some(match baud_rate {
+        1200 => libc::B1200,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        _ => return None,
+    })
+}
+
+/// Open a serial device for reading, with the line configured for raw
+/// 8N1 operation at the given baud rate.
+pub fn open_serial(path: &Path, baud_rate: u32) -> io::Result<File> {
+    let speed = baud_rate_constant(baud_rate).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported baud rate {}", baud_rate),
+        )
+    })?;
+
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    let fd = file.as_raw_fd();
+
+    // Safety: termios is a plain C struct for which all zeroes is a valid
+    // value, and it is overwritten by tcgetattr before being used.
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::tcgetattr(fd, &mut termios as *mut _) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    unsafe { libc::cfmakeraw(&mut termios as *mut _) };
+    // ignore modem control lines, and enable the receiver
+    termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+    // block until at least one byte is available
+    termios.c_cc[libc::VMIN] = 1;
+    termios.c_cc[libc::VTIME] = 0;
+
+    if unsafe { libc::cfsetispeed(&mut termios as *mut _, speed) } == -1
+        || unsafe { libc::cfsetospeed(&mut termios as *mut _, speed) } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios as *const _) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unsupported_baud_rate() {
+        let error = open_serial(Path::new("/dev/null"), 1234).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_not_a_terminal() {
+        // /dev/null is not a serial device, so configuring it must fail
+        assert!(open_serial(Path::new("/dev/null"), 9600).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+//! Benchmarks of the code that runs for every packet or clock update.
+//!
+//! Run with `cargo bench -p ntp-proto --features bench`.
+
+use std::net::Ipv4Addr;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use ntp_proto::{
+    peer_snapshot, BenchClockFilter, FilterAndCombine, NtpClock, NtpDuration, NtpInstant,
+    NtpLeapIndicator, NtpPacket, NtpTimestamp, PeerSnapshot, PeerStatistics, PollInterval,
+    ReferenceId, SystemConfig, SystemSnapshot,
+};
+
+/// A clock that is never steered, as only reading it is benchmarked
+#[derive(Debug, Clone, Copy)]
+struct FixedClock;
+
+impl NtpClock for FixedClock {
+    type Error = std::io::Error;
+
+    fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+        Ok(NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0))
+    }
+
+    fn set_freq(&self, _freq: f64) -> Result<(), Self::Error> {
+        unreachable!("not steered in benchmarks")
+    }
+
+    fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
+        unreachable!("not steered in benchmarks")
+    }
+
+    fn update_clock(
+        &self,
+        _offset: NtpDuration,
+        _est_error: NtpDuration,
+        _max_error: NtpDuration,
+        _poll_interval: PollInterval,
+        _leap_status: NtpLeapIndicator,
+    ) -> Result<(), Self::Error> {
+        unreachable!("not steered in benchmarks")
+    }
+}
+
+/// Offsets and delays that vary between samples, like those of a real peer
+fn sample(index: u64) -> (NtpDuration, NtpDuration) {
+    let noise = (index.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 40) as f64 / (1u64 << 24) as f64;
+    (
+        NtpDuration::from_seconds(0.001 * (noise - 0.5)),
+        NtpDuration::from_seconds(0.010 + 0.005 * noise),
+    )
+}
+
+fn clock_filter(c: &mut Criterion) {
+    let mut filter = BenchClockFilter::new();
+    // fill the register, so every step sorts eight real samples
+    for index in 0..8 {
+        let (offset, delay) = sample(index);
+        filter.step(offset, delay);
+    }
+
+    let mut index = 8;
+    c.bench_function("clock_filter", |b| {
+        b.iter(|| {
+            index += 1;
+            let (offset, delay) = sample(index);
+            black_box(filter.step(offset, delay))
+        })
+    });
+}
+
+fn peers(peer_count: u64, now: NtpInstant) -> Vec<PeerSnapshot> {
+    (0..peer_count)
+        .map(|index| {
+            let (offset, delay) = sample(index);
+            let statistics = PeerStatistics {
+                offset,
+                delay,
+                dispersion: NtpDuration::from_seconds(0.001),
+                jitter: 0.0001,
+            };
+            let mut peer = peer_snapshot(
+                statistics,
+                now,
+                NtpDuration::from_seconds(0.01),
+                NtpDuration::from_seconds(0.01),
+            );
+            peer.peer_id = ReferenceId::from_ip(Ipv4Addr::from(index as u32 + 1).into());
+            peer.stratum = 2;
+            peer
+        })
+        .collect()
+}
+
+fn clock_select(c: &mut Criterion) {
+    let config = SystemConfig::default();
+    let now = NtpInstant::now();
+    let system_poll = PollInterval::default();
+
+    let mut group = c.benchmark_group("clock_select");
+    for peer_count in [4, 16, 64, 256, 500] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(peer_count),
+            &peers(peer_count, now),
+            |b, peers| b.iter(|| FilterAndCombine::run(&config, peers, now, system_poll).ok()),
+        );
+    }
+    group.finish();
+
+    // every peer takes part, instead of the default maximum number of candidates
+    let config = SystemConfig {
+        max_candidates: usize::MAX,
+        ..config
+    };
+    let mut group = c.benchmark_group("clock_select_uncapped");
+    for peer_count in [500, 1000] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(peer_count),
+            &peers(peer_count, now),
+            |b, peers| b.iter(|| FilterAndCombine::run(&config, peers, now, system_poll).ok()),
+        );
+    }
+    group.finish();
+}
+
+fn packet(c: &mut Criterion) {
+    let (request, _) = NtpPacket::poll_message(PollInterval::default());
+    let mut data = [0; 48];
+    request.serialize_into(&mut data).unwrap();
+
+    c.bench_function("packet_parse", |b| {
+        b.iter(|| NtpPacket::deserialize(black_box(&data)).unwrap())
+    });
+
+    c.bench_function("packet_serialize", |b| {
+        let mut buf = [0; 48];
+        b.iter(|| black_box(&request).serialize_into(&mut buf).unwrap())
+    });
+}
+
+/// Everything a server does with a request, apart from receiving and sending
+fn server_response(c: &mut Criterion) {
+    let (request, _) = NtpPacket::poll_message(PollInterval::default());
+    let mut data = [0; 48];
+    request.serialize_into(&mut data).unwrap();
+
+    let system = SystemSnapshot {
+        stratum: 2,
+        leap_indicator: NtpLeapIndicator::NoWarning,
+        ..Default::default()
+    };
+    let recv_timestamp = NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0);
+
+    c.bench_function("server_response", |b| {
+        b.iter_batched_ref(
+            || [0; 48],
+            |buf| {
+                let request = NtpPacket::deserialize(black_box(&data)).unwrap();
+                let response =
+                    NtpPacket::timestamp_response(&system, request, recv_timestamp, &FixedClock);
+                let len = response.serialize_into(buf).unwrap();
+                NtpPacket::stamp_transmit_timestamp(&mut buf[..len], FixedClock.now().unwrap());
+                len
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, clock_filter, clock_select, packet, server_response);
+criterion_main!(benches);
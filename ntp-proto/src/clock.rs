@@ -2,7 +2,7 @@ use crate::{
     packet::NtpLeapIndicator, time_types::PollInterval, NtpDuration, NtpInstant, NtpTimestamp,
     SystemConfig, SystemSnapshot,
 };
-use tracing::{debug, error, info, instrument, trace};
+use tracing::{debug, error, info, instrument, trace, warn};
 
 /// Jitter averaging factor
 const JITTER_AVG: f64 = 4.;
@@ -25,13 +25,20 @@ pub trait NtpClock: Clone + Send + 'static {
         poll_interval: PollInterval,
         leap_status: NtpLeapIndicator,
     ) -> Result<(), Self::Error>;
+
+    /// The time of this clock at the moment the system clock read
+    /// `system_time`, for timestamps taken by the kernel, such as those of
+    /// received packets. Clocks that steer the system clock itself leave
+    /// them as they are.
+    fn adjust_system_timestamp(&self, system_time: NtpTimestamp) -> NtpTimestamp {
+        system_time
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum ClockState {
     StartupBlank,
-    // Needed when implementing frequency backups
-    #[allow(dead_code)]
+    // The frequency of the clock is known, but its offset is not
     StartupFreq,
     MeasureFreq,
     Spike,
@@ -51,6 +58,7 @@ pub struct ClockController<C: NtpClock> {
     poll_interval_counter: i32,
     offset: NtpDuration,
     jitter: NtpDuration,
+    frequency: f64,
     accumulated_steps: NtpDuration,
 }
 
@@ -78,6 +86,7 @@ impl<C: NtpClock> ClockController<C> {
             poll_interval_counter: 0,
             offset: NtpDuration::ZERO,
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         }
     }
@@ -102,10 +111,22 @@ impl<C: NtpClock> ClockController<C> {
     ) -> ClockUpdateResult {
         // Check that we have a somewhat reasonable result
         if self.offset_too_large(config, offset) {
-            error!("Detected overly large offset");
+            if matches!(
+                self.state,
+                ClockState::StartupBlank | ClockState::StartupFreq
+            ) {
+                error!(
+                    offset = debug(offset),
+                    "Initial offset exceeds the startup panic threshold, refusing to step the clock. Set the clock manually, or raise startup-panic-threshold if the sources are trusted"
+                );
+            } else {
+                error!("Detected overly large offset");
+            }
             return ClockUpdateResult::Panic;
         }
 
+        let offset = self.limit_correction(config, offset);
+
         if self.combined_steps_too_large(config, offset) {
             error!("Current offset too large combined with previously made steps");
             return ClockUpdateResult::Panic;
@@ -265,6 +286,21 @@ impl<C: NtpClock> ClockController<C> {
         ClockUpdateResult::Slew
     }
 
+    /// Forget the offset and jitter after the clock was stepped by someone
+    /// else. The frequency of the clock is kept, but the next offset is
+    /// corrected immediately, as on a startup with a known frequency.
+    pub fn reset(&mut self, system: &SystemSnapshot, config: &SystemConfig) {
+        self.state = match self.state {
+            ClockState::StartupBlank | ClockState::MeasureFreq => ClockState::StartupBlank,
+            _ => ClockState::StartupFreq,
+        };
+        self.last_update_time = NtpInstant::now();
+        self.preferred_poll_interval = config.initial_poll;
+        self.poll_interval_counter = 0;
+        self.offset = NtpDuration::ZERO;
+        self.jitter = system.precision;
+    }
+
     pub fn preferred_poll_interval(&self) -> PollInterval {
         self.preferred_poll_interval
     }
@@ -281,6 +317,27 @@ impl<C: NtpClock> ClockController<C> {
         self.jitter
     }
 
+    /// Frequency correction set on the clock, in seconds per second. Further
+    /// corrections made by the kernel are not included
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    /// Continue with the frequency correction of a previous run, as kept in
+    /// a state file. Like after a reset, the first offset is then corrected
+    /// right away, without measuring the frequency again.
+    pub fn restore_frequency(&mut self, frequency: f64) {
+        if let Err(e) = self.clock.set_freq(frequency) {
+            error!(error = %e, "Could not restore clock frequency");
+            return;
+        }
+
+        self.frequency = frequency;
+        if self.state == ClockState::StartupBlank {
+            self.state = ClockState::StartupFreq;
+        }
+    }
+
     fn offset_too_large(&self, config: &SystemConfig, offset: NtpDuration) -> bool {
         let threshold = match self.state {
             // The system might be wildly off on startup
@@ -318,6 +375,30 @@ impl<C: NtpClock> ClockController<C> {
         }
     }
 
+    /// Limit the offset to the maximum correction, once the clock has been
+    /// synchronized and its frequency measured
+    fn limit_correction(&self, config: &SystemConfig, offset: NtpDuration) -> NtpDuration {
+        if !matches!(self.state, ClockState::Sync | ClockState::Spike) {
+            return offset;
+        }
+
+        match config.max_correction {
+            Some(max) if offset.abs() > max => {
+                warn!(
+                    offset = debug(offset),
+                    max_correction = debug(max),
+                    "Offset exceeds the maximum correction, limiting the adjustment"
+                );
+                if offset > NtpDuration::ZERO {
+                    max
+                } else {
+                    -max
+                }
+            }
+            _ => offset,
+        }
+    }
+
     fn do_step(
         &mut self,
         offset: NtpDuration,
@@ -358,14 +439,13 @@ impl<C: NtpClock> ClockController<C> {
             ),
             "Setting initial frequency"
         );
-        let result = self.clock.set_freq(
-            offset.to_seconds()
-                / NtpInstant::abs_diff(last_peer_update, self.last_update_time).to_seconds(),
-        );
-        if let Err(e) = result {
+        let frequency = offset.to_seconds()
+            / NtpInstant::abs_diff(last_peer_update, self.last_update_time).to_seconds();
+        if let Err(e) = self.clock.set_freq(frequency) {
             error!(error = %e, "Unable to adjust clock frequency, exiting");
             std::process::exit(exitcode::NOPERM);
         }
+        self.frequency = frequency;
     }
 }
 
@@ -436,6 +516,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -538,6 +619,7 @@ mod tests {
             Some(NtpDuration::from_fixed_int(1 << 32))
         );
         assert_eq!(*controller.clock.last_freq.borrow(), Some(1. / 1800.));
+        assert_eq!(controller.frequency(), 1. / 1800.);
     }
 
     #[test]
@@ -554,6 +636,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -588,6 +671,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -635,6 +719,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -685,6 +770,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::ZERO,
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -726,6 +812,104 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_reset() {
+        let base = NtpInstant::now();
+        let config = SystemConfig::default();
+        let system = SystemSnapshot::default();
+
+        let mut controller = ClockController {
+            clock: TestClock::default(),
+            state: ClockState::Sync,
+            last_update_time: base,
+            preferred_poll_interval: PollIntervalLimits::default().max,
+            poll_interval_counter: 0,
+            offset: NtpDuration::from_seconds(0.01),
+            jitter: NtpDuration::from_seconds(0.001),
+            frequency: 1e-6,
+            accumulated_steps: NtpDuration::ZERO,
+        };
+
+        controller.reset(&system, &config);
+        assert_eq!(controller.offset(), NtpDuration::ZERO);
+        assert_eq!(controller.preferred_poll_interval(), config.initial_poll);
+        assert_eq!(controller.frequency(), 1e-6);
+
+        // a large offset is stepped right away, instead of waiting out a spike
+        assert_eq!(
+            controller.update(
+                &config,
+                &system,
+                NtpDuration::from_seconds(10.0),
+                NtpDuration::from_seconds(0.02),
+                NtpDuration::from_seconds(0.03),
+                NtpLeapIndicator::NoWarning,
+                base + Duration::from_secs(1),
+            ),
+            ClockUpdateResult::Step
+        );
+        assert_eq!(controller.state, ClockState::Sync);
+
+        // without a known frequency, it is measured again
+        controller.state = ClockState::MeasureFreq;
+        controller.reset(&system, &config);
+        assert_eq!(controller.state, ClockState::StartupBlank);
+    }
+
+    #[test]
+    fn test_restore_frequency() {
+        let config = SystemConfig::default();
+        let system = SystemSnapshot::default();
+
+        let mut controller = ClockController::new(TestClock::default(), &system, &config);
+        assert_eq!(controller.state, ClockState::StartupBlank);
+
+        controller.restore_frequency(2e-6);
+        assert_eq!(controller.state, ClockState::StartupFreq);
+        assert_eq!(controller.frequency(), 2e-6);
+        assert_eq!(*controller.clock.last_freq.borrow(), Some(2e-6));
+    }
+
+    #[test]
+    fn test_max_correction() {
+        let base = NtpInstant::now();
+        let config = SystemConfig {
+            max_correction: Some(NtpDuration::from_seconds(10.0)),
+            ..Default::default()
+        };
+        let system = SystemSnapshot::default();
+
+        let mut controller = ClockController {
+            clock: TestClock::default(),
+            state: ClockState::Sync,
+            last_update_time: base,
+            preferred_poll_interval: PollIntervalLimits::default().min,
+            poll_interval_counter: 0,
+            offset: NtpDuration::ZERO,
+            jitter: system.precision,
+            frequency: 0.0,
+            accumulated_steps: NtpDuration::ZERO,
+        };
+
+        let mut update = |offset: f64, time: u64| {
+            controller.update(
+                &config,
+                &system,
+                NtpDuration::from_seconds(offset),
+                NtpDuration::from_seconds(0.02),
+                NtpDuration::from_seconds(0.03),
+                NtpLeapIndicator::NoWarning,
+                base + Duration::from_secs(time),
+            )
+        };
+
+        assert_eq!(update(-80.0, 1), ClockUpdateResult::Ignore);
+        assert_eq!(update(-80.0, 1000), ClockUpdateResult::Step);
+        let stepped = controller.clock.last_offset.borrow().unwrap();
+        assert!((stepped.to_seconds() + 10.0).abs() < 1e-6);
+        assert!((controller.accumulated_steps().to_seconds() - 10.0).abs() < 1e-6);
+    }
+
     #[test]
     fn test_jitter_calc() {
         let base = NtpInstant::now();
@@ -740,6 +924,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -826,6 +1011,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_seconds(2e-3),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -909,6 +1095,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -933,6 +1120,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -957,6 +1145,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -981,6 +1170,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -1005,6 +1195,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -1029,6 +1220,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -1053,6 +1245,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
@@ -1077,6 +1270,7 @@ mod tests {
             poll_interval_counter: 0,
             offset: NtpDuration::from_fixed_int(0),
             jitter: system.precision,
+            frequency: 0.0,
             accumulated_steps: NtpDuration::ZERO,
         };
 
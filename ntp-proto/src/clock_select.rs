@@ -1,8 +1,47 @@
 use crate::peer::PeerSnapshot;
 use crate::time_types::{FrequencyTolerance, NtpInstant};
-use crate::{NtpDuration, PollInterval, SystemConfig};
+use crate::{
+    AuthenticationPolicy, NtpDuration, PollInterval, ReferenceId, SourceKind, SystemConfig,
+};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::Display;
 use tracing::{debug, instrument, trace, warn};
 
+/// Why clock selection did not produce a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionError {
+    /// Fewer sources agree on the time than the configured minimum
+    InsufficientConsensus { agreeing: usize, required: usize },
+    /// Fewer of the sources that agree on the time are authenticated than
+    /// the authentication policy requires
+    InsufficientAuthenticated {
+        authenticated: usize,
+        required: usize,
+    },
+}
+
+impl Display for SelectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InsufficientConsensus { agreeing, required } => f.write_fmt(format_args!(
+                "Insufficient consensus: {} sources agree on the time, {} required",
+                agreeing, required
+            )),
+            Self::InsufficientAuthenticated {
+                authenticated,
+                required,
+            } => f.write_fmt(format_args!(
+                "Insufficient authenticated sources: {} of the agreeing sources are authenticated, {} required",
+                authenticated, required
+            )),
+        }
+    }
+}
+
+impl std::error::Error for SelectionError {}
+
 #[derive(Debug, Clone)]
 pub struct FilterAndCombine {
     pub system_offset: NtpDuration,
@@ -10,6 +49,8 @@ pub struct FilterAndCombine {
     pub system_root_delay: NtpDuration,
     pub system_root_dispersion: NtpDuration,
     pub system_peer_snapshot: PeerSnapshot,
+    /// Peers that survived the selection and clustering algorithms
+    pub survivors: Vec<ReferenceId>,
 }
 
 impl FilterAndCombine {
@@ -19,7 +60,7 @@ impl FilterAndCombine {
         peers: &[PeerSnapshot],
         local_clock_time: NtpInstant,
         system_poll: PollInterval,
-    ) -> Option<Self> {
+    ) -> Result<Self, SelectionError> {
         let selection = clock_select(config, peers, local_clock_time, system_poll)?;
 
         // the clustering algorithm (part of `clock_select`) sorts the peers, best peer first.
@@ -40,28 +81,42 @@ impl FilterAndCombine {
         );
 
         // Update the system root delay and dispersion with the contributions from our synchronization process.
-        let root_delay = system_peer_snapshot.root_delay + system_peer_snapshot.statistics.delay;
-        let root_dispersion = system_peer_snapshot.root_dispersion
-            + std::cmp::max(
+        // These start from the values in the packets of the source, which can be
+        // anything, so all of this saturates instead of overflowing.
+        let root_delay = system_peer_snapshot
+            .root_delay
+            .saturating_add(system_peer_snapshot.statistics.delay);
+        let root_dispersion = system_peer_snapshot
+            .root_dispersion
+            .saturating_add(std::cmp::max(
                 NtpDuration::MIN_DISPERSION,
-                system_peer_snapshot.statistics.dispersion
-                    + NtpDuration::from_seconds(system_peer_snapshot.statistics.jitter)
-                    + local_clock_time.abs_diff(system_peer_snapshot.time)
-                        * config.frequency_tolerance
-                    + combined.system_offset.abs(),
-            );
-
-        Some(FilterAndCombine {
+                system_peer_snapshot
+                    .statistics
+                    .dispersion
+                    .saturating_add(NtpDuration::from_seconds(
+                        system_peer_snapshot.statistics.jitter,
+                    ))
+                    .saturating_add(
+                        local_clock_time.abs_diff(system_peer_snapshot.time)
+                            * config.frequency_tolerance,
+                    )
+                    .saturating_add(combined.system_offset.abs()),
+            ));
+
+        Ok(FilterAndCombine {
             system_offset: combined.system_offset,
             system_jitter: combined.system_jitter,
             system_root_delay: root_delay,
             system_root_dispersion: root_dispersion,
             system_peer_snapshot,
+            survivors: selection.survivors.iter().map(|s| s.peer.peer_id).collect(),
         })
     }
 
     pub fn system_root_delay(&self) -> NtpDuration {
-        self.system_peer_snapshot.root_delay + self.system_peer_snapshot.statistics.delay
+        self.system_peer_snapshot
+            .root_delay
+            .saturating_add(self.system_peer_snapshot.statistics.delay)
     }
 
     pub fn system_root_dispersion(
@@ -91,8 +146,11 @@ impl FilterAndCombine {
         // > The system offset (THETA) represents the maximum-likelihood offset estimate for the server population.
         //
         // The code skeleton instead uses `p.offset`. We use the fresh system offset here.
-        let dispersion_increment =
-            statistics.dispersion + jitter + drift_upper_bound + self.system_offset.abs();
+        let dispersion_increment = statistics
+            .dispersion
+            .saturating_add(jitter)
+            .saturating_add(drift_upper_bound)
+            .saturating_add(self.system_offset.abs());
 
         // per the spec
         //
@@ -100,7 +158,8 @@ impl FilterAndCombine {
         // > below by MINDISP.  In subnets with very fast processors and networks and very small delay
         // > and dispersion this forces a monotone-definite increase in s.rootdisp (EPSILON), which avoids
         // > loops between peers operating at the same stratum.
-        peer.root_dispersion + Ord::max(NtpDuration::MIN_DISPERSION, dispersion_increment)
+        peer.root_dispersion
+            .saturating_add(Ord::max(NtpDuration::MIN_DISPERSION, dispersion_increment))
     }
 
     pub fn root_synchronization_distance(
@@ -109,10 +168,114 @@ impl FilterAndCombine {
         frequency_tolerance: FrequencyTolerance,
     ) -> NtpDuration {
         self.system_root_dispersion(local_clock_time, frequency_tolerance)
-            + self.system_root_delay() / 2
+            .saturating_add(self.system_root_delay() / 2)
     }
 }
 
+/// The correctness interval of a source: its offset, plus and minus its
+/// root distance. The true offset of a correct source lies within it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CorrectnessInterval {
+    pub peer_id: ReferenceId,
+    pub offset: NtpDuration,
+    pub low: NtpDuration,
+    pub high: NtpDuration,
+    /// Whether the offset lies within the intersection, which makes the
+    /// source a truechimer. Otherwise it is a falseticker.
+    pub truechimer: bool,
+}
+
+/// The correctness intervals of the sources that were acceptable for
+/// synchronization, and the intersection that the intersection algorithm
+/// found among them. This shows why a source was tagged a falseticker, or
+/// why no intersection was found at all.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SelectionIntervals {
+    /// The intervals, in order of offset
+    pub candidates: Vec<CorrectnessInterval>,
+    /// The largest intersection of the intervals of a majority of the
+    /// sources, if there is one
+    pub intersection: Option<(NtpDuration, NtpDuration)>,
+}
+
+impl SelectionIntervals {
+    /// The intervals as seen by clock selection with the same arguments
+    pub fn new(
+        config: &SystemConfig,
+        peers: &[PeerSnapshot],
+        local_clock_time: NtpInstant,
+        system_poll: PollInterval,
+    ) -> Self {
+        let associations = candidate_associations(config, peers, local_clock_time, system_poll);
+        let chime_list = construct_candidate_list(config, associations, local_clock_time);
+        let intersection = find_interval(&chime_list);
+
+        let candidates = chime_list
+            .iter()
+            .filter(|candidate| candidate.endpoint_type == EndpointType::Middle)
+            .map(|candidate| {
+                let root_distance = candidate
+                    .peer
+                    .root_distance(local_clock_time, config.frequency_tolerance);
+                let offset = candidate.edge;
+                CorrectnessInterval {
+                    peer_id: candidate.peer.peer_id,
+                    offset,
+                    low: offset - root_distance,
+                    high: offset + root_distance,
+                    truechimer: matches!(
+                        intersection,
+                        Some((low, high)) if low <= offset && offset <= high
+                    ),
+                }
+            })
+            .collect();
+
+        SelectionIntervals {
+            candidates,
+            intersection,
+        }
+    }
+}
+
+/// The peers that may be used for synchronization, at most
+/// `config.max_candidates` of them with the smallest root distance
+fn candidate_associations<'a>(
+    config: &SystemConfig,
+    peers: &'a [PeerSnapshot],
+    local_clock_time: NtpInstant,
+    system_poll: PollInterval,
+) -> Vec<&'a PeerSnapshot> {
+    let mut associations: Vec<_> = peers
+        .iter()
+        .filter(|p| {
+            p.accept_synchronization(
+                local_clock_time,
+                config.frequency_tolerance,
+                config.distance_threshold,
+                system_poll,
+                config.local_stratum,
+            )
+            .is_ok()
+        })
+        .collect();
+
+    if associations.len() > config.max_candidates {
+        debug!(
+            valid = associations.len(),
+            max_candidates = config.max_candidates,
+            "Too many candidates, using those with the smallest root distance"
+        );
+        associations
+            .sort_by_cached_key(|p| p.root_distance(local_clock_time, config.frequency_tolerance));
+        associations.truncate(config.max_candidates);
+    }
+
+    associations
+}
+
 struct ClockSelect<'a> {
     survivors: Vec<SurvivorTuple<'a>>,
     system_selection_jitter: NtpDuration,
@@ -124,37 +287,98 @@ fn clock_select<'a>(
     peers: &'a [PeerSnapshot],
     local_clock_time: NtpInstant,
     system_poll: PollInterval,
-) -> Option<ClockSelect<'a>> {
-    let valid_associations = peers.iter().filter(|p| {
-        p.accept_synchronization(
-            local_clock_time,
-            config.frequency_tolerance,
-            config.distance_threshold,
-            system_poll,
-            config.local_stratum,
-        )
-        .is_ok()
-    });
+) -> Result<ClockSelect<'a>, SelectionError> {
+    let associations = candidate_associations(config, peers, local_clock_time, system_poll);
 
-    let candidates = construct_candidate_list(config, valid_associations, local_clock_time);
+    let candidates = construct_candidate_list(config, associations, local_clock_time);
 
-    let mut survivors = construct_survivors(config, &candidates, local_clock_time);
+    let survivors = construct_survivors(config, &candidates, local_clock_time);
+    let survivors = corroborated_survivors(survivors);
 
     trace!(survivors = debug(&survivors));
     if survivors.len() < config.min_intersection_survivors {
-        warn!("No clique of peers that agree on the current time.");
-        return None;
+        warn!(
+            agreeing = survivors.len(),
+            required = config.min_intersection_survivors,
+            "No clique of peers that agree on the current time."
+        );
+        return Err(SelectionError::InsufficientConsensus {
+            agreeing: survivors.len(),
+            required: config.min_intersection_survivors,
+        });
     }
 
+    let mut survivors = authenticated_survivors(config.authentication_policy, survivors)?;
+
     let system_selection_jitter =
         NtpDuration::from_seconds(cluster_algorithm(config, &mut survivors));
 
-    Some(ClockSelect {
+    Ok(ClockSelect {
         survivors,
         system_selection_jitter,
     })
 }
 
+/// Reference clocks that need corroboration only survive when a network
+/// source agrees with them
+fn corroborated_survivors(survivors: Vec<SurvivorTuple>) -> Vec<SurvivorTuple> {
+    let corroborated = survivors
+        .iter()
+        .any(|survivor| survivor.peer.kind == SourceKind::Network);
+    if corroborated {
+        return survivors;
+    }
+
+    survivors
+        .into_iter()
+        .filter(|survivor| match survivor.peer.kind {
+            SourceKind::RefClock {
+                needs_corroboration: true,
+            } => {
+                debug!(refclock = ?survivor.peer.peer_id, "reference clock not corroborated by a network source");
+                false
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+/// The survivors that may set the clock under the authentication policy
+fn authenticated_survivors<'a>(
+    policy: AuthenticationPolicy,
+    survivors: Vec<SurvivorTuple<'a>>,
+) -> Result<Vec<SurvivorTuple<'a>>, SelectionError> {
+    let authenticated = survivors
+        .iter()
+        .filter(|survivor| survivor.peer.authenticated)
+        .count();
+    let required = match policy {
+        AuthenticationPolicy::Any => 0,
+        AuthenticationPolicy::Majority => survivors.len() / 2 + 1,
+        AuthenticationPolicy::Required => 1,
+    };
+
+    if authenticated < required {
+        warn!(
+            authenticated,
+            required, "Too few of the peers that agree on the time are authenticated."
+        );
+        return Err(SelectionError::InsufficientAuthenticated {
+            authenticated,
+            required,
+        });
+    }
+
+    Ok(match policy {
+        AuthenticationPolicy::Any | AuthenticationPolicy::Majority => survivors,
+        // the others only served to cross-check the authenticated sources
+        AuthenticationPolicy::Required => survivors
+            .into_iter()
+            .filter(|survivor| survivor.peer.authenticated)
+            .collect(),
+    })
+}
+
 /// Observation: Chrony (sources.c, SRC_SelectSource, line ~920) does not use the Middle tag
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(i8)]
@@ -257,116 +481,158 @@ fn filter_survivor<'a>(
 }
 
 /// Find the largest contiguous intersection of correctness intervals.
-#[instrument]
+///
+/// For every number of allowed falsetickers, the lower endpoint is where a
+/// scan from below has entered enough intervals, and likewise for the upper
+/// endpoint from above. Both scans are done once for all numbers of allowed
+/// falsetickers, which keeps this linear in the number of candidates.
+#[instrument(skip_all)]
 fn find_interval(chime_list: &[CandidateTuple]) -> Option<(NtpDuration, NtpDuration)> {
     let n = chime_list.len() / 3;
 
-    let mut low = None;
-    let mut high = None;
+    let from_below = depth_edges(chime_list.iter(), EndpointType::Lower);
+    let from_above = depth_edges(chime_list.iter().rev(), EndpointType::Upper);
 
     // allow is the number of allowed falsetickers
     for allow in (0..).take_while(|allow| 2 * allow < n) {
-        // variable "d", falsetickers found in the current iteration
-        let mut found = 0;
-
-        // variable "c", the number of intervals that we have entered but not yet exited
-        // incremented when hitting a Lower, decremented when hitting an Upper
-        let mut depth = 0;
-
-        // Scan the chime list from lowest to highest to find the lower endpoint.
-        // any middle that we find before the lower endpoint counts as a falseticker
-        for tuple in chime_list {
-            depth -= tuple.endpoint_type as i32;
-
-            // the code skeleton uses `n - found` here, which is wrong!
-            if depth >= (n - allow) as i32 {
-                low = Some(tuple.edge);
-                break;
-            }
-
-            if let EndpointType::Middle = tuple.endpoint_type {
-                found += 1;
-            }
-        }
-
-        // Scan the chime list from highest to lowest to find the upper endpoint.
-        // any middle that we find before the upper endpoint counts as a falseticker
-        depth = 0;
-        for tuple in chime_list.iter().rev() {
-            depth += tuple.endpoint_type as i32;
-
-            // the code skeleton uses `n - found` here, which is wrong!
-            if depth >= (n - allow) as i32 {
-                high = Some(tuple.edge);
-                break;
-            }
-
-            if let EndpointType::Middle = tuple.endpoint_type {
-                found += 1;
-            }
-        }
+        // any middle that a scan passes before its endpoint counts as a falseticker.
+        // the code skeleton uses `n - found` as the depth here, which is wrong!
+        let (low, high) = match (from_below.get(n - allow - 1), from_above.get(n - allow - 1)) {
+            (Some(low), Some(high)) => (low, high),
+            _ => continue,
+        };
 
         // counted more falsetickers than allowed in this iteration;
         // we loop and try again allowing one more falseticker
-        if found > allow {
+        if low.1 + high.1 > allow {
             continue;
         }
 
-        //  If the intersection is non-empty, declare success.
-        if let (Some(l), Some(h)) = (low, high) {
-            debug!(
-                low = debug(l),
-                high = debug(h),
-                "Found correctness interval"
-            );
-            return Some((l, h));
-        }
+        debug!(
+            low = debug(low.0),
+            high = debug(high.0),
+            "Found correctness interval"
+        );
+        return Some((low.0, high.0));
     }
 
     None
 }
 
+/// Scan the chime list in the given order, entering an interval at the
+/// `entering` endpoints and leaving it at the opposite ones. Element `d` of
+/// the result is the edge at which `d + 1` intervals were entered at once for
+/// the first time, and the number of middles passed before it.
+fn depth_edges<'a, 'b: 'a>(
+    chime_list: impl Iterator<Item = &'a CandidateTuple<'b>>,
+    entering: EndpointType,
+) -> Vec<(NtpDuration, usize)> {
+    let mut edges = Vec::new();
+
+    // variable "c", the number of intervals that we have entered but not yet exited
+    let mut depth = 0;
+    let mut middles = 0;
+
+    for tuple in chime_list {
+        if tuple.endpoint_type == EndpointType::Middle {
+            middles += 1;
+        } else if tuple.endpoint_type == entering {
+            depth += 1;
+            if depth > edges.len() as i64 {
+                edges.push((tuple.edge, middles));
+            }
+        } else {
+            depth -= 1;
+        }
+    }
+
+    edges
+}
+
 /// Discard the survivor with maximum selection jitter until a termination condition is met.
 ///
+/// The selection jitter of a candidate only depends on its own offset and the
+/// sum of the (squared) offsets of all candidates. As a function of the
+/// offset, it is largest at the lowest or the highest offset, so only those
+/// two candidates are considered for removal in every round. With the
+/// candidates ordered by offset and by jitter, this takes `O(n log n)` time.
+///
 /// returns the (maximum) selection jitter
-#[instrument]
+#[instrument(skip_all)]
 fn cluster_algorithm(config: &SystemConfig, candidates: &mut Vec<SurvivorTuple>) -> f64 {
     // sort the candidates by increasing lambda_p (the merit factor)
     candidates.sort_by(|a, b| a.metric.cmp(&b.metric));
 
-    loop {
-        // the lowest jitter of any candidate peer
-        let mut min_peer_jitter: f64 = 2.0e9;
-
-        // highest RMS average of the `offset` of a candidate vs all others
-        // the candidate with the max_selection_jitter is the worst candidate
-        // we have seen so far, it's offset is most unlike the others.
-        let mut max_selection_jitter = -2.0e9;
-        let mut max_selection_jitter_index = 0;
+    // offsets relative to one of the candidates, to keep the sums precise
+    let reference = match candidates.first() {
+        Some(candidate) => candidate.peer.statistics.offset,
+        None => NtpDuration::ZERO,
+    };
+    let offsets: Vec<f64> = candidates
+        .iter()
+        .map(|candidate| (candidate.peer.statistics.offset - reference).to_seconds())
+        .collect();
+    let mut sum: f64 = offsets.iter().sum();
+    let mut sum_squares: f64 = offsets.iter().map(|offset| offset.powi(2)).sum();
+
+    // ties are broken towards the candidate with the lowest merit factor, as
+    // when searching the candidates in order
+    let mut by_offset: BTreeSet<(NtpDuration, usize)> = candidates
+        .iter()
+        .enumerate()
+        .map(|(index, candidate)| (candidate.peer.statistics.offset, index))
+        .collect();
+    let mut by_jitter: Vec<usize> = (0..candidates.len()).collect();
+    by_jitter.sort_by(|a, b| {
+        let jitter = |index: &usize| candidates[*index].peer.statistics.jitter;
+        jitter(a).total_cmp(&jitter(b))
+    });
+    let mut by_jitter = by_jitter.into_iter().peekable();
+    let mut removed = vec![false; candidates.len()];
 
-        for (index, candidate) in candidates.iter().enumerate() {
-            let p = candidate.peer;
+    loop {
+        let count = by_offset.len();
 
-            min_peer_jitter = f64::min(min_peer_jitter, p.statistics.jitter);
+        // the lowest jitter of any candidate peer
+        while by_jitter.next_if(|index| removed[*index]).is_some() {}
+        let min_peer_jitter = match by_jitter.peek() {
+            Some(index) => f64::min(2.0e9, candidates[*index].peer.statistics.jitter),
+            None => 2.0e9,
+        };
 
-            let selection_jitter_sum = candidates
-                .iter()
-                .map(|q| p.statistics.offset - q.peer.statistics.offset)
-                .map(|delta| delta.to_seconds().powi(2))
-                .sum::<f64>();
+        // RMS average of the `offset` of a candidate vs all others
+        let selection_jitter = |index: usize| {
+            let offset = offsets[index];
+            let selection_jitter_sum =
+                count as f64 * offset.powi(2) - 2.0 * offset * sum + sum_squares;
 
             // prevent a division by 0 if there is just 1 candidate
-            let selection_jitter = if selection_jitter_sum == 0.0 {
+            if count < 2 || selection_jitter_sum <= 0.0 {
                 0.0
             } else {
-                (selection_jitter_sum / ((candidates.len() - 1) as f64)).sqrt()
-            };
+                (selection_jitter_sum / ((count - 1) as f64)).sqrt()
+            }
+        };
 
-            if selection_jitter > max_selection_jitter {
-                max_selection_jitter_index = index;
-                max_selection_jitter = selection_jitter;
+        // the candidate with the max_selection_jitter is the worst candidate
+        // we have seen so far, it's offset is most unlike the others.
+        let lowest = by_offset.iter().next().map(|(_, index)| *index);
+        let highest = by_offset
+            .iter()
+            .next_back()
+            .and_then(|(offset, _)| by_offset.range((*offset, 0)..).next())
+            .map(|(_, index)| *index);
+        let (max_selection_jitter, max_selection_jitter_index) = match (lowest, highest) {
+            (Some(lowest), Some(highest)) => {
+                let (low, high) = (selection_jitter(lowest), selection_jitter(highest));
+                if high > low || (high == low && highest < lowest) {
+                    (high, highest)
+                } else {
+                    (low, lowest)
+                }
             }
-        }
+            _ => (-2.0e9, 0),
+        };
 
         trace!(selection_jitter = debug(max_selection_jitter));
 
@@ -380,9 +646,15 @@ fn cluster_algorithm(config: &SystemConfig, candidates: &mut Vec<SurvivorTuple>)
 
         // To make sure a few survivors are left for the clustering algorithm to chew on, we stop
         // if the number of survivors is less than or equal to NMIN (3).
-        let too_few_survivors = candidates.len() <= config.min_cluster_survivors;
+        let too_few_survivors = count <= config.min_cluster_survivors;
 
         if removed_bad_candidates || too_few_survivors {
+            let mut index = 0;
+            candidates.retain(|_| {
+                index += 1;
+                !removed[index - 1]
+            });
+
             // the final version of max_selection_jitter (psi_max in the spec) is
             // stored under the name "system selection jitter" (PSI_s)
 
@@ -398,11 +670,15 @@ fn cluster_algorithm(config: &SystemConfig, candidates: &mut Vec<SurvivorTuple>)
         }
 
         // delete the survivor qmax (the one with the highest jitter) and go around again
+        let worst = &candidates[max_selection_jitter_index];
         trace!(
-            peer = debug(candidates[max_selection_jitter_index].peer.peer_id),
+            peer = debug(worst.peer.peer_id),
             "Removing high-jitter peer"
         );
-        candidates.remove(max_selection_jitter_index);
+        by_offset.remove(&(worst.peer.statistics.offset, max_selection_jitter_index));
+        removed[max_selection_jitter_index] = true;
+        sum -= offsets[max_selection_jitter_index];
+        sum_squares -= offsets[max_selection_jitter_index].powi(2);
     }
 }
 
@@ -496,7 +772,7 @@ pub fn fuzz_find_interval(spec: &[(i64, u64)]) {
     assert!(survivors.is_empty() || 2 * survivors.len() > spec.len());
 }
 
-#[cfg(any(test, feature = "fuzz", feature = "ext-test"))]
+#[cfg(any(test, feature = "fuzz", feature = "ext-test", feature = "bench"))]
 pub fn test_peer_snapshot(instant: NtpInstant) -> PeerSnapshot {
     peer_snapshot(
         crate::peer::PeerStatistics::default(),
@@ -506,7 +782,7 @@ pub fn test_peer_snapshot(instant: NtpInstant) -> PeerSnapshot {
     )
 }
 
-#[cfg(any(test, feature = "fuzz", feature = "ext-test"))]
+#[cfg(any(test, feature = "fuzz", feature = "ext-test", feature = "bench"))]
 pub fn peer_snapshot(
     statistics: crate::peer::PeerStatistics,
     instant: NtpInstant,
@@ -539,6 +815,10 @@ pub fn peer_snapshot(
         our_id: ReferenceId::from_int(1),
         reach,
         poll_interval: crate::time_types::PollIntervalLimits::default().min,
+        authenticated: false,
+        failures: 0,
+        kind: SourceKind::Network,
+        version: None,
     }
 }
 
@@ -1306,6 +1586,125 @@ mod test {
         }
     }
 
+    /// The cluster algorithm as the code skeleton has it, which computes the
+    /// selection jitter of every candidate from scratch in every round
+    fn quadratic_cluster_algorithm(config: &SystemConfig, candidates: &mut Vec<SurvivorTuple>) {
+        candidates.sort_by_key(|candidate| candidate.metric);
+
+        while candidates.len() > config.min_cluster_survivors {
+            let selection_jitter = |p: &SurvivorTuple| {
+                let sum = candidates
+                    .iter()
+                    .map(|q| (p.peer.statistics.offset - q.peer.statistics.offset).to_seconds())
+                    .map(|delta| delta.powi(2))
+                    .sum::<f64>();
+                (sum / (candidates.len() - 1) as f64).sqrt()
+            };
+            let (worst, max_selection_jitter) = candidates
+                .iter()
+                .map(selection_jitter)
+                .enumerate()
+                .fold((0, -2.0e9), |max, (index, jitter)| match jitter > max.1 {
+                    true => (index, jitter),
+                    false => max,
+                });
+            let min_peer_jitter = candidates
+                .iter()
+                .map(|candidate| candidate.peer.statistics.jitter)
+                .fold(2.0e9, f64::min);
+
+            if max_selection_jitter < min_peer_jitter {
+                break;
+            }
+            candidates.remove(worst);
+        }
+    }
+
+    #[test]
+    fn cluster_algorithm_many_candidates() {
+        let base = test_peer_snapshot(NtpInstant::now());
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let peers: Vec<_> = (0..200)
+            .map(|index| {
+                let mut peer = base;
+                peer.peer_id = ReferenceId::from_int(index + 1);
+                // a few falsetickers among peers that mostly agree
+                let spread = if index % 20 == 0 { 0.5 } else { 0.002 };
+                peer.statistics.offset = NtpDuration::from_seconds(spread * (random() - 0.5));
+                peer.statistics.jitter = 0.0001 + 0.0001 * random();
+                peer
+            })
+            .collect();
+        let metrics: Vec<_> = (0..peers.len())
+            .map(|_| NtpDuration::from_seconds(random()))
+            .collect();
+        let candidates: Vec<_> = peers
+            .iter()
+            .zip(&metrics)
+            .map(|(peer, metric)| SurvivorTuple {
+                peer,
+                metric: *metric,
+            })
+            .collect();
+
+        let config = SystemConfig::default();
+        let mut fast = candidates.clone();
+        cluster_algorithm(&config, &mut fast);
+        let mut quadratic = candidates;
+        quadratic_cluster_algorithm(&config, &mut quadratic);
+
+        let ids = |candidates: &[SurvivorTuple]| -> Vec<_> {
+            candidates.iter().map(|c| c.peer.peer_id).collect()
+        };
+        assert!(fast.len() < 200);
+        assert_eq!(ids(&fast), ids(&quadratic));
+    }
+
+    #[test]
+    fn candidates_are_capped_by_root_distance() {
+        let base = NtpInstant::now();
+        let peers: Vec<_> = (0..10)
+            .map(|index| {
+                let statistics = PeerStatistics {
+                    offset: NtpDuration::from_seconds(0.001),
+                    delay: NtpDuration::from_seconds(0.01),
+                    dispersion: NtpDuration::from_seconds(0.01),
+                    jitter: 0.001,
+                };
+                // the root distance grows with the index, apart from the last peer
+                let root_delay = NtpDuration::from_seconds(0.01 * ((index + 1) % 10) as f64);
+                PeerSnapshot {
+                    peer_id: ReferenceId::from_int(index + 1),
+                    ..peer_snapshot(statistics, base, root_delay, NtpDuration::ZERO)
+                }
+            })
+            .collect();
+        let config = SystemConfig {
+            max_candidates: 4,
+            ..Default::default()
+        };
+        let poll = PollIntervalLimits::default().min;
+
+        let intervals = SelectionIntervals::new(&config, &peers, base, poll);
+        let mut used: Vec<_> = intervals
+            .candidates
+            .iter()
+            .map(|interval| interval.peer_id)
+            .collect();
+        used.sort_by_key(|id| id.to_bytes());
+        let expected: Vec<_> = [1, 2, 3, 10].map(ReferenceId::from_int).into();
+        assert_eq!(used, expected);
+
+        let result = FilterAndCombine::run(&config, &peers, base, poll).unwrap();
+        assert_eq!(result.survivors.len(), 4);
+    }
+
     #[test]
     fn system_variable_update() {
         let instant = NtpInstant::now();
@@ -1321,6 +1720,7 @@ mod test {
                 NtpDuration::ZERO,
                 NtpDuration::ZERO,
             ),
+            survivors: vec![],
         };
 
         let frequency_tolerance = FrequencyTolerance::ppm(15);
@@ -1343,6 +1743,231 @@ mod test {
         assert!(distance < NtpDuration::ONE / 2i64);
     }
 
+    #[test]
+    fn system_variables_saturate() {
+        let instant = NtpInstant::now();
+
+        // values far beyond what a packet can carry, to stress the arithmetic
+        let state = FilterAndCombine {
+            system_offset: NtpDuration::MIN,
+            system_jitter: Default::default(),
+            system_root_delay: Default::default(),
+            system_root_dispersion: Default::default(),
+            system_peer_snapshot: peer_snapshot(
+                PeerStatistics {
+                    offset: NtpDuration::MIN,
+                    delay: NtpDuration::MAX,
+                    dispersion: NtpDuration::MAX,
+                    jitter: f64::MAX,
+                },
+                instant,
+                NtpDuration::MAX,
+                NtpDuration::MAX,
+            ),
+            survivors: vec![],
+        };
+
+        let frequency_tolerance = FrequencyTolerance::ppm(1_000_000);
+        let local_clock_time = instant + Duration::from_secs(1 << 40);
+
+        assert_eq!(state.system_root_delay(), NtpDuration::MAX);
+        assert_eq!(
+            state.system_root_dispersion(local_clock_time, frequency_tolerance),
+            NtpDuration::MAX
+        );
+        assert_eq!(
+            state.root_synchronization_distance(local_clock_time, frequency_tolerance),
+            NtpDuration::MAX
+        );
+        assert_eq!(
+            state
+                .system_peer_snapshot
+                .root_distance(local_clock_time, frequency_tolerance),
+            NtpDuration::MAX
+        );
+    }
+
+    #[test]
+    fn authentication_policy() {
+        let base = NtpInstant::now();
+        let peers: Vec<_> = (0..3)
+            .map(|index| {
+                let statistics = PeerStatistics {
+                    offset: NtpDuration::from_seconds(0.001 * index as f64),
+                    delay: NtpDuration::from_seconds(0.01),
+                    dispersion: NtpDuration::from_seconds(0.01),
+                    jitter: 0.001,
+                };
+                PeerSnapshot {
+                    peer_id: ReferenceId::from_int(index + 1),
+                    authenticated: index == 0,
+                    ..peer_snapshot(statistics, base, NtpDuration::ZERO, NtpDuration::ZERO)
+                }
+            })
+            .collect();
+
+        let run = |policy, peers: &[PeerSnapshot]| {
+            let config = SystemConfig {
+                authentication_policy: policy,
+                ..Default::default()
+            };
+            FilterAndCombine::run(&config, peers, base, PollIntervalLimits::default().min)
+        };
+
+        let result = run(AuthenticationPolicy::Any, &peers).unwrap();
+        assert_eq!(result.survivors.len(), 3);
+
+        assert_eq!(
+            run(AuthenticationPolicy::Majority, &peers).unwrap_err(),
+            SelectionError::InsufficientAuthenticated {
+                authenticated: 1,
+                required: 2
+            }
+        );
+
+        // the unauthenticated sources count towards the consensus, but do not set the clock
+        let result = run(AuthenticationPolicy::Required, &peers).unwrap();
+        assert_eq!(result.survivors, vec![ReferenceId::from_int(1)]);
+
+        let mut peers = peers;
+        peers[1].authenticated = true;
+        let result = run(AuthenticationPolicy::Majority, &peers).unwrap();
+        assert_eq!(result.survivors.len(), 3);
+
+        for peer in &mut peers {
+            peer.authenticated = false;
+        }
+        assert_eq!(
+            run(AuthenticationPolicy::Required, &peers).unwrap_err(),
+            SelectionError::InsufficientAuthenticated {
+                authenticated: 0,
+                required: 1
+            }
+        );
+    }
+
+    #[test]
+    fn selection_intervals() {
+        let base = NtpInstant::now();
+        let offsets = [0.001, 0.002, 0.003, 0.5];
+        let peers: Vec<_> = offsets
+            .iter()
+            .enumerate()
+            .map(|(index, offset)| {
+                let statistics = PeerStatistics {
+                    offset: NtpDuration::from_seconds(*offset),
+                    delay: NtpDuration::from_seconds(0.01),
+                    dispersion: NtpDuration::from_seconds(0.01),
+                    jitter: 0.001,
+                };
+                PeerSnapshot {
+                    peer_id: ReferenceId::from_int(index as u32 + 1),
+                    ..peer_snapshot(statistics, base, NtpDuration::ZERO, NtpDuration::ZERO)
+                }
+            })
+            .collect();
+        let config = SystemConfig::default();
+        let poll = PollIntervalLimits::default().min;
+
+        let intervals = SelectionIntervals::new(&config, &peers, base, poll);
+        assert_eq!(intervals.candidates.len(), 4);
+        let (low, high) = intervals.intersection.unwrap();
+        for (interval, peer) in intervals.candidates.iter().zip(&peers) {
+            assert_eq!(interval.peer_id, peer.peer_id);
+            assert_eq!(interval.offset, peer.statistics.offset);
+            assert!(interval.low < interval.offset && interval.offset < interval.high);
+        }
+        let truechimers: Vec<_> = intervals
+            .candidates
+            .iter()
+            .map(|interval| interval.truechimer)
+            .collect();
+        assert_eq!(truechimers, [true, true, true, false]);
+        assert!(low <= NtpDuration::from_seconds(0.001));
+        assert!(high >= NtpDuration::from_seconds(0.003));
+        assert!(high < NtpDuration::from_seconds(0.5));
+
+        // the survivors of clock selection are the truechimers
+        let result = FilterAndCombine::run(&config, &peers, base, poll).unwrap();
+        assert!(!result.survivors.contains(&ReferenceId::from_int(4)));
+
+        // two sources that disagree have no majority
+        let disagreeing = [peers[0], peers[3]];
+        let intervals = SelectionIntervals::new(&config, &disagreeing, base, poll);
+        assert_eq!(intervals.intersection, None);
+        assert!(intervals
+            .candidates
+            .iter()
+            .all(|interval| !interval.truechimer));
+    }
+
+    #[test]
+    fn refclock_corroboration() {
+        let base = NtpInstant::now();
+        let statistics = |offset| PeerStatistics {
+            offset: NtpDuration::from_seconds(offset),
+            delay: NtpDuration::from_seconds(0.01),
+            dispersion: NtpDuration::from_seconds(0.01),
+            jitter: 0.001,
+        };
+        let refclock = PeerSnapshot {
+            peer_id: ReferenceId::GPS,
+            kind: SourceKind::RefClock {
+                needs_corroboration: true,
+            },
+            ..peer_snapshot(statistics(0.0), base, NtpDuration::ZERO, NtpDuration::ZERO)
+        };
+        let peer = PeerSnapshot {
+            peer_id: ReferenceId::from_int(1),
+            stratum: 1,
+            ..peer_snapshot(
+                statistics(0.002),
+                base,
+                NtpDuration::ZERO,
+                NtpDuration::ZERO,
+            )
+        };
+
+        let config = SystemConfig {
+            min_intersection_survivors: 1,
+            ..Default::default()
+        };
+        let run = |peers: &[PeerSnapshot]| {
+            FilterAndCombine::run(&config, peers, base, PollIntervalLimits::default().min)
+        };
+
+        // on its own, the reference clock is not trusted
+        assert_eq!(
+            run(&[refclock]).unwrap_err(),
+            SelectionError::InsufficientConsensus {
+                agreeing: 0,
+                required: 1
+            }
+        );
+
+        // a peer that agrees with it makes it the system peer, as it has the lowest stratum
+        let result = run(&[refclock, peer]).unwrap();
+        assert_eq!(result.survivors.len(), 2);
+        assert_eq!(result.system_peer_snapshot.peer_id, ReferenceId::GPS);
+
+        // a peer that does not agree with it does not
+        let peer = PeerSnapshot {
+            statistics: statistics(1.0),
+            ..peer
+        };
+        assert!(run(&[refclock, peer]).is_err());
+
+        // without the requirement, the reference clock is used on its own
+        let refclock = PeerSnapshot {
+            kind: SourceKind::RefClock {
+                needs_corroboration: false,
+            },
+            ..refclock
+        };
+        let result = run(&[refclock]).unwrap();
+        assert_eq!(result.system_peer_snapshot.peer_id, ReferenceId::GPS);
+    }
+
     #[test]
     fn root_delay_dispersion_calculation() {
         let base = NtpInstant::now();
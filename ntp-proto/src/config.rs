@@ -1,5 +1,7 @@
+#[cfg(feature = "serde")]
 use std::fmt;
 
+#[cfg(feature = "serde")]
 use serde::{
     de::{self, MapAccess, Visitor},
     Deserialize, Deserializer,
@@ -10,6 +12,7 @@ use crate::{
     NtpDuration, PollInterval,
 };
 
+#[cfg(feature = "serde")]
 fn deserialize_option_threshold<'de, D>(deserializer: D) -> Result<Option<NtpDuration>, D::Error>
 where
     D: Deserializer<'de>,
@@ -22,15 +25,97 @@ where
     })
 }
 
+/// Which sources are allowed to set the clock, depending on whether their
+/// packets are authenticated
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum AuthenticationPolicy {
+    /// Authenticated and unauthenticated sources are treated alike
+    #[default]
+    Any,
+    /// The sources that agree on the time must mostly be authenticated
+    Majority,
+    /// Only authenticated sources set the clock. Unauthenticated sources
+    /// still take part in finding the sources that agree on the time, so
+    /// they cross-check the authenticated ones.
+    Required,
+}
+
+/// What to do with sources that appear to smear leap seconds, while other
+/// sources insert them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum SmearPolicy {
+    /// Leave the smearing sources out of clock selection, as combining them
+    /// with the other sources pulls the clock somewhere in between
+    #[default]
+    Exclude,
+    /// Only report the smearing sources
+    Report,
+}
+
+/// Largest age of the reference timestamp of a source, relative to the
+/// transmit timestamp of its packets. A source that did not update its own
+/// clock for longer than this is not used. Disabled when `None`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize),
+    serde(rename_all = "kebab-case", default, deny_unknown_fields)
+)]
+pub struct ReferenceAgeLimits {
+    /// Limit for stratum 1 servers, which may hold their reference clock
+    /// through a long outage
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "deserialize_option_threshold")
+    )]
+    pub primary: Option<NtpDuration>,
+    /// Limit for servers of a higher stratum
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "deserialize_option_threshold")
+    )]
+    pub secondary: Option<NtpDuration>,
+}
+
+impl ReferenceAgeLimits {
+    pub fn for_stratum(&self, stratum: u8) -> Option<NtpDuration> {
+        match stratum {
+            1 => self.primary,
+            _ => self.secondary,
+        }
+    }
+}
+
+impl Default for ReferenceAgeLimits {
+    fn default() -> Self {
+        Self {
+            primary: Some(NtpDuration::from_seconds(86400.)),
+            secondary: Some(NtpDuration::from_seconds(3600.)),
+        }
+    }
+}
+
 #[derive(Debug, Default, Copy, Clone)]
 pub struct StepThreshold {
     pub forward: Option<NtpDuration>,
     pub backward: Option<NtpDuration>,
 }
 
+#[cfg(feature = "serde")]
 #[derive(Debug, Copy, Clone)]
 struct ThresholdPart(Option<NtpDuration>);
 
+#[cfg(feature = "serde")]
 impl<'de> Deserialize<'de> for ThresholdPart {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -42,7 +127,7 @@ impl<'de> Deserialize<'de> for ThresholdPart {
             type Value = ThresholdPart;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("float or \"inf\"")
+                formatter.write_str("float, duration or \"inf\"")
             }
 
             fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
@@ -70,13 +155,16 @@ impl<'de> Deserialize<'de> for ThresholdPart {
             where
                 E: de::Error,
             {
-                if v != "inf" {
-                    return Err(de::Error::invalid_value(
+                if v == "inf" {
+                    return Ok(ThresholdPart(None));
+                }
+                match v.parse() {
+                    Ok(duration) => Ok(ThresholdPart(Some(duration))),
+                    Err(_) => Err(de::Error::invalid_value(
                         de::Unexpected::Str(v),
-                        &"float or \"inf\"",
-                    ));
+                        &"float, duration or \"inf\"",
+                    )),
                 }
-                Ok(ThresholdPart(None))
             }
         }
 
@@ -86,6 +174,7 @@ impl<'de> Deserialize<'de> for ThresholdPart {
 
 // We have a custom deserializer for StepThreshold because we
 // want to deserialize it from either a number or map
+#[cfg(feature = "serde")]
 impl<'de> Deserialize<'de> for StepThreshold {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -97,7 +186,7 @@ impl<'de> Deserialize<'de> for StepThreshold {
             type Value = StepThreshold;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("float, map or \"inf\"")
+                formatter.write_str("float, duration, map or \"inf\"")
             }
 
             fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
@@ -129,16 +218,22 @@ impl<'de> Deserialize<'de> for StepThreshold {
             where
                 E: de::Error,
             {
-                if v != "inf" {
-                    return Err(de::Error::invalid_value(
+                if v == "inf" {
+                    return Ok(StepThreshold {
+                        forward: None,
+                        backward: None,
+                    });
+                }
+                match v.parse() {
+                    Ok(duration) => Ok(StepThreshold {
+                        forward: Some(duration),
+                        backward: Some(duration),
+                    }),
+                    Err(_) => Err(de::Error::invalid_value(
                         de::Unexpected::Str(v),
-                        &"float, map or \"inf\"",
-                    ));
+                        &"float, duration, map or \"inf\"",
+                    )),
                 }
-                Ok(StepThreshold {
-                    forward: None,
-                    backward: None,
-                })
             }
 
             fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<StepThreshold, M::Error> {
@@ -178,8 +273,9 @@ impl<'de> Deserialize<'de> for StepThreshold {
     }
 }
 
-#[derive(Deserialize, Debug, Clone, Copy)]
-#[serde(rename_all = "kebab-case")]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
 pub struct SystemConfig {
     /// Minimum number of survivors needed to be able to discipline the system clock.
     /// More survivors (so more servers from which to get the time) means a more accurate time.
@@ -189,9 +285,25 @@ pub struct SystemConfig {
     /// > CMIN defines the minimum number of servers consistent with the correctness requirements.
     /// > Suspicious operators would set CMIN to ensure multiple redundant servers are available for the
     /// > algorithms to mitigate properly. However, for historic reasons the default value for CMIN is one.
-    #[serde(default = "default_min_intersection_survivors")]
+    ///
+    /// Until this many sources agree on the time, the clock is not adjusted at
+    /// all, which protects against a single compromised source. It can also
+    /// be configured as `minimum-agreeing-sources`.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            default = "default_min_intersection_survivors",
+            alias = "minimum-agreeing-sources"
+        )
+    )]
     pub min_intersection_survivors: usize,
 
+    /// Whether authenticated sources (NTS, a symmetric key, or a local
+    /// reference clock) must make up the majority of the sources that agree
+    /// on the time, or are the only ones that may set the clock
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub authentication_policy: AuthenticationPolicy,
+
     /// Number of survivors that the cluster_algorithm tries to keep.
     ///
     /// The code skeleton notes that the goal is to give the cluster algorithm something to chew on.
@@ -200,28 +312,39 @@ pub struct SystemConfig {
     ///
     /// Because the input can have fewer than 3 survivors, the MIN_CLUSTER_SURVIVORS
     /// is not an actual lower bound on the number of survivors.
-    #[serde(default = "default_min_cluster_survivors")]
+    #[cfg_attr(feature = "serde", serde(default = "default_min_cluster_survivors"))]
     pub min_cluster_survivors: usize,
 
+    /// Largest number of sources that take part in clock selection. When
+    /// more sources are acceptable for synchronization, those with the
+    /// smallest root distance are used. This bounds the work of every clock
+    /// update when many sources are configured, such as on a machine that
+    /// monitors a large number of servers.
+    #[cfg_attr(feature = "serde", serde(default = "default_max_candidates"))]
+    pub max_candidates: usize,
+
     /// How much the time is allowed to drift (worst-case) per second.
     /// The drift caused by our frequency not exactly matching the real time
-    #[serde(default = "default_frequency_tolerance")]
+    #[cfg_attr(feature = "serde", serde(default = "default_frequency_tolerance"))]
     pub frequency_tolerance: FrequencyTolerance,
 
     /// A distance error occurs if the root distance exceeds the
     /// distance threshold plus an increment equal to one poll interval.
-    #[serde(default = "default_distance_threshold")]
+    #[cfg_attr(feature = "serde", serde(default = "default_distance_threshold"))]
     pub distance_threshold: NtpDuration,
 
     /// The amount of time to use to measure the system clocks frequency error
     /// on startup. Longer time periods give a more accurate initial estimate,
     /// but it will take longer for the clock to be fully synchronized
-    #[serde(default = "default_frequency_measurement_period")]
+    #[cfg_attr(
+        feature = "serde",
+        serde(default = "default_frequency_measurement_period")
+    )]
     pub frequency_measurement_period: NtpDuration,
 
     /// The amount of time before a spike (a time difference greater than 0.125s)
     /// is considered real and not the result of a transient network condition
-    #[serde(default = "default_spike_threshold")]
+    #[cfg_attr(feature = "serde", serde(default = "default_spike_threshold"))]
     pub spike_threshold: NtpDuration,
 
     /// The maximum amount the system clock is allowed to change in a single go
@@ -231,40 +354,99 @@ pub struct SystemConfig {
     ///
     /// Note that this is not used during startup. To limit system clock changes
     /// during startup, use startup_panic_threshold
-    #[serde(default = "default_panic_threshold")]
+    #[cfg_attr(feature = "serde", serde(default = "default_panic_threshold"))]
     pub panic_threshold: StepThreshold,
 
     /// The maximum amount the system clock is allowed to change during startup.
     /// This can be used to limit the impact of bad servers if the system clock
     /// is known to be reasonable on startup
-    #[serde(default = "startup_panic_threshold")]
+    #[cfg_attr(feature = "serde", serde(default = "startup_panic_threshold"))]
     pub startup_panic_threshold: StepThreshold,
 
     /// The maximum amount distributed amongst all steps except at startup the
     /// daemon is allowed to step the system clock.
-    #[serde(deserialize_with = "deserialize_option_threshold", default)]
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "deserialize_option_threshold", default)
+    )]
     pub accumulated_threshold: Option<NtpDuration>,
 
+    /// The largest correction of the clock made by a single update. Larger
+    /// offsets are corrected over several updates instead, which bounds how
+    /// fast a broken or malicious source can move the clock. Like the panic
+    /// threshold, this is not used during startup. Disabled when `None`
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "deserialize_option_threshold", default)
+    )]
+    pub max_correction: Option<NtpDuration>,
+
     /// Stratum of the local clock, when not synchronized through ntp. This
     /// can be used in servers to indicate that there are external mechanisms
     /// synchronizing the clock
-    #[serde(default = "default_local_stratum")]
+    #[cfg_attr(feature = "serde", serde(default = "default_local_stratum"))]
     pub local_stratum: u8,
 
+    /// Maximum age of the samples in the clock filter of a source. Older
+    /// samples are discarded when a new sample comes in, instead of being
+    /// used with an increased dispersion. Disabled when `None`
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "deserialize_option_threshold", default)
+    )]
+    pub filter_max_age: Option<NtpDuration>,
+
+    /// Largest age of the reference timestamp of a source, by its stratum.
+    /// Protects against an upstream server that lost its own sources but
+    /// keeps claiming a low stratum.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_reference_age: ReferenceAgeLimits,
+
     /// Minima and maxima for the poll interval of clients
-    #[serde(default)]
+    #[cfg_attr(feature = "serde", serde(default))]
     pub poll_limits: PollIntervalLimits,
 
     /// Initial poll interval of the system
-    #[serde(default = "default_initial_poll")]
+    #[cfg_attr(feature = "serde", serde(default = "default_initial_poll"))]
     pub initial_poll: PollInterval,
+
+    /// Number of requests a peer sends at startup, 2 seconds apart, before
+    /// it continues at its poll interval. Such a burst gives clock selection
+    /// several samples of every peer soon after startup, so that the clock
+    /// can be set quickly. Disabled when 0, the default.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub initial_burst: u8,
+
+    /// Number of consecutive requests of a peer that may go unanswered, or
+    /// be answered with a RATE kiss code, before the peer is replaced by a
+    /// new one with a freshly resolved address. Disabled when 0, the default.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_failures: u32,
+
+    /// Number of low order bits of the transmit timestamp of requests that
+    /// are random, the others are taken from the local clock. Responses must
+    /// echo the transmit timestamp, so random bits make it harder for an
+    /// off-path attacker to spoof responses. With 64, the default, the
+    /// transmit timestamp is entirely random.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default = "default_transmit_timestamp_random_bits")
+    )]
+    pub transmit_timestamp_random_bits: u8,
+
+    /// What to do with sources that appear to smear leap seconds, when
+    /// other sources do not
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub smeared_sources: SmearPolicy,
 }
 
 impl Default for SystemConfig {
     fn default() -> Self {
         Self {
             min_intersection_survivors: default_min_intersection_survivors(),
+            authentication_policy: AuthenticationPolicy::Any,
             min_cluster_survivors: default_min_cluster_survivors(),
+            max_candidates: default_max_candidates(),
             frequency_tolerance: default_frequency_tolerance(),
             distance_threshold: default_distance_threshold(),
 
@@ -273,11 +455,18 @@ impl Default for SystemConfig {
             panic_threshold: default_panic_threshold(),
             startup_panic_threshold: StepThreshold::default(),
             accumulated_threshold: None,
+            max_correction: None,
 
             local_stratum: default_local_stratum(),
+            filter_max_age: None,
+            max_reference_age: ReferenceAgeLimits::default(),
 
             poll_limits: Default::default(),
             initial_poll: default_initial_poll(),
+            initial_burst: 0,
+            max_failures: 0,
+            transmit_timestamp_random_bits: default_transmit_timestamp_random_bits(),
+            smeared_sources: SmearPolicy::Exclude,
         }
     }
 }
@@ -290,6 +479,10 @@ fn default_min_cluster_survivors() -> usize {
     3
 }
 
+fn default_max_candidates() -> usize {
+    256
+}
+
 fn default_frequency_tolerance() -> FrequencyTolerance {
     FrequencyTolerance::ppm(15)
 }
@@ -314,6 +507,7 @@ fn default_panic_threshold() -> StepThreshold {
     }
 }
 
+#[cfg(feature = "serde")]
 fn startup_panic_threshold() -> StepThreshold {
     StepThreshold {
         forward: None,
@@ -328,3 +522,7 @@ fn default_local_stratum() -> u8 {
 fn default_initial_poll() -> PollInterval {
     PollIntervalLimits::default().min
 }
+
+fn default_transmit_timestamp_random_bits() -> u8 {
+    64
+}
@@ -0,0 +1,497 @@
+// Read-only subset of the NTP control protocol (mode 6), as used by `ntpq`.
+//
+// A control message consists of a 12 byte header followed by data:
+//
+//  - byte 0: leap indicator (always 0), version and mode (6)
+//  - byte 1: response, error and more bits followed by the 5 bit opcode
+//  - sequence number, status, association id, offset and count (all u16)
+//
+// Data is padded to a multiple of 4 bytes. Responses that do not fit in a
+// single packet are split into fragments, each carrying the offset of its
+// data in the full response, with the more bit set on all but the last.
+//
+// Variables are encoded as text of the form `name=value, name=value`, with
+// the names of the requested variables given in the same form (without
+// values) in the data of the request.
+
+use std::fmt::{Display, Write};
+
+use crate::{
+    packet::PacketParsingError, NtpLeapIndicator, PeerSnapshot, ReferenceId, SystemSnapshot,
+};
+
+const HEADER_LENGTH: usize = 12;
+
+/// Maximum amount of data in a single fragment
+const MAX_FRAGMENT_DATA: usize = 468;
+
+/// Lines of variables are broken when they grow longer than this
+const MAX_LINE_LENGTH: usize = 72;
+
+const RESPONSE_BIT: u8 = 0x80;
+const ERROR_BIT: u8 = 0x40;
+const MORE_BIT: u8 = 0x20;
+const OPCODE_MASK: u8 = 0x1f;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlOpcode {
+    /// Read the status of the system and a list of associations, or the
+    /// status of a single association
+    ReadStatus,
+    /// Read the variables of the system or of a single association
+    ReadVariables,
+    Unsupported(u8),
+}
+
+impl ControlOpcode {
+    fn from_bits(bits: u8) -> Self {
+        match bits {
+            1 => ControlOpcode::ReadStatus,
+            2 => ControlOpcode::ReadVariables,
+            other => ControlOpcode::Unsupported(other),
+        }
+    }
+
+    fn to_bits(self) -> u8 {
+        match self {
+            ControlOpcode::ReadStatus => 1,
+            ControlOpcode::ReadVariables => 2,
+            ControlOpcode::Unsupported(other) => other & OPCODE_MASK,
+        }
+    }
+}
+
+/// Error codes, following the values used by ntpd
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlError {
+    Unspecified = 0,
+    Permission = 1,
+    BadFormat = 2,
+    BadOpcode = 3,
+    BadAssociation = 4,
+    UnknownVariable = 5,
+}
+
+/// A control request, only requests (not responses) are accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlMessage<'a> {
+    pub version: u8,
+    pub opcode: ControlOpcode,
+    pub sequence: u16,
+    pub association_id: u16,
+    pub data: &'a [u8],
+}
+
+impl<'a> ControlMessage<'a> {
+    /// Whether the packet has the mode of a control message. Control
+    /// messages are shorter than regular NTP packets, so this needs to be
+    /// determined before the length of the packet is checked.
+    pub fn is_control(data: &[u8]) -> bool {
+        data.first().map(|b| b & 0x07) == Some(6)
+    }
+
+    pub fn deserialize(data: &'a [u8]) -> Result<Self, PacketParsingError> {
+        if data.len() < HEADER_LENGTH || data[1] & (RESPONSE_BIT | MORE_BIT) != 0 {
+            return Err(PacketParsingError::IncorrectLength);
+        }
+
+        let version = (data[0] & 0x38) >> 3;
+        if !(2..=4).contains(&version) {
+            return Err(PacketParsingError::InvalidVersion(version));
+        }
+
+        let word = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+        let opcode = ControlOpcode::from_bits(data[1] & OPCODE_MASK);
+        let sequence = word(2);
+        let association_id = word(6);
+        let count = word(10) as usize;
+
+        let data = data
+            .get(HEADER_LENGTH..HEADER_LENGTH + count)
+            .ok_or(PacketParsingError::IncorrectLength)?;
+
+        Ok(ControlMessage {
+            version,
+            opcode,
+            sequence,
+            association_id,
+            data,
+        })
+    }
+
+    /// Names of the variables requested, an empty list means all variables
+    pub fn requested_variables(&self) -> Vec<&'a str> {
+        std::str::from_utf8(self.data)
+            .unwrap_or_default()
+            .split(',')
+            // values given with the names are meaningless for reading
+            .map(|item| {
+                item.split('=')
+                    .next()
+                    .unwrap_or_default()
+                    .trim_matches(|c: char| c.is_whitespace() || c == '\0')
+            })
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+}
+
+/// The answer to a single control request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlResponse {
+    version: u8,
+    opcode: ControlOpcode,
+    sequence: u16,
+    status: u16,
+    association_id: u16,
+    error: bool,
+    data: Vec<u8>,
+}
+
+impl ControlResponse {
+    pub fn new(request: &ControlMessage, status: u16, data: Vec<u8>) -> Self {
+        ControlResponse {
+            version: request.version,
+            opcode: request.opcode,
+            sequence: request.sequence,
+            status,
+            association_id: request.association_id,
+            error: false,
+            data,
+        }
+    }
+
+    pub fn error(request: &ControlMessage, error: ControlError) -> Self {
+        ControlResponse {
+            error: true,
+            ..Self::new(request, (error as u16) << 8, vec![])
+        }
+    }
+
+    /// Split the response into packets of at most 480 bytes
+    pub fn serialize_fragments(&self) -> Vec<Vec<u8>> {
+        let mut chunks: Vec<&[u8]> = self.data.chunks(MAX_FRAGMENT_DATA).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+
+        let last = chunks.len() - 1;
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut flags = RESPONSE_BIT;
+                if self.error {
+                    flags |= ERROR_BIT;
+                }
+                if i != last {
+                    flags |= MORE_BIT;
+                }
+
+                let offset = (i * MAX_FRAGMENT_DATA) as u16;
+                let mut packet = Vec::with_capacity(HEADER_LENGTH + chunk.len() + 3);
+                packet.push((self.version << 3) | 6);
+                packet.push(flags | self.opcode.to_bits());
+                packet.extend_from_slice(&self.sequence.to_be_bytes());
+                packet.extend_from_slice(&self.status.to_be_bytes());
+                packet.extend_from_slice(&self.association_id.to_be_bytes());
+                packet.extend_from_slice(&offset.to_be_bytes());
+                packet.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+                packet.extend_from_slice(chunk);
+                while packet.len() % 4 != 0 {
+                    packet.push(0);
+                }
+                packet
+            })
+            .collect()
+    }
+}
+
+/// Selection state of a peer, as reported in its status word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSelection {
+    /// Not (yet) usable
+    Rejected = 0,
+    /// Survived clock selection
+    Candidate = 4,
+    /// The primary source of the system
+    SystemPeer = 6,
+}
+
+/// The status word of the system: leap indicator and whether the clock is
+/// synchronized to NTP (source 6)
+pub fn system_status(leap_indicator: NtpLeapIndicator) -> u16 {
+    let source = if leap_indicator.is_synchronized() {
+        6
+    } else {
+        0
+    };
+    ((leap_indicator.to_bits() as u16) << 14) | (source << 8)
+}
+
+/// The status word of a peer. All our associations are configured ones.
+pub fn peer_status(reachable: bool, selection: PeerSelection) -> u16 {
+    const CONFIGURED: u16 = 0x80;
+    const REACHABLE: u16 = 0x10;
+
+    let mut status = CONFIGURED;
+    if reachable {
+        status |= REACHABLE;
+    }
+    (status | selection as u16) << 8
+}
+
+/// A list of variables and their values, as returned in response to a read
+/// variables request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ControlVariables {
+    variables: Vec<(&'static str, String)>,
+}
+
+impl ControlVariables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, name: &'static str, value: impl Display) {
+        self.variables.push((name, value.to_string()));
+    }
+
+    /// Add a variable with a quoted string value
+    pub fn push_quoted(&mut self, name: &'static str, value: impl Display) {
+        self.variables.push((name, format!("\"{}\"", value)));
+    }
+
+    /// Variables of the system. Durations are given in milliseconds.
+    pub fn for_system(system: &SystemSnapshot) -> Self {
+        let mut variables = Self::new();
+        variables.push("leap", leap_bits(system.leap_indicator));
+        variables.push("stratum", system.stratum);
+        variables.push("precision", system.precision.log2());
+        variables.push("rootdelay", millis(system.root_delay.to_seconds()));
+        variables.push("rootdisp", millis(system.root_dispersion.to_seconds()));
+        variables.push(
+            "refid",
+            format_reference_id(system.reference_id, system.stratum),
+        );
+        variables.push("tc", system.poll_interval.as_log());
+        variables
+    }
+
+    /// Variables describing the measurements of a peer. Durations are given
+    /// in milliseconds.
+    pub fn for_peer(snapshot: &PeerSnapshot) -> Self {
+        let mut variables = Self::new();
+        variables.push("leap", leap_bits(snapshot.leap_indicator));
+        variables.push("stratum", snapshot.stratum);
+        variables.push("rootdelay", millis(snapshot.root_delay.to_seconds()));
+        variables.push("rootdisp", millis(snapshot.root_dispersion.to_seconds()));
+        variables.push(
+            "refid",
+            format_reference_id(snapshot.reference_id, snapshot.stratum),
+        );
+        variables.push("reach", format_args!("{:#04x}", snapshot.reach.bits()));
+        variables.push("unreach", snapshot.reach.unanswered_polls());
+        variables.push("hmode", 3);
+        variables.push("pmode", 4);
+        variables.push("hpoll", snapshot.poll_interval.as_log());
+        variables.push("ppoll", snapshot.poll_interval.as_log());
+        variables.push("offset", millis(snapshot.statistics.offset.to_seconds()));
+        variables.push("delay", millis(snapshot.statistics.delay.to_seconds()));
+        variables.push(
+            "dispersion",
+            millis(snapshot.statistics.dispersion.to_seconds()),
+        );
+        variables.push("jitter", millis(snapshot.statistics.jitter));
+        variables
+    }
+
+    /// Encode the requested variables (all of them when none are requested)
+    /// as text. Requested variables that are unknown are left out, as
+    /// monitoring tools ask for variables that we do not track.
+    pub fn encode(&self, requested: &[&str]) -> Vec<u8> {
+        let mut result = String::new();
+        let mut line_length = 0;
+
+        let selected = self
+            .variables
+            .iter()
+            .filter(|(name, _)| requested.is_empty() || requested.contains(name));
+
+        for (name, value) in selected {
+            let item_length = name.len() + 1 + value.len();
+
+            if !result.is_empty() {
+                if line_length + 2 + item_length > MAX_LINE_LENGTH {
+                    result.push_str(",\r\n");
+                    line_length = 0;
+                } else {
+                    result.push_str(", ");
+                    line_length += 2;
+                }
+            }
+
+            // Writing to a string can not fail
+            let _ = write!(result, "{}={}", name, value);
+            line_length += item_length;
+        }
+
+        if !result.is_empty() {
+            result.push_str("\r\n");
+        }
+
+        result.into_bytes()
+    }
+}
+
+fn millis(seconds: f64) -> String {
+    format!("{:.3}", seconds * 1e3)
+}
+
+fn leap_bits(leap_indicator: NtpLeapIndicator) -> String {
+    format!("{:02b}", leap_indicator.to_bits())
+}
+
+/// Reference ids of stratum 0 and 1 sources are ASCII, others are addresses
+pub fn format_reference_id(reference_id: ReferenceId, stratum: u8) -> String {
+    let bytes = reference_id.to_bytes();
+
+    if stratum <= 1 {
+        bytes
+            .iter()
+            .take_while(|b| **b != 0)
+            .filter(|b| b.is_ascii_graphic())
+            .map(|b| *b as char)
+            .collect()
+    } else {
+        format!("{}.{}.{}.{}", bytes[0], bytes[1], bytes[2], bytes[3])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{clock_select::peer_snapshot, NtpDuration, NtpInstant, PeerStatistics};
+
+    use super::*;
+
+    #[test]
+    fn test_deserialize() {
+        // ntpq "rv 0 stratum,refid"
+        let mut packet = vec![0x16, 0x02, 0x00, 0x07, 0, 0, 0, 0, 0, 0, 0, 13];
+        packet.extend_from_slice(b"stratum,refid\0\0\0");
+
+        let message = ControlMessage::deserialize(&packet).unwrap();
+        assert_eq!(message.version, 2);
+        assert_eq!(message.opcode, ControlOpcode::ReadVariables);
+        assert_eq!(message.sequence, 7);
+        assert_eq!(message.association_id, 0);
+        assert_eq!(message.requested_variables(), vec!["stratum", "refid"]);
+
+        // responses are not accepted
+        packet[1] |= RESPONSE_BIT;
+        assert!(ControlMessage::deserialize(&packet).is_err());
+
+        // count beyond the end of the packet
+        let packet = [0x16, 0x01, 0, 1, 0, 0, 0, 0, 0, 0, 0, 4];
+        assert!(ControlMessage::deserialize(&packet).is_err());
+
+        let packet = [0x16, 0x01, 0, 1, 0, 0, 0, 3, 0, 0, 0, 0];
+        let message = ControlMessage::deserialize(&packet).unwrap();
+        assert_eq!(message.opcode, ControlOpcode::ReadStatus);
+        assert_eq!(message.association_id, 3);
+        assert!(message.requested_variables().is_empty());
+
+        assert!(ControlMessage::is_control(&packet));
+        assert!(!ControlMessage::is_control(&[0x23]));
+    }
+
+    #[test]
+    fn test_fragments() {
+        let packet = [0x26, 0x02, 0, 9, 0, 0, 0, 1, 0, 0, 0, 0];
+        let request = ControlMessage::deserialize(&packet).unwrap();
+
+        let response = ControlResponse::new(&request, 0x1234, vec![b'a'; 500]);
+        let fragments = response.serialize_fragments();
+        assert_eq!(fragments.len(), 2);
+
+        assert_eq!(fragments[0].len(), 480);
+        assert_eq!(
+            &fragments[0][..12],
+            &[0x26, 0xa2, 0, 9, 0x12, 0x34, 0, 1, 0, 0, 0x01, 0xd4]
+        );
+
+        assert_eq!(fragments[1].len(), 12 + 32);
+        assert_eq!(
+            &fragments[1][..12],
+            &[0x26, 0x82, 0, 9, 0x12, 0x34, 0, 1, 0x01, 0xd4, 0, 32]
+        );
+
+        let error = ControlResponse::error(&request, ControlError::BadAssociation);
+        let fragments = error.serialize_fragments();
+        assert_eq!(
+            fragments,
+            vec![vec![0x26, 0xc2, 0, 9, 4, 0, 0, 1, 0, 0, 0, 0]]
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut variables = ControlVariables::new();
+        variables.push("stratum", 2);
+        variables.push_quoted("version", "ntpd-rs");
+        variables.push("offset", millis(-0.0012345));
+
+        assert_eq!(
+            variables.encode(&[]),
+            b"stratum=2, version=\"ntpd-rs\", offset=-1.234\r\n"
+        );
+        assert_eq!(variables.encode(&["offset", "foo"]), b"offset=-1.234\r\n");
+        assert_eq!(variables.encode(&["foo"]), b"");
+
+        let mut variables = ControlVariables::new();
+        for _ in 0..10 {
+            variables.push("dispersion", "1000.000");
+        }
+        let encoded = String::from_utf8(variables.encode(&[])).unwrap();
+        assert!(encoded
+            .lines()
+            .all(|line| line.len() <= MAX_LINE_LENGTH + 1));
+        assert_eq!(encoded.matches("dispersion").count(), 10);
+    }
+
+    #[test]
+    fn test_status_words() {
+        assert_eq!(system_status(NtpLeapIndicator::NoWarning), 0x0600);
+        assert_eq!(system_status(NtpLeapIndicator::Unknown), 0xc000);
+
+        assert_eq!(peer_status(true, PeerSelection::SystemPeer), 0x9600);
+        assert_eq!(peer_status(false, PeerSelection::Rejected), 0x8000);
+    }
+
+    #[test]
+    fn test_reference_id() {
+        assert_eq!(format_reference_id(ReferenceId::GPS, 1), "GPS");
+        assert_eq!(
+            format_reference_id(ReferenceId::from_ip("192.0.2.1".parse().unwrap()), 2),
+            "192.0.2.1"
+        );
+    }
+
+    #[test]
+    fn test_peer_variables() {
+        let snapshot = peer_snapshot(
+            PeerStatistics {
+                offset: NtpDuration::from_seconds(0.0015),
+                delay: NtpDuration::from_seconds(0.02),
+                dispersion: NtpDuration::from_seconds(0.001),
+                jitter: 0.0002,
+            },
+            NtpInstant::now(),
+            NtpDuration::ZERO,
+            NtpDuration::ZERO,
+        );
+
+        let encoded = ControlVariables::for_peer(&snapshot).encode(&["offset", "delay", "jitter"]);
+        assert_eq!(encoded, b"offset=1.500, delay=20.000, jitter=0.200\r\n");
+    }
+}
@@ -11,8 +11,15 @@ use crate::packet::NtpAssociationMode;
 use crate::peer::PeerStatistics;
 use crate::time_types::{FrequencyTolerance, NtpInstant};
 use crate::{packet::NtpLeapIndicator, NtpDuration, NtpPacket, NtpTimestamp};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use tracing::{debug, instrument, warn};
 
+/// Number of valid samples from which on the regular clock filter statistics
+/// are used. With fewer samples, the dummies in the register dominate the
+/// dispersion, so a quick-start estimate is used instead.
+const QUICK_START_SAMPLES: usize = 4;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct FilterTuple {
     offset: NtpDuration,
@@ -31,6 +38,14 @@ impl FilterTuple {
         }
     }
 
+    pub(crate) fn offset(&self) -> NtpDuration {
+        self.offset
+    }
+
+    pub(crate) fn delay(&self) -> NtpDuration {
+        self.delay
+    }
+
     fn is_dummy(self) -> bool {
         self.offset == NtpDuration::ZERO
             && self.delay == NtpDuration::MAX_DISPERSION
@@ -83,7 +98,10 @@ impl FilterTuple {
         // delay is clamped to ensure it is always positive
         let delay = Ord::max(system_precision, delta1 - delta2);
 
-        let dispersion = packet_precision + system_precision + (delta1 * frequency_tolerance);
+        // the precision of the packet is chosen by the server, and can be anything
+        let dispersion = packet_precision
+            .saturating_add(system_precision)
+            .saturating_add(delta1 * frequency_tolerance);
 
         Self {
             offset,
@@ -92,31 +110,177 @@ impl FilterTuple {
             time: local_clock_time,
         }
     }
+
+    /// Tuple for a sample taken from a local reference clock.
+    ///
+    /// There is no network path involved, so the delay is bounded from below
+    /// by the system precision only, and the dispersion is made up of the
+    /// precisions of both clocks.
+    pub(crate) fn from_refclock_sample(
+        offset: NtpDuration,
+        refclock_precision: NtpDuration,
+        system_precision: NtpDuration,
+        local_clock_time: NtpInstant,
+    ) -> Self {
+        Self {
+            offset,
+            delay: system_precision,
+            dispersion: refclock_precision + system_precision,
+            time: local_clock_time,
+        }
+    }
+}
+
+/// A measurement in the clock filter, as kept across restarts of the daemon.
+/// Instead of the monotonic time of the measurement, which means nothing to
+/// another process, this holds its age at the time it was saved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SavedMeasurement {
+    pub offset: NtpDuration,
+    pub delay: NtpDuration,
+    pub dispersion: NtpDuration,
+    /// Seconds between the measurement and saving it
+    pub age: f64,
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct LastMeasurements {
+    /// The last eight tuples, from new to old
     register: [FilterTuple; 8],
+    /// Indices into `register`, ordered by increasing delay. Tuples with the
+    /// same delay are ordered from new to old, like a stable sort of the
+    /// register would order them.
+    ///
+    /// Only a single tuple is added per sample, so this is kept up to date
+    /// incrementally rather than sorting the whole register every time.
+    by_delay: [usize; 8],
 }
 
 impl LastMeasurements {
     pub const fn new(instant: NtpInstant) -> Self {
         Self {
             register: [FilterTuple::dummy(instant); 8],
+            by_delay: [0, 1, 2, 3, 4, 5, 6, 7],
         }
     }
 
+    /// The smallest delay among the valid measurements
+    pub(crate) fn min_delay(&self) -> Option<NtpDuration> {
+        self.by_delay
+            .iter()
+            .map(|index| self.register[*index])
+            .find(|tuple| !tuple.is_dummy())
+            .map(|tuple| tuple.delay)
+    }
+
+    /// The valid measurements, from new to old
+    pub(crate) fn save(&self, now: NtpInstant) -> Vec<SavedMeasurement> {
+        self.register
+            .iter()
+            .filter(|tuple| !tuple.is_dummy())
+            .map(|tuple| SavedMeasurement {
+                offset: tuple.offset,
+                delay: tuple.delay,
+                dispersion: tuple.dispersion,
+                age: NtpInstant::abs_diff(now, tuple.time).to_seconds(),
+            })
+            .collect()
+    }
+
+    /// The register with saved measurements, which were saved `downtime`
+    /// before `now`. Measurements from before the start of the monotonic
+    /// clock, e.g. from before a reboot, can not be placed and are left out.
+    pub(crate) fn restore(
+        saved: &[SavedMeasurement],
+        now: NtpInstant,
+        downtime: std::time::Duration,
+    ) -> Self {
+        let mut measurements = Self::new(now);
+
+        let tuples = saved.iter().filter_map(|saved| {
+            // negative, infinite and NaN ages can not be placed either
+            if !(0.0..u64::MAX as f64).contains(&saved.age) {
+                return None;
+            }
+            let age = std::time::Duration::from_secs_f64(saved.age);
+            Some(FilterTuple {
+                offset: saved.offset,
+                delay: saved.delay,
+                dispersion: Ord::min(saved.dispersion, NtpDuration::MAX_DISPERSION),
+                time: now.checked_sub(downtime.checked_add(age)?)?,
+            })
+        });
+        for (target, tuple) in measurements.register.iter_mut().zip(tuples) {
+            *target = tuple;
+        }
+
+        measurements.sort_by_delay();
+        measurements
+    }
+
     /// Insert the new tuple at index 0, move all other tuples one to the right.
     /// The final (oldest) tuple is discarded
     fn shift_and_insert(&mut self, mut current: FilterTuple, dispersion_correction: NtpDuration) {
+        let delay = current.delay;
+
         for tuple in self.register.iter_mut() {
             // adding the dispersion correction would make the dummy no longer a dummy
             if !tuple.is_dummy() {
-                tuple.dispersion += dispersion_correction;
+                // after a long gap, aging is bounded like everywhere else in the spec
+                tuple.dispersion = Ord::min(
+                    tuple.dispersion.saturating_add(dispersion_correction),
+                    NtpDuration::MAX_DISPERSION,
+                );
             }
 
             std::mem::swap(&mut current, tuple);
         }
+
+        // the oldest tuple is gone, and all others moved one place
+        let oldest = self.register.len() - 1;
+        let mut by_delay = [0; 8];
+        let remaining = self.by_delay.iter().filter(|&&index| index != oldest);
+        for (target, index) in by_delay.iter_mut().zip(remaining) {
+            *target = index + 1;
+        }
+
+        // the new tuple goes before all older tuples with the same delay
+        let position = by_delay[..oldest]
+            .iter()
+            .position(|&index| self.register[index].delay >= delay)
+            .unwrap_or(oldest);
+        by_delay.copy_within(position..oldest, position + 1);
+        by_delay[position] = 0;
+
+        self.by_delay = by_delay;
+    }
+
+    /// Order the whole register by delay, which is only needed when tuples
+    /// other than the newest change their delay
+    fn sort_by_delay(&mut self) {
+        let register = &self.register;
+        // the index breaks ties, like a stable sort would
+        self.by_delay
+            .sort_unstable_by_key(|&index| (register[index].delay, index));
+    }
+
+    /// Replace all samples taken more than `max_age` before `local_clock_time`
+    /// by dummies, such that a source that was silent for a long time does
+    /// not come back with stale offsets.
+    pub(crate) fn expire(&mut self, local_clock_time: NtpInstant, max_age: NtpDuration) {
+        let mut expired = false;
+        for tuple in self.register.iter_mut() {
+            if !tuple.is_dummy() && NtpInstant::abs_diff(local_clock_time, tuple.time) > max_age {
+                debug!(time = debug(tuple.time), "Expired filter sample");
+                *tuple = FilterTuple::dummy(tuple.time);
+                expired = true;
+            }
+        }
+
+        if expired {
+            self.sort_by_delay();
+        }
     }
 
     #[instrument(level = "trace")]
@@ -128,7 +292,9 @@ impl LastMeasurements {
         system_precision: NtpDuration,
         frequency_tolerance: FrequencyTolerance,
     ) -> Option<(PeerStatistics, NtpInstant)> {
-        // correction depends on time passed since last register update!, not peer_time
+        // correction depends on time passed since last register update!, not peer_time.
+        // This is the actual (monotonic) interval between samples, so it covers missed
+        // polls, and is not affected by steps of the system clock.
         let dispersion_correction =
             NtpInstant::abs_diff(new_tuple.time, self.register[0].time) * frequency_tolerance;
         self.shift_and_insert(new_tuple, dispersion_correction);
@@ -149,17 +315,15 @@ impl LastMeasurements {
             return None;
         }
 
-        let offset = smallest_delay.offset;
-        let delay = smallest_delay.delay;
-
-        let dispersion = temporary_list.dispersion();
-        let jitter = temporary_list.jitter(smallest_delay, system_precision);
-
-        let statistics = PeerStatistics {
-            offset,
-            delay,
-            dispersion,
-            jitter,
+        let statistics = if temporary_list.valid_tuples().count() < QUICK_START_SAMPLES {
+            temporary_list.quick_start(smallest_delay, system_precision)?
+        } else {
+            PeerStatistics {
+                offset: smallest_delay.offset,
+                delay: smallest_delay.delay,
+                dispersion: temporary_list.dispersion(),
+                jitter: temporary_list.jitter(smallest_delay, system_precision),
+            }
         };
 
         debug!(
@@ -172,43 +336,38 @@ impl LastMeasurements {
 }
 
 /// Temporary list
-#[derive(Debug, Clone)]
-struct TemporaryList {
-    /// Invariant: this array is always sorted by increasing delay!
-    register: [FilterTuple; 8],
+///
+/// A view of the clock filter register, ordered by increasing delay
+#[derive(Debug, Clone, Copy)]
+struct TemporaryList<'a> {
+    register: &'a [FilterTuple; 8],
+    /// Invariant: the tuples at these indices have increasing delay!
+    order: &'a [usize; 8],
 }
 
-impl TemporaryList {
-    fn from_clock_filter_contents(source: &LastMeasurements) -> Self {
-        // copy the registers
-        let mut register = source.register;
-
-        // sort by delay, ignoring NaN
-        register.sort_by(|t1, t2| {
-            t1.delay
-                .partial_cmp(&t2.delay)
-                .unwrap_or(std::cmp::Ordering::Less)
-        });
+impl<'a> TemporaryList<'a> {
+    fn from_clock_filter_contents(source: &'a LastMeasurements) -> Self {
+        Self {
+            register: &source.register,
+            order: &source.by_delay,
+        }
+    }
 
-        Self { register }
+    fn tuples(self) -> impl DoubleEndedIterator<Item = &'a FilterTuple> + Clone {
+        self.order.iter().map(move |&index| &self.register[index])
     }
 
     fn smallest_delay(&self) -> &FilterTuple {
-        &self.register[0]
+        &self.register[self.order[0]]
     }
 
     /// Prefix of the temporary list containing only the valid tuples
-    fn valid_tuples(&self) -> &[FilterTuple] {
-        let num_invalid_tuples = self
-            .register
-            .iter()
-            .rev()
-            .take_while(|t| t.is_dummy())
-            .count();
+    fn valid_tuples(self) -> impl Iterator<Item = &'a FilterTuple> + Clone {
+        let num_invalid_tuples = self.tuples().rev().take_while(|t| t.is_dummy()).count();
 
-        let num_valid_tuples = self.register.len() - num_invalid_tuples;
+        let num_valid_tuples = self.order.len() - num_invalid_tuples;
 
-        &self.register[..num_valid_tuples]
+        self.tuples().take(num_valid_tuples)
     }
 
     /// #[no_run]
@@ -220,8 +379,7 @@ impl TemporaryList {
     ///                     i=0
     /// Invariant: the register is sorted wrt delay
     fn dispersion(&self) -> NtpDuration {
-        self.register
-            .iter()
+        self.tuples()
             .enumerate()
             .map(|(i, t)| t.dispersion / 2i64.pow(i as u32 + 1))
             .fold(NtpDuration::default(), |a, b| a + b)
@@ -243,18 +401,18 @@ impl TemporaryList {
     }
 
     fn jitter_help(
-        valid_tuples: &[FilterTuple],
+        valid_tuples: impl Iterator<Item = &'a FilterTuple> + Clone,
         smallest_delay: FilterTuple,
         system_precision: NtpDuration,
     ) -> f64 {
         let root_mean_square = valid_tuples
-            .iter()
+            .clone()
             .map(|t| (t.offset - smallest_delay.offset).to_seconds().powi(2))
             .sum::<f64>()
             .sqrt();
 
         // root mean square average (RMS average). - 1 to exclude the smallest_delay
-        let jitter = root_mean_square / (valid_tuples.len() - 1) as f64;
+        let jitter = root_mean_square / (valid_tuples.count() - 1) as f64;
 
         // In order to ensure consistency and avoid divide exceptions in other
         // computations, the psi is bounded from below by the system precision
@@ -262,11 +420,68 @@ impl TemporaryList {
         f64::max(jitter, system_precision.to_seconds())
     }
 
-    #[cfg(test)]
-    const fn new(instant: NtpInstant) -> Self {
-        Self {
-            register: [FilterTuple::dummy(instant); 8],
+    /// Degraded-mode estimate for when fewer than [`QUICK_START_SAMPLES`]
+    /// valid samples are available.
+    ///
+    /// - the offset is the median of the offsets of the valid samples
+    /// - the delay is the smallest delay, like in the regular filter
+    /// - the dispersion is the mean dispersion of the valid samples
+    /// - the jitter is the RMS deviation of the offsets from the median, but
+    ///   at least half the delay (the bound on the error of a single sample)
+    ///
+    /// Both the dispersion and the jitter are then multiplied by
+    /// `QUICK_START_SAMPLES / n` for `n` valid samples, so the estimate is
+    /// trusted less the fewer samples it is based on. Without any valid
+    /// samples, there is no estimate.
+    ///
+    /// Invariant: the register is sorted wrt delay
+    fn quick_start(
+        &self,
+        smallest_delay: FilterTuple,
+        system_precision: NtpDuration,
+    ) -> Option<PeerStatistics> {
+        let valid_tuples = self.valid_tuples();
+
+        let mut buffer = [NtpDuration::ZERO; 8];
+        let mut n = 0;
+        for tuple in valid_tuples.clone() {
+            buffer[n] = tuple.offset;
+            n += 1;
         }
+        if n == 0 {
+            return None;
+        }
+
+        let offsets = &mut buffer[..n];
+        offsets.sort_unstable();
+        // for an odd number of samples, both indices point at the middle one
+        let offset = (offsets[(n - 1) / 2] + offsets[n / 2]) / 2i64;
+
+        let dispersion = valid_tuples
+            .clone()
+            .map(|t| t.dispersion)
+            .fold(NtpDuration::ZERO, NtpDuration::saturating_add)
+            / n as i64;
+
+        let root_mean_square = (valid_tuples
+            .map(|t| (t.offset - offset).to_seconds().powi(2))
+            .sum::<f64>()
+            / n as f64)
+            .sqrt();
+        let jitter = root_mean_square
+            .max(smallest_delay.delay.to_seconds() / 2.0)
+            .max(system_precision.to_seconds());
+
+        let inflation = QUICK_START_SAMPLES as i64;
+        Some(PeerStatistics {
+            offset,
+            delay: smallest_delay.delay,
+            dispersion: Ord::min(
+                dispersion * inflation / n as i64,
+                NtpDuration::MAX_DISPERSION,
+            ),
+            jitter: jitter * inflation as f64 / n as f64,
+        })
     }
 }
 
@@ -300,6 +515,54 @@ pub fn fuzz_tuple_from_packet_default(
     assert!(result.dispersion >= NtpDuration::from_fixed_int(0));
 }
 
+/// The clock filter of a single peer, for benchmarks, which receives a sample
+/// every second
+#[cfg(feature = "bench")]
+pub struct BenchClockFilter {
+    measurements: LastMeasurements,
+    time: NtpInstant,
+}
+
+#[cfg(feature = "bench")]
+impl BenchClockFilter {
+    pub fn new() -> Self {
+        let time = NtpInstant::now();
+        Self {
+            measurements: LastMeasurements::new(time),
+            time,
+        }
+    }
+
+    pub fn step(&mut self, offset: NtpDuration, delay: NtpDuration) -> Option<PeerStatistics> {
+        let peer_time = self.time;
+        self.time = self.time + std::time::Duration::from_secs(1);
+        let tuple = FilterTuple {
+            offset,
+            delay,
+            dispersion: NtpDuration::from_exponent(-20),
+            time: self.time,
+        };
+
+        // while unsynchronized, every sample updates the statistics
+        self.measurements
+            .step(
+                tuple,
+                peer_time,
+                NtpLeapIndicator::Unknown,
+                NtpDuration::from_exponent(-18),
+                FrequencyTolerance::ppm(15),
+            )
+            .map(|(statistics, _)| statistics)
+    }
+}
+
+#[cfg(feature = "bench")]
+impl Default for BenchClockFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -309,8 +572,10 @@ mod test {
         // The observer should note (a) if all stages contain the dummy tuple
         // with dispersion MAXDISP, the computed dispersion is a little less than 16 s
 
-        let register = TemporaryList::new(NtpInstant::now());
-        let value = register.dispersion().to_seconds();
+        let register = LastMeasurements::new(NtpInstant::now());
+        let value = TemporaryList::from_clock_filter_contents(&register)
+            .dispersion()
+            .to_seconds();
 
         assert!((16.0 - value) < 0.1)
     }
@@ -318,7 +583,13 @@ mod test {
     #[test]
     fn dummys_are_not_valid() {
         let instant = NtpInstant::now();
-        assert!(TemporaryList::new(instant).valid_tuples().is_empty())
+        let register = LastMeasurements::new(instant);
+        assert_eq!(
+            TemporaryList::from_clock_filter_contents(&register)
+                .valid_tuples()
+                .count(),
+            0
+        )
     }
 
     #[test]
@@ -334,11 +605,12 @@ mod test {
 
     #[test]
     fn jitter_of_pair() {
-        let mut register = TemporaryList::new(NtpInstant::now());
+        let mut register = LastMeasurements::new(NtpInstant::now());
         register.register[0].offset = NtpDuration::from_seconds(20.0);
         register.register[1].offset = NtpDuration::from_seconds(30.0);
         let first = register.register[0];
-        let value = register.jitter(first, NtpDuration::ZERO);
+        let value =
+            TemporaryList::from_clock_filter_contents(&register).jitter(first, NtpDuration::ZERO);
 
         // jitter is calculated relative to the first tuple
         assert!((value - 10.0).abs() < 1e-6)
@@ -346,17 +618,69 @@ mod test {
 
     #[test]
     fn jitter_of_triple() {
-        let mut register = TemporaryList::new(NtpInstant::now());
+        let mut register = LastMeasurements::new(NtpInstant::now());
         register.register[0].offset = NtpDuration::from_seconds(20.0);
         register.register[1].offset = NtpDuration::from_seconds(20.0);
         register.register[2].offset = NtpDuration::from_seconds(30.0);
         let first = register.register[0];
-        let value = register.jitter(first, NtpDuration::ZERO);
+        let value =
+            TemporaryList::from_clock_filter_contents(&register).jitter(first, NtpDuration::ZERO);
 
         // jitter is calculated relative to the first tuple
         assert!((value - 5.0).abs() < 1e-6)
     }
 
+    #[test]
+    fn save_and_restore() {
+        let base = NtpInstant::now();
+        let tuple = |seconds, delay| FilterTuple {
+            offset: NtpDuration::from_seconds(0.001),
+            delay: NtpDuration::from_seconds(delay),
+            dispersion: NtpDuration::from_seconds(0.0001),
+            time: base + std::time::Duration::from_secs(seconds),
+        };
+
+        let mut register = LastMeasurements::new(base);
+        register.shift_and_insert(tuple(10, 0.02), NtpDuration::ZERO);
+        register.shift_and_insert(tuple(20, 0.01), NtpDuration::ZERO);
+
+        let saved_at = base + std::time::Duration::from_secs(100);
+        let saved = register.save(saved_at);
+        assert_eq!(saved.len(), 2);
+        assert!((saved[0].age - 80.0).abs() < 1e-6);
+
+        // restarted 5 seconds later
+        let downtime = std::time::Duration::from_secs(5);
+        let restored = LastMeasurements::restore(&saved, saved_at + downtime, downtime);
+        let list = TemporaryList::from_clock_filter_contents(&restored);
+        assert_eq!(list.valid_tuples().count(), 2);
+        assert_eq!(list.smallest_delay().delay, NtpDuration::from_seconds(0.01));
+        // the measurements aged by the downtime
+        let resaved = restored.save(saved_at + downtime);
+        assert!((resaved[0].age - 85.0).abs() < 1e-6);
+
+        // measurements from before the monotonic clock started are left out
+        let lost = LastMeasurements::restore(&saved, base, std::time::Duration::MAX);
+        assert_eq!(
+            TemporaryList::from_clock_filter_contents(&lost)
+                .valid_tuples()
+                .count(),
+            0
+        );
+
+        // as are measurements with an age that is not a duration
+        let mut corrupt = saved.clone();
+        corrupt[0].age = -1.0;
+        corrupt[1].age = f64::NAN;
+        let restored = LastMeasurements::restore(&corrupt, saved_at + downtime, downtime);
+        assert_eq!(
+            TemporaryList::from_clock_filter_contents(&restored)
+                .valid_tuples()
+                .count(),
+            0
+        );
+    }
+
     #[test]
     fn clock_filter_defaults() {
         let instant = NtpInstant::now();
@@ -417,13 +741,14 @@ mod test {
 
         peer_time = new_time;
 
-        // there is just one valid sample
-        assert_eq!(statistics.jitter, 0.0);
+        // there is just one valid sample, so its error is only bounded by the delay
+        assert!((statistics.jitter - 4.0 * 0.05 / 2.0).abs() < 1e-9);
+        assert!(statistics.dispersion < NtpDuration::from_seconds(0.001));
 
         let temporary = TemporaryList::from_clock_filter_contents(&measurements);
 
-        assert_eq!(temporary.register[0], new_tuple);
-        assert_eq!(temporary.valid_tuples(), &[new_tuple]);
+        assert_eq!(temporary.smallest_delay(), &new_tuple);
+        assert!(temporary.valid_tuples().eq(&[new_tuple]));
 
         let new_tuple = FilterTuple {
             offset: NtpDuration::from_seconds(0.09),
@@ -466,6 +791,67 @@ mod test {
         assert!(update.is_none());
     }
 
+    #[test]
+    fn quick_start_estimate() {
+        let base = NtpInstant::now();
+        let mut measurements = LastMeasurements::new(base);
+        let mut peer_time = base;
+
+        let tuple = |offset: f64, delay: f64, secs: u64| FilterTuple {
+            offset: NtpDuration::from_seconds(offset),
+            delay: NtpDuration::from_seconds(delay),
+            dispersion: NtpDuration::from_seconds(0.001),
+            time: base + std::time::Duration::from_secs(secs),
+        };
+
+        // decreasing delays, so every sample results in an update
+        let samples = [
+            tuple(0.5, 0.04, 1),
+            tuple(0.1, 0.03, 2),
+            tuple(0.2, 0.02, 3),
+            tuple(0.3, 0.01, 4),
+        ];
+        let mut updates = vec![];
+        for sample in samples {
+            let (statistics, time) = measurements
+                .step(
+                    sample,
+                    peer_time,
+                    NtpLeapIndicator::NoWarning,
+                    NtpDuration::ZERO,
+                    FrequencyTolerance::ppm(0),
+                )
+                .unwrap();
+            peer_time = time;
+            updates.push(statistics);
+        }
+
+        // median of the available samples
+        assert_eq!(updates[0].offset, NtpDuration::from_seconds(0.5));
+        assert_eq!(updates[1].offset, NtpDuration::from_seconds(0.3));
+        assert_eq!(updates[2].offset, NtpDuration::from_seconds(0.2));
+
+        // the uncertainty is inflated, and shrinks as samples come in
+        let dispersion = NtpDuration::from_seconds(0.001).to_seconds();
+        assert!((updates[0].dispersion.to_seconds() - 4.0 * dispersion).abs() < 1e-6);
+        assert!((updates[1].dispersion.to_seconds() - 2.0 * dispersion).abs() < 1e-6);
+        assert!((updates[0].jitter - 4.0 * 0.02).abs() < 1e-9);
+        assert!((updates[1].jitter - 2.0 * 0.2).abs() < 1e-9);
+        assert!(updates[2].jitter < updates[1].jitter);
+
+        // with enough samples, the regular filter takes over
+        assert_eq!(updates[3].offset, NtpDuration::from_seconds(0.3));
+        assert!(updates[3].dispersion > NtpDuration::from_seconds(0.5));
+
+        // without any valid samples, there is no estimate
+        let empty = LastMeasurements::new(base);
+        let temporary = TemporaryList::from_clock_filter_contents(&empty);
+        let smallest_delay = *temporary.smallest_delay();
+        assert!(temporary
+            .quick_start(smallest_delay, NtpDuration::ZERO)
+            .is_none());
+    }
+
     #[test]
     fn test_tuple_from_packet_default() {
         let instant = NtpInstant::now();
@@ -558,4 +944,335 @@ mod test {
 
         assert!((filter.register[1].dispersion.to_seconds() - 15e-3) < 1e-6);
     }
+
+    #[test]
+    fn dispersion_aging_long_gap() {
+        let base = NtpInstant::now();
+        let mut filter = LastMeasurements::new(base);
+
+        let tuple = |seconds| FilterTuple {
+            offset: Default::default(),
+            delay: Default::default(),
+            dispersion: Default::default(),
+            time: base + std::time::Duration::from_secs(seconds),
+        };
+
+        // a sample, followed by 8 missed polls of 1024 seconds
+        for (time, tolerance) in [(16, 15), (16 + 8 * 1024, 15), (86400, 100)] {
+            filter.step(
+                tuple(time),
+                base,
+                NtpLeapIndicator::NoWarning,
+                NtpDuration::from_exponent(-32),
+                FrequencyTolerance::ppm(tolerance),
+            );
+        }
+
+        // aged over the full interval, not just a single poll
+        let aged = filter.register[2].dispersion.to_seconds();
+        assert!(
+            (aged - 8.0 * 1024.0 * 15e-6 - (86400.0 - 16.0 - 8.0 * 1024.0) * 100e-6).abs() < 1e-6
+        );
+
+        // but never beyond the maximum dispersion
+        filter.step(
+            tuple(30 * 86400),
+            base,
+            NtpLeapIndicator::NoWarning,
+            NtpDuration::from_exponent(-32),
+            FrequencyTolerance::ppm(15),
+        );
+        assert_eq!(filter.register[1].dispersion, NtpDuration::MAX_DISPERSION);
+        assert!(!filter.register[1].is_dummy());
+    }
+
+    #[test]
+    fn dispersion_aging_across_clock_step() {
+        let base = NtpInstant::now();
+        let mut filter = LastMeasurements::new(base);
+
+        let mut packet = NtpPacket::test();
+        let mut sample = |local: u64, instant| {
+            // server and client agree, up to the step of the local clock
+            let timestamp = NtpTimestamp::from_fixed_int((10000 + instant - 1) << 32);
+            packet.set_receive_timestamp(timestamp);
+            packet.set_transmit_timestamp(timestamp);
+            let local = NtpTimestamp::from_fixed_int(local << 32);
+
+            FilterTuple::from_packet_default(
+                &packet,
+                NtpDuration::ZERO,
+                base + std::time::Duration::from_secs(instant),
+                FrequencyTolerance::ppm(15),
+                local,
+                local,
+            )
+        };
+
+        let first = sample(10000, 1);
+        // the local clock is stepped back an hour between the two samples
+        let second = sample(10016 - 3600, 17);
+
+        for tuple in [first, second] {
+            filter.step(
+                tuple,
+                base,
+                NtpLeapIndicator::NoWarning,
+                NtpDuration::ZERO,
+                FrequencyTolerance::ppm(15),
+            );
+        }
+
+        // the step shows up in the offset, but aging only sees the 16 seconds
+        // of monotonic time between the samples
+        assert_eq!(filter.register[0].offset, NtpDuration::from_seconds(3600.0));
+        let aged = filter.register[1].dispersion - first.dispersion;
+        assert!((aged.to_seconds() - 16.0 * 15e-6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn expire_old_samples() {
+        let base = NtpInstant::now();
+        let mut filter = LastMeasurements::new(base);
+
+        let tuple = |seconds, offset| FilterTuple {
+            offset: NtpDuration::from_seconds(offset),
+            delay: NtpDuration::from_seconds(0.01),
+            dispersion: Default::default(),
+            time: base + std::time::Duration::from_secs(seconds),
+        };
+
+        for (seconds, offset) in [(1, 0.5), (17, 0.5), (4 * 3600, 0.001)] {
+            filter.expire(
+                base + std::time::Duration::from_secs(seconds),
+                NtpDuration::from_seconds(3600.0),
+            );
+            filter.step(
+                tuple(seconds, offset),
+                base,
+                NtpLeapIndicator::NoWarning,
+                NtpDuration::ZERO,
+                FrequencyTolerance::ppm(15),
+            );
+        }
+
+        // only the latest sample is left
+        let temporary = TemporaryList::from_clock_filter_contents(&filter);
+        assert!(temporary.valid_tuples().eq(&[tuple(4 * 3600, 0.001)]));
+
+        // recent samples are kept
+        filter.expire(
+            base + std::time::Duration::from_secs(4 * 3600 + 16),
+            NtpDuration::from_seconds(3600.0),
+        );
+        let temporary = TemporaryList::from_clock_filter_contents(&filter);
+        assert_eq!(temporary.valid_tuples().count(), 1);
+    }
+
+    /// Whether two outcomes of a step of the clock filter in the test vectors
+    /// differ by more than rounding
+    fn outcomes_differ(a: &serde_json::Value, b: &serde_json::Value) -> bool {
+        match (a.as_object(), b.as_object()) {
+            (Some(a), Some(b)) => a.iter().any(|(field, value)| {
+                (value.as_f64().unwrap() - b[field].as_f64().unwrap()).abs() > 1e-9
+            }),
+            (a, b) => a.is_none() != b.is_none(),
+        }
+    }
+
+    /// The vectors hold, for every sample, the outcome of `clock_filter()` of
+    /// the reference implementation, and ours where it differs. See
+    /// `testdata/filter/generate.py` for how they are made.
+    #[test]
+    fn reference_vectors() {
+        let vectors: serde_json::Value =
+            serde_json::from_str(include_str!("../testdata/filter/vectors.json")).unwrap();
+
+        for vector in vectors.as_array().unwrap() {
+            let precision = NtpDuration::from_exponent(vector["precision"].as_i64().unwrap() as i8);
+            let tolerance = FrequencyTolerance::ppm(vector["tolerance"].as_u64().unwrap() as u32);
+
+            let base = NtpInstant::now();
+            let mut measurements = LastMeasurements::new(base);
+            let mut peer_time = base;
+
+            for step in vector["steps"].as_array().unwrap() {
+                let context = format!("{} at {} s", vector["name"], step["time"]);
+                let seconds =
+                    |field: &str| NtpDuration::from_seconds(step[field].as_f64().unwrap());
+                let tuple = FilterTuple {
+                    offset: seconds("offset"),
+                    delay: seconds("delay"),
+                    dispersion: seconds("dispersion"),
+                    time: base + std::time::Duration::from_secs(step["time"].as_u64().unwrap()),
+                };
+
+                let reference = &step["ntpd"];
+                let expected = match step.get("ntpd-rs") {
+                    Some(expected) => {
+                        // differences are listed only where there are any
+                        assert!(outcomes_differ(expected, reference), "{context}");
+                        expected
+                    }
+                    None => reference,
+                };
+
+                let update = measurements.step(
+                    tuple,
+                    peer_time,
+                    NtpLeapIndicator::NoWarning,
+                    precision,
+                    tolerance,
+                );
+                let (statistics, time) = match update {
+                    Some(update) => update,
+                    None => {
+                        assert!(expected.is_null(), "{context}: no update");
+                        continue;
+                    }
+                };
+                assert!(!expected.is_null(), "{context}: unexpected update");
+                peer_time = time;
+
+                let actual = [
+                    ("offset", statistics.offset.to_seconds()),
+                    ("delay", statistics.delay.to_seconds()),
+                    ("dispersion", statistics.dispersion.to_seconds()),
+                    ("jitter", statistics.jitter),
+                ];
+                for (field, value) in actual {
+                    let expected = expected[field].as_f64().unwrap();
+                    assert!(
+                        (value - expected).abs() < 1e-8,
+                        "{context}: {field} is {value}, expected {expected}"
+                    );
+                }
+            }
+        }
+    }
+
+    /// The temporary list as it was built before the order by delay was kept
+    /// up to date incrementally: a sorted copy of the register
+    fn sorted_copy(source: &LastMeasurements) -> [FilterTuple; 8] {
+        let mut register = source.register;
+        register.sort_by(|t1, t2| {
+            t1.delay
+                .partial_cmp(&t2.delay)
+                .unwrap_or(std::cmp::Ordering::Less)
+        });
+        register
+    }
+
+    /// Check that the incrementally ordered temporary list is the same as a
+    /// sorted copy, and that all statistics computed from it are identical
+    fn assert_equivalent(measurements: &LastMeasurements) {
+        let sorted = sorted_copy(measurements);
+        let identity = [0, 1, 2, 3, 4, 5, 6, 7];
+        let reference = TemporaryList {
+            register: &sorted,
+            order: &identity,
+        };
+        let temporary = TemporaryList::from_clock_filter_contents(measurements);
+
+        assert!(temporary.tuples().eq(reference.tuples()));
+        assert!(temporary.valid_tuples().eq(reference.valid_tuples()));
+        assert_eq!(temporary.dispersion(), reference.dispersion());
+
+        let smallest_delay = *reference.smallest_delay();
+        let precision = NtpDuration::from_exponent(-18);
+        assert_eq!(temporary.smallest_delay(), &smallest_delay);
+        if temporary.valid_tuples().count() > 1 {
+            assert_eq!(
+                temporary.jitter(smallest_delay, precision).to_bits(),
+                reference.jitter(smallest_delay, precision).to_bits()
+            );
+        }
+        if temporary.valid_tuples().count() > 0 {
+            let estimate = temporary.quick_start(smallest_delay, precision).unwrap();
+            let expected = reference.quick_start(smallest_delay, precision).unwrap();
+            assert_eq!(estimate.offset, expected.offset);
+            assert_eq!(estimate.delay, expected.delay);
+            assert_eq!(estimate.dispersion, expected.dispersion);
+            assert_eq!(estimate.jitter.to_bits(), expected.jitter.to_bits());
+        }
+    }
+
+    #[test]
+    fn incremental_order_matches_sort() {
+        let base = NtpInstant::now();
+        let mut measurements = LastMeasurements::new(base);
+        assert_equivalent(&measurements);
+
+        // few distinct delays, so there are many ties to break
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for second in 1..200 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            let tuple = FilterTuple {
+                offset: NtpDuration::from_seconds((state % 1000) as f64 * 1e-6),
+                delay: NtpDuration::from_seconds((state >> 20) as f64 % 4.0 * 0.01),
+                dispersion: NtpDuration::from_seconds(1e-3),
+                time: base + std::time::Duration::from_secs(second),
+            };
+            measurements.step(
+                tuple,
+                base,
+                NtpLeapIndicator::Unknown,
+                NtpDuration::from_exponent(-18),
+                FrequencyTolerance::ppm(15),
+            );
+            assert_equivalent(&measurements);
+
+            // now and then, most of the samples expire
+            if second % 37 == 0 {
+                measurements.expire(
+                    base + std::time::Duration::from_secs(second + 3600),
+                    NtpDuration::from_seconds(3603.0),
+                );
+                assert_equivalent(&measurements);
+            }
+        }
+    }
+
+    #[test]
+    fn incremental_order_of_equal_delays() {
+        let base = NtpInstant::now();
+        let mut measurements = LastMeasurements::new(base);
+
+        let tuple = |seconds, delay| FilterTuple {
+            offset: NtpDuration::from_seconds(seconds as f64),
+            delay,
+            dispersion: Default::default(),
+            time: base + std::time::Duration::from_secs(seconds),
+        };
+
+        // a real sample with the delay of a dummy sorts before the dummies
+        let samples = [
+            tuple(1, NtpDuration::from_seconds(0.01)),
+            tuple(2, NtpDuration::from_seconds(0.01)),
+            tuple(3, NtpDuration::MAX_DISPERSION),
+            tuple(4, NtpDuration::from_seconds(0.005)),
+            tuple(5, NtpDuration::from_seconds(0.01)),
+        ];
+        for sample in samples {
+            measurements.step(
+                sample,
+                base,
+                NtpLeapIndicator::Unknown,
+                NtpDuration::ZERO,
+                FrequencyTolerance::ppm(15),
+            );
+            assert_equivalent(&measurements);
+        }
+
+        // ties are ordered from new to old
+        let temporary = TemporaryList::from_clock_filter_contents(&measurements);
+        let times: Vec<_> = temporary.valid_tuples().map(|t| t.time).collect();
+        assert_eq!(
+            times,
+            [4, 5, 2, 1, 3].map(|seconds| base + std::time::Duration::from_secs(seconds))
+        );
+    }
 }
@@ -1,9 +1,11 @@
 use std::net::IpAddr;
 
 use md5::{Digest, Md5};
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ReferenceId(u32);
 
 impl ReferenceId {
@@ -13,6 +15,12 @@ impl ReferenceId {
     pub const KISS_RSTR: ReferenceId = ReferenceId(u32::from_be_bytes(*b"RSTR"));
     pub const NONE: ReferenceId = ReferenceId(u32::from_be_bytes(*b"XNON"));
 
+    // Reference identifiers for stratum 1 servers, following the
+    // clock source codes listed in rfc5905 figure 12
+    pub const GPS: ReferenceId = ReferenceId(u32::from_be_bytes(*b"GPS\0"));
+    pub const PPS: ReferenceId = ReferenceId(u32::from_be_bytes(*b"PPS\0"));
+    pub const PTP: ReferenceId = ReferenceId(u32::from_be_bytes(*b"PTP\0"));
+
     pub fn from_ip(addr: IpAddr) -> ReferenceId {
         match addr {
             IpAddr::V4(addr) => ReferenceId(u32::from_be_bytes(addr.octets())),
@@ -38,11 +46,11 @@ impl ReferenceId {
         *self == Self::KISS_RSTR
     }
 
-    pub(crate) fn to_bytes(self) -> [u8; 4] {
+    pub fn to_bytes(self) -> [u8; 4] {
         self.0.to_be_bytes()
     }
 
-    pub(crate) fn from_bytes(bits: [u8; 4]) -> ReferenceId {
+    pub fn from_bytes(bits: [u8; 4]) -> ReferenceId {
         ReferenceId(u32::from_be_bytes(bits))
     }
 }
@@ -0,0 +1,102 @@
+//! The monotonic clock behind [`crate::NtpInstant`].
+//!
+//! On wasm32-unknown-unknown the standard library has no clock, and
+//! `std::time::Instant::now` panics. There the time is taken from the clock
+//! of the JavaScript host (`Date.now()`), which only has millisecond
+//! resolution and can be set back, so it is kept from going backwards.
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) use std::time::Instant;
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub(crate) use host::Instant;
+
+#[cfg(any(test, all(target_arch = "wasm32", target_os = "unknown")))]
+mod host {
+    use std::{
+        ops::{Add, AddAssign, Sub},
+        time::Duration,
+    };
+
+    /// Time since the unix epoch according to the host, which is never
+    /// earlier than a previous reading
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub(crate) struct Instant {
+        since_epoch: Duration,
+    }
+
+    impl Instant {
+        #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+        pub(crate) fn now() -> Self {
+            use std::sync::atomic::{AtomicU64, Ordering};
+
+            static LATEST_MILLIS: AtomicU64 = AtomicU64::new(0);
+
+            let millis = js_sys::Date::now().max(0.0) as u64;
+            let latest = LATEST_MILLIS.fetch_max(millis, Ordering::Relaxed);
+            Instant {
+                since_epoch: Duration::from_millis(latest.max(millis)),
+            }
+        }
+
+        #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+        pub(crate) fn elapsed(&self) -> Duration {
+            Self::now() - *self
+        }
+
+        pub(crate) fn checked_sub(&self, duration: Duration) -> Option<Self> {
+            Some(Instant {
+                since_epoch: self.since_epoch.checked_sub(duration)?,
+            })
+        }
+    }
+
+    /// Saturates at zero, like `std::time::Instant`
+    impl Sub for Instant {
+        type Output = Duration;
+
+        fn sub(self, rhs: Self) -> Duration {
+            self.since_epoch.saturating_sub(rhs.since_epoch)
+        }
+    }
+
+    impl Add<Duration> for Instant {
+        type Output = Instant;
+
+        fn add(self, rhs: Duration) -> Instant {
+            Instant {
+                since_epoch: self.since_epoch + rhs,
+            }
+        }
+    }
+
+    impl AddAssign<Duration> for Instant {
+        fn add_assign(&mut self, rhs: Duration) {
+            self.since_epoch += rhs;
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_arithmetic() {
+            let start = Instant {
+                since_epoch: Duration::from_secs(100),
+            };
+            let later = start + Duration::from_secs(5);
+
+            assert!(later > start);
+            assert_eq!(later - start, Duration::from_secs(5));
+            assert_eq!(start - later, Duration::ZERO);
+
+            let mut moved = start;
+            moved += Duration::from_secs(5);
+            assert_eq!(moved, later);
+
+            assert_eq!(later.checked_sub(Duration::from_secs(5)), Some(start));
+            assert_eq!(start.checked_sub(Duration::from_secs(101)), None);
+        }
+    }
+}
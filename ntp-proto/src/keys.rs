@@ -0,0 +1,461 @@
+//! Symmetric keys, with which packets carry a MAC that shows they come from
+//! a holder of the key.
+//!
+//! The MD5 and SHA-1 MACs are the digest of the key followed by the packet,
+//! as in RFC 5905 and the reference implementation, and are only there to
+//! work with older implementations. The AES-CMAC of RFC 8573 is the one to
+//! use otherwise.
+
+use std::{
+    fmt::{Debug, Display},
+    ops::Deref,
+    sync::Arc,
+};
+
+use aes::Aes128;
+use cmac::{Cmac, Mac as _};
+use md5::{Digest, Md5};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::{NtpDuration, NtpPacket, NtpTimestamp};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum KeyAlgorithm {
+    Md5,
+    Sha1,
+    #[cfg_attr(feature = "serde", serde(rename = "aes-128-cmac"))]
+    Aes128Cmac,
+}
+
+impl KeyAlgorithm {
+    /// Size in bytes of the keys that are generated for the algorithm
+    pub const fn key_size(self) -> usize {
+        match self {
+            KeyAlgorithm::Md5 => 16,
+            KeyAlgorithm::Sha1 => 20,
+            KeyAlgorithm::Aes128Cmac => 16,
+        }
+    }
+}
+
+impl Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyAlgorithm::Md5 => f.write_str("md5"),
+            KeyAlgorithm::Sha1 => f.write_str("sha1"),
+            KeyAlgorithm::Aes128Cmac => f.write_str("aes-128-cmac"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyError {
+    /// Key id 0 is reserved for the crypto-NAK of RFC 5905
+    ZeroId,
+    /// The key has a length the algorithm does not work with
+    InvalidLength {
+        algorithm: KeyAlgorithm,
+        length: usize,
+    },
+}
+
+impl Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ZeroId => f.write_str("Key id 0 is reserved"),
+            Self::InvalidLength { algorithm, length } => f.write_fmt(format_args!(
+                "Key of {} bytes can not be used with {}",
+                length, algorithm
+            )),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Why the MAC of a packet is not accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacError {
+    /// The packet has no MAC
+    Missing,
+    /// There is no key with this id that is valid now
+    UnknownKey(u32),
+    /// The MAC was not made with any of the valid keys with this id
+    Mismatch(u32),
+}
+
+impl Display for MacError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing => f.write_str("Packet has no MAC"),
+            Self::UnknownKey(id) => f.write_fmt(format_args!("No valid key with id {}", id)),
+            Self::Mismatch(id) => f.write_fmt(format_args!("MAC does not match key {}", id)),
+        }
+    }
+}
+
+impl std::error::Error for MacError {}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct SymmetricKey {
+    id: u32,
+    algorithm: KeyAlgorithm,
+    // shared, such that a key is cloned without allocating
+    key: Arc<[u8]>,
+    valid_from: Option<NtpTimestamp>,
+    valid_until: Option<NtpTimestamp>,
+}
+
+// the key itself is left out, as it should not end up in logs
+impl Debug for SymmetricKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SymmetricKey")
+            .field("id", &self.id)
+            .field("algorithm", &self.algorithm)
+            .field("valid_from", &self.valid_from)
+            .field("valid_until", &self.valid_until)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SymmetricKey {
+    /// A key that is valid at all times
+    pub fn new(id: u32, algorithm: KeyAlgorithm, key: Vec<u8>) -> Result<Self, KeyError> {
+        if id == 0 {
+            return Err(KeyError::ZeroId);
+        }
+
+        let valid_length = match algorithm {
+            KeyAlgorithm::Md5 | KeyAlgorithm::Sha1 => !key.is_empty(),
+            KeyAlgorithm::Aes128Cmac => key.len() == 16,
+        };
+        if !valid_length {
+            return Err(KeyError::InvalidLength {
+                algorithm,
+                length: key.len(),
+            });
+        }
+
+        Ok(SymmetricKey {
+            id,
+            algorithm,
+            key: key.into(),
+            valid_from: None,
+            valid_until: None,
+        })
+    }
+
+    /// Only use the key from `time` on
+    pub fn valid_from(mut self, time: Option<NtpTimestamp>) -> Self {
+        self.valid_from = time;
+        self
+    }
+
+    /// Only use the key until `time`
+    pub fn valid_until(mut self, time: Option<NtpTimestamp>) -> Self {
+        self.valid_until = time;
+        self
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        self.algorithm
+    }
+
+    pub fn is_valid_at(&self, time: NtpTimestamp) -> bool {
+        // timestamps are compared through their difference, which is also
+        // right across an era boundary
+        self.valid_from
+            .map(|from| time - from >= NtpDuration::ZERO)
+            .unwrap_or(true)
+            && self
+                .valid_until
+                .map(|until| until - time > NtpDuration::ZERO)
+                .unwrap_or(true)
+    }
+
+    pub(crate) fn mac(&self, data: &[u8]) -> MacBytes {
+        match self.algorithm {
+            KeyAlgorithm::Md5 => MacBytes::new(
+                &Md5::new()
+                    .chain_update(&self.key)
+                    .chain_update(data)
+                    .finalize(),
+            ),
+            KeyAlgorithm::Sha1 => MacBytes::new(
+                &Sha1::new()
+                    .chain_update(&self.key)
+                    .chain_update(data)
+                    .finalize(),
+            ),
+            KeyAlgorithm::Aes128Cmac => {
+                // the length is checked when the key is made
+                let mut mac = <Cmac<Aes128> as cmac::Mac>::new_from_slice(&self.key)
+                    .expect("AES-128 key of 16 bytes");
+                mac.update(data);
+                MacBytes::new(&mac.finalize().into_bytes())
+            }
+        }
+    }
+}
+
+/// A MAC, kept on the stack such that packets are signed and verified
+/// without allocating
+pub(crate) struct MacBytes {
+    bytes: [u8; MacBytes::MAXIMUM_SIZE],
+    len: usize,
+}
+
+impl MacBytes {
+    /// Size of a SHA-1 digest, the largest of the algorithms
+    const MAXIMUM_SIZE: usize = 20;
+
+    fn new(mac: &[u8]) -> Self {
+        let mut bytes = [0; Self::MAXIMUM_SIZE];
+        bytes[..mac.len()].copy_from_slice(mac);
+        MacBytes {
+            bytes,
+            len: mac.len(),
+        }
+    }
+}
+
+impl Deref for MacBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Comparison that takes as long for any pair of MACs of the same length,
+/// such that the timing does not tell how much of a forged MAC was right
+fn macs_equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The keys that are in use. Several keys can have the same id, with
+/// validity periods that follow each other, such that a key is replaced
+/// without changing the configuration that refers to its id.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeySet {
+    keys: Vec<SymmetricKey>,
+}
+
+impl KeySet {
+    pub fn new(keys: Vec<SymmetricKey>) -> Self {
+        KeySet { keys }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn keys(&self) -> &[SymmetricKey] {
+        &self.keys
+    }
+
+    /// The key to sign with: of the keys with `id` that are valid at `time`,
+    /// the one that became valid last
+    pub fn signing_key(&self, id: u32, time: NtpTimestamp) -> Option<&SymmetricKey> {
+        self.keys
+            .iter()
+            .filter(|key| key.id == id && key.is_valid_at(time))
+            .min_by_key(|key| {
+                key.valid_from
+                    .map(|from| time - from)
+                    .unwrap_or(NtpDuration::MAX)
+            })
+    }
+
+    /// Check the MAC of `packet`, which was received as `data`, and return
+    /// the key that made it. While a key is replaced, the old and the new key
+    /// are both valid, and either is accepted.
+    pub fn verify(
+        &self,
+        packet: &NtpPacket,
+        data: &[u8],
+        time: NtpTimestamp,
+    ) -> Result<&SymmetricKey, MacError> {
+        let (id, mac) = packet.mac().ok_or(MacError::Missing)?;
+        let signed = data
+            .len()
+            .checked_sub(4 + mac.len())
+            .and_then(|length| data.get(..length))
+            .ok_or(MacError::Mismatch(id))?;
+
+        let mut candidates = self
+            .keys
+            .iter()
+            .filter(|key| key.id == id && key.is_valid_at(time))
+            .peekable();
+        if candidates.peek().is_none() {
+            return Err(MacError::UnknownKey(id));
+        }
+
+        candidates
+            .find(|key| macs_equal(&key.mac(signed), mac))
+            .ok_or(MacError::Mismatch(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PollInterval;
+
+    use super::*;
+
+    fn time(seconds: u32) -> NtpTimestamp {
+        NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, 0)
+    }
+
+    #[test]
+    fn test_mac() {
+        // digests of "abc", split between key and data
+        let md5 = SymmetricKey::new(1, KeyAlgorithm::Md5, b"a".to_vec()).unwrap();
+        assert_eq!(
+            *md5.mac(b"bc"),
+            [
+                0x90, 0x01, 0x50, 0x98, 0x3c, 0xd2, 0x4f, 0xb0, 0xd6, 0x96, 0x3f, 0x7d, 0x28, 0xe1,
+                0x7f, 0x72
+            ]
+        );
+        let sha1 = SymmetricKey::new(1, KeyAlgorithm::Sha1, b"ab".to_vec()).unwrap();
+        assert_eq!(
+            *sha1.mac(b"c"),
+            [
+                0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+                0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d
+            ]
+        );
+
+        // RFC 4493, example 2
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        let cmac = SymmetricKey::new(1, KeyAlgorithm::Aes128Cmac, key.to_vec()).unwrap();
+        let message = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a,
+        ];
+        assert_eq!(
+            *cmac.mac(&message),
+            [
+                0x07, 0x0a, 0x16, 0xb4, 0x6b, 0x4d, 0x41, 0x44, 0xf7, 0x9b, 0xdd, 0x9d, 0xd0, 0x4a,
+                0x28, 0x7c
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        assert_eq!(
+            SymmetricKey::new(0, KeyAlgorithm::Md5, vec![1]),
+            Err(KeyError::ZeroId)
+        );
+        assert_eq!(
+            SymmetricKey::new(1, KeyAlgorithm::Aes128Cmac, vec![1; 20]),
+            Err(KeyError::InvalidLength {
+                algorithm: KeyAlgorithm::Aes128Cmac,
+                length: 20
+            })
+        );
+        assert!(SymmetricKey::new(1, KeyAlgorithm::Sha1, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = SymmetricKey::new(7, KeyAlgorithm::Aes128Cmac, vec![1; 16]).unwrap();
+        let keys = KeySet::new(vec![key.clone()]);
+
+        let (mut packet, _) = NtpPacket::poll_message(PollInterval::default());
+        packet.sign(&key);
+        assert_eq!(packet.key_id(), Some(7));
+
+        let mut data = vec![0; packet.serialized_len()];
+        packet.serialize_into(&mut data).unwrap();
+        assert_eq!(data.len(), 48 + 4 + 16);
+
+        let received = NtpPacket::deserialize(&data).unwrap();
+        assert_eq!(keys.verify(&received, &data, time(0)), Ok(&key));
+
+        let mut tampered = data.clone();
+        tampered[40] ^= 1;
+        let received = NtpPacket::deserialize(&tampered).unwrap();
+        assert_eq!(
+            keys.verify(&received, &tampered, time(0)),
+            Err(MacError::Mismatch(7))
+        );
+
+        let other = KeySet::new(vec![SymmetricKey::new(
+            8,
+            KeyAlgorithm::Aes128Cmac,
+            vec![1; 16],
+        )
+        .unwrap()]);
+        let received = NtpPacket::deserialize(&data).unwrap();
+        assert_eq!(
+            other.verify(&received, &data, time(0)),
+            Err(MacError::UnknownKey(7))
+        );
+
+        let (unsigned, _) = NtpPacket::poll_message(PollInterval::default());
+        assert_eq!(
+            keys.verify(&unsigned, &data, time(0)),
+            Err(MacError::Missing)
+        );
+
+        // signing while serializing gives the same packet
+        let mut buf = [0; 128];
+        assert_eq!(
+            unsigned.serialize_signed_into(&mut buf, &key),
+            Ok(data.len())
+        );
+        let received = NtpPacket::deserialize(&buf[..data.len()]).unwrap();
+        assert_eq!(
+            keys.verify(&received, &buf[..data.len()], time(0)),
+            Ok(&key)
+        );
+        assert!(matches!(
+            unsigned.serialize_signed_into(&mut buf[..60], &key),
+            Err(crate::PacketSerializationError::BufferTooSmall { needed: 68, .. })
+        ));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old = SymmetricKey::new(1, KeyAlgorithm::Aes128Cmac, vec![1; 16])
+            .unwrap()
+            .valid_until(Some(time(200)));
+        let new = SymmetricKey::new(1, KeyAlgorithm::Aes128Cmac, vec![2; 16])
+            .unwrap()
+            .valid_from(Some(time(100)));
+        let keys = KeySet::new(vec![old.clone(), new.clone()]);
+
+        assert_eq!(keys.signing_key(1, time(50)), Some(&old));
+        assert_eq!(keys.signing_key(1, time(150)), Some(&new));
+        assert_eq!(keys.signing_key(1, time(250)), Some(&new));
+        assert_eq!(keys.signing_key(2, time(150)), None);
+
+        // while both are valid, packets signed with the old key are accepted
+        let (mut packet, _) = NtpPacket::poll_message(PollInterval::default());
+        packet.sign(&old);
+        let mut data = vec![0; packet.serialized_len()];
+        packet.serialize_into(&mut data).unwrap();
+        let received = NtpPacket::deserialize(&data).unwrap();
+
+        assert_eq!(keys.verify(&received, &data, time(150)), Ok(&old));
+        assert_eq!(
+            keys.verify(&received, &data, time(250)),
+            Err(MacError::Mismatch(1))
+        );
+    }
+}
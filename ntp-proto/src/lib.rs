@@ -3,30 +3,61 @@
 mod clock;
 mod clock_select;
 mod config;
+mod control;
 mod filter;
 mod identifiers;
+mod instant;
+mod keys;
+mod nmea;
 mod packet;
+mod pcap;
 mod peer;
+mod pretty;
+pub mod ptp;
+mod refclock;
+mod roughtime;
+pub mod sntp;
 mod time_types;
 
 pub use clock::{ClockController, ClockUpdateResult, NtpClock};
 #[cfg(feature = "fuzz")]
 pub use clock_select::fuzz_find_interval;
-pub use clock_select::FilterAndCombine;
-#[cfg(feature = "ext-test")]
+#[cfg(any(feature = "ext-test", feature = "bench"))]
 pub use clock_select::{peer_snapshot, test_peer_snapshot};
-pub use config::{StepThreshold, SystemConfig};
+pub use clock_select::{CorrectnessInterval, FilterAndCombine, SelectionError, SelectionIntervals};
+pub use config::{
+    AuthenticationPolicy, ReferenceAgeLimits, SmearPolicy, StepThreshold, SystemConfig,
+};
+pub use control::{
+    format_reference_id, peer_status, system_status, ControlError, ControlMessage, ControlOpcode,
+    ControlResponse, ControlVariables, PeerSelection,
+};
 #[cfg(feature = "fuzz")]
 pub use filter::fuzz_tuple_from_packet_default;
+#[cfg(feature = "bench")]
+pub use filter::BenchClockFilter;
+pub use filter::SavedMeasurement;
 pub use identifiers::ReferenceId;
+pub use keys::{KeyAlgorithm, KeyError, KeySet, MacError, SymmetricKey};
+pub use nmea::{NmeaDate, NmeaFix, NmeaParsingError, NmeaSentenceKind, NmeaTime};
 
-pub use packet::{NtpAssociationMode, NtpLeapIndicator, NtpPacket};
+pub use packet::{
+    ExtensionFieldLimits, Nonconformance, NtpAssociationMode, NtpLeapIndicator, NtpPacket,
+    NtpVersion, PacketParsingError, PacketSerializationError, ParsingPolicy,
+    ResponseValidationError,
+};
+pub use pcap::{parse_pcap, CapturedPacket, PcapError};
 pub use peer::{
-    AcceptSynchronizationError, IgnoreReason, Peer, PeerSnapshot, PeerStatistics, Reach,
+    AcceptSynchronizationError, AssociationMode, IgnoreReason, Peer, PeerAction, PeerActions,
+    PeerBuilder, PeerEvent, PeerSnapshot, PeerState, PeerStatistics, Reach, SourceKind,
     SystemSnapshot, Update,
 };
+pub use pretty::PrettyPacket;
+pub use refclock::RefClock;
+pub use roughtime::{RoughtimeError, RoughtimeRequest, RoughtimeTime, ROUGHTIME_REQUEST_SIZE};
 #[cfg(feature = "fuzz")]
 pub use time_types::fuzz_duration_from_seconds;
 pub use time_types::{
     FrequencyTolerance, NtpDuration, NtpInstant, NtpTimestamp, PollInterval, PollIntervalLimits,
+    TimeConversionError, TimeParsingError,
 };
@@ -0,0 +1,388 @@
+// Parsing of the NMEA 0183 sentences emitted by GPS receivers, as used by the
+// NMEA reference clock driver. Only the sentences that carry time information
+// are interpreted:
+//
+//  - RMC: recommended minimum data, time of fix plus date
+//  - GGA: fix data, time of fix only
+//  - ZDA: time and date
+//
+// A sentence has the form `$<talker><type>,<field>,...,<field>*<checksum>`,
+// where the checksum is the XOR of all bytes between `$` and `*`, written as
+// two hexadecimal digits.
+
+use std::fmt::Display;
+
+use crate::{
+    time_types::{days_from_civil, DAYS_NTP_TO_UNIX, SECONDS_PER_DAY},
+    NtpTimestamp,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NmeaParsingError {
+    /// The sentence does not start with a `$`
+    MissingStart,
+    /// The sentence does not end in a `*` followed by two hex digits
+    MissingChecksum,
+    /// The checksum in the sentence does not match its content
+    InvalidChecksum { expected: u8, actual: u8 },
+    /// The sentence is valid, but does not contain time information we use
+    UnsupportedSentence,
+    /// One of the fields of the sentence could not be parsed
+    MalformedField(&'static str),
+}
+
+impl Display for NmeaParsingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingStart => f.write_str("Sentence does not start with '$'"),
+            Self::MissingChecksum => f.write_str("Sentence has no checksum"),
+            Self::InvalidChecksum { expected, actual } => f.write_fmt(format_args!(
+                "Invalid checksum {:02X}, expected {:02X}",
+                actual, expected
+            )),
+            Self::UnsupportedSentence => f.write_str("Unsupported sentence type"),
+            Self::MalformedField(field) => f.write_fmt(format_args!("Malformed {} field", field)),
+        }
+    }
+}
+
+impl std::error::Error for NmeaParsingError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NmeaSentenceKind {
+    Rmc,
+    Gga,
+    Zda,
+}
+
+/// UTC calendar date as reported by the receiver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NmeaDate {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+/// UTC time of day as reported by the receiver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NmeaTime {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanos: u32,
+}
+
+/// The time information contained in a single NMEA sentence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NmeaFix {
+    pub kind: NmeaSentenceKind,
+    pub time: NmeaTime,
+    pub date: Option<NmeaDate>,
+    /// Whether the receiver indicated it has a valid fix
+    pub valid: bool,
+}
+
+impl NmeaFix {
+    /// Parse a single NMEA sentence, with or without trailing line terminator
+    pub fn parse(sentence: &str) -> Result<Self, NmeaParsingError> {
+        let sentence = sentence.trim_end_matches(['\r', '\n']);
+        let body = sentence
+            .strip_prefix('$')
+            .ok_or(NmeaParsingError::MissingStart)?;
+
+        let (body, checksum) = body
+            .rsplit_once('*')
+            .ok_or(NmeaParsingError::MissingChecksum)?;
+        let actual = match checksum.len() {
+            2 => u8::from_str_radix(checksum, 16).map_err(|_| NmeaParsingError::MissingChecksum)?,
+            _ => return Err(NmeaParsingError::MissingChecksum),
+        };
+        let expected = body.bytes().fold(0, |acc, b| acc ^ b);
+        if expected != actual {
+            return Err(NmeaParsingError::InvalidChecksum { expected, actual });
+        }
+
+        let fields: Vec<&str> = body.split(',').collect();
+        let kind = match fields[0].get(2..) {
+            Some("RMC") => NmeaSentenceKind::Rmc,
+            Some("GGA") => NmeaSentenceKind::Gga,
+            Some("ZDA") => NmeaSentenceKind::Zda,
+            _ => return Err(NmeaParsingError::UnsupportedSentence),
+        };
+        let field = |index: usize| fields.get(index).copied().unwrap_or("");
+
+        let time = parse_time(field(1))?;
+
+        let (date, valid) = match kind {
+            NmeaSentenceKind::Rmc => (Some(parse_rmc_date(field(9))?), field(2) == "A"),
+            NmeaSentenceKind::Gga => (None, !matches!(field(6), "" | "0")),
+            NmeaSentenceKind::Zda => (
+                Some(NmeaDate {
+                    day: parse_number(field(2), "day")?,
+                    month: parse_number(field(3), "month")?,
+                    year: parse_number(field(4), "year")?,
+                }),
+                true,
+            ),
+        };
+
+        if let Some(date) = date {
+            if !(1..=12).contains(&date.month) || !(1..=31).contains(&date.day) {
+                return Err(NmeaParsingError::MalformedField("date"));
+            }
+        }
+
+        Ok(NmeaFix {
+            kind,
+            time,
+            date,
+            valid,
+        })
+    }
+
+    /// The moment in time described by this sentence.
+    ///
+    /// Sentences without a date (GGA) are placed on the day that brings them
+    /// closest to `local_estimate`, which should be the local clock time at
+    /// which the sentence was received.
+    pub fn timestamp(&self, local_estimate: NtpTimestamp) -> NtpTimestamp {
+        let seconds_of_day =
+            self.time.hour as i64 * 3600 + self.time.minute as i64 * 60 + self.time.second as i64;
+
+        let seconds = match self.date {
+            Some(date) => {
+                (days_from_civil(date.year as i64, date.month as i64, date.day as i64)
+                    + DAYS_NTP_TO_UNIX)
+                    * SECONDS_PER_DAY
+                    + seconds_of_day
+            }
+            None => {
+                // Note: this assumes the local estimate lies in NTP era 0
+                let local_seconds = local_estimate.seconds_since_ntp_era() as i64;
+                let local_seconds_of_day = local_seconds.rem_euclid(SECONDS_PER_DAY);
+                let diff = (seconds_of_day - local_seconds_of_day + SECONDS_PER_DAY / 2)
+                    .rem_euclid(SECONDS_PER_DAY)
+                    - SECONDS_PER_DAY / 2;
+                local_seconds + diff
+            }
+        };
+
+        // Truncating to the era is intended, eras are not represented in timestamps
+        NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds as u32, self.time.nanos)
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(
+    field: &str,
+    name: &'static str,
+) -> Result<T, NmeaParsingError> {
+    field
+        .parse()
+        .map_err(|_| NmeaParsingError::MalformedField(name))
+}
+
+fn parse_time(field: &str) -> Result<NmeaTime, NmeaParsingError> {
+    const ERROR: NmeaParsingError = NmeaParsingError::MalformedField("time");
+
+    let (whole, fraction) = field.split_once('.').unwrap_or((field, ""));
+    if whole.len() != 6 || !whole.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ERROR);
+    }
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ERROR);
+    }
+
+    let hour: u8 = whole[0..2].parse().map_err(|_| ERROR)?;
+    let minute: u8 = whole[2..4].parse().map_err(|_| ERROR)?;
+    // allow 60 seconds to represent a leap second
+    let second: u8 = whole[4..6].parse().map_err(|_| ERROR)?;
+    if hour > 23 || minute > 59 || second > 60 {
+        return Err(ERROR);
+    }
+
+    let nanos = match fraction {
+        "" => 0,
+        fraction => {
+            let digits: u32 = fraction.parse().map_err(|_| ERROR)?;
+            digits * 10u32.pow(9 - fraction.len() as u32)
+        }
+    };
+
+    Ok(NmeaTime {
+        hour,
+        minute,
+        second,
+        nanos,
+    })
+}
+
+fn parse_rmc_date(field: &str) -> Result<NmeaDate, NmeaParsingError> {
+    const ERROR: NmeaParsingError = NmeaParsingError::MalformedField("date");
+
+    if field.len() != 6 || !field.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ERROR);
+    }
+
+    let day = field[0..2].parse().map_err(|_| ERROR)?;
+    let month = field[2..4].parse().map_err(|_| ERROR)?;
+    let short_year: u16 = field[4..6].parse().map_err(|_| ERROR)?;
+
+    // RMC only has a two digit year, GPS did not exist before 1980
+    let year = if short_year < 80 {
+        2000 + short_year
+    } else {
+        1900 + short_year
+    };
+
+    Ok(NmeaDate { year, month, day })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        assert!(NmeaFix::parse("$GPZDA,201530.00,04,07,2002,00,00*60").is_ok());
+        assert!(NmeaFix::parse("$GPZDA,201530.00,04,07,2002,00,00*60\r\n").is_ok());
+        assert_eq!(
+            NmeaFix::parse("$GPZDA,201530.00,04,07,2002,00,00*61"),
+            Err(NmeaParsingError::InvalidChecksum {
+                expected: 0x60,
+                actual: 0x61
+            })
+        );
+        assert_eq!(
+            NmeaFix::parse("$GPZDA,201530.00,04,07,2002,00,00"),
+            Err(NmeaParsingError::MissingChecksum)
+        );
+        assert_eq!(
+            NmeaFix::parse("$GPZDA,201530.00,04,07,2002,00,00*6"),
+            Err(NmeaParsingError::MissingChecksum)
+        );
+        assert_eq!(
+            NmeaFix::parse("GPZDA,201530.00,04,07,2002,00,00*60"),
+            Err(NmeaParsingError::MissingStart)
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        assert_eq!(
+            NmeaFix::parse("$GPGSV,3,1,11,03,03,111,00,04,15,270,00,06,01,010,00,13,06,292,00*74"),
+            Err(NmeaParsingError::UnsupportedSentence)
+        );
+    }
+
+    #[test]
+    fn test_rmc() {
+        let fix = NmeaFix::parse(
+            "$GPRMC,123519.00,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*44",
+        )
+        .unwrap();
+        assert_eq!(fix.kind, NmeaSentenceKind::Rmc);
+        assert!(fix.valid);
+        assert_eq!(
+            fix.date,
+            Some(NmeaDate {
+                year: 1994,
+                month: 3,
+                day: 23
+            })
+        );
+        assert_eq!(
+            fix.timestamp(NtpTimestamp::default()),
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(2973414919, 0)
+        );
+
+        let fix = NmeaFix::parse("$GNRMC,235959.50,V,,,,,,,311222,,,N*66").unwrap();
+        assert!(!fix.valid);
+        assert_eq!(
+            fix.timestamp(NtpTimestamp::default()),
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(3881519999, 500_000_000)
+        );
+    }
+
+    #[test]
+    fn test_gga() {
+        let fix =
+            NmeaFix::parse("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47")
+                .unwrap();
+        assert_eq!(fix.kind, NmeaSentenceKind::Gga);
+        assert!(fix.valid);
+        assert_eq!(fix.date, None);
+
+        // Date is taken from the local estimate
+        let day_start = 2973369600;
+        let expected = NtpTimestamp::from_seconds_nanos_since_ntp_era(2973414919, 0);
+        for local in [day_start + 45319, day_start + 10800, day_start + 86399] {
+            assert_eq!(
+                fix.timestamp(NtpTimestamp::from_seconds_nanos_since_ntp_era(local, 0)),
+                expected
+            );
+        }
+
+        // Just after midnight, so the fix belongs to the previous day
+        let fix = NmeaFix::parse("$GPGGA,123519,,,,,0,00,,,M,,M,,*6B").unwrap();
+        assert!(!fix.valid);
+        let fix = NmeaFix {
+            time: NmeaTime {
+                hour: 23,
+                minute: 59,
+                second: 59,
+                nanos: 0,
+            },
+            ..fix
+        };
+        assert_eq!(
+            fix.timestamp(NtpTimestamp::from_seconds_nanos_since_ntp_era(
+                day_start + 86400 + 1,
+                0
+            )),
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(day_start + 86399, 0)
+        );
+    }
+
+    #[test]
+    fn test_zda() {
+        let fix = NmeaFix::parse("$GPZDA,201530.00,04,07,2002,00,00*60").unwrap();
+        assert_eq!(fix.kind, NmeaSentenceKind::Zda);
+        assert_eq!(
+            fix.time,
+            NmeaTime {
+                hour: 20,
+                minute: 15,
+                second: 30,
+                nanos: 0
+            }
+        );
+        assert_eq!(
+            fix.timestamp(NtpTimestamp::default()),
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(3234802530, 0)
+        );
+    }
+
+    #[test]
+    fn test_malformed_time() {
+        assert_eq!(
+            parse_time("1235"),
+            Err(NmeaParsingError::MalformedField("time"))
+        );
+        assert_eq!(
+            parse_time("250000"),
+            Err(NmeaParsingError::MalformedField("time"))
+        );
+        assert_eq!(
+            parse_time("12a519"),
+            Err(NmeaParsingError::MalformedField("time"))
+        );
+        assert_eq!(parse_time("123519.25").unwrap().nanos, 250_000_000);
+    }
+
+    #[test]
+    fn test_days_from_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1900, 1, 1), -DAYS_NTP_TO_UNIX);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+    }
+}
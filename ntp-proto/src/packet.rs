@@ -1,14 +1,23 @@
 use std::{borrow::Cow, fmt::Display};
 
 use rand::{thread_rng, Rng};
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use crate::{NtpClock, NtpDuration, NtpTimestamp, PollInterval, ReferenceId, SystemSnapshot};
+use crate::{
+    NtpClock, NtpDuration, NtpTimestamp, PollInterval, PrettyPacket, ReferenceId, SymmetricKey,
+    SystemSnapshot,
+};
 
-#[derive(Debug)]
+/// Offset of the transmit timestamp in a serialized NTP header
+const TRANSMIT_TIMESTAMP_OFFSET: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PacketParsingError {
     InvalidVersion(u8),
     IncorrectLength,
+    /// The packet has more or longer extension fields than allowed
+    ExtensionFieldLimit,
 }
 
 impl Display for PacketParsingError {
@@ -18,13 +27,135 @@ impl Display for PacketParsingError {
                 f.write_fmt(format_args!("Invalid version {}", version))
             }
             Self::IncorrectLength => f.write_str("Incorrect packet length"),
+            Self::ExtensionFieldLimit => f.write_str("Extension fields exceed the limits"),
         }
     }
 }
 
 impl std::error::Error for PacketParsingError {}
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Limits on the extension fields of a received packet. Parsing stops at the
+/// first field beyond them, so that the work spent on a packet stays small
+/// whatever it contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtensionFieldLimits {
+    /// Largest number of extension fields
+    pub max_count: usize,
+    /// Largest length of an extension field, including its type and length
+    pub max_length: usize,
+}
+
+impl ExtensionFieldLimits {
+    pub const UNLIMITED: Self = ExtensionFieldLimits {
+        max_count: usize::MAX,
+        max_length: usize::MAX,
+    };
+}
+
+/// How packets that parse, but deviate from RFC 5905, are treated
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum ParsingPolicy {
+    /// Use such packets, as plenty of clients and servers send them
+    #[default]
+    Lenient,
+    /// Drop such packets
+    Strict,
+}
+
+/// A way in which a packet deviates from RFC 5905, see
+/// [`NtpPacket::nonconformance`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nonconformance {
+    /// A reserved value, or a field that must be zero but is not: the reserved
+    /// mode 0, or the key id of a crypto-NAK
+    MustBeZero,
+    /// An extension field of which the length is not a multiple of 4, or a
+    /// MAC that is neither a crypto-NAK nor of a known digest size
+    Padding,
+    /// A stratum above 16, a poll interval outside 4 (16 s) to 17 (36 h), or
+    /// a precision above 0 (1 s) or below -32
+    OutOfRange,
+}
+
+impl Display for Nonconformance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MustBeZero => f.write_str("Nonzero reserved field"),
+            Self::Padding => f.write_str("Incorrect padding"),
+            Self::OutOfRange => f.write_str("Field out of range"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketSerializationError {
+    /// The buffer cannot hold the packet, which needs `needed` bytes
+    BufferTooSmall { needed: usize, available: usize },
+    /// An extension field holds more data than its length can express
+    ExtensionFieldTooLong,
+}
+
+impl Display for PacketSerializationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BufferTooSmall { needed, available } => f.write_fmt(format_args!(
+                "Buffer of {} bytes too small for packet of {} bytes",
+                available, needed
+            )),
+            Self::ExtensionFieldTooLong => f.write_str("Extension field too long"),
+        }
+    }
+}
+
+impl std::error::Error for PacketSerializationError {}
+
+/// Why a packet is not a valid response to one of our requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseValidationError {
+    /// The origin timestamp is not the transmit timestamp of the request
+    OriginMismatch,
+    /// The origin timestamp is zero, so the packet is not a response to any request
+    ZeroOrigin,
+    /// The receive timestamp is zero, so the server did not fill it in
+    ZeroReceive,
+    /// The transmit timestamp is zero, so the server did not fill it in
+    ZeroTransmit,
+    /// The transmit timestamp is that of the previous response, so this is a
+    /// duplicate or a replay of that response
+    DuplicateTransmit,
+}
+
+impl Display for ResponseValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OriginMismatch => f.write_str("Origin timestamp does not match the request"),
+            Self::ZeroOrigin => f.write_str("Origin timestamp is zero"),
+            Self::ZeroReceive => f.write_str("Receive timestamp is zero"),
+            Self::ZeroTransmit => f.write_str("Transmit timestamp is zero"),
+            Self::DuplicateTransmit => {
+                f.write_str("Transmit timestamp is that of the previous response")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResponseValidationError {}
+
+/// The `N` bytes of `data` at `offset`, or an error when `data` is too short
+fn read_bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], PacketParsingError> {
+    data.get(offset..)
+        .and_then(|data| data.get(..N))
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(PacketParsingError::IncorrectLength)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum NtpLeapIndicator {
     NoWarning,
     Leap61,
@@ -33,21 +164,17 @@ pub enum NtpLeapIndicator {
 }
 
 impl NtpLeapIndicator {
-    // This function should only ever be called with 2 bit values
-    // (in the least significant position)
+    // Only the 2 least significant bits are used
     fn from_bits(bits: u8) -> NtpLeapIndicator {
-        match bits {
+        match bits & 0b11 {
             0 => NtpLeapIndicator::NoWarning,
             1 => NtpLeapIndicator::Leap61,
             2 => NtpLeapIndicator::Leap59,
-            3 => NtpLeapIndicator::Unknown,
-            // This function should only ever be called from the packet parser
-            // with just two bits, so this really should be unreachable
-            _ => unreachable!(),
+            _ => NtpLeapIndicator::Unknown,
         }
     }
 
-    fn to_bits(self) -> u8 {
+    pub fn to_bits(self) -> u8 {
         match self {
             NtpLeapIndicator::NoWarning => 0,
             NtpLeapIndicator::Leap61 => 1,
@@ -61,6 +188,66 @@ impl NtpLeapIndicator {
     }
 }
 
+/// Version of the NTP packets exchanged with a peer. Version 4 is backwards
+/// compatible with version 3, which some older appliances still require.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NtpVersion {
+    V3,
+    #[default]
+    V4,
+}
+
+impl NtpVersion {
+    /// The version with this number, when it is supported
+    pub fn from_number(version: u8) -> Option<Self> {
+        match version {
+            3 => Some(NtpVersion::V3),
+            4 => Some(NtpVersion::V4),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(self) -> u8 {
+        match self {
+            NtpVersion::V3 => 3,
+            NtpVersion::V4 => 4,
+        }
+    }
+}
+
+impl Display for NtpVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_number())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for NtpVersion {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.as_number().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for NtpVersion {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let val: u8 = Deserialize::deserialize(deserializer)?;
+        NtpVersion::from_number(val).ok_or_else(|| {
+            // NTPv5 is still a draft, its packets are not understood yet
+            serde::de::Error::invalid_value(
+                serde::de::Unexpected::Unsigned(val as u64),
+                &"NTP version 3 or 4",
+            )
+        })
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum NtpAssociationMode {
     Reserved,
@@ -74,10 +261,9 @@ pub enum NtpAssociationMode {
 }
 
 impl NtpAssociationMode {
-    // This function should only ever be called with 3 bit values
-    // (in the least significant position)
+    // Only the 3 least significant bits are used
     fn from_bits(bits: u8) -> NtpAssociationMode {
-        match bits {
+        match bits & 0b111 {
             0 => NtpAssociationMode::Reserved,
             1 => NtpAssociationMode::SymmetricActive,
             2 => NtpAssociationMode::SymmetricPassive,
@@ -85,14 +271,11 @@ impl NtpAssociationMode {
             4 => NtpAssociationMode::Server,
             5 => NtpAssociationMode::Broadcast,
             6 => NtpAssociationMode::Control,
-            7 => NtpAssociationMode::Private,
-            // This function should only ever be called from the packet parser
-            // with just three bits, so this really should be unreachable
-            _ => unreachable!(),
+            _ => NtpAssociationMode::Private,
         }
     }
 
-    fn to_bits(self) -> u8 {
+    pub fn to_bits(self) -> u8 {
         match self {
             NtpAssociationMode::Reserved => 0,
             NtpAssociationMode::SymmetricActive => 1,
@@ -130,6 +313,12 @@ impl<'a> ExtensionField<'a> {
         }
     }
 
+    fn serialized_len(&self) -> usize {
+        match self {
+            ExtensionField::Unknown { data, .. } => 4 + data.len(),
+        }
+    }
+
     fn serialize<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
         match self {
             ExtensionField::Unknown { typeid, data } => {
@@ -147,18 +336,18 @@ impl<'a> ExtensionField<'a> {
     }
 
     fn deserialize(data: &'a [u8]) -> Result<(ExtensionField<'a>, usize), PacketParsingError> {
-        if data.len() < 4 {
-            return Err(PacketParsingError::IncorrectLength);
-        }
-        let typeid = u16::from_be_bytes(data[0..2].try_into().unwrap());
-        let ef_len = u16::from_be_bytes(data[2..4].try_into().unwrap()) as usize;
-        if ef_len < Self::MINIMUM_SIZE || ef_len > data.len() {
+        let typeid = u16::from_be_bytes(read_bytes(data, 0)?);
+        let ef_len = u16::from_be_bytes(read_bytes(data, 2)?) as usize;
+        if ef_len < Self::MINIMUM_SIZE {
             return Err(PacketParsingError::IncorrectLength);
         }
+        let field_data = data
+            .get(4..ef_len)
+            .ok_or(PacketParsingError::IncorrectLength)?;
         Ok((
             ExtensionField::Unknown {
                 typeid,
-                data: Cow::Borrowed(&data[4..ef_len]),
+                data: Cow::Borrowed(field_data),
             },
             ef_len,
         ))
@@ -192,25 +381,27 @@ impl<'a> ExtensionFieldData<'a> {
         let mut offset = 0;
         std::iter::from_fn(move || match self {
             ExtensionFieldData::Raw(data) => {
-                if offset < data.len() {
-                    let (field, len) = ExtensionField::deserialize(&data[offset..]).unwrap();
-                    offset += len;
-                    Some(Cow::Owned(field))
-                } else {
-                    None
-                }
+                // the data was validated when it was parsed, so this only
+                // stops at the end of the data
+                let (field, len) = ExtensionField::deserialize(data.get(offset..)?).ok()?;
+                offset += len;
+                Some(Cow::Owned(field))
             }
             ExtensionFieldData::List(fields) => {
-                if offset < fields.len() {
-                    offset += 1;
-                    Some(Cow::Borrowed(&fields[offset - 1]))
-                } else {
-                    None
-                }
+                let field = fields.get(offset)?;
+                offset += 1;
+                Some(Cow::Borrowed(field))
             }
         })
     }
 
+    fn serialized_len(&self) -> usize {
+        match self {
+            ExtensionFieldData::Raw(efdata) => efdata.len(),
+            ExtensionFieldData::List(fields) => fields.iter().map(|f| f.serialized_len()).sum(),
+        }
+    }
+
     fn serialize<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
         match self {
             ExtensionFieldData::Raw(efdata) => w.write_all(efdata),
@@ -223,15 +414,24 @@ impl<'a> ExtensionFieldData<'a> {
         }
     }
 
-    fn deserialize(data: &'a [u8]) -> Result<(ExtensionFieldData<'a>, usize), PacketParsingError> {
-        let mut offset = 0;
-        while data.len() - offset >= Mac::MAXIMUM_SIZE {
-            let (_, len) = ExtensionField::deserialize(&data[offset..])?;
-            offset += len;
+    fn deserialize(
+        data: &'a [u8],
+        limits: ExtensionFieldLimits,
+    ) -> Result<(ExtensionFieldData<'a>, usize), PacketParsingError> {
+        let mut remaining = data;
+        let mut count = 0;
+        while remaining.len() >= Mac::MAXIMUM_SIZE {
+            let (_, len) = ExtensionField::deserialize(remaining)?;
+            count += 1;
+            if count > limits.max_count || len > limits.max_length {
+                return Err(PacketParsingError::ExtensionFieldLimit);
+            }
+            remaining = remaining.get(len..).unwrap_or_default();
         }
 
+        let offset = data.len() - remaining.len();
         Ok((
-            ExtensionFieldData::Raw(Cow::Borrowed(&data[..offset])),
+            ExtensionFieldData::Raw(Cow::Borrowed(data.get(..offset).unwrap_or_default())),
             offset,
         ))
     }
@@ -259,13 +459,13 @@ impl<'a> Mac<'a> {
     }
 
     fn deserialize(data: &'a [u8]) -> Result<Mac<'a>, PacketParsingError> {
-        if data.len() < 4 || data.len() >= Self::MAXIMUM_SIZE {
+        if data.len() >= Self::MAXIMUM_SIZE {
             return Err(PacketParsingError::IncorrectLength);
         }
 
         Ok(Mac {
-            keyid: u32::from_be_bytes(data[0..4].try_into().unwrap()),
-            mac: Cow::Borrowed(&data[4..]),
+            keyid: u32::from_be_bytes(read_bytes(data, 0)?),
+            mac: Cow::Borrowed(data.get(4..).unwrap_or_default()),
         })
     }
 }
@@ -322,24 +522,23 @@ impl NtpHeaderV3V4 {
     }
 
     fn deserialize(data: &[u8]) -> Result<(Self, usize), PacketParsingError> {
-        if data.len() < Self::LENGTH {
-            return Err(PacketParsingError::IncorrectLength);
-        }
+        let header: [u8; Self::LENGTH] = read_bytes(data, 0)?;
+        let [flags, stratum, poll, precision] = read_bytes(&header, 0)?;
 
         Ok((
             Self {
-                leap: NtpLeapIndicator::from_bits((data[0] & 0xC0) >> 6),
-                mode: NtpAssociationMode::from_bits(data[0] & 0x07),
-                stratum: data[1],
-                poll: data[2] as i8,
-                precision: data[3] as i8,
-                root_delay: NtpDuration::from_bits_short(data[4..8].try_into().unwrap()),
-                root_dispersion: NtpDuration::from_bits_short(data[8..12].try_into().unwrap()),
-                reference_id: ReferenceId::from_bytes(data[12..16].try_into().unwrap()),
-                reference_timestamp: NtpTimestamp::from_bits(data[16..24].try_into().unwrap()),
-                origin_timestamp: NtpTimestamp::from_bits(data[24..32].try_into().unwrap()),
-                receive_timestamp: NtpTimestamp::from_bits(data[32..40].try_into().unwrap()),
-                transmit_timestamp: NtpTimestamp::from_bits(data[40..48].try_into().unwrap()),
+                leap: NtpLeapIndicator::from_bits(flags >> 6),
+                mode: NtpAssociationMode::from_bits(flags),
+                stratum,
+                poll: poll as i8,
+                precision: precision as i8,
+                root_delay: NtpDuration::from_bits_short(read_bytes(&header, 4)?),
+                root_dispersion: NtpDuration::from_bits_short(read_bytes(&header, 8)?),
+                reference_id: ReferenceId::from_bytes(read_bytes(&header, 12)?),
+                reference_timestamp: NtpTimestamp::from_bits(read_bytes(&header, 16)?),
+                origin_timestamp: NtpTimestamp::from_bits(read_bytes(&header, 24)?),
+                receive_timestamp: NtpTimestamp::from_bits(read_bytes(&header, 32)?),
+                transmit_timestamp: NtpTimestamp::from_bits(read_bytes(&header, 40)?),
             },
             Self::LENGTH,
         ))
@@ -358,17 +557,27 @@ impl NtpHeaderV3V4 {
         Ok(())
     }
 
-    fn poll_message(poll_interval: PollInterval) -> (Self, RequestIdentifier) {
+    fn poll_message(
+        poll_interval: PollInterval,
+        time: NtpTimestamp,
+        random_bits: u8,
+    ) -> (Self, RequestIdentifier) {
         let mut packet = Self::new();
         let poll_interval = poll_interval;
         packet.poll = poll_interval.as_log();
         packet.mode = NtpAssociationMode::Client;
 
-        // In order to increase the entropy of the transmit timestamp
-        // it is just a randomly generated timestamp.
-        // We then expect to get it back identically from the remote
-        // in the origin field.
-        let transmit_timestamp = thread_rng().gen();
+        // The low order bits of the transmit timestamp are random, such that
+        // an off-path attacker cannot guess it to spoof a response. We then
+        // expect to get it back identically from the remote in the origin field.
+        let random_mask = match random_bits {
+            64.. => u64::MAX,
+            bits => (1 << bits) - 1,
+        };
+        let random: u64 = thread_rng().gen();
+        let time = u64::from_be_bytes(time.to_bits());
+        let transmit_timestamp =
+            NtpTimestamp::from_bits(((time & !random_mask) | (random & random_mask)).to_be_bytes());
         packet.transmit_timestamp = transmit_timestamp;
 
         (
@@ -379,6 +588,28 @@ impl NtpHeaderV3V4 {
         )
     }
 
+    fn symmetric_poll_message(
+        system: &SystemSnapshot,
+        poll_interval: PollInterval,
+        time: NtpTimestamp,
+        random_bits: u8,
+    ) -> (Self, RequestIdentifier) {
+        let (packet, identifier) = Self::poll_message(poll_interval, time, random_bits);
+        (
+            Self {
+                mode: NtpAssociationMode::SymmetricActive,
+                leap: system.leap_indicator,
+                stratum: system.stratum,
+                reference_id: system.reference_id,
+                precision: system.precision.log2(),
+                root_delay: system.root_delay,
+                root_dispersion: system.root_dispersion,
+                ..packet
+            },
+            identifier,
+        )
+    }
+
     fn timestamp_response<C: NtpClock>(
         system: &SystemSnapshot,
         input: Self,
@@ -386,11 +617,16 @@ impl NtpHeaderV3V4 {
         clock: &C,
     ) -> Self {
         Self {
-            mode: NtpAssociationMode::Server,
+            mode: match input.mode {
+                // answer a symmetric active peer as its passive counterpart
+                NtpAssociationMode::SymmetricActive => NtpAssociationMode::SymmetricPassive,
+                _ => NtpAssociationMode::Server,
+            },
             stratum: system.stratum,
             origin_timestamp: input.transmit_timestamp,
             receive_timestamp: recv_timestamp,
             reference_id: system.reference_id,
+            reference_timestamp: system.reference_timestamp,
             poll: input.poll,
             precision: system.precision.log2(),
             root_delay: system.root_delay,
@@ -437,18 +673,27 @@ impl<'a> NtpPacket<'a> {
         self.efdata.iter()
     }
 
+    /// Parse a packet, borrowing its extension fields and MAC from `data`
+    /// rather than copying them. Parsing never allocates nor panics.
     pub fn deserialize(data: &'a [u8]) -> Result<Self, PacketParsingError> {
-        if data.is_empty() {
-            return Err(PacketParsingError::IncorrectLength);
-        }
+        Self::deserialize_with_limits(data, ExtensionFieldLimits::UNLIMITED)
+    }
 
-        let version = (data[0] & 0x38) >> 3;
+    /// Parse a packet with at most the extension fields that `limits` allow.
+    /// Parsing never allocates.
+    pub fn deserialize_with_limits(
+        data: &'a [u8],
+        limits: ExtensionFieldLimits,
+    ) -> Result<Self, PacketParsingError> {
+        let [flags] = read_bytes(data, 0)?;
+        let version = (flags & 0x38) >> 3;
 
         match version {
             3 => {
                 let (header, header_size) = NtpHeaderV3V4::deserialize(data)?;
-                let mac = if header_size != data.len() {
-                    Some(Mac::deserialize(&data[header_size..])?)
+                let rest = data.get(header_size..).unwrap_or_default();
+                let mac = if !rest.is_empty() {
+                    Some(Mac::deserialize(rest)?)
                 } else {
                     None
                 };
@@ -460,9 +705,11 @@ impl<'a> NtpPacket<'a> {
             }
             4 => {
                 let (header, header_size) = NtpHeaderV3V4::deserialize(data)?;
-                let (efdata, fields_len) = ExtensionFieldData::deserialize(&data[header_size..])?;
-                let mac = if header_size != data.len() {
-                    Some(Mac::deserialize(&data[header_size + fields_len..])?)
+                let rest = data.get(header_size..).unwrap_or_default();
+                let (efdata, fields_len) = ExtensionFieldData::deserialize(rest, limits)?;
+                let rest = rest.get(fields_len..).unwrap_or_default();
+                let mac = if !rest.is_empty() {
+                    Some(Mac::deserialize(rest)?)
                 } else {
                     None
                 };
@@ -477,6 +724,14 @@ impl<'a> NtpPacket<'a> {
     }
 
     pub fn serialize<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        self.serialize_without_mac(w)?;
+        if let Some(ref mac) = self.mac {
+            mac.serialize(w)?;
+        }
+        Ok(())
+    }
+
+    fn serialize_without_mac<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
         match self.header {
             NtpHeader::V3(header) => header.serialize(w, 3),
             NtpHeader::V4(header) => header.serialize(w, 4),
@@ -484,14 +739,106 @@ impl<'a> NtpPacket<'a> {
         if !matches!(self.header, NtpHeader::V3(_)) {
             self.efdata.serialize(w)?;
         }
-        if let Some(ref mac) = self.mac {
-            mac.serialize(w)?;
-        }
         Ok(())
     }
 
+    /// The number of bytes written by serializing the packet
+    pub fn serialized_len(&self) -> usize {
+        let efdata_len = match self.header {
+            NtpHeader::V3(_) => 0,
+            NtpHeader::V4(_) => self.efdata.serialized_len(),
+        };
+        let mac_len = self.mac.as_ref().map(|mac| 4 + mac.mac.len()).unwrap_or(0);
+
+        NtpHeaderV3V4::LENGTH + efdata_len + mac_len
+    }
+
+    /// Serialize the packet into the start of `buf`, returning the number of
+    /// bytes written. Serializing never allocates nor panics.
+    pub fn serialize_into(&self, buf: &mut [u8]) -> Result<usize, PacketSerializationError> {
+        let needed = self.serialized_len();
+        if buf.len() < needed {
+            return Err(PacketSerializationError::BufferTooSmall {
+                needed,
+                available: buf.len(),
+            });
+        }
+
+        // the buffer is large enough, so this only fails on extension fields
+        // that are too long to be serialized at all
+        let mut cursor = std::io::Cursor::new(buf);
+        self.serialize(&mut cursor)
+            .map_err(|_| PacketSerializationError::ExtensionFieldTooLong)?;
+
+        Ok(needed)
+    }
+
+    /// Serialize the packet into the start of `buf`, with a MAC made with
+    /// `key` in place of the MAC it has, returning the number of bytes
+    /// written. Like `serialize_into`, this never allocates nor panics.
+    pub fn serialize_signed_into(
+        &self,
+        buf: &mut [u8],
+        key: &SymmetricKey,
+    ) -> Result<usize, PacketSerializationError> {
+        let mac_len = self.mac.as_ref().map(|mac| 4 + mac.mac.len()).unwrap_or(0);
+        let unsigned_len = self.serialized_len() - mac_len;
+        let available = buf.len();
+        let unsigned =
+            buf.get_mut(..unsigned_len)
+                .ok_or(PacketSerializationError::BufferTooSmall {
+                    needed: unsigned_len,
+                    available,
+                })?;
+
+        let mut cursor = std::io::Cursor::new(&mut *unsigned);
+        self.serialize_without_mac(&mut cursor)
+            .map_err(|_| PacketSerializationError::ExtensionFieldTooLong)?;
+        let mac = key.mac(unsigned);
+
+        let needed = unsigned_len + 4 + mac.len();
+        let signature = buf
+            .get_mut(unsigned_len..needed)
+            .ok_or(PacketSerializationError::BufferTooSmall { needed, available })?;
+        signature[..4].copy_from_slice(&key.id().to_be_bytes());
+        signature[4..].copy_from_slice(&mac);
+
+        Ok(needed)
+    }
+
+    /// A request with an entirely random transmit timestamp
     pub fn poll_message(poll_interval: PollInterval) -> (Self, RequestIdentifier) {
-        let (header, id) = NtpHeaderV3V4::poll_message(poll_interval);
+        Self::poll_message_at(poll_interval, NtpTimestamp::default(), 64)
+    }
+
+    /// A request with a transmit timestamp that is `time`, with its lowest
+    /// `random_bits` bits randomized
+    pub fn poll_message_at(
+        poll_interval: PollInterval,
+        time: NtpTimestamp,
+        random_bits: u8,
+    ) -> (Self, RequestIdentifier) {
+        let (header, id) = NtpHeaderV3V4::poll_message(poll_interval, time, random_bits);
+        (
+            NtpPacket {
+                header: NtpHeader::V4(header),
+                efdata: Default::default(),
+                mac: None,
+            },
+            id,
+        )
+    }
+
+    /// A request in symmetric active mode, which carries our own system
+    /// state such that the remote can synchronize to us as well
+    pub fn symmetric_poll_message_at(
+        system: &SystemSnapshot,
+        poll_interval: PollInterval,
+        time: NtpTimestamp,
+        random_bits: u8,
+    ) -> (Self, RequestIdentifier) {
+        let (header, id) =
+            NtpHeaderV3V4::symmetric_poll_message(system, poll_interval, time, random_bits);
         (
             NtpPacket {
                 header: NtpHeader::V4(header),
@@ -561,6 +908,14 @@ impl<'a> NtpPacket<'a> {
             },
         }
     }
+
+    /// Overwrite the transmit timestamp of a serialized packet, such that it
+    /// can be taken right before the packet is sent rather than when the
+    /// packet was built. Panics when `buf` is shorter than an NTP header.
+    pub fn stamp_transmit_timestamp(buf: &mut [u8], timestamp: NtpTimestamp) {
+        buf[TRANSMIT_TIMESTAMP_OFFSET..TRANSMIT_TIMESTAMP_OFFSET + 8]
+            .copy_from_slice(&timestamp.to_bits());
+    }
 }
 
 impl<'a> NtpPacket<'a> {
@@ -571,6 +926,40 @@ impl<'a> NtpPacket<'a> {
         }
     }
 
+    /// The packet in `version`. Extension fields are dropped for version 3,
+    /// which has none.
+    pub fn with_version(self, version: NtpVersion) -> Self {
+        let header = match self.header {
+            NtpHeader::V3(header) | NtpHeader::V4(header) => header,
+        };
+
+        match version {
+            NtpVersion::V3 => NtpPacket {
+                header: NtpHeader::V3(header),
+                efdata: ExtensionFieldData::default(),
+                mac: self.mac,
+            },
+            NtpVersion::V4 => NtpPacket {
+                header: NtpHeader::V4(header),
+                ..self
+            },
+        }
+    }
+
+    pub fn ntp_version(&self) -> NtpVersion {
+        match self.header {
+            NtpHeader::V3(_) => NtpVersion::V3,
+            NtpHeader::V4(_) => NtpVersion::V4,
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        match self.header {
+            NtpHeader::V3(_) => 3,
+            NtpHeader::V4(_) => 4,
+        }
+    }
+
     pub fn mode(&self) -> NtpAssociationMode {
         match self.header {
             NtpHeader::V3(header) => header.mode,
@@ -585,6 +974,13 @@ impl<'a> NtpPacket<'a> {
         }
     }
 
+    pub fn poll(&self) -> i8 {
+        match self.header {
+            NtpHeader::V3(header) => header.poll,
+            NtpHeader::V4(header) => header.poll,
+        }
+    }
+
     pub fn precision(&self) -> i8 {
         match self.header {
             NtpHeader::V3(header) => header.precision,
@@ -620,6 +1016,20 @@ impl<'a> NtpPacket<'a> {
         }
     }
 
+    pub fn origin_timestamp(&self) -> NtpTimestamp {
+        match self.header {
+            NtpHeader::V3(header) => header.origin_timestamp,
+            NtpHeader::V4(header) => header.origin_timestamp,
+        }
+    }
+
+    pub fn reference_timestamp(&self) -> NtpTimestamp {
+        match self.header {
+            NtpHeader::V3(header) => header.reference_timestamp,
+            NtpHeader::V4(header) => header.reference_timestamp,
+        }
+    }
+
     pub fn reference_id(&self) -> ReferenceId {
         match self.header {
             NtpHeader::V3(header) => header.reference_id,
@@ -627,6 +1037,12 @@ impl<'a> NtpPacket<'a> {
         }
     }
 
+    /// Every field of the packet, one per line, with kiss codes, timestamps
+    /// and extension fields decoded
+    pub fn pretty(&self) -> PrettyPacket<'_> {
+        PrettyPacket { packet: self }
+    }
+
     pub fn is_kiss(&self) -> bool {
         match self.header {
             NtpHeader::V3(header) => header.stratum == 0,
@@ -646,15 +1062,100 @@ impl<'a> NtpPacket<'a> {
         self.is_kiss() && self.reference_id().is_rstr()
     }
 
-    pub fn valid_server_response(&self, identifier: RequestIdentifier) -> bool {
-        match self.header {
-            NtpHeader::V3(header) => {
-                header.origin_timestamp == identifier.expected_origin_timestamp
+    /// Check that the packet is a response to the request with the given
+    /// identifier, and that the server filled in its timestamps.
+    ///
+    /// Kiss-o'-Death packets only need to match the request, as they need
+    /// not have meaningful receive and transmit timestamps.
+    pub fn validate_server_response(
+        &self,
+        identifier: RequestIdentifier,
+    ) -> Result<(), ResponseValidationError> {
+        let origin_timestamp = match self.header {
+            NtpHeader::V3(header) => header.origin_timestamp,
+            NtpHeader::V4(header) => header.origin_timestamp,
+        };
+
+        if origin_timestamp == NtpTimestamp::default() {
+            return Err(ResponseValidationError::ZeroOrigin);
+        }
+
+        if origin_timestamp != identifier.expected_origin_timestamp {
+            return Err(ResponseValidationError::OriginMismatch);
+        }
+
+        if !self.is_kiss() {
+            if self.receive_timestamp() == NtpTimestamp::default() {
+                return Err(ResponseValidationError::ZeroReceive);
             }
-            NtpHeader::V4(header) => {
-                header.origin_timestamp == identifier.expected_origin_timestamp
+
+            if self.transmit_timestamp() == NtpTimestamp::default() {
+                return Err(ResponseValidationError::ZeroTransmit);
             }
         }
+
+        Ok(())
+    }
+
+    pub fn valid_server_response(&self, identifier: RequestIdentifier) -> bool {
+        self.validate_server_response(identifier).is_ok()
+    }
+
+    /// The first way in which the packet deviates from RFC 5905, if it does
+    pub fn nonconformance(&self) -> Option<Nonconformance> {
+        let crypto_nak = self.mac.as_ref().filter(|mac| mac.mac.is_empty());
+        if self.mode() == NtpAssociationMode::Reserved
+            || matches!(crypto_nak, Some(mac) if mac.keyid != 0)
+        {
+            return Some(Nonconformance::MustBeZero);
+        }
+
+        // digests of MD5 and AES-CMAC are 16 bytes, those of SHA-1 20 bytes
+        let bad_mac = matches!(&self.mac, Some(mac) if ![0, 16, 20].contains(&mac.mac.len()));
+        let bad_field = self
+            .efdata
+            .iter()
+            .any(|field| field.serialized_len() % 4 != 0);
+        if bad_mac || bad_field {
+            return Some(Nonconformance::Padding);
+        }
+
+        // a kiss-o'-death need not fill in the poll interval and precision
+        let kiss = self.mode() == NtpAssociationMode::Server && self.is_kiss();
+        let poll = PollInterval::PROTOCOL_MIN.as_log()..=PollInterval::PROTOCOL_MAX.as_log();
+        if self.stratum() > 16
+            || (!kiss && !poll.contains(&self.poll()))
+            || (!kiss && !(-32..=0).contains(&self.precision()))
+        {
+            return Some(Nonconformance::OutOfRange);
+        }
+
+        None
+    }
+
+    /// The id of the key of the MAC, when the packet has a MAC
+    pub fn key_id(&self) -> Option<u32> {
+        self.mac.as_ref().map(|mac| mac.keyid)
+    }
+
+    pub(crate) fn mac(&self) -> Option<(u32, &[u8])> {
+        self.mac.as_ref().map(|mac| (mac.keyid, &*mac.mac))
+    }
+
+    /// Add a MAC made with `key`, replacing the MAC the packet had. The
+    /// packet must not change after this, so a signed time response can not
+    /// have its transmit timestamp taken again before it is sent.
+    pub fn sign(&mut self, key: &SymmetricKey) {
+        self.mac = None;
+        let mut data = Vec::with_capacity(self.serialized_len());
+        // only extension fields that are too long fail to serialize, and
+        // those fail again when the packet itself is serialized
+        let _ = self.serialize(&mut data);
+
+        self.mac = Some(Mac {
+            keyid: key.id(),
+            mac: Cow::Owned(key.mac(&data).to_vec()),
+        });
     }
 }
 
@@ -713,6 +1214,13 @@ impl<'a> NtpPacket<'a> {
         }
     }
 
+    pub fn set_reference_timestamp(&mut self, timestamp: NtpTimestamp) {
+        match &mut self.header {
+            NtpHeader::V3(ref mut header) => header.reference_timestamp = timestamp,
+            NtpHeader::V4(ref mut header) => header.reference_timestamp = timestamp,
+        }
+    }
+
     pub fn set_reference_id(&mut self, reference_id: ReferenceId) {
         match &mut self.header {
             NtpHeader::V3(ref mut header) => header.reference_id = reference_id,
@@ -749,6 +1257,21 @@ impl<'a> Default for NtpPacket<'a> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_stamp_transmit_timestamp() {
+        let (packet, _) = NtpPacket::poll_message(PollInterval::default());
+        let mut buf = [0; 48];
+        packet.serialize_into(&mut buf).unwrap();
+
+        let timestamp = NtpTimestamp::from_fixed_int(0x0123_4567_89ab_cdef);
+        NtpPacket::stamp_transmit_timestamp(&mut buf, timestamp);
+
+        let stamped = NtpPacket::deserialize(&buf).unwrap();
+        assert_eq!(stamped.transmit_timestamp(), timestamp);
+        assert_eq!(stamped.poll(), packet.poll());
+        assert_eq!(stamped.mode(), packet.mode());
+    }
+
     #[test]
     fn roundtrip_bitrep_leap() {
         for i in 0..4u8 {
@@ -868,6 +1391,67 @@ mod tests {
         assert!(NtpPacket::deserialize(packet).is_err());
     }
 
+    #[test]
+    fn test_with_version() {
+        let (request, _) = NtpPacket::poll_message(PollInterval::default());
+        assert_eq!(request.ntp_version(), NtpVersion::V4);
+
+        let request = request.with_version(NtpVersion::V3);
+        let mut buf = vec![];
+        request.serialize(&mut buf).unwrap();
+        assert_eq!((buf[0] & 0x38) >> 3, 3);
+        let parsed = NtpPacket::deserialize(&buf).unwrap();
+        assert_eq!(parsed.ntp_version(), NtpVersion::V3);
+        assert_eq!(parsed.mode(), NtpAssociationMode::Client);
+
+        assert_eq!(request.with_version(NtpVersion::V4).version(), 4);
+        assert_eq!(NtpVersion::from_number(5), None);
+    }
+
+    #[test]
+    fn test_nonconformance() {
+        let (request, _) = NtpPacket::poll_message(PollInterval::default());
+        let mut base = vec![];
+        request.serialize(&mut base).unwrap();
+        let check = |data: &[u8]| NtpPacket::deserialize(data).unwrap().nonconformance();
+        assert_eq!(check(&base), None);
+
+        let mut data = base.clone();
+        data[0] &= !0x07;
+        assert_eq!(check(&data), Some(Nonconformance::MustBeZero));
+
+        // a crypto-NAK is a MAC of only a key id, which is zero
+        let mut data = base.clone();
+        data.extend_from_slice(&1u32.to_be_bytes());
+        assert_eq!(check(&data), Some(Nonconformance::MustBeZero));
+        let mut data = base.clone();
+        data.extend_from_slice(&0u32.to_be_bytes());
+        assert_eq!(check(&data), None);
+
+        let mut data = base.clone();
+        data.extend_from_slice(&[0; 4 + 12]);
+        assert_eq!(check(&data), Some(Nonconformance::Padding));
+
+        // an extension field of 18 bytes, followed by a MAC
+        let mut data = base.clone();
+        data.extend_from_slice(&[0x12, 0x34, 0, 18]);
+        data.extend_from_slice(&[0; 14]);
+        data.extend_from_slice(&[0; 4 + 16]);
+        assert_eq!(check(&data), Some(Nonconformance::Padding));
+
+        for (offset, value) in [(1, 17), (2, 0), (2, 18), (3, 1), (3, (-33i8) as u8)] {
+            let mut data = base.clone();
+            data[offset] = value;
+            assert_eq!(check(&data), Some(Nonconformance::OutOfRange));
+        }
+
+        let mut data = vec![];
+        NtpPacket::deny_response(request)
+            .serialize(&mut data)
+            .unwrap();
+        assert_eq!(check(&data), None);
+    }
+
     #[test]
     fn test_packed_flags() {
         let base = b"\x24\x02\x06\xe9\x00\x00\x02\x36\x00\x00\x03\xb7\xc0\x35\x67\x6c\xe5\xf6\x61\xfd\x6f\x16\x5f\x03\xe5\xf6\x63\xa8\x76\x19\xef\x40\xe5\xf6\x63\xa8\x79\x8c\x65\x81\xe5\xf6\x63\xa8\x79\x8e\xae\x2b".to_owned();
@@ -897,4 +1481,164 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_serialize_into() {
+        let packet = b"\x24\x02\x06\xe9\x00\x00\x02\x36\x00\x00\x03\xb7\xc0\x35\x67\x6c\xe5\xf6\x61\xfd\x6f\x16\x5f\x03\xe5\xf6\x63\xa8\x76\x19\xef\x40\xe5\xf6\x63\xa8\x79\x8c\x65\x81\xe5\xf6\x63\xa8\x79\x8e\xae\x2b";
+        let parsed = NtpPacket::deserialize(packet).unwrap();
+        assert_eq!(parsed.serialized_len(), 48);
+
+        let mut buf = [0xff; 64];
+        assert_eq!(parsed.serialize_into(&mut buf), Ok(48));
+        assert_eq!(buf[..48], packet[..]);
+        assert_eq!(buf[48..], [0xff; 16]);
+
+        assert_eq!(
+            parsed.serialize_into(&mut buf[..47]),
+            Err(PacketSerializationError::BufferTooSmall {
+                needed: 48,
+                available: 47
+            })
+        );
+    }
+
+    #[test]
+    fn test_extension_fields_and_mac() {
+        let mut packet = b"\x24\x02\x06\xe9\x00\x00\x02\x36\x00\x00\x03\xb7\xc0\x35\x67\x6c\xe5\xf6\x61\xfd\x6f\x16\x5f\x03\xe5\xf6\x63\xa8\x76\x19\xef\x40\xe5\xf6\x63\xa8\x79\x8c\x65\x81\xe5\xf6\x63\xa8\x79\x8e\xae\x2b".to_vec();
+        packet.extend_from_slice(&[0x01, 0x02, 0x00, 0x1c]);
+        packet.extend_from_slice(&[0xaa; 24]);
+
+        // extension fields that fill the packet, without a mac
+        let parsed = NtpPacket::deserialize(&packet).unwrap();
+        assert_eq!(parsed.mac, None);
+        let fields: Vec<_> = parsed.extension_fields().collect();
+        assert_eq!(fields.len(), 1);
+        assert_eq!(
+            *fields[0],
+            ExtensionField::Unknown {
+                typeid: 0x0102,
+                data: Cow::Borrowed(&[0xaa; 24]),
+            }
+        );
+
+        // followed by a mac
+        packet.extend_from_slice(&[0, 0, 0, 1, 0xbb, 0xbb, 0xbb, 0xbb]);
+        let parsed = NtpPacket::deserialize(&packet).unwrap();
+        assert_eq!(parsed.extension_fields().count(), 1);
+        assert_eq!(
+            parsed.mac,
+            Some(Mac {
+                keyid: 1,
+                mac: Cow::Borrowed(&[0xbb; 4]),
+            })
+        );
+
+        let mut buf = [0; 128];
+        assert_eq!(parsed.serialize_into(&mut buf), Ok(packet.len()));
+        assert_eq!(buf[..packet.len()], packet[..]);
+    }
+
+    #[test]
+    fn test_extension_field_limits() {
+        let mut packet = b"\x24\x02\x06\xe9\x00\x00\x02\x36\x00\x00\x03\xb7\xc0\x35\x67\x6c\xe5\xf6\x61\xfd\x6f\x16\x5f\x03\xe5\xf6\x63\xa8\x76\x19\xef\x40\xe5\xf6\x63\xa8\x79\x8c\x65\x81\xe5\xf6\x63\xa8\x79\x8e\xae\x2b".to_vec();
+        for _ in 0..3 {
+            packet.extend_from_slice(&[0x01, 0x04, 0x00, 0x1c]);
+            packet.extend_from_slice(&[0xaa; 24]);
+        }
+
+        let limits = |max_count, max_length| ExtensionFieldLimits {
+            max_count,
+            max_length,
+        };
+        let parsed = NtpPacket::deserialize_with_limits(&packet, limits(3, 28)).unwrap();
+        assert_eq!(parsed.extension_fields().count(), 3);
+        assert_eq!(
+            NtpPacket::deserialize_with_limits(&packet, limits(2, 28)),
+            Err(PacketParsingError::ExtensionFieldLimit)
+        );
+        assert_eq!(
+            NtpPacket::deserialize_with_limits(&packet, limits(3, 27)),
+            Err(PacketParsingError::ExtensionFieldLimit)
+        );
+        assert_eq!(
+            NtpPacket::deserialize_with_limits(&packet[..48], limits(0, 0))
+                .unwrap()
+                .serialized_len(),
+            48
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut packet = b"\x24\x02\x06\xe9\x00\x00\x02\x36\x00\x00\x03\xb7\xc0\x35\x67\x6c\xe5\xf6\x61\xfd\x6f\x16\x5f\x03\xe5\xf6\x63\xa8\x76\x19\xef\x40\xe5\xf6\x63\xa8\x79\x8c\x65\x81\xe5\xf6\x63\xa8\x79\x8e\xae\x2b".to_vec();
+        packet.extend_from_slice(&[0x01, 0x02, 0x00, 0x1c]);
+        packet.extend_from_slice(&[0xaa; 24]);
+
+        for len in 0..48 {
+            assert_eq!(
+                NtpPacket::deserialize(&packet[..len]),
+                Err(PacketParsingError::IncorrectLength)
+            );
+        }
+
+        // an extension field that claims more data than there is
+        packet[51] = 0x40;
+        assert_eq!(
+            NtpPacket::deserialize(&packet),
+            Err(PacketParsingError::IncorrectLength)
+        );
+    }
+
+    #[test]
+    fn test_validate_server_response() {
+        let (request, id) = NtpPacket::poll_message(PollInterval::default());
+
+        let mut deny = NtpPacket::deny_response(request.clone());
+        assert_eq!(deny.validate_server_response(id), Ok(()));
+
+        deny.set_origin_timestamp(NtpTimestamp::default());
+        assert_eq!(
+            deny.validate_server_response(id),
+            Err(ResponseValidationError::ZeroOrigin)
+        );
+
+        let mut response = NtpPacket::test();
+        response.set_mode(NtpAssociationMode::Server);
+        response.set_stratum(1);
+        response.set_origin_timestamp(request.transmit_timestamp());
+        assert_eq!(
+            response.validate_server_response(id),
+            Err(ResponseValidationError::ZeroReceive)
+        );
+
+        response.set_receive_timestamp(NtpTimestamp::from_fixed_int(100));
+        assert_eq!(
+            response.validate_server_response(id),
+            Err(ResponseValidationError::ZeroTransmit)
+        );
+
+        response.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
+        assert!(response.valid_server_response(id));
+    }
+
+    #[test]
+    fn test_poll_message_random_bits() {
+        let time = NtpTimestamp::from_fixed_int(0x1234_5678_9abc_def0);
+        let poll_interval = PollInterval::default();
+
+        let (packet, _) = NtpPacket::poll_message_at(poll_interval, time, 0);
+        assert_eq!(packet.transmit_timestamp(), time);
+
+        for _ in 0..8 {
+            let (packet, id) = NtpPacket::poll_message_at(poll_interval, time, 16);
+            let transmit = u64::from_be_bytes(packet.transmit_timestamp().to_bits());
+            assert_eq!(transmit >> 16, 0x1234_5678_9abc);
+            assert_eq!(id.expected_origin_timestamp, packet.transmit_timestamp());
+        }
+
+        // random bits beyond 64 make the whole timestamp random
+        let (a, _) = NtpPacket::poll_message_at(poll_interval, time, 255);
+        let (b, _) = NtpPacket::poll_message_at(poll_interval, time, 255);
+        assert_ne!(a.transmit_timestamp(), b.transmit_timestamp());
+    }
 }
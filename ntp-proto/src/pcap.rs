@@ -0,0 +1,472 @@
+//! Reading of packet captures, for decoding the NTP packets in them. Only the
+//! classic pcap format is read, with Ethernet or raw IP link layers. Packets
+//! that are not UDP to or from port 123, and IPv4 fragments, are skipped.
+//!
+//! The tests replay the captures in `testdata/pcap`, to lock in how packets of
+//! other implementations are handled: requests are answered as our server
+//! would, and responses are fed to a [`Peer`](crate::Peer) that sent the
+//! matching request, so that both ends of an exchange are exercised.
+
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use crate::NtpTimestamp;
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const PROTOCOL_UDP: u8 = 17;
+const NTP_PORT: u16 = 123;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcapError {
+    /// The file does not start with the magic number of a pcap file
+    UnknownFormat,
+    UnsupportedLinkType(u32),
+    /// The file ends in the middle of a record
+    Truncated,
+}
+
+impl Display for PcapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownFormat => f.write_str("Not a pcap file"),
+            Self::UnsupportedLinkType(link_type) => {
+                f.write_fmt(format_args!("Unsupported link type {}", link_type))
+            }
+            Self::Truncated => f.write_str("The capture is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for PcapError {}
+
+/// An NTP packet, as it was seen on the wire
+#[derive(Debug, Clone)]
+pub struct CapturedPacket {
+    /// Time of capture, by the clock of the machine that made the capture
+    pub time: NtpTimestamp,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    /// UDP payload, which is shorter than the packet that was sent when the
+    /// capture was made with a small snapshot length
+    pub data: Vec<u8>,
+}
+
+fn read_bytes<const N: usize>(data: &[u8], offset: usize) -> Option<[u8; N]> {
+    data.get(offset..offset + N)?.try_into().ok()
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    read_bytes(data, offset).map(u16::from_be_bytes)
+}
+
+/// Read the NTP packets of a capture, in the order in which they were captured
+pub fn parse_pcap(data: &[u8]) -> Result<Vec<CapturedPacket>, PcapError> {
+    let magic = read_bytes(data, 0).ok_or(PcapError::UnknownFormat)?;
+    let (big_endian, nanos) = match u32::from_le_bytes(magic) {
+        0xa1b2c3d4 => (false, false),
+        0xa1b23c4d => (false, true),
+        0xd4c3b2a1 => (true, false),
+        0x4d3cb2a1 => (true, true),
+        _ => return Err(PcapError::UnknownFormat),
+    };
+    let read_u32 = |offset: usize| match read_bytes(data, offset) {
+        Some(bytes) if big_endian => Ok(u32::from_be_bytes(bytes)),
+        Some(bytes) => Ok(u32::from_le_bytes(bytes)),
+        None => Err(PcapError::Truncated),
+    };
+
+    let link_type = read_u32(20)?;
+    if link_type != LINKTYPE_ETHERNET && link_type != LINKTYPE_RAW {
+        return Err(PcapError::UnsupportedLinkType(link_type));
+    }
+
+    let mut packets = vec![];
+    let mut offset = 24;
+    while offset < data.len() {
+        let seconds = read_u32(offset)?;
+        let fraction = read_u32(offset + 4)?;
+        let captured_len = read_u32(offset + 8)? as usize;
+        let frame = data
+            .get(offset + 16..offset + 16 + captured_len)
+            .ok_or(PcapError::Truncated)?;
+        offset += 16 + captured_len;
+
+        let ip = match link_type {
+            LINKTYPE_ETHERNET => match ethernet_payload(frame) {
+                Some(ip) => ip,
+                None => continue,
+            },
+            _ => frame,
+        };
+        let (source, destination, payload) = match ip_payload(ip).and_then(udp_payload) {
+            Some(udp) => udp,
+            None => continue,
+        };
+
+        let nanos = if nanos { fraction } else { fraction * 1000 };
+        packets.push(CapturedPacket {
+            time: NtpTimestamp::from_unix_seconds_nanos(seconds as i64, nanos),
+            source,
+            destination,
+            data: payload.to_vec(),
+        });
+    }
+
+    Ok(packets)
+}
+
+fn ethernet_payload(frame: &[u8]) -> Option<&[u8]> {
+    let mut offset = 12;
+    let mut ethertype = read_u16(frame, offset)?;
+    while ethertype == ETHERTYPE_VLAN {
+        offset += 4;
+        ethertype = read_u16(frame, offset)?;
+    }
+
+    match ethertype {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => frame.get(offset + 2..),
+        _ => None,
+    }
+}
+
+/// The addresses and the UDP datagram of an IP packet
+fn ip_payload(packet: &[u8]) -> Option<(IpAddr, IpAddr, &[u8])> {
+    match packet.first()? >> 4 {
+        4 => {
+            let header_len = (packet[0] & 0x0f) as usize * 4;
+            let total_len = read_u16(packet, 2)? as usize;
+            // a fragment, or the first part of a fragmented packet
+            if read_u16(packet, 6)? & 0x3fff != 0 || packet[9] != PROTOCOL_UDP {
+                return None;
+            }
+            let source = Ipv4Addr::from(read_bytes::<4>(packet, 12)?);
+            let destination = Ipv4Addr::from(read_bytes::<4>(packet, 16)?);
+            let end = total_len.min(packet.len());
+            Some((
+                source.into(),
+                destination.into(),
+                packet.get(header_len..end)?,
+            ))
+        }
+        6 => {
+            // extension headers are not followed
+            if *packet.get(6)? != PROTOCOL_UDP {
+                return None;
+            }
+            let payload_len = read_u16(packet, 4)? as usize;
+            let source = Ipv6Addr::from(read_bytes::<16>(packet, 8)?);
+            let destination = Ipv6Addr::from(read_bytes::<16>(packet, 24)?);
+            let end = (40 + payload_len).min(packet.len());
+            Some((source.into(), destination.into(), packet.get(40..end)?))
+        }
+        _ => None,
+    }
+}
+
+fn udp_payload(
+    (source, destination, datagram): (IpAddr, IpAddr, &[u8]),
+) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let source_port = read_u16(datagram, 0)?;
+    let destination_port = read_u16(datagram, 2)?;
+    if source_port != NTP_PORT && destination_port != NTP_PORT {
+        return None;
+    }
+
+    let end = (read_u16(datagram, 4)? as usize).min(datagram.len());
+    Some((
+        SocketAddr::new(source, source_port),
+        SocketAddr::new(destination, destination_port),
+        datagram.get(8..end)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use crate::{
+        IgnoreReason, NtpAssociationMode, NtpClock, NtpDuration, NtpInstant, NtpLeapIndicator,
+        NtpPacket, PacketParsingError, Peer, PeerBuilder, PollInterval, ResponseValidationError,
+        SystemConfig, SystemSnapshot, Update,
+    };
+
+    use super::*;
+
+    /// What became of a captured packet
+    #[derive(Debug)]
+    enum Outcome {
+        Malformed(PacketParsingError),
+        /// A request, with the response our server sends to it
+        Request(NtpPacket<'static>),
+        /// A response that the peer that sent the request used
+        Accepted(Update),
+        /// A response that the peer that sent the request ignored
+        Ignored(IgnoreReason),
+        /// A response to a request that is not in the capture
+        Unsolicited,
+        /// A packet in another mode than client or server
+        Other(NtpAssociationMode),
+    }
+
+    /// The clock of a server that answers at the time a request was captured
+    #[derive(Debug, Clone)]
+    struct CaptureClock(NtpTimestamp);
+
+    impl NtpClock for CaptureClock {
+        type Error = std::io::Error;
+
+        fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+            Ok(self.0)
+        }
+
+        fn set_freq(&self, _freq: f64) -> Result<(), Self::Error> {
+            Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+        }
+
+        fn step_clock(&self, _offset: NtpDuration) -> Result<(), Self::Error> {
+            Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+        }
+
+        fn update_clock(
+            &self,
+            _offset: NtpDuration,
+            _est_error: NtpDuration,
+            _max_error: NtpDuration,
+            _poll_interval: PollInterval,
+            _leap_status: NtpLeapIndicator,
+        ) -> Result<(), Self::Error> {
+            Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+        }
+    }
+
+    /// Feed the packets of a capture through the parser, our server and our
+    /// peers, in the order in which they were captured
+    fn replay(packets: &[CapturedPacket]) -> Vec<Outcome> {
+        // requests then carry exactly the transmit timestamp of the captured ones
+        let config = SystemConfig {
+            transmit_timestamp_random_bits: 0,
+            ..Default::default()
+        };
+        let system = SystemSnapshot::default();
+
+        let start = NtpInstant::now();
+        let first = match packets.first() {
+            Some(packet) => packet.time,
+            None => return vec![],
+        };
+
+        // peers by client and server address, with the time of their last request
+        let mut peers: HashMap<(SocketAddr, SocketAddr), (Peer, NtpTimestamp)> = HashMap::new();
+
+        packets
+            .iter()
+            .map(|packet| {
+                let since_first = (packet.time - first).to_seconds().max(0.0);
+                let now = start + Duration::from_secs_f64(since_first);
+
+                let message = match NtpPacket::deserialize(&packet.data) {
+                    Ok(message) => message,
+                    Err(error) => return Outcome::Malformed(error),
+                };
+
+                match message.mode() {
+                    NtpAssociationMode::Client => {
+                        let (peer, send_time) = peers
+                            .entry((packet.source, packet.destination))
+                            .or_insert_with(|| {
+                                let peer = PeerBuilder::from_addresses(
+                                    packet.source.ip(),
+                                    packet.destination.ip(),
+                                )
+                                .build(now, &config);
+                                (peer, packet.time)
+                            });
+                        peer.generate_poll_message(
+                            now,
+                            message.transmit_timestamp(),
+                            system,
+                            &config,
+                        );
+                        *send_time = packet.time;
+
+                        let clock = CaptureClock(packet.time);
+                        Outcome::Request(
+                            NtpPacket::timestamp_response(&system, message, packet.time, &clock)
+                                .into_owned(),
+                        )
+                    }
+                    NtpAssociationMode::Server => {
+                        match peers.get_mut(&(packet.destination, packet.source)) {
+                            Some((peer, send_time)) => match peer.handle_incoming(
+                                system,
+                                &config,
+                                message,
+                                now,
+                                *send_time,
+                                packet.time,
+                            ) {
+                                Ok(update) => Outcome::Accepted(update),
+                                Err(reason) => Outcome::Ignored(reason),
+                            },
+                            None => Outcome::Unsolicited,
+                        }
+                    }
+                    mode => Outcome::Other(mode),
+                }
+            })
+            .collect()
+    }
+
+    fn serialized(packet: &NtpPacket) -> Vec<u8> {
+        let mut data = vec![];
+        packet.serialize(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_ntpv3_client() {
+        let packets = parse_pcap(include_bytes!("../testdata/pcap/ntpv3-client.pcap")).unwrap();
+        assert_eq!(packets.len(), 8);
+
+        let outcomes = replay(&packets);
+        for (packet, outcome) in packets.iter().zip(&outcomes) {
+            assert_eq!(NtpPacket::deserialize(&packet.data).unwrap().version(), 3);
+
+            match outcome {
+                // version 3 requests are answered with version 3 responses
+                Outcome::Request(response) => {
+                    assert_eq!(response.version(), 3);
+                    assert_eq!(response.mode(), NtpAssociationMode::Server);
+                    assert_eq!(
+                        response.origin_timestamp(),
+                        NtpPacket::deserialize(&packet.data)
+                            .unwrap()
+                            .transmit_timestamp()
+                    );
+                }
+                Outcome::Accepted(_) => {}
+                outcome => panic!("unexpected outcome {outcome:?}"),
+            }
+        }
+
+        // the clock of the client is 5 ms behind that of the server
+        let snapshot = match outcomes.last() {
+            Some(Outcome::Accepted(Update::NewMeasurement(snapshot))) => snapshot,
+            outcome => panic!("unexpected outcome {outcome:?}"),
+        };
+        assert!((snapshot.statistics.offset.to_seconds() - 0.005).abs() < 0.0005);
+        assert_eq!(snapshot.stratum, 2);
+    }
+
+    #[test]
+    fn test_nts_exchange() {
+        let packets = parse_pcap(include_bytes!("../testdata/pcap/nts-exchange.pcap")).unwrap();
+        assert_eq!(packets.len(), 4);
+        assert!(packets.iter().all(|packet| packet.source.is_ipv6()));
+
+        for packet in &packets {
+            let message = NtpPacket::deserialize(&packet.data).unwrap();
+            let fields: Vec<u16> = message
+                .extension_fields()
+                .map(|field| match field.as_ref() {
+                    crate::packet::ExtensionField::Unknown { typeid, .. } => *typeid,
+                })
+                .collect();
+
+            // unique identifier, cookie, cookie placeholder and authenticator
+            // for requests, unique identifier and authenticator for responses
+            match message.mode() {
+                NtpAssociationMode::Client => {
+                    assert_eq!(fields, [0x0104, 0x0204, 0x0304, 0x0404])
+                }
+                _ => assert_eq!(fields, [0x0104, 0x0404]),
+            }
+            assert_eq!(message.key_id(), None);
+            assert_eq!(serialized(&message), packet.data);
+        }
+
+        // NTS is not supported: requests are answered without extension
+        // fields, and responses are used as if they were not protected
+        for outcome in replay(&packets) {
+            match outcome {
+                Outcome::Request(response) => {
+                    assert_eq!(response.extension_fields().count(), 0);
+                    assert_eq!(serialized(&response).len(), 48);
+                }
+                Outcome::Accepted(Update::NewMeasurement(snapshot)) => {
+                    assert!(!snapshot.authenticated);
+                    assert_eq!(snapshot.stratum, 1);
+                }
+                outcome => panic!("unexpected outcome {outcome:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed() {
+        let packets = parse_pcap(include_bytes!("../testdata/pcap/malformed.pcap")).unwrap();
+        // the packets that are not NTP are skipped
+        assert_eq!(packets.len(), 12);
+
+        let outcomes = replay(&packets);
+        assert!(matches!(
+            outcomes[..],
+            [
+                Outcome::Malformed(PacketParsingError::IncorrectLength),
+                Outcome::Malformed(PacketParsingError::InvalidVersion(0)),
+                Outcome::Malformed(PacketParsingError::InvalidVersion(5)),
+                Outcome::Malformed(PacketParsingError::IncorrectLength),
+                Outcome::Malformed(PacketParsingError::IncorrectLength),
+                Outcome::Malformed(PacketParsingError::IncorrectLength),
+                Outcome::Other(NtpAssociationMode::Broadcast),
+                Outcome::Request(_),
+                Outcome::Ignored(IgnoreReason::InvalidResponse(
+                    ResponseValidationError::ZeroOrigin
+                )),
+                Outcome::Ignored(IgnoreReason::InvalidStratum),
+                Outcome::Ignored(IgnoreReason::InvalidResponse(
+                    ResponseValidationError::ZeroReceive
+                )),
+                Outcome::Unsolicited,
+            ]
+        ));
+
+        // the capture was made with a small snapshot length
+        assert_eq!(packets[5].data.len(), 40);
+    }
+
+    #[test]
+    fn test_pcap_errors() {
+        let data = include_bytes!("../testdata/pcap/ntpv3-client.pcap");
+
+        assert_eq!(parse_pcap(&[]).unwrap_err(), PcapError::UnknownFormat);
+        assert_eq!(
+            parse_pcap(&data[4..]).unwrap_err(),
+            PcapError::UnknownFormat
+        );
+        assert_eq!(parse_pcap(&data[..20]).unwrap_err(), PcapError::Truncated);
+        assert_eq!(
+            parse_pcap(&data[..data.len() - 1]).unwrap_err(),
+            PcapError::Truncated
+        );
+
+        let mut linux_cooked = data.to_vec();
+        linux_cooked[20] = 113;
+        assert_eq!(
+            parse_pcap(&linux_cooked).unwrap_err(),
+            PcapError::UnsupportedLinkType(113)
+        );
+
+        // only the header of the capture
+        assert!(parse_pcap(&data[..24]).unwrap().is_empty());
+        assert!(replay(&[]).is_empty());
+    }
+}
@@ -1,16 +1,57 @@
 use crate::{
-    filter::{FilterTuple, LastMeasurements},
-    packet::{NtpAssociationMode, NtpLeapIndicator, RequestIdentifier},
-    time_types::{FrequencyTolerance, NtpInstant},
+    filter::{FilterTuple, LastMeasurements, SavedMeasurement},
+    packet::{
+        NtpAssociationMode, NtpLeapIndicator, NtpVersion, RequestIdentifier,
+        ResponseValidationError,
+    },
+    time_types::{FrequencyTolerance, NtpInstant, PollIntervalLimits},
     NtpDuration, NtpPacket, NtpTimestamp, PollInterval, ReferenceId, SystemConfig,
 };
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 use tracing::{debug, info, instrument, trace, warn};
 
 const MAX_STRATUM: u8 = 16;
 const POLL_WINDOW: std::time::Duration = std::time::Duration::from_secs(5);
 
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+/// Smallest number of requests sent in quick succession after a resume, or
+/// after the connection to the peer was replaced
+const RESUME_BURST: u8 = 4;
+
+/// How an association exchanges time with its remote
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum AssociationMode {
+    /// Poll a server, which does not synchronize to us
+    #[default]
+    Client,
+    /// Poll another server in symmetric active mode, telling it our own
+    /// state, such that both can synchronize to each other as a mutual
+    /// backup. The remote answers in symmetric passive mode.
+    SymmetricActive,
+}
+
+impl AssociationMode {
+    /// Whether a response in the given packet mode belongs to an
+    /// association in this mode
+    fn accepts(self, mode: NtpAssociationMode) -> bool {
+        match self {
+            AssociationMode::Client => mode == NtpAssociationMode::Server,
+            AssociationMode::SymmetricActive => matches!(
+                mode,
+                NtpAssociationMode::SymmetricPassive | NtpAssociationMode::SymmetricActive
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PeerStatistics {
     pub offset: NtpDuration,
     pub delay: NtpDuration,
@@ -19,6 +60,24 @@ pub struct PeerStatistics {
     pub jitter: f64,
 }
 
+/// The part of the state of a peer that is kept across restarts of the
+/// daemon, such that a restart does not start the clock filter, reachability
+/// and poll interval of the peer over. See [`Peer::state`] and
+/// [`Peer::restore`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PeerState {
+    /// Valid measurements in the clock filter, from new to old
+    pub measurements: Vec<SavedMeasurement>,
+    pub reach: Reach,
+    /// Poll interval from backing off while the peer was unreachable
+    pub backoff_interval: PollInterval,
+    /// Poll interval that the server asked for with a RATE kiss code
+    pub remote_min_poll_interval: PollInterval,
+}
+
+/// The state of the association with a single remote server. Create one with
+/// [`Peer::new`] or [`PeerBuilder`], and drive it with [`Peer::handle_event`].
 #[derive(Debug, Clone)]
 pub struct Peer {
     // Poll interval dictated by unreachability backoff
@@ -28,6 +87,28 @@ pub struct Peer {
     // The poll interval desired by the remove server.
     // Must be increased when the server sends the RATE kiss code.
     remote_min_poll_interval: PollInterval,
+    // Requests left in the initial burst
+    burst_remaining: u8,
+    // Consecutive requests that got no usable response, including those
+    // answered with a RATE kiss code
+    failures: u32,
+    // Measurements with a larger offset are discarded
+    max_offset: Option<NtpDuration>,
+    // Measurements with a larger delay are discarded
+    max_delay: Option<NtpDuration>,
+    // Measurements with a delay larger than this multiple of the smallest
+    // delay in the clock filter are discarded
+    max_delay_ratio: Option<f64>,
+    // Poll limits of this peer, instead of those of the system
+    poll_limits: Option<PollIntervalLimits>,
+    mode: AssociationMode,
+    // Whether the packets of the peer are authenticated before we see them
+    authenticated: bool,
+    // Highest NTP version to send requests in
+    version: NtpVersion,
+    // Version of the last accepted response. Requests are sent in this
+    // version when the server answered in a lower version than requested.
+    observed_version: Option<NtpVersion>,
 
     // Identifier of the last request sent to the server. This is correlated
     // with any received response from the server to guard against replay
@@ -50,7 +131,8 @@ pub struct Peer {
 /// As valid packets arrive, the rightmost bit is set to one.
 /// If the register contains any nonzero bits, the server is considered reachable;
 /// otherwise, it is unreachable.
-#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Reach(u8);
 
 impl std::fmt::Debug for Reach {
@@ -80,7 +162,7 @@ impl Reach {
 
     /// A packet received some number of poll intervals ago is decreasingly relevant for
     /// determining that a peer is still reachable. We discount the packets received so far.
-    fn poll(&mut self) {
+    pub(crate) fn poll(&mut self) {
         self.0 <<= 1
     }
 
@@ -93,9 +175,20 @@ impl Reach {
     pub fn reachability_score(&self) -> u32 {
         8 - self.0.trailing_zeros()
     }
+
+    /// Number of the last 8 polls that were answered
+    pub fn answered_polls(&self) -> u32 {
+        self.0.count_ones()
+    }
+
+    /// The raw shift register
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct SystemSnapshot {
     /// Desired poll interval
     pub poll_interval: PollInterval,
@@ -109,6 +202,9 @@ pub struct SystemSnapshot {
     pub root_dispersion: NtpDuration,
     /// Reference ID of current primary time source
     pub reference_id: ReferenceId,
+    /// Time at which the clock was last updated
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub reference_timestamp: NtpTimestamp,
     /// Current leap indicator state
     pub leap_indicator: NtpLeapIndicator,
     /// Total amount that the clock has stepped
@@ -126,6 +222,7 @@ impl Default for SystemSnapshot {
             root_delay: NtpDuration::ZERO,
             root_dispersion: NtpDuration::ZERO,
             reference_id: ReferenceId::NONE,
+            reference_timestamp: NtpTimestamp::default(),
             leap_indicator: NtpLeapIndicator::Unknown,
             accumulated_steps: NtpDuration::ZERO,
             accumulated_steps_threshold: None,
@@ -133,29 +230,91 @@ impl Default for SystemSnapshot {
     }
 }
 
-#[derive(Debug)]
+/// Why a packet received from a peer was not used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IgnoreReason {
     /// The association mode is not one that this peer supports
     InvalidMode,
-    /// The NTP version is not one that this implementation supports
+    /// The NTP version is not one that this implementation supports, or
+    /// higher than that of the request
     InvalidVersion,
     /// The stratum of the server is too high
     InvalidStratum,
-    /// The send time on the received packet is not the time we sent it at
-    InvalidPacketTime,
+    /// No request is outstanding, or its response came in too late
+    NoOutstandingRequest,
+    /// The packet is not a response to our outstanding request
+    InvalidResponse(ResponseValidationError),
     /// Received a Kiss-o'-Death https://datatracker.ietf.org/doc/html/rfc5905#section-7.4
     KissIgnore,
     /// Received a DENY or RSTR Kiss-o'-Death, and must demobilize the association
     KissDemobilize,
     /// The best packet is older than the peer's current time
     TooOld,
+    /// The offset of the measurement exceeds the maximum offset of the peer
+    OffsetTooLarge,
+    /// The delay of the measurement exceeds the maximum delay of the peer, or
+    /// the maximum ratio to its smallest recent delay
+    DelayTooLarge,
+    /// The server did not update its own clock for longer than the maximum
+    /// reference age for its stratum
+    StaleReference,
+}
+
+impl std::fmt::Display for IgnoreReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidMode => f.write_str("Invalid association mode"),
+            Self::InvalidVersion => f.write_str("Unsupported NTP version"),
+            Self::InvalidStratum => f.write_str("Invalid stratum"),
+            Self::NoOutstandingRequest => f.write_str("No outstanding request"),
+            Self::InvalidResponse(error) => write!(f, "Invalid response: {}", error),
+            Self::KissIgnore => f.write_str("Kiss-o'-Death"),
+            Self::KissDemobilize => f.write_str("Kiss-o'-Death demanding demobilization"),
+            Self::TooOld => f.write_str("Packet older than the current measurement"),
+            Self::OffsetTooLarge => f.write_str("Offset exceeds the maximum offset"),
+            Self::DelayTooLarge => f.write_str("Delay exceeds the maximum delay"),
+            Self::StaleReference => f.write_str("Reference timestamp too old"),
+        }
+    }
+}
+
+impl std::error::Error for IgnoreReason {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidResponse(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// What kind of source a snapshot is of, which matters to the clock selection
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum SourceKind {
+    /// A server or symmetric peer on the network
+    #[default]
+    Network,
+    /// A reference clock attached to this machine
+    RefClock {
+        /// Only set the clock when a network source agrees with the
+        /// reference clock
+        needs_corroboration: bool,
+    },
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PeerSnapshot {
     pub root_distance_without_time: NtpDuration,
     pub statistics: PeerStatistics,
 
+    /// Instants only have meaning within a process, so this is not
+    /// serialized, and deserialized as the current instant
+    #[cfg_attr(feature = "serde", serde(skip, default = "NtpInstant::now"))]
     pub time: NtpInstant,
     pub stratum: u8,
     pub peer_id: ReferenceId,
@@ -168,6 +327,22 @@ pub struct PeerSnapshot {
     pub leap_indicator: NtpLeapIndicator,
     pub root_delay: NtpDuration,
     pub root_dispersion: NtpDuration,
+
+    /// Whether the packets of the source are authenticated, or it is a
+    /// reference clock attached to this machine
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub authenticated: bool,
+
+    /// Consecutive requests that got no usable response
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub failures: u32,
+
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kind: SourceKind,
+
+    /// NTP version of the last accepted response of the source
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub version: Option<NtpVersion>,
 }
 
 impl PeerSnapshot {
@@ -186,7 +361,12 @@ impl PeerSnapshot {
         // A stratum error occurs if
         //     1: the server has never been synchronized,
         //     2: the server stratum is higher than the local stratum
-        if !self.leap_indicator.is_synchronized() || self.stratum >= local_stratum {
+        if !self.leap_indicator.is_synchronized() {
+            warn!("Peer rejected because it is not synchronized");
+            return Err(Unsynchronized);
+        }
+
+        if self.stratum >= local_stratum {
             warn!(
                 stratum = debug(self.stratum),
                 "Peer rejected due to invalid stratum"
@@ -231,7 +411,7 @@ impl PeerSnapshot {
         frequency_tolerance: FrequencyTolerance,
     ) -> NtpDuration {
         self.root_distance_without_time
-            + (NtpInstant::abs_diff(local_clock_time, self.time) * frequency_tolerance)
+            .saturating_add(NtpInstant::abs_diff(local_clock_time, self.time) * frequency_tolerance)
     }
 
     pub fn from_peer(peer: &Peer) -> Self {
@@ -248,40 +428,264 @@ impl PeerSnapshot {
             root_delay: peer.last_packet.root_delay(),
             root_dispersion: peer.last_packet.root_dispersion(),
             poll_interval: peer.last_poll_interval,
+            authenticated: peer.authenticated,
+            failures: peer.failures,
+            kind: SourceKind::Network,
+            version: peer.observed_version,
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Why a peer cannot be used to synchronize to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum AcceptSynchronizationError {
+    /// No recent packets were received from the peer
     ServerUnreachable,
+    /// The peer synchronizes to us
     Loop,
+    /// The root distance of the peer exceeds the distance threshold
     Distance,
+    /// The stratum of the peer is not below our local stratum
     Stratum,
+    /// The peer signals that its clock is not synchronized
+    Unsynchronized,
+}
+
+impl std::fmt::Display for AcceptSynchronizationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ServerUnreachable => f.write_str("Server unreachable"),
+            Self::Loop => f.write_str("Synchronization loop"),
+            Self::Distance => f.write_str("Root distance too large"),
+            Self::Stratum => f.write_str("Stratum too high"),
+            Self::Unsynchronized => f.write_str("Server not synchronized"),
+        }
+    }
 }
 
+impl std::error::Error for AcceptSynchronizationError {}
+
 #[derive(Debug)]
 pub enum Update {
     BareUpdate(PeerSnapshot),
     NewMeasurement(PeerSnapshot),
 }
 
-impl Peer {
-    #[instrument]
-    pub fn new(
-        our_id: ReferenceId,
-        peer_id: ReferenceId,
-        local_clock_time: NtpInstant,
-        system_config: &SystemConfig,
-    ) -> Self {
+/// Input to the peer state machine. The peer never reads a clock or touches
+/// the network itself: all time is passed in with the events, and everything
+/// the peer wants done is returned as [`PeerAction`]s.
+#[derive(Debug)]
+pub enum PeerEvent<'a> {
+    /// The poll timer fired, so the next request must be sent. `time` is the
+    /// current time of the local clock, which the transmit timestamp of the
+    /// request is derived from.
+    PollTimer { now: NtpInstant, time: NtpTimestamp },
+    /// A packet was received from the remote. `send_time` is the time at
+    /// which the last request was sent, and `recv_time` the time at which
+    /// this packet was received.
+    Packet {
+        packet: NtpPacket<'a>,
+        now: NtpInstant,
+        send_time: NtpTimestamp,
+        recv_time: NtpTimestamp,
+    },
+    /// The measurements were invalidated, for instance by a clock step
+    Reset,
+    /// The system resumed from suspend, so the measurements are stale and the
+    /// server may have been unreachable for a while. The measurements are
+    /// invalidated, and the peer polls right away, followed by a burst of
+    /// requests to reacquire the time quickly.
+    Resume { now: NtpInstant, time: NtpTimestamp },
+    /// The connection to the peer was replaced after requests went
+    /// unanswered, for instance because a NAT on the path forgot the mapping
+    /// of the previous one. The measurements are kept, and the peer polls
+    /// right away, followed by a burst of requests.
+    Reconnect { now: NtpInstant, time: NtpTimestamp },
+    /// The network reported that the requests can not be answered, for
+    /// instance because nothing listens on the port of the server. The poll
+    /// interval backs off faster than for requests that are just not
+    /// answered, as waiting for the server is pointless.
+    Unreachable,
+}
+
+/// Output of the peer state machine, to be performed by its driver
+#[derive(Debug)]
+pub enum PeerAction {
+    /// Send the packet to the remote
+    Send(NtpPacket<'static>),
+    /// Pass an updated snapshot of the peer on to the system
+    Update(Update),
+    /// (Re)start the poll timer, to fire this interval after the last request
+    /// was sent
+    SetTimer(PollInterval),
+    /// The received packet was not used
+    Ignore(IgnoreReason),
+    /// The remote asked us to stop, so the association must be removed
+    Demobilize,
+    /// The remote failed to answer too often in a row, so the association
+    /// should be replaced, resolving the address of the remote again
+    Replace,
+}
+
+/// The actions resulting from a single event, in the order in which they
+/// should be performed. Events cause only a few actions, so these are stored
+/// inline rather than on the heap.
+#[derive(Debug, Default)]
+pub struct PeerActions {
+    actions: [Option<PeerAction>; 3],
+    next: usize,
+}
+
+impl PeerActions {
+    fn new(actions: [Option<PeerAction>; 3]) -> Self {
+        PeerActions { actions, next: 0 }
+    }
+}
+
+impl Iterator for PeerActions {
+    type Item = PeerAction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(action) = self.actions.get_mut(self.next) {
+            self.next += 1;
+            if let Some(action) = action.take() {
+                return Some(action);
+            }
+        }
+        None
+    }
+}
+
+/// Builder for a [`Peer`], for associations that need more than the defaults
+/// of [`Peer::new`]
+#[derive(Debug, Clone, Copy)]
+pub struct PeerBuilder {
+    our_id: ReferenceId,
+    peer_id: ReferenceId,
+    initial_poll_interval: Option<PollInterval>,
+    poll_limits: Option<PollIntervalLimits>,
+    max_offset: Option<NtpDuration>,
+    max_delay: Option<NtpDuration>,
+    max_delay_ratio: Option<f64>,
+    mode: AssociationMode,
+    authenticated: bool,
+    version: NtpVersion,
+}
+
+impl PeerBuilder {
+    /// `our_id` identifies the local end of the association, and is used to
+    /// detect synchronization loops. `peer_id` identifies the remote.
+    pub fn new(our_id: ReferenceId, peer_id: ReferenceId) -> Self {
+        PeerBuilder {
+            our_id,
+            peer_id,
+            initial_poll_interval: None,
+            poll_limits: None,
+            max_offset: None,
+            max_delay: None,
+            max_delay_ratio: None,
+            mode: AssociationMode::Client,
+            authenticated: false,
+            version: NtpVersion::V4,
+        }
+    }
+
+    /// Identify both ends of the association by their addresses, the way the
+    /// reference id of a server is derived from its address
+    pub fn from_addresses(local: IpAddr, remote: IpAddr) -> Self {
+        Self::new(ReferenceId::from_ip(local), ReferenceId::from_ip(remote))
+    }
+
+    /// The poll interval to start with, instead of the minimum of the poll
+    /// limits. It is kept within the poll limits of the peer.
+    pub fn initial_poll_interval(mut self, poll_interval: PollInterval) -> Self {
+        self.initial_poll_interval = Some(poll_interval);
+        self
+    }
+
+    /// Poll limits for this peer, instead of the poll limits of the system
+    /// configuration. The poll interval of the system is kept within these
+    /// limits when polling this peer.
+    pub fn poll_limits(mut self, poll_limits: PollIntervalLimits) -> Self {
+        self.poll_limits = Some(poll_limits);
+        self
+    }
+
+    /// Discard measurements of which the offset exceeds `max_offset` in
+    /// either direction, so that a single broken or malicious server cannot
+    /// pull the clock far away. Such measurements are reported as
+    /// [`IgnoreReason::OffsetTooLarge`].
+    pub fn max_offset(mut self, max_offset: NtpDuration) -> Self {
+        self.max_offset = Some(max_offset);
+        self
+    }
+
+    /// Discard measurements of which the round trip delay exceeds
+    /// `max_delay`, as their offset suffered from queueing along the path.
+    /// Such measurements are reported as [`IgnoreReason::DelayTooLarge`].
+    pub fn max_delay(mut self, max_delay: NtpDuration) -> Self {
+        self.max_delay = Some(max_delay);
+        self
+    }
+
+    /// Discard measurements of which the round trip delay exceeds `ratio`
+    /// times the smallest delay among the measurements in the clock filter.
+    /// Such measurements are reported as [`IgnoreReason::DelayTooLarge`].
+    pub fn max_delay_ratio(mut self, ratio: f64) -> Self {
+        self.max_delay_ratio = Some(ratio);
+        self
+    }
+
+    /// The association mode, [`AssociationMode::Client`] by default
+    pub fn mode(mut self, mode: AssociationMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Mark the peer as authenticated, for when the packets of the peer are
+    /// authenticated before they are handed to it. This is taken into account
+    /// by the [`AuthenticationPolicy`](crate::AuthenticationPolicy) of the
+    /// system.
+    pub fn authenticated(mut self, authenticated: bool) -> Self {
+        self.authenticated = authenticated;
+        self
+    }
+
+    /// The highest NTP version to send requests in, [`NtpVersion::V4`] by
+    /// default. When the server answers in a lower version, later requests
+    /// are sent in that version.
+    pub fn version(mut self, version: NtpVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Create the peer. No request has been sent yet, so the first event for
+    /// the peer is normally [`PeerEvent::PollTimer`].
+    pub fn build(self, local_clock_time: NtpInstant, system_config: &SystemConfig) -> Peer {
+        let limits = self.poll_limits.unwrap_or(system_config.poll_limits);
+        let poll_interval = self
+            .initial_poll_interval
+            .map(|poll_interval| poll_interval.max(limits.min).min(limits.max))
+            .unwrap_or(limits.min);
+
         // we initialize with the current time so that we're in the correct epoch.
         let time = local_clock_time;
 
-        Self {
-            last_poll_interval: system_config.poll_limits.min,
-            backoff_interval: system_config.poll_limits.min,
-            remote_min_poll_interval: system_config.poll_limits.min,
+        Peer {
+            last_poll_interval: poll_interval,
+            backoff_interval: poll_interval,
+            remote_min_poll_interval: limits.min,
+            burst_remaining: system_config.initial_burst,
+            failures: 0,
+            max_offset: self.max_offset,
+            max_delay: self.max_delay,
+            max_delay_ratio: self.max_delay_ratio,
+            poll_limits: self.poll_limits,
+            mode: self.mode,
+            authenticated: self.authenticated,
+            version: self.version,
+            observed_version: None,
 
             current_request_identifier: None,
 
@@ -289,32 +693,232 @@ impl Peer {
             last_measurements: LastMeasurements::new(time),
             last_packet: Default::default(),
             time,
-            our_id,
-            peer_id,
+            our_id: self.our_id,
+            peer_id: self.peer_id,
             reach: Default::default(),
         }
     }
+}
+
+impl Peer {
+    /// A new association with the remote identified by `peer_id`, starting
+    /// at the minimum poll interval. See [`PeerBuilder`] for more options.
+    #[instrument]
+    pub fn new(
+        our_id: ReferenceId,
+        peer_id: ReferenceId,
+        local_clock_time: NtpInstant,
+        system_config: &SystemConfig,
+    ) -> Self {
+        PeerBuilder::new(our_id, peer_id).build(local_clock_time, system_config)
+    }
+
+    /// The statistics of the best recent measurement
+    pub fn statistics(&self) -> PeerStatistics {
+        self.statistics
+    }
+
+    /// Which of the recent requests were answered
+    pub fn reach(&self) -> Reach {
+        self.reach
+    }
+
+    pub fn our_id(&self) -> ReferenceId {
+        self.our_id
+    }
+
+    pub fn peer_id(&self) -> ReferenceId {
+        self.peer_id
+    }
+
+    /// The time of the measurement that the statistics are based on
+    pub fn time(&self) -> NtpInstant {
+        self.time
+    }
+
+    /// Whether a request was sent for which no response has been accepted yet
+    pub fn request_in_flight(&self) -> bool {
+        self.current_request_identifier.is_some()
+    }
+
+    /// Whether the packets of the peer are authenticated before we see them
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// The state to keep across a restart of the daemon
+    pub fn state(&self, now: NtpInstant) -> PeerState {
+        PeerState {
+            measurements: self.last_measurements.save(now),
+            reach: self.reach,
+            backoff_interval: self.backoff_interval,
+            remote_min_poll_interval: self.remote_min_poll_interval,
+        }
+    }
+
+    /// Continue from the state of a previous run of the daemon, which was
+    /// saved `downtime` before `now`. With restored measurements, the peer
+    /// skips its initial burst, as the clock filter is already filled.
+    pub fn restore(&mut self, state: &PeerState, now: NtpInstant, downtime: std::time::Duration) {
+        self.last_measurements = LastMeasurements::restore(&state.measurements, now, downtime);
+        self.reach = state.reach;
+        self.backoff_interval = state.backoff_interval;
+        self.remote_min_poll_interval = state.remote_min_poll_interval;
+
+        if !state.measurements.is_empty() {
+            self.burst_remaining = 0;
+        }
+    }
+
+    /// The state of the peer as used by clock selection
+    pub fn snapshot(&self) -> PeerSnapshot {
+        PeerSnapshot::from_peer(self)
+    }
 
     pub fn current_poll_interval(&self, system: SystemSnapshot) -> PollInterval {
-        system
-            .poll_interval
+        let poll_interval = match self.poll_limits {
+            Some(limits) => system.poll_interval.max(limits.min).min(limits.max),
+            None => system.poll_interval,
+        };
+
+        poll_interval
             .max(self.backoff_interval)
             .max(self.remote_min_poll_interval)
     }
 
+    /// The version to send requests in, which is lowered to that of the
+    /// responses of servers that only speak an older version
+    pub fn request_version(&self) -> NtpVersion {
+        match self.observed_version {
+            Some(observed) => observed.min(self.version),
+            None => self.version,
+        }
+    }
+
+    fn poll_limits(&self, system_config: &SystemConfig) -> PollIntervalLimits {
+        self.poll_limits.unwrap_or(system_config.poll_limits)
+    }
+
+    /// Time until the next request, which is shorter than the poll interval
+    /// during the initial burst
+    fn timer_interval(&self, system: SystemSnapshot) -> PollInterval {
+        match self.burst_remaining {
+            0 => self.current_poll_interval(system),
+            _ => PollInterval::BURST,
+        }
+    }
+
+    /// Process an event, returning what the driver of the peer should do
+    pub fn handle_event(
+        &mut self,
+        system: SystemSnapshot,
+        system_config: &SystemConfig,
+        event: PeerEvent,
+    ) -> PeerActions {
+        match event {
+            PeerEvent::PollTimer { now, time } => {
+                // the previous request is still outstanding when it was not
+                // answered, or answered with a kiss code
+                if self.current_request_identifier.is_some() {
+                    self.failures += 1;
+                }
+
+                if system_config.max_failures != 0 && self.failures >= system_config.max_failures {
+                    warn!(
+                        failures = self.failures,
+                        "Peer failed too often, replacing it"
+                    );
+                    return PeerActions::new([Some(PeerAction::Replace), None, None]);
+                }
+
+                let packet = self.generate_poll_message(now, time, system, system_config);
+                PeerActions::new([
+                    Some(PeerAction::Send(packet)),
+                    Some(PeerAction::Update(Update::BareUpdate(
+                        PeerSnapshot::from_peer(self),
+                    ))),
+                    Some(PeerAction::SetTimer(self.timer_interval(system))),
+                ])
+            }
+            PeerEvent::Packet {
+                packet,
+                now,
+                send_time,
+                recv_time,
+            } => {
+                let action = match self.handle_incoming(
+                    system,
+                    system_config,
+                    packet,
+                    now,
+                    send_time,
+                    recv_time,
+                ) {
+                    Ok(update) => PeerAction::Update(update),
+                    Err(IgnoreReason::KissDemobilize) => PeerAction::Demobilize,
+                    Err(reason) => PeerAction::Ignore(reason),
+                };
+
+                // the packet may have changed the poll interval
+                PeerActions::new([
+                    Some(action),
+                    Some(PeerAction::SetTimer(self.timer_interval(system))),
+                    None,
+                ])
+            }
+            PeerEvent::Reset => {
+                self.reset_measurements();
+                PeerActions::default()
+            }
+            PeerEvent::Resume { now, time } => {
+                self.reset_measurements();
+                self.backoff_interval = self.poll_limits(system_config).min;
+                self.failures = 0;
+                self.burst_remaining = system_config.initial_burst.max(RESUME_BURST);
+                self.handle_event(system, system_config, PeerEvent::PollTimer { now, time })
+            }
+            PeerEvent::Reconnect { now, time } => {
+                self.backoff_interval = self.poll_limits(system_config).min;
+                self.burst_remaining = system_config.initial_burst.max(RESUME_BURST);
+                self.handle_event(system, system_config, PeerEvent::PollTimer { now, time })
+            }
+            PeerEvent::Unreachable => {
+                // on top of the backoff for sending the request
+                self.backoff_interval = self.backoff_interval.inc(self.poll_limits(system_config));
+                self.burst_remaining = 0;
+                PeerActions::new([
+                    Some(PeerAction::SetTimer(self.timer_interval(system))),
+                    None,
+                    None,
+                ])
+            }
+        }
+    }
+
     pub fn generate_poll_message(
         &mut self,
+        now: NtpInstant,
+        time: NtpTimestamp,
         system: SystemSnapshot,
         system_config: &SystemConfig,
     ) -> NtpPacket<'static> {
         self.reach.poll();
 
         let poll_interval = self.current_poll_interval(system);
-        let (packet, identifier) = NtpPacket::poll_message(poll_interval);
-        self.current_request_identifier = Some((identifier, NtpInstant::now() + POLL_WINDOW));
+        let random_bits = system_config.transmit_timestamp_random_bits;
+        let (packet, identifier) = match self.mode {
+            AssociationMode::Client => NtpPacket::poll_message_at(poll_interval, time, random_bits),
+            AssociationMode::SymmetricActive => {
+                NtpPacket::symmetric_poll_message_at(&system, poll_interval, time, random_bits)
+            }
+        };
+        let packet = packet.with_version(self.request_version());
+        self.current_request_identifier = Some((identifier, now + POLL_WINDOW));
+        self.last_poll_interval = poll_interval;
+        self.burst_remaining = self.burst_remaining.saturating_sub(1);
 
         // Ensure we don't spam the remote with polls if it is not reachable
-        self.backoff_interval = poll_interval.inc(system_config.poll_limits);
+        self.backoff_interval = poll_interval.inc(self.poll_limits(system_config));
 
         packet
     }
@@ -331,28 +935,36 @@ impl Peer {
         recv_time: NtpTimestamp,
     ) -> Result<Update, IgnoreReason> {
         let request_identifier = match self.current_request_identifier {
-            Some((next_expected_origin, validity)) if validity >= NtpInstant::now() => {
+            Some((next_expected_origin, validity)) if validity >= local_clock_time => {
                 next_expected_origin
             }
             _ => {
                 debug!("Received old/unexpected packet from peer");
-                return Err(IgnoreReason::InvalidPacketTime);
+                return Err(IgnoreReason::NoOutstandingRequest);
             }
         };
 
-        if !message.valid_server_response(request_identifier) {
+        if let Err(error) = message.validate_server_response(request_identifier) {
             // Packets should be a response to a previous request from us,
             // if not just ignore. Note that this might also happen when
             // we reset between sending the request and receiving the response.
             // We do this as the first check since accepting even a KISS
             // packet that is not a response will leave us vulnerable
             // to denial of service attacks.
-            debug!("Received old/unexpected packet from peer");
-            Err(IgnoreReason::InvalidPacketTime)
+            debug!(%error, "Received old/unexpected packet from peer");
+            Err(IgnoreReason::InvalidResponse(error))
+        } else if message.ntp_version() > self.request_version() {
+            // servers answer in the version of the request, or a lower one
+            warn!(
+                version = message.version(),
+                "Received response in a higher NTP version than requested"
+            );
+            Err(IgnoreReason::InvalidVersion)
         } else if message.is_kiss_rate() {
             // KISS packets may not have correct timestamps at all, handle them anyway
             self.remote_min_poll_interval = Ord::max(
-                self.remote_min_poll_interval.inc(system_config.poll_limits),
+                self.remote_min_poll_interval
+                    .inc(self.poll_limits(system_config)),
                 self.last_poll_interval,
             );
             warn!(?self.remote_min_poll_interval, "Peer requested rate limit");
@@ -365,6 +977,13 @@ impl Peer {
             warn!("Unrecognized KISS Message from peer");
             // Ignore unrecognized control messages
             Err(IgnoreReason::KissIgnore)
+        } else if message.transmit_timestamp() == self.last_packet.transmit_timestamp() {
+            // A response to a new request can never carry the transmit
+            // timestamp of an earlier response, unless it is a replay.
+            warn!("Received a duplicate of the previous response");
+            Err(IgnoreReason::InvalidResponse(
+                ResponseValidationError::DuplicateTransmit,
+            ))
         } else if message.stratum() > MAX_STRATUM {
             // A servers stratum should be between 1 and MAX_STRATUM (16) inclusive.
             warn!(
@@ -372,22 +991,29 @@ impl Peer {
                 message.stratum()
             );
             Err(IgnoreReason::InvalidStratum)
-        } else if message.mode() != NtpAssociationMode::Server {
-            // we currently only support a client <-> server association
-            warn!("Received packet with invalid mode");
+        } else if !self.mode.accepts(message.mode()) {
+            warn!(mode = ?message.mode(), "Received packet with invalid mode");
             Err(IgnoreReason::InvalidMode)
         } else {
-            Ok(self.process_message(
+            self.process_message(
                 system,
                 system_config,
                 message,
                 local_clock_time,
                 send_time,
                 recv_time,
-            ))
+            )
         }
     }
 
+    fn delay_too_large(&self, delay: NtpDuration) -> bool {
+        let above_ratio = match (self.max_delay_ratio, self.last_measurements.min_delay()) {
+            (Some(ratio), Some(min_delay)) => delay.to_seconds() > ratio * min_delay.to_seconds(),
+            _ => false,
+        };
+        above_ratio || matches!(self.max_delay, Some(max_delay) if delay > max_delay)
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn process_message(
         &mut self,
@@ -397,17 +1023,39 @@ impl Peer {
         local_clock_time: NtpInstant,
         send_time: NtpTimestamp,
         recv_time: NtpTimestamp,
-    ) -> Update {
+    ) -> Result<Update, IgnoreReason> {
         trace!("Packet accepted for processing");
         // For reachability, mark that we have had a response
         self.reach.received_packet();
 
+        let version = message.ntp_version();
+        if version < self.request_version() {
+            info!(%version, "Server answers in an older NTP version, using that from now on");
+        }
+        self.observed_version = Some(version);
+
         // Got a response, so no need for unreachability backoff
-        self.backoff_interval = system_config.poll_limits.min;
+        self.backoff_interval = self.poll_limits(system_config).min;
+        self.failures = 0;
 
         // we received this packet, and don't want to accept future ones with this next_expected_origin
         self.current_request_identifier = None;
 
+        // a server that lost its own sources keeps answering with the time at
+        // which it last updated its clock. Servers that never did send zero,
+        // and are left to the other checks.
+        let reference_timestamp = message.reference_timestamp();
+        if let Some(max_age) = system_config
+            .max_reference_age
+            .for_stratum(message.stratum())
+        {
+            let age = message.transmit_timestamp() - reference_timestamp;
+            if reference_timestamp != NtpTimestamp::default() && age > max_age {
+                warn!(?age, "Discarding packet with a stale reference timestamp");
+                return Err(IgnoreReason::StaleReference);
+            }
+        }
+
         let filter_input = FilterTuple::from_packet_default(
             &message,
             system.precision,
@@ -417,8 +1065,27 @@ impl Peer {
             recv_time,
         );
 
+        // the server did respond, but its time is not believable
+        if let Some(max_offset) = self.max_offset {
+            if filter_input.offset().abs() > max_offset {
+                warn!(offset = ?filter_input.offset(), "Discarding measurement with excessive offset");
+                return Err(IgnoreReason::OffsetTooLarge);
+            }
+        }
+
+        // the offset of a measurement that was held up along the way is off
+        // by up to half of the extra delay
+        if self.delay_too_large(filter_input.delay()) {
+            debug!(delay = ?filter_input.delay(), "Discarding measurement with excessive delay");
+            return Err(IgnoreReason::DelayTooLarge);
+        }
+
         self.last_packet = message.into_owned();
 
+        if let Some(max_age) = system_config.filter_max_age {
+            self.last_measurements.expire(local_clock_time, max_age);
+        }
+
         let updated = self.last_measurements.step(
             filter_input,
             self.time,
@@ -428,12 +1095,12 @@ impl Peer {
         );
 
         match updated {
-            None => Update::BareUpdate(PeerSnapshot::from_peer(self)),
+            None => Ok(Update::BareUpdate(PeerSnapshot::from_peer(self))),
             Some((statistics, smallest_delay_time)) => {
                 self.statistics = statistics;
                 self.time = smallest_delay_time;
 
-                Update::NewMeasurement(PeerSnapshot::from_peer(self))
+                Ok(Update::NewMeasurement(PeerSnapshot::from_peer(self)))
             }
         }
     }
@@ -449,16 +1116,21 @@ impl Peer {
         frequency_tolerance: FrequencyTolerance,
     ) -> NtpDuration {
         self.root_distance_without_time()
-            + NtpInstant::abs_diff(local_clock_time, self.time) * frequency_tolerance
+            .saturating_add(NtpInstant::abs_diff(local_clock_time, self.time) * frequency_tolerance)
     }
 
     /// Root distance without the `(local_clock_time - self.time) * PHI` term
     fn root_distance_without_time(&self) -> NtpDuration {
-        NtpDuration::MIN_DISPERSION.max(self.last_packet.root_delay() + self.statistics.delay)
-            / 2i64
-            + self.last_packet.root_dispersion()
-            + self.statistics.dispersion
-            + NtpDuration::from_seconds(self.statistics.jitter)
+        // the root delay and dispersion come from the packet, and can be anything
+        let root_delay = self
+            .last_packet
+            .root_delay()
+            .saturating_add(self.statistics.delay);
+
+        (NtpDuration::MIN_DISPERSION.max(root_delay) / 2i64)
+            .saturating_add(self.last_packet.root_dispersion())
+            .saturating_add(self.statistics.dispersion)
+            .saturating_add(NtpDuration::from_seconds(self.statistics.jitter))
     }
 
     /// reset just the measurement data, the poll and connection data is unchanged
@@ -480,6 +1152,16 @@ impl Peer {
             last_poll_interval: PollInterval::default(),
             backoff_interval: PollInterval::default(),
             remote_min_poll_interval: PollInterval::default(),
+            burst_remaining: 0,
+            failures: 0,
+            max_offset: None,
+            max_delay: None,
+            max_delay_ratio: None,
+            poll_limits: None,
+            mode: AssociationMode::Client,
+            authenticated: false,
+            version: NtpVersion::V4,
+            observed_version: None,
 
             current_request_identifier: None,
 
@@ -610,6 +1292,44 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_root_distance_saturates() {
+        let now = NtpInstant::now();
+        let ft = FrequencyTolerance::ppm(1_000_000);
+
+        // the largest values a server can put in a packet
+        let mut packet = NtpPacket::test();
+        packet.set_root_delay(NtpDuration::from_bits_short([0xFF; 4]));
+        packet.set_root_dispersion(NtpDuration::from_bits_short([0xFF; 4]));
+        let peer = Peer {
+            statistics: PeerStatistics {
+                delay: NtpDuration::MAX,
+                dispersion: NtpDuration::MAX,
+                jitter: f64::INFINITY,
+                ..Default::default()
+            },
+            last_packet: packet,
+            ..Peer::test_peer(now)
+        };
+
+        let later = now + std::time::Duration::from_secs(1 << 40);
+        assert_eq!(peer.root_distance(later, ft), NtpDuration::MAX);
+        assert_eq!(
+            PeerSnapshot::from_peer(&peer).root_distance(later, ft),
+            NtpDuration::MAX
+        );
+
+        // the extreme values still fit in a packet
+        let mut buffer = [0; 48];
+        let mut response = NtpPacket::test();
+        response.set_root_delay(peer.root_distance(later, ft));
+        response.set_root_dispersion(-peer.root_distance(later, ft));
+        response.serialize_into(&mut buffer).unwrap();
+        let parsed = NtpPacket::deserialize(&buffer).unwrap();
+        assert_eq!(parsed.root_delay(), NtpDuration::from_bits_short([0xFF; 4]));
+        assert_eq!(parsed.root_dispersion(), NtpDuration::ZERO);
+    }
+
     #[test]
     fn reachability() {
         let mut reach = Reach::default();
@@ -670,7 +1390,7 @@ mod test {
         assert_eq!(accept!(), Ok(()));
 
         peer.last_packet.set_leap(NtpLeapIndicator::Unknown);
-        assert_eq!(accept!(), Err(Stratum));
+        assert_eq!(accept!(), Err(Unsynchronized));
 
         peer.last_packet.set_leap(NtpLeapIndicator::NoWarning);
         peer.last_packet.set_stratum(42);
@@ -705,12 +1425,19 @@ mod test {
         peer.remote_min_poll_interval = PollIntervalLimits::default().min;
 
         let prev = peer.current_poll_interval(system);
-        let packet = peer.generate_poll_message(system, &SystemConfig::default());
+        let packet = peer.generate_poll_message(
+            base,
+            NtpTimestamp::default(),
+            system,
+            &SystemConfig::default(),
+        );
         assert!(peer.current_poll_interval(system) > prev);
         let mut response = NtpPacket::test();
         response.set_mode(NtpAssociationMode::Server);
         response.set_stratum(1);
         response.set_origin_timestamp(packet.transmit_timestamp());
+        response.set_receive_timestamp(NtpTimestamp::from_fixed_int(100));
+        response.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
         assert!(peer
             .handle_incoming(
                 system,
@@ -724,7 +1451,12 @@ mod test {
         assert_eq!(peer.current_poll_interval(system), prev);
 
         let prev = peer.current_poll_interval(system);
-        let packet = peer.generate_poll_message(system, &SystemConfig::default());
+        let packet = peer.generate_poll_message(
+            base,
+            NtpTimestamp::default(),
+            system,
+            &SystemConfig::default(),
+        );
         assert!(peer.current_poll_interval(system) > prev);
         let mut response = NtpPacket::test();
         response.set_mode(NtpAssociationMode::Server);
@@ -751,7 +1483,12 @@ mod test {
         let mut peer = Peer::test_peer(base);
 
         let system = SystemSnapshot::default();
-        let outgoing = peer.generate_poll_message(system, &SystemConfig::default());
+        let outgoing = peer.generate_poll_message(
+            base,
+            NtpTimestamp::default(),
+            system,
+            &SystemConfig::default(),
+        );
         let mut packet = NtpPacket::test();
         let system = SystemSnapshot::default();
         packet.set_stratum(1);
@@ -771,8 +1508,8 @@ mod test {
             )
             .is_ok());
         assert_eq!(peer.last_packet, packet);
-        assert!(peer
-            .handle_incoming(
+        assert_eq!(
+            peer.handle_incoming(
                 system,
                 &SystemConfig::default(),
                 packet,
@@ -780,7 +1517,111 @@ mod test {
                 NtpTimestamp::from_fixed_int(0),
                 NtpTimestamp::from_fixed_int(500)
             )
-            .is_err());
+            .unwrap_err(),
+            IgnoreReason::NoOutstandingRequest
+        );
+    }
+
+    #[test]
+    fn test_ignore_reasons() {
+        let base = NtpInstant::now();
+        let mut peer = Peer::test_peer(base);
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+
+        let outgoing = peer.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+        let mut packet = NtpPacket::test();
+        packet.set_stratum(1);
+        packet.set_mode(NtpAssociationMode::Server);
+        packet.set_origin_timestamp(outgoing.transmit_timestamp() + NtpDuration::ONE);
+        packet.set_receive_timestamp(NtpTimestamp::from_fixed_int(100));
+        packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
+
+        let mut incoming = |packet: &NtpPacket| {
+            peer.handle_incoming(
+                system,
+                &config,
+                packet.clone(),
+                base + Duration::from_secs(1),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            )
+            .unwrap_err()
+        };
+
+        let reason = incoming(&packet);
+        assert_eq!(
+            reason,
+            IgnoreReason::InvalidResponse(ResponseValidationError::OriginMismatch)
+        );
+        assert_eq!(
+            reason.to_string(),
+            "Invalid response: Origin timestamp does not match the request"
+        );
+
+        packet.set_origin_timestamp(outgoing.transmit_timestamp());
+        packet.set_mode(NtpAssociationMode::Client);
+        assert_eq!(incoming(&packet), IgnoreReason::InvalidMode);
+
+        packet.set_mode(NtpAssociationMode::Server);
+        packet.set_stratum(MAX_STRATUM + 1);
+        assert_eq!(incoming(&packet), IgnoreReason::InvalidStratum);
+    }
+
+    #[test]
+    fn test_timestamp_sanity() {
+        let base = NtpInstant::now();
+        let mut peer = Peer::test_peer(base);
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+
+        let incoming = |peer: &mut Peer, packet: &NtpPacket| {
+            peer.handle_incoming(
+                system,
+                &config,
+                packet.clone(),
+                base + Duration::from_secs(1),
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            )
+        };
+
+        let outgoing = peer.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+        let mut packet = NtpPacket::test();
+        packet.set_stratum(1);
+        packet.set_mode(NtpAssociationMode::Server);
+        let invalid = |error| Err(IgnoreReason::InvalidResponse(error));
+
+        assert_eq!(
+            incoming(&mut peer, &packet).map(|_| ()),
+            invalid(ResponseValidationError::ZeroOrigin)
+        );
+
+        packet.set_origin_timestamp(outgoing.transmit_timestamp());
+        assert_eq!(
+            incoming(&mut peer, &packet).map(|_| ()),
+            invalid(ResponseValidationError::ZeroReceive)
+        );
+
+        packet.set_receive_timestamp(NtpTimestamp::from_fixed_int(100));
+        assert_eq!(
+            incoming(&mut peer, &packet).map(|_| ()),
+            invalid(ResponseValidationError::ZeroTransmit)
+        );
+
+        packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
+        assert!(incoming(&mut peer, &packet).is_ok());
+
+        // a response to the next request with the same transmit timestamp is a replay
+        let outgoing = peer.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+        packet.set_origin_timestamp(outgoing.transmit_timestamp());
+        assert_eq!(
+            incoming(&mut peer, &packet).map(|_| ()),
+            invalid(ResponseValidationError::DuplicateTransmit)
+        );
+
+        packet.set_transmit_timestamp(NtpTimestamp::from_fixed_int(300));
+        assert!(incoming(&mut peer, &packet).is_ok());
     }
 
     #[test]
@@ -789,7 +1630,12 @@ mod test {
         let mut peer = Peer::test_peer(base);
 
         let system = SystemSnapshot::default();
-        let outgoing = peer.generate_poll_message(system, &SystemConfig::default());
+        let outgoing = peer.generate_poll_message(
+            base,
+            NtpTimestamp::default(),
+            system,
+            &SystemConfig::default(),
+        );
         let mut packet = NtpPacket::test();
         let system = SystemSnapshot::default();
         packet.set_stratum(MAX_STRATUM + 1);
@@ -844,7 +1690,12 @@ mod test {
 
         let mut packet = NtpPacket::test();
         let system = SystemSnapshot::default();
-        let outgoing = peer.generate_poll_message(system, &SystemConfig::default());
+        let outgoing = peer.generate_poll_message(
+            base,
+            NtpTimestamp::default(),
+            system,
+            &SystemConfig::default(),
+        );
         packet.set_reference_id(ReferenceId::KISS_RSTR);
         packet.set_origin_timestamp(outgoing.transmit_timestamp());
         packet.set_mode(NtpAssociationMode::Server);
@@ -878,7 +1729,12 @@ mod test {
 
         let mut packet = NtpPacket::test();
         let system = SystemSnapshot::default();
-        let outgoing = peer.generate_poll_message(system, &SystemConfig::default());
+        let outgoing = peer.generate_poll_message(
+            base,
+            NtpTimestamp::default(),
+            system,
+            &SystemConfig::default(),
+        );
         packet.set_reference_id(ReferenceId::KISS_DENY);
         packet.set_origin_timestamp(outgoing.transmit_timestamp());
         packet.set_mode(NtpAssociationMode::Server);
@@ -910,14 +1766,21 @@ mod test {
                 NtpTimestamp::from_fixed_int(100)
             )
             .is_err());
-        assert_eq!(peer.remote_min_poll_interval, old_poll_interval);
+        // a kiss that is not a response to our request changes nothing
+        assert_eq!(peer.remote_min_poll_interval, PollInterval::default());
         assert_eq!(peer.remote_min_poll_interval, old_remote_interval);
+        assert_eq!(peer.last_poll_interval, old_poll_interval);
 
         let old_poll_interval = peer.last_poll_interval;
         let old_remote_interval = peer.remote_min_poll_interval;
         let mut packet = NtpPacket::test();
         let system = SystemSnapshot::default();
-        let outgoing = peer.generate_poll_message(system, &SystemConfig::default());
+        let outgoing = peer.generate_poll_message(
+            base,
+            NtpTimestamp::default(),
+            system,
+            &SystemConfig::default(),
+        );
         packet.set_reference_id(ReferenceId::KISS_RATE);
         packet.set_origin_timestamp(outgoing.transmit_timestamp());
         packet.set_mode(NtpAssociationMode::Server);
@@ -934,4 +1797,639 @@ mod test {
         assert!(peer.remote_min_poll_interval > old_poll_interval);
         assert!(peer.remote_min_poll_interval >= old_remote_interval);
     }
+
+    #[test]
+    fn test_poll_message_interval() {
+        let base = NtpInstant::now();
+        let mut peer = Peer::test_peer(base);
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+
+        // requests that go unanswered back off, and the interval of the last
+        // one sent is the one of the peer
+        for log in [4, 5, 6] {
+            let packet = peer.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+            assert_eq!(packet.poll(), log);
+            assert_eq!(peer.last_poll_interval.as_log(), log);
+        }
+    }
+
+    #[test]
+    fn test_handle_event() {
+        let base = NtpInstant::now();
+        let mut peer = Peer::test_peer(base);
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+
+        let mut actions = peer.handle_event(
+            system,
+            &config,
+            PeerEvent::PollTimer {
+                now: base,
+                time: NtpTimestamp::default(),
+            },
+        );
+        let request = match actions.next() {
+            Some(PeerAction::Send(packet)) => packet,
+            other => panic!("expected a packet to send, got {:?}", other),
+        };
+        assert!(matches!(
+            actions.next(),
+            Some(PeerAction::Update(Update::BareUpdate(_)))
+        ));
+        assert!(matches!(actions.next(), Some(PeerAction::SetTimer(_))));
+        assert!(actions.next().is_none());
+
+        let mut response = NtpPacket::test();
+        response.set_mode(NtpAssociationMode::Server);
+        response.set_stratum(1);
+        response.set_origin_timestamp(request.transmit_timestamp());
+        response.set_receive_timestamp(NtpTimestamp::from_fixed_int(100));
+        response.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
+
+        // responses are only accepted for a while after sending the request
+        let late = PeerEvent::Packet {
+            packet: response.clone(),
+            now: base + POLL_WINDOW + Duration::from_secs(1),
+            send_time: NtpTimestamp::from_fixed_int(0),
+            recv_time: NtpTimestamp::from_fixed_int(100),
+        };
+        let mut actions = peer.handle_event(system, &config, late);
+        assert!(matches!(
+            actions.next(),
+            Some(PeerAction::Ignore(IgnoreReason::NoOutstandingRequest))
+        ));
+
+        let event = PeerEvent::Packet {
+            packet: response,
+            now: base + Duration::from_secs(1),
+            send_time: NtpTimestamp::from_fixed_int(0),
+            recv_time: NtpTimestamp::from_fixed_int(100),
+        };
+        let mut actions = peer.handle_event(system, &config, event);
+        assert!(matches!(actions.next(), Some(PeerAction::Update(_))));
+        assert!(matches!(actions.next(), Some(PeerAction::SetTimer(_))));
+        assert!(actions.next().is_none());
+
+        let mut actions = peer.handle_event(
+            system,
+            &config,
+            PeerEvent::PollTimer {
+                now: base,
+                time: NtpTimestamp::default(),
+            },
+        );
+        let request = match actions.next() {
+            Some(PeerAction::Send(packet)) => packet,
+            other => panic!("expected a packet to send, got {:?}", other),
+        };
+        let mut deny = NtpPacket::test();
+        deny.set_mode(NtpAssociationMode::Server);
+        deny.set_reference_id(ReferenceId::KISS_DENY);
+        deny.set_origin_timestamp(request.transmit_timestamp());
+        let event = PeerEvent::Packet {
+            packet: deny,
+            now: base + Duration::from_secs(1),
+            send_time: NtpTimestamp::from_fixed_int(0),
+            recv_time: NtpTimestamp::from_fixed_int(100),
+        };
+        let mut actions = peer.handle_event(system, &config, event);
+        assert!(matches!(actions.next(), Some(PeerAction::Demobilize)));
+
+        assert!(peer
+            .handle_event(system, &config, PeerEvent::Reset)
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    fn test_initial_burst() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig {
+            initial_burst: 2,
+            ..Default::default()
+        };
+        let mut peer = PeerBuilder::new(ReferenceId::NONE, ReferenceId::NONE).build(base, &config);
+
+        let mut timers = vec![];
+        for _ in 0..3 {
+            let actions = peer.handle_event(
+                system,
+                &config,
+                PeerEvent::PollTimer {
+                    now: base,
+                    time: NtpTimestamp::default(),
+                },
+            );
+            for action in actions {
+                if let PeerAction::SetTimer(interval) = action {
+                    timers.push(interval);
+                }
+            }
+        }
+
+        assert_eq!(timers.len(), 3);
+        assert_eq!(timers[0], PollInterval::BURST);
+        assert!(timers[1] >= config.poll_limits.min);
+        assert!(timers[2] >= config.poll_limits.min);
+    }
+
+    #[test]
+    fn test_resume() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+        let mut peer = Peer::test_peer(base);
+        peer.backoff_interval = config.poll_limits.max;
+        peer.statistics.offset = NtpDuration::from_seconds(0.5);
+
+        let actions: Vec<_> = peer
+            .handle_event(
+                system,
+                &config,
+                PeerEvent::Resume {
+                    now: base,
+                    time: NtpTimestamp::default(),
+                },
+            )
+            .collect();
+
+        assert_eq!(peer.statistics.offset, NtpDuration::ZERO);
+        assert!(peer.request_in_flight());
+        assert!(matches!(actions[0], PeerAction::Send(_)));
+        assert!(matches!(
+            actions[2],
+            PeerAction::SetTimer(PollInterval::BURST)
+        ));
+        assert_eq!(peer.burst_remaining, RESUME_BURST - 1);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+        let mut peer = Peer::test_peer(base);
+        peer.backoff_interval = config.poll_limits.max;
+        peer.statistics.offset = NtpDuration::from_seconds(0.5);
+        peer.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+
+        let actions: Vec<_> = peer
+            .handle_event(
+                system,
+                &config,
+                PeerEvent::Reconnect {
+                    now: base,
+                    time: NtpTimestamp::default(),
+                },
+            )
+            .collect();
+
+        // unlike a resume, the measurements are still good
+        assert_eq!(peer.statistics.offset, NtpDuration::from_seconds(0.5));
+        // the request sent over the previous connection went unanswered
+        assert_eq!(peer.failures, 1);
+        assert!(matches!(actions[0], PeerAction::Send(_)));
+        assert!(matches!(
+            actions[2],
+            PeerAction::SetTimer(PollInterval::BURST)
+        ));
+        assert_eq!(peer.burst_remaining, RESUME_BURST - 1);
+    }
+
+    #[test]
+    fn test_unreachable_backoff() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+        let mut silent = Peer::test_peer(base);
+        let mut refusing = Peer::test_peer(base);
+
+        for _ in 0..3 {
+            silent.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+            refusing.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+
+            let actions: Vec<_> = refusing
+                .handle_event(system, &config, PeerEvent::Unreachable)
+                .collect();
+            assert!(matches!(
+                actions[..],
+                [PeerAction::SetTimer(interval)] if interval == refusing.current_poll_interval(system)
+            ));
+        }
+
+        // every unanswered request backs off a step, every refused one two
+        assert_eq!(silent.current_poll_interval(system).as_log(), 7);
+        assert_eq!(refusing.current_poll_interval(system).as_log(), 10);
+    }
+
+    #[test]
+    fn test_max_failures() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig {
+            max_failures: 3,
+            ..SystemConfig::default()
+        };
+        let mut peer = PeerBuilder::new(ReferenceId::NONE, ReferenceId::NONE).build(base, &config);
+
+        let poll = |peer: &mut Peer| {
+            let event = PeerEvent::PollTimer {
+                now: base,
+                time: NtpTimestamp::default(),
+            };
+            let actions: Vec<_> = peer.handle_event(system, &config, event).collect();
+            match &actions[..] {
+                [PeerAction::Send(request), PeerAction::Update(Update::BareUpdate(snapshot)), PeerAction::SetTimer(_)] => {
+                    Some((request.clone(), *snapshot))
+                }
+                [PeerAction::Replace] => None,
+                other => panic!("unexpected actions {other:?}"),
+            }
+        };
+
+        poll(&mut peer).unwrap();
+        let (request, snapshot) = poll(&mut peer).unwrap();
+        assert_eq!(snapshot.failures, 1);
+        // the unanswered request backed off the poll interval
+        assert!(snapshot.poll_interval > config.poll_limits.min);
+
+        // a usable response ends the streak
+        let mut response = NtpPacket::test();
+        response.set_stratum(1);
+        response.set_mode(NtpAssociationMode::Server);
+        response.set_origin_timestamp(request.transmit_timestamp());
+        response.set_receive_timestamp(NtpTimestamp::from_fixed_int(100));
+        response.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
+        peer.handle_incoming(
+            system,
+            &config,
+            response,
+            base,
+            NtpTimestamp::from_fixed_int(0),
+            NtpTimestamp::from_fixed_int(400),
+        )
+        .unwrap();
+        let (request, snapshot) = poll(&mut peer).unwrap();
+        assert_eq!(snapshot.failures, 0);
+
+        // rate limiting counts as a failure, like silence
+        let kiss = NtpPacket::rate_limit_response(request);
+        assert_eq!(
+            peer.handle_incoming(
+                system,
+                &config,
+                kiss,
+                base,
+                NtpTimestamp::from_fixed_int(0),
+                NtpTimestamp::from_fixed_int(400),
+            )
+            .unwrap_err(),
+            IgnoreReason::KissIgnore
+        );
+        assert_eq!(poll(&mut peer).unwrap().1.failures, 1);
+        assert_eq!(poll(&mut peer).unwrap().1.failures, 2);
+        assert!(poll(&mut peer).is_none());
+    }
+
+    #[test]
+    fn test_state_restore() {
+        let base = NtpInstant::now();
+        let config = SystemConfig {
+            initial_burst: 4,
+            ..SystemConfig::default()
+        };
+
+        let mut peer = Peer::test_peer(base);
+        peer.reach = Reach(0b1011);
+        peer.remote_min_poll_interval = config.poll_limits.max;
+        let sample = FilterTuple::from_refclock_sample(
+            NtpDuration::from_seconds(0.001),
+            NtpDuration::ZERO,
+            NtpDuration::ZERO,
+            base + std::time::Duration::from_secs(1),
+        );
+        peer.last_measurements.step(
+            sample,
+            base,
+            NtpLeapIndicator::Unknown,
+            NtpDuration::ZERO,
+            config.frequency_tolerance,
+        );
+
+        let saved_at = base + std::time::Duration::from_secs(10);
+        let state = peer.state(saved_at);
+        assert_eq!(state.measurements.len(), 1);
+
+        let restarted = saved_at + std::time::Duration::from_secs(5);
+        let mut restored =
+            PeerBuilder::new(ReferenceId::NONE, ReferenceId::NONE).build(restarted, &config);
+        assert!(restored.burst_remaining > 0);
+        restored.restore(&state, restarted, std::time::Duration::from_secs(5));
+
+        assert_eq!(restored.reach, Reach(0b1011));
+        assert_eq!(restored.remote_min_poll_interval, config.poll_limits.max);
+        assert_eq!(restored.burst_remaining, 0);
+        let resaved = restored.state(saved_at);
+        assert_eq!(resaved.measurements.len(), 1);
+        assert!((resaved.measurements[0].age - state.measurements[0].age).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_max_offset() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+        let mut peer = PeerBuilder::new(ReferenceId::NONE, ReferenceId::NONE)
+            .max_offset(NtpDuration::from_seconds(1.0))
+            .build(base, &config);
+
+        let mut incoming = |server_time: i64| {
+            let outgoing =
+                peer.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+            let mut packet = NtpPacket::test();
+            packet.set_stratum(1);
+            packet.set_mode(NtpAssociationMode::Server);
+            packet.set_origin_timestamp(outgoing.transmit_timestamp());
+            packet.set_receive_timestamp(NtpTimestamp::from_unix_seconds_nanos(server_time, 0));
+            packet.set_transmit_timestamp(NtpTimestamp::from_unix_seconds_nanos(server_time, 1000));
+
+            peer.handle_incoming(
+                system,
+                &config,
+                packet,
+                base + Duration::from_secs(1),
+                NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0),
+                NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 2000),
+            )
+        };
+
+        // an hour ahead is not believable
+        assert_eq!(
+            incoming(1_700_003_600).unwrap_err(),
+            IgnoreReason::OffsetTooLarge
+        );
+        assert!(incoming(1_700_000_000).is_ok());
+    }
+
+    #[test]
+    fn test_max_delay() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+        let mut peer = PeerBuilder::new(ReferenceId::NONE, ReferenceId::NONE)
+            .max_delay(NtpDuration::from_seconds(0.1))
+            .max_delay_ratio(3.0)
+            .build(base, &config);
+
+        let mut incoming = |delay_ms: u32| {
+            let outgoing =
+                peer.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+            let mut packet = NtpPacket::test();
+            packet.set_stratum(1);
+            packet.set_mode(NtpAssociationMode::Server);
+            packet.set_origin_timestamp(outgoing.transmit_timestamp());
+            packet.set_receive_timestamp(NtpTimestamp::from_unix_seconds_nanos(
+                1_700_000_000,
+                delay_ms * 500_000,
+            ));
+            packet.set_transmit_timestamp(NtpTimestamp::from_unix_seconds_nanos(
+                1_700_000_000,
+                delay_ms * 500_000 + 1000,
+            ));
+
+            peer.handle_incoming(
+                system,
+                &config,
+                packet,
+                base + Duration::from_secs(1),
+                NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0),
+                NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, delay_ms * 1_000_000 + 1000),
+            )
+        };
+
+        // beyond the maximum delay
+        assert_eq!(incoming(200).unwrap_err(), IgnoreReason::DelayTooLarge);
+
+        // within three times the smallest delay in the filter
+        assert!(incoming(10).is_ok());
+        assert!(incoming(25).is_ok());
+        assert_eq!(incoming(40).unwrap_err(), IgnoreReason::DelayTooLarge);
+    }
+
+    #[test]
+    fn test_stale_reference() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+        let mut peer = PeerBuilder::new(ReferenceId::NONE, ReferenceId::NONE).build(base, &config);
+
+        let ago = |seconds: i64| NtpTimestamp::from_unix_seconds_nanos(1_700_000_000 - seconds, 0);
+        let mut sent = 0;
+        let mut incoming = |stratum: u8, reference_timestamp| {
+            // servers never send the same transmit timestamp twice
+            sent += 1;
+            let outgoing =
+                peer.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+            let mut packet = NtpPacket::test();
+            packet.set_stratum(stratum);
+            packet.set_mode(NtpAssociationMode::Server);
+            packet.set_origin_timestamp(outgoing.transmit_timestamp());
+            packet.set_reference_timestamp(reference_timestamp);
+            packet.set_receive_timestamp(NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0));
+            packet.set_transmit_timestamp(NtpTimestamp::from_unix_seconds_nanos(
+                1_700_000_000,
+                1000 + sent,
+            ));
+
+            peer.handle_incoming(
+                system,
+                &config,
+                packet,
+                base + Duration::from_secs(1),
+                NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0),
+                NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 2000),
+            )
+        };
+
+        // primary servers may hold their reference clock for longer
+        assert!(incoming(1, ago(7200)).is_ok());
+        assert_eq!(
+            incoming(1, ago(2 * 86400)).unwrap_err(),
+            IgnoreReason::StaleReference
+        );
+        assert!(incoming(2, ago(600)).is_ok());
+        assert_eq!(
+            incoming(2, ago(7200)).unwrap_err(),
+            IgnoreReason::StaleReference
+        );
+
+        // a server that never set its clock is not stale
+        assert!(incoming(2, NtpTimestamp::default()).is_ok());
+    }
+
+    #[test]
+    fn test_symmetric_mode() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot {
+            stratum: 2,
+            leap_indicator: NtpLeapIndicator::NoWarning,
+            reference_id: ReferenceId::from_int(42),
+            ..Default::default()
+        };
+        let config = SystemConfig::default();
+        let mut peer = PeerBuilder::new(ReferenceId::NONE, ReferenceId::NONE)
+            .mode(AssociationMode::SymmetricActive)
+            .build(base, &config);
+
+        // the remote learns our state from the request
+        let outgoing = peer.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+        assert_eq!(outgoing.mode(), NtpAssociationMode::SymmetricActive);
+        assert_eq!(outgoing.stratum(), 2);
+        assert_eq!(outgoing.leap(), NtpLeapIndicator::NoWarning);
+        assert_eq!(outgoing.reference_id(), ReferenceId::from_int(42));
+
+        let mut incoming = |mode| {
+            let outgoing =
+                peer.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+            let mut packet = NtpPacket::test();
+            packet.set_stratum(1);
+            packet.set_mode(mode);
+            packet.set_origin_timestamp(outgoing.transmit_timestamp());
+            packet.set_receive_timestamp(NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0));
+            packet
+                .set_transmit_timestamp(NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 1000));
+
+            peer.handle_incoming(
+                system,
+                &config,
+                packet,
+                base + Duration::from_secs(1),
+                NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 0),
+                NtpTimestamp::from_unix_seconds_nanos(1_700_000_000, 2000),
+            )
+        };
+
+        assert_eq!(
+            incoming(NtpAssociationMode::Server).unwrap_err(),
+            IgnoreReason::InvalidMode
+        );
+        assert!(incoming(NtpAssociationMode::SymmetricPassive).is_ok());
+    }
+
+    #[test]
+    fn test_peer_builder() {
+        let base = NtpInstant::now();
+        let config = SystemConfig::default();
+        let limits = config.poll_limits;
+        let system = SystemSnapshot {
+            poll_interval: limits.min,
+            ..Default::default()
+        };
+
+        let local = IpAddr::from([192, 0, 2, 1]);
+        let remote = IpAddr::from([192, 0, 2, 2]);
+        let peer = PeerBuilder::from_addresses(local, remote).build(base, &config);
+        assert_eq!(peer.our_id(), ReferenceId::from_ip(local));
+        assert_eq!(peer.peer_id(), ReferenceId::from_ip(remote));
+        assert_eq!(peer.time(), base);
+        assert_eq!(peer.current_poll_interval(system), limits.min);
+        assert!(!peer.reach().is_reachable());
+        assert!(!peer.request_in_flight());
+
+        // the initial poll interval is kept within the limits
+        let narrow = SystemConfig {
+            poll_limits: PollIntervalLimits {
+                min: limits.min,
+                max: limits.min.inc(limits),
+            },
+            ..config
+        };
+        let mut peer = PeerBuilder::from_addresses(local, remote)
+            .initial_poll_interval(limits.max)
+            .build(base, &narrow);
+        assert_eq!(peer.current_poll_interval(system), narrow.poll_limits.max);
+        assert_eq!(peer.snapshot().poll_interval, narrow.poll_limits.max);
+
+        peer.generate_poll_message(base, NtpTimestamp::default(), system, &narrow);
+        assert!(peer.request_in_flight());
+    }
+
+    #[test]
+    fn test_peer_poll_limits() {
+        let base = NtpInstant::now();
+        let config = SystemConfig::default();
+        let peer_limits = PollIntervalLimits {
+            min: PollInterval::PROTOCOL_MIN,
+            max: PollInterval::PROTOCOL_MIN.inc(config.poll_limits),
+        };
+        let mut system = SystemSnapshot {
+            poll_interval: config.poll_limits.max,
+            ..Default::default()
+        };
+
+        let mut peer = PeerBuilder::new(ReferenceId::NONE, ReferenceId::NONE)
+            .poll_limits(peer_limits)
+            .initial_poll_interval(config.poll_limits.max)
+            .build(base, &config);
+
+        // the poll interval of the system is kept within the limits of the peer
+        assert_eq!(peer.current_poll_interval(system), peer_limits.max);
+        system.poll_interval = PollInterval::PROTOCOL_MIN;
+        assert_eq!(peer.current_poll_interval(system), peer_limits.max);
+
+        // so is the unreachability backoff
+        for _ in 0..4 {
+            peer.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+        }
+        assert_eq!(peer.current_poll_interval(system), peer_limits.max);
+    }
+
+    #[test]
+    fn test_version_downgrade() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+        let mut peer = Peer::test_peer(base);
+
+        let respond = |peer: &mut Peer, version| {
+            let request =
+                peer.generate_poll_message(base, NtpTimestamp::default(), system, &config);
+            let mut response = NtpPacket::test().with_version(version);
+            response.set_mode(NtpAssociationMode::Server);
+            response.set_stratum(1);
+            response.set_origin_timestamp(request.transmit_timestamp());
+            response.set_receive_timestamp(NtpTimestamp::from_fixed_int(100));
+            response.set_transmit_timestamp(NtpTimestamp::from_fixed_int(200));
+            let result = peer.handle_incoming(
+                system,
+                &config,
+                response,
+                base,
+                NtpTimestamp::default(),
+                NtpTimestamp::default(),
+            );
+            (request.ntp_version(), result)
+        };
+
+        // an old server answers a request in version 4 in version 3
+        let (sent, result) = respond(&mut peer, NtpVersion::V3);
+        assert_eq!(sent, NtpVersion::V4);
+        assert!(result.is_ok());
+        assert_eq!(peer.snapshot().version, Some(NtpVersion::V3));
+
+        // which is used from then on, so a response in version 4 is invalid
+        let (sent, result) = respond(&mut peer, NtpVersion::V4);
+        assert_eq!(sent, NtpVersion::V3);
+        assert!(matches!(result, Err(IgnoreReason::InvalidVersion)));
+
+        let mut peer = PeerBuilder::new(ReferenceId::from_int(0), ReferenceId::from_int(1))
+            .version(NtpVersion::V3)
+            .build(base, &config);
+        let (sent, result) = respond(&mut peer, NtpVersion::V3);
+        assert_eq!(sent, NtpVersion::V3);
+        assert!(result.is_ok());
+        assert_eq!(peer.request_version(), NtpVersion::V3);
+    }
 }
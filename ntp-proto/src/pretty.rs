@@ -0,0 +1,332 @@
+//! A rendering of every field of a packet, in the style of Wireshark, for
+//! debugging interoperability problems. Values are shown both decoded and as
+//! they are on the wire.
+
+use std::fmt::{Display, Formatter};
+
+use crate::{
+    format_reference_id, NtpAssociationMode, NtpDuration, NtpLeapIndicator, NtpPacket, NtpTimestamp,
+};
+
+/// Kiss codes of RFC 5905 and RFC 8915, with their meaning
+const KISS_CODES: &[(&[u8; 4], &str)] = &[
+    (b"ACST", "the association belongs to a unicast server"),
+    (b"AUTH", "server authentication failed"),
+    (b"AUTO", "autokey sequence failed"),
+    (b"BCST", "the association belongs to a broadcast server"),
+    (
+        b"CRYP",
+        "cryptographic authentication or identification failed",
+    ),
+    (b"DENY", "access denied by the server"),
+    (b"DROP", "lost peer in symmetric mode"),
+    (b"RSTR", "access denied due to local policy"),
+    (
+        b"INIT",
+        "the association has not yet synchronized for the first time",
+    ),
+    (
+        b"MCST",
+        "the association belongs to a dynamically discovered server",
+    ),
+    (b"NKEY", "no key found"),
+    (b"NTSN", "the server could not decrypt the NTS cookie"),
+    (b"RATE", "rate exceeded, the client must poll less often"),
+    (
+        b"RMOT",
+        "alteration of association from a remote host running ntpdc",
+    ),
+    (b"STEP", "a step change in system time has occurred"),
+];
+
+/// Extension field types of RFC 7821 and RFC 8915
+const EXTENSION_FIELD_TYPES: &[(u16, &str)] = &[
+    (0x0104, "Unique Identifier"),
+    (0x0204, "NTS Cookie"),
+    (0x0304, "NTS Cookie Placeholder"),
+    (0x0404, "NTS Authenticator and Encrypted Extension Fields"),
+    (0x2005, "Checksum Complement"),
+];
+
+/// Bytes of data shown per line
+const BYTES_PER_LINE: usize = 16;
+
+/// Every field of a packet, one per line. See [`NtpPacket::pretty`].
+#[derive(Debug)]
+pub struct PrettyPacket<'a> {
+    pub(crate) packet: &'a NtpPacket<'a>,
+}
+
+impl Display for PrettyPacket<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let packet = self.packet;
+
+        writeln!(
+            f,
+            "Network Time Protocol (NTP Version {}, {})",
+            packet.version(),
+            mode_name(packet.mode())
+        )?;
+        writeln!(
+            f,
+            "    Leap Indicator: {} ({})",
+            leap_name(packet.leap()),
+            packet.leap().to_bits()
+        )?;
+        writeln!(f, "    Version: {}", packet.version())?;
+        writeln!(
+            f,
+            "    Mode: {} ({})",
+            mode_name(packet.mode()),
+            packet.mode().to_bits()
+        )?;
+        writeln!(
+            f,
+            "    Stratum: {} ({})",
+            packet.stratum(),
+            stratum_name(packet.stratum())
+        )?;
+        writeln!(
+            f,
+            "    Poll: {} ({} s)",
+            packet.poll(),
+            2f64.powi(packet.poll() as i32)
+        )?;
+        writeln!(
+            f,
+            "    Precision: {} ({:.9} s)",
+            packet.precision(),
+            2f64.powi(packet.precision() as i32)
+        )?;
+        writeln!(f, "    Root Delay: {}", seconds(packet.root_delay()))?;
+        writeln!(
+            f,
+            "    Root Dispersion: {}",
+            seconds(packet.root_dispersion())
+        )?;
+
+        // requests have stratum 0 as well, without being kiss-o'-death packets
+        let reference_id = packet.reference_id();
+        if packet.is_kiss() && packet.mode() != NtpAssociationMode::Client {
+            let code = reference_id.to_bytes();
+            let meaning = KISS_CODES
+                .iter()
+                .find(|(kiss_code, _)| **kiss_code == code)
+                .map(|(_, meaning)| *meaning)
+                .unwrap_or("unknown kiss code");
+            writeln!(
+                f,
+                "    Reference ID: {} (kiss code: {})",
+                format_reference_id(reference_id, 0),
+                meaning
+            )?;
+        } else {
+            writeln!(
+                f,
+                "    Reference ID: {}",
+                format_reference_id(reference_id, packet.stratum())
+            )?;
+        }
+
+        writeln!(
+            f,
+            "    Reference Timestamp: {}",
+            timestamp(packet.reference_timestamp())
+        )?;
+        writeln!(
+            f,
+            "    Origin Timestamp: {}",
+            timestamp(packet.origin_timestamp())
+        )?;
+        writeln!(
+            f,
+            "    Receive Timestamp: {}",
+            timestamp(packet.receive_timestamp())
+        )?;
+        writeln!(
+            f,
+            "    Transmit Timestamp: {}",
+            timestamp(packet.transmit_timestamp())
+        )?;
+
+        for field in packet.extension_fields() {
+            let crate::packet::ExtensionField::Unknown { typeid, data } = field.as_ref();
+            let name = EXTENSION_FIELD_TYPES
+                .iter()
+                .find(|(known, _)| known == typeid)
+                .map(|(_, name)| *name)
+                .unwrap_or("Unknown");
+            writeln!(
+                f,
+                "    Extension Field: {} (0x{:04x}), {} bytes",
+                name,
+                typeid,
+                4 + data.len()
+            )?;
+            hex_lines(f, data)?;
+        }
+
+        if let Some((keyid, mac)) = packet.mac() {
+            writeln!(f, "    MAC: key {}, {} bytes", keyid, mac.len())?;
+            hex_lines(f, mac)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn leap_name(leap: NtpLeapIndicator) -> &'static str {
+    match leap {
+        NtpLeapIndicator::NoWarning => "no warning",
+        NtpLeapIndicator::Leap61 => "last minute of the day has 61 seconds",
+        NtpLeapIndicator::Leap59 => "last minute of the day has 59 seconds",
+        NtpLeapIndicator::Unknown => "unsynchronized",
+    }
+}
+
+fn mode_name(mode: NtpAssociationMode) -> &'static str {
+    match mode {
+        NtpAssociationMode::Reserved => "reserved",
+        NtpAssociationMode::SymmetricActive => "symmetric active",
+        NtpAssociationMode::SymmetricPassive => "symmetric passive",
+        NtpAssociationMode::Client => "client",
+        NtpAssociationMode::Server => "server",
+        NtpAssociationMode::Broadcast => "broadcast",
+        NtpAssociationMode::Control => "control message",
+        NtpAssociationMode::Private => "private",
+    }
+}
+
+fn stratum_name(stratum: u8) -> &'static str {
+    match stratum {
+        0 => "unspecified or invalid",
+        1 => "primary reference",
+        2..=15 => "secondary reference",
+        16 => "unsynchronized",
+        _ => "reserved",
+    }
+}
+
+fn seconds(duration: NtpDuration) -> String {
+    format!("{:.6} s", duration.to_seconds())
+}
+
+/// A timestamp as a UTC date and time, followed by its value on the wire
+fn timestamp(timestamp: NtpTimestamp) -> String {
+    if timestamp == NtpTimestamp::default() {
+        return "(not set)".into();
+    }
+
+    let (unix_seconds, nanos) = timestamp.to_unix_seconds_nanos();
+    let (year, month, day) = civil_from_days(unix_seconds.div_euclid(86400));
+    let seconds = unix_seconds.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:09} UTC ({})",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        nanos,
+        timestamp
+    )
+}
+
+/// Convert days since the unix epoch to a (year, month, day) civil date
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn hex_lines(f: &mut Formatter<'_>, data: &[u8]) -> std::fmt::Result {
+    for line in data.chunks(BYTES_PER_LINE) {
+        write!(f, "       ")?;
+        for byte in line {
+            write!(f, " {byte:02x}")?;
+        }
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pretty_response() {
+        let mut data = vec![
+            0x24, 0x02, 0x06, 0xec, 0x00, 0x00, 0x03, 0x25, 0x00, 0x00, 0x05, 0x24, 0xc0, 0x00,
+            0x02, 0xc8,
+        ];
+        data.extend_from_slice(&[0xe9, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00]);
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&[0xe9, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00]);
+        data.extend_from_slice(&[0xe9, 0x00, 0x00, 0x10, 0x00, 0x10, 0x00, 0x00]);
+        // a unique identifier extension field and a MAC
+        data.extend_from_slice(&[0x01, 0x04, 0x00, 0x24]);
+        data.extend_from_slice(&[0xab; 32]);
+        data.extend_from_slice(&[0x00, 0x00, 0x00, 0x05]);
+        data.extend_from_slice(&[0xcd; 20]);
+
+        let packet = NtpPacket::deserialize(&data).unwrap();
+        let pretty = packet.pretty().to_string();
+        let lines: Vec<&str> = pretty.lines().collect();
+
+        assert_eq!(lines[0], "Network Time Protocol (NTP Version 4, server)");
+        assert_eq!(lines[1], "    Leap Indicator: no warning (0)");
+        assert_eq!(lines[3], "    Mode: server (4)");
+        assert_eq!(lines[4], "    Stratum: 2 (secondary reference)");
+        assert_eq!(lines[5], "    Poll: 6 (64 s)");
+        assert_eq!(lines[6], "    Precision: -20 (0.000000954 s)");
+        assert_eq!(lines[7], "    Root Delay: 0.012283 s");
+        assert_eq!(lines[9], "    Reference ID: 192.0.2.200");
+        assert_eq!(
+            lines[10],
+            "    Reference Timestamp: 2023-11-16 02:42:08.500000000 UTC (3909091328.500000000)"
+        );
+        assert_eq!(lines[11], "    Origin Timestamp: (not set)");
+        assert_eq!(
+            lines[14],
+            "    Extension Field: Unique Identifier (0x0104), 36 bytes"
+        );
+        assert_eq!(lines[15], format!("       {}", " ab".repeat(16)));
+        assert_eq!(lines[17], "    MAC: key 5, 20 bytes");
+        assert_eq!(lines[18], format!("       {}", " cd".repeat(16)));
+        assert_eq!(lines[19], format!("       {}", " cd".repeat(4)));
+        assert_eq!(lines.len(), 20);
+    }
+
+    #[test]
+    fn test_pretty_kiss() {
+        let mut data = vec![0xe4, 0x00, 0x04, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(b"RATE");
+        data.extend_from_slice(&[0; 32]);
+
+        let packet = NtpPacket::deserialize(&data).unwrap();
+        let pretty = packet.pretty().to_string();
+
+        assert!(pretty.contains("    Leap Indicator: unsynchronized (3)\n"));
+        assert!(pretty.contains("    Stratum: 0 (unspecified or invalid)\n"));
+        assert!(pretty.contains(
+            "    Reference ID: RATE (kiss code: rate exceeded, the client must poll less often)\n"
+        ));
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19675), (2023, 11, 14));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+    }
+}
@@ -0,0 +1,1075 @@
+//! An experimental client for the Precision Time Protocol (IEEE 1588-2008,
+//! PTPv2) over UDP, for networks where a PTP grandmaster is available and NTP
+//! servers are not, or not as good.
+//!
+//! [`PtpClient`] is an ordinary clock that is only ever a slave. It listens
+//! to the `Announce` messages of the masters in its domain, picks the best
+//! one with a subset of the best master clock algorithm (BMCA), and measures
+//! the offset and delay to it with the end-to-end delay request-response
+//! mechanism. Like [`crate::sntp::SntpClient`] it does no I/O of its own: the
+//! caller receives the messages on the event and general ports, timestamps
+//! them, and sends the delay requests.
+//!
+//! Not supported are the peer-to-peer delay mechanism, the layer 2 transport
+//! (and with it gPTP, IEEE 802.1AS), unicast negotiation, management messages
+//! and being a master. Measurements are only made to masters that announce
+//! the PTP timescale with a valid UTC offset, as the time of other masters
+//! cannot be related to UTC.
+
+use std::fmt::Display;
+
+use rand::{thread_rng, Rng};
+
+use crate::{NtpDuration, NtpInstant, NtpTimestamp};
+
+/// Port of the messages that are timestamped: `Sync` and `Delay_Req`
+pub const EVENT_PORT: u16 = 319;
+/// Port of the other messages, among which `Follow_Up`, `Delay_Resp` and
+/// `Announce`
+pub const GENERAL_PORT: u16 = 320;
+/// The multicast group of all PTP messages except the peer delay mechanism
+pub const PRIMARY_MULTICAST_V4: std::net::Ipv4Addr = std::net::Ipv4Addr::new(224, 0, 1, 129);
+
+const HEADER_SIZE: usize = 34;
+const TIMESTAMP_SIZE: usize = 10;
+const PORT_IDENTITY_SIZE: usize = 10;
+const VERSION: u8 = 2;
+
+// flagField, first octet
+const FLAG_TWO_STEP: u8 = 0x02;
+// flagField, second octet
+const FLAG_LEAP_61: u8 = 0x01;
+const FLAG_LEAP_59: u8 = 0x02;
+const FLAG_UTC_OFFSET_VALID: u8 = 0x04;
+const FLAG_PTP_TIMESCALE: u8 = 0x08;
+
+/// A master is qualified once this many announcements arrived within
+/// `FOREIGN_MASTER_TIME_WINDOW` announce intervals
+const FOREIGN_MASTER_THRESHOLD: usize = 2;
+const FOREIGN_MASTER_TIME_WINDOW: u32 = 4;
+/// A master that is silent for this many announce intervals is dropped
+const ANNOUNCE_RECEIPT_TIMEOUT: u32 = 3;
+
+/// `logMessageInterval` values are clamped to this range, such that a
+/// bogus value cannot make an interval overflow or vanish
+const MIN_LOG_INTERVAL: i8 = -7;
+const MAX_LOG_INTERVAL: i8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtpError {
+    /// The message is shorter than its type requires
+    Incomplete,
+    /// The message is not of PTP version 2
+    UnsupportedVersion(u8),
+    /// The message type is not one an ordinary slave clock handles, such as
+    /// the peer delay, signaling and management messages
+    UnsupportedMessage(u8),
+}
+
+impl Display for PtpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Incomplete => f.write_str("Incomplete message"),
+            Self::UnsupportedVersion(version) => write!(f, "Unsupported PTP version {version}"),
+            Self::UnsupportedMessage(kind) => write!(f, "Unsupported message type {kind:#x}"),
+        }
+    }
+}
+
+impl std::error::Error for PtpError {}
+
+/// A point in time as sent on the wire: seconds and nanoseconds since the
+/// PTP epoch, which is 1970-01-01 in the timescale of the master. Usually
+/// that is TAI, which is ahead of UTC by the announced UTC offset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PtpTimestamp {
+    /// Only the lower 48 bits are sent
+    pub seconds: u64,
+    pub nanos: u32,
+}
+
+impl PtpTimestamp {
+    fn deserialize(data: &[u8]) -> Self {
+        let mut seconds = [0; 8];
+        seconds[2..].copy_from_slice(&data[0..6]);
+        PtpTimestamp {
+            seconds: u64::from_be_bytes(seconds),
+            nanos: u32::from_be_bytes(data[6..10].try_into().unwrap()),
+        }
+    }
+
+    fn serialize(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.seconds.to_be_bytes()[2..]);
+        buf.extend_from_slice(&self.nanos.to_be_bytes());
+    }
+
+    /// The time in UTC, for a timestamp on the PTP timescale that is
+    /// `utc_offset` seconds ahead of UTC
+    pub fn to_utc(self, utc_offset: i16) -> NtpTimestamp {
+        NtpTimestamp::from_unix_seconds_nanos(
+            self.seconds as i64 - utc_offset as i64,
+            self.nanos.min(999_999_999),
+        )
+    }
+}
+
+/// Identifies a clock, normally derived from the MAC address of its
+/// interface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ClockIdentity(pub [u8; 8]);
+
+impl ClockIdentity {
+    /// A random identity, for clients that have no access to the MAC
+    /// address of their interface
+    pub fn random() -> Self {
+        ClockIdentity(thread_rng().gen())
+    }
+}
+
+impl Display for ClockIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d, e, g, h, i] = self.0;
+        write!(
+            f,
+            "{a:02x}{b:02x}{c:02x}.{d:02x}{e:02x}.{g:02x}{h:02x}{i:02x}"
+        )
+    }
+}
+
+/// Identifies a port of a clock, which is what messages are sent from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PortIdentity {
+    pub clock_identity: ClockIdentity,
+    pub port_number: u16,
+}
+
+impl PortIdentity {
+    fn deserialize(data: &[u8]) -> Self {
+        PortIdentity {
+            clock_identity: ClockIdentity(data[0..8].try_into().unwrap()),
+            port_number: u16::from_be_bytes([data[8], data[9]]),
+        }
+    }
+
+    fn serialize(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.clock_identity.0);
+        buf.extend_from_slice(&self.port_number.to_be_bytes());
+    }
+}
+
+/// How good the clock of a grandmaster is, lower being better
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ClockQuality {
+    /// 6 for a clock synchronized to a primary reference such as GNSS, 248
+    /// for a clock that is not synchronized at all
+    pub class: u8,
+    /// An enumeration of ranges, 0x20 meaning within 25 ns
+    pub accuracy: u8,
+    pub offset_scaled_log_variance: u16,
+}
+
+/// What a master announces about itself and its grandmaster
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Announce {
+    pub priority1: u8,
+    pub quality: ClockQuality,
+    pub priority2: u8,
+    pub grandmaster_identity: ClockIdentity,
+    /// Number of boundary clocks between the grandmaster and the sender
+    pub steps_removed: u16,
+    /// Seconds the PTP timescale is ahead of UTC (TAI - UTC)
+    pub current_utc_offset: i16,
+    pub utc_offset_valid: bool,
+    /// Whether the master runs on the PTP timescale (TAI) rather than an
+    /// arbitrary one
+    pub ptp_timescale: bool,
+    pub leap_61: bool,
+    pub leap_59: bool,
+    /// The source of the time of the grandmaster, 0x20 meaning GNSS
+    pub time_source: u8,
+}
+
+impl Announce {
+    /// The attributes compared by the BMCA, in order of importance
+    fn rank(&self) -> impl Ord {
+        (
+            self.priority1,
+            self.quality,
+            self.priority2,
+            self.grandmaster_identity,
+            self.steps_removed,
+        )
+    }
+
+    /// The UTC offset, when the time of the master can be related to UTC
+    fn utc_offset(&self) -> Option<i16> {
+        (self.ptp_timescale && self.utc_offset_valid).then_some(self.current_utc_offset)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageBody {
+    Sync {
+        /// Only an approximation for two-step clocks, which send the precise
+        /// time in a `Follow_Up`
+        origin: PtpTimestamp,
+    },
+    DelayReq {
+        origin: PtpTimestamp,
+    },
+    FollowUp {
+        precise_origin: PtpTimestamp,
+    },
+    DelayResp {
+        /// When the master received the `Delay_Req`
+        receive: PtpTimestamp,
+        requesting_port: PortIdentity,
+    },
+    Announce(Announce),
+}
+
+impl MessageBody {
+    fn message_type(&self) -> u8 {
+        match self {
+            MessageBody::Sync { .. } => 0x0,
+            MessageBody::DelayReq { .. } => 0x1,
+            MessageBody::FollowUp { .. } => 0x8,
+            MessageBody::DelayResp { .. } => 0x9,
+            MessageBody::Announce(_) => 0xb,
+        }
+    }
+
+    /// The obsolete controlField, which PTPv1 hardware still looks at
+    fn control(&self) -> u8 {
+        match self {
+            MessageBody::Sync { .. } => 0,
+            MessageBody::DelayReq { .. } => 1,
+            MessageBody::FollowUp { .. } => 2,
+            MessageBody::DelayResp { .. } => 3,
+            MessageBody::Announce(_) => 5,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            MessageBody::Sync { .. }
+            | MessageBody::DelayReq { .. }
+            | MessageBody::FollowUp { .. } => TIMESTAMP_SIZE,
+            MessageBody::DelayResp { .. } => TIMESTAMP_SIZE + PORT_IDENTITY_SIZE,
+            MessageBody::Announce(_) => TIMESTAMP_SIZE + 20,
+        }
+    }
+}
+
+/// A PTPv2 message of one of the types an ordinary slave clock handles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtpMessage {
+    pub domain: u8,
+    /// Whether a `Follow_Up` with the precise origin time follows a `Sync`
+    pub two_step: bool,
+    /// Residence and path delays accumulated by transparent clocks, in
+    /// units of 2^-16 ns
+    pub correction: i64,
+    pub source_port: PortIdentity,
+    pub sequence_id: u16,
+    /// Log2 of the interval between messages of this type in seconds, or
+    /// of the minimal interval between delay requests in a `Delay_Resp`
+    pub log_message_interval: i8,
+    pub body: MessageBody,
+}
+
+impl PtpMessage {
+    pub fn deserialize(data: &[u8]) -> Result<Self, PtpError> {
+        if data.len() < HEADER_SIZE {
+            return Err(PtpError::Incomplete);
+        }
+
+        let version = data[1] & 0x0f;
+        if version != VERSION {
+            return Err(PtpError::UnsupportedVersion(version));
+        }
+
+        let message_type = data[0] & 0x0f;
+        // the message length field covers suffixes (TLVs) we do not use, so
+        // only the fixed size of each type is checked
+        let body = &data[HEADER_SIZE..];
+        let needs = |size: usize| {
+            if body.len() < size {
+                Err(PtpError::Incomplete)
+            } else {
+                Ok(())
+            }
+        };
+
+        let body = match message_type {
+            0x0 | 0x1 | 0x8 => {
+                needs(TIMESTAMP_SIZE)?;
+                let timestamp = PtpTimestamp::deserialize(body);
+                match message_type {
+                    0x0 => MessageBody::Sync { origin: timestamp },
+                    0x1 => MessageBody::DelayReq { origin: timestamp },
+                    _ => MessageBody::FollowUp {
+                        precise_origin: timestamp,
+                    },
+                }
+            }
+            0x9 => {
+                needs(TIMESTAMP_SIZE + PORT_IDENTITY_SIZE)?;
+                MessageBody::DelayResp {
+                    receive: PtpTimestamp::deserialize(body),
+                    requesting_port: PortIdentity::deserialize(&body[TIMESTAMP_SIZE..]),
+                }
+            }
+            0xb => {
+                needs(TIMESTAMP_SIZE + 20)?;
+                let fields = &body[TIMESTAMP_SIZE..];
+                MessageBody::Announce(Announce {
+                    current_utc_offset: i16::from_be_bytes([fields[0], fields[1]]),
+                    priority1: fields[3],
+                    quality: ClockQuality {
+                        class: fields[4],
+                        accuracy: fields[5],
+                        offset_scaled_log_variance: u16::from_be_bytes([fields[6], fields[7]]),
+                    },
+                    priority2: fields[8],
+                    grandmaster_identity: ClockIdentity(fields[9..17].try_into().unwrap()),
+                    steps_removed: u16::from_be_bytes([fields[17], fields[18]]),
+                    time_source: fields[19],
+                    utc_offset_valid: data[7] & FLAG_UTC_OFFSET_VALID != 0,
+                    ptp_timescale: data[7] & FLAG_PTP_TIMESCALE != 0,
+                    leap_61: data[7] & FLAG_LEAP_61 != 0,
+                    leap_59: data[7] & FLAG_LEAP_59 != 0,
+                })
+            }
+            other => return Err(PtpError::UnsupportedMessage(other)),
+        };
+
+        Ok(PtpMessage {
+            domain: data[4],
+            two_step: data[6] & FLAG_TWO_STEP != 0,
+            correction: i64::from_be_bytes(data[8..16].try_into().unwrap()),
+            source_port: PortIdentity::deserialize(&data[20..30]),
+            sequence_id: u16::from_be_bytes([data[30], data[31]]),
+            log_message_interval: data[33] as i8,
+            body,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let length = HEADER_SIZE + self.body.size();
+        let mut buf = Vec::with_capacity(length);
+
+        let mut flags = [0u8; 2];
+        if self.two_step {
+            flags[0] |= FLAG_TWO_STEP;
+        }
+        if let MessageBody::Announce(announce) = &self.body {
+            for (set, flag) in [
+                (announce.leap_61, FLAG_LEAP_61),
+                (announce.leap_59, FLAG_LEAP_59),
+                (announce.utc_offset_valid, FLAG_UTC_OFFSET_VALID),
+                (announce.ptp_timescale, FLAG_PTP_TIMESCALE),
+            ] {
+                if set {
+                    flags[1] |= flag;
+                }
+            }
+        }
+
+        buf.push(self.body.message_type());
+        buf.push(VERSION);
+        buf.extend_from_slice(&(length as u16).to_be_bytes());
+        buf.push(self.domain);
+        buf.push(0);
+        buf.extend_from_slice(&flags);
+        buf.extend_from_slice(&self.correction.to_be_bytes());
+        buf.extend_from_slice(&[0; 4]);
+        self.source_port.serialize(&mut buf);
+        buf.extend_from_slice(&self.sequence_id.to_be_bytes());
+        buf.push(self.body.control());
+        buf.push(self.log_message_interval as u8);
+
+        match &self.body {
+            MessageBody::Sync { origin } | MessageBody::DelayReq { origin } => {
+                origin.serialize(&mut buf)
+            }
+            MessageBody::FollowUp { precise_origin } => precise_origin.serialize(&mut buf),
+            MessageBody::DelayResp {
+                receive,
+                requesting_port,
+            } => {
+                receive.serialize(&mut buf);
+                requesting_port.serialize(&mut buf);
+            }
+            MessageBody::Announce(announce) => {
+                PtpTimestamp::default().serialize(&mut buf);
+                buf.extend_from_slice(&announce.current_utc_offset.to_be_bytes());
+                buf.push(0);
+                buf.push(announce.priority1);
+                buf.push(announce.quality.class);
+                buf.push(announce.quality.accuracy);
+                buf.extend_from_slice(&announce.quality.offset_scaled_log_variance.to_be_bytes());
+                buf.push(announce.priority2);
+                buf.extend_from_slice(&announce.grandmaster_identity.0);
+                buf.extend_from_slice(&announce.steps_removed.to_be_bytes());
+                buf.push(announce.time_source);
+            }
+        }
+
+        buf
+    }
+
+    fn correction(&self) -> NtpDuration {
+        NtpDuration::from_seconds(self.correction as f64 / (65536.0 * 1e9))
+    }
+}
+
+/// The length of `2^log_interval` seconds
+fn interval(log_interval: i8) -> std::time::Duration {
+    let log_interval = log_interval.clamp(MIN_LOG_INTERVAL, MAX_LOG_INTERVAL);
+    std::time::Duration::from_secs_f64(2f64.powi(log_interval as i32))
+}
+
+#[derive(Debug, Clone)]
+struct ForeignMaster {
+    port: PortIdentity,
+    announce: Announce,
+    log_announce_interval: i8,
+    /// Arrival of the most recent announcements, newest last
+    received: Vec<NtpInstant>,
+}
+
+impl ForeignMaster {
+    fn is_qualified(&self, now: NtpInstant) -> bool {
+        let window = interval(self.log_announce_interval) * FOREIGN_MASTER_TIME_WINDOW;
+        let recent = self
+            .received
+            .iter()
+            .filter(|time| {
+                NtpInstant::abs_diff(now, **time) <= NtpDuration::from_system_duration(window)
+            })
+            .count();
+        recent >= FOREIGN_MASTER_THRESHOLD
+    }
+
+    fn is_expired(&self, now: NtpInstant) -> bool {
+        let timeout = interval(self.log_announce_interval) * ANNOUNCE_RECEIPT_TIMEOUT;
+        match self.received.last() {
+            Some(last) => {
+                NtpInstant::abs_diff(now, *last) > NtpDuration::from_system_duration(timeout)
+            }
+            None => true,
+        }
+    }
+}
+
+/// The best master among those that were announced, by the part of the
+/// BMCA that compares the grandmasters, with the number of steps to them and
+/// the identity of the sender as tie breakers. A slave-only clock never
+/// becomes master itself, so it is not compared to the announced ones.
+fn best_master(masters: &[ForeignMaster], now: NtpInstant) -> Option<&ForeignMaster> {
+    masters
+        .iter()
+        .filter(|master| master.is_qualified(now))
+        .min_by(|a, b| {
+            a.announce
+                .rank()
+                .cmp(&b.announce.rank())
+                .then(a.port.cmp(&b.port))
+        })
+}
+
+/// A `Sync` of the master, and when we received it
+#[derive(Debug, Clone, Copy)]
+struct SyncExchange {
+    sequence_id: u16,
+    /// Missing until the `Follow_Up` of a two-step master arrives
+    origin: Option<PtpTimestamp>,
+    /// Of both the `Sync` and the `Follow_Up`
+    correction: NtpDuration,
+    receive: NtpTimestamp,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DelayExchange {
+    sequence_id: u16,
+    send: NtpTimestamp,
+    sent_at: NtpInstant,
+    /// The time from master to us, measured by the latest `Sync`
+    master_to_slave: NtpDuration,
+}
+
+/// What the caller of [`PtpClient::handle_message`] should do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtpUpdate {
+    /// Nothing, the message was stored or ignored
+    None,
+    /// A `Sync` was completed, and a delay request is due: send the message
+    /// of [`PtpClient::delay_request`] to the event port of the master
+    DelayRequestDue,
+    /// A delay request was answered, completing a measurement
+    Measurement(PtpMeasurement),
+}
+
+/// A single measurement of the clock of the grandmaster against ours
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtpMeasurement {
+    /// The time of the grandmaster in UTC minus ours, so positive when our
+    /// clock is behind
+    pub offset: NtpDuration,
+    /// The round trip time to the master, without the residence times in
+    /// transparent clocks on the way
+    pub delay: NtpDuration,
+    pub grandmaster: ClockIdentity,
+    pub steps_removed: u16,
+    pub quality: ClockQuality,
+}
+
+/// An ordinary clock that is only ever a slave, on a single port
+#[derive(Debug, Clone)]
+pub struct PtpClient {
+    port: PortIdentity,
+    domain: u8,
+    masters: Vec<ForeignMaster>,
+    /// The port of the master that was chosen when the last message arrived
+    selected: Option<PortIdentity>,
+
+    sync: Option<SyncExchange>,
+    delay: Option<DelayExchange>,
+    next_sequence_id: u16,
+    /// As told by the master in its `Delay_Resp`
+    log_min_delay_request_interval: i8,
+}
+
+impl PtpClient {
+    pub fn new(clock_identity: ClockIdentity, domain: u8) -> Self {
+        PtpClient {
+            port: PortIdentity {
+                clock_identity,
+                port_number: 1,
+            },
+            domain,
+            masters: Vec::new(),
+            selected: None,
+            sync: None,
+            delay: None,
+            next_sequence_id: thread_rng().gen(),
+            log_min_delay_request_interval: 0,
+        }
+    }
+
+    /// The announcement of the master we synchronize to, if any
+    pub fn selected_master(&self) -> Option<(PortIdentity, Announce)> {
+        let port = self.selected?;
+        self.masters
+            .iter()
+            .find(|master| master.port == port)
+            .map(|master| (port, master.announce))
+    }
+
+    /// Handle a message received on the event or general port at
+    /// `recv_time` according to our clock, and at `now` on the monotonic
+    /// clock. Only the receive times of `Sync` messages are used, so for
+    /// messages on the general port any time will do.
+    pub fn handle_message(
+        &mut self,
+        data: &[u8],
+        recv_time: NtpTimestamp,
+        now: NtpInstant,
+    ) -> Result<PtpUpdate, PtpError> {
+        let message = PtpMessage::deserialize(data)?;
+
+        if message.domain != self.domain || message.source_port == self.port {
+            return Ok(PtpUpdate::None);
+        }
+
+        if let MessageBody::Announce(announce) = message.body {
+            self.handle_announce(&message, announce, now);
+        }
+        self.select(now);
+
+        if Some(message.source_port) != self.selected {
+            return Ok(PtpUpdate::None);
+        }
+
+        let update = match message.body {
+            MessageBody::Sync { origin } => {
+                self.sync = Some(SyncExchange {
+                    sequence_id: message.sequence_id,
+                    origin: (!message.two_step).then_some(origin),
+                    correction: message.correction(),
+                    receive: recv_time,
+                });
+                self.sync_completed(now)
+            }
+            MessageBody::FollowUp { precise_origin } => match &mut self.sync {
+                Some(sync) if sync.sequence_id == message.sequence_id && sync.origin.is_none() => {
+                    sync.origin = Some(precise_origin);
+                    sync.correction += message.correction();
+                    self.sync_completed(now)
+                }
+                _ => PtpUpdate::None,
+            },
+            MessageBody::DelayResp {
+                receive,
+                requesting_port,
+            } => match self.delay {
+                Some(delay)
+                    if requesting_port == self.port && delay.sequence_id == message.sequence_id =>
+                {
+                    self.delay = None;
+                    self.log_min_delay_request_interval = message.log_message_interval;
+                    self.measurement(delay, receive, message.correction())
+                }
+                _ => PtpUpdate::None,
+            },
+            MessageBody::Announce(_) | MessageBody::DelayReq { .. } => PtpUpdate::None,
+        };
+
+        Ok(update)
+    }
+
+    fn handle_announce(&mut self, message: &PtpMessage, announce: Announce, now: NtpInstant) {
+        match self
+            .masters
+            .iter_mut()
+            .find(|master| master.port == message.source_port)
+        {
+            Some(master) => {
+                master.announce = announce;
+                master.log_announce_interval = message.log_message_interval;
+                master.received.push(now);
+                if master.received.len() > FOREIGN_MASTER_THRESHOLD {
+                    master.received.remove(0);
+                }
+            }
+            None => self.masters.push(ForeignMaster {
+                port: message.source_port,
+                announce,
+                log_announce_interval: message.log_message_interval,
+                received: vec![now],
+            }),
+        }
+    }
+
+    /// Run the BMCA, forgetting any exchange with a master that is no longer
+    /// the best one
+    fn select(&mut self, now: NtpInstant) {
+        self.masters.retain(|master| !master.is_expired(now));
+
+        let selected = best_master(&self.masters, now).map(|master| master.port);
+        if selected != self.selected {
+            self.selected = selected;
+            self.sync = None;
+            self.delay = None;
+        }
+    }
+
+    fn sync_completed(&self, now: NtpInstant) -> PtpUpdate {
+        let completed = matches!(
+            self.sync,
+            Some(SyncExchange {
+                origin: Some(_),
+                ..
+            })
+        );
+        let due = match self.delay {
+            None => true,
+            // also when the response was lost
+            Some(delay) => {
+                NtpInstant::abs_diff(now, delay.sent_at)
+                    >= NtpDuration::from_system_duration(interval(
+                        self.log_min_delay_request_interval,
+                    ))
+            }
+        };
+
+        if completed && due && self.utc_offset().is_some() {
+            PtpUpdate::DelayRequestDue
+        } else {
+            PtpUpdate::None
+        }
+    }
+
+    fn utc_offset(&self) -> Option<i16> {
+        self.selected_master()?.1.utc_offset()
+    }
+
+    /// A `Delay_Req` for the master, which we send at `send_time` according
+    /// to our clock, and at `now` on the monotonic clock. There is none
+    /// without a completed `Sync` of the selected master.
+    pub fn delay_request(&mut self, send_time: NtpTimestamp, now: NtpInstant) -> Option<Vec<u8>> {
+        let utc_offset = self.utc_offset()?;
+        let sync = self.sync?;
+        let origin = sync.origin?.to_utc(utc_offset);
+
+        let sequence_id = self.next_sequence_id;
+        self.next_sequence_id = self.next_sequence_id.wrapping_add(1);
+        self.delay = Some(DelayExchange {
+            sequence_id,
+            send: send_time,
+            sent_at: now,
+            master_to_slave: sync.receive - origin - sync.correction,
+        });
+
+        let message = PtpMessage {
+            domain: self.domain,
+            two_step: false,
+            correction: 0,
+            source_port: self.port,
+            sequence_id,
+            log_message_interval: 0x7f,
+            // the origin timestamp may be zero, and does not reveal our time
+            body: MessageBody::DelayReq {
+                origin: PtpTimestamp::default(),
+            },
+        };
+
+        Some(message.serialize())
+    }
+
+    fn measurement(
+        &self,
+        delay: DelayExchange,
+        receive: PtpTimestamp,
+        correction: NtpDuration,
+    ) -> PtpUpdate {
+        let (utc_offset, (_, announce)) = match (self.utc_offset(), self.selected_master()) {
+            (Some(utc_offset), Some(master)) => (utc_offset, master),
+            _ => return PtpUpdate::None,
+        };
+
+        let slave_to_master = receive.to_utc(utc_offset) - delay.send - correction;
+
+        PtpUpdate::Measurement(PtpMeasurement {
+            offset: (slave_to_master - delay.master_to_slave) / 2i64,
+            // with clocks that run at different rates the delay can come out
+            // negative on a very fast network
+            delay: Ord::max(delay.master_to_slave + slave_to_master, NtpDuration::ZERO),
+            grandmaster: announce.grandmaster_identity,
+            steps_removed: announce.steps_removed,
+            quality: announce.quality,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const GNSS_QUALITY: ClockQuality = ClockQuality {
+        class: 6,
+        accuracy: 0x21,
+        offset_scaled_log_variance: 0x4e5d,
+    };
+
+    fn announce(grandmaster: u8, quality: ClockQuality) -> Announce {
+        Announce {
+            priority1: 128,
+            quality,
+            priority2: 128,
+            grandmaster_identity: ClockIdentity([grandmaster; 8]),
+            steps_removed: 0,
+            current_utc_offset: 37,
+            utc_offset_valid: true,
+            ptp_timescale: true,
+            leap_61: false,
+            leap_59: false,
+            time_source: 0x20,
+        }
+    }
+
+    fn message(source: u8, sequence_id: u16, body: MessageBody) -> Vec<u8> {
+        PtpMessage {
+            domain: 0,
+            two_step: matches!(body, MessageBody::Sync { .. }),
+            correction: 0,
+            source_port: PortIdentity {
+                clock_identity: ClockIdentity([source; 8]),
+                port_number: 1,
+            },
+            sequence_id,
+            log_message_interval: 0,
+            body,
+        }
+        .serialize()
+    }
+
+    fn ptp_time(seconds: u64, nanos: u32) -> PtpTimestamp {
+        PtpTimestamp { seconds, nanos }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let messages = [
+            MessageBody::Sync {
+                origin: ptp_time(1_700_000_037, 5),
+            },
+            MessageBody::DelayReq {
+                origin: PtpTimestamp::default(),
+            },
+            MessageBody::FollowUp {
+                precise_origin: ptp_time(0xffff_ffff_ffff, 999_999_999),
+            },
+            MessageBody::DelayResp {
+                receive: ptp_time(1_700_000_038, 10),
+                requesting_port: PortIdentity {
+                    clock_identity: ClockIdentity([1, 2, 3, 4, 5, 6, 7, 8]),
+                    port_number: 2,
+                },
+            },
+            MessageBody::Announce(announce(7, GNSS_QUALITY)),
+        ];
+
+        for body in messages {
+            let message = PtpMessage {
+                domain: 24,
+                two_step: true,
+                correction: -12345,
+                source_port: PortIdentity::default(),
+                sequence_id: 4321,
+                log_message_interval: -3,
+                body,
+            };
+            let data = message.serialize();
+            assert_eq!(data.len(), u16::from_be_bytes([data[2], data[3]]) as usize);
+            assert_eq!(PtpMessage::deserialize(&data), Ok(message));
+        }
+    }
+
+    #[test]
+    fn test_deserialize_errors() {
+        let data = message(1, 0, MessageBody::Announce(announce(1, GNSS_QUALITY)));
+        assert_eq!(
+            PtpMessage::deserialize(&data[..HEADER_SIZE + 10]),
+            Err(PtpError::Incomplete)
+        );
+        assert_eq!(
+            PtpMessage::deserialize(&data[..20]),
+            Err(PtpError::Incomplete)
+        );
+
+        let mut other = data.clone();
+        other[1] = 1;
+        assert_eq!(
+            PtpMessage::deserialize(&other),
+            Err(PtpError::UnsupportedVersion(1))
+        );
+
+        // peer delay request
+        let mut other = data;
+        other[0] = 0x2;
+        assert_eq!(
+            PtpMessage::deserialize(&other),
+            Err(PtpError::UnsupportedMessage(0x2))
+        );
+    }
+
+    #[test]
+    fn test_best_master() {
+        let mut client = PtpClient::new(ClockIdentity([0xaa; 8]), 0);
+        let start = NtpInstant::now();
+        let time = NtpTimestamp::from_fixed_int(0);
+
+        let worse = ClockQuality {
+            class: 248,
+            ..GNSS_QUALITY
+        };
+
+        // a single announcement does not qualify a master
+        let update = client
+            .handle_message(
+                &message(2, 0, MessageBody::Announce(announce(2, worse))),
+                time,
+                start,
+            )
+            .unwrap();
+        assert_eq!(update, PtpUpdate::None);
+        assert_eq!(client.selected_master(), None);
+
+        let second = start + Duration::from_secs(1);
+        client
+            .handle_message(
+                &message(2, 1, MessageBody::Announce(announce(2, worse))),
+                time,
+                second,
+            )
+            .unwrap();
+        assert_eq!(client.selected_master().unwrap().1.quality, worse);
+
+        // a better grandmaster takes over once it is qualified
+        for (sequence_id, now) in [(0, second), (1, start + Duration::from_secs(2))] {
+            client
+                .handle_message(
+                    &message(
+                        3,
+                        sequence_id,
+                        MessageBody::Announce(announce(3, GNSS_QUALITY)),
+                    ),
+                    time,
+                    now,
+                )
+                .unwrap();
+        }
+        let (port, selected) = client.selected_master().unwrap();
+        assert_eq!(port.clock_identity, ClockIdentity([3; 8]));
+        assert_eq!(selected.grandmaster_identity, ClockIdentity([3; 8]));
+
+        // and is dropped when it goes silent
+        let later = start + Duration::from_secs(4);
+        client
+            .handle_message(
+                &message(2, 2, MessageBody::Announce(announce(2, worse))),
+                time,
+                later,
+            )
+            .unwrap();
+        let later = start + Duration::from_secs(6);
+        client
+            .handle_message(
+                &message(2, 3, MessageBody::Announce(announce(2, worse))),
+                time,
+                later,
+            )
+            .unwrap();
+        assert_eq!(
+            client.selected_master().unwrap().0.clock_identity,
+            ClockIdentity([2; 8])
+        );
+    }
+
+    #[test]
+    fn test_measurement() {
+        let mut client = PtpClient::new(ClockIdentity([0xaa; 8]), 0);
+        let start = NtpInstant::now();
+        let unused = NtpTimestamp::from_fixed_int(0);
+
+        for (sequence_id, seconds) in [(0, 0), (1, 1)] {
+            let now = start + Duration::from_secs(seconds);
+            let data = message(
+                1,
+                sequence_id,
+                MessageBody::Announce(announce(1, GNSS_QUALITY)),
+            );
+            client.handle_message(&data, unused, now).unwrap();
+        }
+        let now = start + Duration::from_secs(1);
+
+        // our clock is 1 ms behind UTC, and the path takes 2 ms each way;
+        // the master runs on TAI, 37 s ahead of UTC
+        let ours = |seconds: i64, millis: u32| {
+            NtpTimestamp::from_unix_seconds_nanos(seconds, millis * 1_000_000)
+        };
+        let tai = |seconds: u64, millis: u32| ptp_time(seconds + 37, millis * 1_000_000);
+
+        let sync = message(
+            1,
+            10,
+            MessageBody::Sync {
+                origin: PtpTimestamp::default(),
+            },
+        );
+        let update = client
+            .handle_message(&sync, ours(1_700_000_000, 1), now)
+            .unwrap();
+        assert_eq!(update, PtpUpdate::None);
+        // no delay request before the precise origin time is known
+        assert_eq!(client.delay_request(ours(1_700_000_000, 2), now), None);
+
+        let follow_up = message(
+            1,
+            10,
+            MessageBody::FollowUp {
+                precise_origin: tai(1_700_000_000, 0),
+            },
+        );
+        let update = client.handle_message(&follow_up, unused, now).unwrap();
+        assert_eq!(update, PtpUpdate::DelayRequestDue);
+
+        let request = client.delay_request(ours(1_700_000_000, 100), now).unwrap();
+        let request = PtpMessage::deserialize(&request).unwrap();
+        assert!(matches!(request.body, MessageBody::DelayReq { .. }));
+
+        // a response to a different request is ignored
+        let response = |sequence_id, requesting_port| {
+            message(
+                1,
+                sequence_id,
+                MessageBody::DelayResp {
+                    receive: tai(1_700_000_000, 103),
+                    requesting_port,
+                },
+            )
+        };
+        let update = client
+            .handle_message(
+                &response(request.sequence_id.wrapping_add(1), request.source_port),
+                unused,
+                now,
+            )
+            .unwrap();
+        assert_eq!(update, PtpUpdate::None);
+
+        let update = client
+            .handle_message(
+                &response(request.sequence_id, request.source_port),
+                unused,
+                now,
+            )
+            .unwrap();
+        let measurement = match update {
+            PtpUpdate::Measurement(measurement) => measurement,
+            update => panic!("Unexpected update {update:?}"),
+        };
+        assert!((measurement.offset.to_seconds() - 0.001).abs() < 1e-9);
+        assert!((measurement.delay.to_seconds() - 0.004).abs() < 1e-9);
+        assert_eq!(measurement.grandmaster, ClockIdentity([1; 8]));
+
+        // the response is only used once
+        let update = client
+            .handle_message(
+                &response(request.sequence_id, request.source_port),
+                unused,
+                now,
+            )
+            .unwrap();
+        assert_eq!(update, PtpUpdate::None);
+    }
+
+    #[test]
+    fn test_arbitrary_timescale() {
+        let mut client = PtpClient::new(ClockIdentity([0xaa; 8]), 0);
+        let start = NtpInstant::now();
+        let time = NtpTimestamp::from_fixed_int(0);
+
+        let arbitrary = Announce {
+            ptp_timescale: false,
+            ..announce(1, GNSS_QUALITY)
+        };
+        for (sequence_id, seconds) in [(0, 0), (1, 1)] {
+            let now = start + Duration::from_secs(seconds);
+            let data = message(1, sequence_id, MessageBody::Announce(arbitrary));
+            client.handle_message(&data, time, now).unwrap();
+        }
+        let now = start + Duration::from_secs(1);
+
+        // the master is selected, but its time cannot be related to UTC
+        assert!(client.selected_master().is_some());
+        let sync = PtpMessage {
+            two_step: false,
+            ..PtpMessage::deserialize(&message(
+                1,
+                5,
+                MessageBody::Sync {
+                    origin: ptp_time(1000, 0),
+                },
+            ))
+            .unwrap()
+        };
+        let update = client.handle_message(&sync.serialize(), time, now).unwrap();
+        assert_eq!(update, PtpUpdate::None);
+        assert_eq!(client.delay_request(time, now), None);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::{
+    filter::{FilterTuple, LastMeasurements},
+    packet::NtpLeapIndicator,
+    peer::{PeerStatistics, Reach, SourceKind, Update},
+    time_types::NtpInstant,
+    NtpDuration, PeerSnapshot, PollInterval, ReferenceId, SystemConfig, SystemSnapshot,
+};
+use tracing::{debug, info, instrument};
+
+/// A local reference clock (e.g. a GPS receiver), used as a stratum 0 source.
+///
+/// Unlike a [`Peer`](crate::Peer), a reference clock has no network path. The
+/// driver measures the offset between the reference and the local clock
+/// directly, and hands each such sample to [`RefClock::handle_sample`], which
+/// runs it through the same clock filter used for network peers.
+///
+/// When it is selected as the system peer, the system operates at a stratum
+/// one above that of the reference clock, with its reference id.
+#[derive(Debug, Clone)]
+pub struct RefClock {
+    reference_id: ReferenceId,
+    // Precision with which the driver can determine the reference time
+    precision: NtpDuration,
+    poll_interval: PollInterval,
+    stratum: u8,
+    needs_corroboration: bool,
+
+    statistics: PeerStatistics,
+    last_measurements: LastMeasurements,
+    time: NtpInstant,
+    reach: Reach,
+}
+
+impl RefClock {
+    #[instrument]
+    pub fn new(
+        reference_id: ReferenceId,
+        precision: NtpDuration,
+        stratum: u8,
+        needs_corroboration: bool,
+        local_clock_time: NtpInstant,
+    ) -> Self {
+        Self {
+            reference_id,
+            precision,
+            poll_interval: PollInterval::default(),
+            stratum,
+            needs_corroboration,
+
+            statistics: Default::default(),
+            last_measurements: LastMeasurements::new(local_clock_time),
+            time: local_clock_time,
+            reach: Default::default(),
+        }
+    }
+
+    pub fn current_poll_interval(&self, system: SystemSnapshot) -> PollInterval {
+        system.poll_interval
+    }
+
+    /// Mark the start of a new poll interval. The driver should call this
+    /// at the same rate at which it hands samples to the filter, such that
+    /// a driver that stops producing samples becomes unreachable.
+    pub fn poll(&mut self, system: SystemSnapshot) {
+        self.reach.poll();
+        self.poll_interval = self.current_poll_interval(system);
+    }
+
+    /// Process a new sample from the reference clock.
+    ///
+    /// The `offset` is the reference time minus the local clock time at
+    /// `local_clock_time`, with any configured fudge already applied.
+    #[instrument(skip(self, system, system_config), fields(refclock = debug(self.reference_id)))]
+    pub fn handle_sample(
+        &mut self,
+        system: SystemSnapshot,
+        system_config: &SystemConfig,
+        offset: NtpDuration,
+        local_clock_time: NtpInstant,
+    ) -> Update {
+        self.reach.received_packet();
+
+        let filter_input = FilterTuple::from_refclock_sample(
+            offset,
+            self.precision,
+            system.precision,
+            local_clock_time,
+        );
+
+        if let Some(max_age) = system_config.filter_max_age {
+            self.last_measurements.expire(local_clock_time, max_age);
+        }
+
+        let updated = self.last_measurements.step(
+            filter_input,
+            self.time,
+            system.leap_indicator,
+            system.precision,
+            system_config.frequency_tolerance,
+        );
+
+        match updated {
+            None => Update::BareUpdate(self.snapshot()),
+            Some((statistics, smallest_delay_time)) => {
+                debug!(?statistics, "reference clock statistics updated");
+                self.statistics = statistics;
+                self.time = smallest_delay_time;
+
+                Update::NewMeasurement(self.snapshot())
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> PeerSnapshot {
+        PeerSnapshot {
+            root_distance_without_time: self.root_distance_without_time(),
+            statistics: self.statistics,
+            time: self.time,
+            stratum: self.stratum,
+            peer_id: self.reference_id,
+            poll_interval: self.poll_interval,
+            reference_id: self.reference_id,
+            our_id: ReferenceId::NONE,
+            reach: self.reach,
+            leap_indicator: NtpLeapIndicator::NoWarning,
+            root_delay: NtpDuration::ZERO,
+            root_dispersion: NtpDuration::ZERO,
+            // attached to this machine, so nobody can tamper with it on the way
+            authenticated: true,
+            failures: 0,
+            kind: SourceKind::RefClock {
+                needs_corroboration: self.needs_corroboration,
+            },
+            version: None,
+        }
+    }
+
+    /// Root distance without the `(local_clock_time - self.time) * PHI` term.
+    /// The reference clock is the root, so only our own measurement counts.
+    fn root_distance_without_time(&self) -> NtpDuration {
+        NtpDuration::MIN_DISPERSION.max(self.statistics.delay) / 2i64
+            + self.statistics.dispersion
+            + NtpDuration::from_seconds(self.statistics.jitter)
+    }
+
+    /// reset just the measurement data, the reachability is unchanged
+    #[instrument(level="trace", skip(self), fields(refclock = debug(self.reference_id)))]
+    pub fn reset_measurements(&mut self) {
+        self.statistics = Default::default();
+        self.last_measurements = LastMeasurements::new(self.time);
+
+        info!(refclock = ?self.reference_id, "Reference clock reset");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refclock_sample() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+
+        let mut refclock = RefClock::new(
+            ReferenceId::GPS,
+            NtpDuration::from_exponent(-10),
+            0,
+            false,
+            base,
+        );
+
+        // fill the filter register, such that the dummy tuples no longer
+        // dominate the dispersion
+        let mut update = None;
+        for i in 1..=8 {
+            refclock.poll(system);
+            update = Some(refclock.handle_sample(
+                system,
+                &config,
+                NtpDuration::from_seconds(0.25),
+                base + std::time::Duration::from_secs(i),
+            ));
+        }
+
+        let snapshot = match update.unwrap() {
+            Update::NewMeasurement(snapshot) => snapshot,
+            Update::BareUpdate(_) => panic!("Expected a new measurement"),
+        };
+
+        assert_eq!(snapshot.stratum, 0);
+        assert_eq!(snapshot.peer_id, ReferenceId::GPS);
+        assert_eq!(snapshot.statistics.offset, NtpDuration::from_seconds(0.25));
+        assert!(snapshot.reach.is_reachable());
+        assert!(snapshot
+            .accept_synchronization(
+                base + std::time::Duration::from_secs(8),
+                config.frequency_tolerance,
+                config.distance_threshold,
+                system.poll_interval,
+                config.local_stratum,
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_refclock_unreachable() {
+        let base = NtpInstant::now();
+        let system = SystemSnapshot::default();
+        let config = SystemConfig::default();
+
+        let mut refclock = RefClock::new(
+            ReferenceId::GPS,
+            NtpDuration::from_exponent(-10),
+            0,
+            false,
+            base,
+        );
+        refclock.handle_sample(system, &config, NtpDuration::ZERO, base);
+        assert!(refclock.snapshot().reach.is_reachable());
+
+        for _ in 0..8 {
+            refclock.poll(system);
+        }
+
+        assert!(!refclock.snapshot().reach.is_reachable());
+    }
+}
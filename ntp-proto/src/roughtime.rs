@@ -0,0 +1,509 @@
+//! A client for the Roughtime protocol, which provides coarse but
+//! authenticated time.
+//!
+//! A request holds a random nonce. The server answers with a midpoint and a
+//! radius around it, signed with a short-lived key that is in turn delegated
+//! by the long-term key of the server. Servers sign a batch of requests at
+//! once: the signature covers the root of a merkle tree of the nonces, and
+//! the response contains the path from our nonce to that root.
+//!
+//! Messages are maps from 4 byte tags to values. They start with the number
+//! of tags, followed by the offsets of all but the first value, the tags in
+//! increasing order, and finally the values. All numbers are little endian,
+//! and all offsets and lengths are multiples of 4.
+
+use std::{fmt::Display, time::Duration};
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha512};
+
+use crate::{NtpDuration, NtpTimestamp};
+
+const SIG: [u8; 4] = *b"SIG\0";
+const NONC: [u8; 4] = *b"NONC";
+const PATH: [u8; 4] = *b"PATH";
+const SREP: [u8; 4] = *b"SREP";
+const CERT: [u8; 4] = *b"CERT";
+const INDX: [u8; 4] = *b"INDX";
+const DELE: [u8; 4] = *b"DELE";
+const PUBK: [u8; 4] = *b"PUBK";
+const MINT: [u8; 4] = *b"MINT";
+const MAXT: [u8; 4] = *b"MAXT";
+const ROOT: [u8; 4] = *b"ROOT";
+const MIDP: [u8; 4] = *b"MIDP";
+const RADI: [u8; 4] = *b"RADI";
+const PAD: [u8; 4] = *b"PAD\xff";
+
+const DELEGATION_CONTEXT: &[u8] = b"RoughTime v1 delegation signature--\0";
+const RESPONSE_CONTEXT: &[u8] = b"RoughTime v1 response signature\0";
+
+/// Requests are padded to this size, such that responses are never larger
+/// than requests, which would make servers useful for amplification attacks
+pub const ROUGHTIME_REQUEST_SIZE: usize = 1024;
+
+const NONCE_SIZE: usize = 64;
+const HASH_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoughtimeError {
+    /// The message is not a valid tag-value map
+    Malformed,
+    /// The message lacks a required tag
+    MissingTag([u8; 4]),
+    /// The value of a tag does not have the expected length
+    InvalidLength([u8; 4]),
+    /// The public key is not a valid Ed25519 key
+    InvalidPublicKey,
+    /// The delegation is not signed by the long-term key of the server
+    InvalidDelegation,
+    /// The response is not signed by the delegated key
+    InvalidSignature,
+    /// The merkle path does not lead from our nonce to the signed root
+    InvalidMerkleProof,
+    /// The midpoint lies outside of the validity of the delegated key
+    OutsideDelegation,
+}
+
+impl Display for RoughtimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => f.write_str("Malformed Roughtime message"),
+            Self::MissingTag(tag) => f.write_fmt(format_args!(
+                "Missing tag {}",
+                String::from_utf8_lossy(tag).trim_end_matches('\0')
+            )),
+            Self::InvalidLength(tag) => f.write_fmt(format_args!(
+                "Invalid length of tag {}",
+                String::from_utf8_lossy(tag).trim_end_matches('\0')
+            )),
+            Self::InvalidPublicKey => f.write_str("Invalid public key"),
+            Self::InvalidDelegation => f.write_str("Delegation not signed by the server key"),
+            Self::InvalidSignature => f.write_str("Response not signed by the delegated key"),
+            Self::InvalidMerkleProof => f.write_str("Merkle proof does not match the signed root"),
+            Self::OutsideDelegation => {
+                f.write_str("Midpoint outside of the validity of the delegated key")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RoughtimeError {}
+
+/// A parsed tag-value map, borrowing its values from the message
+struct Message<'a> {
+    fields: Vec<([u8; 4], &'a [u8])>,
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+impl<'a> Message<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, RoughtimeError> {
+        if data.len() < 4 || !data.chunks_exact(4).remainder().is_empty() {
+            return Err(RoughtimeError::Malformed);
+        }
+
+        let count = read_u32(data) as usize;
+        if count == 0 {
+            return Ok(Message { fields: vec![] });
+        }
+
+        let header = count
+            .checked_mul(8)
+            .filter(|header| *header <= data.len())
+            .ok_or(RoughtimeError::Malformed)?;
+        let offsets = &data[4..4 * count];
+        let tags = &data[4 * count..header];
+        let values = &data[header..];
+
+        let mut fields = Vec::with_capacity(count);
+        let mut previous: Option<u32> = None;
+        for i in 0..count {
+            let tag = [
+                tags[4 * i],
+                tags[4 * i + 1],
+                tags[4 * i + 2],
+                tags[4 * i + 3],
+            ];
+
+            // tags must be strictly increasing, which also rules out duplicates
+            let tag_int = u32::from_le_bytes(tag);
+            if matches!(previous, Some(previous) if previous >= tag_int) {
+                return Err(RoughtimeError::Malformed);
+            }
+            previous = Some(tag_int);
+
+            let start = match i {
+                0 => 0,
+                i => read_u32(&offsets[4 * (i - 1)..]) as usize,
+            };
+            let end = match i + 1 {
+                next if next == count => values.len(),
+                next => read_u32(&offsets[4 * (next - 1)..]) as usize,
+            };
+
+            // values are aligned to four bytes
+            if start & 0b11 != 0 || start > end || end > values.len() {
+                return Err(RoughtimeError::Malformed);
+            }
+
+            fields.push((tag, &values[start..end]));
+        }
+
+        Ok(Message { fields })
+    }
+
+    fn get(&self, tag: [u8; 4]) -> Result<&'a [u8], RoughtimeError> {
+        self.fields
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, value)| *value)
+            .ok_or(RoughtimeError::MissingTag(tag))
+    }
+
+    fn get_array<const N: usize>(&self, tag: [u8; 4]) -> Result<[u8; N], RoughtimeError> {
+        self.get(tag)?
+            .try_into()
+            .map_err(|_| RoughtimeError::InvalidLength(tag))
+    }
+
+    fn get_u32(&self, tag: [u8; 4]) -> Result<u32, RoughtimeError> {
+        self.get_array(tag).map(u32::from_le_bytes)
+    }
+
+    fn get_u64(&self, tag: [u8; 4]) -> Result<u64, RoughtimeError> {
+        self.get_array(tag).map(u64::from_le_bytes)
+    }
+}
+
+/// Serialize a tag-value map. The values must have lengths that are multiples of 4.
+fn encode(fields: &[([u8; 4], &[u8])]) -> Vec<u8> {
+    let mut fields = fields.to_vec();
+    fields.sort_by_key(|(tag, _)| u32::from_le_bytes(*tag));
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+
+    let mut offset = 0;
+    for (_, value) in fields.iter().take(fields.len().saturating_sub(1)) {
+        offset += value.len() as u32;
+        out.extend_from_slice(&offset.to_le_bytes());
+    }
+
+    for (tag, _) in &fields {
+        out.extend_from_slice(tag);
+    }
+
+    for (_, value) in &fields {
+        out.extend_from_slice(value);
+    }
+
+    out
+}
+
+fn verify(key: &VerifyingKey, context: &[u8], message: &[u8], signature: [u8; 64]) -> bool {
+    let mut signed = Vec::with_capacity(context.len() + message.len());
+    signed.extend_from_slice(context);
+    signed.extend_from_slice(message);
+
+    key.verify_strict(&signed, &Signature::from_bytes(&signature))
+        .is_ok()
+}
+
+fn hash_leaf(nonce: &[u8]) -> [u8; HASH_SIZE] {
+    Sha512::new()
+        .chain_update([0])
+        .chain_update(nonce)
+        .finalize()
+        .into()
+}
+
+fn hash_node(left: &[u8], right: &[u8]) -> [u8; HASH_SIZE] {
+    Sha512::new()
+        .chain_update([1])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// The time according to a Roughtime server, which is authenticated by its
+/// public key: the true time lies within `radius` of `midpoint`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoughtimeTime {
+    pub midpoint: NtpTimestamp,
+    pub radius: NtpDuration,
+}
+
+impl RoughtimeTime {
+    /// Whether the timestamp lies within the interval given by the server
+    pub fn contains(&self, time: NtpTimestamp) -> bool {
+        (time - self.midpoint).abs() <= self.radius
+    }
+}
+
+/// An outstanding request to a Roughtime server
+#[derive(Debug, Clone)]
+pub struct RoughtimeRequest {
+    nonce: [u8; NONCE_SIZE],
+}
+
+impl RoughtimeRequest {
+    /// A request with a random nonce
+    pub fn new() -> Self {
+        let mut nonce = [0; NONCE_SIZE];
+        thread_rng().fill(&mut nonce[..]);
+        RoughtimeRequest { nonce }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        // the header of a message with two tags is 16 bytes long
+        let padding = [0; ROUGHTIME_REQUEST_SIZE - 16 - NONCE_SIZE];
+        encode(&[(NONC, &self.nonce), (PAD, &padding)])
+    }
+
+    /// Verify that the response answers this request and is signed by the
+    /// server with the given long-term public key
+    pub fn verify_response(
+        &self,
+        data: &[u8],
+        public_key: &[u8; 32],
+    ) -> Result<RoughtimeTime, RoughtimeError> {
+        let server_key =
+            VerifyingKey::from_bytes(public_key).map_err(|_| RoughtimeError::InvalidPublicKey)?;
+
+        let response = Message::parse(data)?;
+
+        // the delegation of a short-lived key by the long-term key
+        let cert = Message::parse(response.get(CERT)?)?;
+        let delegation_data = cert.get(DELE)?;
+        let delegation_signature = cert.get_array(SIG)?;
+        if !verify(
+            &server_key,
+            DELEGATION_CONTEXT,
+            delegation_data,
+            delegation_signature,
+        ) {
+            return Err(RoughtimeError::InvalidDelegation);
+        }
+
+        let delegation = Message::parse(delegation_data)?;
+        let delegated_key = VerifyingKey::from_bytes(&delegation.get_array(PUBK)?)
+            .map_err(|_| RoughtimeError::InvalidPublicKey)?;
+        let min_time = delegation.get_u64(MINT)?;
+        let max_time = delegation.get_u64(MAXT)?;
+
+        // the signed response by the short-lived key
+        let signed_data = response.get(SREP)?;
+        let signature = response.get_array(SIG)?;
+        if !verify(&delegated_key, RESPONSE_CONTEXT, signed_data, signature) {
+            return Err(RoughtimeError::InvalidSignature);
+        }
+
+        let signed = Message::parse(signed_data)?;
+        let root: [u8; HASH_SIZE] = signed.get_array(ROOT)?;
+        let midpoint = signed.get_u64(MIDP)?;
+        let radius = signed.get_u32(RADI)?;
+
+        // the path from our nonce to the signed root
+        let path = response.get(PATH)?.chunks_exact(HASH_SIZE);
+        if !path.remainder().is_empty() {
+            return Err(RoughtimeError::InvalidLength(PATH));
+        }
+
+        let mut index = response.get_u32(INDX)?;
+        let mut hash = hash_leaf(&self.nonce);
+        for sibling in path {
+            hash = match index & 1 {
+                0 => hash_node(&hash, sibling),
+                _ => hash_node(sibling, &hash),
+            };
+            index >>= 1;
+        }
+
+        if index != 0 || hash != root {
+            return Err(RoughtimeError::InvalidMerkleProof);
+        }
+
+        if midpoint < min_time || midpoint > max_time {
+            return Err(RoughtimeError::OutsideDelegation);
+        }
+
+        let micros = Duration::from_micros(midpoint);
+        Ok(RoughtimeTime {
+            midpoint: NtpTimestamp::from_unix_seconds_nanos(
+                micros.as_secs() as i64,
+                micros.subsec_nanos(),
+            ),
+            radius: NtpDuration::from_system_duration(Duration::from_micros(radius as u64)),
+        })
+    }
+}
+
+impl Default for RoughtimeRequest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+
+    const SERVER_SEED: [u8; 32] = [7; 32];
+    const DELEGATED_SEED: [u8; 32] = [9; 32];
+    // 2023-01-01T00:00:00Z, in microseconds since the unix epoch
+    const MIDPOINT: u64 = 1_672_531_200_000_000;
+
+    fn sign(key: &SigningKey, context: &[u8], message: &[u8]) -> [u8; 64] {
+        let mut signed = context.to_vec();
+        signed.extend_from_slice(message);
+        key.sign(&signed).to_bytes()
+    }
+
+    /// A response to the request at `index` in a batch of 4 requests
+    fn respond(nonces: &[[u8; NONCE_SIZE]; 4], index: usize, midpoint: u64) -> Vec<u8> {
+        let server = SigningKey::from_bytes(&SERVER_SEED);
+        let delegated = SigningKey::from_bytes(&DELEGATED_SEED);
+
+        let leaves: Vec<_> = nonces.iter().map(|nonce| hash_leaf(nonce)).collect();
+        let nodes = [
+            hash_node(&leaves[0], &leaves[1]),
+            hash_node(&leaves[2], &leaves[3]),
+        ];
+        let root = hash_node(&nodes[0], &nodes[1]);
+
+        let mut path = leaves[index ^ 1].to_vec();
+        path.extend_from_slice(&nodes[(index >> 1) ^ 1]);
+
+        let delegation = encode(&[
+            (PUBK, delegated.verifying_key().as_bytes()),
+            (MINT, &(MIDPOINT - 3_600_000_000).to_le_bytes()),
+            (MAXT, &(MIDPOINT + 3_600_000_000).to_le_bytes()),
+        ]);
+        let cert = encode(&[
+            (DELE, &delegation),
+            (SIG, &sign(&server, DELEGATION_CONTEXT, &delegation)),
+        ]);
+        let signed = encode(&[
+            (ROOT, &root),
+            (MIDP, &midpoint.to_le_bytes()),
+            (RADI, &1_000_000u32.to_le_bytes()),
+        ]);
+
+        encode(&[
+            (SIG, &sign(&delegated, RESPONSE_CONTEXT, &signed)),
+            (PATH, &path),
+            (SREP, &signed),
+            (CERT, &cert),
+            (INDX, &(index as u32).to_le_bytes()),
+        ])
+    }
+
+    fn server_key() -> [u8; 32] {
+        SigningKey::from_bytes(&SERVER_SEED)
+            .verifying_key()
+            .to_bytes()
+    }
+
+    #[test]
+    fn test_request() {
+        let request = RoughtimeRequest::new();
+        let data = request.serialize();
+        assert_eq!(data.len(), ROUGHTIME_REQUEST_SIZE);
+
+        let message = Message::parse(&data).unwrap();
+        assert_eq!(message.get(NONC).unwrap(), &request.nonce);
+        assert_eq!(message.fields[1].0, PAD);
+
+        assert_ne!(RoughtimeRequest::new().nonce, request.nonce);
+    }
+
+    #[test]
+    fn test_verify_response() {
+        let requests: Vec<_> = (0..4).map(|_| RoughtimeRequest::new()).collect();
+        let nonces = [
+            requests[0].nonce,
+            requests[1].nonce,
+            requests[2].nonce,
+            requests[3].nonce,
+        ];
+
+        for (index, request) in requests.iter().enumerate() {
+            let response = respond(&nonces, index, MIDPOINT);
+            let time = request.verify_response(&response, &server_key()).unwrap();
+            assert_eq!(
+                time.midpoint,
+                NtpTimestamp::from_unix_seconds_nanos(1_672_531_200, 0)
+            );
+            assert_eq!(time.radius, NtpDuration::from_seconds(1.0));
+            assert!(time.contains(time.midpoint + NtpDuration::from_seconds(0.5)));
+            assert!(!time.contains(time.midpoint - NtpDuration::from_seconds(2.0)));
+        }
+
+        // a response to another request in the batch
+        let response = respond(&nonces, 1, MIDPOINT);
+        assert_eq!(
+            requests[0].verify_response(&response, &server_key()),
+            Err(RoughtimeError::InvalidMerkleProof)
+        );
+
+        // a response signed by another server
+        let other = SigningKey::from_bytes(&[1; 32]).verifying_key().to_bytes();
+        assert_eq!(
+            requests[1].verify_response(&response, &other),
+            Err(RoughtimeError::InvalidDelegation)
+        );
+
+        // a midpoint the delegated key is not valid for
+        let response = respond(&nonces, 2, MIDPOINT + 7_200_000_000);
+        assert_eq!(
+            requests[2].verify_response(&response, &server_key()),
+            Err(RoughtimeError::OutsideDelegation)
+        );
+    }
+
+    #[test]
+    fn test_tampered_response() {
+        let request = RoughtimeRequest::new();
+        let nonces = [request.nonce, [1; 64], [2; 64], [3; 64]];
+        let response = respond(&nonces, 0, MIDPOINT);
+
+        // every bit of the signed response is covered by the signature
+        let signed = Message::parse(&response).unwrap().get(SREP).unwrap();
+        let start = signed.as_ptr() as usize - response.as_ptr() as usize;
+        for i in start..start + signed.len() {
+            let mut tampered = response.clone();
+            tampered[i] ^= 1;
+            assert!(request.verify_response(&tampered, &server_key()).is_err());
+        }
+
+        assert_eq!(
+            request.verify_response(&response[..response.len() - 2], &server_key()),
+            Err(RoughtimeError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        assert!(Message::parse(&[]).is_err());
+        assert!(Message::parse(&[1, 0, 0]).is_err());
+
+        // more tags than fit in the message
+        assert!(Message::parse(&[2, 0, 0, 0, 4, 0, 0, 0]).is_err());
+
+        // tags out of order
+        let mut data = encode(&[(NONC, &[0; 4]), (PATH, &[0; 4])]);
+        data[8..16].rotate_left(4);
+        assert_eq!(Message::parse(&data).err(), Some(RoughtimeError::Malformed));
+
+        // an offset beyond the end of the message
+        let mut data = encode(&[(NONC, &[0; 4]), (PATH, &[0; 4])]);
+        data[4] = 12;
+        assert_eq!(Message::parse(&data).err(), Some(RoughtimeError::Malformed));
+    }
+}
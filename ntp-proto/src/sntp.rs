@@ -0,0 +1,355 @@
+//! Single time measurements, as made by SNTP clients (RFC 4330), for
+//! applications that need the time once rather than a clock that is kept in
+//! sync, such as installers and provisioning scripts.
+//!
+//! [`SntpClient`] makes the request and validates the response, leaving the
+//! sending and receiving to the caller. [`query`] does all of it with a
+//! blocking UDP socket and the system clock.
+//!
+//! ```no_run
+//! let measurement = ntp_proto::sntp::query("pool.ntp.org:123", std::time::Duration::from_secs(5))?;
+//! println!("offset {} s", measurement.offset.to_seconds());
+//! # Ok::<(), ntp_proto::sntp::SntpError>(())
+//! ```
+
+use std::{
+    fmt::Display,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    packet::RequestIdentifier, NtpAssociationMode, NtpDuration, NtpLeapIndicator, NtpPacket,
+    NtpTimestamp, PacketParsingError, PollInterval, ReferenceId, ResponseValidationError,
+};
+
+/// Responses are no larger than requests, but servers may add extension fields
+const MAX_RESPONSE_SIZE: usize = 1024;
+
+#[derive(Debug)]
+pub enum SntpError {
+    /// The address could not be resolved, or the socket failed
+    Io(std::io::Error),
+    /// No valid response arrived in time
+    Timeout,
+    /// The response is not a valid NTP packet
+    Parse(PacketParsingError),
+    /// The response does not answer our request
+    InvalidResponse(ResponseValidationError),
+    /// The response is not in server mode
+    UnexpectedMode(NtpAssociationMode),
+    /// The server sent a Kiss-o'-Death with this code instead of its time
+    Kiss(ReferenceId),
+    /// The server is not synchronized itself
+    Unsynchronized,
+}
+
+impl Display for SntpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "{error}"),
+            Self::Timeout => f.write_str("No response in time"),
+            Self::Parse(error) => write!(f, "Invalid packet: {error}"),
+            Self::InvalidResponse(error) => write!(f, "Invalid response: {error}"),
+            Self::UnexpectedMode(mode) => write!(f, "Unexpected mode {mode:?}"),
+            Self::Kiss(code) => write!(
+                f,
+                "Kiss-o'-Death {}",
+                String::from_utf8_lossy(&code.to_bytes())
+            ),
+            Self::Unsynchronized => f.write_str("Server is not synchronized"),
+        }
+    }
+}
+
+impl std::error::Error for SntpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::InvalidResponse(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SntpError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+/// A single measurement of the clock of a server against ours
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OffsetDelay {
+    /// The time of the server minus ours, so positive when our clock is behind
+    pub offset: NtpDuration,
+    /// The round trip time, without the time the server took to respond
+    pub delay: NtpDuration,
+    pub stratum: u8,
+    pub leap: NtpLeapIndicator,
+}
+
+/// A request to a server, and the validation of its response
+#[derive(Debug, Clone)]
+pub struct SntpClient {
+    request: Vec<u8>,
+    identifier: RequestIdentifier,
+}
+
+impl SntpClient {
+    /// A request with an entirely random transmit timestamp, such that it
+    /// does not reveal our time
+    pub fn new() -> Self {
+        let (packet, identifier) = NtpPacket::poll_message(PollInterval::default());
+        let mut request = Vec::with_capacity(48);
+        // writing to a vector does not fail
+        let _ = packet.serialize(&mut request);
+
+        SntpClient {
+            request,
+            identifier,
+        }
+    }
+
+    /// The request to send to the server
+    pub fn serialize(&self) -> &[u8] {
+        &self.request
+    }
+
+    /// The measurement from the response to our request, which was sent at
+    /// `send_time` and received at `recv_time` according to our clock
+    pub fn handle_response(
+        &self,
+        data: &[u8],
+        send_time: NtpTimestamp,
+        recv_time: NtpTimestamp,
+    ) -> Result<OffsetDelay, SntpError> {
+        let packet = NtpPacket::deserialize(data).map_err(SntpError::Parse)?;
+
+        if packet.mode() != NtpAssociationMode::Server {
+            return Err(SntpError::UnexpectedMode(packet.mode()));
+        }
+
+        packet
+            .validate_server_response(self.identifier)
+            .map_err(SntpError::InvalidResponse)?;
+
+        if packet.is_kiss() {
+            return Err(SntpError::Kiss(packet.reference_id()));
+        }
+
+        if packet.leap() == NtpLeapIndicator::Unknown || packet.stratum() >= 16 {
+            return Err(SntpError::Unsynchronized);
+        }
+
+        // the timestamps of RFC 4330: T1 and T4 are ours, T2 and T3 the server's
+        let offset1 = packet.receive_timestamp() - send_time;
+        let offset2 = packet.transmit_timestamp() - recv_time;
+        let round_trip = recv_time - send_time;
+        let processing = packet.transmit_timestamp() - packet.receive_timestamp();
+
+        Ok(OffsetDelay {
+            offset: (offset1 + offset2) / 2i64,
+            // with clocks that run at different rates the delay can come out
+            // negative on a very fast network
+            delay: Ord::max(round_trip - processing, NtpDuration::ZERO),
+            stratum: packet.stratum(),
+            leap: packet.leap(),
+        })
+    }
+}
+
+impl Default for SntpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn system_time() -> NtpTimestamp {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => NtpTimestamp::from_unix_seconds_nanos(
+            since_epoch.as_secs() as i64,
+            since_epoch.subsec_nanos(),
+        ),
+        // a clock before 1970 is far off, which the measurement will show
+        Err(_) => NtpTimestamp::from_unix_seconds_nanos(0, 0),
+    }
+}
+
+/// Measure the clock of the server at `addr` against the system clock,
+/// waiting at most `timeout` for a valid response.
+///
+/// Responses that do not answer our request, e.g. late responses to an
+/// earlier request, are skipped. Kiss-o'-Death packets and responses of
+/// unsynchronized servers end the query with an error.
+pub fn query(addr: impl ToSocketAddrs, timeout: Duration) -> Result<OffsetDelay, SntpError> {
+    let deadline = Instant::now() + timeout;
+
+    let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "address did not resolve")
+    })?;
+    let bind_addr: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.connect(addr)?;
+
+    let client = SntpClient::new();
+    let send_time = system_time();
+    socket.send(client.serialize())?;
+
+    let mut buf = [0; MAX_RESPONSE_SIZE];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(SntpError::Timeout);
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let size = match socket.recv(&mut buf) {
+            Ok(size) => size,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(SntpError::Timeout)
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let recv_time = system_time();
+
+        match client.handle_response(&buf[..size], send_time, recv_time) {
+            Err(SntpError::Parse(_))
+            | Err(SntpError::InvalidResponse(
+                ResponseValidationError::OriginMismatch | ResponseValidationError::ZeroOrigin,
+            )) => continue,
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(client: &SntpClient, receive: NtpTimestamp, transmit: NtpTimestamp) -> Vec<u8> {
+        let request = NtpPacket::deserialize(client.serialize()).unwrap();
+        let mut packet = NtpPacket::test();
+        packet.set_mode(NtpAssociationMode::Server);
+        packet.set_stratum(2);
+        packet.set_leap(NtpLeapIndicator::NoWarning);
+        packet.set_origin_timestamp(request.transmit_timestamp());
+        packet.set_receive_timestamp(receive);
+        packet.set_transmit_timestamp(transmit);
+
+        let mut data = vec![];
+        packet.serialize(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_measurement() {
+        let client = SntpClient::new();
+        let send_time = NtpTimestamp::from_seconds_nanos_since_ntp_era(1000, 0);
+        let recv_time = NtpTimestamp::from_seconds_nanos_since_ntp_era(1000, 100_000_000);
+
+        // the server is 1 second ahead, 40 ms away and takes 20 ms to respond
+        let data = response(
+            &client,
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(1001, 40_000_000),
+            NtpTimestamp::from_seconds_nanos_since_ntp_era(1001, 60_000_000),
+        );
+        let measurement = client.handle_response(&data, send_time, recv_time).unwrap();
+
+        assert!((measurement.offset.to_seconds() - 1.0).abs() < 1e-6);
+        assert!((measurement.delay.to_seconds() - 0.08).abs() < 1e-6);
+        assert_eq!(measurement.stratum, 2);
+        assert_eq!(measurement.leap, NtpLeapIndicator::NoWarning);
+    }
+
+    #[test]
+    fn test_invalid_responses() {
+        let client = SntpClient::new();
+        let time = NtpTimestamp::from_seconds_nanos_since_ntp_era(1000, 0);
+        let valid = response(&client, time, time);
+
+        // a response to another request
+        let other = response(&SntpClient::new(), time, time);
+        assert!(matches!(
+            client.handle_response(&other, time, time),
+            Err(SntpError::InvalidResponse(
+                ResponseValidationError::OriginMismatch
+            ))
+        ));
+
+        let mut kiss = valid.clone();
+        kiss[1] = 0;
+        kiss[12..16].copy_from_slice(b"RATE");
+        assert!(matches!(
+            client.handle_response(&kiss, time, time),
+            Err(SntpError::Kiss(ReferenceId::KISS_RATE))
+        ));
+
+        let mut unsynchronized = valid.clone();
+        unsynchronized[0] |= 0xc0;
+        assert!(matches!(
+            client.handle_response(&unsynchronized, time, time),
+            Err(SntpError::Unsynchronized)
+        ));
+
+        // our own request, reflected back
+        let mut reflected = valid.clone();
+        reflected[0] = (reflected[0] & !0x07) | 3;
+        assert!(matches!(
+            client.handle_response(&reflected, time, time),
+            Err(SntpError::UnexpectedMode(NtpAssociationMode::Client))
+        ));
+
+        assert!(matches!(
+            client.handle_response(&valid[..40], time, time),
+            Err(SntpError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_query() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 48];
+            let (_, client_addr) = server.recv_from(&mut buf).unwrap();
+            let request = NtpPacket::deserialize(&buf).unwrap();
+
+            // first a stray packet, then the response of a server 10 s ahead
+            server.send_to(b"stray", client_addr).unwrap();
+            let now = system_time() + NtpDuration::from_seconds(10.0);
+            let mut packet = NtpPacket::test();
+            packet.set_mode(NtpAssociationMode::Server);
+            packet.set_stratum(1);
+            packet.set_leap(NtpLeapIndicator::NoWarning);
+            packet.set_origin_timestamp(request.transmit_timestamp());
+            packet.set_receive_timestamp(now);
+            packet.set_transmit_timestamp(now);
+            let mut data = vec![];
+            packet.serialize(&mut data).unwrap();
+            server.send_to(&data, client_addr).unwrap();
+        });
+
+        let measurement = query(addr, Duration::from_secs(5)).unwrap();
+        handle.join().unwrap();
+
+        assert!((measurement.offset.to_seconds() - 10.0).abs() < 1.0);
+        assert_eq!(measurement.stratum, 1);
+
+        // nobody answers
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        assert!(matches!(
+            query(silent.local_addr().unwrap(), Duration::from_millis(50)),
+            Err(SntpError::Timeout)
+        ));
+    }
+}
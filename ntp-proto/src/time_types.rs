@@ -2,9 +2,153 @@ use rand::{
     distributions::{Distribution, Standard},
     Rng,
 };
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
+use std::fmt::Display;
 use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
-use std::time::{Duration, Instant};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::instant::Instant;
+
+/// Number of days between 1900-01-01 (the NTP epoch) and 1970-01-01
+pub(crate) const DAYS_NTP_TO_UNIX: i64 = 70 * 365 + 17;
+
+pub(crate) const SECONDS_PER_DAY: i64 = 86400;
+
+/// Seconds between 1900-01-01 (the NTP epoch) and 1970-01-01
+const SECONDS_NTP_TO_UNIX: i64 = DAYS_NTP_TO_UNIX * SECONDS_PER_DAY;
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// Year, month and day of the given number of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400;
+
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeParsingError {
+    /// The number could not be parsed
+    InvalidNumber,
+    /// The unit is not one of s, ms, us, µs or ns
+    UnknownUnit,
+    /// The value is too large for the type
+    OutOfRange,
+    /// The text is not a valid ISO 8601 date and time with a UTC offset
+    InvalidTimestamp,
+}
+
+impl Display for TimeParsingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidNumber => f.write_str("Invalid number"),
+            Self::UnknownUnit => f.write_str("Unknown unit, expected s, ms, us or ns"),
+            Self::OutOfRange => f.write_str("Value out of range"),
+            Self::InvalidTimestamp => f.write_str("Invalid timestamp"),
+        }
+    }
+}
+
+impl std::error::Error for TimeParsingError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeConversionError {
+    /// The value cannot be represented by the target type. For timestamps,
+    /// this is a time outside the era window of 1968-01-20 to 2104-02-26.
+    OutOfRange,
+    /// The target type cannot represent negative durations
+    Negative,
+}
+
+impl Display for TimeConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OutOfRange => f.write_str("Time out of range"),
+            Self::Negative => f.write_str("Negative duration"),
+        }
+    }
+}
+
+impl std::error::Error for TimeConversionError {}
+
+/// Seconds since the unix epoch at which NTP era 1 starts, 2036-02-07T06:28:16Z
+const ERA_1_START: i64 = (1 << 32) - SECONDS_NTP_TO_UNIX;
+
+/// Seconds since the unix epoch at which the fixed era window of timestamps
+/// starts, 1968-01-20T03:14:08Z
+const ERA_WINDOW_START: i64 = ERA_1_START - (1 << 31);
+
+/// Multiples of `1 / 2^32` seconds in the given number of nanoseconds,
+/// rounded down
+fn nanos_to_fixed(nanos: i128) -> i128 {
+    (nanos << 32).div_euclid(1_000_000_000)
+}
+
+/// Nanoseconds in the given multiple of `1 / 2^32` seconds, rounded up, such
+/// that it is the exact inverse of [`nanos_to_fixed`]
+fn fixed_to_nanos(fixed: i128) -> i128 {
+    -(-fixed * 1_000_000_000).div_euclid(1 << 32)
+}
+
+/// Parse a decimal number, such as `-1.25`, as a multiple of `1 / 2^32`
+/// of the unit, of which there are `per_second` in a second, rounded towards
+/// zero
+fn parse_fixed_point(number: &str, per_second: i128) -> Result<i128, TimeParsingError> {
+    let (negative, number) = match number.strip_prefix('-') {
+        Some(number) => (true, number),
+        None => (false, number.strip_prefix('+').unwrap_or(number)),
+    };
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+
+    let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    if (whole.is_empty() && fraction.is_empty()) || !is_digits(whole) || !is_digits(fraction) {
+        return Err(TimeParsingError::InvalidNumber);
+    }
+
+    // digits beyond the precision of the result are dropped
+    let fraction = &fraction[..fraction.len().min(12)];
+
+    let mut mantissa: i128 = 0;
+    for digit in whole.bytes().chain(fraction.bytes()) {
+        mantissa = mantissa * 10 + (digit - b'0') as i128;
+
+        // leaves room for the fixed point shift below
+        if mantissa > i128::MAX >> 32 {
+            return Err(TimeParsingError::OutOfRange);
+        }
+    }
+    let scale = 10_i128.pow(fraction.len() as u32) * per_second;
+
+    let fixed = (mantissa << 32) / scale;
+    Ok(if negative { -fixed } else { fixed })
+}
 
 /// NtpInstant is a monotonically increasing value modelling the uptime of the NTP service
 ///
@@ -43,6 +187,14 @@ impl NtpInstant {
     pub fn elapsed(&self) -> std::time::Duration {
         self.instant.elapsed()
     }
+
+    /// The instant `duration` before this one, if the monotonic clock goes
+    /// back that far
+    pub fn checked_sub(self, duration: Duration) -> Option<Self> {
+        Some(Self {
+            instant: self.instant.checked_sub(duration)?,
+        })
+    }
 }
 
 impl Add<Duration> for NtpInstant {
@@ -56,7 +208,15 @@ impl Add<Duration> for NtpInstant {
 }
 
 /// NtpTimestamp represents an ntp timestamp without the era number.
+///
+/// Arithmetic wraps around at the end of an era, such that the difference of
+/// two timestamps is correct as long as they are less than 68 years apart,
+/// also when they lie on different sides of an era boundary. Ordering compares
+/// the value within the era, and should not be used to order points in time.
+///
+/// It is serialized as the 64 bit fixed point number of the on-wire format.
 #[derive(Copy, Clone, Eq, PartialEq, PartialOrd, Ord, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
 pub struct NtpTimestamp {
     timestamp: u64,
 }
@@ -69,6 +229,15 @@ impl std::fmt::Debug for NtpTimestamp {
     }
 }
 
+/// Formats as seconds since the start of the NTP era, with nanosecond
+/// precision, like the statistics files of ntpd
+impl std::fmt::Display for NtpTimestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let nanos = ((self.timestamp & 0xFFFF_FFFF) * 1_000_000_000) >> 32;
+        write!(f, "{}.{:09}", self.timestamp >> 32, nanos)
+    }
+}
+
 impl NtpTimestamp {
     pub(crate) const fn from_bits(bits: [u8; 8]) -> NtpTimestamp {
         NtpTimestamp {
@@ -98,12 +267,199 @@ impl NtpTimestamp {
         NtpTimestamp::from_bits(timestamp.to_be_bytes())
     }
 
+    /// Number of whole seconds that have passed since the last ntp era boundary.
+    pub(crate) const fn seconds_since_ntp_era(self) -> u32 {
+        (self.timestamp >> 32) as u32
+    }
+
+    /// Offset from this timestamp to the nearest whole second, which is
+    /// positive when the timestamp lies just before that second. Used to turn
+    /// a pulse-per-second edge into a measurement, as the edge marks the
+    /// start of a second.
+    pub const fn offset_to_nearest_second(self) -> NtpDuration {
+        // Interpreting the fraction as signed maps the second half of each
+        // second to the negative distance to the next second.
+        NtpDuration {
+            duration: -((self.timestamp as u32 as i32) as i64),
+        }
+    }
+
+    /// The timestamp of the given time since the unix epoch. Timestamps do
+    /// not include the NTP era, so only the time within the era is kept.
+    pub fn from_unix_seconds_nanos(seconds: i64, nanos: u32) -> Self {
+        let seconds = seconds.wrapping_add(SECONDS_NTP_TO_UNIX);
+
+        // Truncating to the era is intended, eras are not represented in timestamps
+        Self::from_seconds_nanos_since_ntp_era(seconds as u32, nanos)
+    }
+
+    /// Seconds and nanoseconds since the unix epoch (negative before 1970).
+    ///
+    /// Timestamps do not include the NTP era, so this picks the era for which
+    /// the timestamp lies between 1968-01-20 and 2104-02-26, as described in
+    /// RFC 4330. Use [`NtpTimestamp::to_unix_seconds_nanos_near`] or
+    /// [`NtpTimestamp::to_system_time`] for times outside of that window.
+    pub fn to_unix_seconds_nanos(self) -> (i64, u32) {
+        self.to_unix_seconds_nanos_near(ERA_1_START)
+    }
+
+    /// Seconds and nanoseconds since the unix epoch, in the era that puts the
+    /// timestamp closest to `reference`, in seconds since the unix epoch. This
+    /// is correct for timestamps less than 68 years away from the reference.
+    pub fn to_unix_seconds_nanos_near(self, reference: i64) -> (i64, u32) {
+        let reference_timestamp = NtpTimestamp::from_unix_seconds_nanos(reference, 0);
+
+        // the reference has no fraction, so this is rounded down to whole seconds
+        let difference = (self - reference_timestamp).duration >> 32;
+
+        // fractions that round up to a whole second do not result from any
+        // number of nanoseconds, and are kept within the second
+        let nanos = fixed_to_nanos((self.timestamp & 0xFFFF_FFFF) as i128).min(999_999_999);
+
+        (reference + difference, nanos as u32)
+    }
+
+    /// The wall-clock time of the timestamp, in the era that puts it closest
+    /// to the current time of the system clock
+    pub fn to_system_time(self) -> SystemTime {
+        let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_secs() as i64,
+            Err(error) => -(error.duration().as_secs() as i64),
+        };
+
+        let (seconds, nanos) = self.to_unix_seconds_nanos_near(now);
+        system_time_from_unix(seconds, nanos)
+    }
+
+    fn try_from_unix_nanos(nanos: i128) -> Result<Self, TimeConversionError> {
+        let seconds = nanos.div_euclid(1_000_000_000);
+        let window = ERA_WINDOW_START as i128..ERA_WINDOW_START as i128 + (1 << 32);
+        if !window.contains(&seconds) {
+            return Err(TimeConversionError::OutOfRange);
+        }
+
+        Ok(Self::from_unix_seconds_nanos(
+            seconds as i64,
+            nanos.rem_euclid(1_000_000_000) as u32,
+        ))
+    }
+
+    /// Formats as an ISO 8601 UTC date and time with nanosecond precision,
+    /// such as `2023-04-27T12:34:56.500000000Z`, with the era chosen like
+    /// [`NtpTimestamp::to_unix_seconds_nanos`]
+    pub fn iso8601(self) -> impl Display {
+        struct Iso8601(NtpTimestamp);
+
+        impl Display for Iso8601 {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                let (seconds, nanos) = self.0.to_unix_seconds_nanos();
+                let (year, month, day) = civil_from_days(seconds.div_euclid(SECONDS_PER_DAY));
+                let seconds_of_day = seconds.rem_euclid(SECONDS_PER_DAY);
+
+                write!(
+                    f,
+                    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+                    year,
+                    month,
+                    day,
+                    seconds_of_day / 3600,
+                    seconds_of_day / 60 % 60,
+                    seconds_of_day % 60,
+                    nanos
+                )
+            }
+        }
+
+        Iso8601(self)
+    }
+
+    fn parse_iso8601(s: &str) -> Option<Self> {
+        let (date, time) = s.split_once(['T', 't', ' '])?;
+
+        let mut date_parts = date.splitn(3, '-');
+        let year: i64 = parse_digits(date_parts.next()?, 4)?;
+        let month: i64 = parse_digits(date_parts.next()?, 2)?;
+        let day: i64 = parse_digits(date_parts.next()?, 2)?;
+        let days = days_from_civil(year, month, day);
+        if !(1..=12).contains(&month) || civil_from_days(days) != (year, month, day) {
+            return None;
+        }
+
+        // the offset from UTC, which is required to make the time unambiguous
+        let (time, offset) = match time.strip_suffix(['Z', 'z']) {
+            Some(time) => (time, 0),
+            None => {
+                let split = time.rfind(['+', '-'])?;
+                let (time, offset) = time.split_at(split);
+                let (hours, minutes) = offset[1..].split_once(':')?;
+                let seconds =
+                    parse_digits::<i64>(hours, 2)? * 3600 + parse_digits::<i64>(minutes, 2)? * 60;
+                match offset.starts_with('-') {
+                    true => (time, -seconds),
+                    false => (time, seconds),
+                }
+            }
+        };
+
+        let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+        let mut time_parts = time.splitn(3, ':');
+        let hour: i64 = parse_digits(time_parts.next()?, 2)?;
+        let minute: i64 = parse_digits(time_parts.next()?, 2)?;
+        // 60 is allowed for leap seconds, which then end up in the next minute
+        let second: i64 = parse_digits(time_parts.next()?, 2)?;
+        if hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+
+        if fraction.is_empty() && s.contains('.') || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let mut nanos = 0;
+        for i in 0..9 {
+            let digit = fraction.as_bytes().get(i).map_or(0, |b| b - b'0');
+            nanos = nanos * 10 + digit as u32;
+        }
+
+        let seconds = days * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second - offset;
+        Some(Self::from_unix_seconds_nanos(seconds, nanos))
+    }
+
     #[cfg(any(test, feature = "fuzz"))]
     pub(crate) const fn from_fixed_int(timestamp: u64) -> NtpTimestamp {
         NtpTimestamp { timestamp }
     }
 }
 
+/// Parse a field of exactly `len` ascii digits
+fn parse_digits<T: FromStr>(field: &str, len: usize) -> Option<T> {
+    if field.len() != len || !field.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    field.parse().ok()
+}
+
+/// Parses either an ISO 8601 date and time with a UTC offset, such as
+/// `2023-04-27T12:34:56.5Z`, or seconds since the start of the NTP era, as
+/// formatted by `Display`
+impl FromStr for NtpTimestamp {
+    type Err = TimeParsingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(['-', ':']) {
+            return Self::parse_iso8601(s).ok_or(TimeParsingError::InvalidTimestamp);
+        }
+
+        if s.starts_with('+') {
+            return Err(TimeParsingError::InvalidNumber);
+        }
+        let timestamp = parse_fixed_point(s, 1)?;
+
+        Ok(NtpTimestamp {
+            timestamp: u64::try_from(timestamp).map_err(|_| TimeParsingError::OutOfRange)?,
+        })
+    }
+}
+
 // In order to provide increased entropy on origin timestamps,
 // we should generate these randomly. This helps avoid
 // attacks from attackers guessing our current time.
@@ -193,12 +549,88 @@ impl std::fmt::Debug for NtpDuration {
     }
 }
 
+/// Formats in the largest unit of s, ms, us and ns in which the duration is
+/// at least 1, such as `1.5ms`. By default up to 3 decimals are shown, with
+/// trailing zeros removed; the precision of the formatter overrides this.
+impl Display for NtpDuration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // nanoseconds, rounded to the nearest
+        let nanos = ((self.duration as i128) * 1_000_000_000 + (1 << 31)) >> 32;
+        let sign = if nanos < 0 { "-" } else { "" };
+        let nanos = nanos.unsigned_abs();
+
+        let (unit, digits) = match nanos {
+            n if n >= 1_000_000_000 => ("s", 9),
+            n if n >= 1_000_000 => ("ms", 6),
+            n if n >= 1_000 => ("us", 3),
+            _ => ("ns", 0),
+        };
+        let precision = f.precision().unwrap_or(3).min(digits);
+
+        // rounded to the number of decimals shown
+        let step = 10_u128.pow((digits - precision) as u32);
+        let rounded = (nanos + step / 2) / step;
+        let scale = 10_u128.pow(precision as u32);
+        let (whole, mut fraction) = (rounded / scale, rounded % scale);
+
+        let mut decimals = [b'0'; 9];
+        for decimal in decimals[..precision].iter_mut().rev() {
+            *decimal = b'0' + (fraction % 10) as u8;
+            fraction /= 10;
+        }
+        let mut len = precision;
+        if f.precision().is_none() {
+            while len > 0 && decimals[len - 1] == b'0' {
+                len -= 1;
+            }
+        }
+
+        write!(f, "{}{}", sign, whole)?;
+        if len > 0 {
+            f.write_str(".")?;
+            for &decimal in &decimals[..len] {
+                write!(f, "{}", decimal as char)?;
+            }
+        }
+        f.write_str(unit)
+    }
+}
+
+/// Parses a decimal number followed by an optional unit of s, ms, us, µs or
+/// ns, such as `1.5ms`. Numbers without a unit are in seconds.
+impl FromStr for NtpDuration {
+    type Err = TimeParsingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| c.is_alphabetic()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+
+        let per_second = match unit {
+            "" | "s" => 1,
+            "ms" => 1_000,
+            "us" | "µs" => 1_000_000,
+            "ns" => 1_000_000_000,
+            _ => return Err(TimeParsingError::UnknownUnit),
+        };
+
+        let duration = parse_fixed_point(number.trim_end(), per_second)?;
+
+        Ok(NtpDuration {
+            duration: i64::try_from(duration).map_err(|_| TimeParsingError::OutOfRange)?,
+        })
+    }
+}
+
 impl NtpDuration {
     pub const ZERO: Self = Self { duration: 0 };
+    pub const MAX: Self = Self { duration: i64::MAX };
+    pub const MIN: Self = Self { duration: i64::MIN };
     pub(crate) const ONE: Self = Self { duration: 1 << 32 };
 
-    /// NtpDuration::from_seconds(0.125)
-    pub(crate) const STEP_THRESHOLD: Self = Self { duration: 1 << 29 };
+    /// NtpDuration::from_seconds(0.125). Larger offsets are corrected by
+    /// stepping the clock rather than slewing it
+    pub const STEP_THRESHOLD: Self = Self { duration: 1 << 29 };
 
     /// NtpDuration::from_seconds(16.0)
     pub(crate) const MAX_DISPERSION: Self = Self {
@@ -221,22 +653,19 @@ impl NtpDuration {
     }
 
     pub(crate) const fn to_bits_short(self) -> [u8; 4] {
-        // serializing negative durations should never happen
-        // and indicates a programming error elsewhere.
-        // as for duration that are too large, saturating is
-        // the safe option.
-        assert!(self.duration >= 0);
-
-        // Although saturating is safe to do, it probably still
-        // should never happen in practice, so ensure we will
-        // see it when running in debug mode.
-        debug_assert!(self.duration <= 0x0000FFFFFFFFFFFF);
+        // The root delay and dispersion we send are derived from the
+        // values of our sources, which can be anything. Saturating keeps
+        // such values from crashing the server: negative durations become
+        // zero, and durations that are too large the largest value.
+        let bits = if self.duration < 0 {
+            0
+        } else if self.duration > 0x0000FFFFFFFFFFFF {
+            0xFFFFFFFF_u32
+        } else {
+            ((self.duration & 0x0000FFFFFFFF0000) >> 16) as u32
+        };
 
-        match self.duration > 0x0000FFFFFFFFFFFF {
-            true => 0xFFFFFFFF_u32,
-            false => ((self.duration & 0x0000FFFFFFFF0000) >> 16) as u32,
-        }
-        .to_be_bytes()
+        bits.to_be_bytes()
     }
 
     /// Convert to an f64; required for statistical calculations
@@ -263,10 +692,59 @@ impl NtpDuration {
         Self { duration }
     }
 
-    /// Interval of same length, but positive direction
+    /// Interval of same length, but positive direction. Saturates at
+    /// [`NtpDuration::MAX`] for [`NtpDuration::MIN`].
     pub const fn abs(self) -> Self {
         Self {
-            duration: self.duration.abs(),
+            duration: self.duration.saturating_abs(),
+        }
+    }
+
+    /// Sum of the durations, or `None` on overflow
+    pub const fn checked_add(self, rhs: Self) -> Option<Self> {
+        match self.duration.checked_add(rhs.duration) {
+            Some(duration) => Some(Self { duration }),
+            None => None,
+        }
+    }
+
+    /// Difference of the durations, or `None` on overflow
+    pub const fn checked_sub(self, rhs: Self) -> Option<Self> {
+        match self.duration.checked_sub(rhs.duration) {
+            Some(duration) => Some(Self { duration }),
+            None => None,
+        }
+    }
+
+    /// The duration multiplied by `rhs`, or `None` on overflow
+    pub const fn checked_mul(self, rhs: i64) -> Option<Self> {
+        match self.duration.checked_mul(rhs) {
+            Some(duration) => Some(Self { duration }),
+            None => None,
+        }
+    }
+
+    /// Sum of the durations, saturating at [`NtpDuration::MIN`] and
+    /// [`NtpDuration::MAX`]. This is also what `+` does.
+    pub const fn saturating_add(self, rhs: Self) -> Self {
+        Self {
+            duration: self.duration.saturating_add(rhs.duration),
+        }
+    }
+
+    /// Difference of the durations, saturating at [`NtpDuration::MIN`] and
+    /// [`NtpDuration::MAX`]. This is also what `-` does.
+    pub const fn saturating_sub(self, rhs: Self) -> Self {
+        Self {
+            duration: self.duration.saturating_sub(rhs.duration),
+        }
+    }
+
+    /// The duration multiplied by `rhs`, saturating at [`NtpDuration::MIN`]
+    /// and [`NtpDuration::MAX`]. This is also what `*` does.
+    pub const fn saturating_mul(self, rhs: i64) -> Self {
+        Self {
+            duration: self.duration.saturating_mul(rhs),
         }
     }
 
@@ -326,6 +804,7 @@ impl NtpDuration {
     }
 }
 
+#[cfg(feature = "serde")]
 impl Serialize for NtpDuration {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -336,13 +815,41 @@ impl Serialize for NtpDuration {
     }
 }
 
+/// Deserializes from a number of seconds, or from a string with a unit such
+/// as `"1.5ms"`
+#[cfg(feature = "serde")]
 impl<'de> Deserialize<'de> for NtpDuration {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        let seconds: f64 = Deserialize::deserialize(deserializer)?;
-        Ok(NtpDuration::from_seconds(seconds))
+        struct NtpDurationVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for NtpDurationVisitor {
+            type Value = NtpDuration;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a number of seconds or a duration with a unit")
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                Ok(NtpDuration::from_seconds(v))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                self.visit_f64(v as f64)
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                self.visit_f64(v as f64)
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(NtpDurationVisitor)
     }
 }
 
@@ -354,9 +861,7 @@ impl Add for NtpDuration {
         // addition or substraction of two big durations never
         // unintentionally cancel, ensuring that filtering
         // can properly reject on the result.
-        NtpDuration {
-            duration: self.duration.saturating_add(rhs.duration),
-        }
+        self.saturating_add(rhs)
     }
 }
 
@@ -366,7 +871,7 @@ impl AddAssign for NtpDuration {
         // addition or substraction of two big durations never
         // unintentionally cancel, ensuring that filtering
         // can properly reject on the result.
-        self.duration = self.duration.saturating_add(rhs.duration);
+        *self = self.saturating_add(rhs);
     }
 }
 
@@ -378,9 +883,7 @@ impl Sub for NtpDuration {
         // addition or substraction of two big durations never
         // unintentionally cancel, ensuring that filtering
         // can properly reject on the result.
-        NtpDuration {
-            duration: self.duration.saturating_sub(rhs.duration),
-        }
+        self.saturating_sub(rhs)
     }
 }
 
@@ -390,7 +893,7 @@ impl SubAssign for NtpDuration {
         // addition or substraction of two big durations never
         // unintentionally cancel, ensuring that filtering
         // can properly reject on the result.
-        self.duration = self.duration.saturating_sub(rhs.duration);
+        *self = self.saturating_sub(rhs);
     }
 }
 
@@ -398,8 +901,9 @@ impl Neg for NtpDuration {
     type Output = NtpDuration;
 
     fn neg(self) -> Self::Output {
+        // saturating, as NtpDuration::MIN has no positive counterpart
         NtpDuration {
-            duration: -self.duration,
+            duration: self.duration.saturating_neg(),
         }
     }
 }
@@ -462,17 +966,17 @@ macro_rules! ntp_duration_scalar_div {
             type Output = NtpDuration;
 
             fn div(self, rhs: $scalar_type) -> NtpDuration {
-                // No overflow risks for division
+                // Only dividing NtpDuration::MIN by -1 overflows
                 NtpDuration {
-                    duration: self.duration / (rhs as i64),
+                    duration: self.duration.saturating_div(rhs as i64),
                 }
             }
         }
 
         impl DivAssign<$scalar_type> for NtpDuration {
             fn div_assign(&mut self, rhs: $scalar_type) {
-                // No overflow risks for division
-                self.duration /= (rhs as i64);
+                // Only dividing NtpDuration::MIN by -1 overflows
+                self.duration = self.duration.saturating_div(rhs as i64);
             }
         }
     };
@@ -494,10 +998,12 @@ ntp_duration_scalar_div!(u32);
 //
 // - a value of 4 means 2^4 = 16 seconds
 // - a value of 17 is 2^17 = ~36h
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PollInterval(i8);
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PollIntervalLimits {
     pub min: PollInterval,
     pub max: PollInterval,
@@ -523,6 +1029,15 @@ impl std::fmt::Debug for PollInterval {
 }
 
 impl PollInterval {
+    /// Interval between the requests of the initial burst of a peer
+    pub(crate) const BURST: Self = Self(1);
+
+    /// Shortest poll interval allowed by the protocol, 16 s
+    pub const PROTOCOL_MIN: Self = Self(4);
+
+    /// Longest poll interval allowed by the protocol, about 36 h
+    pub const PROTOCOL_MAX: Self = Self(17);
+
     #[must_use]
     pub fn inc(self, limits: PollIntervalLimits) -> Self {
         Self(self.0 + 1).min(limits.max)
@@ -555,24 +1070,61 @@ impl Default for PollInterval {
 }
 
 /// Frequency tolerance PHI (unit: seconds per second)
-#[derive(Debug, Clone, Copy)]
+///
+/// Stored in parts-per-billion, such that fractional ppm values can be
+/// configured for clocks that are known to be very stable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct FrequencyTolerance {
-    ppm: u32,
+    ppb: u64,
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for FrequencyTolerance {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.to_ppm().serialize(serializer)
+    }
 }
 
+#[cfg(feature = "serde")]
 impl<'de> Deserialize<'de> for FrequencyTolerance {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        let val: u32 = Deserialize::deserialize(deserializer)?;
-        Ok(FrequencyTolerance { ppm: val })
+        let val: f64 = Deserialize::deserialize(deserializer)?;
+        FrequencyTolerance::from_ppm(val).ok_or_else(|| {
+            serde::de::Error::invalid_value(
+                serde::de::Unexpected::Float(val),
+                &"a non-negative number of parts-per-million",
+            )
+        })
     }
 }
 
 impl FrequencyTolerance {
     pub const fn ppm(ppm: u32) -> Self {
-        Self { ppm }
+        Self {
+            ppb: ppm as u64 * 1_000,
+        }
+    }
+
+    /// Frequency tolerance from a possibly fractional number of ppm. Returns
+    /// `None` for negative or non-finite values
+    pub fn from_ppm(ppm: f64) -> Option<Self> {
+        if ppm.is_finite() && ppm >= 0.0 && ppm <= u32::MAX as f64 {
+            Some(Self {
+                ppb: (ppm * 1_000.0).round() as u64,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn to_ppm(self) -> f64 {
+        self.ppb as f64 / 1_000.0
     }
 }
 
@@ -580,7 +1132,166 @@ impl Mul<FrequencyTolerance> for NtpDuration {
     type Output = NtpDuration;
 
     fn mul(self, rhs: FrequencyTolerance) -> Self::Output {
-        (self * rhs.ppm) / 1_000_000
+        // The intermediate product can be far outside of the range of an
+        // NtpDuration for long intervals (e.g. after many missed polls), so
+        // it is calculated at double width, and only the result saturates.
+        let duration = self.duration as i128 * rhs.ppb as i128 / 1_000_000_000;
+        NtpDuration {
+            duration: duration.clamp(i64::MIN as i128, i64::MAX as i128) as i64,
+        }
+    }
+}
+
+// Conversions to and from the time types of std and other crates. Timestamps
+// use the era window of `NtpTimestamp::to_unix_seconds_nanos`, and converting
+// to and back from nanoseconds always gives the original value.
+
+fn system_time_from_unix(seconds: i64, nanos: u32) -> SystemTime {
+    let since_epoch = Duration::new(seconds.unsigned_abs(), 0);
+    let epoch_second = if seconds >= 0 {
+        UNIX_EPOCH + since_epoch
+    } else {
+        UNIX_EPOCH - since_epoch
+    };
+
+    epoch_second + Duration::from_nanos(nanos as u64)
+}
+
+/// Uses the fixed era window of [`NtpTimestamp::to_unix_seconds_nanos`], see
+/// [`NtpTimestamp::to_system_time`] for the era of the current time
+impl From<NtpTimestamp> for SystemTime {
+    fn from(timestamp: NtpTimestamp) -> Self {
+        let (seconds, nanos) = timestamp.to_unix_seconds_nanos();
+        system_time_from_unix(seconds, nanos)
+    }
+}
+
+/// Fails for times outside the era window of
+/// [`NtpTimestamp::to_unix_seconds_nanos`]
+impl TryFrom<SystemTime> for NtpTimestamp {
+    type Error = TimeConversionError;
+
+    fn try_from(time: SystemTime) -> Result<Self, Self::Error> {
+        let nanos = match time.duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_nanos() as i128,
+            Err(error) => -(error.duration().as_nanos() as i128),
+        };
+
+        NtpTimestamp::try_from_unix_nanos(nanos)
+    }
+}
+
+impl TryFrom<Duration> for NtpDuration {
+    type Error = TimeConversionError;
+
+    fn try_from(duration: Duration) -> Result<Self, Self::Error> {
+        let duration = nanos_to_fixed(duration.as_nanos() as i128);
+
+        Ok(NtpDuration {
+            duration: i64::try_from(duration).map_err(|_| TimeConversionError::OutOfRange)?,
+        })
+    }
+}
+
+impl TryFrom<NtpDuration> for Duration {
+    type Error = TimeConversionError;
+
+    fn try_from(duration: NtpDuration) -> Result<Self, Self::Error> {
+        if duration.duration < 0 {
+            return Err(TimeConversionError::Negative);
+        }
+
+        Ok(Duration::from_nanos(
+            fixed_to_nanos(duration.duration as i128) as u64,
+        ))
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<NtpTimestamp> for chrono::DateTime<chrono::Utc> {
+    fn from(timestamp: NtpTimestamp) -> Self {
+        let (seconds, nanos) = timestamp.to_unix_seconds_nanos();
+
+        // the era window lies well within the range of chrono
+        chrono::DateTime::from_timestamp(seconds, nanos).unwrap_or_default()
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl TryFrom<chrono::DateTime<chrono::Utc>> for NtpTimestamp {
+    type Error = TimeConversionError;
+
+    fn try_from(time: chrono::DateTime<chrono::Utc>) -> Result<Self, Self::Error> {
+        let nanos =
+            time.timestamp() as i128 * 1_000_000_000 + time.timestamp_subsec_nanos() as i128;
+
+        NtpTimestamp::try_from_unix_nanos(nanos)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<NtpDuration> for chrono::Duration {
+    fn from(duration: NtpDuration) -> Self {
+        // at most 2^31 seconds, which fits in an i64 of nanoseconds
+        chrono::Duration::nanoseconds(fixed_to_nanos(duration.duration as i128) as i64)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl TryFrom<chrono::Duration> for NtpDuration {
+    type Error = TimeConversionError;
+
+    fn try_from(duration: chrono::Duration) -> Result<Self, Self::Error> {
+        let nanos = duration
+            .num_nanoseconds()
+            .ok_or(TimeConversionError::OutOfRange)?;
+
+        Ok(NtpDuration {
+            duration: i64::try_from(nanos_to_fixed(nanos as i128))
+                .map_err(|_| TimeConversionError::OutOfRange)?,
+        })
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<NtpTimestamp> for time::OffsetDateTime {
+    fn from(timestamp: NtpTimestamp) -> Self {
+        let (seconds, nanos) = timestamp.to_unix_seconds_nanos();
+        let nanos = seconds as i128 * 1_000_000_000 + nanos as i128;
+
+        // the era window lies well within the range of time
+        time::OffsetDateTime::from_unix_timestamp_nanos(nanos)
+            .unwrap_or(time::OffsetDateTime::UNIX_EPOCH)
+    }
+}
+
+#[cfg(feature = "time")]
+impl TryFrom<time::OffsetDateTime> for NtpTimestamp {
+    type Error = TimeConversionError;
+
+    fn try_from(time: time::OffsetDateTime) -> Result<Self, Self::Error> {
+        NtpTimestamp::try_from_unix_nanos(time.unix_timestamp_nanos())
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<NtpDuration> for time::Duration {
+    fn from(duration: NtpDuration) -> Self {
+        // at most 2^31 seconds, which fits in an i64 of nanoseconds
+        time::Duration::nanoseconds(fixed_to_nanos(duration.duration as i128) as i64)
+    }
+}
+
+#[cfg(feature = "time")]
+impl TryFrom<time::Duration> for NtpDuration {
+    type Error = TimeConversionError;
+
+    fn try_from(duration: time::Duration) -> Result<Self, Self::Error> {
+        let duration = nanos_to_fixed(duration.whole_nanoseconds());
+
+        Ok(NtpDuration {
+            duration: i64::try_from(duration).map_err(|_| TimeConversionError::OutOfRange)?,
+        })
     }
 }
 
@@ -604,6 +1315,15 @@ mod tests {
         assert_eq!(b - a, NtpDuration::from_fixed_int(-2));
     }
 
+    #[test]
+    fn test_timestamp_display() {
+        let timestamp = NtpTimestamp::from_seconds_nanos_since_ntp_era(3_900_000_000, 500_000_000);
+        assert_eq!(timestamp.to_string(), "3900000000.500000000");
+
+        let timestamp = NtpTimestamp::from_fixed_int(1);
+        assert_eq!(timestamp.to_string(), "0.000000000");
+    }
+
     #[test]
     fn test_timestamp_era_change() {
         let mut a = NtpTimestamp::from_fixed_int(1);
@@ -624,6 +1344,97 @@ mod tests {
         assert_eq!(a, NtpTimestamp::from_fixed_int(1));
     }
 
+    #[test]
+    fn test_era_boundary() {
+        // the last moments of era 0 and the first of era 1
+        let before = NtpTimestamp::from_unix_seconds_nanos(ERA_1_START - 1, 900_000_000);
+        let after = NtpTimestamp::from_unix_seconds_nanos(ERA_1_START, 100_000_000);
+        assert_eq!(before.seconds_since_ntp_era(), u32::MAX);
+        assert_eq!(after.seconds_since_ntp_era(), 0);
+
+        let difference = after - before;
+        assert!((difference.to_seconds() - 0.2).abs() < 1e-9);
+        assert!(((before - after).to_seconds() + 0.2).abs() < 1e-9);
+        assert_eq!(before + difference, after);
+        assert_eq!(after - difference, before);
+
+        // an exchange of packets during the rollover, with the client 1ms ahead
+        let ms = NtpDuration::from_seconds(0.001);
+        let t1 = before;
+        let t2 = t1 + 5i64 * ms - ms;
+        let t3 = t2 + 2i64 * ms;
+        let t4 = t3 + 5i64 * ms + ms;
+        let offset = ((t2 - t1) + (t3 - t4)) / 2i64;
+        let delay = (t4 - t1) - (t3 - t2);
+        assert!((offset.to_seconds() + 0.001).abs() < 1e-9);
+        assert!((delay.to_seconds() - 0.010).abs() < 1e-9);
+
+        // the same on both sides of the boundary
+        assert_eq!(
+            before.to_unix_seconds_nanos(),
+            (ERA_1_START - 1, 900_000_000)
+        );
+        assert_eq!(after.to_unix_seconds_nanos(), (ERA_1_START, 100_000_000));
+        assert_eq!(
+            before.to_unix_seconds_nanos_near(ERA_1_START),
+            (ERA_1_START - 1, 900_000_000)
+        );
+        assert_eq!(
+            after.to_unix_seconds_nanos_near(ERA_1_START - 1),
+            (ERA_1_START, 100_000_000)
+        );
+    }
+
+    #[test]
+    fn test_era_from_reference() {
+        let era = 1 << 32;
+        let timestamp = NtpTimestamp::from_unix_seconds_nanos(2_300_000_000, 250_000_000);
+
+        for reference in [200_000_000, 1_682_598_896, 2_300_000_000, 4_000_000_000] {
+            assert_eq!(
+                timestamp.to_unix_seconds_nanos_near(reference),
+                (2_300_000_000, 250_000_000)
+            );
+        }
+
+        // a reference in the next era, or in the one before
+        assert_eq!(
+            timestamp.to_unix_seconds_nanos_near(6_500_000_000),
+            (2_300_000_000 + era, 250_000_000)
+        );
+        assert_eq!(
+            timestamp.to_unix_seconds_nanos_near(-2_500_000_000),
+            (2_300_000_000 - era, 250_000_000)
+        );
+
+        // the current era is taken from the system clock
+        let now = SystemTime::now();
+        let timestamp = NtpTimestamp::try_from(now).unwrap();
+        assert_eq!(timestamp.to_system_time(), now);
+    }
+
+    #[test]
+    fn test_timestamp_offset_to_nearest_second() {
+        let a = NtpTimestamp::from_fixed_int((5 << 32) + 0x100);
+        assert_eq!(
+            a.offset_to_nearest_second(),
+            NtpDuration::from_fixed_int(-0x100)
+        );
+
+        let b = NtpTimestamp::from_fixed_int((5 << 32) + 0xFFFFFF00);
+        assert_eq!(
+            b.offset_to_nearest_second(),
+            NtpDuration::from_fixed_int(0x100)
+        );
+        assert_eq!(
+            b + b.offset_to_nearest_second(),
+            NtpTimestamp::from_fixed_int(6 << 32)
+        );
+
+        let c = NtpTimestamp::from_fixed_int(5 << 32);
+        assert_eq!(c.offset_to_nearest_second(), NtpDuration::ZERO);
+    }
+
     #[test]
     fn test_timestamp_from_seconds_nanos() {
         assert_eq!(
@@ -660,6 +1471,45 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_checked_saturating() {
+        let one = NtpDuration::ONE;
+
+        assert_eq!(one.checked_add(one), Some(NtpDuration::from_seconds(2.0)));
+        assert_eq!(NtpDuration::MAX.checked_add(one), None);
+        assert_eq!(NtpDuration::MIN.checked_sub(one), None);
+        assert_eq!(one.checked_mul(3), Some(NtpDuration::from_seconds(3.0)));
+        assert_eq!(NtpDuration::MAX.checked_mul(2), None);
+
+        assert_eq!(NtpDuration::MAX.saturating_add(one), NtpDuration::MAX);
+        assert_eq!(NtpDuration::MIN.saturating_sub(one), NtpDuration::MIN);
+        assert_eq!(NtpDuration::MIN.saturating_mul(2), NtpDuration::MIN);
+        assert_eq!(NtpDuration::MAX + NtpDuration::MAX, NtpDuration::MAX);
+        assert_eq!(NtpDuration::MIN - NtpDuration::MAX, NtpDuration::MIN);
+
+        assert_eq!(-NtpDuration::MIN, NtpDuration::MAX);
+        assert_eq!(NtpDuration::MIN.abs(), NtpDuration::MAX);
+        assert_eq!(NtpDuration::MIN / -1i64, NtpDuration::MAX);
+        let mut duration = NtpDuration::MIN;
+        duration /= -1i64;
+        assert_eq!(duration, NtpDuration::MAX);
+    }
+
+    #[test]
+    fn test_bits_short_saturates() {
+        assert_eq!(NtpDuration::MAX.to_bits_short(), [0xFF; 4]);
+        assert_eq!(
+            NtpDuration::from_seconds(65536.0).to_bits_short(),
+            [0xFF; 4]
+        );
+        assert_eq!(NtpDuration::from_seconds(-1.0).to_bits_short(), [0; 4]);
+        assert_eq!(NtpDuration::MIN.to_bits_short(), [0; 4]);
+        assert_eq!(
+            "1.5".parse::<NtpDuration>().unwrap().to_bits_short(),
+            [0, 1, 0x80, 0]
+        );
+    }
+
     #[test]
     fn test_duration_math() {
         let mut a = NtpDuration::from_fixed_int(5);
@@ -813,5 +1663,290 @@ mod tests {
             NtpDuration::from_seconds(1.0),
             NtpDuration::from_seconds(1.0) * FrequencyTolerance::ppm(1_000_000),
         );
+
+        let tolerance = FrequencyTolerance::from_ppm(0.5).unwrap();
+        let aged = NtpDuration::from_seconds(1024.0) * tolerance;
+        assert!((aged.to_seconds() - 512e-6).abs() < 1e-9);
+
+        assert_eq!(FrequencyTolerance::from_ppm(-1.0), None);
+        assert_eq!(FrequencyTolerance::from_ppm(f64::NAN), None);
+    }
+
+    #[test]
+    fn frequency_tolerance_long_interval() {
+        // a year without samples should not overflow, even for large tolerances
+        let year = NtpDuration::from_seconds(365.0 * 86400.0);
+        let aged = year * FrequencyTolerance::ppm(15);
+        assert!((aged.to_seconds() - 473.04).abs() < 1e-6);
+
+        let aged = year * FrequencyTolerance::ppm(1_000_000);
+        assert!((aged.to_seconds() - year.to_seconds()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_duration_display() {
+        let display = |seconds: f64| NtpDuration::from_seconds(seconds).to_string();
+
+        assert_eq!(display(0.0), "0ns");
+        assert_eq!(display(1.5), "1.5s");
+        assert_eq!(display(-2.0), "-2s");
+        assert_eq!(display(0.0015), "1.5ms");
+        assert_eq!(display(0.000_012_345), "12.345us");
+        assert_eq!(display(0.000_000_1), "100ns");
+        assert_eq!(display(1234.5678), "1234.568s");
+        assert_eq!(
+            format!("{:.6}", NtpDuration::from_seconds(0.0015)),
+            "1.500000ms"
+        );
+        assert_eq!(format!("{:.0}", NtpDuration::from_seconds(1.5)), "2s");
+    }
+
+    #[test]
+    fn test_duration_from_str() {
+        let parse = |s: &str| s.parse::<NtpDuration>();
+
+        assert_eq!(parse("1.5"), Ok(NtpDuration { duration: 3 << 31 }));
+        assert_eq!(parse("1.5s"), parse("1.5"));
+        assert_eq!(parse("1500ms"), parse("1.5"));
+        assert_eq!(parse("1500000us"), parse("1.5"));
+        assert_eq!(parse("1500000 µs"), parse("1.5"));
+        assert_eq!(parse("1500000000ns"), parse("1.5"));
+        assert_eq!(
+            parse("-.25"),
+            Ok(NtpDuration {
+                duration: -(1 << 30)
+            })
+        );
+        assert_eq!(parse("+2"), Ok(NtpDuration::from_seconds(2.0)));
+        assert_eq!(parse("1ns"), Ok(NtpDuration { duration: 4 }));
+
+        assert_eq!(parse(""), Err(TimeParsingError::InvalidNumber));
+        assert_eq!(parse("ms"), Err(TimeParsingError::InvalidNumber));
+        assert_eq!(parse("1.2.3"), Err(TimeParsingError::InvalidNumber));
+        assert_eq!(parse("1e3"), Err(TimeParsingError::UnknownUnit));
+        assert_eq!(parse("5min"), Err(TimeParsingError::UnknownUnit));
+        assert_eq!(parse("3000000000"), Err(TimeParsingError::OutOfRange));
+
+        for duration in [1, -1, 3 << 31, 123_456_789_012, -987_654_321] {
+            let duration = NtpDuration { duration };
+            let parsed = parse(&format!("{:.9}", duration)).unwrap();
+            assert!((parsed - duration).abs() <= NtpDuration::from_seconds(1e-9));
+        }
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        for days in [-800_000, -719_468, -1, 0, 1, 11_016, 24_855, 100_000] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn test_unix_conversion() {
+        let timestamp = NtpTimestamp::from_unix_seconds_nanos(1_682_598_896, 500_000_000);
+        assert_eq!(timestamp.seconds_since_ntp_era(), 3_891_587_696);
+        assert_eq!(
+            timestamp.to_unix_seconds_nanos(),
+            (1_682_598_896, 500_000_000)
+        );
+
+        // the first second of NTP era 1
+        let era_start = NtpTimestamp::from_unix_seconds_nanos(2_085_978_496, 0);
+        assert_eq!(era_start.timestamp, 0);
+        assert_eq!(era_start.to_unix_seconds_nanos(), (2_085_978_496, 0));
+        let before = era_start - NtpDuration::from_seconds(1.0);
+        assert_eq!(before.to_unix_seconds_nanos(), (2_085_978_495, 0));
+
+        // the pivot of RFC 4330, timestamps before it lie in era 1
+        let pivot = NtpTimestamp::from_fixed_int(1 << 63);
+        assert_eq!(pivot.to_unix_seconds_nanos(), (-61_505_152, 0));
+        let last = pivot - NtpDuration { duration: 1 };
+        assert_eq!(last.to_unix_seconds_nanos(), (4_233_462_143, 999_999_999));
+
+        // before 1970
+        let timestamp = NtpTimestamp::from_unix_seconds_nanos(-1, 0);
+        assert_eq!(timestamp.to_unix_seconds_nanos(), (-1, 0));
+    }
+
+    #[test]
+    fn test_iso8601() {
+        let format = |timestamp: NtpTimestamp| timestamp.iso8601().to_string();
+
+        let timestamp = NtpTimestamp::from_unix_seconds_nanos(1_682_598_896, 500_000_000);
+        assert_eq!(format(timestamp), "2023-04-27T12:34:56.500000000Z");
+        assert_eq!(
+            format(NtpTimestamp::from_fixed_int(0)),
+            "2036-02-07T06:28:16.000000000Z"
+        );
+        assert_eq!(
+            format(NtpTimestamp::from_fixed_int(1 << 63)),
+            "1968-01-20T03:14:08.000000000Z"
+        );
+
+        let parse = |s: &str| s.parse::<NtpTimestamp>();
+        assert_eq!(parse("2023-04-27T12:34:56.5Z"), Ok(timestamp));
+        assert_eq!(parse("2023-04-27t12:34:56.500000000z"), Ok(timestamp));
+        assert_eq!(parse("2023-04-27T14:34:56.5+02:00"), Ok(timestamp));
+        assert_eq!(parse("2023-04-27T08:04:56.5-04:30"), Ok(timestamp));
+        assert_eq!(
+            parse("2036-02-07 06:28:16Z"),
+            Ok(NtpTimestamp::from_fixed_int(0))
+        );
+        assert_eq!(
+            parse("1968-01-20T03:14:08Z"),
+            Ok(NtpTimestamp::from_fixed_int(1 << 63))
+        );
+
+        for invalid in [
+            "2023-04-27T12:34:56",
+            "2023-04-27T12:34:56.Z",
+            "2023-02-29T12:34:56Z",
+            "2023-13-01T12:34:56Z",
+            "2023-04-27T24:00:00Z",
+            "2023-4-27T12:34:56Z",
+            "2023-04-27T12:34:56+0200",
+            "2023-04-27",
+        ] {
+            assert_eq!(parse(invalid), Err(TimeParsingError::InvalidTimestamp));
+        }
+
+        // the format of Display
+        assert_eq!(parse(&timestamp.to_string()), Ok(timestamp));
+        assert_eq!(parse("1.5"), Ok(NtpTimestamp::from_fixed_int(3 << 31)));
+        assert_eq!(parse("4294967296"), Err(TimeParsingError::OutOfRange));
+        assert_eq!(parse("-1"), Err(TimeParsingError::InvalidTimestamp));
+    }
+
+    #[test]
+    fn test_std_conversions() {
+        let time = UNIX_EPOCH + Duration::new(1_682_598_896, 123_456_789);
+        let timestamp = NtpTimestamp::try_from(time).unwrap();
+        assert_eq!(
+            timestamp.to_unix_seconds_nanos(),
+            (1_682_598_896, 123_456_789)
+        );
+        assert_eq!(SystemTime::from(timestamp), time);
+
+        // across the era boundary and before the unix epoch
+        for seconds in [2_085_978_495, 2_085_978_496, -1, ERA_WINDOW_START] {
+            for nanos in [0, 1, 999_999_999] {
+                let time = match seconds {
+                    0.. => UNIX_EPOCH + Duration::new(seconds as u64, nanos),
+                    _ => UNIX_EPOCH - Duration::new(-seconds as u64, 0) + Duration::new(0, nanos),
+                };
+                let timestamp = NtpTimestamp::try_from(time).unwrap();
+                assert_eq!(timestamp.to_unix_seconds_nanos(), (seconds, nanos));
+                assert_eq!(SystemTime::from(timestamp), time);
+            }
+        }
+
+        let before = UNIX_EPOCH - Duration::new(-ERA_WINDOW_START as u64, 1);
+        assert_eq!(
+            NtpTimestamp::try_from(before),
+            Err(TimeConversionError::OutOfRange)
+        );
+        let after = UNIX_EPOCH + Duration::from_secs((ERA_WINDOW_START + (1 << 32)) as u64);
+        assert_eq!(
+            NtpTimestamp::try_from(after),
+            Err(TimeConversionError::OutOfRange)
+        );
+
+        for nanos in [0, 1, 999_999_999, 1_500_000_000, 12_345_678_901_234] {
+            let duration = Duration::from_nanos(nanos);
+            let ntp = NtpDuration::try_from(duration).unwrap();
+            assert_eq!(Duration::try_from(ntp), Ok(duration));
+        }
+        assert_eq!(
+            NtpDuration::try_from(Duration::from_secs(1 << 31)),
+            Err(TimeConversionError::OutOfRange)
+        );
+        assert_eq!(
+            Duration::try_from(NtpDuration::from_seconds(-1.0)),
+            Err(TimeConversionError::Negative)
+        );
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_chrono_conversions() {
+        let time = chrono::DateTime::from_timestamp(2_085_978_496, 123_456_789).unwrap();
+        let timestamp = NtpTimestamp::try_from(time).unwrap();
+        assert_eq!(
+            timestamp.to_unix_seconds_nanos(),
+            (2_085_978_496, 123_456_789)
+        );
+        assert_eq!(chrono::DateTime::from(timestamp), time);
+
+        let time = chrono::DateTime::from_timestamp(5_000_000_000, 0).unwrap();
+        assert_eq!(
+            NtpTimestamp::try_from(time),
+            Err(TimeConversionError::OutOfRange)
+        );
+
+        let duration = chrono::Duration::nanoseconds(-1_500_000_001);
+        let ntp = NtpDuration::try_from(duration).unwrap();
+        assert!(ntp < NtpDuration::from_seconds(-1.5));
+        assert_eq!(chrono::Duration::from(ntp), duration);
+        assert_eq!(
+            NtpDuration::try_from(chrono::Duration::seconds(1 << 32)),
+            Err(TimeConversionError::OutOfRange)
+        );
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn test_time_conversions() {
+        let time =
+            time::OffsetDateTime::from_unix_timestamp_nanos(-61_505_152_000_000_001).unwrap();
+        assert_eq!(
+            NtpTimestamp::try_from(time),
+            Err(TimeConversionError::OutOfRange)
+        );
+        let time = time + time::Duration::nanoseconds(1);
+        let timestamp = NtpTimestamp::try_from(time).unwrap();
+        assert_eq!(timestamp, NtpTimestamp::from_fixed_int(1 << 63));
+        assert_eq!(time::OffsetDateTime::from(timestamp), time);
+
+        let duration = time::Duration::new(-7, -999_999_999);
+        let ntp = NtpDuration::try_from(duration).unwrap();
+        assert_eq!(time::Duration::from(ntp), duration);
+        assert_eq!(
+            NtpDuration::try_from(time::Duration::seconds(1 << 32)),
+            Err(TimeConversionError::OutOfRange)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let timestamp = NtpTimestamp::from_fixed_int(0xe5f6_63a8_798c_6581);
+        let json = serde_json::to_string(&timestamp).unwrap();
+        assert_eq!(json, "16570541454201218433");
+        assert_eq!(
+            serde_json::from_str::<NtpTimestamp>(&json).unwrap(),
+            timestamp
+        );
+
+        // durations are serialized as seconds
+        let duration = NtpDuration::from_seconds(1.5);
+        let json = serde_json::to_string(&duration).unwrap();
+        assert!((serde_json::from_str::<f64>(&json).unwrap() - 1.5).abs() < 1e-9);
+        let parsed = serde_json::from_str::<NtpDuration>(&json).unwrap();
+        assert!((parsed - duration).to_seconds().abs() < 1e-9);
+
+        let parsed = serde_json::from_str::<NtpDuration>("\"1500ms\"").unwrap();
+        assert_eq!(parsed, "1.5".parse().unwrap());
+        assert!(serde_json::from_str::<NtpDuration>("\"1.5 parsecs\"").is_err());
+
+        let tolerance = FrequencyTolerance::ppm(15);
+        let json = serde_json::to_string(&tolerance).unwrap();
+        assert_eq!(json, "15.0");
+        assert_eq!(
+            serde_json::from_str::<FrequencyTolerance>(&json).unwrap(),
+            tolerance
+        );
     }
 }
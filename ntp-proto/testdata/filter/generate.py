@@ -0,0 +1,300 @@
+#!/usr/bin/env python3
+"""Generate the clock filter test vectors in vectors.json.
+
+Every sample of a vector is run through two models of the clock filter:
+
+- `NtpdFilter`, a transcription of `clock_filter()` in ntp_proto.c of the
+  reference implementation (ntp-4.2.8), limited to what determines the
+  statistics of the peer and whether the clock is updated
+- `NtpdRsFilter`, a model of `LastMeasurements::step` in ntp-proto/src/filter.rs
+
+The outcome of the reference is stored for every sample. Where the outcome of
+ntpd-rs differs, it is stored next to it, so that every difference with the
+reference is visible in the vectors, and any change to one shows up in review.
+
+Run from this directory with `python3 generate.py > vectors.json`.
+"""
+
+import json
+import math
+
+MAX_DISPERSION = 16.0
+# the reference uses `sys_maxdist` to stop counting samples as valid, and to
+# decide whether a popcorn spike can be trusted
+SYS_MAXDIST = 1.5
+# log2 of the Allan intercept, beyond which samples are sorted by their
+# distance rather than their delay
+ALLAN_INTERCEPT = 11
+# spikes of more than this many times the jitter are suppressed
+SGATE = 3.0
+QUICK_START_SAMPLES = 4
+# differences below this are rounding, not a difference in behavior
+EPSILON = 1e-9
+
+
+class NtpdFilter:
+    def __init__(self, tolerance, precision, poll):
+        self.phi = tolerance * 1e-6
+        self.precision = 2.0**precision
+        self.poll = poll
+        self.offset = [0.0] * 8
+        self.delay = [0.0] * 8
+        self.disp = [MAX_DISPERSION] * 8
+        self.epoch_of = [0] * 8
+        self.nextpt = 0
+        self.update = 0
+        self.epoch = 0
+        self.peer_offset = 0.0
+
+    def step(self, time, offset, delay, disp):
+        j = self.nextpt
+        self.offset[j] = offset
+        self.delay[j] = delay
+        self.disp[j] = disp
+        self.epoch_of[j] = time
+        j = (j + 1) % 8
+        self.nextpt = j
+
+        dtemp = self.phi * (time - self.update)
+        self.update = time
+        dst = [0.0] * 8
+        ord = [0] * 8
+        for i in range(7, -1, -1):
+            if i != 0:
+                self.disp[j] += dtemp
+            if self.disp[j] >= MAX_DISPERSION:
+                self.disp[j] = MAX_DISPERSION
+                dst[i] = MAX_DISPERSION
+            elif self.update - self.epoch_of[j] > 2**ALLAN_INTERCEPT:
+                dst[i] = self.delay[j] + self.disp[j]
+            else:
+                dst[i] = self.delay[j]
+            ord[i] = j
+            j = (j + 1) % 8
+
+        for i in range(1, 8):
+            for j in range(i):
+                if dst[j] > dst[i]:
+                    ord[i], ord[j] = ord[j], ord[i]
+                    dst[i], dst[j] = dst[j], dst[i]
+
+        m = 0
+        for i in range(8):
+            if dst[i] >= MAX_DISPERSION or (m >= 2 and dst[i] >= SYS_MAXDIST):
+                continue
+            m += 1
+
+        peer_disp = 0.0
+        jitter = 0.0
+        k = ord[0]
+        for i in range(7, -1, -1):
+            j = ord[i]
+            peer_disp = 0.5 * (peer_disp + self.disp[j])
+            if i < m:
+                jitter += (self.offset[j] - self.offset[k]) ** 2
+
+        if m == 0:
+            return None
+
+        etemp = abs(self.peer_offset - self.offset[k])
+        self.peer_offset = self.offset[k]
+        if m > 1:
+            jitter /= m - 1
+        jitter = max(math.sqrt(jitter), self.precision)
+
+        if (
+            peer_disp < SYS_MAXDIST
+            and self.disp[k] < SYS_MAXDIST
+            and etemp > SGATE * jitter
+            and self.epoch_of[k] - self.epoch < 2.0 * 2**self.poll
+        ):
+            # popcorn spike
+            return None
+
+        if self.epoch_of[k] <= self.epoch:
+            return None
+        self.epoch = self.epoch_of[k]
+
+        return outcome(self.offset[k], self.delay[k], peer_disp, jitter)
+
+
+class NtpdRsFilter:
+    def __init__(self, tolerance, precision):
+        self.tolerance = tolerance * 1e-6
+        self.precision = 2.0**precision
+        # from new to old: (offset, delay, dispersion, time)
+        self.register = [self.dummy(0)] * 8
+        self.peer_time = 0
+
+    @staticmethod
+    def dummy(time):
+        return (0.0, MAX_DISPERSION, MAX_DISPERSION, time)
+
+    @staticmethod
+    def is_dummy(tuple):
+        return tuple[:3] == (0.0, MAX_DISPERSION, MAX_DISPERSION)
+
+    def step(self, time, offset, delay, disp):
+        correction = abs(time - self.register[0][3]) * self.tolerance
+        aged = [
+            t if self.is_dummy(t) else (t[0], t[1], min(t[2] + correction, MAX_DISPERSION), t[3])
+            for t in self.register
+        ]
+        self.register = [(offset, delay, disp, time)] + aged[:7]
+
+        # ties in delay are ordered from new to old
+        order = sorted(range(8), key=lambda index: (self.register[index][1], index))
+        tuples = [self.register[index] for index in order]
+        smallest = tuples[0]
+
+        if smallest[3] <= self.peer_time:
+            return None
+        self.peer_time = smallest[3]
+
+        invalid = 0
+        for t in reversed(tuples):
+            if not self.is_dummy(t):
+                break
+            invalid += 1
+        valid = tuples[: 8 - invalid]
+        n = len(valid)
+
+        if n < QUICK_START_SAMPLES:
+            offsets = sorted(t[0] for t in valid)
+            median = (offsets[(n - 1) // 2] + offsets[n // 2]) / 2
+            dispersion = sum(t[2] for t in valid) / n
+            rms = math.sqrt(sum((t[0] - median) ** 2 for t in valid) / n)
+            jitter = max(rms, smallest[1] / 2, self.precision)
+            inflation = QUICK_START_SAMPLES / n
+            return outcome(
+                median,
+                smallest[1],
+                min(dispersion * inflation, MAX_DISPERSION),
+                jitter * inflation,
+            )
+
+        dispersion = sum(t[2] / 2 ** (i + 1) for i, t in enumerate(tuples))
+        rms = math.sqrt(sum((t[0] - smallest[0]) ** 2 for t in valid))
+        jitter = max(rms / (n - 1), self.precision)
+        return outcome(smallest[0], smallest[1], dispersion, jitter)
+
+
+def outcome(offset, delay, dispersion, jitter):
+    return {
+        "offset": round(offset, 12),
+        "delay": round(delay, 12),
+        "dispersion": round(dispersion, 12),
+        "jitter": round(jitter, 12),
+    }
+
+
+def differs(a, b):
+    if a is None or b is None:
+        return (a is None) != (b is None)
+    return any(abs(a[field] - b[field]) > EPSILON for field in a)
+
+
+class Random:
+    """Deterministic noise, so the vectors are the same on every run"""
+
+    def __init__(self, seed):
+        self.state = seed
+
+    def uniform(self, low, high):
+        self.state = (self.state * 6364136223846793005 + 1442695040888963407) % 2**64
+        return low + (high - low) * (self.state >> 11) / 2**53
+
+
+def samples(seed, count, poll, offset, jitter, delay, congestion, gaps=()):
+    random = Random(seed)
+    time = 0
+    result = []
+    for index in range(count):
+        time += 2**poll * (1 + (index in gaps) * 4)
+        extra = random.uniform(0, congestion) if random.uniform(0, 1) < 0.4 else 0.0
+        sample_delay = delay + random.uniform(0, delay / 10) + extra
+        result.append(
+            {
+                "time": time,
+                "offset": round(offset(time) + random.uniform(-jitter, jitter) + extra / 4, 9),
+                "delay": round(sample_delay, 9),
+                "dispersion": round(2**-20 + 2**-20 + 15e-6 * sample_delay, 9),
+            }
+        )
+    return result
+
+
+VECTORS = [
+    {
+        "name": "steady",
+        "description": "A nearby server on a quiet network, polled every 64 seconds",
+        "poll": 6,
+        "samples": samples(1, 16, 6, lambda t: 0.0002, 0.00005, 0.002, 0.0005),
+    },
+    {
+        "name": "congested",
+        "description": "A distant server behind a congested link, where many samples are delayed, and their offsets with them",
+        "poll": 6,
+        "samples": samples(2, 20, 6, lambda t: -0.0031, 0.0002, 0.040, 0.080),
+    },
+    {
+        "name": "drift",
+        "description": "An unsteered clock running 20 ppm fast",
+        "poll": 7,
+        "samples": samples(3, 16, 7, lambda t: -20e-6 * t, 0.00005, 0.010, 0.002),
+    },
+    {
+        "name": "offset-step",
+        "description": "The clock of the server steps by 5 ms halfway",
+        "poll": 6,
+        "samples": samples(4, 16, 6, lambda t: 0.0 if t < 600 else 0.005, 0.00005, 0.004, 0.0005),
+    },
+    {
+        "name": "missed-polls",
+        "description": "Some responses are lost, so the dispersion of older samples grows over longer gaps",
+        "poll": 8,
+        "samples": samples(5, 14, 8, lambda t: 0.0007, 0.0001, 0.020, 0.010, gaps=(4, 5, 9)),
+    },
+    {
+        "name": "long-poll",
+        "description": "Polling every 1024 seconds, so the oldest samples are beyond the Allan intercept",
+        "poll": 10,
+        "samples": samples(6, 14, 10, lambda t: 0.0015, 0.0003, 0.015, 0.004),
+    },
+]
+
+
+def main():
+    vectors = []
+    for vector in VECTORS:
+        tolerance = 15
+        precision = -20
+        ntpd = NtpdFilter(tolerance, precision, vector["poll"])
+        ntpd_rs = NtpdRsFilter(tolerance, precision)
+
+        steps = []
+        for sample in vector["samples"]:
+            args = (sample["time"], sample["offset"], sample["delay"], sample["dispersion"])
+            reference = ntpd.step(*args)
+            ours = ntpd_rs.step(*args)
+            step = dict(sample, ntpd=reference)
+            if differs(reference, ours):
+                step["ntpd-rs"] = ours
+            steps.append(step)
+
+        vectors.append(
+            {
+                "name": vector["name"],
+                "description": vector["description"],
+                "tolerance": tolerance,
+                "precision": precision,
+                "poll": vector["poll"],
+                "steps": steps,
+            }
+        )
+
+    print(json.dumps(vectors, indent=2))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,108 @@
+#![forbid(unsafe_code)]
+
+//! Sockets passed by the service manager (systemd socket activation). This
+//! allows binding privileged ports without running as root, and keeps the
+//! sockets open when the daemon is restarted. Sockets can also be handed to
+//! the service manager, which passes them again after a restart.
+
+use std::{
+    io,
+    net::SocketAddr,
+    os::unix::{
+        net::{UnixDatagram, UnixListener},
+        prelude::RawFd,
+    },
+    path::Path,
+};
+
+use crate::raw_socket::{activated_sockets, send_with_fds};
+
+/// The UDP socket passed by the service manager that is bound to `addr`, if any
+pub fn activated_udp_socket(addr: SocketAddr) -> io::Result<Option<std::net::UdpSocket>> {
+    let sockets: Vec<std::net::UdpSocket> = activated_sockets(|domain, socket_type| {
+        (domain == libc::AF_INET || domain == libc::AF_INET6) && socket_type == libc::SOCK_DGRAM
+    })?;
+
+    Ok(sockets
+        .into_iter()
+        .find(|socket| socket.local_addr().ok() == Some(addr)))
+}
+
+/// The listening unix stream socket passed by the service manager that is
+/// bound to `path`, if any
+pub fn activated_unix_listener(path: &Path) -> io::Result<Option<UnixListener>> {
+    let sockets: Vec<UnixListener> = activated_sockets(|domain, socket_type| {
+        domain == libc::AF_UNIX && socket_type == libc::SOCK_STREAM
+    })?;
+
+    Ok(sockets.into_iter().find(|socket| {
+        socket
+            .local_addr()
+            .map(|addr| addr.as_pathname() == Some(path))
+            .unwrap_or(false)
+    }))
+}
+
+/// Keep sockets in the file descriptor store of the service manager
+/// (`FDSTORE=1`, see `sd_pid_notify_with_fds(3)`), through its notification
+/// socket at `notify_socket`. The service manager passes them to the next
+/// process of the service, in which they are found like activated sockets.
+pub fn store_sockets(notify_socket: &Path, name: &str, sockets: &[RawFd]) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(notify_socket)?;
+
+    let message = format!("FDSTORE=1\nFDNAME={name}");
+    send_with_fds(&socket, message.as_bytes(), sockets)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::prelude::AsRawFd;
+
+    use super::*;
+
+    #[test]
+    fn test_activated_udp_socket() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        assert!(activated_udp_socket(addr).unwrap().is_none());
+
+        // pretend the service manager passed everything up to and including our socket
+        std::env::set_var("LISTEN_PID", std::process::id().to_string());
+        std::env::set_var("LISTEN_FDS", (socket.as_raw_fd() - 2).to_string());
+
+        let activated = activated_udp_socket(addr).unwrap().unwrap();
+        assert_eq!(activated.local_addr().unwrap(), addr);
+        assert_ne!(activated.as_raw_fd(), socket.as_raw_fd());
+
+        // not bound to this address
+        let other = "127.0.0.1:1".parse().unwrap();
+        assert!(activated_udp_socket(other).unwrap().is_none());
+
+        // meant for another process
+        std::env::set_var("LISTEN_PID", "1");
+        assert!(activated_udp_socket(addr).unwrap().is_none());
+
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+    }
+
+    #[test]
+    fn test_store_sockets() {
+        let path = std::env::temp_dir().join("ntp-udp-test-notify");
+        std::fs::remove_file(&path).ok();
+        let service_manager = UnixDatagram::bind(&path).unwrap();
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        store_sockets(&path, "ntp-server", &[socket.as_raw_fd()]).unwrap();
+
+        let mut buf = [0; 64];
+        let n = service_manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"FDSTORE=1\nFDNAME=ntp-server");
+
+        // not a file descriptor
+        assert!(store_sockets(&path, "ntp-server", &[-1]).is_err());
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -34,6 +34,18 @@ pub fn interface_name(local_addr: SocketAddr) -> std::io::Result<Option<[libc::c
     }
 }
 
+/// Names of the network interfaces of the host, each once
+pub fn interface_names() -> std::io::Result<Vec<String>> {
+    let mut names: Vec<String> = vec![];
+    for interface in getifaddrs()? {
+        if !names.contains(&interface.interface_name) {
+            names.push(interface.interface_name);
+        }
+    }
+
+    Ok(names)
+}
+
 /// Describes a single address for an interface as returned by `getifaddrs`.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 struct InterfaceAddress {
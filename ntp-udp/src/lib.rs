@@ -1,7 +1,15 @@
 #![forbid(unsafe_op_in_unsafe_fn)]
 
+mod activation;
 mod interface_name;
 mod raw_socket;
 mod socket;
+mod timestamping;
+#[cfg(feature = "io-uring")]
+mod uring;
 
-pub use socket::UdpSocket;
+pub use activation::{activated_udp_socket, activated_unix_listener, store_sockets};
+pub use socket::{IcmpError, UdpSocket, MAX_BATCH_SIZE};
+pub use timestamping::{interface_timestamping, InterfaceTimestamping};
+#[cfg(feature = "io-uring")]
+pub use uring::{SendQueue, UringSocket};
@@ -12,12 +12,20 @@
 /// All unsafe blocks are preceded with a comment explaining why that
 /// specific unsafe code should be safe within the context in which it
 /// is used.
+pub(crate) use activated_sockets::{activated_sockets, send_with_fds};
 pub(crate) use exceptional_condition_fd::exceptional_condition_fd;
+pub(crate) use mmsg::{receive_messages, send_messages, MAX_MESSAGES};
+pub(crate) use network_namespace::bind_in_network_namespace;
+#[cfg(feature = "io-uring")]
+pub(crate) use recv_message::control_messages;
 pub(crate) use recv_message::{
     control_message_space, receive_message, ControlMessage, MessageQueue,
 };
+pub(crate) use reuse_port::bind_reuse_port;
+pub(crate) use reuse_port::socket_address;
 pub(crate) use set_timestamping_options::set_timestamping_options;
-pub(crate) use timestamping_config::TimestampingConfig;
+pub(crate) use socket_options::{bind_to_device, int_option, set_int_option};
+pub(crate) use timestamping_config::{interface_timestamping_info, TimestampingConfig};
 
 /// Turn a C failure (-1 is returned) into a rust Result
 pub(crate) fn cerr(t: libc::c_int) -> std::io::Result<libc::c_int> {
@@ -80,6 +88,231 @@ mod set_timestamping_options {
     }
 }
 
+mod socket_options {
+    use std::os::unix::prelude::AsRawFd;
+
+    use super::cerr;
+
+    /// Set a socket option that takes an integer value
+    pub(crate) fn set_int_option(
+        udp_socket: &std::net::UdpSocket,
+        level: libc::c_int,
+        option: libc::c_int,
+        value: libc::c_int,
+    ) -> std::io::Result<()> {
+        // Safety:
+        // we have a reference to the socket, so fd is a valid file descriptor for the duration of
+        // the call. value is owned by us and lives for the duration of the call, and option_len is
+        // the size of value. The kernel rejects options that do not take an integer.
+        cerr(unsafe {
+            libc::setsockopt(
+                udp_socket.as_raw_fd(),
+                level,
+                option,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        })?;
+
+        Ok(())
+    }
+
+    /// Only send and receive packets on the given network interface
+    pub(crate) fn bind_to_device(
+        udp_socket: &impl AsRawFd,
+        interface: &str,
+    ) -> std::io::Result<()> {
+        // IFNAMSIZ includes the terminating nul byte
+        if interface.len() >= libc::IFNAMSIZ || interface.contains('\0') {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid interface name",
+            ));
+        }
+
+        // Safety:
+        // we have a reference to the socket, so fd is a valid file descriptor for the duration of
+        // the call. The kernel reads at most option_len bytes of the name, which we borrow for the
+        // duration of the call, and does not need a terminating nul byte.
+        cerr(unsafe {
+            libc::setsockopt(
+                udp_socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                interface.as_ptr() as *const libc::c_void,
+                interface.len() as libc::socklen_t,
+            )
+        })?;
+
+        Ok(())
+    }
+
+    /// Get a socket option that has an integer value
+    pub(crate) fn int_option(
+        udp_socket: &std::net::UdpSocket,
+        level: libc::c_int,
+        option: libc::c_int,
+    ) -> std::io::Result<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+        // Safety:
+        // we have a reference to the socket, so fd is a valid file descriptor for the duration of
+        // the call. value and length point to memory we own for the duration of the call, and
+        // length is the size of value.
+        cerr(unsafe {
+            libc::getsockopt(
+                udp_socket.as_raw_fd(),
+                level,
+                option,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut length,
+            )
+        })?;
+
+        Ok(value)
+    }
+}
+
+mod reuse_port {
+    use std::{
+        net::SocketAddr,
+        os::unix::prelude::{AsRawFd, FromRawFd},
+    };
+
+    use super::{cerr, set_int_option};
+
+    pub(crate) fn socket_address(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        // Safety:
+        // sockaddr_storage is plain data, for which all zeroes is a valid value
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+
+        let length = match addr {
+            SocketAddr::V4(addr) => {
+                let sockaddr = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: addr.port().to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                    },
+                    sin_zero: [0; 8],
+                };
+
+                // Safety:
+                // sockaddr_storage is large enough and suitably aligned to hold any socket address
+                unsafe {
+                    std::ptr::write(
+                        &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in,
+                        sockaddr,
+                    )
+                };
+                std::mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sockaddr = libc::sockaddr_in6 {
+                    sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                    sin6_port: addr.port().to_be(),
+                    sin6_flowinfo: addr.flowinfo(),
+                    sin6_addr: libc::in6_addr {
+                        s6_addr: addr.ip().octets(),
+                    },
+                    sin6_scope_id: addr.scope_id(),
+                };
+
+                // Safety:
+                // sockaddr_storage is large enough and suitably aligned to hold any socket address
+                unsafe {
+                    std::ptr::write(
+                        &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6,
+                        sockaddr,
+                    )
+                };
+                std::mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+
+        (storage, length as libc::socklen_t)
+    }
+
+    /// A non-blocking UDP socket bound to the address with `SO_REUSEPORT`, such
+    /// that several sockets can share the address, with the kernel spreading the
+    /// incoming packets over them by the address of the sender
+    pub(crate) fn bind_reuse_port(addr: SocketAddr) -> std::io::Result<std::net::UdpSocket> {
+        let domain = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+
+        // Safety:
+        // socket has no memory safety preconditions
+        let fd = cerr(unsafe {
+            libc::socket(
+                domain,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        })?;
+
+        // Safety:
+        // fd is a UDP socket we just created, so nothing else owns it. From here on, it is
+        // closed when the socket is dropped, also when one of the steps below fails.
+        let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+
+        set_int_option(&socket, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+
+        let (storage, length) = socket_address(addr);
+
+        // Safety:
+        // we own the socket, so its fd is valid for the duration of the call. storage holds a
+        // socket address of the given length, and lives for the duration of the call.
+        cerr(unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &storage as *const libc::sockaddr_storage as *const libc::sockaddr,
+                length,
+            )
+        })?;
+
+        Ok(socket)
+    }
+}
+
+mod network_namespace {
+    use std::{fs::File, net::SocketAddr, os::unix::prelude::AsRawFd, path::Path};
+
+    use super::cerr;
+
+    /// A non-blocking UDP socket bound to the address in another network
+    /// namespace, given by its file (such as `/run/netns/NAME`). The socket
+    /// keeps sending and receiving in that namespace, whichever namespace
+    /// the process is in.
+    pub(crate) fn bind_in_network_namespace(
+        addr: SocketAddr,
+        namespace: &Path,
+    ) -> std::io::Result<std::net::UdpSocket> {
+        let namespace = File::open(namespace)?;
+
+        // sockets are created in the namespace of the thread that creates
+        // them, so a thread of its own enters the namespace. That thread
+        // ends with it, leaving those of the runtime in their namespace.
+        std::thread::Builder::new()
+            .name("network-namespace".into())
+            .spawn(move || {
+                // Safety:
+                // the thread owns the file, so its fd is valid for the duration of
+                // the call. setns only changes the namespace of this thread, which
+                // no other code runs on.
+                cerr(unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) })?;
+
+                let socket = std::net::UdpSocket::bind(addr)?;
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            })?
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
 mod recv_message {
     use std::{io::IoSliceMut, marker::PhantomData, net::SocketAddr, os::unix::prelude::AsRawFd};
 
@@ -169,6 +402,34 @@ mod recv_message {
         ))
     }
 
+    /// The control messages in a buffer that was filled by the kernel, such as
+    /// the control data of a multishot receive
+    ///
+    /// # Safety
+    ///
+    /// The buffer must contain valid control messages as written by the kernel,
+    /// and must be aligned for `libc::cmsghdr`.
+    #[cfg(feature = "io-uring")]
+    pub(crate) unsafe fn control_messages(
+        control_buf: &[u8],
+    ) -> impl Iterator<Item = ControlMessage> + '_ {
+        let mhdr = libc::msghdr {
+            // the control messages are only ever read
+            msg_control: control_buf.as_ptr() as *mut libc::c_void,
+            msg_controllen: control_buf.len(),
+            msg_iov: std::ptr::null_mut(),
+            msg_iovlen: 0,
+            msg_flags: 0,
+            msg_name: std::ptr::null_mut(),
+            msg_namelen: 0,
+        };
+
+        // Safety:
+        // the caller guarantees that the control buffer contains valid control messages,
+        // and mhdr describes exactly that buffer, which lives for as long as the iterator
+        unsafe { ControlMessageIterator::new(mhdr) }
+    }
+
     // Invariants:
     // self.mhdr points to a valid libc::msghdr with a valid control
     // message region.
@@ -179,7 +440,7 @@ mod recv_message {
     // calling ControlMessageIterator::new, the fact that next preserves
     // these invariants and that the fields of ControlMessageIterator
     // are not modified outside these two functions.
-    struct ControlMessageIterator<'a> {
+    pub(super) struct ControlMessageIterator<'a> {
         mhdr: libc::msghdr,
         next_msg: *const libc::cmsghdr,
         phantom: PhantomData<&'a [u8]>,
@@ -191,7 +452,7 @@ mod recv_message {
         // that together describe a memory region
         // with lifetime 'a containing valid control
         // messages
-        unsafe fn new(mhdr: libc::msghdr) -> Self {
+        pub(super) unsafe fn new(mhdr: libc::msghdr) -> Self {
             // Safety:
             // mhdr's control and controllen fields are valid and point
             // to valid control messages.
@@ -282,6 +543,187 @@ mod recv_message {
     }
 }
 
+mod mmsg {
+    use std::{net::SocketAddr, os::unix::prelude::AsRawFd};
+
+    use tracing::warn;
+
+    use crate::interface_name::sockaddr_storage_to_socket_addr;
+
+    use super::{
+        cerr, control_message_space, recv_message::ControlMessageIterator, socket_address,
+        ControlMessage,
+    };
+
+    /// Largest number of packets received or sent with a single call. The
+    /// headers of the call are kept on the stack, so that it does not allocate.
+    pub(crate) const MAX_MESSAGES: usize = 64;
+
+    /// Room for the receive timestamp of every packet, aligned for control messages
+    #[derive(Clone, Copy)]
+    #[repr(C, align(8))]
+    struct ControlBuffer([u8; control_message_space::<[libc::timespec; 3]>()]);
+
+    /// Receive as many packets as are available, up to one per buffer and at
+    /// most `MAX_MESSAGES`, with a single `recvmmsg`. `handle` is called with
+    /// the size, control messages and sender of every received packet, in the
+    /// order of the buffers. Returns the number of packets received.
+    pub(crate) fn receive_messages<B: AsMut<[u8]>>(
+        socket: &std::net::UdpSocket,
+        packet_bufs: &mut [B],
+        mut handle: impl FnMut(usize, &mut dyn Iterator<Item = ControlMessage>, Option<SocketAddr>),
+    ) -> std::io::Result<usize> {
+        let count = packet_bufs.len().min(MAX_MESSAGES);
+        // Safety:
+        // iovec, sockaddr_storage and mmsghdr are plain data, for which all
+        // zeroes (null pointers and lengths) is a valid value
+        let mut iovecs: [libc::iovec; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
+        let mut control_bufs =
+            [ControlBuffer([0; control_message_space::<[libc::timespec; 3]>()]); MAX_MESSAGES];
+        let mut addrs: [libc::sockaddr_storage; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
+        let mut headers: [libc::mmsghdr; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
+
+        for (iovec, buf) in iovecs.iter_mut().zip(packet_bufs.iter_mut()) {
+            let buf = buf.as_mut();
+            *iovec = libc::iovec {
+                iov_base: buf.as_mut_ptr().cast::<libc::c_void>(),
+                iov_len: buf.len(),
+            };
+        }
+
+        let parts = iovecs
+            .iter_mut()
+            .zip(control_bufs.iter_mut())
+            .zip(addrs.iter_mut());
+        for (header, ((iovec, control), addr)) in headers.iter_mut().zip(parts).take(count) {
+            header.msg_hdr = libc::msghdr {
+                msg_name: (addr as *mut libc::sockaddr_storage).cast::<libc::c_void>(),
+                msg_namelen: std::mem::size_of::<libc::sockaddr_storage>() as u32,
+                msg_iov: iovec,
+                msg_iovlen: 1,
+                msg_control: control.0.as_mut_ptr().cast::<libc::c_void>(),
+                msg_controllen: control.0.len(),
+                msg_flags: 0,
+            };
+        }
+
+        // Safety:
+        // every header points to its own iovec, address and control buffer, with their
+        // lengths, which all live until after the call. Every iovec points to a packet
+        // buffer we have a mutable reference to. The headers are at least count entries
+        // long. If one of the buffers is too small, recvmmsg cuts off data at the
+        // appropriate boundary.
+        let received = loop {
+            match cerr(unsafe {
+                libc::recvmmsg(
+                    socket.as_raw_fd(),
+                    headers.as_mut_ptr(),
+                    count as libc::c_uint,
+                    0,
+                    std::ptr::null_mut(),
+                )
+            }) {
+                Err(e) if std::io::ErrorKind::Interrupted == e.kind() => continue,
+                Err(e) => return Err(e),
+                Ok(received) => break received as usize,
+            }
+        };
+
+        let messages = headers.iter().zip(addrs.iter()).zip(iovecs.iter());
+        for ((header, addr), iovec) in messages.take(received) {
+            if header.msg_hdr.msg_flags & libc::MSG_TRUNC > 0 {
+                warn!(
+                    max_len = iovec.iov_len,
+                    "truncated packet because it was larger than expected",
+                );
+            }
+
+            if header.msg_hdr.msg_flags & libc::MSG_CTRUNC > 0 {
+                warn!("truncated control messages");
+            }
+
+            // Clear out the fields for which we are giving up the reference
+            let mut mhdr = header.msg_hdr;
+            mhdr.msg_iov = std::ptr::null_mut();
+            mhdr.msg_iovlen = 0;
+            mhdr.msg_name = std::ptr::null_mut();
+            mhdr.msg_namelen = 0;
+
+            // Safety:
+            // recvmmsg ensures that the control buffer of every received message contains
+            // a set of valid control messages and that controllen is the length these take
+            // up in the buffer. The control buffers outlive the iterator.
+            let mut control_messages = unsafe { ControlMessageIterator::new(mhdr) };
+            handle(
+                header.msg_len as usize,
+                &mut control_messages,
+                sockaddr_storage_to_socket_addr(addr),
+            );
+        }
+
+        Ok(received)
+    }
+
+    /// Send as many of the packets as possible with a single `sendmmsg`, at
+    /// most `MAX_MESSAGES`. Returns the number of packets sent, which is at
+    /// least one. An error concerns the first packet.
+    pub(crate) fn send_messages(
+        socket: &std::net::UdpSocket,
+        packets: &[(&[u8], SocketAddr)],
+    ) -> std::io::Result<usize> {
+        let count = packets.len().min(MAX_MESSAGES);
+        // Safety:
+        // iovec, sockaddr_storage and mmsghdr are plain data, for which all
+        // zeroes (null pointers and lengths) is a valid value
+        let mut iovecs: [libc::iovec; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
+        let mut addrs: [(libc::sockaddr_storage, libc::socklen_t); MAX_MESSAGES] =
+            unsafe { std::mem::zeroed() };
+        let mut headers: [libc::mmsghdr; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
+
+        let parts = iovecs.iter_mut().zip(addrs.iter_mut());
+        for ((header, (iovec, (addr, length))), (buf, destination)) in
+            headers.iter_mut().zip(parts).zip(packets).take(count)
+        {
+            *iovec = libc::iovec {
+                // sendmmsg only reads from the buffers
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            };
+            (*addr, *length) = socket_address(*destination);
+            header.msg_hdr = libc::msghdr {
+                msg_name: (addr as *mut libc::sockaddr_storage).cast::<libc::c_void>(),
+                msg_namelen: *length,
+                msg_iov: iovec,
+                msg_iovlen: 1,
+                msg_control: std::ptr::null_mut(),
+                msg_controllen: 0,
+                msg_flags: 0,
+            };
+        }
+
+        // Safety:
+        // every header points to its own iovec and address, with their lengths, which all
+        // live until after the call. Every iovec points to a packet buffer that lives for
+        // the duration of the call. The headers are at least count entries long.
+        let sent = loop {
+            match cerr(unsafe {
+                libc::sendmmsg(
+                    socket.as_raw_fd(),
+                    headers.as_mut_ptr(),
+                    count as libc::c_uint,
+                    0,
+                )
+            }) {
+                Err(e) if std::io::ErrorKind::Interrupted == e.kind() => continue,
+                Err(e) => return Err(e),
+                Ok(sent) => break sent as usize,
+            }
+        };
+
+        Ok(sent)
+    }
+}
+
 mod timestamping_config {
     use std::os::unix::prelude::AsRawFd;
 
@@ -352,6 +794,44 @@ mod timestamping_config {
             }
         }
     }
+
+    /// The timestamping flags (`SOF_TIMESTAMPING_*`) the interface supports,
+    /// and the index of its PTP hardware clock, if it has one
+    pub(crate) fn interface_timestamping_info(
+        udp_socket: &std::net::UdpSocket,
+        interface: &str,
+    ) -> std::io::Result<(u32, Option<u32>)> {
+        const ETHTOOL_GET_TS_INFO: u32 = 0x00000041;
+
+        let mut tsi: ethtool_ts_info = ethtool_ts_info {
+            cmd: ETHTOOL_GET_TS_INFO,
+            ..Default::default()
+        };
+
+        let mut ifr_name = [0; 16];
+        // the name must be terminated by a zero byte
+        for (source, target) in interface.as_bytes().iter().zip(ifr_name[..15].iter_mut()) {
+            *target = *source as libc::c_char;
+        }
+
+        let ifr: libc::ifreq = libc::ifreq {
+            ifr_name,
+            ifr_ifru: libc::__c_anonymous_ifr_ifru {
+                ifru_data: (&mut tsi as *mut _) as *mut libc::c_char,
+            },
+        };
+
+        // Safety:
+        // the ifreq is valid for the duration of the call, and points to an
+        // ethtool_ts_info, which is what ETHTOOL_GET_TS_INFO writes to
+        const SIOCETHTOOL: u64 = 0x8946;
+        cerr(unsafe { libc::ioctl(udp_socket.as_raw_fd(), SIOCETHTOOL as libc::c_ulong, &ifr) })?;
+
+        // the kernel reports -1 for interfaces without a PTP hardware clock
+        let phc_index = (tsi.phc_index as i32 >= 0).then_some(tsi.phc_index);
+
+        Ok((tsi.so_timestamping, phc_index))
+    }
 }
 
 mod exceptional_condition_fd {
@@ -396,3 +876,148 @@ mod exceptional_condition_fd {
         AsyncFd::new(fd)
     }
 }
+
+mod activated_sockets {
+    use std::os::unix::{
+        net::UnixDatagram,
+        prelude::{AsRawFd, FromRawFd, RawFd},
+    };
+
+    use super::cerr;
+
+    /// First file descriptor passed by the service manager
+    const LISTEN_FDS_START: RawFd = 3;
+
+    /// The file descriptors passed to this process by the service manager
+    /// (systemd socket activation, see `sd_listen_fds(3)`)
+    fn listen_fds() -> std::ops::Range<RawFd> {
+        let for_us = match std::env::var("LISTEN_PID") {
+            Ok(pid) => pid == std::process::id().to_string(),
+            Err(_) => false,
+        };
+
+        let count = std::env::var("LISTEN_FDS")
+            .ok()
+            .filter(|_| for_us)
+            .and_then(|count| count.parse::<RawFd>().ok())
+            .unwrap_or(0);
+
+        LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count.max(0))
+    }
+
+    fn socket_option(fd: RawFd, option: libc::c_int) -> std::io::Result<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+        // Safety:
+        // getsockopt fails gracefully for file descriptors that are not open or
+        // not a socket. value and length point to memory we own for the duration
+        // of the call, and length is the size of value.
+        cerr(unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut length,
+            )
+        })?;
+
+        Ok(value)
+    }
+
+    /// Duplicates of the sockets passed by the service manager, for which
+    /// `accept(domain, type)` holds. The passed sockets themselves stay open,
+    /// such that they can be taken again (for instance after a network change).
+    pub(crate) fn activated_sockets<T: FromRawFd>(
+        accept: impl Fn(libc::c_int, libc::c_int) -> bool,
+    ) -> std::io::Result<Vec<T>> {
+        let mut sockets = vec![];
+
+        for fd in listen_fds() {
+            let (domain, socket_type) = match (
+                socket_option(fd, libc::SO_DOMAIN),
+                socket_option(fd, libc::SO_TYPE),
+            ) {
+                (Ok(domain), Ok(socket_type)) => (domain, socket_type),
+                // not a socket
+                _ => continue,
+            };
+
+            if !accept(domain, socket_type) {
+                continue;
+            }
+
+            // Safety:
+            // fcntl with F_DUPFD_CLOEXEC only creates a new file descriptor, and
+            // fails gracefully when fd is not open
+            let duplicate =
+                cerr(unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, LISTEN_FDS_START) })?;
+
+            // Safety:
+            // duplicate is a file descriptor we just created, so nothing else owns
+            // it, and it refers to a socket of the domain and type accepted by the
+            // caller for T
+            sockets.push(unsafe { T::from_raw_fd(duplicate) });
+        }
+
+        Ok(sockets)
+    }
+
+    /// Send `message` on a connected unix datagram socket, with the file
+    /// descriptors `fds` attached (`SCM_RIGHTS`), as for handing sockets to
+    /// the service manager
+    pub(crate) fn send_with_fds(
+        socket: &UnixDatagram,
+        message: &[u8],
+        fds: &[RawFd],
+    ) -> std::io::Result<()> {
+        let fds_size = std::mem::size_of_val(fds);
+
+        // Safety:
+        // CMSG_SPACE only computes the size of a control message
+        let space = unsafe { libc::CMSG_SPACE(fds_size as _) } as usize;
+        // the elements of a u64 buffer are aligned for cmsghdr, and one
+        // element more than space / 8 is always enough
+        let mut control_buf = vec![0u64; space / 8 + 1];
+
+        let mut iov = libc::iovec {
+            // the message is only read by sendmsg
+            iov_base: message.as_ptr() as *mut libc::c_void,
+            iov_len: message.len(),
+        };
+
+        let mhdr = libc::msghdr {
+            msg_name: std::ptr::null_mut(),
+            msg_namelen: 0,
+            msg_iov: &mut iov,
+            msg_iovlen: 1,
+            msg_control: control_buf.as_mut_ptr().cast::<libc::c_void>(),
+            msg_controllen: space,
+            msg_flags: 0,
+        };
+
+        // Safety:
+        // the control buffer is aligned for cmsghdr and CMSG_SPACE(fds_size)
+        // long, so the first header is not null, and it and its data of
+        // fds_size bytes lie within the buffer
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&mhdr);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_size as _) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr().cast::<u8>(),
+                libc::CMSG_DATA(cmsg),
+                fds_size,
+            );
+        }
+
+        // Safety:
+        // mhdr points to the message and control buffer we own for the
+        // duration of the call, with their lengths, and has no address as
+        // the socket is connected
+        cerr(unsafe { libc::sendmsg(socket.as_raw_fd(), &mhdr, 0) } as _)?;
+        Ok(())
+    }
+}
@@ -1,14 +1,21 @@
 #![forbid(unsafe_code)]
 
-use std::{io, net::SocketAddr, os::unix::prelude::RawFd};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    os::unix::prelude::RawFd,
+    path::Path,
+};
 
 use ntp_proto::NtpTimestamp;
 use tokio::io::unix::AsyncFd;
 use tracing::{debug, instrument, trace, warn};
 
+use crate::activation::activated_udp_socket;
 use crate::raw_socket::{
-    control_message_space, exceptional_condition_fd, receive_message, set_timestamping_options,
-    ControlMessage, MessageQueue, TimestampingConfig,
+    bind_in_network_namespace, bind_reuse_port, bind_to_device, control_message_space,
+    exceptional_condition_fd, int_option, receive_message, receive_messages, send_messages,
+    set_int_option, set_timestamping_options, ControlMessage, MessageQueue, TimestampingConfig,
 };
 
 enum Timestamping {
@@ -17,6 +24,79 @@ enum Timestamping {
     AllSupported,
 }
 
+/// Why packets sent on a socket did not arrive, as reported by ICMP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpError {
+    /// Nothing listens on the port at the destination
+    PortUnreachable,
+    /// The destination host can not be reached
+    HostUnreachable,
+    /// There is no route to the network of the destination
+    NetworkUnreachable,
+    /// Communication with the destination is administratively prohibited,
+    /// usually by a firewall
+    Prohibited,
+    /// Any other ICMP error
+    Other { icmp_type: u8, code: u8 },
+}
+
+impl IcmpError {
+    fn from_extended_error(error: &libc::sock_extended_err) -> Option<Self> {
+        match error.ee_origin {
+            libc::SO_EE_ORIGIN_ICMP => Some(Self::from_icmp(error.ee_type, error.ee_code)),
+            libc::SO_EE_ORIGIN_ICMP6 => Some(Self::from_icmp6(error.ee_type, error.ee_code)),
+            _ => None,
+        }
+    }
+
+    /// Destination unreachable codes of RFC 792 and RFC 1812
+    fn from_icmp(icmp_type: u8, code: u8) -> Self {
+        match (icmp_type, code) {
+            (3, 0 | 6) => Self::NetworkUnreachable,
+            (3, 1 | 7) => Self::HostUnreachable,
+            (3, 3) => Self::PortUnreachable,
+            (3, 9 | 10 | 13) => Self::Prohibited,
+            _ => Self::Other { icmp_type, code },
+        }
+    }
+
+    /// Destination unreachable codes of RFC 4443
+    fn from_icmp6(icmp_type: u8, code: u8) -> Self {
+        match (icmp_type, code) {
+            (1, 0) => Self::NetworkUnreachable,
+            (1, 3) => Self::HostUnreachable,
+            (1, 4) => Self::PortUnreachable,
+            (1, 1 | 5 | 6) => Self::Prohibited,
+            _ => Self::Other { icmp_type, code },
+        }
+    }
+
+    /// Whether the destination will not answer until its configuration
+    /// changes, rather than it being a (possibly temporary) routing problem.
+    /// These are the hard errors of RFC 1122.
+    pub fn is_hard(self) -> bool {
+        matches!(self, Self::PortUnreachable | Self::Prohibited)
+    }
+}
+
+impl std::fmt::Display for IcmpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PortUnreachable => f.write_str("port unreachable"),
+            Self::HostUnreachable => f.write_str("host unreachable"),
+            Self::NetworkUnreachable => f.write_str("network unreachable"),
+            Self::Prohibited => f.write_str("communication administratively prohibited"),
+            Self::Other { icmp_type, code } => {
+                write!(f, "ICMP error of type {icmp_type}, code {code}")
+            }
+        }
+    }
+}
+
+/// Largest number of packets that `recv_batch` and `send_batch` handle with a
+/// single call
+pub const MAX_BATCH_SIZE: usize = crate::raw_socket::MAX_MESSAGES;
+
 pub struct UdpSocket {
     io: AsyncFd<std::net::UdpSocket>,
     exceptional_condition: AsyncFd<RawFd>,
@@ -35,32 +115,130 @@ impl UdpSocket {
 
         Self::client_with_timestamping(
             listen_addr,
-            peer_addr,
+            Some(peer_addr),
+            None,
+            None,
             Timestamping::Configure(timestamping),
         )
         .await
     }
 
-    async fn client_with_timestamping(
+    /// A client socket that only sends and receives on the given network
+    /// interface (`SO_BINDTODEVICE`), for instance to select a VRF
+    #[instrument(level = "debug", skip(peer_addr))]
+    pub async fn client_on_interface(
         listen_addr: SocketAddr,
         peer_addr: SocketAddr,
+        interface: &str,
+    ) -> io::Result<UdpSocket> {
+        // disable tx timestamping for now (outside of tests)
+        let timestamping = TimestampingConfig {
+            rx_software: true,
+            tx_software: false,
+        };
+
+        Self::client_with_timestamping(
+            listen_addr,
+            Some(peer_addr),
+            Some(interface),
+            None,
+            Timestamping::Configure(timestamping),
+        )
+        .await
+    }
+
+    /// A client socket in another network namespace, given by its file such
+    /// as `/run/netns/NAME`, optionally bound to a network interface of that
+    /// namespace. Entering the namespace requires `CAP_SYS_ADMIN`.
+    #[instrument(level = "debug", skip(peer_addr))]
+    pub async fn client_in_network_namespace(
+        listen_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        interface: Option<&str>,
+        namespace: &Path,
+    ) -> io::Result<UdpSocket> {
+        // disable tx timestamping for now (outside of tests)
+        let timestamping = TimestampingConfig {
+            rx_software: true,
+            tx_software: false,
+        };
+
+        Self::client_with_timestamping(
+            listen_addr,
+            Some(peer_addr),
+            interface,
+            Some(namespace),
+            Timestamping::Configure(timestamping),
+        )
+        .await
+    }
+
+    /// A client socket that is not connected to a peer, such that it receives
+    /// packets from any address. Packets are sent with `send_to`. Interface
+    /// and network namespace are as for the connected client sockets.
+    #[instrument(level = "debug")]
+    pub async fn unconnected_client(
+        listen_addr: SocketAddr,
+        interface: Option<&str>,
+        namespace: Option<&Path>,
+    ) -> io::Result<UdpSocket> {
+        // disable tx timestamping for now (outside of tests)
+        let timestamping = TimestampingConfig {
+            rx_software: true,
+            tx_software: false,
+        };
+
+        Self::client_with_timestamping(
+            listen_addr,
+            None,
+            interface,
+            namespace,
+            Timestamping::Configure(timestamping),
+        )
+        .await
+    }
+
+    async fn client_with_timestamping(
+        listen_addr: SocketAddr,
+        peer_addr: Option<SocketAddr>,
+        interface: Option<&str>,
+        network_namespace: Option<&Path>,
         timestamping: Timestamping,
     ) -> io::Result<UdpSocket> {
-        let socket = tokio::net::UdpSocket::bind(listen_addr).await?;
+        let socket = match network_namespace {
+            Some(namespace) => {
+                tokio::net::UdpSocket::from_std(bind_in_network_namespace(listen_addr, namespace)?)?
+            }
+            None => tokio::net::UdpSocket::bind(listen_addr).await?,
+        };
         debug!(
             local_addr = debug(socket.local_addr().unwrap()),
             "client socket bound"
         );
 
-        socket.connect(peer_addr).await?;
-        debug!(
-            local_addr = debug(socket.local_addr().unwrap()),
-            peer_addr = debug(socket.peer_addr().unwrap()),
-            "client socket connected"
-        );
+        // before connecting, such that the route is looked up on the interface
+        if let Some(interface) = interface {
+            bind_to_device(&socket, interface)?;
+        }
+
+        if let Some(peer_addr) = peer_addr {
+            socket.connect(peer_addr).await?;
+            debug!(
+                local_addr = debug(socket.local_addr().unwrap()),
+                peer_addr = debug(socket.peer_addr().unwrap()),
+                "client socket connected"
+            );
+        }
 
         let socket = socket.into_std()?;
 
+        // queue ICMP errors for our requests, see `UdpSocket::take_icmp_error`. Without this,
+        // only some of them are reported, and without any detail.
+        match peer_addr.unwrap_or(listen_addr) {
+            SocketAddr::V4(_) => set_int_option(&socket, libc::SOL_IP, libc::IP_RECVERR, 1)?,
+            SocketAddr::V6(_) => set_int_option(&socket, libc::SOL_IPV6, libc::IPV6_RECVERR, 1)?,
+        }
+
         let timestamping = match timestamping {
             Timestamping::Configure(config) => config,
             Timestamping::AllSupported => TimestampingConfig::all_supported(&socket)?,
@@ -78,16 +256,94 @@ impl UdpSocket {
 
     #[instrument(level = "debug")]
     pub async fn server(listen_addr: SocketAddr) -> io::Result<UdpSocket> {
-        let socket = tokio::net::UdpSocket::bind(listen_addr).await?;
+        Self::server_with_reuse_port(listen_addr, false).await
+    }
+
+    /// A server socket that can share its address with other sockets created
+    /// by this function (`SO_REUSEPORT`). The kernel spreads incoming packets
+    /// over the sockets by the address of the sender, so that all packets of a
+    /// client arrive on the same socket.
+    #[instrument(level = "debug")]
+    pub async fn server_reuse_port(listen_addr: SocketAddr) -> io::Result<UdpSocket> {
+        Self::server_with_reuse_port(listen_addr, true).await
+    }
+
+    async fn server_with_reuse_port(
+        listen_addr: SocketAddr,
+        reuse_port: bool,
+    ) -> io::Result<UdpSocket> {
+        let socket = match activated_udp_socket(listen_addr)? {
+            Some(socket) => {
+                // all sockets share the single socket passed by the service manager
+                debug!(?listen_addr, "using server socket of the service manager");
+                socket.set_nonblocking(true)?;
+                socket
+            }
+            None if reuse_port => {
+                let socket = bind_reuse_port(listen_addr)?;
+                debug!(
+                    local_addr = debug(socket.local_addr().unwrap()),
+                    "server socket bound with SO_REUSEPORT"
+                );
+
+                socket
+            }
+            None => {
+                let socket = tokio::net::UdpSocket::bind(listen_addr).await?;
+                debug!(
+                    local_addr = debug(socket.local_addr().unwrap()),
+                    "server socket bound"
+                );
+
+                socket.into_std()?
+            }
+        };
+
+        // our supported kernel versions always have receive timestamping. Send timestamping for a
+        // server connection is not relevant, so we don't even bother with checking if it is supported
+        let timestamping = TimestampingConfig {
+            rx_software: true,
+            tx_software: false,
+        };
+
+        set_timestamping_options(&socket, timestamping)?;
+
+        Ok(UdpSocket {
+            exceptional_condition: exceptional_condition_fd(&socket)?,
+            io: AsyncFd::new(socket)?,
+            send_counter: 0,
+            timestamping,
+        })
+    }
+
+    /// A socket on `port` of all addresses that joins the IPv4 multicast
+    /// `group` on the interface with address `interface`, and sends its
+    /// packets for the group out of that interface. With an unspecified
+    /// `interface` the kernel picks one.
+    #[instrument(level = "debug")]
+    pub async fn multicast_v4(
+        port: u16,
+        group: Ipv4Addr,
+        interface: Ipv4Addr,
+    ) -> io::Result<UdpSocket> {
+        let socket = tokio::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+        socket.join_multicast_v4(group, interface)?;
         debug!(
             local_addr = debug(socket.local_addr().unwrap()),
-            "server socket bound"
+            "multicast socket bound"
         );
 
         let socket = socket.into_std()?;
+        if !interface.is_unspecified() {
+            // the kernel takes an option of 4 bytes as the address (`struct in_addr`)
+            set_int_option(
+                &socket,
+                libc::SOL_IP,
+                libc::IP_MULTICAST_IF,
+                libc::c_int::from_ne_bytes(interface.octets()),
+            )?;
+        }
 
-        // our supported kernel versions always have receive timestamping. Send timestamping for a
-        // server connection is not relevant, so we don't even bother with checking if it is supported
         let timestamping = TimestampingConfig {
             rx_software: true,
             tx_software: false,
@@ -103,6 +359,74 @@ impl UdpSocket {
         })
     }
 
+    /// Mark outgoing packets with the given DSCP value (0 to 63), so that they
+    /// can be prioritized by the network. The marking is a property of the
+    /// socket, and so applies to every packet sent, timestamped or not.
+    ///
+    /// IPv6 sockets set both the IPv6 traffic class and the IPv4 type of
+    /// service, as dual stack sockets also send IPv4 packets.
+    pub fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        if dscp > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DSCP values range from 0 to 63",
+            ));
+        }
+
+        // the DSCP is the upper 6 bits of the field, the lower 2 bits are for ECN
+        let traffic_class = (dscp as libc::c_int) << 2;
+        let socket = self.io.get_ref();
+
+        match socket.local_addr()? {
+            SocketAddr::V4(_) => {
+                set_int_option(socket, libc::IPPROTO_IP, libc::IP_TOS, traffic_class)
+            }
+            SocketAddr::V6(_) => {
+                set_int_option(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, traffic_class)?;
+
+                // fails on sockets that only do IPv6, which have no use for it anyway
+                if let Err(error) =
+                    set_int_option(socket, libc::IPPROTO_IP, libc::IP_TOS, traffic_class)
+                {
+                    debug!(?error, "could not set the type of service for IPv4");
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// The DSCP value of outgoing packets
+    pub fn dscp(&self) -> io::Result<u8> {
+        let socket = self.io.get_ref();
+        let traffic_class = match socket.local_addr()? {
+            SocketAddr::V4(_) => int_option(socket, libc::IPPROTO_IP, libc::IP_TOS)?,
+            SocketAddr::V6(_) => int_option(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS)?,
+        };
+
+        Ok((traffic_class >> 2) as u8)
+    }
+
+    /// Set the priority of outgoing packets within the host (`SO_PRIORITY`),
+    /// which selects the queue of the network interface they are sent on.
+    /// Priorities above 6 need `CAP_NET_ADMIN`.
+    pub fn set_priority(&self, priority: u32) -> io::Result<()> {
+        let priority = libc::c_int::try_from(priority)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        set_int_option(
+            self.io.get_ref(),
+            libc::SOL_SOCKET,
+            libc::SO_PRIORITY,
+            priority,
+        )
+    }
+
+    /// The priority of outgoing packets within the host
+    pub fn priority(&self) -> io::Result<u32> {
+        let priority = int_option(self.io.get_ref(), libc::SOL_SOCKET, libc::SO_PRIORITY)?;
+        Ok(priority as u32)
+    }
+
     #[instrument(level = "trace", skip(self, buf), fields(
         local_addr = debug(self.as_ref().local_addr().unwrap()),
         peer_addr = debug(self.as_ref().peer_addr()),
@@ -208,6 +532,58 @@ impl UdpSocket {
         }
     }
 
+    /// Like [`UdpSocket::send_to`], but `prepare` is called on the packet
+    /// right before the system call that sends it, once the socket is known
+    /// to be writable. This allows a timestamp in the packet to be taken as
+    /// late as possible. `prepare` is called again when sending is retried.
+    #[instrument(level = "trace", skip(self, buf, prepare), fields(
+        local_addr = debug(self.as_ref().local_addr().unwrap()),
+        buf_size = buf.len(),
+    ))]
+    pub async fn send_to_with<F: FnMut(&mut [u8])>(
+        &self,
+        buf: &mut [u8],
+        addr: SocketAddr,
+        mut prepare: F,
+    ) -> io::Result<usize> {
+        trace!(size = buf.len(), ?addr, "sending bytes");
+        loop {
+            let mut guard = self.io.writable().await?;
+            match guard.try_io(|inner| {
+                prepare(buf);
+                inner.get_ref().send_to(buf, addr)
+            }) {
+                Ok(result) => {
+                    match &result {
+                        Ok(size) => trace!(sent = size, "sent bytes"),
+                        Err(e) => debug!(error = debug(e), "error sending data"),
+                    }
+                    return result;
+                }
+                Err(_would_block) => {
+                    trace!("blocked after becoming writable, retrying");
+                    continue;
+                }
+            }
+        }
+    }
+
+    /// Take the errors that ICMP reported for packets sent on a client socket
+    /// from its error queue, returning the latest. This explains why a
+    /// receive failed with, for instance, `ECONNREFUSED`.
+    pub fn take_icmp_error(&self) -> io::Result<Option<IcmpError>> {
+        let mut latest = None;
+        loop {
+            match receive_icmp_error(self.io.get_ref()) {
+                Ok(Some(error)) => latest = Some(error),
+                // something else, like a send timestamp
+                Ok(None) => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(latest),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     #[instrument(level = "trace", skip(self, buf), fields(
         local_addr = debug(self.as_ref().local_addr().unwrap()),
         peer_addr = debug(self.as_ref().peer_addr().ok()),
@@ -219,7 +595,21 @@ impl UdpSocket {
     ) -> io::Result<(usize, SocketAddr, Option<NtpTimestamp>)> {
         loop {
             trace!("waiting for socket to become readable");
-            let mut guard = self.io.readable().await?;
+            let mut guard = tokio::select! {
+                guard = self.io.readable() => guard?,
+                // errors reported by ICMP do not make the socket readable
+                guard = self.exceptional_condition.readable() => {
+                    let mut guard = guard?;
+                    if let Some(error) = self.io.get_ref().take_error()? {
+                        debug!(error = debug(&error), "error receiving data");
+                        return Err(error);
+                    }
+
+                    // something else on the error queue, like a send timestamp
+                    guard.clear_ready();
+                    continue;
+                }
+            };
             let result = match guard.try_io(|inner| recv(inner.get_ref(), buf)) {
                 Err(_would_block) => {
                     trace!("blocked after becoming readable, retrying");
@@ -236,6 +626,68 @@ impl UdpSocket {
             return result;
         }
     }
+
+    /// Receive as many packets as are available at once, up to one per buffer
+    /// and at most `MAX_BATCH_SIZE`, waiting for at least one. The size, sender and receive timestamp of
+    /// every packet are appended to `received`, in the order of the buffers.
+    /// Returns the number of packets received.
+    #[instrument(level = "trace", skip_all, fields(
+        local_addr = debug(self.as_ref().local_addr().unwrap()),
+        batch_size = bufs.len(),
+    ))]
+    pub async fn recv_batch<B: AsMut<[u8]>>(
+        &self,
+        bufs: &mut [B],
+        received: &mut Vec<(usize, SocketAddr, Option<NtpTimestamp>)>,
+    ) -> io::Result<usize> {
+        if bufs.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            trace!("waiting for socket to become readable");
+            let mut guard = self.io.readable().await?;
+            match guard.try_io(|inner| recv_batch(inner.get_ref(), bufs, received)) {
+                Err(_would_block) => {
+                    trace!("blocked after becoming readable, retrying");
+                    continue;
+                }
+                Ok(Ok(count)) => {
+                    trace!(count, "received messages");
+                    return Ok(count);
+                }
+                Ok(Err(e)) => {
+                    debug!(error = debug(&e), "error receiving data");
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Send as many of the packets as possible at once, at most
+    /// `MAX_BATCH_SIZE`, waiting until at least one can be sent. Returns the number of packets sent. An error concerns
+    /// the first packet, after which the others can be sent with another call.
+    #[instrument(level = "trace", skip_all, fields(
+        local_addr = debug(self.as_ref().local_addr().unwrap()),
+        batch_size = packets.len(),
+    ))]
+    pub async fn send_batch(&self, packets: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        if packets.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            trace!("waiting for socket to become writable");
+            let mut guard = self.io.writable().await?;
+            match guard.try_io(|inner| send_messages(inner.get_ref(), packets)) {
+                Err(_would_block) => {
+                    trace!("blocked after becoming writable, retrying");
+                    continue;
+                }
+                Ok(result) => return result,
+            }
+        }
+    }
 }
 
 impl AsRef<std::net::UdpSocket> for UdpSocket {
@@ -256,13 +708,31 @@ fn recv(
     let sock_addr =
         sock_addr.unwrap_or_else(|| unreachable!("We never constructed a non-ip socket"));
 
+    let timestamp = receive_timestamp(control_messages);
+    Ok((bytes_read as usize, sock_addr, timestamp))
+}
+
+fn recv_batch<B: AsMut<[u8]>>(
+    socket: &std::net::UdpSocket,
+    bufs: &mut [B],
+    received: &mut Vec<(usize, SocketAddr, Option<NtpTimestamp>)>,
+) -> io::Result<usize> {
+    receive_messages(socket, bufs, |size, control_messages, sock_addr| {
+        let sock_addr =
+            sock_addr.unwrap_or_else(|| unreachable!("We never constructed a non-ip socket"));
+        received.push((size, sock_addr, receive_timestamp(control_messages)));
+    })
+}
+
+/// The receive timestamp among the control messages of a received packet
+fn receive_timestamp(
+    control_messages: impl Iterator<Item = ControlMessage>,
+) -> Option<NtpTimestamp> {
     // Loops through the control messages, but we should only get a single message in practice
     for msg in control_messages {
         match msg {
             ControlMessage::Timestamping(timespec) => {
-                let timestamp = read_ntp_timestamp(timespec);
-
-                return Ok((bytes_read as usize, sock_addr, Some(timestamp)));
+                return Some(read_ntp_timestamp(timespec));
             }
 
             ControlMessage::ReceiveError(_error) => {
@@ -278,7 +748,7 @@ fn recv(
         }
     }
 
-    Ok((bytes_read as usize, sock_addr, None))
+    None
 }
 
 fn fetch_send_timestamp_help(
@@ -341,7 +811,32 @@ fn fetch_send_timestamp_help(
     Ok(send_ts)
 }
 
-fn read_ntp_timestamp(timespec: libc::timespec) -> NtpTimestamp {
+/// Read a single message from the error queue, which holds a copy of the
+/// packet that caused the error
+fn receive_icmp_error(socket: &std::net::UdpSocket) -> io::Result<Option<IcmpError>> {
+    const CONTROL_SIZE: usize = control_message_space::<[libc::timespec; 3]>()
+        + control_message_space::<(libc::sock_extended_err, libc::sockaddr_storage)>();
+
+    let mut control_buf = [0; CONTROL_SIZE];
+    let mut packet_buf = [0; 48];
+
+    let (_, control_messages, _) = receive_message(
+        socket,
+        &mut packet_buf,
+        &mut control_buf,
+        MessageQueue::Error,
+    )?;
+
+    let error = control_messages
+        .filter_map(|msg| match msg {
+            ControlMessage::ReceiveError(error) => IcmpError::from_extended_error(&error),
+            _ => None,
+        })
+        .last();
+    Ok(error)
+}
+
+pub(crate) fn read_ntp_timestamp(timespec: libc::timespec) -> NtpTimestamp {
     // Unix uses an epoch located at 1/1/1970-00:00h (UTC) and NTP uses 1/1/1900-00:00h.
     // This leads to an offset equivalent to 70 years in seconds
     // there are 17 leap years between the two dates so the offset is
@@ -358,8 +853,6 @@ fn read_ntp_timestamp(timespec: libc::timespec) -> NtpTimestamp {
 
 #[cfg(test)]
 mod tests {
-    use std::net::Ipv4Addr;
-
     use super::*;
 
     #[tokio::test]
@@ -420,6 +913,52 @@ mod tests {
         assert_eq!(buf, [2; 48]);
     }
 
+    #[tokio::test]
+    async fn test_client_port_unreachable() {
+        // nothing listens on the port of the peer
+        let mut a = UdpSocket::client(
+            "127.0.0.1:10016".parse().unwrap(),
+            "127.0.0.1:10017".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(a.take_icmp_error().unwrap(), None);
+
+        a.send(&[1; 48]).await.unwrap();
+        let mut buf = [0; 48];
+        let error = a.recv(&mut buf).await.unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::ECONNREFUSED));
+
+        assert_eq!(
+            a.take_icmp_error().unwrap(),
+            Some(IcmpError::PortUnreachable)
+        );
+        assert_eq!(a.take_icmp_error().unwrap(), None);
+    }
+
+    #[test]
+    fn test_icmp_error_codes() {
+        assert_eq!(IcmpError::from_icmp(3, 3), IcmpError::PortUnreachable);
+        assert_eq!(IcmpError::from_icmp(3, 1), IcmpError::HostUnreachable);
+        assert_eq!(IcmpError::from_icmp(3, 0), IcmpError::NetworkUnreachable);
+        assert_eq!(IcmpError::from_icmp(3, 13), IcmpError::Prohibited);
+        assert_eq!(
+            IcmpError::from_icmp(11, 0),
+            IcmpError::Other {
+                icmp_type: 11,
+                code: 0
+            }
+        );
+
+        assert_eq!(IcmpError::from_icmp6(1, 4), IcmpError::PortUnreachable);
+        assert_eq!(IcmpError::from_icmp6(1, 3), IcmpError::HostUnreachable);
+        assert_eq!(IcmpError::from_icmp6(1, 0), IcmpError::NetworkUnreachable);
+        assert_eq!(IcmpError::from_icmp6(1, 1), IcmpError::Prohibited);
+
+        assert!(IcmpError::PortUnreachable.is_hard());
+        assert!(!IcmpError::HostUnreachable.is_hard());
+    }
+
     #[tokio::test]
     async fn test_server_basic_ipv4() {
         let a = UdpSocket::server("127.0.0.1:10002".parse().unwrap())
@@ -472,11 +1011,226 @@ mod tests {
         assert_eq!(buf, [2; 48]);
     }
 
+    #[tokio::test]
+    async fn test_traffic_marking() {
+        let a = UdpSocket::client(
+            "127.0.0.1:10004".parse().unwrap(),
+            "127.0.0.1:10005".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(a.dscp().unwrap(), 0);
+        a.set_dscp(46).unwrap();
+        assert_eq!(a.dscp().unwrap(), 46);
+        assert!(a.set_dscp(64).is_err());
+
+        a.set_priority(6).unwrap();
+        assert_eq!(a.priority().unwrap(), 6);
+
+        let b = UdpSocket::server("[::]:10005".parse().unwrap())
+            .await
+            .unwrap();
+        b.set_dscp(46).unwrap();
+        assert_eq!(b.dscp().unwrap(), 46);
+    }
+
+    #[tokio::test]
+    async fn test_client_on_interface() {
+        let mut a = UdpSocket::client_on_interface(
+            "127.0.0.1:10006".parse().unwrap(),
+            "127.0.0.1:10007".parse().unwrap(),
+            "lo",
+        )
+        .await
+        .unwrap();
+        let b = UdpSocket::client(
+            "127.0.0.1:10007".parse().unwrap(),
+            "127.0.0.1:10006".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        a.send(&[1; 48]).await.unwrap();
+        let mut buf = [0; 48];
+        let (size, addr, _) = b.recv(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+        assert_eq!(addr, "127.0.0.1:10006".parse().unwrap());
+
+        assert!(UdpSocket::client_on_interface(
+            "127.0.0.1:10008".parse().unwrap(),
+            "127.0.0.1:10009".parse().unwrap(),
+            "an-interface-name-that-is-too-long",
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unconnected_client() {
+        let a = UdpSocket::unconnected_client("127.0.0.1:10034".parse().unwrap(), None, None)
+            .await
+            .unwrap();
+        let mut b = UdpSocket::client(
+            "127.0.0.1:10035".parse().unwrap(),
+            "127.0.0.1:10034".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        let mut c = UdpSocket::client(
+            "127.0.0.1:10036".parse().unwrap(),
+            "127.0.0.1:10034".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        a.send_to(&[1; 48], "127.0.0.1:10035".parse().unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0; 48];
+        let (size, addr, _) = b.recv(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+        assert_eq!(addr, "127.0.0.1:10034".parse().unwrap());
+
+        // answers arrive from any address
+        b.send(&[2; 48]).await.unwrap();
+        let (_, addr, _) = a.recv(&mut buf).await.unwrap();
+        assert_eq!(addr, "127.0.0.1:10035".parse().unwrap());
+        c.send(&[3; 48]).await.unwrap();
+        let (_, addr, _) = a.recv(&mut buf).await.unwrap();
+        assert_eq!(addr, "127.0.0.1:10036".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_client_in_network_namespace() {
+        let result = UdpSocket::client_in_network_namespace(
+            "127.0.0.1:10030".parse().unwrap(),
+            "127.0.0.1:10031".parse().unwrap(),
+            None,
+            Path::new("/run/netns/a-namespace-that-does-not-exist"),
+        )
+        .await;
+        assert!(matches!(result, Err(error) if error.kind() == io::ErrorKind::NotFound));
+
+        // entering even our own namespace needs CAP_SYS_ADMIN
+        let a = match UdpSocket::client_in_network_namespace(
+            "127.0.0.1:10032".parse().unwrap(),
+            "127.0.0.1:10033".parse().unwrap(),
+            Some("lo"),
+            Path::new("/proc/self/ns/net"),
+        )
+        .await
+        {
+            Ok(socket) => socket,
+            Err(error) => {
+                assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+                return;
+            }
+        };
+        let mut b = UdpSocket::client(
+            "127.0.0.1:10033".parse().unwrap(),
+            "127.0.0.1:10032".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        b.send(&[1; 48]).await.unwrap();
+        let mut buf = [0; 48];
+        let (size, addr, _) = a.recv(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+        assert_eq!(addr, "127.0.0.1:10033".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_server_reuse_port() {
+        let a = UdpSocket::server_reuse_port("127.0.0.1:10010".parse().unwrap())
+            .await
+            .unwrap();
+        let b = UdpSocket::server_reuse_port("127.0.0.1:10010".parse().unwrap())
+            .await
+            .unwrap();
+
+        // without SO_REUSEPORT, the address is in use
+        assert!(UdpSocket::server("127.0.0.1:10010".parse().unwrap())
+            .await
+            .is_err());
+
+        let mut client = UdpSocket::client(
+            "127.0.0.1:10011".parse().unwrap(),
+            "127.0.0.1:10010".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        client.send(&[1; 48]).await.unwrap();
+
+        // the packet arrives on exactly one of the sockets
+        let (mut buf_a, mut buf_b) = ([0; 48], [0; 48]);
+        let (size, addr, _) = tokio::select! {
+            result = a.recv(&mut buf_a) => result.unwrap(),
+            result = b.recv(&mut buf_b) => result.unwrap(),
+        };
+        assert_eq!(size, 48);
+        assert_eq!(addr, "127.0.0.1:10011".parse().unwrap());
+
+        let c = UdpSocket::server_reuse_port("[::1]:10010".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            c.as_ref().local_addr().unwrap(),
+            "[::1]:10010".parse().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_batch() {
+        let server = UdpSocket::server("127.0.0.1:10014".parse().unwrap())
+            .await
+            .unwrap();
+        let mut client = UdpSocket::client(
+            "127.0.0.1:10015".parse().unwrap(),
+            "127.0.0.1:10014".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        for i in 1..=3 {
+            client.send(&[i; 48]).await.unwrap();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let mut bufs = [[0; 64]; 4];
+        let mut received = vec![];
+        let count = server.recv_batch(&mut bufs, &mut received).await.unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(received.len(), 3);
+        for (i, (size, addr, timestamp)) in received.iter().enumerate() {
+            assert_eq!(*size, 48);
+            assert_eq!(*addr, "127.0.0.1:10015".parse().unwrap());
+            assert!(timestamp.is_some());
+            assert_eq!(bufs[i][0], i as u8 + 1);
+        }
+
+        let packets: Vec<_> = received
+            .iter()
+            .zip(bufs.iter())
+            .map(|((size, addr, _), buf)| (&buf[..*size], *addr))
+            .collect();
+        assert_eq!(server.send_batch(&packets).await.unwrap(), 3);
+
+        let mut buf = [0; 48];
+        for i in 1..=3 {
+            client.recv(&mut buf).await.unwrap();
+            assert_eq!(buf, [i; 48]);
+        }
+    }
+
     #[tokio::test]
     async fn test_timestamping_reasonable() {
         let mut a = UdpSocket::client_with_timestamping(
             SocketAddr::from((Ipv4Addr::LOCALHOST, 8000)),
-            SocketAddr::from((Ipv4Addr::LOCALHOST, 8001)),
+            Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 8001))),
+            None,
+            None,
             Timestamping::AllSupported,
         )
         .await
@@ -511,7 +1265,9 @@ mod tests {
     async fn test_send_timestamp() {
         let mut a = UdpSocket::client_with_timestamping(
             SocketAddr::from((Ipv4Addr::LOCALHOST, 8012)),
-            SocketAddr::from((Ipv4Addr::LOCALHOST, 8013)),
+            Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 8013))),
+            None,
+            None,
             Timestamping::AllSupported,
         )
         .await
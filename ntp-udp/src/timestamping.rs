@@ -0,0 +1,63 @@
+//! Which timestamps the network interfaces of the host can take
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use crate::{interface_name::interface_names, raw_socket::interface_timestamping_info};
+
+/// Which timestamps a network interface can take of the packets it sends
+/// and receives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceTimestamping {
+    pub interface: String,
+    /// Timestamps taken by the kernel, in both directions
+    pub software: bool,
+    /// Timestamps taken by the network card, in both directions
+    pub hardware: bool,
+    /// Index of the PTP hardware clock of the card, `/dev/ptpN`
+    pub phc_index: Option<u32>,
+}
+
+/// The timestamping support of every network interface of the host.
+/// Interfaces of which the driver does not report its support are listed as
+/// taking no timestamps at all.
+pub fn interface_timestamping() -> std::io::Result<Vec<InterfaceTimestamping>> {
+    const SOFTWARE: u32 = libc::SOF_TIMESTAMPING_RX_SOFTWARE | libc::SOF_TIMESTAMPING_TX_SOFTWARE;
+    const HARDWARE: u32 = libc::SOF_TIMESTAMPING_RX_HARDWARE
+        | libc::SOF_TIMESTAMPING_TX_HARDWARE
+        | libc::SOF_TIMESTAMPING_RAW_HARDWARE;
+
+    let socket = std::net::UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?;
+
+    Ok(interface_names()?
+        .into_iter()
+        .map(|interface| {
+            let (flags, phc_index) =
+                interface_timestamping_info(&socket, &interface).unwrap_or((0, None));
+
+            InterfaceTimestamping {
+                interface,
+                software: flags & SOFTWARE == SOFTWARE,
+                hardware: flags & HARDWARE == HARDWARE,
+                phc_index,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loopback_timestamping() {
+        let interfaces = interface_timestamping().unwrap();
+        let loopback = interfaces
+            .iter()
+            .find(|interface| interface.interface == "lo")
+            .unwrap();
+
+        // the loopback interface has no network card to take timestamps
+        assert!(!loopback.hardware);
+        assert_eq!(loopback.phc_index, None);
+    }
+}
@@ -0,0 +1,550 @@
+//! Batched receiving and sending of server packets with io_uring.
+//!
+//! A single multishot `recvmsg` keeps receiving packets into buffers that are
+//! provided to the kernel up front. Responses are sent with `sendmsg`
+//! operations that are submitted together with the buffers given back, so a
+//! whole batch of packets costs a single system call, instead of two per
+//! packet. Multishot receives need Linux 6.0 or later.
+
+// Note on unsafe usage.
+//
+// While an operation is in flight, the kernel reads from and writes to memory
+// owned by `UringSocket`: the message header and buffers of the receive, and
+// the slots of the sends. All of it is allocated once, in boxes that are never
+// reallocated, and `UringSocket` waits for all operations to finish before its
+// memory is freed. Any pointer handed to the kernel therefore stays valid for
+// the duration of the operation.
+
+use std::{
+    io,
+    marker::PhantomData,
+    net::SocketAddr,
+    os::unix::prelude::{AsRawFd, RawFd},
+};
+
+use io_uring::{cqueue, opcode, squeue, types, IoUring};
+use ntp_proto::NtpTimestamp;
+use tokio::io::unix::AsyncFd;
+use tracing::{debug, trace, warn};
+
+use crate::{
+    interface_name::sockaddr_storage_to_socket_addr,
+    raw_socket::{control_message_space, control_messages, socket_address, ControlMessage},
+    socket::read_ntp_timestamp,
+    UdpSocket,
+};
+
+/// User data of the completions that are not sends, which use the index of
+/// their slot instead
+const RECEIVE: u64 = u64::MAX;
+const PROVIDE_BUFFERS: u64 = u64::MAX - 1;
+const CANCEL: u64 = u64::MAX - 2;
+
+const RING_ENTRIES: u32 = 1024;
+
+const BUFFER_GROUP: u16 = 0;
+const RECEIVE_BUFFERS: u16 = 256;
+
+/// Large enough for control requests, which are not limited to 48 bytes
+const MAX_PACKET_SIZE: usize = 1024;
+
+/// Space for the sender address of a received packet
+const NAME_SPACE: usize = std::mem::size_of::<libc::sockaddr_storage>();
+
+/// Space for the receive timestamp of a received packet
+const CONTROL_SPACE: usize = control_message_space::<[libc::timespec; 3]>();
+
+/// Every received packet is preceded by a `struct io_uring_recvmsg_out`, the
+/// sender address and the control messages. With a header of 16 bytes and
+/// name and control spaces that are multiples of 8, the control messages are
+/// always suitably aligned.
+const PAYLOAD_OFFSET: usize = 16 + NAME_SPACE + CONTROL_SPACE;
+const RECEIVE_BUFFER_SIZE: usize = PAYLOAD_OFFSET + MAX_PACKET_SIZE;
+
+const SEND_SLOTS: usize = 256;
+
+/// Large enough for the largest fragment of a control response
+const MAX_SEND_SIZE: usize = 512;
+
+#[repr(C, align(8))]
+struct ReceiveBuffer([u8; RECEIVE_BUFFER_SIZE]);
+
+struct SendSlot {
+    header: libc::msghdr,
+    iov: libc::iovec,
+    addr: libc::sockaddr_storage,
+    data: [u8; MAX_SEND_SIZE],
+}
+
+/// Responses that are sent with the next submission of the ring
+pub struct SendQueue {
+    slots: Box<[SendSlot]>,
+    free: Vec<usize>,
+    queued: Vec<usize>,
+}
+
+impl SendQueue {
+    fn new() -> Self {
+        let slots = (0..SEND_SLOTS)
+            // Safety:
+            // the slots are plain data, for which all zeroes is a valid value
+            .map(|_| unsafe { std::mem::zeroed::<SendSlot>() })
+            .collect();
+
+        SendQueue {
+            slots,
+            free: (0..SEND_SLOTS).rev().collect(),
+            queued: Vec::with_capacity(SEND_SLOTS),
+        }
+    }
+
+    /// Queue a packet to be sent to the address. Fails when the packet is too
+    /// large, or when too many packets are waiting to be sent already.
+    pub fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> io::Result<()> {
+        if buf.len() > MAX_SEND_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet is too large to send",
+            ));
+        }
+
+        let index = self.free.pop().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::WouldBlock, "too many packets in flight")
+        })?;
+
+        let slot = &mut self.slots[index];
+        slot.data[..buf.len()].copy_from_slice(buf);
+        let (storage, length) = socket_address(addr);
+        slot.addr = storage;
+        slot.iov = libc::iovec {
+            iov_base: slot.data.as_mut_ptr().cast::<libc::c_void>(),
+            iov_len: buf.len(),
+        };
+        slot.header = libc::msghdr {
+            msg_name: (&mut slot.addr as *mut libc::sockaddr_storage).cast::<libc::c_void>(),
+            msg_namelen: length,
+            msg_iov: &mut slot.iov,
+            msg_iovlen: 1,
+            msg_control: std::ptr::null_mut(),
+            msg_controllen: 0,
+            msg_flags: 0,
+        };
+
+        self.queued.push(index);
+        Ok(())
+    }
+}
+
+/// A server socket that receives and sends packets in batches through
+/// io_uring. It borrows the socket, and can be dropped at any time to go back
+/// to receiving through the socket itself.
+pub struct UringSocket<'a> {
+    ring: AsyncFd<IoUring>,
+    fd: RawFd,
+    /// Template for the multishot receive, of which only the name and control
+    /// lengths are used
+    receive_header: Box<libc::msghdr>,
+    receive_buffers: Box<[ReceiveBuffer]>,
+    receiving: bool,
+    /// Receive buffers to give back to the kernel with the next submission
+    returned: Vec<u16>,
+    sends: SendQueue,
+    /// Operations that may still access the memory of this socket
+    in_flight: usize,
+    completions: Vec<cqueue::Entry>,
+    socket: PhantomData<&'a UdpSocket>,
+}
+
+// Safety:
+// the raw pointers in the message headers only point into memory owned by the
+// UringSocket itself, which moves along with it
+unsafe impl Send for UringSocket<'_> {}
+
+impl<'a> UringSocket<'a> {
+    /// Set up a ring for the socket. This fails when io_uring is not available,
+    /// because of the kernel version or because it is disabled.
+    pub fn new(socket: &'a UdpSocket) -> io::Result<Self> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+
+        let receive_header = Box::new(libc::msghdr {
+            msg_name: std::ptr::null_mut(),
+            msg_namelen: NAME_SPACE as libc::socklen_t,
+            msg_iov: std::ptr::null_mut(),
+            msg_iovlen: 0,
+            msg_control: std::ptr::null_mut(),
+            msg_controllen: CONTROL_SPACE,
+            msg_flags: 0,
+        });
+
+        let mut uring = UringSocket {
+            ring: AsyncFd::new(ring)?,
+            fd: socket.as_ref().as_raw_fd(),
+            receive_header,
+            receive_buffers: (0..RECEIVE_BUFFERS)
+                .map(|_| ReceiveBuffer([0; RECEIVE_BUFFER_SIZE]))
+                .collect(),
+            receiving: false,
+            returned: Vec::with_capacity(RECEIVE_BUFFERS as usize),
+            sends: SendQueue::new(),
+            in_flight: 0,
+            completions: Vec::with_capacity(RING_ENTRIES as usize),
+            socket: PhantomData,
+        };
+
+        // the buffers are laid out contiguously, as their size is a multiple of their alignment
+        let provide = opcode::ProvideBuffers::new(
+            uring.receive_buffers.as_mut_ptr().cast::<u8>(),
+            RECEIVE_BUFFER_SIZE as i32,
+            RECEIVE_BUFFERS,
+            BUFFER_GROUP,
+            0,
+        )
+        .build()
+        .user_data(PROVIDE_BUFFERS);
+
+        // Safety:
+        // the receive buffers are owned by the socket, which outlives the operation
+        unsafe { uring.push(&provide)? };
+        uring.submit()?;
+
+        debug!(fd = uring.fd, "receiving through io_uring");
+        Ok(uring)
+    }
+
+    /// Push an entry onto the submission queue, submitting first if it is full
+    ///
+    /// # Safety
+    ///
+    /// All memory the entry refers to must stay valid until its last completion.
+    unsafe fn push(&mut self, entry: &squeue::Entry) -> io::Result<()> {
+        let ring = self.ring.get_mut();
+
+        // Safety: guaranteed by the caller
+        if unsafe { ring.submission().push(entry) }.is_err() {
+            ring.submit()?;
+
+            // Safety: guaranteed by the caller
+            unsafe { ring.submission().push(entry) }
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "submission queue is full"))?;
+        }
+
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Give back the used buffers, send the queued packets, and make sure the
+    /// socket is being received from, all in a single system call
+    fn submit(&mut self) -> io::Result<()> {
+        for bid in std::mem::take(&mut self.returned) {
+            let provide = opcode::ProvideBuffers::new(
+                self.receive_buffers[bid as usize].0.as_mut_ptr(),
+                RECEIVE_BUFFER_SIZE as i32,
+                1,
+                BUFFER_GROUP,
+                bid,
+            )
+            .build()
+            .user_data(PROVIDE_BUFFERS);
+
+            // Safety:
+            // the receive buffers are owned by the socket, which outlives the operation
+            unsafe { self.push(&provide)? };
+        }
+
+        for index in std::mem::take(&mut self.sends.queued) {
+            let send = opcode::SendMsg::new(types::Fd(self.fd), &self.sends.slots[index].header)
+                .build()
+                .user_data(index as u64);
+
+            // Safety:
+            // the header points into its own slot, which is owned by the socket and not touched
+            // until the send completes, as only then the slot becomes free again
+            unsafe { self.push(&send)? };
+        }
+
+        if !self.receiving {
+            let receive =
+                opcode::RecvMsgMulti::new(types::Fd(self.fd), &*self.receive_header, BUFFER_GROUP)
+                    .build()
+                    .user_data(RECEIVE);
+
+            // Safety:
+            // the header is owned by the socket, and the borrowed socket outlives the ring
+            unsafe { self.push(&receive)? };
+            self.receiving = true;
+        }
+
+        self.ring.get_ref().submit()?;
+        Ok(())
+    }
+
+    /// Wait for packets, and call `handle` with the result of receiving each of
+    /// them, like that of [`UdpSocket::recv`], and the received data. Responses
+    /// queued with the [`SendQueue`] are sent when the next batch is received.
+    ///
+    /// Returns the number of earlier responses that could not be sent. An error
+    /// means that the socket cannot be received from through io_uring anymore.
+    pub async fn recv_batch<F>(&mut self, mut handle: F) -> io::Result<usize>
+    where
+        F: FnMut(io::Result<(usize, SocketAddr, Option<NtpTimestamp>)>, &[u8], &mut SendQueue),
+    {
+        let mut completions = std::mem::take(&mut self.completions);
+
+        loop {
+            self.submit()?;
+
+            completions.extend(self.ring.get_mut().completion());
+            if !completions.is_empty() {
+                break;
+            }
+
+            // completions posted after the check above mark the ring readable again
+            trace!("waiting for completions");
+            self.ring.readable().await?.clear_ready();
+        }
+
+        let mut send_errors = 0;
+        let mut unsupported = None;
+
+        for completion in completions.drain(..) {
+            let flags = completion.flags();
+            if !cqueue::more(flags) {
+                self.in_flight -= 1;
+            }
+
+            let result = completion.result();
+            match completion.user_data() {
+                RECEIVE => {
+                    if !cqueue::more(flags) {
+                        self.receiving = false;
+                    }
+
+                    if let Some(bid) = cqueue::buffer_select(flags) {
+                        let buffer =
+                            &self.receive_buffers[bid as usize].0[..result.max(0) as usize];
+                        match parse_message(buffer, &self.receive_header) {
+                            Ok((addr, timestamp, data)) => {
+                                trace!(
+                                    size = data.len(),
+                                    ts = debug(timestamp),
+                                    addr = debug(addr),
+                                    "received message"
+                                );
+                                handle(Ok((data.len(), addr, timestamp)), data, &mut self.sends)
+                            }
+                            Err(error) => handle(Err(error), &[], &mut self.sends),
+                        }
+                        self.returned.push(bid);
+                    } else if result < 0 {
+                        match -result {
+                            // receiving stopped until the buffers are given back
+                            libc::ENOBUFS => trace!("out of receive buffers"),
+                            libc::EINVAL | libc::EOPNOTSUPP => {
+                                unsupported = Some(io::Error::from_raw_os_error(-result))
+                            }
+                            errno => {
+                                let error = io::Error::from_raw_os_error(errno);
+                                debug!(error = debug(&error), "error receiving data");
+                                handle(Err(error), &[], &mut self.sends)
+                            }
+                        }
+                    }
+                }
+                PROVIDE_BUFFERS | CANCEL => {
+                    if result < 0 {
+                        let error = io::Error::from_raw_os_error(-result);
+                        warn!(error = debug(error), "io_uring operation failed");
+                    }
+                }
+                index => {
+                    self.sends.free.push(index as usize);
+                    if result < 0 {
+                        let error = io::Error::from_raw_os_error(-result);
+                        debug!(error = debug(error), "error sending data");
+                        send_errors += 1;
+                    }
+                }
+            }
+        }
+
+        self.completions = completions;
+
+        match unsupported {
+            Some(error) => Err(error),
+            None => Ok(send_errors),
+        }
+    }
+
+    /// Cancel all operations, and wait until the kernel is done with them
+    fn cancel(&mut self) -> io::Result<()> {
+        if self.in_flight == 0 {
+            return Ok(());
+        }
+
+        let cancel = opcode::AsyncCancel2::new(types::CancelBuilder::any())
+            .build()
+            .user_data(CANCEL);
+
+        // Safety:
+        // cancelling does not refer to any memory
+        unsafe { self.push(&cancel)? };
+
+        while self.in_flight > 0 {
+            let ring = self.ring.get_mut();
+            ring.submit_and_wait(1)?;
+            for completion in ring.completion() {
+                if !cqueue::more(completion.flags()) {
+                    self.in_flight -= 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for UringSocket<'_> {
+    fn drop(&mut self) {
+        if let Err(error) = self.cancel() {
+            // the kernel may still write to the buffers, so they can never be freed
+            warn!(error = debug(error), "could not stop io_uring operations");
+            Box::leak(std::mem::take(&mut self.receive_buffers));
+            Box::leak(std::mem::take(&mut self.sends.slots));
+            let header = *self.receive_header;
+            Box::leak(std::mem::replace(
+                &mut self.receive_header,
+                Box::new(header),
+            ));
+        }
+    }
+}
+
+/// The sender, receive timestamp and data of a packet received by a multishot receive
+fn parse_message<'b>(
+    buffer: &'b [u8],
+    header: &libc::msghdr,
+) -> io::Result<(SocketAddr, Option<NtpTimestamp>, &'b [u8])> {
+    let message = types::RecvMsgOut::parse(buffer, header)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed received message"))?;
+
+    if message.is_payload_truncated() {
+        warn!(
+            max_len = MAX_PACKET_SIZE,
+            "truncated packet because it was larger than expected",
+        );
+    }
+
+    if message.is_control_data_truncated() {
+        warn!("truncated control messages");
+    }
+
+    let addr = socket_addr(message.name_data())
+        .unwrap_or_else(|| unreachable!("We never constructed a non-ip socket"));
+
+    let mut timestamp = None;
+
+    // Safety:
+    // the control data was written by the kernel, at an offset in the buffer that is aligned
+    // for control messages
+    for msg in unsafe { control_messages(message.control_data()) } {
+        match msg {
+            ControlMessage::Timestamping(timespec) => {
+                timestamp = Some(read_ntp_timestamp(timespec));
+            }
+            ControlMessage::ReceiveError(_) | ControlMessage::Other(_) => {
+                warn!("unexpected control message");
+            }
+        }
+    }
+
+    let payload = PAYLOAD_OFFSET..PAYLOAD_OFFSET + message.payload_data().len();
+    Ok((addr, timestamp, &buffer[payload]))
+}
+
+fn socket_addr(name: &[u8]) -> Option<SocketAddr> {
+    // Safety:
+    // sockaddr_storage is plain data, for which all zeroes is a valid value
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let length = name
+        .len()
+        .min(std::mem::size_of::<libc::sockaddr_storage>());
+
+    // Safety:
+    // both regions are at least length bytes, and the storage is plain data, so any bytes
+    // copied into it make a valid value
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            name.as_ptr(),
+            (&mut storage as *mut libc::sockaddr_storage).cast::<u8>(),
+            length,
+        )
+    };
+
+    sockaddr_storage_to_socket_addr(&storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_uring_server() {
+        let server = UdpSocket::server("127.0.0.1:10012".parse().unwrap())
+            .await
+            .unwrap();
+        let mut uring = match UringSocket::new(&server) {
+            Ok(uring) => uring,
+            Err(error) => {
+                // not every kernel or sandbox running the tests has io_uring
+                eprintln!("skipping, io_uring is not available: {}", error);
+                return;
+            }
+        };
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:10013")
+            .await
+            .unwrap();
+        client.connect("127.0.0.1:10012").await.unwrap();
+        client.send(&[1; 48]).await.unwrap();
+        client.send(&[2; 48]).await.unwrap();
+
+        let mut received = vec![];
+        while received.len() < 2 {
+            let receive = uring.recv_batch(|result, buf, sends| {
+                let (size, addr, timestamp) = result.unwrap();
+                assert_eq!(addr, "127.0.0.1:10013".parse().unwrap());
+                assert!(timestamp.is_some());
+                received.push(buf[0]);
+                sends.send_to(&buf[..size], addr).unwrap();
+            });
+
+            match tokio::time::timeout(Duration::from_secs(1), receive).await {
+                Ok(result) => assert_eq!(result.unwrap(), 0),
+                Err(_) => panic!("no packets received"),
+            }
+        }
+        assert_eq!(received, vec![1, 2]);
+
+        // the responses go out with the next submission
+        let respond = uring.recv_batch(|_, _, _| {});
+        assert!(matches!(
+            tokio::time::timeout(Duration::from_secs(1), respond).await,
+            Ok(Ok(0))
+        ));
+
+        let mut buf = [0; 48];
+        client.recv(&mut buf).await.unwrap();
+        assert_eq!(buf, [1; 48]);
+        client.recv(&mut buf).await.unwrap();
+        assert_eq!(buf, [2; 48]);
+
+        // the socket can be received from normally after the ring is gone
+        drop(uring);
+        client.send(&[3; 48]).await.unwrap();
+        let (size, _, _) = server.recv(&mut buf).await.unwrap();
+        assert_eq!(size, 48);
+        assert_eq!(buf, [3; 48]);
+    }
+}
@@ -0,0 +1,531 @@
+// replays measurements through the clock filter, clock selection and clock steering of
+// ntp-proto, without touching the system clock, and prints how the offset and frequency of the
+// clock evolve as CSV. The measurements are read from rawstats files written by the daemon
+// (`rawstats = true` in the statistics section), or generated from a synthetic scenario:
+//
+//   cargo run --bin ntp-sim -- rawstats.20231105 rawstats.20231106 > trajectory.csv
+//   cargo run --bin ntp-sim -- --config ntp.toml rawstats.20231105
+//   cargo run --bin ntp-sim -- --frequency-error 25 --initial-offset 0.2 --jitter 0.002
+//
+// The local timestamps of recorded exchanges are taken as those of a free running clock. When
+// the daemon that recorded them steered the clock, its corrections are part of the measurements,
+// so compare runs of the simulation with each other rather than with the original daemon.
+//
+// The steering of the kernel (the PLL of adjtimex) is simulated as well: every second, a part of
+// the remaining offset is corrected, and every update adjusts the frequency by an amount that
+// depends on the offset and the time since the previous update.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use clap::Parser;
+use ntp_proto::{
+    ClockController, ClockUpdateResult, FilterAndCombine, NtpClock, NtpDuration, NtpInstant,
+    NtpLeapIndicator, NtpPacket, NtpTimestamp, Peer, PeerBuilder, PeerSnapshot, PollInterval,
+    SystemConfig, SystemSnapshot, Update,
+};
+
+#[derive(Parser)]
+struct Args {
+    /// Rawstats files to replay, in any order. Without files, a synthetic scenario is simulated
+    files: Vec<PathBuf>,
+
+    /// Daemon configuration file, of which the system section is used
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Number of servers in the synthetic scenario
+    #[arg(long, default_value_t = 3)]
+    servers: u8,
+
+    /// Length of the synthetic scenario, in seconds
+    #[arg(long, default_value_t = 86400.0)]
+    duration: f64,
+
+    /// Poll interval of the servers in the synthetic scenario, as an exponent of two seconds
+    #[arg(long, default_value_t = 6)]
+    poll: u8,
+
+    /// Round trip delay to the servers in the synthetic scenario, in seconds
+    #[arg(long, default_value_t = 0.02)]
+    delay: f64,
+
+    /// Largest extra delay in either direction of a synthetic exchange, in seconds
+    #[arg(long, default_value_t = 0.001)]
+    jitter: f64,
+
+    /// Offset of the local clock at the start of the synthetic scenario, in seconds
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    initial_offset: f64,
+
+    /// Frequency error of the local clock in the synthetic scenario, in ppm
+    #[arg(long, default_value_t = 10.0, allow_hyphen_values = true)]
+    frequency_error: f64,
+
+    /// Seed of the jitter of the synthetic scenario
+    #[arg(long, default_value_t = 1)]
+    seed: u32,
+}
+
+/// One exchange with a server, with the timestamps of the free running local clock
+#[derive(Debug, Clone, Copy)]
+struct Exchange {
+    source: IpAddr,
+    destination: IpAddr,
+    send: NtpTimestamp,
+    /// Receive and transmit timestamp of the server, as sent on the wire
+    server_receive: u64,
+    server_transmit: u64,
+    recv: NtpTimestamp,
+    leap: u8,
+    version: u8,
+    stratum: u8,
+    poll: i8,
+    precision: i8,
+    root_delay: f64,
+    root_dispersion: f64,
+    /// Offset of the local clock from the true time, when it is known
+    clock_error: Option<f64>,
+}
+
+/// A time in NTP timestamp format, from seconds since the start of the NTP era
+fn timestamp_bits(seconds: f64) -> u64 {
+    let whole = seconds.floor();
+    ((whole as u64) << 32) + ((seconds - whole) * 4294967296.0) as u64
+}
+
+fn timestamp(bits: u64) -> NtpTimestamp {
+    let nanos = ((bits & 0xFFFF_FFFF) * 1_000_000_000) >> 32;
+    NtpTimestamp::from_seconds_nanos_since_ntp_era((bits >> 32) as u32, nanos as u32)
+}
+
+/// Parse a timestamp formatted as seconds since the start of the NTP era
+fn parse_timestamp(field: &str) -> Option<u64> {
+    let (seconds, fraction) = field.split_once('.').unwrap_or((field, "0"));
+    let seconds: u32 = seconds.parse().ok()?;
+    let nanos: u64 = format!("{fraction:0<9}").get(..9)?.parse().ok()?;
+    Some(((seconds as u64) << 32) + (nanos << 32) / 1_000_000_000)
+}
+
+/// Parse a line of a rawstats file: the day and time of the record, followed by the addresses,
+/// the four timestamps and the header fields of the response
+fn parse_rawstats(line: &str) -> Option<Exchange> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    // only responses of servers can be replayed
+    if fields.len() < 16 || fields[10] != "4" {
+        return None;
+    }
+
+    Some(Exchange {
+        source: fields[2].parse().ok()?,
+        destination: fields[3].parse().ok()?,
+        send: timestamp(parse_timestamp(fields[4])?),
+        server_receive: parse_timestamp(fields[5])?,
+        server_transmit: parse_timestamp(fields[6])?,
+        recv: timestamp(parse_timestamp(fields[7])?),
+        leap: fields[8].parse().ok()?,
+        version: fields[9].parse().ok()?,
+        stratum: fields[11].parse().ok()?,
+        poll: fields[12].parse().ok()?,
+        precision: fields[13].parse().ok()?,
+        root_delay: fields[14].parse().ok()?,
+        root_dispersion: fields[15].parse().ok()?,
+        clock_error: None,
+    })
+}
+
+fn read_rawstats(files: &[PathBuf]) -> std::io::Result<Vec<Exchange>> {
+    let mut exchanges = vec![];
+    let mut skipped = 0;
+    for path in files {
+        for line in BufReader::new(std::fs::File::open(path)?).lines() {
+            match parse_rawstats(&line?) {
+                Some(exchange) if exchange.version >= 3 && exchange.stratum > 0 => {
+                    exchanges.push(exchange)
+                }
+                _ => skipped += 1,
+            }
+        }
+    }
+    if skipped > 0 {
+        eprintln!("skipped {skipped} lines that are not responses of a server");
+    }
+
+    sort_by_time(&mut exchanges);
+    Ok(exchanges)
+}
+
+fn sort_by_time(exchanges: &mut [Exchange]) {
+    exchanges.sort_by(|a, b| (a.recv - b.recv).to_seconds().total_cmp(&0.0));
+}
+
+/// Exchanges with perfect servers, by a local clock with a constant frequency error
+fn synthetic(args: &Args) -> Vec<Exchange> {
+    // a linear congruential generator keeps runs with the same seed identical
+    let mut state = args.seed;
+    let mut jitter = || {
+        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        (state >> 8) as f64 / (1 << 24) as f64 * args.jitter
+    };
+
+    let start = 3_900_000_000.0;
+    let clock_error = |time: f64| args.initial_offset + args.frequency_error * 1e-6 * time;
+    let interval = (1u64 << args.poll) as f64;
+
+    let mut exchanges = vec![];
+    for server in 0..args.servers {
+        // spread the polls of the servers over the poll interval
+        let mut time = interval * server as f64 / args.servers as f64;
+        while time < args.duration {
+            let server_receive = time + args.delay / 2.0 + jitter();
+            let server_transmit = server_receive + 0.000_02;
+            let recv = server_transmit + args.delay / 2.0 + jitter();
+
+            exchanges.push(Exchange {
+                source: IpAddr::V4(Ipv4Addr::new(192, 0, 2, server + 1)),
+                destination: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 100)),
+                send: timestamp(timestamp_bits(start + time + clock_error(time))),
+                server_receive: timestamp_bits(start + server_receive),
+                server_transmit: timestamp_bits(start + server_transmit),
+                recv: timestamp(timestamp_bits(start + recv + clock_error(recv))),
+                leap: 0,
+                version: 4,
+                stratum: 1,
+                poll: args.poll as i8,
+                precision: -20,
+                root_delay: 0.0,
+                root_dispersion: 0.0,
+                clock_error: Some(clock_error(recv)),
+            });
+            time += interval;
+        }
+    }
+
+    sort_by_time(&mut exchanges);
+    exchanges
+}
+
+/// The response of the server, as it would have been received
+fn response(
+    exchange: &Exchange,
+    request: &NtpPacket,
+) -> Result<NtpPacket<'static>, Box<dyn Error>> {
+    let mut data = vec![];
+    request.serialize(&mut data)?;
+    let short = |seconds: f64| ((seconds * 65536.0) as u32).to_be_bytes();
+
+    let mut packet = [0u8; 48];
+    packet[0] = (exchange.leap << 6) | (exchange.version << 3) | 4;
+    packet[1] = exchange.stratum;
+    packet[2] = exchange.poll as u8;
+    packet[3] = exchange.precision as u8;
+    packet[4..8].copy_from_slice(&short(exchange.root_delay));
+    packet[8..12].copy_from_slice(&short(exchange.root_dispersion));
+    packet[12..16].copy_from_slice(b"XSIM");
+    // the origin timestamp is the transmit timestamp of the request
+    packet[24..32].copy_from_slice(&data[40..48]);
+    packet[32..40].copy_from_slice(&exchange.server_receive.to_be_bytes());
+    packet[40..48].copy_from_slice(&exchange.server_transmit.to_be_bytes());
+
+    Ok(NtpPacket::deserialize(&packet)?.into_owned())
+}
+
+#[derive(Debug, Default)]
+struct KernelState {
+    /// Seconds since the start of the simulation
+    time: f64,
+    /// Total correction applied to the clock, in seconds
+    phase: f64,
+    frequency: f64,
+    /// Offset that is still being corrected
+    remaining: f64,
+    time_constant: i32,
+    last_update: Option<f64>,
+}
+
+/// A clock that keeps track of the corrections it was given, and applies them like the kernel
+#[derive(Debug, Clone, Default)]
+struct SimulatedClock {
+    state: Arc<Mutex<KernelState>>,
+}
+
+impl SimulatedClock {
+    const MAX_FREQUENCY: f64 = 500e-6;
+    const MAX_PHASE: f64 = 0.5;
+
+    fn state(&self) -> std::sync::MutexGuard<'_, KernelState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Let the clock run until `time` seconds since the start of the simulation
+    fn advance(&self, time: f64) {
+        let mut state = self.state();
+        let elapsed = time - state.time;
+        if elapsed <= 0.0 {
+            return;
+        }
+
+        // every second, the kernel corrects a part of the remaining offset
+        let fraction = (-(2 + state.time_constant) as f64).exp2();
+        let remaining = state.remaining * (1.0 - fraction).powf(elapsed);
+        state.phase += state.remaining - remaining + state.frequency * elapsed;
+        state.remaining = remaining;
+        state.time = time;
+    }
+
+    fn phase(&self) -> f64 {
+        self.state().phase
+    }
+
+    fn frequency(&self) -> f64 {
+        self.state().frequency
+    }
+}
+
+impl NtpClock for SimulatedClock {
+    type Error = std::io::Error;
+
+    fn now(&self) -> Result<NtpTimestamp, Self::Error> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+    }
+
+    fn set_freq(&self, freq: f64) -> Result<(), Self::Error> {
+        self.state().frequency = freq.clamp(-Self::MAX_FREQUENCY, Self::MAX_FREQUENCY);
+        Ok(())
+    }
+
+    fn step_clock(&self, offset: NtpDuration) -> Result<(), Self::Error> {
+        self.state().phase += offset.to_seconds();
+        Ok(())
+    }
+
+    fn update_clock(
+        &self,
+        offset: NtpDuration,
+        _est_error: NtpDuration,
+        _max_error: NtpDuration,
+        poll_interval: PollInterval,
+        _leap_status: NtpLeapIndicator,
+    ) -> Result<(), Self::Error> {
+        let mut state = self.state();
+        let offset = offset.to_seconds().clamp(-Self::MAX_PHASE, Self::MAX_PHASE);
+        state.time_constant = (poll_interval.as_log() as i32).clamp(0, 10);
+
+        let mut seconds = state
+            .last_update
+            .map(|last| state.time - last)
+            .unwrap_or(0.0);
+        state.last_update = Some(state.time);
+
+        // after a long time without updates, the kernel switches to frequency locking
+        let mut adjustment = 0.0;
+        if seconds > 2048.0 {
+            adjustment += offset / seconds / 4.0;
+        }
+        seconds = seconds
+            .min((3 + state.time_constant) as f64)
+            .exp2()
+            .min(seconds);
+        adjustment += offset * seconds / (2.0 * (4 + state.time_constant) as f64).exp2();
+
+        state.frequency =
+            (state.frequency + adjustment).clamp(-Self::MAX_FREQUENCY, Self::MAX_FREQUENCY);
+        state.remaining = offset;
+        Ok(())
+    }
+}
+
+struct Simulation {
+    config: SystemConfig,
+    system: SystemSnapshot,
+    clock: SimulatedClock,
+    controller: ClockController<SimulatedClock>,
+    peers: HashMap<IpAddr, (Peer, Option<PeerSnapshot>)>,
+    start: NtpInstant,
+    ignored: u64,
+}
+
+impl Simulation {
+    fn new(mut config: SystemConfig) -> Self {
+        // the transmit timestamp of a request is the send timestamp of the exchange
+        config.transmit_timestamp_random_bits = 0;
+
+        let system = SystemSnapshot {
+            stratum: config.local_stratum,
+            ..Default::default()
+        };
+        let clock = SimulatedClock::default();
+        let controller = ClockController::new(clock.clone(), &system, &config);
+
+        Simulation {
+            config,
+            system,
+            clock,
+            controller,
+            peers: HashMap::new(),
+            start: NtpInstant::now(),
+            ignored: 0,
+        }
+    }
+
+    /// Process an exchange that completed `time` seconds after the start, returning the outcome
+    /// of the clock update it led to
+    fn exchange(
+        &mut self,
+        time: f64,
+        exchange: &Exchange,
+    ) -> Result<Option<ClockUpdateResult>, Box<dyn Error>> {
+        let now = self.start + Duration::from_secs_f64(time);
+        self.clock.advance(time);
+
+        // the clock being simulated reads the time of the free running clock plus its corrections
+        let phase = NtpDuration::from_seconds(self.clock.phase());
+        let send = exchange.send + phase;
+        let recv = exchange.recv + phase;
+
+        let (config, system) = (self.config, self.system);
+        let (peer, snapshot) = self.peers.entry(exchange.source).or_insert_with(|| {
+            let peer = PeerBuilder::from_addresses(exchange.destination, exchange.source)
+                .build(now, &config);
+            (peer, None)
+        });
+        let request = peer.generate_poll_message(now, send, system, &config);
+        let response = response(exchange, &request)?;
+
+        match peer.handle_incoming(system, &config, response, now, send, recv) {
+            Ok(Update::BareUpdate(update)) => {
+                *snapshot = Some(update);
+                Ok(None)
+            }
+            Ok(Update::NewMeasurement(update)) => {
+                *snapshot = Some(update);
+                Ok(self.update_clock(now))
+            }
+            Err(_) => {
+                self.ignored += 1;
+                Ok(None)
+            }
+        }
+    }
+
+    fn update_clock(&mut self, now: NtpInstant) -> Option<ClockUpdateResult> {
+        let snapshots: Vec<_> = self.peers.values().filter_map(|(_, s)| *s).collect();
+        let selection =
+            FilterAndCombine::run(&self.config, &snapshots, now, self.system.poll_interval).ok()?;
+
+        let result = self.controller.update(
+            &self.config,
+            &self.system,
+            selection.system_offset,
+            selection.system_root_delay,
+            selection.system_root_dispersion,
+            selection.system_peer_snapshot.leap_indicator,
+            selection.system_peer_snapshot.time,
+        );
+
+        if result == ClockUpdateResult::Step {
+            for (peer, snapshot) in self.peers.values_mut() {
+                peer.reset_measurements();
+                *snapshot = None;
+            }
+        }
+
+        if result != ClockUpdateResult::Ignore {
+            self.system.poll_interval = self.controller.preferred_poll_interval();
+            self.system.leap_indicator = selection.system_peer_snapshot.leap_indicator;
+            self.system.stratum = selection.system_peer_snapshot.stratum.saturating_add(1);
+            self.system.reference_id = selection.system_peer_snapshot.peer_id;
+            self.system.accumulated_steps = self.controller.accumulated_steps();
+            self.system.accumulated_steps_threshold = self.config.accumulated_threshold;
+            self.system.root_delay = selection.system_root_delay;
+            self.system.root_dispersion = selection.system_root_dispersion;
+        }
+
+        Some(result)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let config = match &args.config {
+        Some(path) => {
+            ntp_daemon::config::Config::from_args(Some(path), vec![], vec![])
+                .await?
+                .system
+        }
+        None => SystemConfig::default(),
+    };
+
+    let exchanges = if args.files.is_empty() {
+        synthetic(&args)
+    } else {
+        read_rawstats(&args.files)?
+    };
+    let first = match exchanges.first() {
+        Some(exchange) => exchange.recv,
+        None => return Err("no exchanges to replay".into()),
+    };
+
+    let mut simulation = Simulation::new(config);
+    let mut output = std::io::BufWriter::new(std::io::stdout().lock());
+    // offsets are those of the time of the servers relative to the clock, as in the logs of the
+    // daemon, and the true offset follows the same convention
+    writeln!(
+        output,
+        "time,offset,frequency,jitter,poll,update,true_offset"
+    )?;
+
+    for exchange in &exchanges {
+        let time = (exchange.recv - first).to_seconds().max(0.0);
+        let result = match simulation.exchange(time, exchange)? {
+            Some(result) => result,
+            None => continue,
+        };
+
+        let update = match result {
+            ClockUpdateResult::Ignore => "ignore",
+            ClockUpdateResult::Slew => "slew",
+            ClockUpdateResult::Step => "step",
+            ClockUpdateResult::Panic => "panic",
+        };
+        let true_offset = exchange
+            .clock_error
+            .map(|error| format!("{:.9}", -(error + simulation.clock.phase())))
+            .unwrap_or_default();
+        writeln!(
+            output,
+            "{:.3},{:.9},{:.3},{:.9},{},{},{}",
+            time,
+            simulation.controller.offset().to_seconds(),
+            simulation.clock.frequency() * 1e6,
+            simulation.controller.jitter().to_seconds(),
+            simulation.controller.preferred_poll_interval().as_log(),
+            update,
+            true_offset,
+        )?;
+
+        // the daemon exits here
+        if result == ClockUpdateResult::Panic {
+            eprintln!("the offset exceeds the panic threshold at {time:.3}s, stopping");
+            break;
+        }
+    }
+
+    output.flush()?;
+    eprintln!(
+        "replayed {} exchanges, of which {} were ignored by the peers",
+        exchanges.len(),
+        simulation.ignored
+    );
+
+    Ok(())
+}
@@ -0,0 +1,201 @@
+// floods a server of the daemon on localhost with garbage and with requests that carry many
+// extension fields, and compares the processor time this takes with that of as many valid
+// requests. Exits with an error when a kind of packet takes disproportionately more time, or
+// when the server no longer answers afterwards. Run with --release:
+//
+//   cargo run --release --bin server-flood
+//   cargo run --release --bin server-flood -- --batch-size 32
+
+use std::{
+    error::Error,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use ntp_daemon::config::ServerConfig;
+use ntp_proto::{NtpPacket, PollIntervalLimits, SystemConfig};
+
+#[derive(Parser)]
+struct Args {
+    /// Maximum number of packets the server receives at once
+    #[arg(long, default_value_t = 1)]
+    batch_size: usize,
+
+    /// Number of packets sent of every kind
+    #[arg(long, default_value_t = 200_000)]
+    packets: u64,
+
+    /// Largest accepted processor time per packet, relative to a valid request
+    #[arg(long, default_value_t = 3.0)]
+    max_ratio: f64,
+
+    /// Address to run the server on
+    #[arg(long, default_value = "127.0.0.1:8124")]
+    addr: SocketAddr,
+}
+
+/// Processor time used by the process so far, in clock ticks
+fn cpu_time() -> std::io::Result<u64> {
+    let stat = std::fs::read_to_string("/proc/self/stat")?;
+    // the fields after the command, which is in parentheses and can contain spaces
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .map(|(_, rest)| rest.split_whitespace().collect())
+        .unwrap_or_default();
+    // utime and stime are the 14th and 15th field, counting the pid and command
+    let ticks = |index: usize| {
+        fields
+            .get(index)
+            .and_then(|field| field.parse::<u64>().ok())
+    };
+    match (ticks(11), ticks(12)) {
+        (Some(user), Some(system)) => Ok(user + system),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "unexpected format of /proc/self/stat",
+        )),
+    }
+}
+
+fn valid_request() -> Vec<u8> {
+    let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+    let mut data = vec![];
+    packet.serialize(&mut data).unwrap();
+    data
+}
+
+/// A request with extension fields, as an NTS request has them
+fn request_with_extension_fields(count: usize, length: usize) -> Vec<u8> {
+    let mut data = valid_request();
+    for index in 0..count {
+        data.extend_from_slice(&0x0104u16.to_be_bytes());
+        data.extend_from_slice(&(length as u16).to_be_bytes());
+        data.resize(data.len() + length - 4, index as u8);
+    }
+    data
+}
+
+/// Bytes from a linear congruential generator, behind a header that makes them look like a
+/// request
+fn garbage(length: usize) -> Vec<u8> {
+    let mut state = 0x2545f491u32;
+    let mut data: Vec<u8> = (0..length)
+        .map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 24) as u8
+        })
+        .collect();
+    data[0] = 0x23;
+    data
+}
+
+/// Send `packets` copies of `request`, paced such that the server is not simply handed more
+/// than it can receive, and return the processor time this took
+fn flood(server: SocketAddr, request: &[u8], packets: u64) -> std::io::Result<u64> {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    socket.connect(server)?;
+
+    let start = cpu_time()?;
+    for sent in 0..packets {
+        // the responses are of no interest, and the send fails when they are refused
+        let _ = socket.send(request);
+        if sent % 100 == 99 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // let the server catch up
+    std::thread::sleep(Duration::from_millis(200));
+    Ok(cpu_time()? - start)
+}
+
+/// Whether the server still answers a valid request
+fn answers(server: SocketAddr) -> std::io::Result<bool> {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+    let mut buf = [0; 48];
+    for _ in 0..10 {
+        socket.send(&valid_request())?;
+        if socket.recv(&mut buf).is_ok() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let mut server = ServerConfig::try_from(args.addr.to_string().as_str())?;
+    server.workers = 1;
+    server.batch_size = args.batch_size;
+    // every packet is processed, instead of mostly being answered with a rate limiting kiss code
+    server.rate_limiting_cutoff = Duration::ZERO;
+
+    let (_handle, _) = ntp_daemon::spawn(
+        SystemConfig::default(),
+        &[],
+        &[],
+        &[],
+        &[server],
+        &Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        &Default::default(),
+        &Default::default(),
+        Default::default(),
+    )
+    .await?;
+
+    // give the server time to open its sockets
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let kinds = [
+        ("valid requests", valid_request()),
+        ("garbage", garbage(1024)),
+        ("short packets", vec![0x23; 20]),
+        ("NTS-like requests", request_with_extension_fields(15, 64)),
+        (
+            "too many extension fields",
+            request_with_extension_fields(60, 16),
+        ),
+        (
+            "too long extension fields",
+            request_with_extension_fields(1, 900),
+        ),
+    ];
+
+    let start = Instant::now();
+    let mut baseline = None;
+    let mut disproportionate = false;
+    for (name, request) in kinds {
+        let (addr, packets) = (args.addr, args.packets);
+        let ticks = tokio::task::spawn_blocking(move || flood(addr, &request, packets)).await??;
+        // a kind of packet that is handled in no time is as good as it gets
+        let per_packet = ticks.max(1) as f64 / args.packets as f64;
+        let baseline = *baseline.get_or_insert(per_packet);
+
+        let ratio = per_packet / baseline;
+        disproportionate |= ratio > args.max_ratio;
+        println!("{name}: {ticks} ticks, {ratio:.2} times the time of a valid request");
+    }
+
+    let answering = tokio::task::spawn_blocking(move || answers(args.addr)).await??;
+    if !answering {
+        println!("the server no longer answers");
+    }
+    println!("flooded for {:.1}s", start.elapsed().as_secs_f64());
+
+    if disproportionate || !answering {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,121 @@
+// floods a server of the daemon on localhost with requests from many clients, and reports the
+// number of responses per second. Run with --release, and compare different numbers of workers
+// and batch sizes:
+//
+//   cargo run --release --bin server-throughput -- --workers 1
+//   cargo run --release --bin server-throughput -- --workers 0
+//   cargo run --release --bin server-throughput -- --batch-size 32
+
+use std::{
+    error::Error,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use ntp_daemon::config::ServerConfig;
+use ntp_proto::{NtpPacket, PollIntervalLimits, SystemConfig};
+use tokio::net::UdpSocket;
+
+#[derive(Parser)]
+struct Args {
+    /// Number of server workers, 0 for one per available core
+    #[arg(long, default_value_t = 1)]
+    workers: usize,
+
+    /// Maximum number of packets a server worker receives at once
+    #[arg(long, default_value_t = 1)]
+    batch_size: usize,
+
+    /// Number of clients sending requests concurrently
+    #[arg(long, default_value_t = 64)]
+    clients: usize,
+
+    /// Duration of the measurement, in seconds
+    #[arg(long, default_value_t = 10)]
+    duration: u64,
+
+    /// Address to run the server on
+    #[arg(long, default_value = "127.0.0.1:8123")]
+    addr: SocketAddr,
+}
+
+/// Send requests one after the other until the deadline, returning the number of responses and
+/// the number of requests that were not answered in time
+async fn client(server: SocketAddr, deadline: Instant) -> std::io::Result<(u64, u64)> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    socket.connect(server).await?;
+
+    let (packet, _) = NtpPacket::poll_message(PollIntervalLimits::default().min);
+    let mut request = vec![];
+    packet.serialize(&mut request).unwrap();
+
+    let mut buf = [0; 48];
+    let (mut responses, mut timeouts) = (0, 0);
+    while Instant::now() < deadline {
+        socket.send(&request).await?;
+        match tokio::time::timeout(Duration::from_millis(100), socket.recv(&mut buf)).await {
+            Ok(result) => {
+                result?;
+                responses += 1;
+            }
+            Err(_) => timeouts += 1,
+        }
+    }
+
+    Ok((responses, timeouts))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let mut server = ServerConfig::try_from(args.addr.to_string().as_str())?;
+    server.workers = args.workers;
+    server.batch_size = args.batch_size;
+
+    let (_handle, _) = ntp_daemon::spawn(
+        SystemConfig::default(),
+        &[],
+        &[],
+        &[],
+        &[server],
+        &Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        &Default::default(),
+        &Default::default(),
+        Default::default(),
+    )
+    .await?;
+
+    // give the server time to open its sockets
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(args.duration);
+    let clients: Vec<_> = (0..args.clients)
+        .map(|_| tokio::spawn(client(args.addr, deadline)))
+        .collect();
+
+    let (mut responses, mut timeouts) = (0, 0);
+    for client in clients {
+        let (client_responses, client_timeouts) = client.await??;
+        responses += client_responses;
+        timeouts += client_timeouts;
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "{} workers, batches of {}, {} clients: {:.0} responses/s, {} requests unanswered",
+        args.workers,
+        args.batch_size,
+        args.clients,
+        responses as f64 / elapsed,
+        timeouts
+    );
+
+    Ok(())
+}